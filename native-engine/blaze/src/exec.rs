@@ -17,34 +17,75 @@ use std::error::Error;
 
 use std::panic::AssertUnwindSafe;
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 
 use datafusion::arrow::array::{export_array_into_raw, StructArray};
+use datafusion::arrow::datatypes::SchemaRef;
+use datafusion::arrow::error::{ArrowError, Result as ArrowResult};
 use datafusion::arrow::ffi::{FFI_ArrowArray, FFI_ArrowSchema};
+use datafusion::arrow::ffi_stream::FFI_ArrowArrayStream;
+use datafusion::arrow::record_batch::{RecordBatch, RecordBatchReader};
+use datafusion::error::DataFusionError;
+use datafusion::execution::context::TaskContext;
 use datafusion::execution::disk_manager::DiskManagerConfig;
 use datafusion::execution::memory_manager::MemoryManagerConfig;
 use datafusion::execution::runtime_env::{RuntimeConfig, RuntimeEnv};
-use datafusion::physical_plan::{displayable, ExecutionPlan};
+use datafusion::physical_plan::{displayable, ExecutionPlan, SendableRecordBatchStream};
 use datafusion::prelude::{SessionConfig, SessionContext};
+use datafusion_ext::blaze_error::BlazeError;
+use datafusion_ext::fault_injection::{inject_fault, FaultPoint};
+use datafusion_ext::ffi_compat::FFICompatConverter;
+use datafusion_ext::intra_task_parallel_exec::parallelize_plan;
 use datafusion_ext::jni_bridge::JavaClasses;
+use datafusion_ext::native_conf::{
+    native_conf, native_conf_with_overrides, set_task_native_conf, update_native_conf,
+    NativeConf,
+};
+use datafusion_ext::native_counters::take_task_counters;
+use datafusion_ext::output_metrics_exec::output_metrics_plan;
+use datafusion_ext::prefetch_stream::{consume_blocking, PrefetchStream};
+use datafusion_ext::shuffle_output_writer::{
+    abort_local_shuffle_output, commit_local_shuffle_output,
+};
+use datafusion_ext::spill_manager::SpillManager;
+use datafusion_ext::task_cancellation::{
+    cancel_task, cancellable_plan, TaskCancellation,
+};
+use datafusion_ext::unsafe_row::{is_unsafe_row_convertible, write_unsafe_rows};
 use datafusion_ext::*;
 use futures::{FutureExt, StreamExt};
+use jni::objects::{GlobalRef, JObject, JThrowable};
 use jni::objects::{JClass, JString};
-use jni::objects::{JObject, JThrowable};
-use jni::sys::{jboolean, jlong, JNI_FALSE, JNI_TRUE};
+use jni::sys::{jboolean, jbyteArray, jint, jlong, jlongArray, jstring};
+use jni::sys::{JNI_FALSE, JNI_TRUE};
 use jni::JNIEnv;
 use log::LevelFilter;
 use once_cell::sync::OnceCell;
-use plan_serde::protobuf::TaskDefinition;
+use plan_serde::plan_cache::{clear_plan_cache, convert_plan_cached};
+use plan_serde::protobuf::{
+    PartitionId, PlanConversionReport, PlanValidationResult, TaskDefinition,
+    UnsupportedItem,
+};
+use plan_serde::task_definition::{
+    convert_plan, decode_task_definition, supported_features, PLAN_VERSION,
+};
 use prost::Message;
-use simplelog::{ColorChoice, ConfigBuilder, TermLogger, TerminalMode, ThreadLogMode};
 use tokio::runtime::Runtime;
 
+use crate::explain::{save_explain, take_explain};
+use crate::logging::init_logging;
+use crate::memory_report::{
+    native_memory_report, register_batch_iterator, unregister_batch_iterator,
+};
 use crate::metrics::update_spark_metric_node;
+use crate::profiling::{init_profiling, profiling_enabled, TaskProfiler};
+use crate::telemetry::render_plan_conversion_report;
 
-static LOGGING_INIT: OnceCell<()> = OnceCell::new();
 static SESSIONCTX: OnceCell<SessionContext> = OnceCell::new();
+static NATIVE_MEMORY: OnceCell<(usize, f64)> = OnceCell::new();
+static BUFFER_POOL_TRACKED_BYTES: AtomicUsize = AtomicUsize::new(0);
 
 #[allow(non_snake_case)]
 #[allow(clippy::single_match)]
@@ -56,20 +97,11 @@ pub extern "system" fn Java_org_apache_spark_sql_blaze_JniBridge_initNative(
     native_memory: i64,
     memory_fraction: f64,
     tmp_dirs: JString,
+    profiling_enabled: jboolean,
 ) {
     match std::panic::catch_unwind(|| {
-        // init logging
-        LOGGING_INIT.get_or_init(|| {
-            TermLogger::init(
-                LevelFilter::Info,
-                ConfigBuilder::new()
-                    .set_thread_mode(ThreadLogMode::Both)
-                    .build(),
-                TerminalMode::Stderr,
-                ColorChoice::Never,
-            )
-            .unwrap();
-        });
+        // init logging, records are forwarded to log4j once java classes are inited
+        init_logging(native_conf().log_level.unwrap_or(LevelFilter::Info));
 
         // init jni java classes
         JavaClasses::init(&env);
@@ -82,14 +114,20 @@ pub extern "system" fn Java_org_apache_spark_sql_blaze_JniBridge_initNative(
                 .map(PathBuf::from)
                 .collect::<Vec<_>>();
             let max_memory = native_memory as usize;
-            let batch_size = batch_size as usize;
+            let batch_size = native_conf().batch_size.unwrap_or(batch_size as usize);
             let runtime_config = RuntimeConfig::new()
                 .with_memory_manager(MemoryManagerConfig::New {
                     max_memory,
                     memory_fraction,
                 })
-                .with_disk_manager(DiskManagerConfig::NewSpecified(dirs));
+                .with_disk_manager(DiskManagerConfig::NewSpecified(dirs.clone()));
             let runtime = Arc::new(RuntimeEnv::new(runtime_config).unwrap());
+            init_profiling(profiling_enabled == JNI_TRUE, dirs[0].clone());
+            SpillManager::init(dirs);
+            if native_memory > 0 {
+                NATIVE_MEMORY.get_or_init(|| (max_memory, memory_fraction));
+            }
+            apply_buffer_pool_capacity(&native_conf(), &runtime);
             let config = SessionConfig::new().with_batch_size(batch_size);
             SessionContext::with_config_rt(config, runtime)
         });
@@ -108,224 +146,1048 @@ pub extern "system" fn Java_org_apache_spark_sql_blaze_JniBridge_callNative(
     _: JClass,
     wrapper: JObject,
 ) {
-    if let Err(err) = std::panic::catch_unwind(|| {
-        log::info!("Entering blaze callNative()");
+    match std::panic::catch_unwind(|| call_native(wrapper)) {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => throw_blaze_error(err),
+        Err(err) => handle_unwinded(err),
+    }
+    set_task_native_conf(None);
+}
 
-        let wrapper = Arc::new(jni_new_global_ref!(wrapper).unwrap());
-        let wrapper_clone = wrapper.clone();
+fn call_native(wrapper: JObject) -> Result<(), BlazeError> {
+    log::info!("Entering blaze callNative()");
 
-        let obj_true =
-            jni_new_global_ref!(jni_new_object!(JavaBoolean, JNI_TRUE).unwrap()).unwrap();
+    let wrapper = Arc::new(jni_new_global_ref!(wrapper)?);
+    let wrapper_clone = wrapper.clone();
 
-        let obj_false =
-            jni_new_global_ref!(jni_new_object!(JavaBoolean, JNI_FALSE).unwrap())
-                .unwrap();
+    let obj_true = jni_new_global_ref!(jni_new_object!(JavaBoolean, JNI_TRUE)?)?;
+    let obj_false = jni_new_global_ref!(jni_new_object!(JavaBoolean, JNI_FALSE)?)?;
 
-        // decode plan
-        let raw_task_definition: JObject = jni_call!(
-            BlazeCallNativeWrapper(wrapper.as_obj()).getRawTaskDefinition() -> JObject
-        )
-        .unwrap();
+    // decode plan
+    let raw_task_definition: JObject = jni_call!(
+        BlazeCallNativeWrapper(wrapper.as_obj()).getRawTaskDefinition() -> JObject
+    )?;
 
-        let task_definition = TaskDefinition::decode(
-            jni_convert_byte_array!(raw_task_definition.into_inner())
-                .unwrap()
-                .as_slice(),
-        )
-        .unwrap();
-
-        let task_id = &task_definition.task_id.expect("task_id is empty");
-        let plan = &task_definition.plan.expect("plan is empty");
-
-        // get execution plan
-        let execution_plan: Arc<dyn ExecutionPlan> = plan.try_into().unwrap();
-        let execution_plan_displayable =
-            displayable(execution_plan.as_ref()).indent().to_string();
-        log::info!("Creating native execution plan succeeded");
-        log::info!("  task_id={:?}", task_id);
-        log::info!("  execution plan:\n{}", execution_plan_displayable);
-
-        // execute
-        let session_ctx = SESSIONCTX.get().unwrap();
-        let task_ctx = session_ctx.task_ctx();
-        let mut stream = execution_plan
-            .execute(task_id.partition_id as usize, task_ctx)
-            .unwrap();
-
-        let task_context = jni_new_global_ref!(
-            jni_call_static!(JniBridge.getTaskContext() -> JObject).unwrap()
-        )
-        .unwrap();
-
-        // a runtime wrapper that calls shutdown_background on dropping
-        struct RuntimeWrapper {
-            runtime: Option<Runtime>,
-        }
-        impl Drop for RuntimeWrapper {
-            fn drop(&mut self) {
-                if let Some(rt) = self.runtime.take() {
-                    rt.shutdown_background();
-                }
+    // malformed task definitions fail the task with descriptive errors
+    let task_definition = decode_task_definition(
+        jni_convert_byte_array!(raw_task_definition.into_inner())?.as_slice(),
+    )?;
+
+    let PreparedTask {
+        task_id,
+        task_conf,
+        execution_plan,
+    } = prepare_task(&task_definition)?;
+
+    // execute
+    // batch size may be overridden by JVM side, e.g. when retrying the task with a
+    // smaller batch size after native memory is overcommitted
+    let batch_size = jni_call!(
+        BlazeCallNativeWrapper(wrapper.as_obj()).getBatchSize() -> jlong
+    )?;
+    let batch_size = match batch_size {
+        batch_size if batch_size > 0 => Some(batch_size as usize),
+        _ => task_conf.as_ref().and_then(|conf| conf.batch_size),
+    };
+    let task_ctx = create_task_ctx(batch_size);
+    let cancellation = register_task_cancellation()?;
+
+    // sorts, aggregations and joins of a fat partition may be executed by
+    // multiple threads of the task runtime
+    let parallelism = native_conf().intra_task_parallelism;
+    let execution_plan = parallelize_plan(execution_plan, parallelism)?;
+    let execution_plan = cancellable_plan(execution_plan, cancellation.clone())?;
+    let execution_plan = output_metrics_plan(execution_plan)?;
+    let (profiler, execution_plan) = if profiling_enabled() {
+        let (profiler, instrumented_plan) =
+            TaskProfiler::start(task_id.stage_id, task_id.partition_id, execution_plan)?;
+        (Some(profiler), instrumented_plan)
+    } else {
+        (None, execution_plan)
+    };
+
+    // multiple partitions are polled concurrently and their batches are
+    // multiplexed into one output tagged with partition ids
+    let partition_ids = if task_definition.partition_ids.is_empty() {
+        vec![task_id.partition_id]
+    } else {
+        task_definition.partition_ids.clone()
+    };
+    let multiplexed = partition_ids.len() > 1;
+    let streams = partition_ids
+        .iter()
+        .map(|&partition_id| {
+            let stream =
+                execution_plan.execute(partition_id as usize, task_ctx.clone())?;
+            Ok::<_, BlazeError>((partition_id, stream))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // bytes of output batches allowed to be prefetched ahead of JVM consumer,
+    // prefetching is disabled if not positive
+    let output_prefetch_bytes = match native_conf().output_prefetch_bytes {
+        Some(output_prefetch_bytes) => output_prefetch_bytes,
+        None => jni_call!(
+            BlazeCallNativeWrapper(wrapper.as_obj()).getOutputPrefetchBytes() -> jlong
+        )?,
+    };
+
+    // output UnsafeRow bytes instead of arrow batches if requested by JVM side
+    // and all output fields are supported
+    let row_output = jni_call!(
+        BlazeCallNativeWrapper(wrapper.as_obj()).isRowOutputRequested() -> jboolean
+    )? == JNI_TRUE
+        && is_unsafe_row_convertible(&execution_plan.schema());
+    if row_output {
+        let num_fields = execution_plan.schema().fields().len() as i32;
+        jni_call!(
+            BlazeCallNativeWrapper(wrapper.as_obj()).enableRowOutput(num_fields) -> ()
+        )?;
+    }
+
+    // save explain annotated with metrics after execution if requested by JVM side
+    let explain_requested = jni_call!(
+        BlazeCallNativeWrapper(wrapper.as_obj()).isNativeExplainRequested() -> jboolean
+    )? == JNI_TRUE;
+    let (stage_id, partition_id) = (task_id.stage_id, task_id.partition_id);
+
+    let task_context =
+        jni_new_global_ref!(jni_call_static!(JniBridge.getTaskContext() -> JObject)?)?;
+
+    // a runtime wrapper that calls shutdown_background on dropping
+    struct RuntimeWrapper {
+        runtime: Option<Runtime>,
+    }
+    impl Drop for RuntimeWrapper {
+        fn drop(&mut self) {
+            if let Some(rt) = self.runtime.take() {
+                rt.shutdown_background();
             }
         }
+    }
 
-        // spawn a thread to poll batches
-        let runtime = Arc::new(RuntimeWrapper {
-            runtime: Some(
-                tokio::runtime::Builder::new_multi_thread()
-                    .worker_threads(1)
-                    .thread_keep_alive(Duration::MAX) // always use same thread
-                    .build()
-                    .unwrap(),
-            ),
-        });
-        let runtime_clone = runtime.clone();
-
-        runtime.clone().runtime.as_ref().unwrap().spawn(async move {
-            AssertUnwindSafe(async move {
-                let mut total_batches = 0;
-                let mut total_rows = 0;
-
-                // propagate task context to spawned children threads
-                jni_call_static!(JniBridge.setTaskContext(task_context.as_obj()) -> ()).unwrap();
-
-                // load batches
-                while let Some(r) = stream.next().await {
-                    match r {
-                        Ok(batch) => {
-                            let num_rows = batch.num_rows();
-                            if num_rows == 0 {
-                                continue;
-                            }
-                            total_batches += 1;
-                            total_rows += num_rows;
-
-                            // value_queue -> (schema_ptr, array_ptr)
-                            let mut input = JObject::null();
-                            while jni_call!(BlazeCallNativeWrapper(wrapper.as_obj()).isFinished() -> jboolean).unwrap() != JNI_TRUE {
-                                input = jni_call!(BlazeCallNativeWrapper(wrapper.as_obj()).dequeueWithTimeout() -> JObject).unwrap();
-
-                                if !input.is_null() {
-                                    break;
-                                }
-                            }
-                            if input.is_null() { // wrapper.isFinished = true
-                                break;
-                            }
-
-                            let schema_ptr = jni_call!(ScalaTuple2(input)._1() -> JObject).unwrap();
-                            let schema_ptr = jni_call!(JavaLong(schema_ptr).longValue() -> jlong).unwrap();
-                            let array_ptr = jni_call!(ScalaTuple2(input)._2() -> JObject).unwrap();
-                            let array_ptr = jni_call!(JavaLong(array_ptr).longValue() -> jlong).unwrap();
-
-                            let out_schema = schema_ptr as *mut FFI_ArrowSchema;
-                            let out_array = array_ptr as *mut FFI_ArrowArray;
-                            let batch: Arc<StructArray> = Arc::new(batch.into());
-                            unsafe {
-                                export_array_into_raw(
-                                    batch,
-                                    out_array,
-                                    out_schema,
-                                )
-                                .expect("export_array_into_raw error");
-                            }
-
-                            // value_queue <- hasNext=true
-                            while {
-                                jni_call!(BlazeCallNativeWrapper(wrapper.as_obj()).isFinished() -> jboolean).unwrap() != JNI_TRUE &&
-                                jni_call!(BlazeCallNativeWrapper(wrapper.as_obj()).enqueueWithTimeout(obj_true.as_obj()) -> jboolean).unwrap() != JNI_TRUE
-                            } {}
+    // worker threads executing sub-partitions of the task also need the task
+    // context, tunables and cancellation of the task
+    let on_thread_start = {
+        let task_context = task_context.clone();
+        let task_conf = task_conf.clone();
+        let cancellation = cancellation.clone();
+        move || {
+            let result = jni_call_static!(
+                JniBridge.setTaskContext(task_context.as_obj()) -> ()
+            );
+            if let Err(err) = result {
+                log::warn!("failed to set task context of worker thread: {}", err);
+            }
+            set_task_native_conf(task_conf.clone());
+            TaskCancellation::set_current(Some(cancellation.clone()));
+        }
+    };
+
+    // spawn a thread to poll batches, worker threads are named after the task so
+    // that they can be told apart in jstack and top
+    let worker_threads = parallelism.max(native_conf().task_worker_threads);
+    let runtime = Arc::new(RuntimeWrapper {
+        runtime: Some(
+            tokio::runtime::Builder::new_multi_thread()
+                .worker_threads(worker_threads)
+                .thread_name(format!("blaze-stage{}-p{}", stage_id, partition_id))
+                .thread_keep_alive(Duration::MAX) // always use same threads
+                .on_thread_start(on_thread_start)
+                .build()
+                .map_err(DataFusionError::IoError)?,
+        ),
+    });
+    let runtime_clone = runtime.clone();
+
+    runtime.clone().runtime.as_ref().unwrap().spawn(async move {
+        let result = AssertUnwindSafe(async move {
+            let mut total_batches = 0;
+            let mut total_rows = 0;
+
+            // propagate task context to spawned children threads
+            jni_call_static!(JniBridge.setTaskContext(task_context.as_obj()) -> ())?;
+            set_task_native_conf(task_conf);
+            TaskCancellation::set_current(Some(cancellation));
+
+            // types not supported by arrow FFI are cast before exporting
+            let ffi_compat_converter = FFICompatConverter::new(&execution_plan.schema());
+            let mut row_buffer: Vec<u8> = vec![];
+
+            // the prefetching budget is shared by all partitions
+            let num_partitions = streams.len() as i64;
+            let mut stream = futures::stream::select_all(streams.into_iter().map(
+                |(partition_id, stream)| {
+                    let stream: SendableRecordBatchStream = if output_prefetch_bytes > 0 {
+                        let budget_bytes = output_prefetch_bytes / num_partitions;
+                        Box::pin(PrefetchStream::new(stream, budget_bytes as usize))
+                    } else {
+                        stream
+                    };
+                    stream.map(move |batch| (partition_id, batch))
+                },
+            ));
+
+            // load batches
+            while let Some((output_partition_id, r)) = stream.next().await {
+                let batch = r?;
+                let num_rows = batch.num_rows();
+                if num_rows == 0 {
+                    continue;
+                }
+                total_batches += 1;
+                total_rows += num_rows;
+
+                // the JVM handshake blocks the worker thread, which must not stop
+                // prefetching of the following batches
+                let exported = consume_blocking(|| -> Result<bool, BlazeError> {
+                    // value_queue -> (schema_ptr, array_ptr)
+                    let mut input = JObject::null();
+                    while jni_call!(BlazeCallNativeWrapper(wrapper.as_obj()).isFinished() -> jboolean)? != JNI_TRUE {
+                        input = jni_call!(BlazeCallNativeWrapper(wrapper.as_obj()).dequeueWithTimeout() -> JObject)?;
+
+                        if !input.is_null() {
+                            break;
                         }
-                        Err(e) => {
-                            panic!("stream.next() error: {:?}", e);
+                    }
+                    if input.is_null() { // wrapper.isFinished = true
+                        return Ok(false);
+                    }
+
+                    if row_output {
+                        // JVM side copies the rows before setOutputRows() returns
+                        row_buffer.clear();
+                        write_unsafe_rows(&batch, &mut row_buffer)?;
+                        let rows = jni_new_direct_byte_buffer!(&mut row_buffer)?;
+                        jni_call!(BlazeCallNativeWrapper(wrapper.as_obj()).setOutputRows(rows) -> ())?;
+                        jni_delete_local_ref!(rows.into())?;
+                    } else {
+                        let schema_ptr = jni_call!(ScalaTuple2(input)._1() -> JObject)?;
+                        let schema_ptr = jni_call!(JavaLong(schema_ptr).longValue() -> jlong)?;
+                        let array_ptr = jni_call!(ScalaTuple2(input)._2() -> JObject)?;
+                        let array_ptr = jni_call!(JavaLong(array_ptr).longValue() -> jlong)?;
+
+                        inject_fault(FaultPoint::FfiExport)?;
+                        let out_schema = schema_ptr as *mut FFI_ArrowSchema;
+                        let out_array = array_ptr as *mut FFI_ArrowArray;
+                        let batch = ffi_compat_converter.convert(batch)?;
+                        let batch: Arc<StructArray> = Arc::new(batch.into());
+                        unsafe {
+                            export_array_into_raw(batch, out_array, out_schema)?;
                         }
                     }
-                }
 
-                // value_queue -> (discard)
-                while jni_call!(BlazeCallNativeWrapper(wrapper.as_obj()).isFinished() -> jboolean).unwrap() != JNI_TRUE {
-                    let input = jni_call!(BlazeCallNativeWrapper(wrapper.as_obj()).dequeueWithTimeout() -> JObject).unwrap();
-                    if !input.is_null() {
-                        break;
+                    if multiplexed {
+                        let partition_id = output_partition_id as i32;
+                        jni_call!(BlazeCallNativeWrapper(wrapper.as_obj()).setOutputPartitionId(partition_id) -> ())?;
                     }
+
+                    // value_queue <- hasNext=true
+                    while {
+                        jni_call!(BlazeCallNativeWrapper(wrapper.as_obj()).isFinished() -> jboolean)? != JNI_TRUE &&
+                        jni_call!(BlazeCallNativeWrapper(wrapper.as_obj()).enqueueWithTimeout(obj_true.as_obj()) -> jboolean)? != JNI_TRUE
+                    } {}
+                    Ok(true)
+                })?;
+                if !exported {
+                    break;
                 }
+            }
 
-                // value_queue <- hasNext=false
-                while {
-                    jni_call!(BlazeCallNativeWrapper(wrapper.as_obj()).isFinished() -> jboolean).unwrap() != JNI_TRUE &&
-                    jni_call!(BlazeCallNativeWrapper(wrapper.as_obj()).enqueueWithTimeout(obj_false.as_obj()) -> jboolean).unwrap() != JNI_TRUE
-                } {}
-
-                log::info!("Updating blaze exec metrics ...");
-                let metrics = jni_call!(
-                    BlazeCallNativeWrapper(wrapper.as_obj()).getMetrics() -> JObject
-                ).unwrap();
-
-                update_spark_metric_node(
-                    metrics,
-                    execution_plan.clone(),
-                ).unwrap();
-
-                log::info!("Blaze native executing finished.");
-                log::info!("  total loaded batches: {}", total_batches);
-                log::info!("  total loaded rows: {}", total_rows);
-                std::mem::drop(runtime);
-            })
-            .catch_unwind()
-            .await
-            .map_err(|err| {
-                let panic_message = panic_message::panic_message(&err);
-
-                let e = if jni_exception_check!()? {
-                    log::error!("native execution panics with an java exception");
-                    log::error!("panic message: {}", panic_message);
-                    jni_exception_occurred!()?.into()
-                } else {
-                    log::error!("native execution panics");
-                    log::error!("panic message: {}", panic_message);
-                    jni_new_object!(
-                        JavaRuntimeException,
-                        jni_new_string!("blaze native panics")?,
-                        JObject::null()
-                    )?
-                };
-
-                // error_queue <- exception
-
-                while jni_call!(
-                    BlazeCallNativeWrapper(wrapper_clone.as_obj()).isFinished() -> jboolean
-                ).unwrap() != JNI_TRUE {
-                    let enqueued = jni_call!(
-                        BlazeCallNativeWrapper(wrapper_clone.as_obj()).enqueueError(e) -> jboolean
-                    )?;
-                    if enqueued == JNI_TRUE {
-                        break;
-                    }
+            if let Some(profiler) = profiler {
+                profiler.finish();
+            }
+
+            // saved before signaling the end of output, so that the explain is
+            // available once the JVM side has consumed all output
+            if explain_requested {
+                save_explain(stage_id, partition_id, execution_plan.as_ref());
+            }
+
+            // value_queue -> (discard)
+            while jni_call!(BlazeCallNativeWrapper(wrapper.as_obj()).isFinished() -> jboolean)? != JNI_TRUE {
+                let input = jni_call!(BlazeCallNativeWrapper(wrapper.as_obj()).dequeueWithTimeout() -> JObject)?;
+                if !input.is_null() {
+                    break;
                 }
-                log::info!("Blaze native executing exited with error.");
-                std::mem::drop(runtime_clone);
-                datafusion::error::Result::Ok(())
-            })
-            .unwrap();
-        });
+            }
+
+            // value_queue <- hasNext=false
+            while {
+                jni_call!(BlazeCallNativeWrapper(wrapper.as_obj()).isFinished() -> jboolean)? != JNI_TRUE &&
+                jni_call!(BlazeCallNativeWrapper(wrapper.as_obj()).enqueueWithTimeout(obj_false.as_obj()) -> jboolean)? != JNI_TRUE
+            } {}
+
+            log::info!("Updating blaze exec metrics ...");
+            let metrics = jni_call!(
+                BlazeCallNativeWrapper(wrapper.as_obj()).getMetrics() -> JObject
+            )?;
+
+            update_spark_metric_node(
+                metrics,
+                execution_plan.clone(),
+            )?;
+
+            log::info!("Blaze native executing finished.");
+            log::info!("  total loaded batches: {}", total_batches);
+            log::info!("  total loaded rows: {}", total_rows);
+            std::mem::drop(runtime);
+            Ok::<_, BlazeError>(())
+        })
+        .catch_unwind()
+        .await;
+
+        // error_queue <- exception
+        let enqueue_error = |e: JObject| {
+            while jni_call!(
+                BlazeCallNativeWrapper(wrapper_clone.as_obj()).isFinished() -> jboolean
+            )? != JNI_TRUE {
+                let enqueued = jni_call!(
+                    BlazeCallNativeWrapper(wrapper_clone.as_obj()).enqueueError(e) -> jboolean
+                )?;
+                if enqueued == JNI_TRUE {
+                    break;
+                }
+            }
+            log::info!("Blaze native executing exited with error.");
+            datafusion::error::Result::Ok(())
+        };
+        let handled = match result {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(err)) => blaze_error_to_throwable(err).and_then(enqueue_error),
+            Err(err) => panic_to_throwable(err).and_then(enqueue_error),
+        };
+        handled.unwrap();
+        std::mem::drop(runtime_clone);
+    });
+
+    log::info!("Blaze native thread created");
+    Ok(())
+}
+
+/// A decoded task with its converted execution plan
+struct PreparedTask {
+    task_id: PartitionId,
+    task_conf: Option<Arc<NativeConf>>,
+    execution_plan: Arc<dyn ExecutionPlan>,
+}
+
+/// converts the plan of the task definition, tunables of the spark session
+/// are set for the current thread
+fn prepare_task(task_definition: &TaskDefinition) -> Result<PreparedTask, BlazeError> {
+    let task_id = task_definition
+        .task_id
+        .clone()
+        .ok_or_else(|| BlazeError::UserError("task_id is empty".to_owned()))?;
+    let plan = task_definition
+        .plan
+        .as_ref()
+        .ok_or_else(|| BlazeError::UserError("plan is empty".to_owned()))?;
+
+    // tunables of the spark session override the global tunables for this
+    // task, in both this thread and the thread polling batches
+    let task_conf = if task_definition.conf.is_empty() {
+        None
+    } else {
+        Some(native_conf_with_overrides(task_definition.conf.clone())?)
+    };
+    set_task_native_conf(task_conf.clone());
+
+    // get execution plan, tasks of the same stage reuse the converted plan
+    let execution_plan: Arc<dyn ExecutionPlan> = if native_conf().plan_cache {
+        convert_plan_cached(task_id.stage_id, &task_definition.conf, plan)?
+    } else {
+        convert_plan(plan)?
+    };
+    let execution_plan_displayable =
+        displayable(execution_plan.as_ref()).indent().to_string();
+    log::info!("Creating native execution plan succeeded");
+    log::info!("  task_id={:?}", task_id);
+    log::info!("  execution plan:\n{}", execution_plan_displayable);
+
+    Ok(PreparedTask {
+        task_id,
+        task_conf,
+        execution_plan,
+    })
+}
+
+/// registers the cancellation of the spark task of the current thread, set by
+/// cancelNative() once the task is interrupted or completed
+fn register_task_cancellation() -> Result<TaskCancellation, BlazeError> {
+    Ok(TaskCancellation::register(current_task_attempt_id()?))
+}
+
+fn current_task_attempt_id() -> Result<i64, BlazeError> {
+    Ok(jni_call_static!(JniBridge.getTaskAttemptId() -> jlong)? as i64)
+}
+
+fn create_task_ctx(batch_size: Option<usize>) -> Arc<TaskContext> {
+    let session_ctx = SESSIONCTX.get().unwrap();
+    if let Some(batch_size) = batch_size {
+        let config = SessionConfig::new().with_batch_size(batch_size);
+        let runtime = session_ctx.task_ctx().runtime_env();
+        SessionContext::with_config_rt(config, runtime).task_ctx()
+    } else {
+        session_ctx.task_ctx()
+    }
+}
+
+/// A native plan polled by the JVM thread calling nextBatch(), without the
+/// exchanger protocol of callNative(). the plan runs in a current-thread
+/// runtime, so the calling thread executes it and JVM callbacks from
+/// operators run in the task thread.
+struct NativeBatchIterator {
+    runtime: Runtime,
+    execution_plan: Arc<dyn ExecutionPlan>,
+    stream: SendableRecordBatchStream,
+    ffi_compat_converter: FFICompatConverter,
+    task_conf: Option<Arc<NativeConf>>,
+    cancellation: TaskCancellation,
+    task_attempt_id: i64,
+    stage_id: u32,
+    partition_id: u32,
+    total_batches: usize,
+    total_rows: usize,
+}
+
+impl NativeBatchIterator {
+    fn open(raw_task_definition: jbyteArray) -> Result<Self, BlazeError> {
+        log::info!("Opening blaze native batch iterator");
+        let task_definition = decode_task_definition(
+            jni_convert_byte_array!(raw_task_definition)?.as_slice(),
+        )?;
+        if task_definition.partition_ids.len() > 1 {
+            return Err(BlazeError::UserError(
+                "batch iterators do not support multiple partitions".to_owned(),
+            ));
+        }
+        let PreparedTask {
+            task_id,
+            task_conf,
+            execution_plan,
+        } = prepare_task(&task_definition)?;
+        let batch_size = task_conf.as_ref().and_then(|conf| conf.batch_size);
+        let task_ctx = create_task_ctx(batch_size);
+        let task_attempt_id = current_task_attempt_id()?;
+        let cancellation = TaskCancellation::register(task_attempt_id);
+        let execution_plan = cancellable_plan(execution_plan, cancellation.clone())?;
+        let execution_plan = output_metrics_plan(execution_plan)?;
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .map_err(DataFusionError::IoError)?;
+        let partition_id = task_id.partition_id as usize;
+        let stream = execution_plan.execute(partition_id, task_ctx)?;
+        let ffi_compat_converter = FFICompatConverter::new(&execution_plan.schema());
+        set_task_native_conf(None);
 
-        log::info!("Blaze native thread created");
+        Ok(Self {
+            runtime,
+            execution_plan,
+            stream,
+            ffi_compat_converter,
+            task_conf,
+            cancellation,
+            task_attempt_id,
+            stage_id: task_id.stage_id,
+            partition_id: task_id.partition_id,
+            total_batches: 0,
+            total_rows: 0,
+        })
+    }
+
+    /// exports the next non-empty batch, returns its row count, or -1 on the
+    /// end of output. batches without rows are skipped, so that a returned
+    /// row count is either positive or -1.
+    fn next_batch(
+        &mut self,
+        array_ptr: jlong,
+        schema_ptr: jlong,
+    ) -> Result<jint, BlazeError> {
+        let batch = match self.next_record_batch()? {
+            Some(batch) => batch,
+            None => return Ok(-1),
+        };
+        let num_rows = batch.num_rows();
+        let out_schema = schema_ptr as *mut FFI_ArrowSchema;
+        let out_array = array_ptr as *mut FFI_ArrowArray;
+        let batch: Arc<StructArray> = Arc::new(batch.into());
+        unsafe {
+            export_array_into_raw(batch, out_array, out_schema)?;
+        }
+        Ok(num_rows as jint)
+    }
+
+    /// returns the next non-empty batch converted for exporting, or None on
+    /// the end of output
+    fn next_record_batch(&mut self) -> Result<Option<RecordBatch>, BlazeError> {
+        let stream = &mut self.stream;
+        let next_batch = self.runtime.block_on(async move {
+            while let Some(batch) = stream.next().await {
+                let batch = batch?;
+                if batch.num_rows() > 0 {
+                    return Ok(Some(batch));
+                }
+            }
+            Ok::<_, BlazeError>(None)
+        })?;
+        let batch = match next_batch {
+            Some(batch) => batch,
+            None => return Ok(None),
+        };
+        self.total_batches += 1;
+        self.total_rows += batch.num_rows();
+
+        inject_fault(FaultPoint::FfiExport)?;
+        Ok(Some(self.ffi_compat_converter.convert(batch)?))
+    }
+
+    fn close(self, metrics: JObject) -> Result<(), BlazeError> {
+        log::info!("Updating blaze exec metrics ...");
+        update_spark_metric_node(metrics, self.execution_plan.clone())?;
+
+        log::info!("Blaze native batch iterator closed.");
+        log::info!("  total loaded batches: {}", self.total_batches);
+        log::info!("  total loaded rows: {}", self.total_rows);
+        std::mem::drop(self.stream);
+        self.runtime.shutdown_background();
+
+        // spill files of the plan are deleted with its operators, its spill
+        // directories are removed unless other executions of the task use them
+        SpillManager::get().release_unused_task_dirs(self.task_attempt_id);
+        Ok(())
+    }
+}
+
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "system" fn Java_org_apache_spark_sql_blaze_JniBridge_openBatchIterator(
+    _: JNIEnv,
+    _: JClass,
+    raw_task_definition: jbyteArray,
+) -> jlong {
+    let result =
+        std::panic::catch_unwind(|| NativeBatchIterator::open(raw_task_definition));
+    set_task_native_conf(None);
+    match result {
+        Ok(Ok(iter)) => {
+            let (stage_id, partition_id) = (iter.stage_id, iter.partition_id);
+            let iter_ptr = Box::into_raw(Box::new(iter)) as usize;
+            register_batch_iterator(iter_ptr, stage_id, partition_id);
+            iter_ptr as jlong
+        }
+        Ok(Err(err)) => {
+            throw_blaze_error(err);
+            0
+        }
+        Err(err) => {
+            handle_unwinded(err);
+            0
+        }
+    }
+}
+
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "system" fn Java_org_apache_spark_sql_blaze_JniBridge_nextBatch(
+    _: JNIEnv,
+    _: JClass,
+    iter_ptr: jlong,
+    array_ptr: jlong,
+    schema_ptr: jlong,
+) -> jint {
+    let iter = unsafe { &mut *(iter_ptr as *mut NativeBatchIterator) };
+    set_task_native_conf(iter.task_conf.clone());
+    TaskCancellation::set_current(Some(iter.cancellation.clone()));
+    let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+        iter.next_batch(array_ptr, schema_ptr)
+    }));
+    set_task_native_conf(None);
+    TaskCancellation::set_current(None);
+    match result {
+        Ok(Ok(num_rows)) => num_rows,
+        Ok(Err(err)) => {
+            throw_blaze_error(err);
+            -1
+        }
+        Err(err) => {
+            handle_unwinded(err);
+            -1
+        }
+    }
+}
+
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "system" fn Java_org_apache_spark_sql_blaze_JniBridge_closeBatchIterator(
+    _: JNIEnv,
+    _: JClass,
+    iter_ptr: jlong,
+    metrics: JObject,
+) {
+    unregister_batch_iterator(iter_ptr as usize);
+    let iter = unsafe { Box::from_raw(iter_ptr as *mut NativeBatchIterator) };
+    match std::panic::catch_unwind(AssertUnwindSafe(|| iter.close(metrics))) {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => throw_blaze_error(err),
+        Err(err) => handle_unwinded(err),
+    }
+}
+
+/// A native batch iterator exported as an ArrowArrayStream of the C stream
+/// interface, so that the JVM side imports its output with the standard arrow
+/// APIs. the iterator is closed, updating the metrics, once the stream is
+/// exhausted or released.
+struct NativeBatchStreamReader {
+    iter: Option<Box<NativeBatchIterator>>,
+    schema: SchemaRef,
+    metrics: GlobalRef,
+}
+
+impl NativeBatchStreamReader {
+    fn new(iter: Box<NativeBatchIterator>, metrics: GlobalRef) -> Self {
+        let iter_ptr = &*iter as *const NativeBatchIterator as usize;
+        register_batch_iterator(iter_ptr, iter.stage_id, iter.partition_id);
+        Self {
+            schema: iter.ffi_compat_converter.compat_schema(),
+            iter: Some(iter),
+            metrics,
+        }
+    }
+
+    fn next_batch(&mut self) -> Result<Option<RecordBatch>, BlazeError> {
+        let iter = match &mut self.iter {
+            Some(iter) => iter,
+            None => return Ok(None),
+        };
+        set_task_native_conf(iter.task_conf.clone());
+        TaskCancellation::set_current(Some(iter.cancellation.clone()));
+        let next_batch = iter.next_record_batch();
+        set_task_native_conf(None);
+        TaskCancellation::set_current(None);
+
+        let next_batch = next_batch?;
+        if next_batch.is_none() {
+            self.close()?;
+        }
+        Ok(next_batch)
+    }
+
+    fn close(&mut self) -> Result<(), BlazeError> {
+        if let Some(iter) = self.iter.take() {
+            unregister_batch_iterator(&*iter as *const NativeBatchIterator as usize);
+            iter.close(self.metrics.as_obj())?;
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for NativeBatchStreamReader {
+    type Item = ArrowResult<RecordBatch>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // called back by the JVM side through the stream, panics must not
+        // unwind across the FFI boundary
+        let next_batch = std::panic::catch_unwind(AssertUnwindSafe(|| self.next_batch()))
+            .unwrap_or_else(|err| {
+                Err(BlazeError::UserError(format!(
+                    "native batch stream panicked: {}",
+                    panic_message::panic_message(&err)
+                )))
+            });
+
+        // the importer throws its own exception with the error message, which
+        // must not be thrown with another exception pending
+        if next_batch.is_err() && jni_exception_check!().unwrap_or(false) {
+            let _ = jni_exception_clear!();
+        }
+        next_batch
+            .map_err(|err| ArrowError::ExternalError(Box::new(err)))
+            .transpose()
+    }
+}
+
+impl RecordBatchReader for NativeBatchStreamReader {
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+}
+
+impl Drop for NativeBatchStreamReader {
+    fn drop(&mut self) {
+        match std::panic::catch_unwind(AssertUnwindSafe(|| self.close())) {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => log::warn!("failed to close native batch stream: {}", err),
+            Err(err) => log::warn!(
+                "failed to close native batch stream: {}",
+                panic_message::panic_message(&err)
+            ),
+        }
+    }
+}
+
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "system" fn Java_org_apache_spark_sql_blaze_JniBridge_exportBatchStream(
+    _: JNIEnv,
+    _: JClass,
+    raw_task_definition: jbyteArray,
+    stream_ptr: jlong,
+    metrics: JObject,
+) {
+    let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+        let iter = Box::new(NativeBatchIterator::open(raw_task_definition)?);
+        let metrics = jni_new_global_ref!(metrics)?;
+        Ok::<_, BlazeError>(NativeBatchStreamReader::new(iter, metrics))
+    }));
+    set_task_native_conf(None);
+    match result {
+        Ok(Ok(reader)) => {
+            let stream = FFI_ArrowArrayStream::new(Box::new(reader));
+            // safety: the JVM side allocates the released stream struct
+            unsafe {
+                std::ptr::write(stream_ptr as *mut FFI_ArrowArrayStream, stream);
+            }
+        }
+        Ok(Err(err)) => throw_blaze_error(err),
+        Err(err) => handle_unwinded(err),
+    }
+}
+
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "system" fn Java_org_apache_spark_sql_blaze_JniBridge_cancelNative(
+    _: JNIEnv,
+    _: JClass,
+    task_attempt_id: jlong,
+) {
+    if let Err(err) = std::panic::catch_unwind(|| {
+        if cancel_task(task_attempt_id as i64) {
+            log::info!("Cancelled native execution of task {}", task_attempt_id);
+        }
+
+        // spill files of cancelled executions are not read back anymore
+        let num_deleted = SpillManager::get().release_task(task_attempt_id);
+        if num_deleted > 0 {
+            log::info!(
+                "Deleted {} spill files of cancelled task {}",
+                num_deleted,
+                task_attempt_id
+            );
+        }
     }) {
         handle_unwinded(err);
     }
 }
 
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "system" fn Java_org_apache_spark_sql_blaze_JniBridge_flushNativeCounters(
+    _: JNIEnv,
+    _: JClass,
+    task_attempt_id: jlong,
+) {
+    match std::panic::catch_unwind(|| {
+        for (name, value) in take_task_counters(task_attempt_id as i64) {
+            jni_call_static!(
+                JniBridge.updateNativeAccumulator(jni_new_string!(name)?, value) -> ()
+            )?;
+        }
+        Ok::<_, BlazeError>(())
+    }) {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => throw_blaze_error(err),
+        Err(err) => handle_unwinded(err),
+    }
+}
+
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "system" fn Java_org_apache_spark_sql_blaze_JniBridge_commitShuffleOutput(
+    env: JNIEnv,
+    _: JClass,
+    data_file: JString,
+    index_file: JString,
+    task_attempt_id: jlong,
+) -> jlongArray {
+    match std::panic::catch_unwind(|| {
+        let data_file =
+            String::from(jni_map_error_with_env!(env, env.get_string(data_file))?);
+        let index_file =
+            String::from(jni_map_error_with_env!(env, env.get_string(index_file))?);
+        let partition_lengths =
+            commit_local_shuffle_output(&data_file, &index_file, task_attempt_id as i64)?
+                .into_iter()
+                .map(|length| length as jlong)
+                .collect::<Vec<_>>();
+        let lengths_array = jni_map_error_with_env!(
+            env,
+            env.new_long_array(partition_lengths.len() as i32)
+        )?;
+        jni_map_error_with_env!(
+            env,
+            env.set_long_array_region(lengths_array, 0, &partition_lengths)
+        )?;
+        Ok::<_, BlazeError>(lengths_array)
+    }) {
+        Ok(Ok(lengths_array)) => lengths_array,
+        Ok(Err(err)) => {
+            throw_blaze_error(err);
+            std::ptr::null_mut()
+        }
+        Err(err) => {
+            handle_unwinded(err);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "system" fn Java_org_apache_spark_sql_blaze_JniBridge_abortShuffleOutput(
+    env: JNIEnv,
+    _: JClass,
+    data_file: JString,
+    index_file: JString,
+    task_attempt_id: jlong,
+) {
+    match std::panic::catch_unwind(|| {
+        let data_file =
+            String::from(jni_map_error_with_env!(env, env.get_string(data_file))?);
+        let index_file =
+            String::from(jni_map_error_with_env!(env, env.get_string(index_file))?);
+        abort_local_shuffle_output(&data_file, &index_file, task_attempt_id as i64)
+            .unwrap_or_else(|err| {
+                log::warn!("Error deleting shuffle output of aborted attempt: {}", err);
+            });
+        Ok::<_, BlazeError>(())
+    }) {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => throw_blaze_error(err),
+        Err(err) => handle_unwinded(err),
+    }
+}
+
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "system" fn Java_org_apache_spark_sql_blaze_JniBridge_getNativeVersion(
+    _: JNIEnv,
+    _: JClass,
+) -> jint {
+    PLAN_VERSION as jint
+}
+
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "system" fn Java_org_apache_spark_sql_blaze_JniBridge_supportedFeatures(
+    _: JNIEnv,
+    _: JClass,
+) -> jlong {
+    supported_features() as jlong
+}
+
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "system" fn Java_org_apache_spark_sql_blaze_JniBridge_validateTaskDefinition(
+    env: JNIEnv,
+    _: JClass,
+    raw_task_definition: jbyteArray,
+) -> jbyteArray {
+    match std::panic::catch_unwind(|| {
+        let task_definition_raw =
+            jni_map_error_with_env!(env, env.convert_byte_array(raw_task_definition))?;
+        let result = match decode_task_definition(&task_definition_raw) {
+            Ok(TaskDefinition {
+                plan: Some(plan), ..
+            }) => plan_serde::validate::validate_plan(&plan),
+            Ok(_) => invalid_task_definition("task definition has no plan".to_owned()),
+            Err(err) => invalid_task_definition(err.to_string()),
+        };
+        if !result.unsupported.is_empty() {
+            log::info!(
+                "Native plan validation found {} unsupported item(s)",
+                result.unsupported.len()
+            );
+        }
+        let result = jni_map_error_with_env!(
+            env,
+            env.byte_array_from_slice(&result.encode_to_vec())
+        )?;
+        Ok::<_, BlazeError>(result)
+    }) {
+        Ok(Ok(result)) => result,
+        Ok(Err(err)) => {
+            throw_blaze_error(err);
+            std::ptr::null_mut()
+        }
+        Err(err) => {
+            handle_unwinded(err);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "system" fn Java_org_apache_spark_sql_blaze_JniBridge_renderPlanConversionReport(
+    env: JNIEnv,
+    _: JClass,
+    raw_report: jbyteArray,
+) -> jstring {
+    match std::panic::catch_unwind(|| {
+        let report_raw =
+            jni_map_error_with_env!(env, env.convert_byte_array(raw_report))?;
+        let report = PlanConversionReport::decode(&*report_raw).map_err(|err| {
+            BlazeError::UserError(format!("malformed plan conversion report: {}", err))
+        })?;
+        let rendered = jni_map_error_with_env!(
+            env,
+            env.new_string(render_plan_conversion_report(&report))
+        )?;
+        Ok::<_, BlazeError>(rendered.into_inner())
+    }) {
+        Ok(Ok(rendered)) => rendered,
+        Ok(Err(err)) => {
+            throw_blaze_error(err);
+            std::ptr::null_mut()
+        }
+        Err(err) => {
+            handle_unwinded(err);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "system" fn Java_org_apache_spark_sql_blaze_JniBridge_takeNativeExplain(
+    env: JNIEnv,
+    _: JClass,
+    stage_id: jint,
+    partition_id: jint,
+) -> jstring {
+    match std::panic::catch_unwind(|| {
+        match take_explain(stage_id as u32, partition_id as u32) {
+            Some(explain) => env.new_string(explain).unwrap().into_inner(),
+            None => std::ptr::null_mut(),
+        }
+    }) {
+        Ok(explain) => explain,
+        Err(err) => {
+            handle_unwinded(err);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "system" fn Java_org_apache_spark_sql_blaze_JniBridge_dumpNativeMemory(
+    env: JNIEnv,
+    _: JClass,
+) -> jstring {
+    match std::panic::catch_unwind(|| {
+        env.new_string(native_memory_report()).unwrap().into_inner()
+    }) {
+        Ok(report) => report,
+        Err(err) => {
+            handle_unwinded(err);
+            std::ptr::null_mut()
+        }
+    }
+}
+
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "system" fn Java_org_apache_spark_sql_blaze_JniBridge_releaseTaskSpills(
+    _: JNIEnv,
+    _: JClass,
+    task_attempt_id: jlong,
+) {
+    if let Err(err) = std::panic::catch_unwind(|| {
+        let num_deleted = SpillManager::get().release_task(task_attempt_id);
+        if num_deleted > 0 {
+            log::info!(
+                "Deleted {} spill files left by task {}",
+                num_deleted,
+                task_attempt_id
+            );
+        }
+    }) {
+        handle_unwinded(err);
+    }
+}
+
+#[allow(non_snake_case)]
+#[no_mangle]
+pub extern "system" fn Java_org_apache_spark_sql_blaze_JniBridge_updateConfig(
+    env: JNIEnv,
+    _: JClass,
+    conf: JObject,
+) {
+    match std::panic::catch_unwind(|| {
+        let conf_map = jni_map_error_with_env!(env, env.get_map(conf))?;
+        let mut entries = vec![];
+        for (key, value) in jni_map_error_with_env!(env, conf_map.iter())? {
+            let key = jni_map_error_with_env!(env, env.get_string(key.into()))?;
+            let value = jni_map_error_with_env!(env, env.get_string(value.into()))?;
+            entries.push((String::from(key), String::from(value)));
+        }
+
+        // invalid entries fail as user errors, nothing is updated
+        let conf = update_native_conf(entries)?;
+        apply_native_conf(&conf);
+
+        // cached plans embed tunables read when converting
+        clear_plan_cache();
+        log::info!("Native conf updated: {:?}", conf);
+        Ok::<_, BlazeError>(())
+    }) {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => throw_blaze_error(err),
+        Err(err) => handle_unwinded(err),
+    }
+}
+
+/// applies tunables held by global states, other tunables are read by each
+/// task when launched
+fn apply_native_conf(conf: &NativeConf) {
+    if let Some(log_level) = conf.log_level {
+        log::set_max_level(log_level);
+    }
+    if let (Some(batch_size), Some(session_ctx)) = (conf.batch_size, SESSIONCTX.get()) {
+        session_ctx.state.write().config.batch_size = batch_size;
+    }
+    if let Some(session_ctx) = SESSIONCTX.get() {
+        apply_buffer_pool_capacity(conf, &session_ctx.task_ctx().runtime_env());
+    }
+}
+
+/// sizes the buffer pool by native memory, pooling is disabled if native memory
+/// is unknown. the pool is capped to the memory managed by the memory manager,
+/// where it is tracked as used so that operators spill before native memory is
+/// exceeded by pooled buffers.
+fn apply_buffer_pool_capacity(conf: &NativeConf, runtime: &RuntimeEnv) {
+    let capacity = match NATIVE_MEMORY.get() {
+        Some(&(native_memory, memory_fraction)) => {
+            let managed_memory = native_memory as f64 * memory_fraction;
+            let capacity = native_memory as f64 * conf.buffer_pool_fraction;
+            capacity.clamp(0.0, managed_memory) as usize
+        }
+        None => 0,
+    };
+    crate::ALLOC.set_capacity(capacity);
+
+    let tracked = BUFFER_POOL_TRACKED_BYTES.swap(capacity, Ordering::SeqCst);
+    runtime.shrink_tracker_usage(tracked);
+    runtime.grow_tracker_usage(capacity);
+}
+
 fn is_jvm_interrupted() -> datafusion::error::Result<bool> {
-    let interrupted_exception_class = "java.lang.InterruptedException";
     if jni_exception_check!()? {
         let e: JObject = jni_exception_occurred!()?.into();
-        let class = jni_get_object_class!(e)?;
-        let classname_obj = jni_call!(Class(class).getName() -> JObject)?;
-        let classname = jni_get_string!(classname_obj.into())?;
-
-        if classname == interrupted_exception_class {
-            return Ok(true);
-        }
+        return is_interrupted_exception(e);
     }
     Ok(false)
 }
@@ -343,6 +1205,93 @@ fn throw_runtime_exception(msg: &str, cause: JObject) -> datafusion::error::Resu
     Ok(())
 }
 
+/// takes the java exception pending in the current thread, if any
+fn take_pending_exception() -> datafusion::error::Result<Option<JObject<'static>>> {
+    if !jni_exception_check!()? {
+        return Ok(None);
+    }
+    let e: JObject = jni_exception_occurred!()?.into();
+    jni_exception_clear!()?;
+    Ok(Some(e))
+}
+
+fn is_interrupted_exception(e: JObject) -> datafusion::error::Result<bool> {
+    let class = jni_get_object_class!(e)?;
+    let classname_obj = jni_call!(Class(class).getName() -> JObject)?;
+    let classname = jni_get_string!(classname_obj.into())?;
+    Ok(classname == "java.lang.InterruptedException")
+}
+
+/// converts the error failing a native execution into the java exception
+/// rethrown by the JVM side. a java exception pending in the current thread
+/// (e.g. thrown by a JVM callback) is propagated as is, unless it interrupts
+/// the execution.
+fn blaze_error_to_throwable(
+    err: BlazeError,
+) -> datafusion::error::Result<JObject<'static>> {
+    let err = match take_pending_exception()? {
+        Some(e) if is_interrupted_exception(e)? => BlazeError::Cancelled,
+        Some(e) => {
+            log::error!("native execution failed with a java exception: {}", err);
+            return Ok(e);
+        }
+        None => err,
+    };
+    if let BlazeError::Cancelled = err {
+        log::info!("native execution cancelled");
+    } else {
+        log::error!("native execution failed ({}): {}", err.kind(), err);
+    }
+    jni_call_static!(
+        JniBridge.newNativeException(
+            jni_new_string!(err.kind())?,
+            jni_new_string!(err.to_string())?,
+        ) -> JObject
+    )
+}
+
+fn panic_to_throwable(
+    err: Box<dyn Any + Send>,
+) -> datafusion::error::Result<JObject<'static>> {
+    let panic_message = panic_message::panic_message(&err);
+    if let Some(e) = take_pending_exception()? {
+        log::error!("native execution panics with an java exception");
+        log::error!("panic message: {}", panic_message);
+        return Ok(e);
+    }
+    log::error!("native execution panics");
+    log::error!("panic message: {}", panic_message);
+    jni_new_object!(
+        JavaRuntimeException,
+        jni_new_string!("blaze native panics")?,
+        JObject::null()
+    )
+}
+
+fn throw_blaze_error(err: BlazeError) {
+    let throw = || {
+        let e = blaze_error_to_throwable(err)?;
+        jni_throw!(JThrowable::from(e))
+    };
+    if let Err(err) = throw() {
+        jni_fatal_error!(format!(
+            "Error throwing native error, cannot result: {:?}",
+            err
+        ));
+    }
+}
+
+fn invalid_task_definition(reason: String) -> PlanValidationResult {
+    PlanValidationResult {
+        unsupported: vec![UnsupportedItem {
+            path: "root".to_owned(),
+            operator: "Unknown".to_owned(),
+            expression: String::new(),
+            reason,
+        }],
+    }
+}
+
 fn handle_unwinded(err: Box<dyn Any + Send>) {
     // default handling:
     //  * caused by InterruptedException: do nothing but just print a message.
@@ -0,0 +1,57 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use datafusion::physical_plan::display::DisplayableExecutionPlan;
+use datafusion::physical_plan::ExecutionPlan;
+use once_cell::sync::OnceCell;
+
+/// explains of executed plans not yet taken by JVM side, oldest stages are
+/// evicted first if exceeded
+const MAX_SAVED_EXPLAINS: usize = 1024;
+
+fn saved_explains() -> &'static Mutex<BTreeMap<(u32, u32), String>> {
+    static SAVED_EXPLAINS: OnceCell<Mutex<BTreeMap<(u32, u32), String>>> =
+        OnceCell::new();
+    SAVED_EXPLAINS.get_or_init(|| Mutex::new(BTreeMap::new()))
+}
+
+/// renders the executed plan annotated with runtime metrics of each node, and
+/// saves it until taken by `take_explain()`
+pub fn save_explain(
+    stage_id: u32,
+    partition_id: u32,
+    execution_plan: &dyn ExecutionPlan,
+) {
+    let explain = DisplayableExecutionPlan::with_metrics(execution_plan)
+        .indent()
+        .to_string();
+
+    let mut explains = saved_explains().lock().unwrap();
+    explains.insert((stage_id, partition_id), explain);
+    while explains.len() > MAX_SAVED_EXPLAINS {
+        let oldest = *explains.keys().next().unwrap();
+        explains.remove(&oldest);
+    }
+}
+
+/// takes the saved explain of the latest native execution of the partition
+pub fn take_explain(stage_id: u32, partition_id: u32) -> Option<String> {
+    saved_explains()
+        .lock()
+        .unwrap()
+        .remove(&(stage_id, partition_id))
+}
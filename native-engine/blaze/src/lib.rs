@@ -13,12 +13,26 @@
 // limitations under the License.
 
 mod exec;
+mod explain;
+mod logging;
+mod memory_report;
 mod metrics;
+mod profiling;
+mod telemetry;
+
+use datafusion_ext::buffer_pool::BufferPoolAllocator;
 
 #[cfg(feature = "mm")]
 #[global_allocator]
-static ALLOC: mimalloc::MiMalloc = mimalloc::MiMalloc;
+static ALLOC: BufferPoolAllocator<mimalloc::MiMalloc> =
+    BufferPoolAllocator::new(mimalloc::MiMalloc);
 
 #[cfg(feature = "sn")]
 #[global_allocator]
-static ALLOC: snmalloc_rs::SnMalloc = snmalloc_rs::SnMalloc;
+static ALLOC: BufferPoolAllocator<snmalloc_rs::SnMalloc> =
+    BufferPoolAllocator::new(snmalloc_rs::SnMalloc);
+
+#[cfg(not(any(feature = "mm", feature = "sn")))]
+#[global_allocator]
+static ALLOC: BufferPoolAllocator<std::alloc::System> =
+    BufferPoolAllocator::new(std::alloc::System);
@@ -0,0 +1,96 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Forwards native log records to the executor's log4j through JniBridge, so
+//! that native logs interleave with JVM logs and are tagged with the task
+//! context of the logging thread.
+
+use std::cell::Cell;
+use std::io::Write;
+
+use datafusion_ext::jni_bridge::JavaClasses;
+use datafusion_ext::{jni_call_static, jni_new_string};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use once_cell::sync::OnceCell;
+
+static LOGGER: OnceCell<JniLogger> = OnceCell::new();
+
+thread_local! {
+    // records logged while forwarding a record (e.g. trace logs of JNI calls)
+    // are dropped to avoid infinite recursion
+    static FORWARDING: Cell<bool> = Cell::new(false);
+}
+
+/// installs the logger, max level is adjusted by native conf
+pub fn init_logging(max_level: LevelFilter) {
+    LOGGER.get_or_init(|| {
+        log::set_logger(&JniLogger).expect("error installing native logger");
+        log::set_max_level(max_level);
+        JniLogger
+    });
+}
+
+struct JniLogger;
+
+impl Log for JniLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) || FORWARDING.with(|f| f.replace(true)) {
+            return;
+        }
+
+        // records before JNI is initialized are written to stderr, which is
+        // also captured in executor logs
+        let message = record.args().to_string();
+        let forwarded = JavaClasses::initialized()
+            && forward_record(record.level(), record.target(), &message).is_ok();
+        if !forwarded {
+            let _ = writeln!(
+                std::io::stderr(),
+                "[{}] {}: {}",
+                record.level(),
+                record.target(),
+                message
+            );
+        }
+        FORWARDING.with(|f| f.set(false));
+    }
+
+    fn flush(&self) {}
+}
+
+fn forward_record(
+    level: Level,
+    target: &str,
+    message: &str,
+) -> datafusion::error::Result<()> {
+    // levels are mapped to log4j levels in JniBridge.nativeLog()
+    let level = match level {
+        Level::Error => 1,
+        Level::Warn => 2,
+        Level::Info => 3,
+        Level::Debug => 4,
+        Level::Trace => 5,
+    };
+    jni_call_static!(
+        JniBridge.nativeLog(
+            level,
+            jni_new_string!(target)?,
+            jni_new_string!(message)?,
+        ) -> ()
+    )
+}
@@ -0,0 +1,88 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use datafusion_ext::spark_memory::reservations_report;
+use once_cell::sync::OnceCell;
+
+/// A batch iterator handle returned to JVM side and not yet closed
+struct OpenBatchIterator {
+    stage_id: u32,
+    partition_id: u32,
+    opened_at: Instant,
+}
+
+fn open_batch_iterators() -> &'static Mutex<BTreeMap<usize, OpenBatchIterator>> {
+    static OPEN_BATCH_ITERATORS: OnceCell<Mutex<BTreeMap<usize, OpenBatchIterator>>> =
+        OnceCell::new();
+    OPEN_BATCH_ITERATORS.get_or_init(|| Mutex::new(BTreeMap::new()))
+}
+
+/// records the batch iterator handle until `unregister_batch_iterator()`
+pub fn register_batch_iterator(iter_ptr: usize, stage_id: u32, partition_id: u32) {
+    open_batch_iterators().lock().unwrap().insert(
+        iter_ptr,
+        OpenBatchIterator {
+            stage_id,
+            partition_id,
+            opened_at: Instant::now(),
+        },
+    );
+}
+
+pub fn unregister_batch_iterator(iter_ptr: usize) {
+    open_batch_iterators().lock().unwrap().remove(&iter_ptr);
+}
+
+/// renders a single-line json report of native memory: living reservations
+/// of each task, stats of the buffer pool, and batch iterators not closed by
+/// JVM side, which may leak the memory of their plans
+pub fn native_memory_report() -> String {
+    let pool = crate::ALLOC.stats();
+    let iterators = open_batch_iterators()
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(iter_ptr, iter)| {
+            format!(
+                concat!(
+                    "{{\"handle\":{},\"stage_id\":{},\"partition_id\":{},",
+                    "\"open_millis\":{}}}",
+                ),
+                iter_ptr,
+                iter.stage_id,
+                iter.partition_id,
+                iter.opened_at.elapsed().as_millis(),
+            )
+        })
+        .collect::<Vec<_>>();
+
+    format!(
+        concat!(
+            "{{\"reservations\":{},",
+            "\"buffer_pool\":{{\"capacity\":{},\"pooled_bytes\":{},",
+            "\"hits\":{},\"misses\":{}}},",
+            "\"batch_iterators\":[{}]}}",
+        ),
+        reservations_report(),
+        pool.capacity,
+        pool.pooled_bytes,
+        pool.hits,
+        pool.misses,
+        iterators.join(","),
+    )
+}
@@ -19,12 +19,15 @@ use jni::objects::JObject;
 
 use datafusion_ext::jni_call;
 use datafusion_ext::jni_new_string;
+use datafusion_ext::parquet_column_metrics_exec::COLUMN_METRIC_PREFIXES;
 
 const REPORTED_METRICS: &[&str] = &[
     "input_rows",
     "input_batches",
     "output_rows",
     "output_batches",
+    "output_bytes",
+    "peak_mem_used",
     "elapsed_compute",
     "join_time",
 ];
@@ -60,7 +63,11 @@ fn update_metrics(
     metric_values: &[(&str, i64)],
 ) -> datafusion::error::Result<()> {
     for &(name, value) in metric_values {
-        if REPORTED_METRICS.contains(&name) {
+        let is_column_metric = name
+            .split_once('.')
+            .map(|(prefix, _)| COLUMN_METRIC_PREFIXES.contains(&prefix))
+            .unwrap_or(false);
+        if REPORTED_METRICS.contains(&name) || is_column_metric {
             let jname = jni_new_string!(&name)?;
             jni_call!(SparkMetricNode(metric_node).add(jname, value) -> ())?;
         }
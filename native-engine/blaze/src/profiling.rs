@@ -0,0 +1,386 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Opt-in profiling of native executions, enabled by initNative().
+//!
+//! each operator of a profiled plan is wrapped with a tracing span entered
+//! whenever the operator's stream is polled, recording the time busy in the
+//! operator. a pprof flamegraph of the whole task is additionally captured
+//! and dumped into the first tmp dir, with a report of per-operator times.
+
+use std::any::Any;
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use datafusion::arrow::datatypes::SchemaRef;
+use datafusion::arrow::error::Result as ArrowResult;
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::error::Result;
+use datafusion::execution::context::TaskContext;
+use datafusion::physical_plan::expressions::PhysicalSortExpr;
+use datafusion::physical_plan::{
+    DisplayFormatType, ExecutionPlan, Partitioning, RecordBatchStream,
+    SendableRecordBatchStream, Statistics,
+};
+use datafusion_ext::with_new_children_preserving_partitioning;
+use futures::{Stream, StreamExt};
+use once_cell::sync::OnceCell;
+use pprof::ProfilerGuard;
+use tracing::Span;
+
+/// sampling frequency of pprof, a prime avoids lockstep with periodic work
+const PPROF_FREQUENCY: i32 = 99;
+
+static PROFILING_DIR: OnceCell<Option<PathBuf>> = OnceCell::new();
+
+/// enables profiling of all subsequent native executions, dumping profiles
+/// into the specified dir. only the first call takes effect.
+pub fn init_profiling(enabled: bool, dir: PathBuf) {
+    PROFILING_DIR.get_or_init(|| enabled.then(|| dir));
+}
+
+pub fn profiling_enabled() -> bool {
+    matches!(PROFILING_DIR.get(), Some(Some(_)))
+}
+
+/// Busy time of one operator of a profiled plan
+#[derive(Debug)]
+struct OperatorProfile {
+    name: String,
+    depth: usize,
+    busy_ns: AtomicU64,
+    output_rows: AtomicU64,
+}
+
+/// Profiles one native execution, started before executing the plan
+pub struct TaskProfiler {
+    stage_id: u32,
+    partition_id: u32,
+    operators: Vec<Arc<OperatorProfile>>,
+    pprof_guard: Option<ProfilerGuard<'static>>,
+}
+
+impl TaskProfiler {
+    /// wraps every operator of the plan for profiling and starts capturing
+    /// pprof samples. pprof profiles the whole process and only one profiler
+    /// can be active at a time, so concurrent tasks only get operator times.
+    pub fn start(
+        stage_id: u32,
+        partition_id: u32,
+        plan: Arc<dyn ExecutionPlan>,
+    ) -> Result<(Self, Arc<dyn ExecutionPlan>)> {
+        let mut operators = vec![];
+        let plan = instrument_plan(plan, 0, &mut operators)?;
+
+        let pprof_guard = pprof::ProfilerGuardBuilder::default()
+            .frequency(PPROF_FREQUENCY)
+            .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+            .build()
+            .map_err(|err| {
+                log::warn!("pprof not captured for this task: {}", err);
+            })
+            .ok();
+
+        let profiler = Self {
+            stage_id,
+            partition_id,
+            operators,
+            pprof_guard,
+        };
+        Ok((profiler, plan))
+    }
+
+    /// stops capturing and dumps the flamegraph and operator report
+    pub fn finish(self) {
+        let dir = match PROFILING_DIR.get() {
+            Some(Some(dir)) => dir,
+            _ => return,
+        };
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis())
+            .unwrap_or_default();
+        let file_prefix = dir.join(format!(
+            "blaze-profile-stage{}-partition{}-{}",
+            self.stage_id, self.partition_id, timestamp
+        ));
+
+        let report = self.operator_report();
+        log::info!("Native operator profile:\n{}", report);
+        let report_path = file_prefix.with_extension("txt");
+        if let Err(err) = std::fs::write(&report_path, &report) {
+            log::warn!("error writing profile {:?}: {}", report_path, err);
+        }
+
+        if let Some(guard) = self.pprof_guard {
+            let flamegraph_path = file_prefix.with_extension("svg");
+            let written = guard
+                .report()
+                .build()
+                .map_err(|err| err.to_string())
+                .and_then(|report| {
+                    let mut file =
+                        File::create(&flamegraph_path).map_err(|err| err.to_string())?;
+                    report
+                        .flamegraph(&mut file)
+                        .map_err(|err| err.to_string())?;
+                    file.flush().map_err(|err| err.to_string())
+                });
+            match written {
+                Ok(()) => {
+                    log::info!("Native flamegraph written to {:?}", flamegraph_path)
+                }
+                Err(err) => log::warn!("error writing flamegraph: {}", err),
+            }
+        }
+    }
+
+    /// renders the operator tree with busy times, self time excludes the
+    /// time children are polled within the operator
+    fn operator_report(&self) -> String {
+        let mut report = String::new();
+        for (i, operator) in self.operators.iter().enumerate() {
+            let busy_ns = operator.busy_ns.load(Ordering::Relaxed);
+            let children_busy_ns: u64 = self.operators[i + 1..]
+                .iter()
+                .take_while(|child| child.depth > operator.depth)
+                .filter(|child| child.depth == operator.depth + 1)
+                .map(|child| child.busy_ns.load(Ordering::Relaxed))
+                .sum();
+            report.push_str(&format!(
+                "{}{}: busy={:.3}ms, self={:.3}ms, output_rows={}\n",
+                "  ".repeat(operator.depth),
+                operator.name,
+                busy_ns as f64 / 1e6,
+                busy_ns.saturating_sub(children_busy_ns) as f64 / 1e6,
+                operator.output_rows.load(Ordering::Relaxed),
+            ));
+        }
+        report
+    }
+}
+
+fn instrument_plan(
+    plan: Arc<dyn ExecutionPlan>,
+    depth: usize,
+    operators: &mut Vec<Arc<OperatorProfile>>,
+) -> Result<Arc<dyn ExecutionPlan>> {
+    let profile = Arc::new(OperatorProfile {
+        name: operator_name(plan.as_ref()),
+        depth,
+        busy_ns: AtomicU64::new(0),
+        output_rows: AtomicU64::new(0),
+    });
+    operators.push(profile.clone());
+
+    let children = plan
+        .children()
+        .into_iter()
+        .map(|child| instrument_plan(child, depth + 1, operators))
+        .collect::<Result<Vec<_>>>()?;
+    let plan = if children.is_empty() {
+        plan
+    } else {
+        with_new_children_preserving_partitioning(plan, children)?
+    };
+    Ok(Arc::new(ProfiledExec {
+        input: plan,
+        profile,
+    }))
+}
+
+/// name of the operator, e.g. `SortExec`
+fn operator_name(plan: &dyn ExecutionPlan) -> String {
+    struct Fmt<'a>(&'a dyn ExecutionPlan);
+    impl Display for Fmt<'_> {
+        fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+            self.0.fmt_as(DisplayFormatType::Default, f)
+        }
+    }
+    let display = Fmt(plan).to_string();
+    display
+        .split(|c: char| c == ':' || c.is_whitespace())
+        .next()
+        .unwrap_or_default()
+        .to_owned()
+}
+
+/// Records busy time of its input operator.
+///
+/// displaying is that of the input, so that explains are unchanged.
+#[derive(Debug)]
+struct ProfiledExec {
+    input: Arc<dyn ExecutionPlan>,
+    profile: Arc<OperatorProfile>,
+}
+
+impl ExecutionPlan for ProfiledExec {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.input.schema()
+    }
+
+    fn output_partitioning(&self) -> Partitioning {
+        self.input.output_partitioning()
+    }
+
+    fn output_ordering(&self) -> Option<&[PhysicalSortExpr]> {
+        self.input.output_ordering()
+    }
+
+    datafusion_ext::transparent_plan_tree!(input, |self, input| Self {
+        input,
+        profile: self.profile.clone()
+    });
+
+    fn execute(
+        &self,
+        partition: usize,
+        context: Arc<TaskContext>,
+    ) -> Result<SendableRecordBatchStream> {
+        let span = tracing::info_span!(
+            "blaze_operator",
+            operator = %self.profile.name,
+            partition,
+            busy_ns = tracing::field::Empty,
+        );
+        let input = span.in_scope(|| self.input.execute(partition, context))?;
+        Ok(Box::pin(ProfiledStream {
+            input,
+            span,
+            profile: self.profile.clone(),
+        }))
+    }
+
+    fn fmt_as(&self, t: DisplayFormatType, f: &mut Formatter) -> std::fmt::Result {
+        self.input.fmt_as(t, f)
+    }
+
+    fn statistics(&self) -> Statistics {
+        self.input.statistics()
+    }
+}
+
+struct ProfiledStream {
+    input: SendableRecordBatchStream,
+    span: Span,
+    profile: Arc<OperatorProfile>,
+}
+
+impl Stream for ProfiledStream {
+    type Item = ArrowResult<RecordBatch>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        let span = self.span.clone();
+        let _entered = span.enter();
+        let start_time = Instant::now();
+        let poll = self.input.poll_next_unpin(cx);
+        let busy_ns = start_time.elapsed().as_nanos() as u64;
+        let total_busy_ns = self.profile.busy_ns.fetch_add(busy_ns, Ordering::Relaxed);
+
+        match &poll {
+            Poll::Ready(Some(Ok(batch))) => {
+                let num_rows = batch.num_rows() as u64;
+                self.profile
+                    .output_rows
+                    .fetch_add(num_rows, Ordering::Relaxed);
+            }
+            Poll::Ready(None) => {
+                span.record("busy_ns", &(total_busy_ns + busy_ns));
+            }
+            _ => {}
+        }
+        poll
+    }
+}
+
+impl RecordBatchStream for ProfiledStream {
+    fn schema(&self) -> SchemaRef {
+        self.input.schema()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use datafusion::arrow::array::Int32Array;
+    use datafusion::arrow::datatypes::{DataType, Field, Schema};
+    use datafusion::arrow::record_batch::RecordBatch;
+    use datafusion::error::Result;
+    use datafusion::physical_plan::common::collect;
+    use datafusion::physical_plan::expressions::col;
+    use datafusion::physical_plan::memory::MemoryExec;
+    use datafusion::physical_plan::sorts::sort::SortExec;
+    use datafusion::prelude::SessionContext;
+
+    use super::*;
+
+    #[test]
+    fn test_profiled_partitioned_sort() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int32, false)]));
+        let batch = |values: Vec<i32>| {
+            RecordBatch::try_new(schema.clone(), vec![Arc::new(Int32Array::from(values))])
+        };
+        let input = Arc::new(MemoryExec::try_new(
+            &[vec![batch(vec![3, 1, 2])?], vec![batch(vec![6, 4, 5])?]],
+            schema.clone(),
+            None,
+        )?);
+        let sort = Arc::new(SortExec::new_with_partitioning(
+            vec![PhysicalSortExpr {
+                expr: col("a", &schema)?,
+                options: Default::default(),
+            }],
+            input,
+            true,
+        ));
+
+        let mut operators = vec![];
+        let plan = instrument_plan(sort, 0, &mut operators)?;
+        assert_eq!(plan.output_partitioning().partition_count(), 2);
+        assert_eq!(operators.len(), 2);
+        assert_eq!(operators[0].name, "SortExec");
+
+        let runtime = tokio::runtime::Runtime::new()?;
+        let task_ctx = SessionContext::new().task_ctx();
+        for (partition, expected) in [[1, 2, 3], [4, 5, 6]].into_iter().enumerate() {
+            let stream = plan.execute(partition, task_ctx.clone())?;
+            let batches = runtime.block_on(collect(stream))?;
+            let values = batches
+                .iter()
+                .flat_map(|batch| {
+                    let array = batch.column(0).as_any().downcast_ref::<Int32Array>();
+                    array.unwrap().values().to_vec()
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(values, expected);
+        }
+        assert_eq!(operators[0].output_rows.load(Ordering::Relaxed), 6);
+        Ok(())
+    }
+}
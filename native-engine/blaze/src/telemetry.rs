@@ -0,0 +1,91 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Renders plan conversion reports of the driver as single-line JSON, so that
+//! fallbacks of real workloads can be collected from driver logs and ranked by
+//! reason code.
+
+use std::fmt::Write;
+
+use plan_serde::protobuf::{FallbackReason, PlanConversionReport};
+
+pub fn render_plan_conversion_report(report: &PlanConversionReport) -> String {
+    // most frequent fallbacks first
+    let mut fallbacks = report.fallbacks.iter().collect::<Vec<_>>();
+    fallbacks.sort_by(|a, b| {
+        b.operators
+            .cmp(&a.operators)
+            .then_with(|| a.operator.cmp(&b.operator))
+    });
+
+    let mut json = String::new();
+    write!(
+        json,
+        "{{\"execution_id\":{},\"native_operators\":{},\"fallback_operators\":{},\
+         \"native_expressions\":{},\"fallback_expressions\":{},\"fallbacks\":[",
+        report.execution_id,
+        report.native_operators,
+        report.fallback_operators,
+        report.native_expressions,
+        report.fallback_expressions,
+    )
+    .unwrap();
+    for (i, fallback) in fallbacks.into_iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        write!(
+            json,
+            "{{\"reason\":\"{}\",\"operator\":\"{}\",\"operators\":{},\
+             \"expressions\":{},\"example\":\"{}\"}}",
+            reason_code(fallback.reason),
+            escape(&fallback.operator),
+            fallback.operators,
+            fallback.expressions,
+            escape(&fallback.example),
+        )
+        .unwrap();
+    }
+    json.push_str("]}");
+    json
+}
+
+fn reason_code(reason: i32) -> &'static str {
+    match FallbackReason::from_i32(reason) {
+        Some(FallbackReason::NoNativeImplementation) => "NO_NATIVE_IMPLEMENTATION",
+        Some(FallbackReason::DisabledByConf) => "DISABLED_BY_CONF",
+        Some(FallbackReason::ChildNotNative) => "CHILD_NOT_NATIVE",
+        Some(FallbackReason::UnsupportedExpression) => "UNSUPPORTED_EXPRESSION",
+        Some(FallbackReason::UnsupportedDataType) => "UNSUPPORTED_DATA_TYPE",
+        Some(FallbackReason::UnsupportedFeature) => "UNSUPPORTED_FEATURE",
+        Some(FallbackReason::ConversionError) => "CONVERSION_ERROR",
+        None => "UNKNOWN",
+    }
+}
+
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => write!(escaped, "\\u{:04x}", c as u32).unwrap(),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,585 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Defines spark's higher-order functions of arrays: transform(), filter(),
+//! exists() and aggregate().
+//!
+//! lambdas are evaluated in batches instead of per element. the body of a
+//! lambda is bound to a schema of the columns it captures from the input
+//! (including arguments of enclosing lambdas), followed by its arguments.
+//! transform/filter/exists evaluate the body once against all elements of
+//! the batch, with captured columns repeated for each element. aggregate
+//! folds the k-th elements of all arrays at the k-th step.
+
+use std::any::Any;
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+
+use datafusion::arrow::array::{
+    make_array, Array, ArrayData, ArrayRef, BooleanArray, Int32Array, ListArray,
+    UInt32Array,
+};
+use datafusion::arrow::buffer::Buffer;
+use datafusion::arrow::compute::{concat, take};
+use datafusion::arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::physical_plan::expressions::Column;
+use datafusion::physical_plan::{ColumnarValue, PhysicalExpr};
+
+/// binds an expression to a schema, e.g. from_proto's bind()
+pub type ExprBinder<'a> =
+    &'a dyn Fn(Arc<dyn PhysicalExpr>, &Arc<Schema>) -> Result<Arc<dyn PhysicalExpr>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArrayFunction {
+    /// transform(array, (element[, index]) -> value)
+    Transform,
+    /// filter(array, element -> predicate)
+    Filter,
+    /// exists(array, element -> predicate). with three-valued logic, the
+    /// result is null if no predicate is true and some are null.
+    Exists { three_valued: bool },
+    /// aggregate(array, zero, (acc, element) -> acc[, acc -> result])
+    Aggregate,
+}
+
+/// A lambda of a higher-order function
+#[derive(Debug, Clone)]
+pub struct LambdaFunction {
+    body: Arc<dyn PhysicalExpr>,
+    args: Vec<String>,
+    captured_columns: Vec<String>,
+    captured: Vec<Arc<dyn PhysicalExpr>>,
+    schema: SchemaRef,
+}
+
+impl LambdaFunction {
+    pub fn new(
+        body: Arc<dyn PhysicalExpr>,
+        args: Vec<String>,
+        captured_columns: Vec<String>,
+    ) -> Self {
+        Self {
+            body,
+            args,
+            captured_columns,
+            captured: vec![],
+            schema: Arc::new(Schema::empty()),
+        }
+    }
+
+    fn try_bind(
+        &self,
+        input_schema: &Arc<Schema>,
+        arg_types: &[DataType],
+        bind: ExprBinder,
+    ) -> Result<Self> {
+        let captured = self
+            .captured_columns
+            .iter()
+            .map(|name| bind(Arc::new(Column::new(name, 0)), input_schema))
+            .collect::<Result<Vec<_>>>()?;
+        let mut fields = captured
+            .iter()
+            .zip(&self.captured_columns)
+            .map(|(expr, name)| Ok(Field::new(name, expr.data_type(input_schema)?, true)))
+            .collect::<Result<Vec<_>>>()?;
+        fields.extend(
+            self.args
+                .iter()
+                .zip(arg_types)
+                .map(|(name, data_type)| Field::new(name, data_type.clone(), true)),
+        );
+        let schema = Arc::new(Schema::new(fields));
+
+        Ok(Self {
+            body: bind(self.body.clone(), &schema)?,
+            args: self.args.clone(),
+            captured_columns: self.captured_columns.clone(),
+            captured,
+            schema,
+        })
+    }
+
+    fn data_type(&self) -> Result<DataType> {
+        self.body.data_type(&self.schema)
+    }
+
+    /// evaluates the body against the captured columns of the input rows,
+    /// followed by the arguments
+    fn evaluate(
+        &self,
+        batch: &RecordBatch,
+        rows: &UInt32Array,
+        args: Vec<ArrayRef>,
+    ) -> Result<ArrayRef> {
+        let mut columns = self
+            .captured
+            .iter()
+            .map(|expr| {
+                let captured = expr.evaluate(batch)?.into_array(batch.num_rows());
+                Ok(take(&captured, rows, None)?)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        columns.extend(args.into_iter().take(self.args.len()));
+        let lambda_batch = RecordBatch::try_new(self.schema.clone(), columns)?;
+        Ok(self.body.evaluate(&lambda_batch)?.into_array(rows.len()))
+    }
+}
+
+impl Display for LambdaFunction {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "({}) -> {}", self.args.join(", "), self.body)
+    }
+}
+
+/// Evaluates a higher-order function of arrays
+#[derive(Debug)]
+pub struct ArrayFunctionExpr {
+    fun: ArrayFunction,
+    array: Arc<dyn PhysicalExpr>,
+    function: LambdaFunction,
+    zero: Option<Arc<dyn PhysicalExpr>>,
+    finish: Option<LambdaFunction>,
+}
+
+impl ArrayFunctionExpr {
+    pub fn try_new(
+        fun: ArrayFunction,
+        array: Arc<dyn PhysicalExpr>,
+        function: LambdaFunction,
+        zero: Option<Arc<dyn PhysicalExpr>>,
+        finish: Option<LambdaFunction>,
+    ) -> Result<Self> {
+        if (fun == ArrayFunction::Aggregate) != zero.is_some() {
+            return Err(DataFusionError::Plan(format!(
+                "ArrayFunctionExpr: zero is required by and only by aggregate, got {:?}",
+                fun
+            )));
+        }
+        Ok(Self {
+            fun,
+            array,
+            function,
+            zero,
+            finish,
+        })
+    }
+
+    /// binds the inputs to the input schema and the lambdas to their schemas
+    pub fn try_bind(&self, input_schema: &Arc<Schema>, bind: ExprBinder) -> Result<Self> {
+        let array = bind(self.array.clone(), input_schema)?;
+        let element_type = match array.data_type(input_schema)? {
+            DataType::List(field) => field.data_type().clone(),
+            other => {
+                return Err(DataFusionError::Plan(format!(
+                    "ArrayFunctionExpr: unsupported array type {:?}",
+                    other
+                )));
+            }
+        };
+        let zero = self
+            .zero
+            .as_ref()
+            .map(|zero| bind(zero.clone(), input_schema))
+            .transpose()?;
+
+        let (function, finish) = match &zero {
+            Some(zero) => {
+                let acc_type = zero.data_type(input_schema)?;
+                let arg_types = [acc_type.clone(), element_type];
+                let function = self.function.try_bind(input_schema, &arg_types, bind)?;
+                let finish = self
+                    .finish
+                    .as_ref()
+                    .map(|finish| finish.try_bind(input_schema, &[acc_type], bind))
+                    .transpose()?;
+                (function, finish)
+            }
+            None => {
+                let arg_types = [element_type, DataType::Int32];
+                let function = self.function.try_bind(input_schema, &arg_types, bind)?;
+                (function, None)
+            }
+        };
+        Self::try_new(self.fun, array, function, zero, finish)
+    }
+
+    /// returns (parent rows, positions in the values, indices in the arrays)
+    /// of all elements of non-null arrays
+    fn flatten(list: &ListArray) -> (UInt32Array, UInt32Array, Int32Array) {
+        let offsets = list.value_offsets();
+        let mut rows = vec![];
+        let mut positions = vec![];
+        let mut indices = vec![];
+        for row in (0..list.len()).filter(|&row| list.is_valid(row)) {
+            for (index, position) in (offsets[row]..offsets[row + 1]).enumerate() {
+                rows.push(row as u32);
+                positions.push(position as u32);
+                indices.push(index as i32);
+            }
+        }
+        (rows.into(), positions.into(), indices.into())
+    }
+
+    fn evaluate_aggregate(
+        &self,
+        batch: &RecordBatch,
+        list: &ListArray,
+    ) -> Result<ArrayRef> {
+        let num_rows = batch.num_rows();
+        let zero = self.zero.as_ref().expect("aggregate without zero");
+        let offsets = list.value_offsets();
+        let lengths = (0..num_rows)
+            .map(|row| match list.is_valid(row) {
+                true => (offsets[row + 1] - offsets[row]) as usize,
+                false => 0,
+            })
+            .collect::<Vec<_>>();
+
+        let mut acc = zero.evaluate(batch)?.into_array(num_rows);
+        for k in 0..lengths.iter().copied().max().unwrap_or(0) {
+            let active_rows = (0..num_rows)
+                .filter(|&row| lengths[row] > k)
+                .map(|row| row as u32)
+                .collect::<Vec<_>>();
+            let positions = active_rows
+                .iter()
+                .map(|&row| offsets[row as usize] as u32 + k as u32)
+                .collect::<Vec<_>>();
+            let active_rows = UInt32Array::from(active_rows);
+            let positions = UInt32Array::from(positions);
+            let elements = take(list.values().as_ref(), &positions, None)?;
+            let active_acc = take(&acc, &active_rows, None)?;
+            let merged = self.function.evaluate(
+                batch,
+                &active_rows,
+                vec![active_acc, elements],
+            )?;
+
+            // replaces accumulators of active rows with the merged ones
+            let mut next_merged = num_rows as u32;
+            let indices = (0..num_rows)
+                .map(|row| match lengths[row] > k {
+                    true => {
+                        next_merged += 1;
+                        next_merged - 1
+                    }
+                    false => row as u32,
+                })
+                .collect::<Vec<_>>();
+            let acc_and_merged = concat(&[acc.as_ref(), merged.as_ref()])?;
+            acc = take(&acc_and_merged, &UInt32Array::from(indices), None)?;
+        }
+
+        let result = match &self.finish {
+            Some(finish) => {
+                let rows = UInt32Array::from_iter_values(0..num_rows as u32);
+                finish.evaluate(batch, &rows, vec![acc])?
+            }
+            None => acc,
+        };
+        if list.null_count() == 0 {
+            return Ok(result);
+        }
+        let valid_rows = (0..num_rows)
+            .map(|row| list.is_valid(row).then(|| row as u32))
+            .collect::<UInt32Array>();
+        Ok(take(&result, &valid_rows, None)?)
+    }
+}
+
+/// builds a list array of the values with the given lengths of arrays, and
+/// the same null arrays as the input
+fn build_list(
+    list: &ListArray,
+    data_type: DataType,
+    lengths: impl Iterator<Item = usize>,
+    values: ArrayRef,
+) -> Result<ArrayRef> {
+    let mut offsets = vec![0i32];
+    for length in lengths {
+        offsets.push(offsets[offsets.len() - 1] + length as i32);
+    }
+    let mut builder = ArrayData::builder(data_type)
+        .len(list.len())
+        .add_buffer(Buffer::from_slice_ref(&offsets))
+        .add_child_data(values.data().clone());
+    if list.null_count() > 0 {
+        let valid = (0..list.len())
+            .map(|row| Some(list.is_valid(row)))
+            .collect::<BooleanArray>();
+        builder = builder.null_bit_buffer(valid.values().clone());
+    }
+    Ok(make_array(builder.build()?))
+}
+
+impl Display for ArrayFunctionExpr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match (self.fun, &self.zero) {
+            (ArrayFunction::Aggregate, Some(zero)) => {
+                write!(f, "aggregate({}, {}, {}", self.array, zero, self.function)?;
+                if let Some(finish) = &self.finish {
+                    write!(f, ", {}", finish)?;
+                }
+                write!(f, ")")
+            }
+            (fun, _) => {
+                let name = match fun {
+                    ArrayFunction::Transform => "transform",
+                    ArrayFunction::Filter => "filter",
+                    ArrayFunction::Exists { .. } => "exists",
+                    ArrayFunction::Aggregate => "aggregate",
+                };
+                write!(f, "{}({}, {})", name, self.array, self.function)
+            }
+        }
+    }
+}
+
+impl PhysicalExpr for ArrayFunctionExpr {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn data_type(&self, input_schema: &Schema) -> Result<DataType> {
+        Ok(match self.fun {
+            ArrayFunction::Transform => DataType::List(Box::new(Field::new(
+                "element",
+                self.function.data_type()?,
+                true,
+            ))),
+            ArrayFunction::Filter => self.array.data_type(input_schema)?,
+            ArrayFunction::Exists { .. } => DataType::Boolean,
+            ArrayFunction::Aggregate => match (&self.finish, &self.zero) {
+                (Some(finish), _) => finish.data_type()?,
+                (None, Some(zero)) => zero.data_type(input_schema)?,
+                (None, None) => unreachable!("aggregate without zero"),
+            },
+        })
+    }
+
+    fn nullable(&self, _input_schema: &Schema) -> Result<bool> {
+        Ok(true)
+    }
+
+    fn evaluate(&self, batch: &RecordBatch) -> Result<ColumnarValue> {
+        let num_rows = batch.num_rows();
+        let array = self.array.evaluate(batch)?.into_array(num_rows);
+        let list = array.as_any().downcast_ref::<ListArray>().ok_or_else(|| {
+            DataFusionError::Execution(format!(
+                "ArrayFunctionExpr: unsupported array type {:?}",
+                array.data_type()
+            ))
+        })?;
+        if self.fun == ArrayFunction::Aggregate {
+            let result = self.evaluate_aggregate(batch, list)?;
+            return Ok(ColumnarValue::Array(result));
+        }
+
+        let (rows, positions, indices) = Self::flatten(list);
+        let elements = take(list.values().as_ref(), &positions, None)?;
+        let results = self.function.evaluate(
+            batch,
+            &rows,
+            vec![elements.clone(), Arc::new(indices)],
+        )?;
+        let lengths = (0..num_rows).map(|row| match list.is_valid(row) {
+            true => list.value_length(row) as usize,
+            false => 0,
+        });
+
+        let result: ArrayRef = match self.fun {
+            ArrayFunction::Transform => {
+                let data_type = self.data_type(&batch.schema())?;
+                build_list(list, data_type, lengths, results)?
+            }
+            ArrayFunction::Filter => {
+                let predicates = downcast_predicates(&results)?;
+                let mut kept_lengths = vec![0usize; num_rows];
+                let mut kept = vec![];
+                for i in 0..predicates.len() {
+                    if predicates.is_valid(i) && predicates.value(i) {
+                        kept_lengths[rows.value(i) as usize] += 1;
+                        kept.push(i as u32);
+                    }
+                }
+                let kept_elements = take(&elements, &UInt32Array::from(kept), None)?;
+                let data_type = list.data_type().clone();
+                build_list(list, data_type, kept_lengths.into_iter(), kept_elements)?
+            }
+            ArrayFunction::Exists { three_valued } => {
+                let predicates = downcast_predicates(&results)?;
+                let mut found = vec![false; num_rows];
+                let mut has_null = vec![false; num_rows];
+                for i in 0..predicates.len() {
+                    let row = rows.value(i) as usize;
+                    match predicates.is_valid(i) {
+                        true => found[row] |= predicates.value(i),
+                        false => has_null[row] = true,
+                    }
+                }
+                let exists = (0..num_rows)
+                    .map(|row| {
+                        let unknown = three_valued && !found[row] && has_null[row];
+                        (list.is_valid(row) && !unknown).then(|| found[row])
+                    })
+                    .collect::<BooleanArray>();
+                Arc::new(exists)
+            }
+            ArrayFunction::Aggregate => unreachable!(),
+        };
+        Ok(ColumnarValue::Array(result))
+    }
+}
+
+fn downcast_predicates(results: &ArrayRef) -> Result<&BooleanArray> {
+    results
+        .as_any()
+        .downcast_ref::<BooleanArray>()
+        .ok_or_else(|| {
+            DataFusionError::Execution(format!(
+                "ArrayFunctionExpr: predicates must be boolean, got {:?}",
+                results.data_type()
+            ))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use datafusion::arrow::datatypes::Int32Type;
+    use datafusion::logical_plan::Operator;
+    use datafusion::physical_plan::expressions::{lit, BinaryExpr};
+    use datafusion::scalar::ScalarValue;
+
+    use super::*;
+
+    // binds top-level columns only, enough for the lambdas below
+    fn bind(
+        expr: Arc<dyn PhysicalExpr>,
+        schema: &Arc<Schema>,
+    ) -> Result<Arc<dyn PhysicalExpr>> {
+        match expr.as_any().downcast_ref::<Column>() {
+            Some(column) => Ok(Arc::new(Column::new_with_schema(column.name(), schema)?)),
+            None => Ok(expr),
+        }
+    }
+
+    #[test]
+    fn test_array_functions() -> Result<()> {
+        let a = ListArray::from_iter_primitive::<Int32Type, _, _>(vec![
+            Some(vec![Some(1), Some(2), Some(3)]),
+            None,
+            Some(vec![]),
+            Some(vec![Some(4), None, Some(5)]),
+        ]);
+        let t = Int32Array::from(vec![2, 2, 2, 6]);
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("a", a.data_type().clone(), true),
+            Field::new("t", DataType::Int32, false),
+        ]));
+        let batch = RecordBatch::try_new(schema.clone(), vec![Arc::new(a), Arc::new(t)])?;
+        let a = Arc::new(Column::new("a", 0));
+        let evaluate = |fun, function, zero| -> Result<ArrayRef> {
+            let expr = ArrayFunctionExpr::try_new(fun, a.clone(), function, zero, None)?
+                .try_bind(&schema, &bind)?;
+            Ok(expr.evaluate(&batch)?.into_array(batch.num_rows()))
+        };
+
+        // x -> x + 1
+        let plus_one = LambdaFunction::new(
+            Arc::new(BinaryExpr::new(
+                Arc::new(Column::new("x", 0)),
+                Operator::Plus,
+                lit(ScalarValue::Int32(Some(1))),
+            )),
+            vec!["x".to_owned()],
+            vec![],
+        );
+        let transformed = evaluate(ArrayFunction::Transform, plus_one, None)?;
+        let transformed = transformed.as_any().downcast_ref::<ListArray>().unwrap();
+        assert!(transformed.is_null(1));
+        assert_eq!(transformed.value_length(2), 0);
+        assert_eq!(
+            transformed
+                .value(3)
+                .as_any()
+                .downcast_ref::<Int32Array>()
+                .unwrap(),
+            &Int32Array::from(vec![Some(5), None, Some(6)])
+        );
+
+        // x -> x > t, capturing t
+        let greater_than_t = LambdaFunction::new(
+            Arc::new(BinaryExpr::new(
+                Arc::new(Column::new("x", 1)),
+                Operator::Gt,
+                Arc::new(Column::new("t", 0)),
+            )),
+            vec!["x".to_owned()],
+            vec!["t".to_owned()],
+        );
+        let filtered = evaluate(ArrayFunction::Filter, greater_than_t.clone(), None)?;
+        let filtered = filtered.as_any().downcast_ref::<ListArray>().unwrap();
+        assert_eq!(filtered.data_type(), schema.field(0).data_type());
+        assert!(filtered.is_null(1));
+        assert_eq!(
+            filtered
+                .value(0)
+                .as_any()
+                .downcast_ref::<Int32Array>()
+                .unwrap(),
+            &Int32Array::from(vec![3])
+        );
+        assert_eq!(filtered.value_length(3), 0);
+
+        let exists = |three_valued| {
+            let fun = ArrayFunction::Exists { three_valued };
+            evaluate(fun, greater_than_t.clone(), None)
+        };
+        assert_eq!(
+            exists(true)?
+                .as_any()
+                .downcast_ref::<BooleanArray>()
+                .unwrap(),
+            &BooleanArray::from(vec![Some(true), None, Some(false), None])
+        );
+        assert_eq!(
+            exists(false)?
+                .as_any()
+                .downcast_ref::<BooleanArray>()
+                .unwrap(),
+            &BooleanArray::from(vec![Some(true), None, Some(false), Some(false)])
+        );
+
+        // (acc, x) -> acc + x
+        let sum = LambdaFunction::new(
+            Arc::new(BinaryExpr::new(
+                Arc::new(Column::new("acc", 0)),
+                Operator::Plus,
+                Arc::new(Column::new("x", 1)),
+            )),
+            vec!["acc".to_owned(), "x".to_owned()],
+            vec![],
+        );
+        let zero = Some(lit(ScalarValue::Int32(Some(0))));
+        let aggregated = evaluate(ArrayFunction::Aggregate, sum, zero)?;
+        assert_eq!(
+            aggregated.as_any().downcast_ref::<Int32Array>().unwrap(),
+            &Int32Array::from(vec![Some(6), None, Some(0), None])
+        );
+        Ok(())
+    }
+}
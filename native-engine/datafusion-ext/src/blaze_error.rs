@@ -0,0 +1,161 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Typed errors of native execution.
+//!
+//! Errors failing a native task are classified into a [`BlazeError`] and
+//! converted to a specific java exception class by
+//! JniBridge.newNativeException(), so that the JVM side can tell retryable
+//! failures from fatal ones. Within datafusion operators, a `BlazeError` is
+//! carried as `DataFusionError::External` and recovered when converting back.
+
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+
+use datafusion::arrow::error::ArrowError;
+use datafusion::error::DataFusionError;
+
+#[derive(Debug)]
+pub enum BlazeError {
+    /// errors caused by the plan or the data, which fail again if retried
+    UserError(String),
+    /// errors of arrow computations
+    ArrowError(ArrowError),
+    /// errors calling into the JVM, the java exception thrown by the JVM side
+    /// is left pending and propagated in place of this error
+    JniError(String),
+    /// native memory exhausted, retrying with less memory may succeed
+    OOM(String),
+    /// execution cancelled by the JVM side, e.g. the task is killed
+    Cancelled,
+}
+
+impl BlazeError {
+    /// name of the error kind passed to JniBridge.newNativeException()
+    pub fn kind(&self) -> &'static str {
+        match self {
+            BlazeError::UserError(_) => "user",
+            BlazeError::ArrowError(_) => "arrow",
+            BlazeError::JniError(_) => "jni",
+            BlazeError::OOM(_) => "oom",
+            BlazeError::Cancelled => "cancelled",
+        }
+    }
+
+    /// returns true if retrying the failed task may succeed
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, BlazeError::JniError(_) | BlazeError::OOM(_))
+    }
+}
+
+impl Display for BlazeError {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            BlazeError::UserError(desc) => write!(f, "{}", desc),
+            BlazeError::ArrowError(err) => write!(f, "Arrow error: {}", err),
+            BlazeError::JniError(desc) => write!(f, "JNI error: {}", desc),
+            BlazeError::OOM(desc) => write!(f, "Native memory exhausted: {}", desc),
+            BlazeError::Cancelled => write!(f, "Native execution cancelled"),
+        }
+    }
+}
+
+impl Error for BlazeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            BlazeError::ArrowError(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<ArrowError> for BlazeError {
+    fn from(err: ArrowError) -> Self {
+        match err {
+            ArrowError::ExternalError(err) => {
+                BlazeError::from(DataFusionError::External(err))
+            }
+            err => BlazeError::ArrowError(err),
+        }
+    }
+}
+
+impl From<DataFusionError> for BlazeError {
+    fn from(err: DataFusionError) -> Self {
+        match err {
+            DataFusionError::ArrowError(err) => BlazeError::from(err),
+            DataFusionError::ResourcesExhausted(desc) => BlazeError::OOM(desc),
+            DataFusionError::External(err) => match err.downcast::<BlazeError>() {
+                Ok(err) => *err,
+                Err(err) => match err.downcast::<DataFusionError>() {
+                    Ok(err) => BlazeError::from(*err),
+                    Err(err) => BlazeError::UserError(err.to_string()),
+                },
+            },
+            err => BlazeError::UserError(err.to_string()),
+        }
+    }
+}
+
+impl From<BlazeError> for DataFusionError {
+    fn from(err: BlazeError) -> Self {
+        match err {
+            BlazeError::ArrowError(err) => DataFusionError::ArrowError(err),
+            BlazeError::OOM(desc) => DataFusionError::ResourcesExhausted(desc),
+            err => DataFusionError::External(Box::new(err)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use datafusion::arrow::error::ArrowError;
+    use datafusion::error::DataFusionError;
+
+    use crate::blaze_error::BlazeError;
+
+    #[test]
+    fn test_blaze_error_conversion() {
+        let kind = |err: DataFusionError| BlazeError::from(err).kind();
+        assert_eq!(kind(DataFusionError::Plan("x".to_owned())), "user");
+        assert_eq!(
+            kind(DataFusionError::ResourcesExhausted("x".to_owned())),
+            "oom"
+        );
+        assert_eq!(
+            kind(DataFusionError::ArrowError(ArrowError::ComputeError(
+                "x".to_owned()
+            ))),
+            "arrow"
+        );
+        assert_eq!(
+            kind(DataFusionError::ArrowError(ArrowError::ExternalError(
+                Box::new(DataFusionError::ResourcesExhausted("x".to_owned()))
+            ))),
+            "oom"
+        );
+
+        // typed errors passing through datafusion operators are recovered
+        for err in [
+            BlazeError::JniError("x".to_owned()),
+            BlazeError::OOM("x".to_owned()),
+            BlazeError::Cancelled,
+        ] {
+            let expected = err.kind();
+            assert_eq!(kind(DataFusionError::from(err)), expected);
+        }
+        assert!(BlazeError::OOM("x".to_owned()).is_retryable());
+        assert!(!BlazeError::UserError("x".to_owned()).is_retryable());
+    }
+}
@@ -0,0 +1,325 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A global allocator pooling freed arrow buffers, so that buffers of
+//! subsequent batches and operators are served from the pool. executors
+//! running many short native tasks otherwise keep returning large buffers to
+//! the underlying allocator, which churns and fragments the heap.
+//!
+//! arrow buffers are recognized by their alignment, which other allocations
+//! barely use. their sizes are rounded up to size classes (four classes per
+//! power of two) regardless of whether the pool is enabled, so that a buffer
+//! can always be returned to the class it was taken from.
+
+use std::alloc::{GlobalAlloc, Layout};
+use std::cell::UnsafeCell;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+use datafusion::arrow::alloc::ALIGNMENT;
+
+const MIN_POOLED_SHIFT: u32 = 12; // 4KB
+const MAX_POOLED_SHIFT: u32 = 23; // 8MB
+const MIN_POOLED_SIZE: usize = 1 << MIN_POOLED_SHIFT;
+const MAX_POOLED_SIZE: usize = 1 << MAX_POOLED_SHIFT;
+const CLASSES_PER_SHIFT: usize = 4;
+const NUM_CLASSES: usize =
+    (MAX_POOLED_SHIFT - MIN_POOLED_SHIFT) as usize * CLASSES_PER_SHIFT + 1;
+
+/// Wraps an allocator, pooling freed arrow buffers of 4KB to 8MB up to the
+/// capacity set with set_capacity(). the pool is disabled with zero capacity,
+/// which is the initial state.
+pub struct BufferPoolAllocator<A> {
+    inner: A,
+    classes: [SizeClass; NUM_CLASSES],
+    capacity: AtomicUsize,
+    pooled_bytes: AtomicUsize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+unsafe impl<A: Sync> Sync for BufferPoolAllocator<A> {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BufferPoolStats {
+    pub capacity: usize,
+    pub pooled_bytes: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl<A: GlobalAlloc> BufferPoolAllocator<A> {
+    pub const fn new(inner: A) -> Self {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const EMPTY_CLASS: SizeClass = SizeClass::new();
+        Self {
+            inner,
+            classes: [EMPTY_CLASS; NUM_CLASSES],
+            capacity: AtomicUsize::new(0),
+            pooled_bytes: AtomicUsize::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// sets max total bytes of pooled buffers, buffers beyond the new capacity
+    /// are released to the underlying allocator. 0 to disable pooling.
+    pub fn set_capacity(&self, capacity: usize) {
+        self.capacity.store(capacity, Ordering::SeqCst);
+
+        // release from the largest classes first
+        for (class, entry) in self.classes.iter().enumerate().rev() {
+            let class_size = class_size(class);
+            while self.pooled_bytes.load(Ordering::SeqCst) > capacity {
+                let ptr = entry.pop();
+                if ptr.is_null() {
+                    break;
+                }
+                self.pooled_bytes.fetch_sub(class_size, Ordering::SeqCst);
+                unsafe {
+                    self.inner.dealloc(ptr, pooled_layout(class_size));
+                }
+            }
+        }
+    }
+
+    pub fn stats(&self) -> BufferPoolStats {
+        BufferPoolStats {
+            capacity: self.capacity.load(Ordering::Relaxed),
+            pooled_bytes: self.pooled_bytes.load(Ordering::Relaxed),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    unsafe fn alloc_pooled(&self, class: usize, zeroed: bool) -> *mut u8 {
+        let class_size = class_size(class);
+        let ptr = self.classes[class].pop();
+        if !ptr.is_null() {
+            self.pooled_bytes.fetch_sub(class_size, Ordering::SeqCst);
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            if zeroed {
+                std::ptr::write_bytes(ptr, 0, class_size);
+            }
+            return ptr;
+        }
+        if self.capacity.load(Ordering::Relaxed) > 0 {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+        }
+        if zeroed {
+            self.inner.alloc_zeroed(pooled_layout(class_size))
+        } else {
+            self.inner.alloc(pooled_layout(class_size))
+        }
+    }
+
+    unsafe fn dealloc_pooled(&self, ptr: *mut u8, class: usize) {
+        let class_size = class_size(class);
+        let capacity = self.capacity.load(Ordering::Relaxed);
+        let reserved = self
+            .pooled_bytes
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |pooled| {
+                (pooled + class_size <= capacity).then(|| pooled + class_size)
+            })
+            .is_ok();
+
+        if reserved {
+            self.classes[class].push(ptr);
+        } else {
+            self.inner.dealloc(ptr, pooled_layout(class_size));
+        }
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for BufferPoolAllocator<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        match size_class_of(layout) {
+            Some(class) => self.alloc_pooled(class, false),
+            None => self.inner.alloc(layout),
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        match size_class_of(layout) {
+            Some(class) => self.dealloc_pooled(ptr, class),
+            None => self.inner.dealloc(ptr, layout),
+        }
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        match size_class_of(layout) {
+            Some(class) => self.alloc_pooled(class, true),
+            None => self.inner.alloc_zeroed(layout),
+        }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        match (size_class_of(layout), size_class_of(new_layout)) {
+            (None, None) => self.inner.realloc(ptr, layout, new_size),
+            (Some(old_class), Some(new_class)) if old_class == new_class => ptr,
+            _ => {
+                let new_ptr = self.alloc(new_layout);
+                if !new_ptr.is_null() {
+                    let copy_size = layout.size().min(new_size);
+                    std::ptr::copy_nonoverlapping(ptr, new_ptr, copy_size);
+                    self.dealloc(ptr, layout);
+                }
+                new_ptr
+            }
+        }
+    }
+}
+
+/// A spin-locked intrusive free list, the next pointer is stored in the first
+/// bytes of each free buffer. the allocator must not allocate by itself, so
+/// std locks and collections are not used.
+struct SizeClass {
+    locked: AtomicBool,
+    head: UnsafeCell<*mut u8>,
+}
+
+impl SizeClass {
+    const fn new() -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            head: UnsafeCell::new(std::ptr::null_mut()),
+        }
+    }
+
+    fn with_lock<T>(&self, f: impl FnOnce(&mut *mut u8) -> T) -> T {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            std::hint::spin_loop();
+        }
+        let result = f(unsafe { &mut *self.head.get() });
+        self.locked.store(false, Ordering::Release);
+        result
+    }
+
+    fn push(&self, ptr: *mut u8) {
+        self.with_lock(|head| unsafe {
+            (ptr as *mut *mut u8).write(*head);
+            *head = ptr;
+        })
+    }
+
+    fn pop(&self) -> *mut u8 {
+        self.with_lock(|head| unsafe {
+            let ptr = *head;
+            if !ptr.is_null() {
+                *head = (ptr as *mut *mut u8).read();
+            }
+            ptr
+        })
+    }
+}
+
+/// returns the size class of arrow buffers of 4KB to 8MB
+fn size_class_of(layout: Layout) -> Option<usize> {
+    let size = layout.size();
+    if layout.align() != ALIGNMENT || !(MIN_POOLED_SIZE..=MAX_POOLED_SIZE).contains(&size)
+    {
+        return None;
+    }
+    let shift = usize::BITS - 1 - size.leading_zeros();
+    let base = 1usize << shift;
+    let step = base / CLASSES_PER_SHIFT;
+    let steps = (size - base + step - 1) / step;
+    Some((shift - MIN_POOLED_SHIFT) as usize * CLASSES_PER_SHIFT + steps)
+}
+
+fn class_size(class: usize) -> usize {
+    let shift = MIN_POOLED_SHIFT as usize + class / CLASSES_PER_SHIFT;
+    let base = 1usize << shift;
+    base + base / CLASSES_PER_SHIFT * (class % CLASSES_PER_SHIFT)
+}
+
+fn pooled_layout(class_size: usize) -> Layout {
+    unsafe { Layout::from_size_align_unchecked(class_size, ALIGNMENT) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::alloc::System;
+
+    fn arrow_layout(size: usize) -> Layout {
+        Layout::from_size_align(size, ALIGNMENT).unwrap()
+    }
+
+    #[test]
+    fn test_size_classes() {
+        assert_eq!(size_class_of(arrow_layout(4095)), None);
+        assert_eq!(size_class_of(arrow_layout(MAX_POOLED_SIZE + 1)), None);
+        assert_eq!(
+            size_class_of(Layout::from_size_align(8192, 8).unwrap()),
+            None
+        );
+
+        assert_eq!(size_class_of(arrow_layout(4096)), Some(0));
+        assert_eq!(size_class_of(arrow_layout(4097)), Some(1));
+        assert_eq!(size_class_of(arrow_layout(5120)), Some(1));
+        assert_eq!(size_class_of(arrow_layout(8000)), Some(4));
+        assert_eq!(size_class_of(arrow_layout(8192)), Some(4));
+        assert_eq!(
+            size_class_of(arrow_layout(MAX_POOLED_SIZE)),
+            Some(NUM_CLASSES - 1)
+        );
+
+        for class in 0..NUM_CLASSES {
+            let size = class_size(class);
+            assert_eq!(size_class_of(arrow_layout(size)), Some(class));
+            assert_eq!(size_class_of(arrow_layout(size - 1)).unwrap_or(0), class);
+        }
+    }
+
+    #[test]
+    fn test_buffer_pool_allocator() {
+        let pool = BufferPoolAllocator::new(System);
+        let layout = arrow_layout(10000);
+        unsafe {
+            // disabled: buffers are not pooled
+            let ptr = pool.alloc(layout);
+            pool.dealloc(ptr, layout);
+            assert_eq!(pool.stats().pooled_bytes, 0);
+
+            // enabled: freed buffers are reused
+            pool.set_capacity(1 << 20);
+            let ptr = pool.alloc(layout);
+            pool.dealloc(ptr, layout);
+            assert_eq!(pool.stats().pooled_bytes, 10240);
+            let reused = pool.alloc_zeroed(arrow_layout(9000));
+            assert_eq!(reused, ptr);
+            assert!(std::slice::from_raw_parts(reused, 9000)
+                .iter()
+                .all(|&b| b == 0));
+            assert_eq!(pool.stats().hits, 1);
+
+            // grown in place within its class
+            let grown = pool.realloc(reused, arrow_layout(9000), 10240);
+            assert_eq!(grown, reused);
+            pool.dealloc(grown, arrow_layout(10240));
+
+            // buffers beyond capacity are released
+            let ptrs = (0..200).map(|_| pool.alloc(layout)).collect::<Vec<_>>();
+            ptrs.into_iter().for_each(|ptr| pool.dealloc(ptr, layout));
+            assert_eq!(pool.stats().pooled_bytes, 102 * 10240);
+            pool.set_capacity(0);
+            assert_eq!(pool.stats().pooled_bytes, 0);
+        }
+    }
+}
@@ -0,0 +1,198 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Defines spark's CHAR(n)/VARCHAR(n) semantics of string columns, see
+//! CharVarcharCodegenUtils of spark 3.1+:
+//!
+//! - values of CHAR(n) columns are right-padded with spaces to n characters
+//!   when read, longer values are returned as is
+//! - values written to CHAR(n) columns are right-padded to n characters
+//! - values written to VARCHAR(n) columns are kept as is
+//!
+//! written values longer than n characters have their trailing spaces beyond
+//! n characters trimmed, and fail the query if still longer than n.
+
+use std::any::Any;
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+
+use datafusion::arrow::array::{Array, StringArray};
+use datafusion::arrow::datatypes::{DataType, Schema};
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::physical_plan::{ColumnarValue, PhysicalExpr};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CharVarcharMode {
+    ReadSidePadding,
+    CharWriteSideCheck,
+    VarcharWriteSideCheck,
+}
+
+/// Pads or checks string values of a CHAR(n)/VARCHAR(n) column with length n
+#[derive(Debug)]
+pub struct CharVarcharExpr {
+    expr: Arc<dyn PhysicalExpr>,
+    mode: CharVarcharMode,
+    length: usize,
+}
+
+impl CharVarcharExpr {
+    pub fn new(
+        expr: Arc<dyn PhysicalExpr>,
+        mode: CharVarcharMode,
+        length: usize,
+    ) -> Self {
+        Self { expr, mode, length }
+    }
+
+    pub fn expr(&self) -> &Arc<dyn PhysicalExpr> {
+        &self.expr
+    }
+
+    pub fn mode(&self) -> CharVarcharMode {
+        self.mode
+    }
+
+    pub fn length(&self) -> usize {
+        self.length
+    }
+
+    fn eval_value(&self, value: &str) -> Result<String> {
+        let num_chars = value.chars().count();
+        let limit = self.length;
+        if num_chars < limit && self.mode != CharVarcharMode::VarcharWriteSideCheck {
+            let mut padded = String::with_capacity(value.len() + limit - num_chars);
+            padded.push_str(value);
+            padded.extend(std::iter::repeat(' ').take(limit - num_chars));
+            return Ok(padded);
+        }
+        if num_chars <= limit || self.mode == CharVarcharMode::ReadSidePadding {
+            return Ok(value.to_owned());
+        }
+
+        // only trailing spaces beyond the limit can be trimmed
+        let num_trailing_spaces = value.chars().rev().take_while(|&c| c == ' ').count();
+        if num_trailing_spaces < num_chars - limit {
+            return Err(DataFusionError::Execution(format!(
+                "Exceeds char/varchar type length limitation: {}",
+                limit
+            )));
+        }
+        Ok(value.chars().take(limit).collect())
+    }
+}
+
+impl Display for CharVarcharExpr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}({}, {})", self.mode, self.expr, self.length)
+    }
+}
+
+impl PhysicalExpr for CharVarcharExpr {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn data_type(&self, _input_schema: &Schema) -> Result<DataType> {
+        Ok(DataType::Utf8)
+    }
+
+    fn nullable(&self, input_schema: &Schema) -> Result<bool> {
+        self.expr.nullable(input_schema)
+    }
+
+    fn evaluate(&self, batch: &RecordBatch) -> Result<ColumnarValue> {
+        let input = self.expr.evaluate(batch)?.into_array(batch.num_rows());
+        let input = input
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .ok_or_else(|| {
+                DataFusionError::Execution(format!(
+                    "CharVarcharExpr: unsupported input type {:?}",
+                    input.data_type()
+                ))
+            })?;
+        let result = input
+            .iter()
+            .map(|value| value.map(|value| self.eval_value(value)).transpose())
+            .collect::<Result<StringArray>>()?;
+        Ok(ColumnarValue::Array(Arc::new(result)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use datafusion::arrow::datatypes::Field;
+    use datafusion::physical_plan::expressions::col;
+
+    use super::*;
+
+    #[test]
+    fn test_char_varchar() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![Field::new("s", DataType::Utf8, true)]));
+        let evaluate = |mode, values: Vec<Option<&str>>| -> Result<StringArray> {
+            let batch = RecordBatch::try_new(
+                schema.clone(),
+                vec![Arc::new(StringArray::from(values))],
+            )?;
+            let expr = CharVarcharExpr::new(col("s", &schema)?, mode, 3);
+            let result = expr.evaluate(&batch)?.into_array(batch.num_rows());
+            Ok(result
+                .as_any()
+                .downcast_ref::<StringArray>()
+                .unwrap()
+                .clone())
+        };
+
+        let values = vec![Some("a"), Some("abc"), Some("ab    "), Some("中文"), None];
+        assert_eq!(
+            evaluate(CharVarcharMode::ReadSidePadding, values.clone())?,
+            StringArray::from(vec![
+                Some("a  "),
+                Some("abc"),
+                Some("ab    "),
+                Some("中文 "),
+                None
+            ])
+        );
+        assert_eq!(
+            evaluate(CharVarcharMode::CharWriteSideCheck, values.clone())?,
+            StringArray::from(vec![
+                Some("a  "),
+                Some("abc"),
+                Some("ab "),
+                Some("中文 "),
+                None
+            ])
+        );
+        assert_eq!(
+            evaluate(CharVarcharMode::VarcharWriteSideCheck, values)?,
+            StringArray::from(vec![
+                Some("a"),
+                Some("abc"),
+                Some("ab "),
+                Some("中文"),
+                None
+            ])
+        );
+
+        // values exceeding the limit are rejected on the write side
+        let exceeded = vec![Some("abcd")];
+        assert!(evaluate(CharVarcharMode::CharWriteSideCheck, exceeded).is_err());
+        let exceeded = vec![Some("abc d")];
+        assert!(evaluate(CharVarcharMode::VarcharWriteSideCheck, exceeded).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,188 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Subexpressions shared by multiple expressions of an operator.
+//!
+//! spark's codegen evaluates common subexpressions once, while native
+//! operators evaluate each expression separately. plan conversion replaces
+//! repeated deterministic subexpressions with the same `CommonSubExpr`, which
+//! evaluates once per batch and returns the cached value for the same batch.
+
+use std::any::Any;
+use std::fmt::{Debug, Display, Formatter};
+use std::sync::{Arc, Mutex, Weak};
+
+use datafusion::arrow::array::Array;
+use datafusion::arrow::datatypes::{DataType, Schema};
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::error::Result;
+use datafusion::physical_plan::{ColumnarValue, PhysicalExpr};
+
+/// A subexpression evaluated once per batch by all expressions sharing it
+pub struct CommonSubExpr {
+    expr: Arc<dyn PhysicalExpr>,
+    cached: Mutex<Option<CachedValue>>,
+}
+
+/// value of the last evaluated batch. columns are referenced weakly so that
+/// the batch is not kept alive, a weak reference also keeps the address of
+/// the column from being reused by another array.
+struct CachedValue {
+    columns: Vec<Weak<dyn Array>>,
+    num_rows: usize,
+    value: ColumnarValue,
+}
+
+impl CachedValue {
+    fn is_evaluated_from(&self, batch: &RecordBatch) -> bool {
+        self.num_rows == batch.num_rows()
+            && self.columns.len() == batch.num_columns()
+            && self
+                .columns
+                .iter()
+                .zip(batch.columns())
+                .all(|(cached, column)| Weak::ptr_eq(cached, &Arc::downgrade(column)))
+    }
+}
+
+impl CommonSubExpr {
+    pub fn new(expr: Arc<dyn PhysicalExpr>) -> Self {
+        Self {
+            expr,
+            cached: Mutex::new(None),
+        }
+    }
+
+    pub fn expr(&self) -> &Arc<dyn PhysicalExpr> {
+        &self.expr
+    }
+}
+
+/// formatted as the shared expression, so that expressions sharing it are
+/// formatted the same as before sharing
+impl Debug for CommonSubExpr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Debug::fmt(&self.expr, f)
+    }
+}
+
+impl Display for CommonSubExpr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        Display::fmt(&self.expr, f)
+    }
+}
+
+impl PhysicalExpr for CommonSubExpr {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn data_type(&self, input_schema: &Schema) -> Result<DataType> {
+        self.expr.data_type(input_schema)
+    }
+
+    fn nullable(&self, input_schema: &Schema) -> Result<bool> {
+        self.expr.nullable(input_schema)
+    }
+
+    fn evaluate(&self, batch: &RecordBatch) -> Result<ColumnarValue> {
+        if let Some(cached) = self.cached.lock().unwrap().as_ref() {
+            if cached.is_evaluated_from(batch) {
+                return Ok(cached.value.clone());
+            }
+        }
+
+        // evaluated without holding the lock, concurrent evaluations of other
+        // partitions may replace the cached value
+        let value = self.expr.evaluate(batch)?;
+        *self.cached.lock().unwrap() = Some(CachedValue {
+            columns: batch.columns().iter().map(Arc::downgrade).collect(),
+            num_rows: batch.num_rows(),
+            value: value.clone(),
+        });
+        Ok(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use datafusion::arrow::array::Int32Array;
+    use datafusion::arrow::datatypes::Field;
+    use datafusion::physical_plan::expressions::col;
+
+    use super::*;
+    use crate::test_util::build_batch;
+
+    /// counts evaluations of the column
+    #[derive(Debug)]
+    struct CountedExpr {
+        expr: Arc<dyn PhysicalExpr>,
+        evaluations: AtomicUsize,
+    }
+
+    impl Display for CountedExpr {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            write!(f, "counted({})", self.expr)
+        }
+    }
+
+    impl PhysicalExpr for CountedExpr {
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+
+        fn data_type(&self, input_schema: &Schema) -> Result<DataType> {
+            self.expr.data_type(input_schema)
+        }
+
+        fn nullable(&self, input_schema: &Schema) -> Result<bool> {
+            self.expr.nullable(input_schema)
+        }
+
+        fn evaluate(&self, batch: &RecordBatch) -> Result<ColumnarValue> {
+            self.evaluations.fetch_add(1, Ordering::SeqCst);
+            self.expr.evaluate(batch)
+        }
+    }
+
+    #[test]
+    fn test_common_subexpr() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int32, false)]));
+        let counted = Arc::new(CountedExpr {
+            expr: col("a", &schema)?,
+            evaluations: AtomicUsize::new(0),
+        });
+        let expr = CommonSubExpr::new(counted.clone());
+        assert_eq!(expr.to_string(), "counted(a@0)");
+
+        let evaluate = |batch: &RecordBatch| -> Result<Vec<i32>> {
+            let array = expr.evaluate(batch)?.into_array(batch.num_rows());
+            let array = array.as_any().downcast_ref::<Int32Array>().unwrap();
+            Ok(array.values().to_vec())
+        };
+        let batch1 = build_batch!(schema, vec![1, 2, 3])?;
+        assert_eq!(evaluate(&batch1)?, vec![1, 2, 3]);
+        assert_eq!(evaluate(&batch1)?, vec![1, 2, 3]);
+        assert_eq!(counted.evaluations.load(Ordering::SeqCst), 1);
+
+        // batches of the same values are still different batches
+        let batch2 = build_batch!(schema, vec![1, 2, 3])?;
+        assert_eq!(evaluate(&batch2)?, vec![1, 2, 3]);
+        assert_eq!(evaluate(&build_batch!(schema, vec![4])?)?, vec![4]);
+        assert_eq!(counted.evaluations.load(Ordering::SeqCst), 3);
+        Ok(())
+    }
+}
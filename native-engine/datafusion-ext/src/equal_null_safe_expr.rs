@@ -0,0 +1,134 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Defines spark's null-safe equality `l <=> r`, which is true if both sides
+//! are null, false if only one side is null, and `l = r` otherwise. the
+//! result is never null.
+
+use std::any::Any;
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+
+use datafusion::arrow::array::{Array, BooleanArray};
+use datafusion::arrow::datatypes::{DataType, Field, Schema};
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::logical_plan::Operator;
+use datafusion::physical_plan::expressions::{BinaryExpr, Column};
+use datafusion::physical_plan::{ColumnarValue, PhysicalExpr};
+
+/// Spark's EqualNullSafe, comparing non-null values with datafusion's `=`
+#[derive(Debug)]
+pub struct EqualNullSafeExpr {
+    l: Arc<dyn PhysicalExpr>,
+    r: Arc<dyn PhysicalExpr>,
+    eq: BinaryExpr,
+}
+
+impl EqualNullSafeExpr {
+    pub fn new(l: Arc<dyn PhysicalExpr>, r: Arc<dyn PhysicalExpr>) -> Self {
+        // evaluated against a batch of the evaluated sides
+        let eq = BinaryExpr::new(
+            Arc::new(Column::new("l", 0)),
+            Operator::Eq,
+            Arc::new(Column::new("r", 1)),
+        );
+        Self { l, r, eq }
+    }
+
+    pub fn l(&self) -> &Arc<dyn PhysicalExpr> {
+        &self.l
+    }
+
+    pub fn r(&self) -> &Arc<dyn PhysicalExpr> {
+        &self.r
+    }
+}
+
+impl Display for EqualNullSafeExpr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} <=> {}", self.l, self.r)
+    }
+}
+
+impl PhysicalExpr for EqualNullSafeExpr {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn data_type(&self, _input_schema: &Schema) -> Result<DataType> {
+        Ok(DataType::Boolean)
+    }
+
+    fn nullable(&self, _input_schema: &Schema) -> Result<bool> {
+        Ok(false)
+    }
+
+    fn evaluate(&self, batch: &RecordBatch) -> Result<ColumnarValue> {
+        let num_rows = batch.num_rows();
+        let l = self.l.evaluate(batch)?.into_array(num_rows);
+        let r = self.r.evaluate(batch)?.into_array(num_rows);
+
+        let eq_schema = Arc::new(Schema::new(vec![
+            Field::new("l", l.data_type().clone(), true),
+            Field::new("r", r.data_type().clone(), true),
+        ]));
+        let eq_batch = RecordBatch::try_new(eq_schema, vec![l.clone(), r.clone()])?;
+        let eq = self.eq.evaluate(&eq_batch)?.into_array(num_rows);
+        let eq = eq.as_any().downcast_ref::<BooleanArray>().ok_or_else(|| {
+            DataFusionError::Execution(format!(
+                "EqualNullSafeExpr: unexpected result type {:?} of =",
+                eq.data_type()
+            ))
+        })?;
+
+        let result = (0..num_rows)
+            .map(|i| match (l.is_null(i), r.is_null(i)) {
+                (true, true) => Some(true),
+                (false, false) => Some(eq.is_valid(i) && eq.value(i)),
+                _ => Some(false),
+            })
+            .collect::<BooleanArray>();
+        Ok(ColumnarValue::Array(Arc::new(result)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use datafusion::arrow::array::Int32Array;
+    use datafusion::physical_plan::expressions::col;
+
+    use super::*;
+
+    #[test]
+    fn test_equal_null_safe() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("a", DataType::Int32, true),
+            Field::new("b", DataType::Int32, true),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![
+                Arc::new(Int32Array::from(vec![Some(1), Some(1), None, None])),
+                Arc::new(Int32Array::from(vec![Some(1), Some(2), Some(1), None])),
+            ],
+        )?;
+        let expr = EqualNullSafeExpr::new(col("a", &schema)?, col("b", &schema)?);
+        let result = expr.evaluate(&batch)?.into_array(batch.num_rows());
+        let result = result.as_any().downcast_ref::<BooleanArray>().unwrap();
+        assert_eq!(result, &BooleanArray::from(vec![true, false, false, true]));
+        assert_eq!(expr.to_string(), "a@0 <=> b@1");
+        Ok(())
+    }
+}
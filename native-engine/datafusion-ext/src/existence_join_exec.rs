@@ -0,0 +1,370 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Defines hash joins of spark's subquery rewrites, which datafusion's hash join
+//! does not support: existence joins of IN/EXISTS subqueries in disjunctions,
+//! and null-aware anti joins of NOT IN subqueries.
+
+use std::any::Any;
+use std::collections::HashSet;
+use std::fmt::Formatter;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use datafusion::arrow::array::{Array, ArrayRef, BooleanArray};
+use datafusion::arrow::compute::filter_record_batch;
+use datafusion::arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use datafusion::arrow::error::ArrowError;
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::execution::context::TaskContext;
+use datafusion::physical_plan::expressions::{Column, PhysicalSortExpr};
+use datafusion::physical_plan::metrics::{
+    Count, ExecutionPlanMetricsSet, MetricBuilder, MetricsSet, Time,
+};
+use datafusion::physical_plan::stream::RecordBatchStreamAdapter;
+use datafusion::physical_plan::{
+    DisplayFormatType, ExecutionPlan, Partitioning, SendableRecordBatchStream, Statistics,
+};
+use datafusion::scalar::ScalarValue;
+use futures::{StreamExt, TryStreamExt};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ExistenceJoinMode {
+    /// outputs all left rows with a non-nullable boolean column of the name,
+    /// telling whether a right row of equal keys exists. null keys never match.
+    Exists(String),
+    /// outputs left rows not in the right keys, with the null semantics of NOT
+    /// IN: all left rows if the right side is empty, otherwise no rows if any
+    /// right key is null, otherwise left rows of non-null unmatched keys.
+    NullAwareAnti,
+}
+
+/// Joins left rows against the key set of the right side, which is collected
+/// in memory. left rows are output in order, at most once.
+#[derive(Debug)]
+pub struct ExistenceJoinExec {
+    left: Arc<dyn ExecutionPlan>,
+    right: Arc<dyn ExecutionPlan>,
+    on: Vec<(Column, Column)>,
+    mode: ExistenceJoinMode,
+    schema: SchemaRef,
+    metrics: ExecutionPlanMetricsSet,
+}
+
+impl ExistenceJoinExec {
+    pub fn try_new(
+        left: Arc<dyn ExecutionPlan>,
+        right: Arc<dyn ExecutionPlan>,
+        on: Vec<(Column, Column)>,
+        mode: ExistenceJoinMode,
+    ) -> Result<Self> {
+        if on.is_empty() {
+            return Err(DataFusionError::Plan(
+                "existence join requires join keys".to_owned(),
+            ));
+        }
+        if mode == ExistenceJoinMode::NullAwareAnti && on.len() > 1 {
+            return Err(DataFusionError::Plan(
+                "null-aware anti join supports only one join key".to_owned(),
+            ));
+        }
+        let left_schema = left.schema();
+        let right_schema = right.schema();
+        for (left_col, right_col) in &on {
+            let left_type = left_schema.field(left_col.index()).data_type();
+            let right_type = right_schema.field(right_col.index()).data_type();
+            if left_type != right_type {
+                return Err(DataFusionError::Plan(format!(
+                    "existence join keys of different types: {:?} and {:?}",
+                    left_type, right_type
+                )));
+            }
+        }
+
+        let mut fields = left_schema.fields().clone();
+        if let ExistenceJoinMode::Exists(exists_name) = &mode {
+            fields.push(Field::new(exists_name, DataType::Boolean, false));
+        }
+        let schema = Arc::new(Schema::new(fields));
+
+        Ok(Self {
+            left,
+            right,
+            on,
+            mode,
+            schema,
+            metrics: ExecutionPlanMetricsSet::new(),
+        })
+    }
+}
+
+#[async_trait]
+impl ExecutionPlan for ExistenceJoinExec {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+
+    fn output_partitioning(&self) -> Partitioning {
+        self.left.output_partitioning()
+    }
+
+    fn output_ordering(&self) -> Option<&[PhysicalSortExpr]> {
+        self.left.output_ordering()
+    }
+
+    fn children(&self) -> Vec<Arc<dyn ExecutionPlan>> {
+        vec![self.left.clone(), self.right.clone()]
+    }
+
+    fn with_new_children(
+        self: Arc<Self>,
+        children: Vec<Arc<dyn ExecutionPlan>>,
+    ) -> Result<Arc<dyn ExecutionPlan>> {
+        match children.len() {
+            2 => Ok(Arc::new(Self::try_new(
+                children[0].clone(),
+                children[1].clone(),
+                self.on.clone(),
+                self.mode.clone(),
+            )?)),
+            _ => Err(DataFusionError::Internal(
+                "ExistenceJoinExec wrong number of children".to_owned(),
+            )),
+        }
+    }
+
+    fn execute(
+        &self,
+        partition: usize,
+        context: Arc<TaskContext>,
+    ) -> Result<SendableRecordBatchStream> {
+        let joiner = ExistenceJoiner {
+            mode: self.mode.clone(),
+            schema: self.schema.clone(),
+            left_keys: self.on.iter().map(|(left, _)| left.index()).collect(),
+            right_keys: self.on.iter().map(|(_, right)| right.index()).collect(),
+            build_keys: HashSet::new(),
+            build_empty: true,
+            build_has_null_key: false,
+            output_rows: MetricBuilder::new(&self.metrics).output_rows(partition),
+            elapsed_compute: MetricBuilder::new(&self.metrics).elapsed_compute(partition),
+        };
+        let state = ExistenceJoinState {
+            joiner,
+            build_input: Some(self.right.execute(partition, context.clone())?),
+            streamed_input: self.left.execute(partition, context)?,
+        };
+
+        let output = futures::stream::try_unfold(state, |mut state| async move {
+            if let Some(mut build_input) = state.build_input.take() {
+                while let Some(batch) = build_input.next().await {
+                    state.joiner.add_build_batch(&batch?)?;
+                }
+            }
+            while let Some(batch) = state.streamed_input.next().await {
+                if let Some(output) = state.joiner.join_streamed_batch(&batch?)? {
+                    return Ok(Some((output, state)));
+                }
+            }
+            Ok::<_, DataFusionError>(None)
+        })
+        .map_err(|err| ArrowError::ExternalError(Box::new(err)));
+        Ok(Box::pin(RecordBatchStreamAdapter::new(
+            self.schema(),
+            output,
+        )))
+    }
+
+    fn metrics(&self) -> Option<MetricsSet> {
+        Some(self.metrics.clone_inner())
+    }
+
+    fn fmt_as(&self, t: DisplayFormatType, f: &mut Formatter) -> std::fmt::Result {
+        match t {
+            DisplayFormatType::Default => write!(
+                f,
+                "ExistenceJoinExec: mode={:?}, on={:?}",
+                self.mode, self.on
+            ),
+        }
+    }
+
+    fn statistics(&self) -> Statistics {
+        Statistics::default()
+    }
+}
+
+struct ExistenceJoinState {
+    joiner: ExistenceJoiner,
+    build_input: Option<SendableRecordBatchStream>,
+    streamed_input: SendableRecordBatchStream,
+}
+
+struct ExistenceJoiner {
+    mode: ExistenceJoinMode,
+    schema: SchemaRef,
+    left_keys: Vec<usize>,
+    right_keys: Vec<usize>,
+    /// non-null keys of the build side
+    build_keys: HashSet<Vec<ScalarValue>>,
+    build_empty: bool,
+    build_has_null_key: bool,
+    output_rows: Count,
+    elapsed_compute: Time,
+}
+
+impl ExistenceJoiner {
+    fn add_build_batch(&mut self, batch: &RecordBatch) -> Result<()> {
+        let _timer = self.elapsed_compute.timer();
+        let key_columns = key_columns(batch, &self.right_keys);
+        for row in 0..batch.num_rows() {
+            self.build_empty = false;
+            match row_key(&key_columns, row)? {
+                Some(key) => {
+                    self.build_keys.insert(key);
+                }
+                None => self.build_has_null_key = true,
+            }
+        }
+        Ok(())
+    }
+
+    fn join_streamed_batch(
+        &mut self,
+        batch: &RecordBatch,
+    ) -> Result<Option<RecordBatch>> {
+        let _timer = self.elapsed_compute.timer();
+        let key_columns = key_columns(batch, &self.left_keys);
+        let matched = (0..batch.num_rows())
+            .map(|row| {
+                Ok(match row_key(&key_columns, row)? {
+                    Some(key) => Some(self.build_keys.contains(&key)),
+                    None => None,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let output = match &self.mode {
+            ExistenceJoinMode::Exists(_) => {
+                let exists = BooleanArray::from(
+                    matched.iter().map(|m| m == &Some(true)).collect::<Vec<_>>(),
+                );
+                let mut columns = batch.columns().to_vec();
+                columns.push(Arc::new(exists));
+                RecordBatch::try_new(self.schema.clone(), columns)?
+            }
+            ExistenceJoinMode::NullAwareAnti => {
+                let selected = BooleanArray::from(
+                    matched
+                        .iter()
+                        .map(|m| match m {
+                            _ if self.build_empty => true,
+                            _ if self.build_has_null_key => false,
+                            Some(matched) => !matched,
+                            None => false,
+                        })
+                        .collect::<Vec<_>>(),
+                );
+                filter_record_batch(batch, &selected)?
+            }
+        };
+        if output.num_rows() == 0 {
+            return Ok(None);
+        }
+        self.output_rows.add(output.num_rows());
+        Ok(Some(output))
+    }
+}
+
+fn key_columns(batch: &RecordBatch, keys: &[usize]) -> Vec<ArrayRef> {
+    keys.iter().map(|&i| batch.column(i).clone()).collect()
+}
+
+/// returns the key values of the row, or None if any key is null
+fn row_key(key_columns: &[ArrayRef], row: usize) -> Result<Option<Vec<ScalarValue>>> {
+    let mut key = Vec::with_capacity(key_columns.len());
+    for column in key_columns {
+        if column.is_null(row) {
+            return Ok(None);
+        }
+        key.push(ScalarValue::try_from_array(column, row)?);
+    }
+    Ok(Some(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use datafusion::arrow::array::{BooleanArray, Int32Array};
+    use datafusion::arrow::record_batch::RecordBatch;
+    use datafusion::error::Result;
+    use datafusion::physical_plan::common::collect;
+    use datafusion::physical_plan::expressions::Column;
+    use datafusion::physical_plan::ExecutionPlan;
+    use datafusion::prelude::SessionContext;
+
+    use super::*;
+    use crate::test_util::memory_exec;
+
+    #[test]
+    fn test_existence_join() -> Result<()> {
+        let left = memory_exec("k", vec![Some(1), None, Some(2), Some(3)])?;
+        let task_ctx = SessionContext::new().task_ctx();
+        let runtime = tokio::runtime::Runtime::new()?;
+        let run = |right: Vec<Option<i32>>, mode: ExistenceJoinMode| -> Result<_> {
+            let join = ExistenceJoinExec::try_new(
+                left.clone(),
+                memory_exec("k", right)?,
+                vec![(Column::new("k", 0), Column::new("k", 0))],
+                mode,
+            )?;
+            let batches =
+                runtime.block_on(collect(join.execute(0, task_ctx.clone())?))?;
+            Ok(RecordBatch::concat(&join.schema(), &batches)?)
+        };
+
+        let exists = ExistenceJoinMode::Exists("exists".to_owned());
+        let batch = run(vec![Some(2), None, Some(1)], exists)?;
+        assert_eq!(
+            batch
+                .column(1)
+                .as_any()
+                .downcast_ref::<BooleanArray>()
+                .unwrap(),
+            &BooleanArray::from(vec![true, false, true, false])
+        );
+
+        // NOT IN semantics: nulls of the right side exclude all rows, nulls of
+        // the left side are excluded unless the right side is empty
+        let anti = ExistenceJoinMode::NullAwareAnti;
+        let column = |batch: RecordBatch| {
+            let array = batch.column(0).as_any().downcast_ref::<Int32Array>();
+            array.unwrap().iter().collect::<Vec<_>>()
+        };
+        assert_eq!(
+            column(run(vec![Some(2)], anti.clone())?),
+            vec![Some(1), Some(3)]
+        );
+        assert_eq!(column(run(vec![Some(2), None], anti.clone())?), vec![]);
+        assert_eq!(
+            column(run(vec![], anti)?),
+            vec![Some(1), None, Some(2), Some(3)]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,290 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Opt-in fault injection for testing how spark retries failed native stages.
+//!
+//! faults are configured with native conf `fault_injection`, a comma-separated
+//! list of `point:fault[:probability]` rules, e.g.
+//! `shuffle_read:corrupt:0.1,scan:delay=200,ffi_export:oom:0.01`:
+//!
+//! * points: `shuffle_read` (each shuffle segment), `scan` (each scanned
+//!   batch) and `ffi_export` (each batch exported to JVM)
+//! * faults: `delay=<millis>`, `io_error`, `oom` and `corrupt` (shuffle_read
+//!   only, the decoded segment is garbled)
+//! * probability defaults to 1.0
+//!
+//! delays block the current thread, as slow IO does.
+
+use std::any::Any;
+use std::fmt::Formatter;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use datafusion::arrow::datatypes::SchemaRef;
+use datafusion::arrow::error::ArrowError;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::execution::context::TaskContext;
+use datafusion::physical_plan::expressions::PhysicalSortExpr;
+use datafusion::physical_plan::stream::RecordBatchStreamAdapter;
+use datafusion::physical_plan::{
+    DisplayFormatType, ExecutionPlan, Partitioning, SendableRecordBatchStream, Statistics,
+};
+use futures::StreamExt;
+
+use crate::native_conf::native_conf;
+
+/// Named points where faults can be injected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultPoint {
+    ShuffleRead,
+    Scan,
+    FfiExport,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fault {
+    Delay(Duration),
+    IoError,
+    Oom,
+    Corrupt,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FaultRule {
+    pub point: FaultPoint,
+    pub fault: Fault,
+    pub probability: f64,
+}
+
+/// Fault injection rules parsed from native conf, empty to disable
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FaultInjectionRules(pub Vec<FaultRule>);
+
+impl FaultInjectionRules {
+    fn has_point(&self, point: FaultPoint) -> bool {
+        self.0.iter().any(|rule| rule.point == point)
+    }
+}
+
+impl FromStr for FaultInjectionRules {
+    type Err = DataFusionError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = |rule: &str, reason: &str| {
+            DataFusionError::Plan(format!(
+                "invalid fault injection rule {}: {}",
+                rule, reason
+            ))
+        };
+        let mut rules = vec![];
+        for rule in s.split(',').map(str::trim).filter(|rule| !rule.is_empty()) {
+            let parts = rule.split(':').collect::<Vec<_>>();
+            if parts.len() < 2 || parts.len() > 3 {
+                return Err(invalid(rule, "expect point:fault[:probability]"));
+            }
+            let point = match parts[0] {
+                "shuffle_read" => FaultPoint::ShuffleRead,
+                "scan" => FaultPoint::Scan,
+                "ffi_export" => FaultPoint::FfiExport,
+                _ => return Err(invalid(rule, "unknown point")),
+            };
+            let fault = match parts[1].split_once('=') {
+                Some(("delay", millis)) => {
+                    let millis = millis
+                        .parse::<u64>()
+                        .map_err(|_| invalid(rule, "invalid delay millis"))?;
+                    Fault::Delay(Duration::from_millis(millis))
+                }
+                None if parts[1] == "io_error" => Fault::IoError,
+                None if parts[1] == "oom" => Fault::Oom,
+                None if parts[1] == "corrupt" => Fault::Corrupt,
+                _ => return Err(invalid(rule, "unknown fault")),
+            };
+            if fault == Fault::Corrupt && point != FaultPoint::ShuffleRead {
+                return Err(invalid(rule, "only shuffle_read can be corrupted"));
+            }
+            let probability = match parts.get(2) {
+                Some(probability) => probability
+                    .parse::<f64>()
+                    .ok()
+                    .filter(|p| (0.0..=1.0).contains(p))
+                    .ok_or_else(|| invalid(rule, "probability must be in [0, 1]"))?,
+                None => 1.0,
+            };
+            rules.push(FaultRule {
+                point,
+                fault,
+                probability,
+            });
+        }
+        Ok(Self(rules))
+    }
+}
+
+/// injects faults of the point configured by native conf. delays are applied
+/// and errors are returned, returns true if the data at the point should be
+/// corrupted.
+pub fn inject_fault(point: FaultPoint) -> Result<bool> {
+    let conf = native_conf();
+    let mut corrupt = false;
+    for rule in conf
+        .fault_injection
+        .0
+        .iter()
+        .filter(|rule| rule.point == point)
+    {
+        if rand::random::<f64>() >= rule.probability {
+            continue;
+        }
+        log::warn!("injecting fault {:?} at {:?}", rule.fault, point);
+        match rule.fault {
+            Fault::Delay(delay) => std::thread::sleep(delay),
+            Fault::IoError => {
+                return Err(DataFusionError::IoError(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!("injected IO error at {:?}", point),
+                )));
+            }
+            Fault::Oom => {
+                return Err(DataFusionError::ResourcesExhausted(format!(
+                    "injected OOM at {:?}",
+                    point
+                )));
+            }
+            Fault::Corrupt => corrupt = true,
+        }
+    }
+    Ok(corrupt)
+}
+
+/// garbles the data in place, so that decoding it fails or yields garbage
+pub fn corrupt_data(data: &mut [u8]) {
+    let len = data.len();
+    for byte in &mut data[len / 2..] {
+        *byte = !*byte;
+    }
+}
+
+/// wraps the scan with FaultInjectionExec if scan faults are configured
+pub fn inject_scan_faults(input: Arc<dyn ExecutionPlan>) -> Arc<dyn ExecutionPlan> {
+    if native_conf().fault_injection.has_point(FaultPoint::Scan) {
+        return Arc::new(FaultInjectionExec::new(input, FaultPoint::Scan));
+    }
+    input
+}
+
+/// Injects faults before each batch of its input.
+#[derive(Debug)]
+pub struct FaultInjectionExec {
+    input: Arc<dyn ExecutionPlan>,
+    point: FaultPoint,
+}
+
+impl FaultInjectionExec {
+    pub fn new(input: Arc<dyn ExecutionPlan>, point: FaultPoint) -> Self {
+        Self { input, point }
+    }
+}
+
+#[async_trait]
+impl ExecutionPlan for FaultInjectionExec {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.input.schema()
+    }
+
+    fn output_partitioning(&self) -> Partitioning {
+        self.input.output_partitioning()
+    }
+
+    fn output_ordering(&self) -> Option<&[PhysicalSortExpr]> {
+        self.input.output_ordering()
+    }
+
+    crate::transparent_plan_tree!(input, |self, input| Self::new(input, self.point));
+
+    fn execute(
+        &self,
+        partition: usize,
+        context: Arc<TaskContext>,
+    ) -> Result<SendableRecordBatchStream> {
+        let point = self.point;
+        let input = self.input.execute(partition, context)?;
+        let injected = input.map(move |batch| {
+            batch.and_then(|batch| {
+                inject_fault(point)
+                    .map(|_| batch)
+                    .map_err(|e| ArrowError::ExternalError(Box::new(e)))
+            })
+        });
+        Ok(Box::pin(RecordBatchStreamAdapter::new(
+            self.schema(),
+            injected,
+        )))
+    }
+
+    fn fmt_as(&self, t: DisplayFormatType, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "FaultInjectionExec: point={:?}, ", self.point)?;
+        self.input.fmt_as(t, f)
+    }
+
+    fn statistics(&self) -> Statistics {
+        self.input.statistics()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use datafusion::error::Result;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_fault_injection_rules() -> Result<()> {
+        let rules: FaultInjectionRules =
+            "shuffle_read:corrupt:0.5, scan:delay=200,ffi_export:oom".parse()?;
+        assert_eq!(
+            rules.0,
+            vec![
+                FaultRule {
+                    point: FaultPoint::ShuffleRead,
+                    fault: Fault::Corrupt,
+                    probability: 0.5,
+                },
+                FaultRule {
+                    point: FaultPoint::Scan,
+                    fault: Fault::Delay(Duration::from_millis(200)),
+                    probability: 1.0,
+                },
+                FaultRule {
+                    point: FaultPoint::FfiExport,
+                    fault: Fault::Oom,
+                    probability: 1.0,
+                },
+            ]
+        );
+        assert!("".parse::<FaultInjectionRules>()?.0.is_empty());
+        assert!("scan:corrupt".parse::<FaultInjectionRules>().is_err());
+        assert!("scan:io_error:2".parse::<FaultInjectionRules>().is_err());
+        assert!("unknown:oom".parse::<FaultInjectionRules>().is_err());
+        Ok(())
+    }
+}
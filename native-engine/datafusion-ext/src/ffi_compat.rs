@@ -0,0 +1,180 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Casts output batches into types which can be exported through arrow FFI
+
+use std::sync::Arc;
+
+use datafusion::arrow::array::{make_array, Array, ArrayData, ArrayRef, StructArray};
+use datafusion::arrow::compute::{can_cast_types, cast};
+use datafusion::arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::error::Result;
+
+/// Converts batches of a schema which may contain types not exportable through
+/// arrow FFI (like dictionaries nested in lists or structs) into a supported
+/// representation.
+#[derive(Debug)]
+pub struct FFICompatConverter {
+    compat_schema: SchemaRef,
+    needs_conversion: bool,
+}
+
+impl FFICompatConverter {
+    /// creates a converter for the schema, a warning is logged for each
+    /// column that needs conversion
+    pub fn new(schema: &Schema) -> Self {
+        let compat_fields = schema
+            .fields()
+            .iter()
+            .map(|field| {
+                let compat_field = ffi_compat_field(field);
+                if compat_field.data_type() != field.data_type() {
+                    log::warn!(
+                        "FFI export: column {} of type {:?} is not supported, \
+                            casting to {:?}",
+                        field.name(),
+                        field.data_type(),
+                        compat_field.data_type(),
+                    );
+                }
+                compat_field
+            })
+            .collect::<Vec<_>>();
+        let needs_conversion = compat_fields
+            .iter()
+            .zip(schema.fields())
+            .any(|(compat_field, field)| compat_field.data_type() != field.data_type());
+
+        Self {
+            compat_schema: Arc::new(Schema::new(compat_fields)),
+            needs_conversion,
+        }
+    }
+
+    /// schema of converted batches
+    pub fn compat_schema(&self) -> SchemaRef {
+        self.compat_schema.clone()
+    }
+
+    pub fn convert(&self, batch: RecordBatch) -> Result<RecordBatch> {
+        if !self.needs_conversion {
+            return Ok(batch);
+        }
+        let columns = batch
+            .columns()
+            .iter()
+            .zip(self.compat_schema.fields())
+            .map(|(column, field)| cast_compat(column, field.data_type()))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(RecordBatch::try_new(self.compat_schema.clone(), columns)?)
+    }
+}
+
+fn ffi_compat_field(field: &Field) -> Field {
+    Field::new(
+        field.name(),
+        ffi_compat_data_type(field.data_type()),
+        field.is_nullable(),
+    )
+}
+
+/// dictionaries are unpacked at any nesting level. other types are kept, or
+/// cast to an equivalent supported type if possible
+fn ffi_compat_data_type(data_type: &DataType) -> DataType {
+    match data_type {
+        DataType::Dictionary(_, value_type) => ffi_compat_data_type(value_type),
+        DataType::List(field) => DataType::List(Box::new(ffi_compat_field(field))),
+        DataType::LargeList(field) => {
+            DataType::LargeList(Box::new(ffi_compat_field(field)))
+        }
+        DataType::Struct(fields) => {
+            DataType::Struct(fields.iter().map(ffi_compat_field).collect())
+        }
+        DataType::Duration(_) | DataType::Interval(_)
+            if can_cast_types(data_type, &DataType::Int64) =>
+        {
+            DataType::Int64
+        }
+        other => other.clone(),
+    }
+}
+
+fn cast_compat(array: &ArrayRef, data_type: &DataType) -> Result<ArrayRef> {
+    if array.data_type() == data_type {
+        return Ok(array.clone());
+    }
+    match data_type {
+        // arrow's cast kernel does not support structs, cast the children instead
+        DataType::Struct(fields) => {
+            let struct_array = array.as_any().downcast_ref::<StructArray>().unwrap();
+            let children = struct_array
+                .columns()
+                .iter()
+                .zip(fields)
+                .map(|(child, field)| -> Result<ArrayData> {
+                    Ok(cast_compat(child, field.data_type())?.data().clone())
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let data = array.data();
+            Ok(make_array(ArrayData::try_new(
+                data_type.clone(),
+                data.len(),
+                data.null_buffer().cloned(),
+                data.offset(),
+                vec![],
+                children,
+            )?))
+        }
+        _ => Ok(cast(array, data_type)?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use datafusion::arrow::array::{DictionaryArray, StringArray};
+    use datafusion::arrow::datatypes::Int32Type;
+
+    #[test]
+    fn test_unpack_nested_dictionary() -> Result<()> {
+        let dict: DictionaryArray<Int32Type> = vec!["a", "b", "a"].into_iter().collect();
+        let dict_field = Field::new("d", dict.data_type().clone(), false);
+        let struct_array =
+            StructArray::from(vec![(dict_field, Arc::new(dict) as ArrayRef)]);
+        let schema = Schema::new(vec![Field::new(
+            "s",
+            struct_array.data_type().clone(),
+            false,
+        )]);
+        let batch =
+            RecordBatch::try_new(Arc::new(schema.clone()), vec![Arc::new(struct_array)])?;
+
+        let converted = FFICompatConverter::new(&schema).convert(batch)?;
+        let converted_struct = converted
+            .column(0)
+            .as_any()
+            .downcast_ref::<StructArray>()
+            .unwrap();
+        assert_eq!(
+            converted_struct
+                .column(0)
+                .as_any()
+                .downcast_ref::<StringArray>()
+                .unwrap(),
+            &StringArray::from(vec!["a", "b", "a"])
+        );
+        Ok(())
+    }
+}
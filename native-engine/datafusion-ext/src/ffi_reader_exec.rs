@@ -0,0 +1,211 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Reads the output of a JVM child plan which cannot be converted to native,
+//! so that the supported upper portion of the plan still runs natively.
+//!
+//! the JVM side registers an ArrowFFIExporter as a resource, which writes its
+//! rows into arrow batches and exports them into the FFI structs allocated
+//! here. the imported buffers are owned by the JVM allocator and released
+//! through the FFI release callbacks once the batches are dropped.
+
+use std::any::Any;
+use std::fmt::Formatter;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::Context;
+use std::task::Poll;
+
+use async_trait::async_trait;
+use datafusion::arrow::array::{make_array_from_raw, StructArray};
+use datafusion::arrow::datatypes::SchemaRef;
+use datafusion::arrow::error::{ArrowError, Result as ArrowResult};
+use datafusion::arrow::ffi::ArrowArray;
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::execution::context::TaskContext;
+use datafusion::physical_plan::expressions::PhysicalSortExpr;
+use datafusion::physical_plan::metrics::BaselineMetrics;
+use datafusion::physical_plan::metrics::ExecutionPlanMetricsSet;
+use datafusion::physical_plan::metrics::MetricsSet;
+use datafusion::physical_plan::DisplayFormatType;
+use datafusion::physical_plan::ExecutionPlan;
+use datafusion::physical_plan::Partitioning;
+use datafusion::physical_plan::Partitioning::UnknownPartitioning;
+use datafusion::physical_plan::RecordBatchStream;
+use datafusion::physical_plan::SendableRecordBatchStream;
+use datafusion::physical_plan::Statistics;
+use futures::Stream;
+use jni::objects::{GlobalRef, JObject};
+use jni::sys::{jboolean, JNI_TRUE};
+
+use crate::jni_bridge::get_resource;
+use crate::jni_call;
+use crate::jni_new_global_ref;
+
+#[derive(Debug, Clone)]
+pub struct FFIReaderExec {
+    pub num_partitions: usize,
+    pub export_iter_provider_resource_id: String,
+    pub schema: SchemaRef,
+    pub metrics: ExecutionPlanMetricsSet,
+}
+impl FFIReaderExec {
+    pub fn new(
+        num_partitions: usize,
+        export_iter_provider_resource_id: String,
+        schema: SchemaRef,
+    ) -> FFIReaderExec {
+        FFIReaderExec {
+            num_partitions,
+            export_iter_provider_resource_id,
+            schema,
+            metrics: ExecutionPlanMetricsSet::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl ExecutionPlan for FFIReaderExec {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+
+    fn output_partitioning(&self) -> Partitioning {
+        UnknownPartitioning(self.num_partitions)
+    }
+
+    fn output_ordering(&self) -> Option<&[PhysicalSortExpr]> {
+        None
+    }
+
+    fn children(&self) -> Vec<Arc<dyn ExecutionPlan>> {
+        vec![]
+    }
+
+    fn with_new_children(
+        self: Arc<Self>,
+        _children: Vec<Arc<dyn ExecutionPlan>>,
+    ) -> Result<Arc<dyn ExecutionPlan>> {
+        Err(DataFusionError::Plan(
+            "Blaze FFIReaderExec does not support with_new_children()".to_owned(),
+        ))
+    }
+
+    fn execute(
+        &self,
+        partition: usize,
+        _context: Arc<TaskContext>,
+    ) -> Result<SendableRecordBatchStream> {
+        let baseline_metrics = BaselineMetrics::new(&self.metrics, partition);
+        let elapsed_compute = baseline_metrics.elapsed_compute().clone();
+        let _timer = elapsed_compute.timer();
+
+        let exporter_provider = get_resource(&self.export_iter_provider_resource_id)?;
+        let exporter = jni_new_global_ref!(
+            jni_call!(ScalaFunction0(exporter_provider).apply() -> JObject)?
+        )?;
+
+        Ok(Box::pin(FFIReaderStream {
+            schema: self.schema.clone(),
+            exporter: Some(exporter),
+            baseline_metrics,
+        }))
+    }
+
+    fn metrics(&self) -> Option<MetricsSet> {
+        Some(self.metrics.clone_inner())
+    }
+
+    fn fmt_as(&self, _t: DisplayFormatType, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "FFIReaderExec")
+    }
+
+    fn statistics(&self) -> Statistics {
+        Statistics::default()
+    }
+}
+
+struct FFIReaderStream {
+    schema: SchemaRef,
+    exporter: Option<GlobalRef>,
+    baseline_metrics: BaselineMetrics,
+}
+
+impl FFIReaderStream {
+    fn next_batch(&mut self) -> Result<Option<RecordBatch>> {
+        let exporter = match &self.exporter {
+            Some(exporter) => exporter,
+            None => return Ok(None),
+        };
+
+        // the FFI structs are allocated natively and released by the importer,
+        // even if the JVM side fails before exporting into them
+        let ffi_array = unsafe { ArrowArray::empty() };
+        let (array_ptr, schema_ptr) = ArrowArray::into_raw(ffi_array);
+        let exported = jni_call!(
+            BlazeArrowFFIExporter(exporter.as_obj()).exportNextBatch(
+                array_ptr as i64,
+                schema_ptr as i64,
+            ) -> jboolean
+        );
+        if !matches!(exported, Ok(exported) if exported == JNI_TRUE) {
+            // safety: pointers are created by ArrowArray::into_raw() above
+            drop(unsafe { ArrowArray::try_from_raw(array_ptr, schema_ptr) });
+            self.exporter = None;
+            return exported.map(|_| None);
+        }
+
+        let imported = unsafe { make_array_from_raw(array_ptr, schema_ptr)? };
+        let imported =
+            imported
+                .as_any()
+                .downcast_ref::<StructArray>()
+                .ok_or_else(|| {
+                    DataFusionError::Execution(
+                        "FFIReaderExec: expect struct array from the JVM".to_owned(),
+                    )
+                })?;
+        let batch = RecordBatch::try_new(self.schema.clone(), imported.columns_ref())?;
+        Ok(Some(batch))
+    }
+}
+
+impl Stream for FFIReaderStream {
+    type Item = ArrowResult<RecordBatch>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        let elapsed_compute = self.baseline_metrics.elapsed_compute().clone();
+        let _timer = elapsed_compute.timer();
+
+        let next_batch = self
+            .next_batch()
+            .map_err(|err| ArrowError::ExternalError(Box::new(err)))
+            .transpose();
+        self.baseline_metrics.record_poll(Poll::Ready(next_batch))
+    }
+}
+
+impl RecordBatchStream for FFIReaderStream {
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+}
@@ -0,0 +1,307 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Reads arrow batches from an ArrowArrayStream of the C stream interface,
+//! so that JVM sources already producing arrow data (e.g. connectors) feed
+//! native execution without copying.
+//!
+//! the JVM side registers a `() => java.lang.Long` as a resource, returning
+//! the address of an exported ArrowArrayStream for each executed partition.
+//! the stream is moved out of the struct on import, leaving it released, so
+//! the struct is still owned and freed by the JVM side while the stream and
+//! its batches are released natively.
+
+use std::any::Any;
+use std::fmt::Formatter;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use async_trait::async_trait;
+use datafusion::arrow::datatypes::SchemaRef;
+use datafusion::arrow::error::{ArrowError, Result as ArrowResult};
+use datafusion::arrow::ffi_stream::{ArrowArrayStreamReader, FFI_ArrowArrayStream};
+use datafusion::arrow::record_batch::{RecordBatch, RecordBatchReader};
+use datafusion::error::{DataFusionError, Result};
+use datafusion::execution::context::TaskContext;
+use datafusion::physical_plan::expressions::PhysicalSortExpr;
+use datafusion::physical_plan::metrics::{
+    BaselineMetrics, ExecutionPlanMetricsSet, MetricsSet,
+};
+use datafusion::physical_plan::Partitioning::UnknownPartitioning;
+use datafusion::physical_plan::{
+    DisplayFormatType, ExecutionPlan, Partitioning, RecordBatchStream,
+    SendableRecordBatchStream, Statistics,
+};
+use futures::Stream;
+use jni::objects::JObject;
+use jni::sys::jlong;
+
+use crate::jni_bridge::get_resource;
+use crate::jni_call;
+
+#[derive(Debug, Clone)]
+pub struct FFIStreamReaderExec {
+    pub num_partitions: usize,
+    pub stream_provider_resource_id: String,
+    pub schema: SchemaRef,
+    pub metrics: ExecutionPlanMetricsSet,
+}
+
+impl FFIStreamReaderExec {
+    pub fn new(
+        num_partitions: usize,
+        stream_provider_resource_id: String,
+        schema: SchemaRef,
+    ) -> FFIStreamReaderExec {
+        FFIStreamReaderExec {
+            num_partitions,
+            stream_provider_resource_id,
+            schema,
+            metrics: ExecutionPlanMetricsSet::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl ExecutionPlan for FFIStreamReaderExec {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+
+    fn output_partitioning(&self) -> Partitioning {
+        UnknownPartitioning(self.num_partitions)
+    }
+
+    fn output_ordering(&self) -> Option<&[PhysicalSortExpr]> {
+        None
+    }
+
+    fn children(&self) -> Vec<Arc<dyn ExecutionPlan>> {
+        vec![]
+    }
+
+    fn with_new_children(
+        self: Arc<Self>,
+        _children: Vec<Arc<dyn ExecutionPlan>>,
+    ) -> Result<Arc<dyn ExecutionPlan>> {
+        Err(DataFusionError::Plan(
+            "Blaze FFIStreamReaderExec does not support with_new_children()".to_owned(),
+        ))
+    }
+
+    fn execute(
+        &self,
+        partition: usize,
+        _context: Arc<TaskContext>,
+    ) -> Result<SendableRecordBatchStream> {
+        let baseline_metrics = BaselineMetrics::new(&self.metrics, partition);
+        let elapsed_compute = baseline_metrics.elapsed_compute().clone();
+        let _timer = elapsed_compute.timer();
+
+        let stream_provider = get_resource(&self.stream_provider_resource_id)?;
+        let stream_ptr = jni_call!(
+            JavaLong(jni_call!(ScalaFunction0(stream_provider).apply() -> JObject)?)
+                .longValue() -> jlong
+        )?;
+        if stream_ptr == 0 {
+            return Err(DataFusionError::Execution(
+                "FFIStreamReaderExec: expect non-null ArrowArrayStream".to_owned(),
+            ));
+        }
+
+        // safety: the JVM side provides the address of an exported stream
+        let stream = unsafe { import_stream(stream_ptr as *mut FFI_ArrowArrayStream)? };
+        Ok(Box::pin(FFIStreamReaderStream::try_new(
+            self.schema.clone(),
+            stream,
+            baseline_metrics,
+        )?))
+    }
+
+    fn metrics(&self) -> Option<MetricsSet> {
+        Some(self.metrics.clone_inner())
+    }
+
+    fn fmt_as(&self, _t: DisplayFormatType, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "FFIStreamReaderExec")
+    }
+
+    fn statistics(&self) -> Statistics {
+        Statistics::default()
+    }
+}
+
+/// moves the stream out of the struct, which is left released
+unsafe fn import_stream(
+    stream_ptr: *mut FFI_ArrowArrayStream,
+) -> Result<ArrowArrayStreamReader> {
+    Ok(ArrowArrayStreamReader::from_raw(stream_ptr)?)
+}
+
+/// The imported stream, polled by one thread at a time.
+///
+/// the C stream interface does not require the producer to be called from
+/// the same thread, only not concurrently, which is ensured by polling the
+/// stream through `&mut self`.
+struct ImportedStream(ArrowArrayStreamReader);
+
+unsafe impl Send for ImportedStream {}
+
+struct FFIStreamReaderStream {
+    schema: SchemaRef,
+    stream: Option<ImportedStream>,
+    baseline_metrics: BaselineMetrics,
+}
+
+impl FFIStreamReaderStream {
+    fn try_new(
+        schema: SchemaRef,
+        stream: ArrowArrayStreamReader,
+        baseline_metrics: BaselineMetrics,
+    ) -> Result<Self> {
+        let num_imported_fields = stream.schema().fields().len();
+        if num_imported_fields != schema.fields().len() {
+            return Err(DataFusionError::Execution(format!(
+                "FFIStreamReaderExec: expect {} columns from the JVM, got {}",
+                schema.fields().len(),
+                num_imported_fields,
+            )));
+        }
+        Ok(Self {
+            schema,
+            stream: Some(ImportedStream(stream)),
+            baseline_metrics,
+        })
+    }
+
+    fn next_batch(&mut self) -> Result<Option<RecordBatch>> {
+        let stream = match &mut self.stream {
+            Some(stream) => stream,
+            None => return Ok(None),
+        };
+        match stream.0.next() {
+            Some(Ok(batch)) => {
+                // imported columns are renamed to the declared schema
+                let batch =
+                    RecordBatch::try_new(self.schema.clone(), batch.columns().to_vec())?;
+                Ok(Some(batch))
+            }
+            Some(Err(err)) => {
+                self.stream = None;
+                Err(err.into())
+            }
+            None => {
+                // the stream is released as soon as it is exhausted
+                self.stream = None;
+                Ok(None)
+            }
+        }
+    }
+}
+
+impl Stream for FFIStreamReaderStream {
+    type Item = ArrowResult<RecordBatch>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        let elapsed_compute = self.baseline_metrics.elapsed_compute().clone();
+        let _timer = elapsed_compute.timer();
+
+        let next_batch = self
+            .next_batch()
+            .map_err(|err| ArrowError::ExternalError(Box::new(err)))
+            .transpose();
+        self.baseline_metrics.record_poll(Poll::Ready(next_batch))
+    }
+}
+
+impl RecordBatchStream for FFIStreamReaderStream {
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use datafusion::arrow::datatypes::{DataType, Field, Schema};
+    use datafusion::physical_plan::common::collect;
+
+    use super::*;
+    use crate::test_util::build_batch;
+
+    struct TestReader {
+        schema: SchemaRef,
+        batches: std::vec::IntoIter<RecordBatch>,
+    }
+
+    impl Iterator for TestReader {
+        type Item = ArrowResult<RecordBatch>;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            self.batches.next().map(Ok)
+        }
+    }
+
+    impl RecordBatchReader for TestReader {
+        fn schema(&self) -> SchemaRef {
+            self.schema.clone()
+        }
+    }
+
+    #[test]
+    fn test_ffi_stream_reader() -> Result<()> {
+        let exported_schema = Arc::new(Schema::new(vec![
+            Field::new("col_0", DataType::Int32, false),
+            Field::new("col_1", DataType::Utf8, true),
+        ]));
+        let batch = |values: Vec<i32>| {
+            let strings = values
+                .iter()
+                .map(|v| Some(v.to_string()))
+                .collect::<Vec<_>>();
+            build_batch!(exported_schema, values, strings)
+        };
+        let mut ffi_stream = FFI_ArrowArrayStream::new(Box::new(TestReader {
+            schema: exported_schema.clone(),
+            batches: vec![batch(vec![1, 2, 3])?, batch(vec![4])?].into_iter(),
+        }));
+        let stream = unsafe { import_stream(&mut ffi_stream)? };
+
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("a", DataType::Int32, false),
+            Field::new("b", DataType::Utf8, true),
+        ]));
+        let metrics = ExecutionPlanMetricsSet::new();
+        let stream = FFIStreamReaderStream::try_new(
+            schema.clone(),
+            stream,
+            BaselineMetrics::new(&metrics, 0),
+        )?;
+
+        let runtime = tokio::runtime::Runtime::new()?;
+        let batches = runtime.block_on(collect(Box::pin(stream)))?;
+        assert_eq!(batches.len(), 2);
+        assert_eq!(batches[0].schema(), schema);
+        assert_eq!(batches[0].num_rows() + batches[1].num_rows(), 4);
+        assert_eq!(metrics.clone_inner().output_rows(), Some(4));
+        Ok(())
+    }
+}
@@ -0,0 +1,382 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Scan splits of tables planned natively.
+//!
+//! files of a table are listed from its location with the object store of the
+//! location, and pruned by partition filters evaluated on values parsed from
+//! hive-style partition directories (e.g. `dt=2022-01-01`). the remaining files
+//! are split and packed into scan partitions like spark's
+//! `FilePartition.getFilePartitions()`, with the options of `spark.sql.files.*`,
+//! so that scans may be planned without the JVM enumerating files for every
+//! task.
+
+use std::cmp::Reverse;
+use std::sync::Arc;
+
+use datafusion::arrow::array::{Array, ArrayRef, BooleanArray, StringArray};
+use datafusion::arrow::compute::cast;
+use datafusion::arrow::datatypes::{Schema, SchemaRef};
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::datafusion_data_access::object_store::ObjectStore;
+use datafusion::datafusion_data_access::FileMeta;
+use datafusion::datasource::listing::{FileRange, PartitionedFile};
+use datafusion::error::{DataFusionError, Result};
+use datafusion::physical_plan::PhysicalExpr;
+use datafusion::scalar::ScalarValue;
+use futures::TryStreamExt;
+
+use crate::global_object_store_registry;
+
+/// directory name of null partition values
+pub const HIVE_DEFAULT_PARTITION: &str = "__HIVE_DEFAULT_PARTITION__";
+
+/// Options of splitting files into scan partitions
+#[derive(Debug, Clone)]
+pub struct FileSplitOptions {
+    /// max bytes of a partition, see spark.sql.files.maxPartitionBytes
+    pub max_partition_bytes: u64,
+    /// estimated bytes of opening a file, see spark.sql.files.openCostInBytes
+    pub open_cost_in_bytes: u64,
+    /// suggested min number of partitions, usually the default parallelism.
+    /// see spark.sql.files.minPartitionNum
+    pub min_partition_num: usize,
+    /// whether files can be read by byte ranges, true for parquet and orc
+    pub splittable: bool,
+}
+
+impl Default for FileSplitOptions {
+    fn default() -> Self {
+        Self {
+            max_partition_bytes: 128 << 20,
+            open_cost_in_bytes: 4 << 20,
+            min_partition_num: 1,
+            splittable: true,
+        }
+    }
+}
+
+/// lists files of the table located at `table_path` and splits them into scan
+/// partitions. the object store is decided by the scheme of the path, paths of
+/// files are passed to the store as is, like paths of scanned files.
+pub async fn plan_table_splits(
+    table_path: &str,
+    partition_schema: SchemaRef,
+    partition_filters: &[Arc<dyn PhysicalExpr>],
+    options: &FileSplitOptions,
+) -> Result<Vec<Vec<PartitionedFile>>> {
+    let (object_store, path) = global_object_store_registry().get_by_uri(table_path)?;
+
+    // local files are read without schemes while hdfs paths are resolved by
+    // hadoop with their schemes
+    let table_path = if table_path.starts_with("file://") {
+        path
+    } else {
+        table_path
+    };
+    let files = list_table_files(
+        object_store,
+        table_path,
+        partition_schema,
+        partition_filters,
+    )
+    .await?;
+    Ok(plan_file_splits(files, options))
+}
+
+/// lists files of the table in partition directories matching the partition
+/// schema, with values of the partition columns. hidden files and files of
+/// partitions not satisfying all `partition_filters` are skipped, filters are
+/// bound to the partition schema.
+pub async fn list_table_files(
+    object_store: Arc<dyn ObjectStore>,
+    table_path: &str,
+    partition_schema: SchemaRef,
+    partition_filters: &[Arc<dyn PhysicalExpr>],
+) -> Result<Vec<PartitionedFile>> {
+    let table_path = table_path.trim_end_matches('/');
+    let listed: Vec<FileMeta> = object_store
+        .list_file(table_path)
+        .await?
+        .try_collect()
+        .await?;
+
+    let mut files = vec![];
+    let mut raw_partition_values = vec![];
+    for file_meta in listed {
+        let relative_path = match file_meta.sized_file.path.strip_prefix(table_path) {
+            Some(relative_path) => relative_path,
+            None => continue,
+        };
+        if let Some(values) = parse_partition_values(relative_path, &partition_schema) {
+            files.push(file_meta);
+            raw_partition_values.push(values);
+        }
+    }
+    if files.is_empty() || partition_schema.fields().is_empty() {
+        return Ok(files
+            .into_iter()
+            .map(|file_meta| PartitionedFile {
+                file_meta,
+                partition_values: vec![],
+                range: None,
+            })
+            .collect());
+    }
+
+    // partition values are cast from strings to the partition column types,
+    // values failing to cast are null like spark's partition discovery
+    let columns = partition_schema
+        .fields()
+        .iter()
+        .enumerate()
+        .map(|(i, field)| {
+            let values = raw_partition_values
+                .iter()
+                .map(|values| values[i].as_deref())
+                .collect::<StringArray>();
+            Ok(cast(&(Arc::new(values) as ArrayRef), field.data_type())?)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let batch = RecordBatch::try_new(partition_schema.clone(), columns)?;
+
+    let mut selected = vec![true; batch.num_rows()];
+    for filter in partition_filters {
+        let result = filter.evaluate(&batch)?.into_array(batch.num_rows());
+        let result = result
+            .as_any()
+            .downcast_ref::<BooleanArray>()
+            .ok_or_else(|| {
+                DataFusionError::Plan(format!(
+                    "partition filter {} is not a boolean expression",
+                    filter
+                ))
+            })?;
+        for (i, selected) in selected.iter_mut().enumerate() {
+            *selected &= result.is_valid(i) && result.value(i);
+        }
+    }
+
+    let mut partitioned_files = vec![];
+    for (i, file_meta) in files.into_iter().enumerate() {
+        if selected[i] {
+            partitioned_files.push(PartitionedFile {
+                file_meta,
+                partition_values: batch
+                    .columns()
+                    .iter()
+                    .map(|column| ScalarValue::try_from_array(column, i))
+                    .collect::<Result<Vec<_>>>()?,
+                range: None,
+            });
+        }
+    }
+    Ok(partitioned_files)
+}
+
+/// returns raw values of the partition columns in directories of the path
+/// relative to the table location, None for null values. returns None if the
+/// file is hidden or not in directories of the partition columns.
+fn parse_partition_values(
+    relative_path: &str,
+    partition_schema: &Schema,
+) -> Option<Vec<Option<String>>> {
+    let names = relative_path
+        .split('/')
+        .filter(|name| !name.is_empty())
+        .collect::<Vec<_>>();
+    let (_file_name, dirs) = names.split_last()?;
+
+    // skips hidden files like spark, e.g. _SUCCESS and files in .staging
+    if names.iter().any(|name| {
+        (name.starts_with('_') && !name.contains('=')) || name.starts_with('.')
+    }) {
+        return None;
+    }
+    if dirs.len() != partition_schema.fields().len() {
+        return None;
+    }
+
+    let mut values = vec![];
+    for (dir, field) in dirs.iter().zip(partition_schema.fields()) {
+        let (name, value) = dir.split_once('=')?;
+        if !unescape_path_name(name).eq_ignore_ascii_case(field.name()) {
+            return None;
+        }
+        let value = unescape_path_name(value);
+        values.push(Some(value).filter(|value| value != HIVE_DEFAULT_PARTITION));
+    }
+    Some(values)
+}
+
+/// unescapes %XX escaped characters of hive partition directories
+fn unescape_path_name(name: &str) -> String {
+    let bytes = name.as_bytes();
+    let mut unescaped = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok();
+            if let Some(byte) = hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                unescaped.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        unescaped.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&unescaped).into_owned()
+}
+
+/// splits files into byte ranges and packs them into scan partitions like
+/// spark's `FilePartition.getFilePartitions()`
+pub fn plan_file_splits(
+    files: Vec<PartitionedFile>,
+    options: &FileSplitOptions,
+) -> Vec<Vec<PartitionedFile>> {
+    let open_cost = options.open_cost_in_bytes;
+    let total_bytes = files
+        .iter()
+        .map(|file| file.file_meta.sized_file.size + open_cost)
+        .sum::<u64>();
+    let bytes_per_core = total_bytes / options.min_partition_num.max(1) as u64;
+    let max_split_bytes = options
+        .max_partition_bytes
+        .min(open_cost.max(bytes_per_core))
+        .max(1);
+
+    let mut splits = vec![];
+    for file in files {
+        let size = file.file_meta.sized_file.size;
+        if !options.splittable || size <= max_split_bytes {
+            splits.push(file);
+            continue;
+        }
+        let mut start = 0;
+        while start < size {
+            let end = size.min(start + max_split_bytes);
+            splits.push(PartitionedFile {
+                range: Some(FileRange {
+                    start: start as i64,
+                    end: end as i64,
+                }),
+                ..file.clone()
+            });
+            start = end;
+        }
+    }
+
+    // larger splits are packed first, so that partitions are more even
+    splits.sort_by_key(|split| Reverse(split_bytes(split)));
+    let mut partitions = vec![];
+    let mut current = vec![];
+    let mut current_bytes = 0;
+    for split in splits {
+        let bytes = split_bytes(&split);
+        if !current.is_empty() && current_bytes + bytes > max_split_bytes {
+            partitions.push(std::mem::take(&mut current));
+            current_bytes = 0;
+        }
+        current_bytes += bytes + open_cost;
+        current.push(split);
+    }
+    if !current.is_empty() {
+        partitions.push(current);
+    }
+    partitions
+}
+
+fn split_bytes(split: &PartitionedFile) -> u64 {
+    match &split.range {
+        Some(range) => (range.end - range.start) as u64,
+        None => split.file_meta.sized_file.size,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use datafusion::arrow::datatypes::{DataType, Field};
+    use datafusion::datafusion_data_access::object_store::local::LocalFileSystem;
+    use datafusion::logical_plan::Operator;
+    use datafusion::physical_plan::expressions::{col, lit, BinaryExpr};
+
+    use super::*;
+
+    #[test]
+    fn test_file_splits() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let write = |path: &str, size: usize| -> Result<()> {
+            let path = dir.path().join(path);
+            fs::create_dir_all(path.parent().unwrap())?;
+            Ok(fs::write(path, vec![0u8; size])?)
+        };
+        write("dt=1/a.parquet", 100)?;
+        write("dt=2/b.parquet", 250)?;
+        write("dt=2/_SUCCESS", 0)?;
+        write("dt=3/c.parquet", 30)?;
+        write("dt=__HIVE_DEFAULT_PARTITION__/d.parquet", 10)?;
+        write("dt=3/.c.parquet.crc", 10)?;
+        write("_temporary/0/dt=4/e.parquet", 10)?;
+        write("f.parquet", 10)?;
+
+        // WHERE dt >= 2
+        let schema = Arc::new(Schema::new(vec![Field::new("dt", DataType::Int32, true)]));
+        let filter = Arc::new(BinaryExpr::new(
+            col("dt", &schema)?,
+            Operator::GtEq,
+            lit(ScalarValue::from(2)),
+        ));
+        let table_path = dir.path().to_str().unwrap();
+        let runtime = tokio::runtime::Runtime::new()?;
+        let mut files = runtime.block_on(list_table_files(
+            Arc::new(LocalFileSystem),
+            table_path,
+            schema,
+            &[filter],
+        ))?;
+        files.sort_by_key(|file| file.file_meta.sized_file.path.clone());
+        let listed = files
+            .iter()
+            .map(|file| {
+                let path = &file.file_meta.sized_file.path[table_path.len()..];
+                (path.to_owned(), file.partition_values.clone())
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            listed,
+            vec![
+                ("/dt=2/b.parquet".to_owned(), vec![ScalarValue::from(2)]),
+                ("/dt=3/c.parquet".to_owned(), vec![ScalarValue::from(3)]),
+            ]
+        );
+
+        // b is split into ranges of 100 bytes, packed with open costs of 10
+        let options = FileSplitOptions {
+            max_partition_bytes: 100,
+            open_cost_in_bytes: 10,
+            min_partition_num: 1,
+            splittable: true,
+        };
+        let partitions = plan_file_splits(files, &options)
+            .iter()
+            .map(|partition| partition.iter().map(split_bytes).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(partitions, vec![vec![100], vec![100], vec![50, 30]]);
+        assert_eq!(unescape_path_name("a%3Db%2"), "a=b%2");
+        Ok(())
+    }
+}
@@ -0,0 +1,470 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Defines a filter fused with the projection above it
+//!
+//! rows are selected like LateMaterializationFilterExec, then only the columns
+//! referenced by the projection are gathered for the selected rows and the
+//! projection is evaluated on them, so the filtered batch with all its columns
+//! is never materialized. small outputs of selective filters are coalesced
+//! into batches of batch_size in the same pass.
+//!
+//! the fused filter stays in the plan tree as a FusedFilterExec child holding
+//! the filter metrics, so that the native metric tree still matches the JVM
+//! side. it is only executed through its parent.
+
+use std::any::Any;
+use std::collections::BTreeSet;
+use std::fmt::Formatter;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use async_trait::async_trait;
+use datafusion::arrow::datatypes::{Field, Schema, SchemaRef};
+use datafusion::arrow::error::{ArrowError, Result as ArrowResult};
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::execution::context::TaskContext;
+use datafusion::physical_expr::PhysicalSortExpr;
+use datafusion::physical_plan::metrics::{
+    BaselineMetrics, ExecutionPlanMetricsSet, MetricsSet,
+};
+use datafusion::physical_plan::{
+    DisplayFormatType, ExecutionPlan, Partitioning, PhysicalExpr, RecordBatchStream,
+    SendableRecordBatchStream, Statistics,
+};
+use futures::{ready, Stream, StreamExt};
+
+use crate::late_materialization_filter_exec::{
+    collect_columns, conjuncts_of, gather, select_rows, Conjunct,
+};
+
+/// Filters rows of the input and evaluates projection expressions on the
+/// selected rows in a single pass, like FilterExec followed by ProjectionExec
+/// and CoalesceBatchesExec.
+#[derive(Debug)]
+pub struct FilterProjectExec {
+    filter: Arc<FusedFilterExec>,
+    exprs: Vec<(Arc<dyn PhysicalExpr>, String)>,
+    conjuncts: Arc<Vec<Conjunct>>,
+    /// indices of columns referenced by the projection, None if unknown
+    projected_columns: Option<Vec<usize>>,
+    schema: SchemaRef,
+    metrics: ExecutionPlanMetricsSet,
+}
+
+impl FilterProjectExec {
+    pub fn try_new(
+        predicate: Arc<dyn PhysicalExpr>,
+        exprs: Vec<(Arc<dyn PhysicalExpr>, String)>,
+        input: Arc<dyn ExecutionPlan>,
+    ) -> Result<Self> {
+        let input_schema = input.schema();
+        let fields = exprs
+            .iter()
+            .map(|(expr, name)| {
+                Ok(Field::new(
+                    name,
+                    expr.data_type(&input_schema)?,
+                    expr.nullable(&input_schema)?,
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut columns = BTreeSet::new();
+        let known = exprs
+            .iter()
+            .all(|(expr, _)| collect_columns(expr, &mut columns));
+
+        Ok(Self {
+            conjuncts: Arc::new(conjuncts_of(&predicate)),
+            filter: Arc::new(FusedFilterExec::try_new(predicate, input)?),
+            exprs,
+            projected_columns: known.then(|| columns.into_iter().collect()),
+            schema: Arc::new(Schema::new(fields)),
+            metrics: ExecutionPlanMetricsSet::new(),
+        })
+    }
+
+    pub fn predicate(&self) -> &Arc<dyn PhysicalExpr> {
+        &self.filter.predicate
+    }
+
+    pub fn exprs(&self) -> &[(Arc<dyn PhysicalExpr>, String)] {
+        &self.exprs
+    }
+}
+
+#[async_trait]
+impl ExecutionPlan for FilterProjectExec {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+
+    fn output_partitioning(&self) -> Partitioning {
+        self.filter.input.output_partitioning()
+    }
+
+    fn output_ordering(&self) -> Option<&[PhysicalSortExpr]> {
+        None
+    }
+
+    fn children(&self) -> Vec<Arc<dyn ExecutionPlan>> {
+        vec![self.filter.clone()]
+    }
+
+    fn with_new_children(
+        self: Arc<Self>,
+        children: Vec<Arc<dyn ExecutionPlan>>,
+    ) -> Result<Arc<dyn ExecutionPlan>> {
+        // the new child replaces the fused filter (possibly wrapped by
+        // transparent operators), its child is the new input
+        let input = match children.as_slice() {
+            [filter] => match filter.children().as_slice() {
+                [input] => input.clone(),
+                _ => {
+                    return Err(DataFusionError::Plan(
+                        "FilterProjectExec expects a filter child with one input"
+                            .to_string(),
+                    ))
+                }
+            },
+            _ => {
+                return Err(DataFusionError::Plan(
+                    "FilterProjectExec expects one children".to_string(),
+                ))
+            }
+        };
+        Ok(Arc::new(FilterProjectExec::try_new(
+            self.filter.predicate.clone(),
+            self.exprs.clone(),
+            input,
+        )?))
+    }
+
+    fn execute(
+        &self,
+        partition: usize,
+        context: Arc<TaskContext>,
+    ) -> Result<SendableRecordBatchStream> {
+        let input = self.filter.input.execute(partition, context.clone())?;
+        Ok(Box::pin(FilterProjectStream {
+            input,
+            conjuncts: self.conjuncts.clone(),
+            exprs: self.exprs.iter().map(|(expr, _)| expr.clone()).collect(),
+            projected_columns: self.projected_columns.clone(),
+            schema: self.schema.clone(),
+            batch_size: context.session_config().batch_size.max(1),
+            staged: vec![],
+            num_staged_rows: 0,
+            filter_metrics: BaselineMetrics::new(&self.filter.metrics, partition),
+            baseline_metrics: BaselineMetrics::new(&self.metrics, partition),
+        }))
+    }
+
+    fn metrics(&self) -> Option<MetricsSet> {
+        Some(self.metrics.clone_inner())
+    }
+
+    fn fmt_as(&self, t: DisplayFormatType, f: &mut Formatter) -> std::fmt::Result {
+        match t {
+            DisplayFormatType::Default => {
+                let exprs = self
+                    .exprs
+                    .iter()
+                    .map(|(expr, name)| format!("{} as {}", expr, name))
+                    .collect::<Vec<_>>();
+                write!(f, "FilterProjectExec: expr=[{}]", exprs.join(", "))
+            }
+        }
+    }
+
+    fn statistics(&self) -> Statistics {
+        Statistics::default()
+    }
+}
+
+/// The filter fused into FilterProjectExec, which records its metrics.
+#[derive(Debug)]
+pub struct FusedFilterExec {
+    predicate: Arc<dyn PhysicalExpr>,
+    input: Arc<dyn ExecutionPlan>,
+    metrics: ExecutionPlanMetricsSet,
+}
+
+impl FusedFilterExec {
+    fn try_new(
+        predicate: Arc<dyn PhysicalExpr>,
+        input: Arc<dyn ExecutionPlan>,
+    ) -> Result<Self> {
+        Ok(Self {
+            predicate,
+            input,
+            metrics: ExecutionPlanMetricsSet::new(),
+        })
+    }
+}
+
+#[async_trait]
+impl ExecutionPlan for FusedFilterExec {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.input.schema()
+    }
+
+    fn output_partitioning(&self) -> Partitioning {
+        self.input.output_partitioning()
+    }
+
+    fn output_ordering(&self) -> Option<&[PhysicalSortExpr]> {
+        self.input.output_ordering()
+    }
+
+    fn children(&self) -> Vec<Arc<dyn ExecutionPlan>> {
+        vec![self.input.clone()]
+    }
+
+    fn with_new_children(
+        self: Arc<Self>,
+        children: Vec<Arc<dyn ExecutionPlan>>,
+    ) -> Result<Arc<dyn ExecutionPlan>> {
+        if children.len() != 1 {
+            return Err(DataFusionError::Plan(
+                "FusedFilterExec expects one children".to_string(),
+            ));
+        }
+        Ok(Arc::new(FusedFilterExec::try_new(
+            self.predicate.clone(),
+            children[0].clone(),
+        )?))
+    }
+
+    fn execute(
+        &self,
+        _partition: usize,
+        _context: Arc<TaskContext>,
+    ) -> Result<SendableRecordBatchStream> {
+        Err(DataFusionError::Plan(
+            "FusedFilterExec is only executed through FilterProjectExec".to_owned(),
+        ))
+    }
+
+    fn metrics(&self) -> Option<MetricsSet> {
+        Some(self.metrics.clone_inner())
+    }
+
+    fn fmt_as(&self, t: DisplayFormatType, f: &mut Formatter) -> std::fmt::Result {
+        match t {
+            DisplayFormatType::Default => {
+                write!(f, "FusedFilterExec: {}", self.predicate)
+            }
+        }
+    }
+
+    fn statistics(&self) -> Statistics {
+        Statistics::default()
+    }
+}
+
+struct FilterProjectStream {
+    input: SendableRecordBatchStream,
+    conjuncts: Arc<Vec<Conjunct>>,
+    exprs: Vec<Arc<dyn PhysicalExpr>>,
+    projected_columns: Option<Vec<usize>>,
+    schema: SchemaRef,
+    batch_size: usize,
+    staged: Vec<RecordBatch>,
+    num_staged_rows: usize,
+    filter_metrics: BaselineMetrics,
+    baseline_metrics: BaselineMetrics,
+}
+
+impl FilterProjectStream {
+    /// returns an output batch once enough rows are staged
+    fn filter_project(&mut self, batch: &RecordBatch) -> Result<Option<RecordBatch>> {
+        let timer = self.filter_metrics.elapsed_compute().timer();
+        let selected = select_rows(&self.conjuncts, batch)?;
+        timer.done();
+
+        let num_rows = selected
+            .as_ref()
+            .map(|s| s.len())
+            .unwrap_or(batch.num_rows());
+        self.filter_metrics.record_output(num_rows);
+        if num_rows == 0 {
+            return Ok(None);
+        }
+
+        let elapsed_compute = self.baseline_metrics.elapsed_compute().clone();
+        let _timer = elapsed_compute.timer();
+        let filtered = match &selected {
+            Some(indices) => gather(batch, indices, self.projected_columns.as_deref())?,
+            None => batch.clone(),
+        };
+        let columns = self
+            .exprs
+            .iter()
+            .map(|expr| Ok(expr.evaluate(&filtered)?.into_array(num_rows)))
+            .collect::<Result<Vec<_>>>()?;
+        let output = RecordBatch::try_new(self.schema.clone(), columns)?;
+
+        // large enough outputs are not copied again
+        if self.staged.is_empty() && num_rows >= self.batch_size {
+            return Ok(Some(output));
+        }
+        self.num_staged_rows += num_rows;
+        self.staged.push(output);
+        if self.num_staged_rows >= self.batch_size {
+            return self.take_staged();
+        }
+        Ok(None)
+    }
+
+    fn take_staged(&mut self) -> Result<Option<RecordBatch>> {
+        if self.staged.is_empty() {
+            return Ok(None);
+        }
+        let batch = RecordBatch::concat(&self.schema, &self.staged)?;
+        self.staged.clear();
+        self.num_staged_rows = 0;
+        Ok(Some(batch))
+    }
+}
+
+impl Stream for FilterProjectStream {
+    type Item = ArrowResult<RecordBatch>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        loop {
+            let output = match ready!(self.input.poll_next_unpin(cx)) {
+                Some(Ok(batch)) => match self.filter_project(&batch) {
+                    Ok(None) => continue,
+                    output => output,
+                },
+                Some(Err(err)) => return Poll::Ready(Some(Err(err))),
+                None => self.take_staged(),
+            };
+            let output = output.map_err(ArrowError::from).transpose();
+            return self.baseline_metrics.record_poll(Poll::Ready(output));
+        }
+    }
+}
+
+impl RecordBatchStream for FilterProjectStream {
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use datafusion::arrow::array::{Int32Array, StringArray};
+    use datafusion::arrow::datatypes::DataType;
+    use datafusion::logical_plan::Operator;
+    use datafusion::physical_plan::common::collect;
+    use datafusion::physical_plan::expressions::{col, lit, BinaryExpr};
+    use datafusion::physical_plan::memory::MemoryExec;
+    use datafusion::prelude::{SessionConfig, SessionContext};
+    use datafusion::scalar::ScalarValue;
+
+    use super::*;
+    use crate::test_util::build_batch;
+
+    #[test]
+    fn test_filter_project() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("k", DataType::Int32, true),
+            Field::new("s", DataType::Utf8, true),
+        ]));
+        let input = Arc::new(MemoryExec::try_new(
+            &[vec![
+                build_batch!(
+                    schema,
+                    vec![Some(1), Some(2), None, Some(3)],
+                    vec![Some("a"), Some("b"), Some("c"), Some("d")],
+                )?,
+                // no rows selected
+                build_batch!(schema, vec![Some(0), Some(1)], vec![Some("e"), Some("f")])?,
+                build_batch!(schema, vec![Some(4), Some(5)], vec![Some("g"), None])?,
+            ]],
+            schema.clone(),
+            None,
+        )?);
+
+        // SELECT s, k + 1 AS k1 WHERE k > 1
+        let predicate = Arc::new(BinaryExpr::new(
+            col("k", &schema)?,
+            Operator::Gt,
+            lit(ScalarValue::from(1)),
+        ));
+        let exprs = vec![
+            (col("s", &schema)?, "s".to_owned()),
+            (
+                Arc::new(BinaryExpr::new(
+                    col("k", &schema)?,
+                    Operator::Plus,
+                    lit(ScalarValue::from(1)),
+                )) as Arc<dyn PhysicalExpr>,
+                "k1".to_owned(),
+            ),
+        ];
+        let plan = Arc::new(FilterProjectExec::try_new(predicate, exprs, input)?);
+        assert_eq!(plan.projected_columns, Some(vec![0, 1]));
+        assert_eq!(plan.children().len(), 1);
+        assert_eq!(plan.children()[0].children().len(), 1);
+
+        let config = SessionConfig::new().with_batch_size(3);
+        let task_ctx = SessionContext::with_config(config).task_ctx();
+        let runtime = tokio::runtime::Runtime::new()?;
+        let batches = runtime.block_on(collect(plan.execute(0, task_ctx)?))?;
+
+        // selected rows of the first and last batches are coalesced
+        assert_eq!(batches.len(), 1);
+        let output_schema = Arc::new(Schema::new(vec![
+            Field::new("s", DataType::Utf8, true),
+            Field::new("k1", DataType::Int32, true),
+        ]));
+        assert_eq!(
+            batches[0],
+            RecordBatch::try_new(
+                output_schema,
+                vec![
+                    Arc::new(StringArray::from(vec![
+                        Some("b"),
+                        Some("d"),
+                        Some("g"),
+                        None,
+                    ])),
+                    Arc::new(Int32Array::from(vec![3, 4, 5, 6])),
+                ],
+            )?
+        );
+
+        let filter_metrics = plan.children()[0].metrics().unwrap();
+        assert_eq!(filter_metrics.output_rows(), Some(4));
+        assert_eq!(plan.metrics().unwrap().output_rows(), Some(4));
+        Ok(())
+    }
+}
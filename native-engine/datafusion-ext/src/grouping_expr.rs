@@ -0,0 +1,154 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Defines spark's grouping() of GROUPING SETS/ROLLUP/CUBE aggregations.
+//!
+//! spark resolves grouping(col) against the grouping-id column generated by
+//! ExpandExec, as `cast((gid >> shift) & 1 as tinyint)` where shift is the
+//! position of col counted from the last grouping column. grouping_id() is the
+//! grouping-id column itself and needs no native expression.
+
+use std::any::Any;
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+
+use datafusion::arrow::array::{Array, Int32Array, Int64Array, Int8Array};
+use datafusion::arrow::datatypes::{DataType, Schema};
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::physical_plan::{ColumnarValue, PhysicalExpr};
+
+/// Spark's grouping(col), returning 1 if col is aggregated in the grouping set
+/// of the row, or 0 if it is grouped by. null grouping ids yield nulls.
+#[derive(Debug)]
+pub struct GroupingExpr {
+    grouping_id: Arc<dyn PhysicalExpr>,
+    shift: u32,
+}
+
+impl GroupingExpr {
+    pub fn new(grouping_id: Arc<dyn PhysicalExpr>, shift: u32) -> Self {
+        Self { grouping_id, shift }
+    }
+
+    pub fn grouping_id(&self) -> &Arc<dyn PhysicalExpr> {
+        &self.grouping_id
+    }
+
+    pub fn shift(&self) -> u32 {
+        self.shift
+    }
+}
+
+impl Display for GroupingExpr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "grouping({}, {})", self.grouping_id, self.shift)
+    }
+}
+
+impl PhysicalExpr for GroupingExpr {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn data_type(&self, _input_schema: &Schema) -> Result<DataType> {
+        Ok(DataType::Int8)
+    }
+
+    fn nullable(&self, input_schema: &Schema) -> Result<bool> {
+        self.grouping_id.nullable(input_schema)
+    }
+
+    fn evaluate(&self, batch: &RecordBatch) -> Result<ColumnarValue> {
+        let grouping_id = self
+            .grouping_id
+            .evaluate(batch)?
+            .into_array(batch.num_rows());
+        let shift = self.shift;
+
+        // spark 3.0 generates integer grouping ids, later versions long ones
+        let result: Int8Array = match grouping_id.data_type() {
+            DataType::Int32 if shift < 32 => grouping_id
+                .as_any()
+                .downcast_ref::<Int32Array>()
+                .unwrap()
+                .iter()
+                .map(|gid| gid.map(|gid| ((gid >> shift) & 1) as i8))
+                .collect(),
+            DataType::Int64 if shift < 64 => grouping_id
+                .as_any()
+                .downcast_ref::<Int64Array>()
+                .unwrap()
+                .iter()
+                .map(|gid| gid.map(|gid| ((gid >> shift) & 1) as i8))
+                .collect(),
+            other => {
+                return Err(DataFusionError::Execution(format!(
+                    "GroupingExpr: unsupported grouping id {:?} with shift {}",
+                    other, shift
+                )))
+            }
+        };
+        Ok(ColumnarValue::Array(Arc::new(result)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use datafusion::arrow::array::ArrayRef;
+    use datafusion::arrow::datatypes::Field;
+    use datafusion::physical_plan::expressions::col;
+
+    use super::*;
+
+    #[test]
+    fn test_grouping() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![Field::new(
+            "spark_grouping_id",
+            DataType::Int32,
+            true,
+        )]));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![Arc::new(Int32Array::from(vec![
+                Some(0),
+                Some(1),
+                Some(3),
+                None,
+            ]))],
+        )?;
+
+        // rollup(a, b): a is shifted by 1 and b by 0
+        let grouping_a = GroupingExpr::new(col("spark_grouping_id", &schema)?, 1);
+        let grouping_b = GroupingExpr::new(col("spark_grouping_id", &schema)?, 0);
+        let evaluate = |expr: &GroupingExpr| -> Result<ArrayRef> {
+            Ok(expr.evaluate(&batch)?.into_array(batch.num_rows()))
+        };
+        assert_eq!(
+            evaluate(&grouping_a)?
+                .as_any()
+                .downcast_ref::<Int8Array>()
+                .unwrap(),
+            &Int8Array::from(vec![Some(0), Some(0), Some(1), None])
+        );
+        assert_eq!(
+            evaluate(&grouping_b)?
+                .as_any()
+                .downcast_ref::<Int8Array>()
+                .unwrap(),
+            &Int8Array::from(vec![Some(0), Some(1), Some(1), None])
+        );
+        Ok(())
+    }
+}
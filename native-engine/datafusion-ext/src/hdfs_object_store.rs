@@ -17,18 +17,22 @@ use datafusion::datafusion_data_access::object_store::{
     FileMetaStream, ListEntryStream, ObjectReader, ObjectStore,
 };
 use datafusion::datafusion_data_access::Result;
-use datafusion::datafusion_data_access::SizedFile;
+use datafusion::datafusion_data_access::{FileMeta, SizedFile};
 use futures::AsyncRead;
 use jni::objects::{GlobalRef, JObject};
-use jni::sys::jint;
+use jni::sys::{jboolean, jint, jlong, JNI_TRUE};
 
 use std::fmt::Debug;
 use std::fmt::Formatter;
-use std::io::{BufReader, Read};
+use std::io::{BufReader, Read, Write};
 use std::sync::Arc;
 
 use crate::jni_call;
 use crate::jni_call_static;
+use crate::jni_delete_local_ref;
+use crate::jni_get_array_length;
+use crate::jni_get_object_array_element;
+use crate::jni_get_string;
 use crate::jni_new_direct_byte_buffer;
 use crate::jni_new_global_ref;
 use crate::jni_new_object;
@@ -46,8 +50,12 @@ impl Debug for HDFSSingleFileObjectStore {
 
 #[async_trait::async_trait]
 impl ObjectStore for HDFSSingleFileObjectStore {
-    async fn list_file(&self, _prefix: &str) -> Result<FileMetaStream> {
-        unreachable!()
+    /// lists files under the directory recursively. paths of the files are
+    /// joined from the prefix and names of their parent directories, so that
+    /// they always start with the prefix
+    async fn list_file(&self, prefix: &str) -> Result<FileMetaStream> {
+        let files = list_hdfs_files(prefix).to_io_result()?;
+        Ok(Box::pin(futures::stream::iter(files.into_iter().map(Ok))))
     }
 
     async fn list_dir(
@@ -79,6 +87,45 @@ impl ObjectStore for HDFSSingleFileObjectStore {
     }
 }
 
+fn list_hdfs_files(prefix: &str) -> datafusion::error::Result<Vec<FileMeta>> {
+    let fs = jni_call_static!(JniBridge.getHDFSFileSystem() -> JObject)?;
+    let mut files = vec![];
+    let mut dirs = vec![prefix.trim_end_matches('/').to_owned()];
+    while let Some(dir) = dirs.pop() {
+        let path_str = jni_new_string!(dir.clone())?;
+        let path = jni_new_object!(HadoopPath, path_str)?;
+        let statuses = jni_call!(HadoopFileSystem(fs).listStatus(path) -> JObject)?;
+        for i in 0..jni_get_array_length!(statuses.into_inner())? {
+            let status = jni_get_object_array_element!(statuses.into_inner(), i)?;
+            let status_path = jni_call!(HadoopFileStatus(status).getPath() -> JObject)?;
+            let name = jni_call!(HadoopPath(status_path).getName() -> JObject)?;
+            let child = format!("{}/{}", dir, jni_get_string!(name.into())?);
+            let is_dir = jni_call!(HadoopFileStatus(status).isDirectory() -> jboolean)?;
+            if is_dir == JNI_TRUE {
+                dirs.push(child);
+            } else {
+                let size = jni_call!(HadoopFileStatus(status).getLen() -> jlong)?;
+                files.push(FileMeta {
+                    sized_file: SizedFile {
+                        path: child,
+                        size: size as u64,
+                    },
+                    last_modified: None,
+                });
+            }
+
+            // refs of statuses must be explicitly deleted for large directories
+            jni_delete_local_ref!(name)?;
+            jni_delete_local_ref!(status_path)?;
+            jni_delete_local_ref!(status)?;
+        }
+        jni_delete_local_ref!(statuses)?;
+        jni_delete_local_ref!(path)?;
+        jni_delete_local_ref!(path_str.into())?;
+    }
+    Ok(files)
+}
+
 #[derive(Clone)]
 struct HDFSObjectReader {
     file: SizedFile,
@@ -161,3 +208,84 @@ impl Drop for FSInputStreamWrapper {
         let _ = jni_call!(HadoopFSDataInputStream(self.0.as_obj()).close() -> ());
     }
 }
+
+/// bytes buffered by HDFSFileWriter before writing to the output stream
+const HDFS_WRITE_BUFFER_SIZE: usize = 1 << 20;
+
+/// Writes a file created through hadoop FileSystem, used by native writers of
+/// output files. writes are buffered to reduce JNI calls.
+pub struct HDFSFileWriter {
+    hdfs_output_stream: Option<GlobalRef>,
+    buf: Vec<u8>,
+    bytes_written: u64,
+}
+
+impl HDFSFileWriter {
+    /// creates the file, failing if it exists
+    pub fn try_new(path: &str) -> datafusion::error::Result<Self> {
+        log::debug!("HDFSFileWriter.create: {}", path);
+        let path_str = jni_new_string!(path)?;
+        let hdfs_output_stream = jni_new_global_ref!(jni_call_static!(
+            JniBridge.createFSDataOutputStream(path_str) -> JObject
+        )?)?;
+        Ok(Self {
+            hdfs_output_stream: Some(hdfs_output_stream),
+            buf: Vec::with_capacity(HDFS_WRITE_BUFFER_SIZE),
+            bytes_written: 0,
+        })
+    }
+
+    /// returns number of bytes written so far, including buffered bytes
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written + self.buf.len() as u64
+    }
+
+    /// flushes buffered bytes and closes the file, errors of closing are
+    /// only reported here
+    pub fn close(&mut self) -> std::io::Result<()> {
+        self.flush()?;
+        if let Some(hdfs_output_stream) = self.hdfs_output_stream.take() {
+            jni_call!(HadoopFSDataOutputStream(hdfs_output_stream.as_obj()).close() -> ())
+                .to_io_result()?;
+        }
+        Ok(())
+    }
+}
+
+impl Write for HDFSFileWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.buf.len() + buf.len() > HDFS_WRITE_BUFFER_SIZE {
+            self.flush()?;
+        }
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        let hdfs_output_stream = self.hdfs_output_stream.as_ref().ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::Other, "HDFSFileWriter is closed")
+        })?;
+        let buf = jni_new_direct_byte_buffer!(&mut self.buf[..]).to_io_result()?;
+        jni_call_static!(
+            JniBridge.writeFSDataOutputStream(hdfs_output_stream.as_obj(), buf) -> ()
+        )
+        .to_io_result()?;
+        self.bytes_written += self.buf.len() as u64;
+        self.buf.clear();
+        Ok(())
+    }
+}
+
+impl Drop for HDFSFileWriter {
+    fn drop(&mut self) {
+        // never panic in drop, otherwise the jvm process will be aborted
+        if let Some(hdfs_output_stream) = self.hdfs_output_stream.take() {
+            let _ = jni_call!(
+                HadoopFSDataOutputStream(hdfs_output_stream.as_obj()).close() -> ()
+            );
+        }
+    }
+}
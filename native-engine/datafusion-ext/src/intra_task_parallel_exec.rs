@@ -0,0 +1,370 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Executes CPU-heavy operators of a single task with multiple threads.
+//!
+//! each spark task executes one partition of the native plan, so a fat
+//! partition is sorted, aggregated or joined by a single core. with
+//! `intra_task_parallelism` greater than 1, the input partition of a sort,
+//! aggregation or partitioned hash join is repartitioned within the task into
+//! sub-partitions executed concurrently, whose outputs are merged back into
+//! the task partition:
+//!  * sorts: round-robin split, sorted outputs merged preserving the order
+//!  * partial aggregations: round-robin split, partial outputs concatenated
+//!  * final aggregations: hash split by grouping keys, outputs concatenated
+//!  * partitioned hash joins: both sides hash split by join keys, outputs
+//!    concatenated
+//!
+//! aggregations without grouping keys and other operators are not split.
+
+use std::any::Any;
+use std::fmt::Formatter;
+use std::sync::{Arc, Mutex};
+
+use datafusion::arrow::datatypes::SchemaRef;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::execution::context::TaskContext;
+use datafusion::physical_expr::PhysicalExpr;
+use datafusion::physical_plan::aggregates::{AggregateExec, AggregateMode};
+use datafusion::physical_plan::coalesce_partitions::CoalescePartitionsExec;
+use datafusion::physical_plan::expressions::PhysicalSortExpr;
+use datafusion::physical_plan::hash_join::{HashJoinExec, PartitionMode};
+use datafusion::physical_plan::metrics::MetricsSet;
+use datafusion::physical_plan::repartition::RepartitionExec;
+use datafusion::physical_plan::sorts::sort::SortExec;
+use datafusion::physical_plan::sorts::sort_preserving_merge::SortPreservingMergeExec;
+use datafusion::physical_plan::{
+    DisplayFormatType, ExecutionPlan, Partitioning, SendableRecordBatchStream, Statistics,
+};
+
+use crate::join_key_normalization::ProjectColumnsExec;
+use crate::partial_agg_skipping_exec::PartialAggSkippingExec;
+use crate::radix_sort_exec::RadixSortExec;
+use crate::with_new_children_preserving_partitioning;
+
+/// wraps sorts, aggregations and partitioned hash joins of the plan with
+/// IntraTaskParallelExec, returns the plan unchanged if parallelism <= 1
+pub fn parallelize_plan(
+    plan: Arc<dyn ExecutionPlan>,
+    parallelism: usize,
+) -> Result<Arc<dyn ExecutionPlan>> {
+    if parallelism <= 1 {
+        return Ok(plan);
+    }
+    let children = plan
+        .children()
+        .into_iter()
+        .map(|child| parallelize_plan(child, parallelism))
+        .collect::<Result<Vec<_>>>()?;
+    let plan = if children.is_empty() {
+        plan
+    } else {
+        with_new_children_preserving_partitioning(plan, children)?
+    };
+
+    if split_kind(&plan).is_none() {
+        return Ok(plan);
+    }
+    Ok(Arc::new(IntraTaskParallelExec {
+        op: plan,
+        parallelism,
+        executed_ops: Mutex::default(),
+    }))
+}
+
+enum SplitKind {
+    /// inputs are split round-robin, outputs are merged by the sort order
+    Sorted(Vec<PhysicalSortExpr>),
+    /// inputs are split round-robin, outputs are concatenated
+    RoundRobin,
+    /// each input is hash split by its keys, outputs are concatenated
+    Hashed(Vec<Vec<Arc<dyn PhysicalExpr>>>),
+}
+
+fn split_kind(plan: &Arc<dyn ExecutionPlan>) -> Option<SplitKind> {
+    // wrappers sharing the children of the wrapped operator are split as the
+    // wrapped operator: partial aggregations skipping aggregation adaptively,
+    // and joins projecting out normalized join keys
+    if let Some(skipping) = plan.as_any().downcast_ref::<PartialAggSkippingExec>() {
+        return split_kind(skipping.agg());
+    }
+    if let Some(project) = plan.as_any().downcast_ref::<ProjectColumnsExec>() {
+        return split_kind(project.input());
+    }
+    if let Some(sort) = plan.as_any().downcast_ref::<SortExec>() {
+        return Some(SplitKind::Sorted(sort.expr().to_vec()));
+    }
+    if let Some(sort) = plan.as_any().downcast_ref::<RadixSortExec>() {
+        return Some(SplitKind::Sorted(vec![sort.sort_expr().clone()]));
+    }
+    if let Some(agg) = plan.as_any().downcast_ref::<AggregateExec>() {
+        if agg.group_expr().is_empty() {
+            return None;
+        }
+        return Some(match agg.mode() {
+            AggregateMode::Partial => SplitKind::RoundRobin,
+            _ => {
+                let keys = agg.group_expr().iter().map(|(expr, _)| expr.clone());
+                SplitKind::Hashed(vec![keys.collect()])
+            }
+        });
+    }
+    if let Some(join) = plan.as_any().downcast_ref::<HashJoinExec>() {
+        if !matches!(join.partition_mode(), PartitionMode::Partitioned) {
+            return None;
+        }
+        let (left_keys, right_keys) = join
+            .on()
+            .iter()
+            .map(|(l, r)| {
+                let l: Arc<dyn PhysicalExpr> = Arc::new(l.clone());
+                let r: Arc<dyn PhysicalExpr> = Arc::new(r.clone());
+                (l, r)
+            })
+            .unzip();
+        return Some(SplitKind::Hashed(vec![left_keys, right_keys]));
+    }
+    None
+}
+
+/// Executes the task partition of an operator as sub-partitions running
+/// concurrently.
+///
+/// metrics are those of the operators executing the sub-partitions of all
+/// executed task partitions.
+#[derive(Debug)]
+pub struct IntraTaskParallelExec {
+    op: Arc<dyn ExecutionPlan>,
+    parallelism: usize,
+    executed_ops: Mutex<Vec<Arc<dyn ExecutionPlan>>>,
+}
+
+impl ExecutionPlan for IntraTaskParallelExec {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.op.schema()
+    }
+
+    fn output_partitioning(&self) -> Partitioning {
+        self.op.output_partitioning()
+    }
+
+    fn output_ordering(&self) -> Option<&[PhysicalSortExpr]> {
+        self.op.output_ordering()
+    }
+
+    crate::transparent_plan_tree!(
+        op,
+        |self, op| Self {
+            op,
+            parallelism: self.parallelism,
+            executed_ops: Mutex::default()
+        },
+        own_metrics
+    );
+
+    fn execute(
+        &self,
+        partition: usize,
+        context: Arc<TaskContext>,
+    ) -> Result<SendableRecordBatchStream> {
+        let split_kind = split_kind(&self.op).ok_or_else(|| {
+            DataFusionError::Plan(format!(
+                "IntraTaskParallelExec: unsupported operator: {:?}",
+                self.op
+            ))
+        })?;
+
+        // split the task partition of each child into sub-partitions
+        let children = self
+            .op
+            .children()
+            .into_iter()
+            .enumerate()
+            .map(|(i, child)| {
+                let partitioning = match &split_kind {
+                    SplitKind::Hashed(keys) => {
+                        Partitioning::Hash(keys[i].clone(), self.parallelism)
+                    }
+                    _ => Partitioning::RoundRobinBatch(self.parallelism),
+                };
+                let pinned = Arc::new(PinnedPartitionExec {
+                    input: child,
+                    partition,
+                });
+                let split: Arc<dyn ExecutionPlan> =
+                    Arc::new(RepartitionExec::try_new(pinned, partitioning)?);
+                Ok(split)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let executed_op =
+            with_new_children_preserving_partitioning(self.op.clone(), children)?;
+        self.executed_ops.lock().unwrap().push(executed_op.clone());
+
+        // merge sub-partitions back into the task partition
+        let merged: Arc<dyn ExecutionPlan> = match split_kind {
+            SplitKind::Sorted(expr) => {
+                Arc::new(SortPreservingMergeExec::new(expr, executed_op))
+            }
+            _ => Arc::new(CoalescePartitionsExec::new(executed_op)),
+        };
+        merged.execute(0, context)
+    }
+
+    fn metrics(&self) -> Option<MetricsSet> {
+        let executed_ops = self.executed_ops.lock().unwrap();
+        if executed_ops.is_empty() {
+            return self.op.metrics();
+        }
+        let mut metrics = MetricsSet::new();
+        for executed_op in executed_ops.iter() {
+            for metric in executed_op.metrics().iter().flat_map(|m| m.iter()) {
+                metrics.push(metric.clone());
+            }
+        }
+        Some(metrics)
+    }
+
+    fn fmt_as(&self, t: DisplayFormatType, f: &mut Formatter) -> std::fmt::Result {
+        self.op.fmt_as(t, f)
+    }
+
+    fn statistics(&self) -> Statistics {
+        self.op.statistics()
+    }
+}
+
+/// A single partition plan of one partition of the input.
+#[derive(Debug)]
+struct PinnedPartitionExec {
+    input: Arc<dyn ExecutionPlan>,
+    partition: usize,
+}
+
+impl ExecutionPlan for PinnedPartitionExec {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.input.schema()
+    }
+
+    fn output_partitioning(&self) -> Partitioning {
+        Partitioning::UnknownPartitioning(1)
+    }
+
+    fn output_ordering(&self) -> Option<&[PhysicalSortExpr]> {
+        self.input.output_ordering()
+    }
+
+    fn children(&self) -> Vec<Arc<dyn ExecutionPlan>> {
+        vec![self.input.clone()]
+    }
+
+    fn with_new_children(
+        self: Arc<Self>,
+        children: Vec<Arc<dyn ExecutionPlan>>,
+    ) -> Result<Arc<dyn ExecutionPlan>> {
+        Ok(Arc::new(Self {
+            input: children[0].clone(),
+            partition: self.partition,
+        }))
+    }
+
+    fn execute(
+        &self,
+        partition: usize,
+        context: Arc<TaskContext>,
+    ) -> Result<SendableRecordBatchStream> {
+        if partition != 0 {
+            return Err(DataFusionError::Execution(format!(
+                "PinnedPartitionExec: invalid partition {}",
+                partition
+            )));
+        }
+        self.input.execute(self.partition, context)
+    }
+
+    fn fmt_as(&self, _t: DisplayFormatType, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "PinnedPartitionExec: partition={}", self.partition)
+    }
+
+    fn statistics(&self) -> Statistics {
+        Statistics::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use datafusion::arrow::array::Int32Array;
+    use datafusion::arrow::compute::SortOptions;
+    use datafusion::arrow::datatypes::{DataType, Field, Schema};
+    use datafusion::arrow::record_batch::RecordBatch;
+    use datafusion::error::Result;
+    use datafusion::physical_expr::expressions::Column;
+    use datafusion::physical_plan::common::collect;
+    use datafusion::physical_plan::memory::MemoryExec;
+    use datafusion::prelude::SessionContext;
+
+    use super::*;
+    use crate::test_util::build_batch;
+
+    #[test]
+    fn test_parallel_sort() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int32, false)]));
+        let partitions = vec![
+            vec![build_batch!(schema, vec![100])?],
+            vec![
+                build_batch!(schema, vec![5, 3])?,
+                build_batch!(schema, vec![4, 1])?,
+                build_batch!(schema, vec![2, 6])?,
+            ],
+        ];
+        let input = Arc::new(MemoryExec::try_new(&partitions, schema, None)?);
+        let sort_expr = PhysicalSortExpr {
+            expr: Arc::new(Column::new("a", 0)),
+            options: SortOptions::default(),
+        };
+        let sort = Arc::new(SortExec::new_with_partitioning(
+            vec![sort_expr],
+            input,
+            true,
+        ));
+
+        let plan = parallelize_plan(sort, 3)?;
+        assert!(plan.as_any().is::<IntraTaskParallelExec>());
+        assert_eq!(plan.output_partitioning().partition_count(), 2);
+
+        let runtime = tokio::runtime::Runtime::new()?;
+        let task_ctx = SessionContext::new().task_ctx();
+        let stream = plan.execute(1, task_ctx)?;
+        let batches = runtime.block_on(collect(stream))?;
+        let values = batches
+            .iter()
+            .flat_map(|batch| {
+                let array = batch.column(0).as_any().downcast_ref::<Int32Array>();
+                array.unwrap().values().to_vec()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(values, vec![1, 2, 3, 4, 5, 6]);
+        assert!(plan.metrics().is_some());
+        Ok(())
+    }
+}
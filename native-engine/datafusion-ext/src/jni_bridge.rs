@@ -12,6 +12,11 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::time::Duration;
+use std::time::Instant;
+
+use datafusion::error::DataFusionError;
+use datafusion::error::Result;
 use jni::errors::Result as JniResult;
 use jni::objects::JClass;
 use jni::objects::JMethodID;
@@ -23,6 +28,7 @@ use jni::JNIEnv;
 use jni::JavaVM;
 use once_cell::sync::OnceCell;
 
+use crate::native_conf::native_conf;
 use crate::ResultExt;
 
 thread_local! {
@@ -54,18 +60,21 @@ macro_rules! jni_map_error_with_env {
             Ok(result) => datafusion::error::Result::Ok(result),
             Err(jni::errors::Error::JavaException) => {
                 let _ = $env.exception_describe();
-                Err(datafusion::error::DataFusionError::External(
-                    format!("Java exception thrown at {}:{}", file!(), line!()).into(),
+                Err(datafusion::error::DataFusionError::from(
+                    $crate::blaze_error::BlazeError::JniError(format!(
+                        "Java exception thrown at {}:{}",
+                        file!(),
+                        line!()
+                    )),
                 ))
             }
-            Err(err) => Err(datafusion::error::DataFusionError::External(
-                format!(
+            Err(err) => Err(datafusion::error::DataFusionError::from(
+                $crate::blaze_error::BlazeError::JniError(format!(
                     "Unknown JNI error occurred at {}:{}: {:?}",
                     file!(),
                     line!(),
                     err
-                )
-                .into(),
+                )),
             )),
         }
     }};
@@ -186,6 +195,27 @@ macro_rules! jni_convert_byte_array {
     }};
 }
 
+#[macro_export]
+macro_rules! jni_get_array_length {
+    ($value:expr) => {{
+        $crate::jni_bridge::THREAD_JNIENV.with(|env| {
+            $crate::jni_map_error_with_env!(env, env.get_array_length($value))
+        })
+    }};
+}
+
+#[macro_export]
+macro_rules! jni_get_object_array_element {
+    ($value:expr, $index:expr) => {{
+        $crate::jni_bridge::THREAD_JNIENV.with(|env| {
+            $crate::jni_map_error_with_env!(
+                env,
+                env.get_object_array_element($value, $index)
+            )
+        })
+    }};
+}
+
 #[macro_export]
 macro_rules! jni_new_global_ref {
     ($obj:expr) => {{
@@ -267,6 +297,7 @@ pub struct JavaClasses<'a> {
     pub cClass: JavaClass<'a>,
     pub cJavaRuntimeException: JavaRuntimeException<'a>,
     pub cJavaSeekableByteChannel: JavaSeekableByteChannel<'a>,
+    pub cJavaReadableByteChannel: JavaReadableByteChannel<'a>,
     pub cJavaBoolean: JavaBoolean<'a>,
     pub cJavaLong: JavaLong<'a>,
     pub cJavaList: JavaList<'a>,
@@ -276,16 +307,24 @@ pub struct JavaClasses<'a> {
     pub cScalaIterator: ScalaIterator<'a>,
     pub cScalaTuple2: ScalaTuple2<'a>,
     pub cScalaFunction0: ScalaFunction0<'a>,
+    pub cScalaFunction2: ScalaFunction2<'a>,
+    pub cScalaFunction3: ScalaFunction3<'a>,
 
     pub cHadoopFileSystem: HadoopFileSystem<'a>,
     pub cHadoopPath: HadoopPath<'a>,
     pub cHadoopFileStatus: HadoopFileStatus<'a>,
     pub cHadoopFSDataInputStream: HadoopFSDataInputStream<'a>,
+    pub cHadoopFSDataOutputStream: HadoopFSDataOutputStream<'a>,
 
     pub cSparkSQLMetric: SparkSQLMetric<'a>,
     pub cSparkMetricNode: SparkMetricNode<'a>,
 
     pub cBlazeCallNativeWrapper: BlazeCallNativeWrapper<'a>,
+    pub cBlazeRssPartitionWriterBase: BlazeRssPartitionWriterBase<'a>,
+    pub cBlazeMemoryConsumer: BlazeMemoryConsumer<'a>,
+    pub cBlazeNativeShuffleWriteMetrics: BlazeNativeShuffleWriteMetrics<'a>,
+    pub cBlazeShuffleReadException: BlazeShuffleReadException<'a>,
+    pub cBlazeArrowFFIExporter: BlazeArrowFFIExporter<'a>,
 }
 
 #[allow(clippy::non_send_fields_in_send_ty)]
@@ -319,6 +358,7 @@ impl JavaClasses<'static> {
                 cClass: JavaClass::new(env).unwrap(),
                 cJavaRuntimeException: JavaRuntimeException::new(env).unwrap(),
                 cJavaSeekableByteChannel: JavaSeekableByteChannel::new(env).unwrap(),
+                cJavaReadableByteChannel: JavaReadableByteChannel::new(env).unwrap(),
                 cJavaBoolean: JavaBoolean::new(env).unwrap(),
                 cJavaLong: JavaLong::new(env).unwrap(),
                 cJavaList: JavaList::new(env).unwrap(),
@@ -328,22 +368,37 @@ impl JavaClasses<'static> {
                 cScalaIterator: ScalaIterator::new(env).unwrap(),
                 cScalaTuple2: ScalaTuple2::new(env).unwrap(),
                 cScalaFunction0: ScalaFunction0::new(env).unwrap(),
+                cScalaFunction2: ScalaFunction2::new(env).unwrap(),
+                cScalaFunction3: ScalaFunction3::new(env).unwrap(),
 
                 cHadoopFileSystem: HadoopFileSystem::new(env).unwrap(),
                 cHadoopPath: HadoopPath::new(env).unwrap(),
                 cHadoopFileStatus: HadoopFileStatus::new(env).unwrap(),
                 cHadoopFSDataInputStream: HadoopFSDataInputStream::new(env).unwrap(),
+                cHadoopFSDataOutputStream: HadoopFSDataOutputStream::new(env).unwrap(),
 
                 cSparkSQLMetric: SparkSQLMetric::new(env).unwrap(),
                 cSparkMetricNode: SparkMetricNode::new(env).unwrap(),
 
                 cBlazeCallNativeWrapper: BlazeCallNativeWrapper::new(env).unwrap(),
+                cBlazeRssPartitionWriterBase: BlazeRssPartitionWriterBase::new(env)
+                    .unwrap(),
+                cBlazeMemoryConsumer: BlazeMemoryConsumer::new(env).unwrap(),
+                cBlazeNativeShuffleWriteMetrics: BlazeNativeShuffleWriteMetrics::new(env)
+                    .unwrap(),
+                cBlazeShuffleReadException: BlazeShuffleReadException::new(env).unwrap(),
+                cBlazeArrowFFIExporter: BlazeArrowFFIExporter::new(env).unwrap(),
             };
             log::info!("Initializing JavaClasses finished");
             java_classes
         });
     }
 
+    /// returns true if init() has completed
+    pub fn initialized() -> bool {
+        JNI_JAVA_CLASSES.get().is_some()
+    }
+
     pub fn get() -> &'static JavaClasses<'static> {
         unsafe {
             // safety: JNI_JAVA_CLASSES must be initialized frist
@@ -352,6 +407,55 @@ impl JavaClasses<'static> {
     }
 }
 
+/// gets a resource registered by the JVM side with JniBridge.putResource().
+/// resources may be registered a little later than native plans are executed
+/// during executor startup, so missing resources are polled with exponential
+/// backoff until resource_wait_timeout_ms elapses.
+pub fn get_resource(resource_id: &str) -> Result<JObject<'static>> {
+    let conf = native_conf();
+    let timeout = Duration::from_millis(conf.resource_wait_timeout_ms);
+    let backoff = Duration::from_millis(conf.resource_retry_backoff_ms);
+    let resource = retry_with_backoff(timeout, backoff, || {
+        let resource = jni_call_static!(
+            JniBridge.getResource(jni_new_string!(resource_id)?) -> JObject
+        )?;
+        Ok(Some(resource).filter(|resource| !resource.is_null()))
+    })?;
+    resource.ok_or_else(|| {
+        DataFusionError::Execution(format!(
+            "Blaze resource not found after waiting {} ms: {}",
+            timeout.as_millis(),
+            resource_id
+        ))
+    })
+}
+
+/// calls `f` until it returns Some or an error, sleeping between attempts
+/// with doubled backoff (capped at 1 second). returns None if `timeout` has
+/// elapsed, `f` is always called at least once.
+fn retry_with_backoff<T>(
+    timeout: Duration,
+    backoff: Duration,
+    mut f: impl FnMut() -> Result<Option<T>>,
+) -> Result<Option<T>> {
+    const MAX_BACKOFF: Duration = Duration::from_secs(1);
+    let start = Instant::now();
+    let mut backoff = backoff.max(Duration::from_millis(1));
+    loop {
+        if let Some(value) = f()? {
+            return Ok(Some(value));
+        }
+        let elapsed = start.elapsed();
+        if elapsed >= timeout {
+            return Ok(None);
+        }
+        let delay = backoff.min(timeout - elapsed);
+        log::warn!("Blaze resource not yet registered, retrying in {:?}", delay);
+        std::thread::sleep(delay);
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
 #[allow(non_snake_case)]
 pub struct JniBridge<'a> {
     pub class: JClass<'a>,
@@ -369,6 +473,24 @@ pub struct JniBridge<'a> {
     pub method_getTaskContext_ret: JavaType,
     pub method_readFSDataInputStream: JStaticMethodID<'a>,
     pub method_readFSDataInputStream_ret: JavaType,
+    pub method_createFSDataOutputStream: JStaticMethodID<'a>,
+    pub method_createFSDataOutputStream_ret: JavaType,
+    pub method_writeFSDataOutputStream: JStaticMethodID<'a>,
+    pub method_writeFSDataOutputStream_ret: JavaType,
+    pub method_newMemoryConsumer: JStaticMethodID<'a>,
+    pub method_newMemoryConsumer_ret: JavaType,
+    pub method_getTaskAttemptId: JStaticMethodID<'a>,
+    pub method_getTaskAttemptId_ret: JavaType,
+    pub method_fetchSegments: JStaticMethodID<'a>,
+    pub method_fetchSegments_ret: JavaType,
+    pub method_nextSegmentFully: JStaticMethodID<'a>,
+    pub method_nextSegmentFully_ret: JavaType,
+    pub method_nativeLog: JStaticMethodID<'a>,
+    pub method_nativeLog_ret: JavaType,
+    pub method_newNativeException: JStaticMethodID<'a>,
+    pub method_newNativeException_ret: JavaType,
+    pub method_updateNativeAccumulator: JStaticMethodID<'a>,
+    pub method_updateNativeAccumulator_ret: JavaType,
 }
 impl<'a> JniBridge<'a> {
     pub const SIG_TYPE: &'static str = "org/apache/spark/sql/blaze/JniBridge";
@@ -427,6 +549,68 @@ impl<'a> JniBridge<'a> {
                 "(Lorg/apache/hadoop/fs/FSDataInputStream;Ljava/nio/ByteBuffer;J)I",
             )?,
             method_readFSDataInputStream_ret: JavaType::Primitive(Primitive::Int),
+            method_createFSDataOutputStream: env.get_static_method_id(
+                class,
+                "createFSDataOutputStream",
+                "(Ljava/lang/String;)Lorg/apache/hadoop/fs/FSDataOutputStream;",
+            )?,
+            method_createFSDataOutputStream_ret: JavaType::Object(
+                HadoopFSDataOutputStream::SIG_TYPE.to_owned(),
+            ),
+            method_writeFSDataOutputStream: env.get_static_method_id(
+                class,
+                "writeFSDataOutputStream",
+                "(Lorg/apache/hadoop/fs/FSDataOutputStream;Ljava/nio/ByteBuffer;)V",
+            )?,
+            method_writeFSDataOutputStream_ret: JavaType::Primitive(Primitive::Void),
+            method_newMemoryConsumer: env.get_static_method_id(
+                class,
+                "newMemoryConsumer",
+                "()Lorg/apache/spark/sql/blaze/BlazeMemoryConsumer;",
+            )?,
+            method_newMemoryConsumer_ret: JavaType::Object(
+                BlazeMemoryConsumer::SIG_TYPE.to_owned(),
+            ),
+            method_getTaskAttemptId: env.get_static_method_id(
+                class,
+                "getTaskAttemptId",
+                "()J",
+            )?,
+            method_getTaskAttemptId_ret: JavaType::Primitive(Primitive::Long),
+            method_fetchSegments: env.get_static_method_id(
+                class,
+                "fetchSegments",
+                "(Lscala/collection/Iterator;IJZ)[[B",
+            )?,
+            method_fetchSegments_ret: JavaType::Array(Box::new(JavaType::Array(
+                Box::new(JavaType::Primitive(Primitive::Byte)),
+            ))),
+            method_nextSegmentFully: env.get_static_method_id(
+                class,
+                "nextSegmentFully",
+                "(Lscala/collection/Iterator;Ljava/nio/ByteBuffer;Z)J",
+            )?,
+            method_nextSegmentFully_ret: JavaType::Primitive(Primitive::Long),
+            method_nativeLog: env.get_static_method_id(
+                class,
+                "nativeLog",
+                "(ILjava/lang/String;Ljava/lang/String;)V",
+            )?,
+            method_nativeLog_ret: JavaType::Primitive(Primitive::Void),
+            method_newNativeException: env.get_static_method_id(
+                class,
+                "newNativeException",
+                "(Ljava/lang/String;Ljava/lang/String;)Ljava/lang/Throwable;",
+            )?,
+            method_newNativeException_ret: JavaType::Object(
+                "java/lang/Throwable".to_owned(),
+            ),
+            method_updateNativeAccumulator: env.get_static_method_id(
+                class,
+                "updateNativeAccumulator",
+                "(Ljava/lang/String;J)V",
+            )?,
+            method_updateNativeAccumulator_ret: JavaType::Primitive(Primitive::Void),
         })
     }
 }
@@ -506,6 +690,29 @@ impl<'a> JavaSeekableByteChannel<'a> {
     }
 }
 
+#[allow(non_snake_case)]
+pub struct JavaReadableByteChannel<'a> {
+    pub class: JClass<'a>,
+    pub method_read: JMethodID<'a>,
+    pub method_read_ret: JavaType,
+    pub method_close: JMethodID<'a>,
+    pub method_close_ret: JavaType,
+}
+impl<'a> JavaReadableByteChannel<'a> {
+    pub const SIG_TYPE: &'static str = "java/nio/channels/ReadableByteChannel";
+
+    pub fn new(env: &JNIEnv<'a>) -> JniResult<JavaReadableByteChannel<'a>> {
+        let class = get_global_jclass(env, Self::SIG_TYPE)?;
+        Ok(JavaReadableByteChannel {
+            class,
+            method_read: env.get_method_id(class, "read", "(Ljava/nio/ByteBuffer;)I")?,
+            method_read_ret: JavaType::Primitive(Primitive::Int),
+            method_close: env.get_method_id(class, "close", "()V")?,
+            method_close_ret: JavaType::Primitive(Primitive::Void),
+        })
+    }
+}
+
 #[allow(non_snake_case)]
 pub struct JavaBoolean<'a> {
     pub class: JClass<'a>,
@@ -686,6 +893,55 @@ impl<'a> ScalaFunction0<'a> {
     }
 }
 
+#[allow(non_snake_case)]
+pub struct ScalaFunction2<'a> {
+    pub class: JClass<'a>,
+    pub method_apply: JMethodID<'a>,
+    pub method_apply_ret: JavaType,
+}
+impl<'a> ScalaFunction2<'a> {
+    pub const SIG_TYPE: &'static str = "scala/Function2";
+
+    pub fn new(env: &JNIEnv<'a>) -> JniResult<ScalaFunction2<'a>> {
+        let class = get_global_jclass(env, Self::SIG_TYPE)?;
+        Ok(ScalaFunction2 {
+            class,
+            method_apply: env.get_method_id(
+                class,
+                "apply",
+                "(Ljava/lang/Object;Ljava/lang/Object;)Ljava/lang/Object;",
+            )?,
+            method_apply_ret: JavaType::Object("java/lang/Object".to_owned()),
+        })
+    }
+}
+
+#[allow(non_snake_case)]
+pub struct ScalaFunction3<'a> {
+    pub class: JClass<'a>,
+    pub method_apply: JMethodID<'a>,
+    pub method_apply_ret: JavaType,
+}
+impl<'a> ScalaFunction3<'a> {
+    pub const SIG_TYPE: &'static str = "scala/Function3";
+
+    pub fn new(env: &JNIEnv<'a>) -> JniResult<ScalaFunction3<'a>> {
+        let class = get_global_jclass(env, Self::SIG_TYPE)?;
+        Ok(ScalaFunction3 {
+            class,
+            method_apply: env.get_method_id(
+                class,
+                "apply",
+                concat!(
+                    "(Ljava/lang/Object;Ljava/lang/Object;Ljava/lang/Object;)",
+                    "Ljava/lang/Object;"
+                ),
+            )?,
+            method_apply_ret: JavaType::Object("java/lang/Object".to_owned()),
+        })
+    }
+}
+
 #[allow(non_snake_case)]
 pub struct HadoopFileSystem<'a> {
     pub class: JClass<'a>,
@@ -693,6 +949,8 @@ pub struct HadoopFileSystem<'a> {
     pub method_getFileStatus_ret: JavaType,
     pub method_open: JMethodID<'a>,
     pub method_open_ret: JavaType,
+    pub method_listStatus: JMethodID<'a>,
+    pub method_listStatus_ret: JavaType,
 }
 impl<'a> HadoopFileSystem<'a> {
     pub const SIG_TYPE: &'static str = "org/apache/hadoop/fs/FileSystem";
@@ -717,6 +975,14 @@ impl<'a> HadoopFileSystem<'a> {
             method_open_ret: JavaType::Object(
                 HadoopFSDataInputStream::SIG_TYPE.to_owned(),
             ),
+            method_listStatus: env.get_method_id(
+                class,
+                "listStatus",
+                "(Lorg/apache/hadoop/fs/Path;)[Lorg/apache/hadoop/fs/FileStatus;",
+            )?,
+            method_listStatus_ret: JavaType::Array(Box::new(JavaType::Object(
+                HadoopFileStatus::SIG_TYPE.to_owned(),
+            ))),
         })
     }
 }
@@ -725,6 +991,8 @@ impl<'a> HadoopFileSystem<'a> {
 pub struct HadoopPath<'a> {
     pub class: JClass<'a>,
     pub ctor: JMethodID<'a>,
+    pub method_getName: JMethodID<'a>,
+    pub method_getName_ret: JavaType,
 }
 impl<'a> HadoopPath<'a> {
     pub const SIG_TYPE: &'static str = "org/apache/hadoop/fs/Path";
@@ -734,6 +1002,12 @@ impl<'a> HadoopPath<'a> {
         Ok(HadoopPath {
             class,
             ctor: env.get_method_id(class, "<init>", "(Ljava/lang/String;)V")?,
+            method_getName: env.get_method_id(
+                class,
+                "getName",
+                "()Ljava/lang/String;",
+            )?,
+            method_getName_ret: JavaType::Object("java/lang/String".to_owned()),
         })
     }
 }
@@ -743,6 +1017,10 @@ pub struct HadoopFileStatus<'a> {
     pub class: JClass<'a>,
     pub method_getLen: JMethodID<'a>,
     pub method_getLen_ret: JavaType,
+    pub method_getPath: JMethodID<'a>,
+    pub method_getPath_ret: JavaType,
+    pub method_isDirectory: JMethodID<'a>,
+    pub method_isDirectory_ret: JavaType,
 }
 impl<'a> HadoopFileStatus<'a> {
     pub const SIG_TYPE: &'static str = "org/apache/hadoop/fs/FileStatus";
@@ -753,6 +1031,14 @@ impl<'a> HadoopFileStatus<'a> {
             class,
             method_getLen: env.get_method_id(class, "getLen", "()J")?,
             method_getLen_ret: JavaType::Primitive(Primitive::Long),
+            method_getPath: env.get_method_id(
+                class,
+                "getPath",
+                "()Lorg/apache/hadoop/fs/Path;",
+            )?,
+            method_getPath_ret: JavaType::Object(HadoopPath::SIG_TYPE.to_owned()),
+            method_isDirectory: env.get_method_id(class, "isDirectory", "()Z")?,
+            method_isDirectory_ret: JavaType::Primitive(Primitive::Boolean),
         })
     }
 }
@@ -784,6 +1070,25 @@ impl<'a> HadoopFSDataInputStream<'a> {
     }
 }
 
+#[allow(non_snake_case)]
+pub struct HadoopFSDataOutputStream<'a> {
+    pub class: JClass<'a>,
+    pub method_close: JMethodID<'a>,
+    pub method_close_ret: JavaType,
+}
+impl<'a> HadoopFSDataOutputStream<'a> {
+    pub const SIG_TYPE: &'static str = "org/apache/hadoop/fs/FSDataOutputStream";
+
+    pub fn new(env: &JNIEnv<'a>) -> JniResult<HadoopFSDataOutputStream<'a>> {
+        let class = get_global_jclass(env, Self::SIG_TYPE)?;
+        Ok(HadoopFSDataOutputStream {
+            class,
+            method_close: env.get_method_id(class, "close", "()V")?,
+            method_close_ret: JavaType::Primitive(Primitive::Void),
+        })
+    }
+}
+
 #[allow(non_snake_case)]
 pub struct SparkSQLMetric<'a> {
     pub class: JClass<'a>,
@@ -841,6 +1146,20 @@ pub struct BlazeCallNativeWrapper<'a> {
     pub method_isFinished_ret: JavaType,
     pub method_getRawTaskDefinition: JMethodID<'a>,
     pub method_getRawTaskDefinition_ret: JavaType,
+    pub method_getOutputPrefetchBytes: JMethodID<'a>,
+    pub method_getOutputPrefetchBytes_ret: JavaType,
+    pub method_getBatchSize: JMethodID<'a>,
+    pub method_getBatchSize_ret: JavaType,
+    pub method_isRowOutputRequested: JMethodID<'a>,
+    pub method_isRowOutputRequested_ret: JavaType,
+    pub method_isNativeExplainRequested: JMethodID<'a>,
+    pub method_isNativeExplainRequested_ret: JavaType,
+    pub method_enableRowOutput: JMethodID<'a>,
+    pub method_enableRowOutput_ret: JavaType,
+    pub method_setOutputRows: JMethodID<'a>,
+    pub method_setOutputRows_ret: JavaType,
+    pub method_setOutputPartitionId: JMethodID<'a>,
+    pub method_setOutputPartitionId_ret: JavaType,
     pub method_getMetrics: JMethodID<'a>,
     pub method_getMetrics_ret: JavaType,
     pub method_enqueueWithTimeout: JMethodID<'a>,
@@ -866,6 +1185,32 @@ impl<'a> BlazeCallNativeWrapper<'a> {
             method_getRawTaskDefinition_ret: JavaType::Array(Box::new(
                 JavaType::Primitive(Primitive::Byte),
             )),
+            method_getOutputPrefetchBytes: env
+                .get_method_id(class, "getOutputPrefetchBytes", "()J")
+                .unwrap(),
+            method_getOutputPrefetchBytes_ret: JavaType::Primitive(Primitive::Long),
+            method_getBatchSize: env.get_method_id(class, "getBatchSize", "()J").unwrap(),
+            method_getBatchSize_ret: JavaType::Primitive(Primitive::Long),
+            method_isRowOutputRequested: env
+                .get_method_id(class, "isRowOutputRequested", "()Z")
+                .unwrap(),
+            method_isRowOutputRequested_ret: JavaType::Primitive(Primitive::Boolean),
+            method_isNativeExplainRequested: env
+                .get_method_id(class, "isNativeExplainRequested", "()Z")
+                .unwrap(),
+            method_isNativeExplainRequested_ret: JavaType::Primitive(Primitive::Boolean),
+            method_enableRowOutput: env
+                .get_method_id(class, "enableRowOutput", "(I)V")
+                .unwrap(),
+            method_enableRowOutput_ret: JavaType::Primitive(Primitive::Void),
+            method_setOutputRows: env
+                .get_method_id(class, "setOutputRows", "(Ljava/nio/ByteBuffer;)V")
+                .unwrap(),
+            method_setOutputRows_ret: JavaType::Primitive(Primitive::Void),
+            method_setOutputPartitionId: env
+                .get_method_id(class, "setOutputPartitionId", "(I)V")
+                .unwrap(),
+            method_setOutputPartitionId_ret: JavaType::Primitive(Primitive::Void),
             method_getMetrics: env
                 .get_method_id(
                     class,
@@ -892,6 +1237,87 @@ impl<'a> BlazeCallNativeWrapper<'a> {
     }
 }
 
+#[allow(non_snake_case)]
+pub struct BlazeRssPartitionWriterBase<'a> {
+    pub class: JClass<'a>,
+    pub method_write: JMethodID<'a>,
+    pub method_write_ret: JavaType,
+    pub method_flush: JMethodID<'a>,
+    pub method_flush_ret: JavaType,
+}
+impl<'a> BlazeRssPartitionWriterBase<'a> {
+    pub const SIG_TYPE: &'static str =
+        "org/apache/spark/sql/blaze/RssPartitionWriterBase";
+
+    pub fn new(env: &JNIEnv<'a>) -> JniResult<BlazeRssPartitionWriterBase<'a>> {
+        let class = get_global_jclass(env, Self::SIG_TYPE)?;
+        Ok(BlazeRssPartitionWriterBase {
+            class,
+            method_write: env
+                .get_method_id(class, "write", "(ILjava/nio/ByteBuffer;)V")
+                .unwrap(),
+            method_write_ret: JavaType::Primitive(Primitive::Void),
+            method_flush: env.get_method_id(class, "flush", "()V").unwrap(),
+            method_flush_ret: JavaType::Primitive(Primitive::Void),
+        })
+    }
+}
+
+#[allow(non_snake_case)]
+pub struct BlazeMemoryConsumer<'a> {
+    pub class: JClass<'a>,
+    pub method_acquire: JMethodID<'a>,
+    pub method_acquire_ret: JavaType,
+    pub method_release: JMethodID<'a>,
+    pub method_release_ret: JavaType,
+    pub method_takeSpillRequest: JMethodID<'a>,
+    pub method_takeSpillRequest_ret: JavaType,
+    pub method_reportOvercommit: JMethodID<'a>,
+    pub method_reportOvercommit_ret: JavaType,
+}
+impl<'a> BlazeMemoryConsumer<'a> {
+    pub const SIG_TYPE: &'static str = "org/apache/spark/sql/blaze/BlazeMemoryConsumer";
+
+    pub fn new(env: &JNIEnv<'a>) -> JniResult<BlazeMemoryConsumer<'a>> {
+        let class = get_global_jclass(env, Self::SIG_TYPE)?;
+        Ok(BlazeMemoryConsumer {
+            class,
+            method_acquire: env.get_method_id(class, "acquire", "(J)J").unwrap(),
+            method_acquire_ret: JavaType::Primitive(Primitive::Long),
+            method_release: env.get_method_id(class, "release", "(J)V").unwrap(),
+            method_release_ret: JavaType::Primitive(Primitive::Void),
+            method_takeSpillRequest: env
+                .get_method_id(class, "takeSpillRequest", "()Z")
+                .unwrap(),
+            method_takeSpillRequest_ret: JavaType::Primitive(Primitive::Boolean),
+            method_reportOvercommit: env
+                .get_method_id(class, "reportOvercommit", "(Ljava/lang/String;)Z")
+                .unwrap(),
+            method_reportOvercommit_ret: JavaType::Primitive(Primitive::Boolean),
+        })
+    }
+}
+
+#[allow(non_snake_case)]
+pub struct BlazeNativeShuffleWriteMetrics<'a> {
+    pub class: JClass<'a>,
+    pub method_update: JMethodID<'a>,
+    pub method_update_ret: JavaType,
+}
+impl<'a> BlazeNativeShuffleWriteMetrics<'a> {
+    pub const SIG_TYPE: &'static str =
+        "org/apache/spark/sql/blaze/NativeShuffleWriteMetrics";
+
+    pub fn new(env: &JNIEnv<'a>) -> JniResult<BlazeNativeShuffleWriteMetrics<'a>> {
+        let class = get_global_jclass(env, Self::SIG_TYPE)?;
+        Ok(BlazeNativeShuffleWriteMetrics {
+            class,
+            method_update: env.get_method_id(class, "update", "(JJJJJJ)V").unwrap(),
+            method_update_ret: JavaType::Primitive(Primitive::Void),
+        })
+    }
+}
+
 fn get_global_jclass<'a>(env: &JNIEnv<'a>, cls: &str) -> JniResult<JClass<'static>> {
     let local_jclass = env.find_class(cls)?;
     Ok(get_global_ref_jobject(env, local_jclass.into())?.into())
@@ -912,3 +1338,91 @@ fn get_global_ref_jobject<'a>(
     let _ = std::mem::ManuallyDrop::new(global);
     Ok(global_obj)
 }
+
+#[allow(non_snake_case)]
+pub struct BlazeShuffleReadException<'a> {
+    pub class: JClass<'a>,
+    pub ctor: JMethodID<'a>,
+}
+impl<'a> BlazeShuffleReadException<'a> {
+    pub const SIG_TYPE: &'static str =
+        "org/apache/spark/sql/blaze/BlazeShuffleReadException";
+
+    pub fn new(env: &JNIEnv<'a>) -> JniResult<BlazeShuffleReadException<'a>> {
+        let class = get_global_jclass(env, Self::SIG_TYPE)?;
+        Ok(BlazeShuffleReadException {
+            class,
+            ctor: env.get_method_id(
+                class,
+                "<init>",
+                "(Ljava/lang/String;Lscala/collection/Iterator;JLjava/lang/Throwable;)V",
+            )?,
+        })
+    }
+}
+
+#[allow(non_snake_case)]
+pub struct BlazeArrowFFIExporter<'a> {
+    pub class: JClass<'a>,
+    pub method_exportNextBatch: JMethodID<'a>,
+    pub method_exportNextBatch_ret: JavaType,
+}
+impl<'a> BlazeArrowFFIExporter<'a> {
+    pub const SIG_TYPE: &'static str =
+        "org/apache/spark/sql/blaze/execution/ArrowFFIExporter";
+
+    pub fn new(env: &JNIEnv<'a>) -> JniResult<BlazeArrowFFIExporter<'a>> {
+        let class = get_global_jclass(env, Self::SIG_TYPE)?;
+        Ok(BlazeArrowFFIExporter {
+            class,
+            method_exportNextBatch: env.get_method_id(
+                class,
+                "exportNextBatch",
+                "(JJ)Z",
+            )?,
+            method_exportNextBatch_ret: JavaType::Primitive(Primitive::Boolean),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use datafusion::error::Result;
+
+    use crate::jni_bridge::retry_with_backoff;
+
+    #[test]
+    fn test_retry_with_backoff() -> Result<()> {
+        let backoff = Duration::from_millis(1);
+
+        // succeeds after a few attempts
+        let mut attempts = 0;
+        let value = retry_with_backoff(Duration::from_secs(10), backoff, || {
+            attempts += 1;
+            Ok(Some(attempts).filter(|&attempts| attempts == 3))
+        })?;
+        assert_eq!(value, Some(3));
+
+        // times out
+        let mut attempts = 0;
+        let value: Option<()> =
+            retry_with_backoff(Duration::from_millis(20), backoff, || {
+                attempts += 1;
+                Ok(None)
+            })?;
+        assert_eq!(value, None);
+        assert!(attempts > 1);
+
+        // no retrying with zero timeout
+        let mut attempts = 0;
+        let value: Option<()> = retry_with_backoff(Duration::ZERO, backoff, || {
+            attempts += 1;
+            Ok(None)
+        })?;
+        assert_eq!(value, None);
+        assert_eq!(attempts, 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,554 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Normalizes join keys of both sides into identical representations before
+//! joining, like the implicit casts spark inserts for join keys of different
+//! types. float keys are normalized like spark's NormalizeNaNAndZero.
+
+use std::any::Any;
+use std::fmt::Formatter;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use datafusion::arrow::array::{
+    Array, ArrayRef, DecimalArray, DecimalBuilder, Float64Array, Int64Array, StringArray,
+};
+use datafusion::arrow::compute::cast;
+use datafusion::arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use datafusion::arrow::error::{ArrowError, Result as ArrowResult};
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::execution::context::TaskContext;
+use datafusion::physical_plan::expressions::{Column, PhysicalSortExpr};
+use datafusion::physical_plan::stream::RecordBatchStreamAdapter;
+use datafusion::physical_plan::{
+    DisplayFormatType, ExecutionPlan, Partitioning, SendableRecordBatchStream, Statistics,
+};
+use futures::StreamExt;
+
+use crate::normalize_float_expr::normalize_nan_and_zero;
+
+/// Normalization applied to both keys of a join key pair
+#[derive(Debug, Clone, PartialEq)]
+pub struct JoinKeyNormalization {
+    /// keys are cast to this type, decimals are rescaled
+    pub target_type: DataType,
+    /// trailing spaces are insignificant, as for CHAR keys
+    pub trim_trailing_spaces: bool,
+    /// float keys are replaced with order preserving bits of the normalized
+    /// values, so that NaNs match each other and -0.0 matches 0.0
+    pub normalize_nan_and_zero: bool,
+}
+
+impl JoinKeyNormalization {
+    /// returns the type of normalized keys
+    pub fn normalized_type(&self) -> DataType {
+        match &self.target_type {
+            DataType::Float32 | DataType::Float64 if self.normalize_nan_and_zero => {
+                DataType::Int64
+            }
+            other => other.clone(),
+        }
+    }
+}
+
+/// infers normalization of keys of different types, following spark's type
+/// widening of integers and decimals. returns None if not widenable. float
+/// keys of the same type are always normalized.
+pub fn infer_normalization(
+    left_type: &DataType,
+    right_type: &DataType,
+) -> Option<JoinKeyNormalization> {
+    let target_type = match (left_type, right_type) {
+        (l @ (DataType::Float32 | DataType::Float64), r) if l == r => {
+            return Some(JoinKeyNormalization {
+                target_type: l.clone(),
+                trim_trailing_spaces: false,
+                normalize_nan_and_zero: true,
+            });
+        }
+        (l, r) if l == r => return None,
+        (l, r) if integer_digits(l).is_some() && integer_digits(r).is_some() => {
+            if integer_digits(l) >= integer_digits(r) {
+                l.clone()
+            } else {
+                r.clone()
+            }
+        }
+        (l, r) => {
+            let (p1, s1) = decimal_precision_scale(l)?;
+            let (p2, s2) = decimal_precision_scale(r)?;
+            let scale = s1.max(s2);
+            let range = (p1 - s1).max(p2 - s2);
+            DataType::Decimal((range + scale).min(38), scale)
+        }
+    };
+    Some(JoinKeyNormalization {
+        target_type,
+        trim_trailing_spaces: false,
+        normalize_nan_and_zero: false,
+    })
+}
+
+fn integer_digits(data_type: &DataType) -> Option<usize> {
+    match data_type {
+        DataType::Int8 => Some(3),
+        DataType::Int16 => Some(5),
+        DataType::Int32 => Some(10),
+        DataType::Int64 => Some(20),
+        _ => None,
+    }
+}
+
+fn decimal_precision_scale(data_type: &DataType) -> Option<(usize, usize)> {
+    match data_type {
+        DataType::Decimal(precision, scale) => Some((*precision, *scale)),
+        other => integer_digits(other).map(|digits| (digits, 0)),
+    }
+}
+
+/// normalizes one key array
+pub fn normalize_array(
+    array: &ArrayRef,
+    normalization: &JoinKeyNormalization,
+) -> Result<ArrayRef> {
+    let target_type = &normalization.target_type;
+    let normalized = match (array.data_type(), target_type) {
+        (from, to) if from == to => array.clone(),
+        (DataType::Decimal(_, from_scale), &DataType::Decimal(precision, scale)) => {
+            let decimals = array.as_any().downcast_ref::<DecimalArray>().unwrap();
+            let mut builder = DecimalBuilder::new(decimals.len(), precision, scale);
+            for i in 0..decimals.len() {
+                match decimals
+                    .is_valid(i)
+                    .then(|| rescale(decimals.value(i), *from_scale, precision, scale))
+                    .flatten()
+                {
+                    Some(value) => builder.append_value(value)?,
+                    None => builder.append_null()?,
+                }
+            }
+            Arc::new(builder.finish())
+        }
+        (from, &DataType::Decimal(precision, scale))
+            if integer_digits(from).is_some() =>
+        {
+            let ints = cast(array, &DataType::Int64)?;
+            let ints = ints.as_any().downcast_ref::<Int64Array>().unwrap();
+            let mut builder = DecimalBuilder::new(ints.len(), precision, scale);
+            for value in ints.iter() {
+                match value.and_then(|v| rescale(v as i128, 0, precision, scale)) {
+                    Some(value) => builder.append_value(value)?,
+                    None => builder.append_null()?,
+                }
+            }
+            Arc::new(builder.finish())
+        }
+        (_, to) => cast(array, to)?,
+    };
+
+    if normalization.trim_trailing_spaces && normalized.data_type() == &DataType::Utf8 {
+        let strings = normalized.as_any().downcast_ref::<StringArray>().unwrap();
+        let trimmed = strings
+            .iter()
+            .map(|s| s.map(|s| s.trim_end_matches(' ')))
+            .collect::<StringArray>();
+        return Ok(Arc::new(trimmed));
+    }
+    if normalization.normalize_nan_and_zero
+        && matches!(
+            normalized.data_type(),
+            DataType::Float32 | DataType::Float64
+        )
+    {
+        return sortable_float_bits(&normalized);
+    }
+    Ok(normalized)
+}
+
+/// returns bits of normalized floats as integers in the same order as the
+/// floats, with NaN greater than any other value like spark
+fn sortable_float_bits(floats: &ArrayRef) -> Result<ArrayRef> {
+    let doubles = normalize_nan_and_zero(&cast(floats, &DataType::Float64)?)?;
+    let doubles = doubles.as_any().downcast_ref::<Float64Array>().unwrap();
+    let bits = doubles
+        .iter()
+        .map(|v| {
+            v.map(|v| {
+                let bits = v.to_bits() as i64;
+                bits ^ (((bits >> 63) as u64) >> 1) as i64
+            })
+        })
+        .collect::<Int64Array>();
+    Ok(Arc::new(bits))
+}
+
+/// rescales an unscaled decimal value, rounding half up like spark. values
+/// overflowing the precision become null, which never match.
+fn rescale(
+    value: i128,
+    from_scale: usize,
+    precision: usize,
+    scale: usize,
+) -> Option<i128> {
+    let rescaled = if scale >= from_scale {
+        value.checked_mul(10i128.checked_pow((scale - from_scale) as u32)?)?
+    } else {
+        let divisor = 10i128.pow((from_scale - scale) as u32);
+        let (quotient, remainder) = (value / divisor, value % divisor);
+        if remainder.abs() * 2 >= divisor {
+            quotient + value.signum()
+        } else {
+            quotient
+        }
+    };
+    (rescaled.abs() < 10i128.pow(precision as u32)).then(|| rescaled)
+}
+
+/// Join inputs with normalized keys appended as extra columns
+pub struct NormalizedJoinInputs {
+    pub left: Arc<dyn ExecutionPlan>,
+    pub right: Arc<dyn ExecutionPlan>,
+    /// join keys referring to the normalized columns
+    pub on: Vec<(Column, Column)>,
+    num_left_fields: usize,
+    num_right_fields: usize,
+    num_normalized_keys: usize,
+}
+
+/// appends normalized keys to both join inputs, key pairs without an explicit
+/// normalization are normalized only if their types differ and are widenable
+pub fn normalize_join_keys(
+    left: Arc<dyn ExecutionPlan>,
+    right: Arc<dyn ExecutionPlan>,
+    on: Vec<(Column, Column)>,
+    normalizations: &[Option<JoinKeyNormalization>],
+) -> Result<NormalizedJoinInputs> {
+    let left_schema = left.schema();
+    let right_schema = right.schema();
+    let mut left_keys = vec![];
+    let mut right_keys = vec![];
+    let mut normalized_on = vec![];
+
+    for (i, (left_col, right_col)) in on.into_iter().enumerate() {
+        let left_type = left_schema.field(left_col.index()).data_type();
+        let right_type = right_schema.field(right_col.index()).data_type();
+        let normalization = match normalizations.get(i).cloned().flatten() {
+            Some(normalization) => Some(normalization),
+            None => infer_normalization(left_type, right_type),
+        };
+        let normalization = match normalization {
+            Some(n) if n.trim_trailing_spaces || n.normalize_nan_and_zero => n,
+            Some(n) if &n.target_type != left_type => n,
+            Some(n) if &n.target_type != right_type => n,
+            _ => {
+                normalized_on.push((left_col, right_col));
+                continue;
+            }
+        };
+
+        let key_id = left_keys.len();
+        let left_name = format!("__normalized_left_join_key_{}", key_id);
+        let right_name = format!("__normalized_right_join_key_{}", key_id);
+        normalized_on.push((
+            Column::new(&left_name, left_schema.fields().len() + key_id),
+            Column::new(&right_name, right_schema.fields().len() + key_id),
+        ));
+        left_keys.push((left_col, normalization.clone(), left_name));
+        right_keys.push((right_col, normalization, right_name));
+    }
+
+    let num_normalized_keys = left_keys.len();
+    let (left, right): (Arc<dyn ExecutionPlan>, Arc<dyn ExecutionPlan>) =
+        if num_normalized_keys > 0 {
+            (
+                Arc::new(NormalizeJoinKeysExec::new(left, left_keys)),
+                Arc::new(NormalizeJoinKeysExec::new(right, right_keys)),
+            )
+        } else {
+            (left, right)
+        };
+    Ok(NormalizedJoinInputs {
+        left,
+        right,
+        on: normalized_on,
+        num_left_fields: left_schema.fields().len(),
+        num_right_fields: right_schema.fields().len(),
+        num_normalized_keys,
+    })
+}
+
+impl NormalizedJoinInputs {
+    /// removes normalized keys from output of the join built on these inputs
+    pub fn project_join_output(
+        &self,
+        join: Arc<dyn ExecutionPlan>,
+    ) -> Result<Arc<dyn ExecutionPlan>> {
+        if self.num_normalized_keys == 0 {
+            return Ok(join);
+        }
+        let num_left = self.num_left_fields + self.num_normalized_keys;
+        let num_right = self.num_right_fields + self.num_normalized_keys;
+        let num_output_fields = join.schema().fields().len();
+
+        let indices = if num_output_fields == num_left + num_right {
+            (0..self.num_left_fields)
+                .chain(num_left..num_left + self.num_right_fields)
+                .collect()
+        } else if num_output_fields == num_left {
+            (0..self.num_left_fields).collect()
+        } else if num_output_fields == num_left + 1 {
+            // left columns followed by the exists column of existence joins
+            (0..self.num_left_fields).chain([num_left]).collect()
+        } else if num_output_fields == num_right {
+            (0..self.num_right_fields).collect()
+        } else {
+            return Err(DataFusionError::Plan(format!(
+                "unexpected number of join output fields with normalized keys: {}",
+                num_output_fields
+            )));
+        };
+        Ok(Arc::new(ProjectColumnsExec::new(join, indices)))
+    }
+}
+
+/// Appends normalized join keys to batches of its input.
+#[derive(Debug)]
+pub struct NormalizeJoinKeysExec {
+    input: Arc<dyn ExecutionPlan>,
+    keys: Vec<(Column, JoinKeyNormalization, String)>,
+    schema: SchemaRef,
+}
+
+impl NormalizeJoinKeysExec {
+    pub fn new(
+        input: Arc<dyn ExecutionPlan>,
+        keys: Vec<(Column, JoinKeyNormalization, String)>,
+    ) -> Self {
+        let input_schema = input.schema();
+        let mut fields = input_schema.fields().clone();
+        for (_, normalization, name) in &keys {
+            fields.push(Field::new(name, normalization.normalized_type(), true));
+        }
+        let schema = Arc::new(Schema::new_with_metadata(
+            fields,
+            input_schema.metadata().clone(),
+        ));
+        Self {
+            input,
+            keys,
+            schema,
+        }
+    }
+}
+
+#[async_trait]
+impl ExecutionPlan for NormalizeJoinKeysExec {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+
+    fn output_partitioning(&self) -> Partitioning {
+        self.input.output_partitioning()
+    }
+
+    fn output_ordering(&self) -> Option<&[PhysicalSortExpr]> {
+        self.input.output_ordering()
+    }
+
+    crate::transparent_plan_tree!(input, |self, input| Self::new(
+        input,
+        self.keys.clone()
+    ));
+
+    fn execute(
+        &self,
+        partition: usize,
+        context: Arc<TaskContext>,
+    ) -> Result<SendableRecordBatchStream> {
+        let schema = self.schema();
+        let keys = self.keys.clone();
+        let input = self.input.execute(partition, context)?;
+        let normalized = input.map(move |batch| {
+            batch.and_then(|batch| {
+                let mut columns = batch.columns().to_vec();
+                for (col, normalization, _) in &keys {
+                    columns.push(
+                        normalize_array(batch.column(col.index()), normalization)
+                            .map_err(|e| ArrowError::ExternalError(Box::new(e)))?,
+                    );
+                }
+                RecordBatch::try_new(schema.clone(), columns)
+            })
+        });
+        Ok(Box::pin(RecordBatchStreamAdapter::new(
+            self.schema(),
+            normalized,
+        )))
+    }
+
+    fn fmt_as(&self, t: DisplayFormatType, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "NormalizeJoinKeysExec: keys={:?}, ", self.keys)?;
+        self.input.fmt_as(t, f)
+    }
+
+    fn statistics(&self) -> Statistics {
+        self.input.statistics()
+    }
+}
+
+/// Selects columns of batches from its input.
+#[derive(Debug)]
+pub struct ProjectColumnsExec {
+    input: Arc<dyn ExecutionPlan>,
+    indices: Vec<usize>,
+    schema: SchemaRef,
+}
+
+impl ProjectColumnsExec {
+    pub fn new(input: Arc<dyn ExecutionPlan>, indices: Vec<usize>) -> Self {
+        let input_schema = input.schema();
+        let schema = Arc::new(Schema::new_with_metadata(
+            indices
+                .iter()
+                .map(|&i| input_schema.field(i).clone())
+                .collect(),
+            input_schema.metadata().clone(),
+        ));
+        Self {
+            input,
+            indices,
+            schema,
+        }
+    }
+
+    pub fn input(&self) -> &Arc<dyn ExecutionPlan> {
+        &self.input
+    }
+}
+
+#[async_trait]
+impl ExecutionPlan for ProjectColumnsExec {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+
+    fn output_partitioning(&self) -> Partitioning {
+        self.input.output_partitioning()
+    }
+
+    fn output_ordering(&self) -> Option<&[PhysicalSortExpr]> {
+        None
+    }
+
+    crate::transparent_plan_tree!(input, |self, input| Self::new(
+        input,
+        self.indices.clone()
+    ));
+
+    fn execute(
+        &self,
+        partition: usize,
+        context: Arc<TaskContext>,
+    ) -> Result<SendableRecordBatchStream> {
+        let schema = self.schema();
+        let indices = self.indices.clone();
+        let input = self.input.execute(partition, context)?;
+        let projected = input.map(move |batch| -> ArrowResult<RecordBatch> {
+            let batch = batch?;
+            let columns = indices.iter().map(|&i| batch.column(i).clone()).collect();
+            RecordBatch::try_new(schema.clone(), columns)
+        });
+        Ok(Box::pin(RecordBatchStreamAdapter::new(
+            self.schema(),
+            projected,
+        )))
+    }
+
+    fn fmt_as(&self, t: DisplayFormatType, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "ProjectColumnsExec: indices={:?}, ", self.indices)?;
+        self.input.fmt_as(t, f)
+    }
+
+    fn statistics(&self) -> Statistics {
+        self.input.statistics()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use datafusion::arrow::array::Int32Array;
+
+    #[test]
+    fn test_normalize_join_keys() -> Result<()> {
+        let normalization =
+            infer_normalization(&DataType::Int32, &DataType::Decimal(12, 2)).unwrap();
+        assert_eq!(normalization.target_type, DataType::Decimal(12, 2));
+
+        let ints: ArrayRef = Arc::new(Int32Array::from(vec![Some(7), None]));
+        let decimals = normalize_array(&ints, &normalization)?;
+        let decimals = decimals.as_any().downcast_ref::<DecimalArray>().unwrap();
+        assert_eq!(decimals.value(0), 700);
+        assert!(decimals.is_null(1));
+
+        let chars: ArrayRef = Arc::new(StringArray::from(vec!["ab  ", "ab"]));
+        let trimmed = normalize_array(
+            &chars,
+            &JoinKeyNormalization {
+                target_type: DataType::Utf8,
+                trim_trailing_spaces: true,
+                normalize_nan_and_zero: false,
+            },
+        )?;
+        assert_eq!(
+            trimmed.as_any().downcast_ref::<StringArray>().unwrap(),
+            &StringArray::from(vec!["ab", "ab"])
+        );
+
+        let normalization =
+            infer_normalization(&DataType::Float64, &DataType::Float64).unwrap();
+        assert_eq!(normalization.normalized_type(), DataType::Int64);
+        let doubles: ArrayRef = Arc::new(Float64Array::from(vec![
+            -1.0,
+            -0.0,
+            0.0,
+            1.0,
+            f64::INFINITY,
+            f64::NAN,
+            -f64::NAN,
+        ]));
+        let bits = normalize_array(&doubles, &normalization)?;
+        let bits = bits.as_any().downcast_ref::<Int64Array>().unwrap();
+        assert!(bits.value(0) < bits.value(1));
+        assert_eq!(bits.value(1), bits.value(2));
+        assert!(bits.value(2) < bits.value(3));
+        assert!(bits.value(3) < bits.value(4));
+        assert!(bits.value(4) < bits.value(5));
+        assert_eq!(bits.value(5), bits.value(6));
+
+        assert_eq!(rescale(12345, 3, 10, 2), Some(1235));
+        assert_eq!(rescale(-12345, 3, 10, 2), Some(-1235));
+        assert_eq!(rescale(12345, 0, 5, 2), None);
+        Ok(())
+    }
+}
@@ -45,12 +45,11 @@ use futures::Stream;
 use jni::objects::{GlobalRef, JObject};
 use jni::sys::{jboolean, jint, jlong, JNI_TRUE};
 
+use crate::jni_bridge::get_resource;
 use crate::jni_call;
-use crate::jni_call_static;
 use crate::jni_delete_local_ref;
 use crate::jni_new_direct_byte_buffer;
 use crate::jni_new_global_ref;
-use crate::jni_new_string;
 use crate::ResultExt;
 
 #[derive(Debug, Clone)]
@@ -115,11 +114,7 @@ impl ExecutionPlan for JvmToNativeExec {
         let elapsed_compute = baseline_metrics.elapsed_compute().clone();
         let _timer = elapsed_compute.timer();
 
-        let segments_provider = jni_call_static!(
-            JniBridge.getResource(
-                jni_new_string!(&self.native_resource_id)?
-            ) -> JObject
-        )?;
+        let segments_provider = get_resource(&self.native_resource_id)?;
         let segments = jni_new_global_ref!(
             jni_call!(ScalaFunction0(segments_provider).apply() -> JObject)?
         )?;
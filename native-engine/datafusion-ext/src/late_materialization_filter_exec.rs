@@ -0,0 +1,449 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Defines a filter materializing columns late
+//!
+//! the predicate is split into its conjuncts, which are evaluated one by one.
+//! each conjunct is evaluated only on rows not yet rejected by the previous
+//! conjuncts, gathering only the columns it references. the other columns
+//! (e.g. large strings) are gathered once for the surviving rows, and nothing
+//! is gathered for batches where no rows survive.
+//!
+//! columns referenced by a conjunct are collected from known expressions,
+//! conjuncts with unknown expressions are evaluated with all columns.
+
+use std::any::Any;
+use std::collections::BTreeSet;
+use std::fmt::Formatter;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use datafusion::arrow::array::{new_null_array, Array, BooleanArray, UInt32Array};
+use datafusion::arrow::compute::take;
+use datafusion::arrow::datatypes::{DataType, SchemaRef};
+use datafusion::arrow::error::{ArrowError, Result as ArrowResult};
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::execution::context::TaskContext;
+use datafusion::logical_plan::Operator;
+use datafusion::physical_expr::PhysicalSortExpr;
+use datafusion::physical_plan::expressions::{
+    BinaryExpr, CaseExpr, CastExpr, Column, InListExpr, IsNotNullExpr, IsNullExpr,
+    Literal, NegativeExpr, NotExpr, TryCastExpr,
+};
+use datafusion::physical_plan::functions::ScalarFunctionExpr;
+use datafusion::physical_plan::metrics::{
+    BaselineMetrics, ExecutionPlanMetricsSet, MetricsSet,
+};
+use datafusion::physical_plan::stream::RecordBatchStreamAdapter;
+use datafusion::physical_plan::{
+    DisplayFormatType, ExecutionPlan, Partitioning, PhysicalExpr,
+    SendableRecordBatchStream, Statistics,
+};
+use futures::{StreamExt, TryStreamExt};
+
+use crate::char_varchar_expr::CharVarcharExpr;
+use crate::common_subexpr::CommonSubExpr;
+use crate::grouping_expr::GroupingExpr;
+use crate::normalize_float_expr::{FloatComparisonExpr, NormalizeNaNAndZeroExpr};
+use crate::short_circuit_expr::ShortCircuitBinaryExpr;
+use crate::typed_literal_expr::TypedLiteralExpr;
+
+#[derive(Debug)]
+pub(crate) struct Conjunct {
+    expr: Arc<dyn PhysicalExpr>,
+    /// indices of referenced columns, None if unknown
+    columns: Option<Vec<usize>>,
+}
+
+/// Filters rows of the input like FilterExec, evaluating conjuncts of the
+/// predicate on surviving rows and gathering columns only when needed.
+#[derive(Debug)]
+pub struct LateMaterializationFilterExec {
+    predicate: Arc<dyn PhysicalExpr>,
+    input: Arc<dyn ExecutionPlan>,
+    conjuncts: Arc<Vec<Conjunct>>,
+    metrics: ExecutionPlanMetricsSet,
+}
+
+impl LateMaterializationFilterExec {
+    pub fn try_new(
+        predicate: Arc<dyn PhysicalExpr>,
+        input: Arc<dyn ExecutionPlan>,
+    ) -> Result<Self> {
+        let data_type = predicate.data_type(&input.schema())?;
+        if data_type != DataType::Boolean {
+            return Err(DataFusionError::Plan(format!(
+                "Filter predicate must return boolean values, found {:?}",
+                data_type
+            )));
+        }
+
+        Ok(Self {
+            conjuncts: Arc::new(conjuncts_of(&predicate)),
+            predicate,
+            input,
+            metrics: ExecutionPlanMetricsSet::new(),
+        })
+    }
+
+    pub fn predicate(&self) -> &Arc<dyn PhysicalExpr> {
+        &self.predicate
+    }
+
+    pub fn input(&self) -> &Arc<dyn ExecutionPlan> {
+        &self.input
+    }
+}
+
+#[async_trait]
+impl ExecutionPlan for LateMaterializationFilterExec {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.input.schema()
+    }
+
+    fn output_partitioning(&self) -> Partitioning {
+        self.input.output_partitioning()
+    }
+
+    fn output_ordering(&self) -> Option<&[PhysicalSortExpr]> {
+        self.input.output_ordering()
+    }
+
+    fn children(&self) -> Vec<Arc<dyn ExecutionPlan>> {
+        vec![self.input.clone()]
+    }
+
+    fn with_new_children(
+        self: Arc<Self>,
+        children: Vec<Arc<dyn ExecutionPlan>>,
+    ) -> Result<Arc<dyn ExecutionPlan>> {
+        if children.len() != 1 {
+            return Err(DataFusionError::Plan(
+                "LateMaterializationFilterExec expects one children".to_string(),
+            ));
+        }
+        Ok(Arc::new(LateMaterializationFilterExec::try_new(
+            self.predicate.clone(),
+            children[0].clone(),
+        )?))
+    }
+
+    fn execute(
+        &self,
+        partition: usize,
+        context: Arc<TaskContext>,
+    ) -> Result<SendableRecordBatchStream> {
+        let input = self.input.execute(partition, context)?;
+        let conjuncts = self.conjuncts.clone();
+        let baseline_metrics = BaselineMetrics::new(&self.metrics, partition);
+
+        let output = input
+            .map(move |batch| -> ArrowResult<RecordBatch> {
+                let batch = batch?;
+                let timer = baseline_metrics.elapsed_compute().timer();
+                let filtered =
+                    filter_batch(&conjuncts, &batch).map_err(ArrowError::from)?;
+                timer.done();
+                baseline_metrics.record_output(filtered.num_rows());
+                Ok(filtered)
+            })
+            .try_filter(|batch| futures::future::ready(batch.num_rows() > 0));
+        Ok(Box::pin(RecordBatchStreamAdapter::new(
+            self.schema(),
+            output,
+        )))
+    }
+
+    fn metrics(&self) -> Option<MetricsSet> {
+        Some(self.metrics.clone_inner())
+    }
+
+    fn fmt_as(&self, t: DisplayFormatType, f: &mut Formatter) -> std::fmt::Result {
+        match t {
+            DisplayFormatType::Default => {
+                write!(f, "LateMaterializationFilterExec: {}", self.predicate)
+            }
+        }
+    }
+
+    fn statistics(&self) -> Statistics {
+        Statistics::default()
+    }
+}
+
+/// splits the predicate into conjuncts with their referenced columns
+pub(crate) fn conjuncts_of(predicate: &Arc<dyn PhysicalExpr>) -> Vec<Conjunct> {
+    let mut conjunct_exprs = vec![];
+    split_conjuncts(predicate, &mut conjunct_exprs);
+    conjunct_exprs
+        .into_iter()
+        .map(|expr| {
+            let mut columns = BTreeSet::new();
+            let known = collect_columns(&expr, &mut columns);
+            Conjunct {
+                expr,
+                columns: known.then(|| columns.into_iter().collect()),
+            }
+        })
+        .collect()
+}
+
+fn filter_batch(conjuncts: &[Conjunct], batch: &RecordBatch) -> Result<RecordBatch> {
+    match select_rows(conjuncts, batch)? {
+        Some(indices) if indices.is_empty() => Ok(RecordBatch::new_empty(batch.schema())),
+        Some(indices) => gather(batch, &indices, None),
+        None => Ok(batch.clone()),
+    }
+}
+
+/// returns indices of rows satisfying all conjuncts, None if all rows are
+/// satisfied
+pub(crate) fn select_rows(
+    conjuncts: &[Conjunct],
+    batch: &RecordBatch,
+) -> Result<Option<UInt32Array>> {
+    let num_rows = batch.num_rows();
+
+    // rows not rejected by evaluated conjuncts (None for all rows), and
+    // whether any conjunct is null on them
+    let mut indices: Option<UInt32Array> = None;
+    let mut nulls = vec![false; num_rows];
+    for conjunct in conjuncts {
+        let input = match &indices {
+            Some(indices) => gather(batch, indices, conjunct.columns.as_deref())?,
+            None => batch.clone(),
+        };
+        let mask = conjunct.expr.evaluate(&input)?.into_array(input.num_rows());
+        let mask = mask
+            .as_any()
+            .downcast_ref::<BooleanArray>()
+            .ok_or_else(|| {
+                DataFusionError::Execution(format!(
+                    "Filter predicate must return boolean values, got {:?}",
+                    mask.data_type()
+                ))
+            })?;
+
+        let mut selected = vec![];
+        let mut selected_nulls = vec![];
+        for (i, &null) in nulls.iter().enumerate() {
+            if mask.is_valid(i) && !mask.value(i) {
+                continue;
+            }
+            selected.push(indices.as_ref().map(|v| v.value(i)).unwrap_or(i as u32));
+            selected_nulls.push(null || mask.is_null(i));
+        }
+        nulls = selected_nulls;
+        if selected.is_empty() {
+            return Ok(Some(UInt32Array::from(selected)));
+        }
+        if indices.is_some() || selected.len() < num_rows {
+            indices = Some(UInt32Array::from(selected));
+        }
+    }
+
+    // rows with null conjuncts are not satisfied
+    let selected = match &indices {
+        Some(indices) => indices
+            .values()
+            .iter()
+            .zip(&nulls)
+            .filter(|(_, null)| !**null)
+            .map(|(&i, _)| i)
+            .collect::<Vec<_>>(),
+        None => (0..num_rows as u32)
+            .filter(|&i| !nulls[i as usize])
+            .collect(),
+    };
+    if selected.len() == num_rows {
+        return Ok(None);
+    }
+    Ok(Some(UInt32Array::from(selected)))
+}
+
+/// gathers rows of the batch at the indices. columns not listed are replaced
+/// with nulls, all columns are gathered if columns is None.
+pub(crate) fn gather(
+    batch: &RecordBatch,
+    indices: &UInt32Array,
+    columns: Option<&[usize]>,
+) -> Result<RecordBatch> {
+    let gathered = batch
+        .columns()
+        .iter()
+        .enumerate()
+        .map(|(i, column)| {
+            if columns.map(|columns| columns.contains(&i)).unwrap_or(true) {
+                take(column.as_ref(), indices, None)
+            } else {
+                Ok(new_null_array(column.data_type(), indices.len()))
+            }
+        })
+        .collect::<ArrowResult<Vec<_>>>()?;
+    Ok(RecordBatch::try_new(batch.schema(), gathered)?)
+}
+
+fn split_conjuncts(
+    expr: &Arc<dyn PhysicalExpr>,
+    conjuncts: &mut Vec<Arc<dyn PhysicalExpr>>,
+) {
+    let any = expr.as_any();
+    if let Some(expr) = any.downcast_ref::<BinaryExpr>() {
+        if *expr.op() == Operator::And {
+            split_conjuncts(expr.left(), conjuncts);
+            split_conjuncts(expr.right(), conjuncts);
+            return;
+        }
+    }
+    if let Some(expr) = any.downcast_ref::<ShortCircuitBinaryExpr>() {
+        if *expr.op() == Operator::And {
+            split_conjuncts(expr.left(), conjuncts);
+            split_conjuncts(expr.right(), conjuncts);
+            return;
+        }
+    }
+    conjuncts.push(expr.clone());
+}
+
+/// collects indices of columns referenced by the expression, returns false if
+/// the expression contains unknown expressions
+pub(crate) fn collect_columns(
+    expr: &Arc<dyn PhysicalExpr>,
+    columns: &mut BTreeSet<usize>,
+) -> bool {
+    let any = expr.as_any();
+    let children: Vec<&Arc<dyn PhysicalExpr>> =
+        if let Some(expr) = any.downcast_ref::<Column>() {
+            columns.insert(expr.index());
+            vec![]
+        } else if any.is::<Literal>() || any.is::<TypedLiteralExpr>() {
+            vec![]
+        } else if let Some(expr) = any.downcast_ref::<BinaryExpr>() {
+            vec![expr.left(), expr.right()]
+        } else if let Some(expr) = any.downcast_ref::<ShortCircuitBinaryExpr>() {
+            vec![expr.left(), expr.right()]
+        } else if let Some(expr) = any.downcast_ref::<CaseExpr>() {
+            let mut children = vec![];
+            if let Some(expr) = expr.expr() {
+                children.push(expr);
+            }
+            for (when_expr, then_expr) in expr.when_then_expr() {
+                children.push(when_expr);
+                children.push(then_expr);
+            }
+            if let Some(expr) = expr.else_expr() {
+                children.push(expr);
+            }
+            children
+        } else if let Some(expr) = any.downcast_ref::<NotExpr>() {
+            vec![expr.arg()]
+        } else if let Some(expr) = any.downcast_ref::<IsNullExpr>() {
+            vec![expr.arg()]
+        } else if let Some(expr) = any.downcast_ref::<IsNotNullExpr>() {
+            vec![expr.arg()]
+        } else if let Some(expr) = any.downcast_ref::<InListExpr>() {
+            std::iter::once(expr.expr()).chain(expr.list()).collect()
+        } else if let Some(expr) = any.downcast_ref::<NegativeExpr>() {
+            vec![expr.arg()]
+        } else if let Some(expr) = any.downcast_ref::<CastExpr>() {
+            vec![expr.expr()]
+        } else if let Some(expr) = any.downcast_ref::<TryCastExpr>() {
+            vec![expr.expr()]
+        } else if let Some(expr) = any.downcast_ref::<GroupingExpr>() {
+            vec![expr.grouping_id()]
+        } else if let Some(expr) = any.downcast_ref::<CharVarcharExpr>() {
+            vec![expr.expr()]
+        } else if let Some(expr) = any.downcast_ref::<NormalizeNaNAndZeroExpr>() {
+            vec![expr.expr()]
+        } else if let Some(expr) = any.downcast_ref::<FloatComparisonExpr>() {
+            vec![expr.left(), expr.right()]
+        } else if let Some(expr) = any.downcast_ref::<CommonSubExpr>() {
+            vec![expr.expr()]
+        } else if let Some(expr) = any.downcast_ref::<ScalarFunctionExpr>() {
+            expr.args().iter().collect()
+        } else {
+            return false;
+        };
+    children
+        .into_iter()
+        .all(|child| collect_columns(child, columns))
+}
+
+#[cfg(test)]
+mod tests {
+    use datafusion::arrow::datatypes::{Field, Schema};
+    use datafusion::physical_plan::common::collect;
+    use datafusion::physical_plan::expressions::{col, lit};
+    use datafusion::physical_plan::memory::MemoryExec;
+    use datafusion::prelude::SessionContext;
+    use datafusion::scalar::ScalarValue;
+
+    use super::*;
+    use crate::test_util::build_batch;
+
+    #[test]
+    fn test_late_materialization_filter() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("k", DataType::Int32, true),
+            Field::new("s", DataType::Utf8, true),
+        ]));
+        let input = Arc::new(MemoryExec::try_new(
+            &[vec![
+                build_batch!(
+                    schema,
+                    vec![Some(1), Some(2), None, Some(3), Some(4)],
+                    vec![Some("a"), Some("b"), Some("b"), None, Some("b")],
+                )?,
+                // no rows survive
+                build_batch!(schema, vec![Some(0), Some(1)], vec![Some("b"), Some("b")])?,
+            ]],
+            schema.clone(),
+            None,
+        )?);
+
+        // k > 1 AND s = 'b'
+        let predicate = Arc::new(BinaryExpr::new(
+            Arc::new(BinaryExpr::new(
+                col("k", &schema)?,
+                Operator::Gt,
+                lit(ScalarValue::from(1)),
+            )),
+            Operator::And,
+            Arc::new(BinaryExpr::new(
+                col("s", &schema)?,
+                Operator::Eq,
+                lit(ScalarValue::from("b")),
+            )),
+        ));
+        let filter = LateMaterializationFilterExec::try_new(predicate, input)?;
+        assert_eq!(filter.conjuncts.len(), 2);
+        assert_eq!(filter.conjuncts[0].columns, Some(vec![0]));
+        assert_eq!(filter.conjuncts[1].columns, Some(vec![1]));
+
+        let task_ctx = SessionContext::new().task_ctx();
+        let runtime = tokio::runtime::Runtime::new()?;
+        let batches = runtime.block_on(collect(filter.execute(0, task_ctx)?))?;
+        assert_eq!(batches.len(), 1);
+        assert_eq!(
+            batches[0],
+            build_batch!(schema, vec![Some(2), Some(4)], vec![Some("b"), Some("b")])?
+        );
+        Ok(())
+    }
+}
@@ -13,21 +13,80 @@
 // limitations under the License.
 
 use datafusion::datasource::object_store_registry::ObjectStoreRegistry;
+use datafusion::physical_plan::sorts::sort::SortExec;
+use datafusion::physical_plan::ExecutionPlan;
 use once_cell::sync::OnceCell;
 
 use hdfs_object_store::HDFSSingleFileObjectStore;
 use std::sync::Arc;
 
+pub mod array_function_expr;
+pub mod blaze_error;
+pub mod buffer_pool;
+pub mod char_varchar_expr;
+pub mod common_subexpr;
 pub mod empty_partitions_exec;
+pub mod equal_null_safe_expr;
+pub mod existence_join_exec;
+pub mod fault_injection;
+pub mod ffi_compat;
+pub mod ffi_reader_exec;
+pub mod ffi_stream_reader_exec;
+pub mod file_splits;
+pub mod filter_project_exec;
+pub mod grouping_expr;
 pub mod hdfs_object_store; // note: can be changed to priv once plan transforming is removed
+pub mod intra_task_parallel_exec;
 pub mod jni_bridge;
+pub mod join_key_normalization;
 pub mod jvm_to_native_exec;
+pub mod late_materialization_filter_exec;
+pub mod native_conf;
+pub mod native_counters;
+pub mod nested_loop_join_exec;
+pub mod normalize_float_expr;
+pub mod output_metrics_exec;
+pub mod parquet_column_metrics_exec;
+pub mod parquet_sink_exec;
+pub mod partial_agg_skipping_exec;
+pub mod partial_merge_aggregate_expr;
+pub mod partition_scope_exec;
+pub mod positional_delete_parquet_exec;
+pub mod prefetch_scan_exec;
+pub mod prefetch_stream;
+pub mod radix_sort_exec;
 pub mod rename_columns_exec;
+pub mod reused_exchange_exec;
+pub mod row_input_exec;
+pub mod schema_adapted_parquet_exec;
+pub mod shared_dictionary;
+pub mod short_circuit_expr;
+pub mod shuffle_codec;
+pub mod shuffle_output_writer;
 pub mod shuffle_reader_exec;
+pub mod shuffle_write_metrics;
 pub mod shuffle_writer_exec;
+pub mod sort_aggregate_exec;
+pub mod spark_approx_percentile;
+pub mod spark_bool_aggregate;
+pub mod spark_in_list_expr;
+pub mod spark_interval_expr;
+pub mod spark_like_expr;
+pub mod spark_memory;
+pub mod spark_partition_expr;
+pub mod spark_rand_expr;
+pub mod spill_manager;
+pub mod spillable_sort_merge_join_exec;
+pub mod split_oversized_batches_exec;
+pub mod task_cancellation;
+pub mod topk_groups_exec;
+pub mod typed_literal_expr;
+pub mod unsafe_row;
+pub mod utf8_validation_exec;
 
-mod batch_buffer;
 mod spark_hash;
+#[cfg(test)]
+mod test_util;
 
 pub fn global_object_store_registry() -> &'static ObjectStoreRegistry {
     static OBJECT_STORE_REGISTRY: OnceCell<ObjectStoreRegistry> = OnceCell::new();
@@ -40,6 +99,63 @@ pub fn global_object_store_registry() -> &'static ObjectStoreRegistry {
     })
 }
 
+/// rebuilds the plan with new children. unlike `with_new_children()`, sorts
+/// still sort each partition separately instead of requiring a single input
+/// partition.
+pub fn with_new_children_preserving_partitioning(
+    plan: Arc<dyn ExecutionPlan>,
+    children: Vec<Arc<dyn ExecutionPlan>>,
+) -> datafusion::error::Result<Arc<dyn ExecutionPlan>> {
+    if let Some(sort) = plan.as_any().downcast_ref::<SortExec>() {
+        let preserve_partitioning = sort.output_partitioning().partition_count()
+            == sort.input().output_partitioning().partition_count();
+        return Ok(Arc::new(SortExec::new_with_partitioning(
+            sort.expr().to_vec(),
+            children[0].clone(),
+            preserve_partitioning,
+        )));
+    }
+    plan.with_new_children(children)
+}
+
+/// implements `children()`, `with_new_children()` and `metrics()` of an
+/// operator wrapping its input transparently in the plan tree: children and
+/// metrics are those of the input, so that the native metric tree still
+/// matches the JVM side. new children are passed on to the input, and the
+/// operator is rebuilt around the new input by `|self, input| rewrap`.
+///
+/// with `own_metrics`, the operator implements `metrics()` by itself.
+#[macro_export]
+macro_rules! transparent_plan_tree {
+    ($input:ident, |$self:ident, $new_input:ident| $rewrap:expr) => {
+        $crate::transparent_plan_tree!($input, |$self, $new_input| $rewrap, own_metrics);
+
+        fn metrics(&self) -> Option<datafusion::physical_plan::metrics::MetricsSet> {
+            self.$input.metrics()
+        }
+    };
+    ($input:ident, |$self:ident, $new_input:ident| $rewrap:expr, own_metrics) => {
+        fn children(
+            &self,
+        ) -> Vec<std::sync::Arc<dyn datafusion::physical_plan::ExecutionPlan>> {
+            self.$input.children()
+        }
+
+        fn with_new_children(
+            $self: std::sync::Arc<Self>,
+            children: Vec<std::sync::Arc<dyn datafusion::physical_plan::ExecutionPlan>>,
+        ) -> datafusion::error::Result<
+            std::sync::Arc<dyn datafusion::physical_plan::ExecutionPlan>,
+        > {
+            let $new_input = $crate::with_new_children_preserving_partitioning(
+                $self.$input.clone(),
+                children,
+            )?;
+            Ok(std::sync::Arc::new($rewrap))
+        }
+    };
+}
+
 pub trait ResultExt<T> {
     fn unwrap_or_fatal(self) -> T;
     fn to_io_result(self) -> std::io::Result<T>;
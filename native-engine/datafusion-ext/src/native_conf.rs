@@ -0,0 +1,501 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Native tunables which can be updated without restarting executors
+
+use std::cell::RefCell;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+
+use datafusion::error::{DataFusionError, Result};
+use log::LevelFilter;
+use once_cell::sync::OnceCell;
+
+use crate::fault_injection::FaultInjectionRules;
+use crate::shared_dictionary::SharedDictionaryColumns;
+use crate::shuffle_codec::ShuffleCodecPolicy;
+use crate::utf8_validation_exec::Utf8ValidationPolicy;
+
+pub const CONF_BATCH_SIZE: &str = "batch_size";
+pub const CONF_SHUFFLE_COMPRESSION_LEVEL: &str = "shuffle_compression_level";
+pub const CONF_OUTPUT_PREFETCH_BYTES: &str = "output_prefetch_bytes";
+pub const CONF_LOG_LEVEL: &str = "log_level";
+pub const CONF_MAX_OUTPUT_BATCH_BYTES: &str = "max_output_batch_bytes";
+pub const CONF_MAX_INPUT_BATCH_BYTES: &str = "max_input_batch_bytes";
+pub const CONF_UTF8_VALIDATION: &str = "utf8_validation";
+pub const CONF_SHUFFLE_CODEC: &str = "shuffle_codec";
+pub const CONF_SHUFFLE_ZSTD_DICTIONARY_BYTES: &str = "shuffle_zstd_dictionary_bytes";
+pub const CONF_SHUFFLE_ZSTD_DICTIONARY_SAMPLES: &str = "shuffle_zstd_dictionary_samples";
+pub const CONF_SHUFFLE_CHECKSUM: &str = "shuffle_checksum";
+pub const CONF_SHUFFLE_FETCH_BATCH_SIZE: &str = "shuffle_fetch_batch_size";
+pub const CONF_SHUFFLE_FETCH_BATCH_BYTES: &str = "shuffle_fetch_batch_bytes";
+pub const CONF_SHUFFLE_LOCAL_MMAP: &str = "shuffle_local_mmap";
+pub const CONF_SHUFFLE_SORT_BASED_MIN_PARTITIONS: &str =
+    "shuffle_sort_based_min_partitions";
+pub const CONF_SPILL_MMAP: &str = "spill_mmap";
+pub const CONF_PLAN_CACHE: &str = "plan_cache";
+pub const CONF_SCAN_PREFETCH_BATCHES: &str = "scan_prefetch_batches";
+pub const CONF_SCAN_PREFETCH_BYTES: &str = "scan_prefetch_bytes";
+pub const CONF_FAULT_INJECTION: &str = "fault_injection";
+pub const CONF_SMJ_MAX_BUFFERED_GROUP_BYTES: &str = "smj_max_buffered_group_bytes";
+pub const CONF_SHARED_DICTIONARY_COLUMNS: &str = "shared_dictionary_columns";
+pub const CONF_SHARED_DICTIONARY_MAX_VALUES: &str = "shared_dictionary_max_values";
+pub const CONF_PARTIAL_AGG_SKIPPING_MIN_ROWS: &str = "partial_agg_skipping_min_rows";
+pub const CONF_PARTIAL_AGG_SKIPPING_MIN_RATIO: &str = "partial_agg_skipping_min_ratio";
+pub const CONF_FILTER_LATE_MATERIALIZATION: &str = "filter_late_materialization";
+pub const CONF_FILTER_PROJECT_FUSION: &str = "filter_project_fusion";
+pub const CONF_CONSTANT_FOLDING: &str = "constant_folding";
+pub const CONF_COMMON_SUBEXPR_ELIMINATION: &str = "common_subexpr_elimination";
+pub const CONF_RADIX_SORT_MAX_BYTES: &str = "radix_sort_max_bytes";
+pub const CONF_TOPK_AGGREGATE_MAX_LIMIT: &str = "topk_aggregate_max_limit";
+pub const CONF_TOPK_SHUFFLE_READ_MAX_LIMIT: &str = "topk_shuffle_read_max_limit";
+pub const CONF_RESOURCE_WAIT_TIMEOUT_MS: &str = "resource_wait_timeout_ms";
+pub const CONF_RESOURCE_RETRY_BACKOFF_MS: &str = "resource_retry_backoff_ms";
+pub const CONF_BUFFER_POOL_FRACTION: &str = "buffer_pool_fraction";
+pub const CONF_INTRA_TASK_PARALLELISM: &str = "intra_task_parallelism";
+pub const CONF_TASK_WORKER_THREADS: &str = "task_worker_threads";
+pub const CONF_SPILL_SWEEP_INTERVAL_SECS: &str = "spill_sweep_interval_secs";
+
+/// A snapshot of native tunables. tasks take a snapshot when launched, so
+/// updates only take effect for subsequently launched tasks.
+#[derive(Debug, Clone)]
+pub struct NativeConf {
+    /// overrides batch size passed to initNative()
+    pub batch_size: Option<usize>,
+    /// zstd level used for compressing shuffle segments
+    pub shuffle_compression_level: i32,
+    /// overrides output prefetch bytes set by JVM side
+    pub output_prefetch_bytes: Option<i64>,
+    /// overrides max log level
+    pub log_level: Option<LevelFilter>,
+    /// joins and sort aggregations build output batches of about this size, so
+    /// that hot keys do not produce giant batches, 0 to bound rows only
+    pub max_output_batch_bytes: usize,
+    /// batches of scans and shuffle reads larger than this are split, so that
+    /// wide rows are read in batches of about this size, 0 to disable
+    pub max_input_batch_bytes: usize,
+    /// handling of malformed UTF-8 from scans and JVM inputs
+    pub utf8_validation: Utf8ValidationPolicy,
+    /// codec of shuffle segments: zstd, lz4 or adaptive per stage
+    pub shuffle_codec: ShuffleCodecPolicy,
+    /// max size of the zstd dictionary a map task trains on its first small
+    /// shuffle segments, 0 to disable
+    pub shuffle_zstd_dictionary_bytes: usize,
+    /// number of small shuffle segments a map task samples before training
+    /// the zstd dictionary
+    pub shuffle_zstd_dictionary_samples: usize,
+    /// checksums written shuffle segments with crc32, verified on read
+    pub shuffle_checksum: bool,
+    /// max number of shuffle segments fetched in a single JNI call
+    pub shuffle_fetch_batch_size: usize,
+    /// max total bytes of shuffle segments fetched in a single JNI call, at
+    /// least one segment is fetched regardless of its size
+    pub shuffle_fetch_batch_bytes: usize,
+    /// memory-maps shuffle segments of local shuffle files directly instead of
+    /// reading them through JVM channels
+    pub shuffle_local_mmap: bool,
+    /// shuffles with at least this number of output partitions are written by
+    /// sorting rows by partition id instead of buffering by partition
+    pub shuffle_sort_based_min_partitions: usize,
+    /// reads back spill files with memory-mapped IO
+    pub spill_mmap: bool,
+    /// reuses converted plans of previous tasks of the same stage
+    pub plan_cache: bool,
+    /// max number of batches prefetched ahead of parents of scans, 0 to disable
+    pub scan_prefetch_batches: usize,
+    /// max total bytes of batches prefetched ahead of parents of scans, at
+    /// least one batch is prefetched regardless of its size
+    pub scan_prefetch_bytes: usize,
+    /// faults injected for resilience testing, see fault_injection
+    pub fault_injection: FaultInjectionRules,
+    /// rows of a sort-merge join key group beyond this are spilled, 0 to
+    /// buffer whole groups in memory with datafusion's sort-merge join
+    pub smj_max_buffered_group_bytes: usize,
+    /// string columns dictionary-encoded in shuffles with executor-scoped
+    /// dictionaries, see shared_dictionary
+    pub shared_dictionary_columns: SharedDictionaryColumns,
+    pub shared_dictionary_max_values: usize,
+    /// input rows sampled by partial aggregations before deciding whether to
+    /// skip aggregating, 0 to disable, see partial_agg_skipping_exec
+    pub partial_agg_skipping_min_rows: usize,
+    /// partial aggregations are skipped if the sampled rows are aggregated into
+    /// at least this ratio of rows
+    pub partial_agg_skipping_min_ratio: f64,
+    /// filters evaluate conjuncts on surviving rows and gather other columns
+    /// late, see late_materialization_filter_exec
+    pub filter_late_materialization: bool,
+    /// projections over filters are fused with them into a single operator,
+    /// see filter_project_exec
+    pub filter_project_fusion: bool,
+    /// deterministic expressions of only literals are evaluated when
+    /// converting plans, see plan_serde::expr_rewrite
+    pub constant_folding: bool,
+    /// repeated subexpressions of projections and filters are evaluated once
+    /// per batch, see common_subexpr
+    pub common_subexpr_elimination: bool,
+    /// max bytes of a partition sorted in memory by radix sorts of single
+    /// fixed-width keys, larger inputs fall back to SortExec. 0 to disable,
+    /// see radix_sort_exec
+    pub radix_sort_max_bytes: usize,
+    /// max limit of sorts by group keys of final aggregations, under which
+    /// the aggregations only keep the top groups. 0 to disable, see
+    /// topk_groups_exec
+    pub topk_aggregate_max_limit: usize,
+    /// max limit of sorts over shuffle reads, under which rows of the read
+    /// segments not in the top rows are pruned. 0 to disable, see
+    /// topk_groups_exec
+    pub topk_shuffle_read_max_limit: usize,
+    /// max time waiting for resources not yet registered by the JVM side, 0
+    /// to fail immediately, see jni_bridge::get_resource
+    pub resource_wait_timeout_ms: u64,
+    /// initial backoff between polls of missing resources, doubled each time
+    pub resource_retry_backoff_ms: u64,
+    /// max total bytes of freed arrow buffers pooled for reuse, as a fraction
+    /// of native memory, 0 to disable, see buffer_pool
+    pub buffer_pool_fraction: f64,
+    /// number of threads executing sorts, aggregates and hash joins within a
+    /// task, 1 to disable, see intra_task_parallel_exec
+    pub intra_task_parallelism: usize,
+    /// min number of worker threads of the runtime executing a task, e.g. for
+    /// polling multiplexed partitions and prefetching concurrently. there are
+    /// at least intra_task_parallelism threads.
+    pub task_worker_threads: usize,
+    /// interval of sweeping spill directories of tasks without live spill
+    /// files, which are not modified within the interval. 0 to disable, see
+    /// spill_manager
+    pub spill_sweep_interval_secs: u64,
+}
+
+impl Default for NativeConf {
+    fn default() -> Self {
+        Self {
+            batch_size: None,
+            shuffle_compression_level: 1,
+            output_prefetch_bytes: None,
+            log_level: None,
+            max_output_batch_bytes: 64 << 20,
+            max_input_batch_bytes: 8 << 20,
+            utf8_validation: Utf8ValidationPolicy::default(),
+            shuffle_codec: ShuffleCodecPolicy::default(),
+            shuffle_zstd_dictionary_bytes: 0,
+            shuffle_zstd_dictionary_samples: 32,
+            shuffle_checksum: true,
+            shuffle_fetch_batch_size: 16,
+            shuffle_fetch_batch_bytes: 64 << 20,
+            shuffle_local_mmap: true,
+            shuffle_sort_based_min_partitions: 2000,
+            spill_mmap: true,
+            plan_cache: true,
+            scan_prefetch_batches: 4,
+            scan_prefetch_bytes: 32 << 20,
+            fault_injection: FaultInjectionRules::default(),
+            smj_max_buffered_group_bytes: 128 << 20,
+            shared_dictionary_columns: SharedDictionaryColumns::default(),
+            shared_dictionary_max_values: 65536,
+            partial_agg_skipping_min_rows: 100000,
+            partial_agg_skipping_min_ratio: 0.9,
+            filter_late_materialization: true,
+            filter_project_fusion: true,
+            constant_folding: true,
+            common_subexpr_elimination: true,
+            radix_sort_max_bytes: 64 << 20,
+            topk_aggregate_max_limit: 10000,
+            topk_shuffle_read_max_limit: 10000,
+            resource_wait_timeout_ms: 10000,
+            resource_retry_backoff_ms: 10,
+            buffer_pool_fraction: 0.1,
+            intra_task_parallelism: 1,
+            task_worker_threads: 1,
+            spill_sweep_interval_secs: 600,
+        }
+    }
+}
+
+fn native_conf_cell() -> &'static RwLock<Arc<NativeConf>> {
+    static NATIVE_CONF: OnceCell<RwLock<Arc<NativeConf>>> = OnceCell::new();
+    NATIVE_CONF.get_or_init(|| RwLock::new(Arc::new(NativeConf::default())))
+}
+
+thread_local! {
+    static TASK_NATIVE_CONF: RefCell<Option<Arc<NativeConf>>> = RefCell::new(None);
+}
+
+/// returns the current snapshot of native tunables, or the tunables of the
+/// task running in the current thread if set by `set_task_native_conf()`
+pub fn native_conf() -> Arc<NativeConf> {
+    TASK_NATIVE_CONF
+        .with(|conf| conf.borrow().clone())
+        .unwrap_or_else(|| native_conf_cell().read().unwrap().clone())
+}
+
+/// overrides tunables returned by `native_conf()` in the current thread, None
+/// to restore the global tunables
+pub fn set_task_native_conf(conf: Option<Arc<NativeConf>>) {
+    TASK_NATIVE_CONF.with(|task_conf| *task_conf.borrow_mut() = conf);
+}
+
+/// updates tunables with key-value entries. all entries are validated before
+/// updating, so either all or none of them take effect.
+pub fn update_native_conf(
+    entries: impl IntoIterator<Item = (String, String)>,
+) -> Result<Arc<NativeConf>> {
+    let mut conf_cell = native_conf_cell().write().unwrap();
+    let mut new_conf = conf_cell.as_ref().clone();
+    apply_native_conf_entries(&mut new_conf, entries)?;
+
+    *conf_cell = Arc::new(new_conf);
+    Ok(conf_cell.clone())
+}
+
+/// returns the global tunables overridden with key-value entries, e.g. tunables
+/// set for a spark session. the global tunables are not updated.
+pub fn native_conf_with_overrides(
+    entries: impl IntoIterator<Item = (String, String)>,
+) -> Result<Arc<NativeConf>> {
+    let mut new_conf = native_conf_cell().read().unwrap().as_ref().clone();
+    apply_native_conf_entries(&mut new_conf, entries)?;
+    Ok(Arc::new(new_conf))
+}
+
+fn apply_native_conf_entries(
+    new_conf: &mut NativeConf,
+    entries: impl IntoIterator<Item = (String, String)>,
+) -> Result<()> {
+    for (key, value) in entries {
+        match key.as_str() {
+            CONF_BATCH_SIZE => {
+                let batch_size = parse_conf::<usize>(&key, &value)?;
+                if batch_size == 0 {
+                    return Err(DataFusionError::Plan(format!(
+                        "invalid native conf {}: must be positive",
+                        key
+                    )));
+                }
+                new_conf.batch_size = Some(batch_size);
+            }
+            CONF_SHUFFLE_COMPRESSION_LEVEL => {
+                let level = parse_conf::<i32>(&key, &value)?;
+                if !zstd::compression_level_range().contains(&level) {
+                    return Err(DataFusionError::Plan(format!(
+                        "invalid native conf {}: unsupported zstd level {}",
+                        key, level
+                    )));
+                }
+                new_conf.shuffle_compression_level = level;
+            }
+            CONF_OUTPUT_PREFETCH_BYTES => {
+                new_conf.output_prefetch_bytes = Some(parse_conf::<i64>(&key, &value)?);
+            }
+            CONF_LOG_LEVEL => {
+                new_conf.log_level = Some(parse_conf::<LevelFilter>(&key, &value)?);
+            }
+            CONF_MAX_OUTPUT_BATCH_BYTES => {
+                new_conf.max_output_batch_bytes = parse_conf::<usize>(&key, &value)?;
+            }
+            CONF_MAX_INPUT_BATCH_BYTES => {
+                new_conf.max_input_batch_bytes = parse_conf::<usize>(&key, &value)?;
+            }
+            CONF_UTF8_VALIDATION => {
+                new_conf.utf8_validation = value.trim().parse()?;
+            }
+            CONF_SHUFFLE_CODEC => {
+                new_conf.shuffle_codec = value.trim().parse()?;
+            }
+            CONF_SHUFFLE_ZSTD_DICTIONARY_BYTES => {
+                new_conf.shuffle_zstd_dictionary_bytes =
+                    parse_conf::<usize>(&key, &value)?;
+            }
+            CONF_SHUFFLE_ZSTD_DICTIONARY_SAMPLES => {
+                let samples = parse_conf::<usize>(&key, &value)?;
+                if samples == 0 {
+                    return Err(DataFusionError::Plan(format!(
+                        "invalid native conf {}: must be positive",
+                        key
+                    )));
+                }
+                new_conf.shuffle_zstd_dictionary_samples = samples;
+            }
+            CONF_SHUFFLE_CHECKSUM => {
+                new_conf.shuffle_checksum = parse_conf::<bool>(&key, &value)?;
+            }
+            CONF_SHUFFLE_FETCH_BATCH_SIZE => {
+                let batch_size = parse_conf::<usize>(&key, &value)?;
+                if batch_size == 0 {
+                    return Err(DataFusionError::Plan(format!(
+                        "invalid native conf {}: must be positive",
+                        key
+                    )));
+                }
+                new_conf.shuffle_fetch_batch_size = batch_size;
+            }
+            CONF_SHUFFLE_FETCH_BATCH_BYTES => {
+                new_conf.shuffle_fetch_batch_bytes = parse_conf::<usize>(&key, &value)?;
+            }
+            CONF_SHUFFLE_LOCAL_MMAP => {
+                new_conf.shuffle_local_mmap = parse_conf::<bool>(&key, &value)?;
+            }
+            CONF_SHUFFLE_SORT_BASED_MIN_PARTITIONS => {
+                new_conf.shuffle_sort_based_min_partitions =
+                    parse_conf::<usize>(&key, &value)?;
+            }
+            CONF_SPILL_MMAP => {
+                new_conf.spill_mmap = parse_conf::<bool>(&key, &value)?;
+            }
+            CONF_PLAN_CACHE => {
+                new_conf.plan_cache = parse_conf::<bool>(&key, &value)?;
+            }
+            CONF_SCAN_PREFETCH_BATCHES => {
+                new_conf.scan_prefetch_batches = parse_conf::<usize>(&key, &value)?;
+            }
+            CONF_SCAN_PREFETCH_BYTES => {
+                new_conf.scan_prefetch_bytes = parse_conf::<usize>(&key, &value)?;
+            }
+            CONF_FAULT_INJECTION => {
+                new_conf.fault_injection = value.trim().parse()?;
+            }
+            CONF_SMJ_MAX_BUFFERED_GROUP_BYTES => {
+                new_conf.smj_max_buffered_group_bytes =
+                    parse_conf::<usize>(&key, &value)?;
+            }
+            CONF_SHARED_DICTIONARY_COLUMNS => {
+                new_conf.shared_dictionary_columns = value.trim().parse()?;
+            }
+            CONF_SHARED_DICTIONARY_MAX_VALUES => {
+                new_conf.shared_dictionary_max_values =
+                    parse_conf::<usize>(&key, &value)?;
+            }
+            CONF_PARTIAL_AGG_SKIPPING_MIN_ROWS => {
+                new_conf.partial_agg_skipping_min_rows =
+                    parse_conf::<usize>(&key, &value)?;
+            }
+            CONF_PARTIAL_AGG_SKIPPING_MIN_RATIO => {
+                let ratio = parse_conf::<f64>(&key, &value)?;
+                if ratio.is_nan() || ratio <= 0.0 {
+                    return Err(DataFusionError::Plan(format!(
+                        "invalid native conf {}: must be positive",
+                        key
+                    )));
+                }
+                new_conf.partial_agg_skipping_min_ratio = ratio;
+            }
+            CONF_FILTER_LATE_MATERIALIZATION => {
+                new_conf.filter_late_materialization = parse_conf::<bool>(&key, &value)?;
+            }
+            CONF_FILTER_PROJECT_FUSION => {
+                new_conf.filter_project_fusion = parse_conf::<bool>(&key, &value)?;
+            }
+            CONF_CONSTANT_FOLDING => {
+                new_conf.constant_folding = parse_conf::<bool>(&key, &value)?;
+            }
+            CONF_COMMON_SUBEXPR_ELIMINATION => {
+                new_conf.common_subexpr_elimination = parse_conf::<bool>(&key, &value)?;
+            }
+            CONF_RADIX_SORT_MAX_BYTES => {
+                new_conf.radix_sort_max_bytes = parse_conf::<usize>(&key, &value)?;
+            }
+            CONF_TOPK_AGGREGATE_MAX_LIMIT => {
+                new_conf.topk_aggregate_max_limit = parse_conf::<usize>(&key, &value)?;
+            }
+            CONF_TOPK_SHUFFLE_READ_MAX_LIMIT => {
+                new_conf.topk_shuffle_read_max_limit = parse_conf::<usize>(&key, &value)?;
+            }
+            CONF_RESOURCE_WAIT_TIMEOUT_MS => {
+                new_conf.resource_wait_timeout_ms = parse_conf::<u64>(&key, &value)?;
+            }
+            CONF_RESOURCE_RETRY_BACKOFF_MS => {
+                new_conf.resource_retry_backoff_ms = parse_conf::<u64>(&key, &value)?;
+            }
+            CONF_BUFFER_POOL_FRACTION => {
+                let fraction = parse_conf::<f64>(&key, &value)?;
+                if !(0.0..=1.0).contains(&fraction) {
+                    return Err(DataFusionError::Plan(format!(
+                        "invalid native conf {}: must be between 0 and 1",
+                        key
+                    )));
+                }
+                new_conf.buffer_pool_fraction = fraction;
+            }
+            CONF_INTRA_TASK_PARALLELISM => {
+                let parallelism = parse_conf::<usize>(&key, &value)?;
+                if parallelism == 0 {
+                    return Err(DataFusionError::Plan(format!(
+                        "invalid native conf {}: must be positive",
+                        key
+                    )));
+                }
+                new_conf.intra_task_parallelism = parallelism;
+            }
+            CONF_TASK_WORKER_THREADS => {
+                let worker_threads = parse_conf::<usize>(&key, &value)?;
+                if worker_threads == 0 {
+                    return Err(DataFusionError::Plan(format!(
+                        "invalid native conf {}: must be positive",
+                        key
+                    )));
+                }
+                new_conf.task_worker_threads = worker_threads;
+            }
+            CONF_SPILL_SWEEP_INTERVAL_SECS => {
+                new_conf.spill_sweep_interval_secs = parse_conf::<u64>(&key, &value)?;
+            }
+            _ => {
+                return Err(DataFusionError::Plan(format!(
+                    "unknown native conf: {}",
+                    key
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn parse_conf<T: FromStr>(key: &str, value: &str) -> Result<T> {
+    value.trim().parse::<T>().map_err(|_| {
+        DataFusionError::Plan(format!("invalid native conf {}: {}", key, value))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_update_native_conf() -> Result<()> {
+        let conf = update_native_conf(vec![
+            (CONF_BATCH_SIZE.to_owned(), "4096".to_owned()),
+            (CONF_LOG_LEVEL.to_owned(), "debug".to_owned()),
+        ])?;
+        assert_eq!(conf.batch_size, Some(4096));
+        assert_eq!(conf.log_level, Some(LevelFilter::Debug));
+
+        // invalid entries are not partially applied
+        assert!(update_native_conf(vec![
+            (CONF_BATCH_SIZE.to_owned(), "8192".to_owned()),
+            (CONF_SHUFFLE_COMPRESSION_LEVEL.to_owned(), "x".to_owned()),
+        ])
+        .is_err());
+        assert_eq!(native_conf().batch_size, Some(4096));
+
+        // task tunables override global tunables only in the current thread
+        let task_conf = native_conf_with_overrides(vec![(
+            CONF_BATCH_SIZE.to_owned(),
+            "1024".to_owned(),
+        )])?;
+        set_task_native_conf(Some(task_conf));
+        assert_eq!(native_conf().batch_size, Some(1024));
+        set_task_native_conf(None);
+        assert_eq!(native_conf().batch_size, Some(4096));
+        Ok(())
+    }
+}
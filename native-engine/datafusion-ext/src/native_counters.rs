@@ -0,0 +1,112 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Named counters of native operators and expressions, flushed into spark
+//! accumulators of the same names.
+//!
+//! counters are registered for the spark task and only incremented natively.
+//! the JVM side calls JniBridge.flushNativeCounters() once the task completes,
+//! which adds the values to the accumulators registered with
+//! NativeAccumulators on the driver. counters without a registered
+//! accumulator are dropped.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Arc;
+
+use dashmap::DashMap;
+use datafusion::error::Result;
+use jni::sys::jlong;
+use once_cell::sync::OnceCell;
+
+use crate::jni_call_static;
+
+static TASK_COUNTERS: OnceCell<DashMap<i64, HashMap<String, NativeCounter>>> =
+    OnceCell::new();
+
+fn task_counters() -> &'static DashMap<i64, HashMap<String, NativeCounter>> {
+    TASK_COUNTERS.get_or_init(DashMap::new)
+}
+
+/// A counter of a spark task. a default counter is not registered and never
+/// flushed.
+#[derive(Debug, Clone, Default)]
+pub struct NativeCounter {
+    value: Arc<AtomicI64>,
+}
+
+impl NativeCounter {
+    /// registers the counter for the spark task of the current thread.
+    /// counters of the same name in a task share the same value.
+    pub fn register(name: &str) -> Result<Self> {
+        let task_attempt_id = jni_call_static!(JniBridge.getTaskAttemptId() -> jlong)?;
+        Ok(Self::register_for_task(task_attempt_id as i64, name))
+    }
+
+    pub fn register_for_task(task_attempt_id: i64, name: &str) -> Self {
+        task_counters()
+            .entry(task_attempt_id)
+            .or_default()
+            .entry(name.to_owned())
+            .or_default()
+            .clone()
+    }
+
+    /// adds to the counter, returns the value before adding
+    pub fn add(&self, value: i64) -> i64 {
+        self.value.fetch_add(value, Ordering::Relaxed)
+    }
+
+    pub fn value(&self) -> i64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+/// takes all counters of the task with their current values, sorted by name
+pub fn take_task_counters(task_attempt_id: i64) -> Vec<(String, i64)> {
+    let mut counters = task_counters()
+        .remove(&task_attempt_id)
+        .map(|(_, counters)| counters)
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(name, counter)| (name, counter.value()))
+        .collect::<Vec<_>>();
+    counters.sort();
+    counters
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_native_counters() {
+        let rows = NativeCounter::register_for_task(-1, "rows");
+        let shared_rows = NativeCounter::register_for_task(-1, "rows");
+        let bytes = NativeCounter::register_for_task(-1, "bytes");
+        let other_task = NativeCounter::register_for_task(-2, "rows");
+        assert_eq!(rows.add(3), 0);
+        assert_eq!(shared_rows.add(4), 3);
+        bytes.add(100);
+        other_task.add(1);
+        NativeCounter::default().add(1000);
+
+        assert_eq!(
+            take_task_counters(-1),
+            vec![("bytes".to_owned(), 100), ("rows".to_owned(), 7)]
+        );
+        assert!(take_task_counters(-1).is_empty());
+        assert_eq!(take_task_counters(-2), vec![("rows".to_owned(), 1)]);
+    }
+}
@@ -0,0 +1,554 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Defines a nested-loop join for joins without equi-join keys, e.g. spark's
+//! broadcast nested-loop joins and cartesian products
+
+use std::any::Any;
+use std::fmt::Formatter;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use datafusion::arrow::array::{
+    new_null_array, Array, ArrayRef, BooleanArray, UInt32Array,
+};
+use datafusion::arrow::compute::{filter, filter_record_batch, take};
+use datafusion::arrow::datatypes::{Schema, SchemaRef};
+use datafusion::arrow::error::{ArrowError, Result as ArrowResult};
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::execution::context::TaskContext;
+use datafusion::physical_plan::common::{batch_byte_size, collect};
+use datafusion::physical_plan::expressions::PhysicalSortExpr;
+use datafusion::physical_plan::metrics::{
+    Count, ExecutionPlanMetricsSet, MetricBuilder, MetricsSet, Time,
+};
+use datafusion::physical_plan::stream::RecordBatchStreamAdapter;
+use datafusion::physical_plan::{
+    DisplayFormatType, ExecutionPlan, Partitioning, PhysicalExpr,
+    SendableRecordBatchStream, Statistics,
+};
+use datafusion::prelude::JoinType;
+use futures::{StreamExt, TryStreamExt};
+
+use crate::spillable_sort_merge_join_exec::build_join_schema;
+use crate::split_oversized_batches_exec::{row_bytes, OutputBatchBudget};
+
+/// Side of a nested-loop join collected in memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildSide {
+    Left,
+    Right,
+}
+
+/// Joins each row of the streamed side with all rows of the build side, which
+/// is collected in memory, keeping pairs satisfying the condition if any.
+///
+/// matched rows of the build side are not tracked, so outer/semi/anti joins
+/// must preserve the streamed side: left outer, semi and anti joins build the
+/// right side and right outer joins build the left side.
+///
+/// for cartesian products, `children_partitions` specifies the partitions of
+/// the left and right children joined by the executed partition.
+#[derive(Debug)]
+pub struct NestedLoopJoinExec {
+    left: Arc<dyn ExecutionPlan>,
+    right: Arc<dyn ExecutionPlan>,
+    join_type: JoinType,
+    /// bound to left columns followed by right columns
+    condition: Option<Arc<dyn PhysicalExpr>>,
+    build_side: BuildSide,
+    children_partitions: Option<(usize, usize)>,
+    schema: SchemaRef,
+    metrics: ExecutionPlanMetricsSet,
+}
+
+impl NestedLoopJoinExec {
+    pub fn try_new(
+        left: Arc<dyn ExecutionPlan>,
+        right: Arc<dyn ExecutionPlan>,
+        join_type: JoinType,
+        condition: Option<Arc<dyn PhysicalExpr>>,
+        build_side: BuildSide,
+        children_partitions: Option<(usize, usize)>,
+    ) -> Result<Self> {
+        let supported = match join_type {
+            JoinType::Inner => true,
+            JoinType::Left | JoinType::Semi | JoinType::Anti => {
+                build_side == BuildSide::Right
+            }
+            JoinType::Right => build_side == BuildSide::Left,
+            JoinType::Full => false,
+        };
+        if !supported {
+            return Err(DataFusionError::Plan(format!(
+                "nested-loop join does not support {:?} join building {:?} side",
+                join_type, build_side
+            )));
+        }
+        let schema = Arc::new(build_join_schema(
+            &left.schema(),
+            &right.schema(),
+            &join_type,
+        ));
+
+        Ok(Self {
+            left,
+            right,
+            join_type,
+            condition,
+            build_side,
+            children_partitions,
+            schema,
+            metrics: ExecutionPlanMetricsSet::new(),
+        })
+    }
+
+    /// schema of joined pairs which the condition is evaluated on
+    pub fn condition_schema(left: &Schema, right: &Schema) -> Schema {
+        build_join_schema(left, right, &JoinType::Inner)
+    }
+}
+
+#[async_trait]
+impl ExecutionPlan for NestedLoopJoinExec {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+
+    fn output_partitioning(&self) -> Partitioning {
+        if self.children_partitions.is_some() {
+            let num_partitions = self.left.output_partitioning().partition_count()
+                * self.right.output_partitioning().partition_count();
+            return Partitioning::UnknownPartitioning(num_partitions);
+        }
+        match self.build_side {
+            BuildSide::Left => self.right.output_partitioning(),
+            BuildSide::Right => self.left.output_partitioning(),
+        }
+    }
+
+    fn output_ordering(&self) -> Option<&[PhysicalSortExpr]> {
+        None
+    }
+
+    fn children(&self) -> Vec<Arc<dyn ExecutionPlan>> {
+        vec![self.left.clone(), self.right.clone()]
+    }
+
+    fn with_new_children(
+        self: Arc<Self>,
+        children: Vec<Arc<dyn ExecutionPlan>>,
+    ) -> Result<Arc<dyn ExecutionPlan>> {
+        match children.len() {
+            2 => Ok(Arc::new(Self::try_new(
+                children[0].clone(),
+                children[1].clone(),
+                self.join_type,
+                self.condition.clone(),
+                self.build_side,
+                self.children_partitions,
+            )?)),
+            _ => Err(DataFusionError::Internal(
+                "NestedLoopJoinExec wrong number of children".to_owned(),
+            )),
+        }
+    }
+
+    fn execute(
+        &self,
+        partition: usize,
+        context: Arc<TaskContext>,
+    ) -> Result<SendableRecordBatchStream> {
+        let (left_partition, right_partition) =
+            self.children_partitions.unwrap_or((partition, partition));
+        let (build, build_partition, streamed, streamed_partition) = match self.build_side
+        {
+            BuildSide::Left => (&self.left, left_partition, &self.right, right_partition),
+            BuildSide::Right => {
+                (&self.right, right_partition, &self.left, left_partition)
+            }
+        };
+        let joiner = NestedLoopJoiner {
+            join_type: self.join_type,
+            build_side: self.build_side,
+            condition: self.condition.clone(),
+            schema: self.schema.clone(),
+            condition_schema: Arc::new(Self::condition_schema(
+                &self.left.schema(),
+                &self.right.schema(),
+            )),
+            budget: OutputBatchBudget::new(context.session_config().batch_size),
+            build: RecordBatch::new_empty(build.schema()),
+            streamed: None,
+            staged: vec![],
+            num_staged_rows: 0,
+            num_staged_bytes: 0,
+            output_rows: MetricBuilder::new(&self.metrics).output_rows(partition),
+            elapsed_compute: MetricBuilder::new(&self.metrics).elapsed_compute(partition),
+        };
+        let state = NestedLoopJoinState {
+            joiner,
+            build_input: Some(build.execute(build_partition, context.clone())?),
+            streamed_input: streamed.execute(streamed_partition, context)?,
+            finished: false,
+        };
+
+        let output = futures::stream::try_unfold(state, |mut state| async move {
+            if let Some(build_input) = state.build_input.take() {
+                let build_schema = build_input.schema();
+                let build_batches = collect(build_input).await?;
+                state.joiner.build = RecordBatch::concat(&build_schema, &build_batches)?;
+            }
+            while !state.finished {
+                if let Some(output) = state.joiner.join_streamed_batch()? {
+                    return Ok(Some((output, state)));
+                }
+                match state.streamed_input.next().await {
+                    Some(batch) => state.joiner.start_streamed_batch(batch?),
+                    None => {
+                        state.finished = true;
+                        if let Some(output) = state.joiner.take_staged()? {
+                            return Ok(Some((output, state)));
+                        }
+                    }
+                }
+            }
+            Ok::<_, DataFusionError>(None)
+        })
+        .map_err(|err| ArrowError::ExternalError(Box::new(err)));
+        Ok(Box::pin(RecordBatchStreamAdapter::new(
+            self.schema(),
+            output,
+        )))
+    }
+
+    fn metrics(&self) -> Option<MetricsSet> {
+        Some(self.metrics.clone_inner())
+    }
+
+    fn fmt_as(&self, t: DisplayFormatType, f: &mut Formatter) -> std::fmt::Result {
+        match t {
+            DisplayFormatType::Default => write!(
+                f,
+                "NestedLoopJoinExec: join_type={:?}, build_side={:?}, condition={:?}",
+                self.join_type, self.build_side, self.condition
+            ),
+        }
+    }
+
+    fn statistics(&self) -> Statistics {
+        Statistics::default()
+    }
+}
+
+struct NestedLoopJoinState {
+    joiner: NestedLoopJoiner,
+    build_input: Option<SendableRecordBatchStream>,
+    streamed_input: SendableRecordBatchStream,
+    finished: bool,
+}
+
+/// a streamed batch and its progress of joining with the build side
+struct StreamedBatch {
+    batch: RecordBatch,
+    matched: Vec<bool>,
+    /// index of the next pair of streamed and build rows to join
+    next_pair: usize,
+}
+
+struct NestedLoopJoiner {
+    join_type: JoinType,
+    build_side: BuildSide,
+    condition: Option<Arc<dyn PhysicalExpr>>,
+    schema: SchemaRef,
+    condition_schema: SchemaRef,
+    budget: OutputBatchBudget,
+    /// all rows of the build side
+    build: RecordBatch,
+    /// the streamed batch being joined
+    streamed: Option<StreamedBatch>,
+    staged: Vec<RecordBatch>,
+    num_staged_rows: usize,
+    num_staged_bytes: usize,
+    output_rows: Count,
+    elapsed_compute: Time,
+}
+
+impl NestedLoopJoiner {
+    /// starts joining the streamed batch with the build side
+    fn start_streamed_batch(&mut self, batch: RecordBatch) {
+        let matched = vec![false; batch.num_rows()];
+        self.streamed = Some(StreamedBatch {
+            batch,
+            matched,
+            next_pair: 0,
+        });
+    }
+
+    /// joins the current streamed batch with the build side, returns an output
+    /// batch as soon as enough rows are accumulated. returns None once the
+    /// streamed batch is fully joined.
+    fn join_streamed_batch(&mut self) -> Result<Option<RecordBatch>> {
+        let elapsed_compute = self.elapsed_compute.clone();
+        let _timer = elapsed_compute.timer();
+        let mut streamed = match self.streamed.take() {
+            Some(streamed) => streamed,
+            None => return Ok(None),
+        };
+        let num_build_rows = self.build.num_rows();
+
+        // pairs are enumerated in chunks fitting in the output budget, with
+        // streamed rows in the outer loop
+        let num_pairs = streamed.batch.num_rows() * num_build_rows;
+        let outputs_pairs = !matches!(self.join_type, JoinType::Semi | JoinType::Anti);
+        let max_pairs = self
+            .budget
+            .max_rows(row_bytes(&streamed.batch) + row_bytes(&self.build));
+        while streamed.next_pair < num_pairs {
+            if self.condition.is_none() && !outputs_pairs {
+                streamed.matched.iter_mut().for_each(|m| *m = true);
+                break;
+            }
+            let start = streamed.next_pair;
+            let end = (start + max_pairs).min(num_pairs);
+            let mut streamed_indices = UInt32Array::from_iter_values(
+                (start..end).map(|k| (k / num_build_rows) as u32),
+            );
+            let build_indices = UInt32Array::from_iter_values(
+                (start..end).map(|k| (k % num_build_rows) as u32),
+            );
+            let mut pairs =
+                self.take_pairs(&streamed.batch, &streamed_indices, &build_indices)?;
+
+            if let Some(condition) = &self.condition {
+                let selected = condition.evaluate(&pairs)?.into_array(pairs.num_rows());
+                let selected = selected
+                    .as_any()
+                    .downcast_ref::<BooleanArray>()
+                    .ok_or_else(|| {
+                        DataFusionError::Execution(format!(
+                            "nested-loop join condition must be boolean, got {:?}",
+                            selected.data_type()
+                        ))
+                    })?;
+                // null conditions are not satisfied
+                let selected = BooleanArray::from(
+                    selected.iter().map(|v| v == Some(true)).collect::<Vec<_>>(),
+                );
+                pairs = filter_record_batch(&pairs, &selected)?;
+                streamed_indices = filter(&streamed_indices, &selected)?
+                    .as_any()
+                    .downcast_ref::<UInt32Array>()
+                    .unwrap()
+                    .clone();
+            }
+            for &i in streamed_indices.values() {
+                streamed.matched[i as usize] = true;
+            }
+            if outputs_pairs && pairs.num_rows() > 0 {
+                self.stage(pairs.columns().to_vec())?;
+            }
+            streamed.next_pair = end;
+
+            // the rest of the streamed batch is joined by the next call
+            if self
+                .budget
+                .is_full(self.num_staged_rows, self.num_staged_bytes)
+            {
+                self.streamed = Some(streamed);
+                return self.take_staged();
+            }
+        }
+
+        match self.join_type {
+            JoinType::Left | JoinType::Right => {
+                let unmatched = BooleanArray::from(
+                    streamed.matched.iter().map(|&m| !m).collect::<Vec<_>>(),
+                );
+                let streamed = filter_record_batch(&streamed.batch, &unmatched)?;
+                let num_rows = streamed.num_rows();
+                if num_rows > 0 {
+                    let build_columns = self
+                        .build
+                        .schema()
+                        .fields()
+                        .iter()
+                        .map(|field| new_null_array(field.data_type(), num_rows))
+                        .collect::<Vec<_>>();
+                    let columns =
+                        self.ordered_columns(streamed.columns().to_vec(), build_columns);
+                    self.stage(columns)?;
+                }
+            }
+            JoinType::Semi | JoinType::Anti => {
+                let selected = BooleanArray::from(
+                    streamed
+                        .matched
+                        .iter()
+                        .map(|&m| m == (self.join_type == JoinType::Semi))
+                        .collect::<Vec<_>>(),
+                );
+                let streamed = filter_record_batch(&streamed.batch, &selected)?;
+                if streamed.num_rows() > 0 {
+                    self.stage(streamed.columns().to_vec())?;
+                }
+            }
+            JoinType::Inner | JoinType::Full => {}
+        }
+
+        if self
+            .budget
+            .is_full(self.num_staged_rows, self.num_staged_bytes)
+        {
+            return self.take_staged();
+        }
+        Ok(None)
+    }
+
+    /// builds a batch of joined pairs in the condition schema
+    fn take_pairs(
+        &self,
+        streamed: &RecordBatch,
+        streamed_indices: &UInt32Array,
+        build_indices: &UInt32Array,
+    ) -> Result<RecordBatch> {
+        let take_all = |batch: &RecordBatch, indices: &UInt32Array| {
+            batch
+                .columns()
+                .iter()
+                .map(|column| take(column.as_ref(), indices, None))
+                .collect::<ArrowResult<Vec<_>>>()
+        };
+        let columns = self.ordered_columns(
+            take_all(streamed, streamed_indices)?,
+            take_all(&self.build, build_indices)?,
+        );
+        Ok(RecordBatch::try_new(
+            self.condition_schema.clone(),
+            columns,
+        )?)
+    }
+
+    /// orders columns of both sides as left columns followed by right columns
+    fn ordered_columns(
+        &self,
+        streamed_columns: Vec<ArrayRef>,
+        build_columns: Vec<ArrayRef>,
+    ) -> Vec<ArrayRef> {
+        match self.build_side {
+            BuildSide::Left => [build_columns, streamed_columns].concat(),
+            BuildSide::Right => [streamed_columns, build_columns].concat(),
+        }
+    }
+
+    fn stage(&mut self, columns: Vec<ArrayRef>) -> Result<()> {
+        let batch = RecordBatch::try_new(self.schema.clone(), columns)?;
+        self.num_staged_rows += batch.num_rows();
+        self.num_staged_bytes += batch_byte_size(&batch);
+        self.staged.push(batch);
+        Ok(())
+    }
+
+    fn take_staged(&mut self) -> Result<Option<RecordBatch>> {
+        if self.staged.is_empty() {
+            return Ok(None);
+        }
+        let batch = RecordBatch::concat(&self.schema, &self.staged)?;
+        self.staged.clear();
+        self.num_staged_rows = 0;
+        self.num_staged_bytes = 0;
+        self.output_rows.add(batch.num_rows());
+        Ok(Some(batch))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use datafusion::arrow::array::Int32Array;
+    use datafusion::arrow::record_batch::RecordBatch;
+    use datafusion::error::Result;
+    use datafusion::logical_plan::Operator;
+    use datafusion::physical_plan::common::collect;
+    use datafusion::physical_plan::expressions::{binary, col};
+    use datafusion::physical_plan::ExecutionPlan;
+    use datafusion::prelude::{JoinType, SessionContext};
+
+    use super::*;
+    use crate::test_util::memory_exec;
+
+    #[test]
+    fn test_nested_loop_join() -> Result<()> {
+        let left = memory_exec("a", vec![1, 2, 3])?;
+        let right = memory_exec("b", vec![2, 3])?;
+        let condition_schema =
+            NestedLoopJoinExec::condition_schema(&left.schema(), &right.schema());
+        let condition = binary(
+            col("a", &condition_schema)?,
+            Operator::Lt,
+            col("b", &condition_schema)?,
+            &condition_schema,
+        )?;
+        let task_ctx = SessionContext::new().task_ctx();
+        let runtime = tokio::runtime::Runtime::new()?;
+
+        let mut results = vec![];
+        for join_type in [JoinType::Inner, JoinType::Left, JoinType::Anti] {
+            let join = NestedLoopJoinExec::try_new(
+                left.clone(),
+                right.clone(),
+                join_type,
+                Some(condition.clone()),
+                BuildSide::Right,
+                None,
+            )?;
+            let stream = join.execute(0, task_ctx.clone())?;
+            let batches = runtime.block_on(collect(stream))?;
+            let batch = RecordBatch::concat(&join.schema(), &batches)?;
+            let column = |i: usize| {
+                let array = batch.column(i).as_any().downcast_ref::<Int32Array>();
+                array.unwrap().iter().collect::<Vec<_>>()
+            };
+            results.push((0..batch.num_columns()).map(column).collect::<Vec<_>>());
+        }
+        assert_eq!(
+            results,
+            vec![
+                vec![
+                    vec![Some(1), Some(1), Some(2)],
+                    vec![Some(2), Some(3), Some(3)]
+                ],
+                vec![
+                    vec![Some(1), Some(1), Some(2), Some(3)],
+                    vec![Some(2), Some(3), Some(3), None]
+                ],
+                vec![vec![Some(3)]],
+            ]
+        );
+        assert!(NestedLoopJoinExec::try_new(
+            left,
+            right,
+            JoinType::Semi,
+            None,
+            BuildSide::Left,
+            None,
+        )
+        .is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,404 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Defines spark's NormalizeNaNAndZero, which spark's optimizer wraps around
+//! float join keys, group keys and window partition keys, and spark's
+//! comparisons of float values.
+//!
+//! spark treats all NaNs as equal and -0.0 as equal to 0.0 when grouping and
+//! joining. native hashing and comparisons work on the values' bits, so NaNs
+//! are replaced with the canonical NaN and -0.0 with 0.0 beforehand. sort keys
+//! are normalized the same way, so arrow's total order of floats places NaNs
+//! after all other values like spark. comparisons follow the same order, where
+//! `NaN = NaN` is true and NaN is greater than any other value.
+
+use std::any::Any;
+use std::cmp::Ordering;
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+
+use datafusion::arrow::array::{
+    Array, ArrayRef, BooleanArray, Float32Array, Float64Array,
+};
+use datafusion::arrow::compute::cast;
+use datafusion::arrow::datatypes::{DataType, Schema};
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::logical_plan::Operator;
+use datafusion::physical_expr::PhysicalSortExpr;
+use datafusion::physical_plan::{ColumnarValue, PhysicalExpr};
+
+/// replaces NaNs with the canonical NaN and -0.0 with 0.0, arrays of other
+/// types are returned as is
+pub fn normalize_nan_and_zero(array: &ArrayRef) -> Result<ArrayRef> {
+    Ok(match array.data_type() {
+        DataType::Float32 => {
+            let floats = array.as_any().downcast_ref::<Float32Array>().unwrap();
+            let normalized = floats
+                .iter()
+                .map(|v| {
+                    v.map(|v| match v {
+                        v if v.is_nan() => f32::NAN,
+                        v if v == 0.0 => 0.0,
+                        v => v,
+                    })
+                })
+                .collect::<Float32Array>();
+            Arc::new(normalized)
+        }
+        DataType::Float64 => {
+            let floats = array.as_any().downcast_ref::<Float64Array>().unwrap();
+            let normalized = floats
+                .iter()
+                .map(|v| {
+                    v.map(|v| match v {
+                        v if v.is_nan() => f64::NAN,
+                        v if v == 0.0 => 0.0,
+                        v => v,
+                    })
+                })
+                .collect::<Float64Array>();
+            Arc::new(normalized)
+        }
+        _ => array.clone(),
+    })
+}
+
+/// returns true for float and double types
+pub fn is_float_type(data_type: &DataType) -> bool {
+    matches!(data_type, DataType::Float32 | DataType::Float64)
+}
+
+/// wraps float sort keys with NormalizeNaNAndZeroExpr, other sort keys are
+/// returned as is
+pub fn normalize_sort_expr(
+    sort_expr: PhysicalSortExpr,
+    input_schema: &Schema,
+) -> Result<PhysicalSortExpr> {
+    if !is_float_type(&sort_expr.expr.data_type(input_schema)?) {
+        return Ok(sort_expr);
+    }
+    Ok(PhysicalSortExpr {
+        expr: Arc::new(NormalizeNaNAndZeroExpr::new(sort_expr.expr)),
+        options: sort_expr.options,
+    })
+}
+
+/// Spark's NormalizeNaNAndZero of float and double values
+#[derive(Debug)]
+pub struct NormalizeNaNAndZeroExpr {
+    expr: Arc<dyn PhysicalExpr>,
+}
+
+impl NormalizeNaNAndZeroExpr {
+    pub fn new(expr: Arc<dyn PhysicalExpr>) -> Self {
+        Self { expr }
+    }
+
+    pub fn expr(&self) -> &Arc<dyn PhysicalExpr> {
+        &self.expr
+    }
+}
+
+impl Display for NormalizeNaNAndZeroExpr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "normalize_nan_and_zero({})", self.expr)
+    }
+}
+
+impl PhysicalExpr for NormalizeNaNAndZeroExpr {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn data_type(&self, input_schema: &Schema) -> Result<DataType> {
+        self.expr.data_type(input_schema)
+    }
+
+    fn nullable(&self, input_schema: &Schema) -> Result<bool> {
+        self.expr.nullable(input_schema)
+    }
+
+    fn evaluate(&self, batch: &RecordBatch) -> Result<ColumnarValue> {
+        let array = self.expr.evaluate(batch)?.into_array(batch.num_rows());
+        Ok(ColumnarValue::Array(normalize_nan_and_zero(&array)?))
+    }
+}
+
+/// Spark's `=`, `<>`, `<`, `<=`, `>` and `>=` of float and double values
+#[derive(Debug)]
+pub struct FloatComparisonExpr {
+    left: Arc<dyn PhysicalExpr>,
+    op: Operator,
+    right: Arc<dyn PhysicalExpr>,
+}
+
+impl FloatComparisonExpr {
+    pub fn try_new(
+        left: Arc<dyn PhysicalExpr>,
+        op: Operator,
+        right: Arc<dyn PhysicalExpr>,
+    ) -> Result<Self> {
+        match op {
+            Operator::Eq
+            | Operator::NotEq
+            | Operator::Lt
+            | Operator::LtEq
+            | Operator::Gt
+            | Operator::GtEq => Ok(Self { left, op, right }),
+            _ => Err(DataFusionError::Plan(format!(
+                "FloatComparisonExpr expects a comparison, found {}",
+                op
+            ))),
+        }
+    }
+
+    pub fn left(&self) -> &Arc<dyn PhysicalExpr> {
+        &self.left
+    }
+
+    pub fn op(&self) -> &Operator {
+        &self.op
+    }
+
+    pub fn right(&self) -> &Arc<dyn PhysicalExpr> {
+        &self.right
+    }
+}
+
+impl Display for FloatComparisonExpr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {} {}", self.left, self.op, self.right)
+    }
+}
+
+impl PhysicalExpr for FloatComparisonExpr {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn data_type(&self, _input_schema: &Schema) -> Result<DataType> {
+        Ok(DataType::Boolean)
+    }
+
+    fn nullable(&self, input_schema: &Schema) -> Result<bool> {
+        Ok(self.left.nullable(input_schema)? || self.right.nullable(input_schema)?)
+    }
+
+    fn evaluate(&self, batch: &RecordBatch) -> Result<ColumnarValue> {
+        let num_rows = batch.num_rows();
+        let left = self.left.evaluate(batch)?.into_array(num_rows);
+        let right = self.right.evaluate(batch)?.into_array(num_rows);
+
+        // floats are compared as doubles, which keeps their values and order
+        let left = cast(&left, &DataType::Float64)?;
+        let right = cast(&right, &DataType::Float64)?;
+        let left = left.as_any().downcast_ref::<Float64Array>().unwrap();
+        let right = right.as_any().downcast_ref::<Float64Array>().unwrap();
+
+        let result = left
+            .iter()
+            .zip(right.iter())
+            .map(|(l, r)| {
+                let ordering = spark_float_cmp(l?, r?);
+                Some(match self.op {
+                    Operator::Eq => ordering == Ordering::Equal,
+                    Operator::NotEq => ordering != Ordering::Equal,
+                    Operator::Lt => ordering == Ordering::Less,
+                    Operator::LtEq => ordering != Ordering::Greater,
+                    Operator::Gt => ordering == Ordering::Greater,
+                    _ => ordering != Ordering::Less,
+                })
+            })
+            .collect::<BooleanArray>();
+        Ok(ColumnarValue::Array(Arc::new(result)))
+    }
+}
+
+/// NaNs equal each other and are greater than any other value, -0.0 equals 0.0
+fn spark_float_cmp(l: f64, r: f64) -> Ordering {
+    match (l.is_nan(), r.is_nan()) {
+        (true, true) => Ordering::Equal,
+        (true, false) => Ordering::Greater,
+        (false, true) => Ordering::Less,
+        (false, false) => l.partial_cmp(&r).unwrap(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use datafusion::arrow::compute::SortOptions;
+    use datafusion::arrow::datatypes::Field;
+    use datafusion::physical_plan::common::collect;
+    use datafusion::physical_plan::expressions::{col, lit};
+    use datafusion::physical_plan::memory::MemoryExec;
+    use datafusion::physical_plan::sorts::sort::SortExec;
+    use datafusion::physical_plan::ExecutionPlan;
+    use datafusion::prelude::SessionContext;
+
+    use super::*;
+
+    #[test]
+    fn test_normalize_nan_and_zero() -> Result<()> {
+        let schema =
+            Arc::new(Schema::new(vec![Field::new("a", DataType::Float64, true)]));
+        let other_nan = f64::from_bits(f64::NAN.to_bits() | 1);
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![Arc::new(Float64Array::from(vec![
+                Some(-0.0),
+                Some(other_nan),
+                Some(-f64::NAN),
+                Some(1.5),
+                None,
+            ]))],
+        )?;
+        let expr = NormalizeNaNAndZeroExpr::new(col("a", &schema)?);
+        let result = expr.evaluate(&batch)?.into_array(batch.num_rows());
+        let result = result.as_any().downcast_ref::<Float64Array>().unwrap();
+        let bits = result
+            .iter()
+            .map(|v| v.map(f64::to_bits))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            bits,
+            vec![
+                Some(0.0f64.to_bits()),
+                Some(f64::NAN.to_bits()),
+                Some(f64::NAN.to_bits()),
+                Some(1.5f64.to_bits()),
+                None,
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_float_comparison() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("a", DataType::Float64, true),
+            Field::new("b", DataType::Float64, true),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![
+                Arc::new(Float64Array::from(vec![
+                    Some(f64::NAN),
+                    Some(-0.0),
+                    Some(f64::NAN),
+                    Some(1.0),
+                    None,
+                ])),
+                Arc::new(Float64Array::from(vec![
+                    Some(-f64::NAN),
+                    Some(0.0),
+                    Some(f64::INFINITY),
+                    Some(2.0),
+                    Some(1.0),
+                ])),
+            ],
+        )?;
+        let compare = |op: Operator| -> Result<BooleanArray> {
+            let expr =
+                FloatComparisonExpr::try_new(col("a", &schema)?, op, col("b", &schema)?)?;
+            let result = expr.evaluate(&batch)?.into_array(batch.num_rows());
+            Ok(result
+                .as_any()
+                .downcast_ref::<BooleanArray>()
+                .unwrap()
+                .clone())
+        };
+        assert_eq!(
+            compare(Operator::Eq)?,
+            BooleanArray::from(vec![
+                Some(true),
+                Some(true),
+                Some(false),
+                Some(false),
+                None
+            ])
+        );
+        assert_eq!(
+            compare(Operator::Gt)?,
+            BooleanArray::from(vec![
+                Some(false),
+                Some(false),
+                Some(true),
+                Some(false),
+                None
+            ])
+        );
+        assert_eq!(
+            compare(Operator::LtEq)?,
+            BooleanArray::from(vec![
+                Some(true),
+                Some(true),
+                Some(false),
+                Some(true),
+                None
+            ])
+        );
+        assert!(
+            FloatComparisonExpr::try_new(lit(1.0f64), Operator::Plus, lit(1.0f64))
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_normalized_sort() -> Result<()> {
+        let schema =
+            Arc::new(Schema::new(vec![Field::new("a", DataType::Float64, true)]));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![Arc::new(Float64Array::from(vec![
+                f64::NAN,
+                1.0,
+                -0.0,
+                f64::NEG_INFINITY,
+                -f64::NAN,
+                0.0,
+                f64::INFINITY,
+            ]))],
+        )?;
+        let input = Arc::new(MemoryExec::try_new(&[vec![batch]], schema.clone(), None)?);
+        let sort_expr = normalize_sort_expr(
+            PhysicalSortExpr {
+                expr: col("a", &schema)?,
+                options: SortOptions::default(),
+            },
+            &schema,
+        )?;
+        assert!(sort_expr.expr.as_any().is::<NormalizeNaNAndZeroExpr>());
+        let sort = Arc::new(SortExec::try_new(vec![sort_expr], input)?);
+
+        let task_ctx = SessionContext::new().task_ctx();
+        let runtime = tokio::runtime::Runtime::new()?;
+        let batches = runtime.block_on(collect(sort.execute(0, task_ctx)?))?;
+        let sorted = RecordBatch::concat(&schema, &batches)?;
+        let sorted = sorted
+            .column(0)
+            .as_any()
+            .downcast_ref::<Float64Array>()
+            .unwrap()
+            .values()
+            .to_vec();
+
+        // NaNs sort last, -0.0 and 0.0 are equal keys in any order
+        let expected = [f64::NEG_INFINITY, 0.0, 0.0, 1.0, f64::INFINITY];
+        assert_eq!(sorted[..5], expected);
+        assert!(sorted[5..].iter().all(|v| v.is_nan()));
+        Ok(())
+    }
+}
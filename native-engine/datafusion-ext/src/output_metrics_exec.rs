@@ -0,0 +1,205 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Metrics of output batches recorded for every operator of a plan.
+//!
+//! operators only record rows and times of their own. every operator of the
+//! plan is wrapped to additionally record `output_bytes`, the total memory of
+//! its output batches, and `peak_mem_used`, the memory of its largest output
+//! batch, which is a lower bound of the memory the operator holds at a time.
+//! both are reported to the JVM side with the other metrics of the operator.
+
+use std::any::Any;
+use std::fmt::Formatter;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use datafusion::arrow::datatypes::SchemaRef;
+use datafusion::arrow::error::Result as ArrowResult;
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::error::Result;
+use datafusion::execution::context::TaskContext;
+use datafusion::physical_plan::common::batch_byte_size;
+use datafusion::physical_plan::expressions::PhysicalSortExpr;
+use datafusion::physical_plan::metrics::{
+    Count, ExecutionPlanMetricsSet, Gauge, MetricBuilder, MetricsSet,
+};
+use datafusion::physical_plan::{
+    DisplayFormatType, ExecutionPlan, Partitioning, RecordBatchStream,
+    SendableRecordBatchStream, Statistics,
+};
+use futures::{Stream, StreamExt};
+
+use crate::with_new_children_preserving_partitioning;
+
+/// wraps every operator of the plan to record metrics of its output batches
+pub fn output_metrics_plan(
+    plan: Arc<dyn ExecutionPlan>,
+) -> Result<Arc<dyn ExecutionPlan>> {
+    let children = plan
+        .children()
+        .into_iter()
+        .map(output_metrics_plan)
+        .collect::<Result<Vec<_>>>()?;
+    let plan = if children.is_empty() {
+        plan
+    } else {
+        with_new_children_preserving_partitioning(plan, children)?
+    };
+    Ok(Arc::new(OutputMetricsExec {
+        input: plan,
+        metrics: ExecutionPlanMetricsSet::new(),
+    }))
+}
+
+/// Records output_bytes and peak_mem_used of its input operator.
+///
+/// metrics are those of the input with the recorded ones.
+#[derive(Debug)]
+struct OutputMetricsExec {
+    input: Arc<dyn ExecutionPlan>,
+    metrics: ExecutionPlanMetricsSet,
+}
+
+impl ExecutionPlan for OutputMetricsExec {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.input.schema()
+    }
+
+    fn output_partitioning(&self) -> Partitioning {
+        self.input.output_partitioning()
+    }
+
+    fn output_ordering(&self) -> Option<&[PhysicalSortExpr]> {
+        self.input.output_ordering()
+    }
+
+    crate::transparent_plan_tree!(
+        input,
+        |self, input| Self {
+            input,
+            metrics: self.metrics.clone()
+        },
+        own_metrics
+    );
+
+    fn execute(
+        &self,
+        partition: usize,
+        context: Arc<TaskContext>,
+    ) -> Result<SendableRecordBatchStream> {
+        Ok(Box::pin(OutputMetricsStream {
+            input: self.input.execute(partition, context)?,
+            output_bytes: MetricBuilder::new(&self.metrics)
+                .counter("output_bytes", partition),
+            peak_mem_used: MetricBuilder::new(&self.metrics)
+                .gauge("peak_mem_used", partition),
+        }))
+    }
+
+    fn metrics(&self) -> Option<MetricsSet> {
+        let mut metrics = self.input.metrics().unwrap_or_default();
+        for metric in self.metrics.clone_inner().iter() {
+            metrics.push(metric.clone());
+        }
+        Some(metrics)
+    }
+
+    fn fmt_as(&self, t: DisplayFormatType, f: &mut Formatter) -> std::fmt::Result {
+        self.input.fmt_as(t, f)
+    }
+
+    fn statistics(&self) -> Statistics {
+        self.input.statistics()
+    }
+}
+
+struct OutputMetricsStream {
+    input: SendableRecordBatchStream,
+    output_bytes: Count,
+    peak_mem_used: Gauge,
+}
+
+impl Stream for OutputMetricsStream {
+    type Item = ArrowResult<RecordBatch>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        let poll = self.input.poll_next_unpin(cx);
+        if let Poll::Ready(Some(Ok(batch))) = &poll {
+            let num_bytes = batch_byte_size(batch);
+            self.output_bytes.add(num_bytes);
+            if num_bytes > self.peak_mem_used.value() {
+                self.peak_mem_used.set(num_bytes);
+            }
+        }
+        poll
+    }
+}
+
+impl RecordBatchStream for OutputMetricsStream {
+    fn schema(&self) -> SchemaRef {
+        self.input.schema()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use datafusion::arrow::datatypes::{DataType, Field, Schema};
+    use datafusion::physical_plan::coalesce_batches::CoalesceBatchesExec;
+    use datafusion::physical_plan::common::collect;
+    use datafusion::physical_plan::memory::MemoryExec;
+    use datafusion::prelude::SessionContext;
+
+    use super::*;
+    use crate::test_util::build_batch;
+
+    #[test]
+    fn test_output_metrics() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int32, false)]));
+        let batches = vec![
+            build_batch!(schema, (0..100).collect::<Vec<i32>>())?,
+            build_batch!(schema, (0..1000).collect::<Vec<i32>>())?,
+        ];
+        let input = Arc::new(MemoryExec::try_new(&[batches.clone()], schema, None)?);
+        let plan = output_metrics_plan(Arc::new(CoalesceBatchesExec::new(input, 10)))?;
+        assert_eq!(plan.children().len(), 1);
+
+        let task_ctx = SessionContext::new().task_ctx();
+        let runtime = tokio::runtime::Runtime::new()?;
+        runtime.block_on(collect(plan.execute(0, task_ctx)?))?;
+
+        let metric = |plan: &Arc<dyn ExecutionPlan>, name: &str| {
+            plan.metrics()
+                .unwrap()
+                .sum_by_name(name)
+                .unwrap()
+                .as_usize()
+        };
+        let total_bytes = batch_byte_size(&batches[0]) + batch_byte_size(&batches[1]);
+        let leaf = &plan.children()[0];
+        assert_eq!(metric(leaf, "output_bytes"), total_bytes);
+        assert_eq!(metric(leaf, "peak_mem_used"), batch_byte_size(&batches[1]));
+        assert_eq!(metric(&plan, "output_bytes"), total_bytes);
+        assert_eq!(metric(&plan, "output_rows"), 1100);
+        Ok(())
+    }
+}
@@ -0,0 +1,310 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Defines an operator reporting per-column statistics of a parquet scan
+
+use std::any::Any;
+use std::collections::HashMap;
+use std::fmt::Formatter;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use datafusion::arrow::datatypes::SchemaRef;
+use datafusion::arrow::error::Result as ArrowResult;
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::datasource::file_format::parquet::ChunkObjectReader;
+use datafusion::datasource::listing::PartitionedFile;
+use datafusion::error::Result;
+use datafusion::execution::context::TaskContext;
+use datafusion::parquet::basic::Encoding;
+use datafusion::parquet::file::metadata::RowGroupMetaData;
+use datafusion::parquet::file::reader::{FileReader, SerializedFileReader};
+use datafusion::physical_plan::expressions::PhysicalSortExpr;
+use datafusion::physical_plan::file_format::FileScanConfig;
+use datafusion::physical_plan::metrics::{
+    ExecutionPlanMetricsSet, MetricBuilder, MetricsSet,
+};
+use datafusion::physical_plan::{
+    DisplayFormatType, ExecutionPlan, Partitioning, RecordBatchStream,
+    SendableRecordBatchStream, Statistics,
+};
+use futures::{Stream, StreamExt};
+
+/// prefixes of per-column metrics, followed by `.` and the column name
+pub const COLUMN_METRIC_PREFIXES: &[&str] = &[
+    "decode_time",
+    "compressed_bytes",
+    "uncompressed_bytes",
+    "dictionary_chunks",
+    "plain_chunks",
+    "rle_chunks",
+    "other_chunks",
+];
+
+/// Reports per-column compressed/uncompressed sizes and encodings of the
+/// column chunks scanned by its input parquet scan, read from the footers of
+/// the scanned files once the scan finishes.
+///
+/// the parquet reader decodes all columns together, so the decode time of a
+/// column is estimated by attributing the time waited for the scan to each
+/// column by its share of uncompressed bytes.
+///
+/// metrics are those of the input with the per-column metrics added.
+#[derive(Debug)]
+pub struct ParquetColumnMetricsExec {
+    input: Arc<dyn ExecutionPlan>,
+    base_config: FileScanConfig,
+    metrics: ExecutionPlanMetricsSet,
+}
+
+impl ParquetColumnMetricsExec {
+    pub fn new(input: Arc<dyn ExecutionPlan>, base_config: FileScanConfig) -> Self {
+        Self {
+            input,
+            base_config,
+            metrics: ExecutionPlanMetricsSet::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl ExecutionPlan for ParquetColumnMetricsExec {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.input.schema()
+    }
+
+    fn output_partitioning(&self) -> Partitioning {
+        self.input.output_partitioning()
+    }
+
+    fn output_ordering(&self) -> Option<&[PhysicalSortExpr]> {
+        self.input.output_ordering()
+    }
+
+    crate::transparent_plan_tree!(
+        input,
+        |self, input| Self::new(input, self.base_config.clone()),
+        own_metrics
+    );
+
+    fn execute(
+        &self,
+        partition: usize,
+        context: Arc<TaskContext>,
+    ) -> Result<SendableRecordBatchStream> {
+        let input = self.input.execute(partition, context)?;
+        Ok(Box::pin(ParquetColumnMetricsStream {
+            input,
+            base_config: self.base_config.clone(),
+            partition,
+            metrics: self.metrics.clone(),
+            wait_time: Duration::ZERO,
+            finished: false,
+        }))
+    }
+
+    fn metrics(&self) -> Option<MetricsSet> {
+        let mut metrics = self.input.metrics().unwrap_or_default();
+        for metric in self.metrics.clone_inner().iter() {
+            metrics.push(metric.clone());
+        }
+        Some(metrics)
+    }
+
+    fn fmt_as(&self, t: DisplayFormatType, f: &mut Formatter) -> std::fmt::Result {
+        self.input.fmt_as(t, f)
+    }
+
+    fn statistics(&self) -> Statistics {
+        self.input.statistics()
+    }
+}
+
+struct ParquetColumnMetricsStream {
+    input: SendableRecordBatchStream,
+    base_config: FileScanConfig,
+    partition: usize,
+    metrics: ExecutionPlanMetricsSet,
+    wait_time: Duration,
+    finished: bool,
+}
+
+impl ParquetColumnMetricsStream {
+    fn record_column_metrics(&self) -> Result<()> {
+        let files = self
+            .base_config
+            .file_groups
+            .get(self.partition)
+            .cloned()
+            .unwrap_or_default();
+        let mut stats: HashMap<String, ColumnStats> = HashMap::new();
+        for file in &files {
+            collect_file_column_stats(&self.base_config, file, &mut stats)?;
+        }
+
+        let schema = self.base_config.file_schema.clone();
+        let projection = self
+            .base_config
+            .projection
+            .clone()
+            .unwrap_or_else(|| (0..schema.fields().len()).collect());
+        let projected_names = projection
+            .iter()
+            .map(|&i| schema.field(i).name().clone())
+            .collect::<Vec<_>>();
+
+        let total_uncompressed_bytes: u64 = projected_names
+            .iter()
+            .filter_map(|name| stats.get(name))
+            .map(|stats| stats.uncompressed_bytes)
+            .sum();
+
+        for name in &projected_names {
+            let stats = stats.get(name).cloned().unwrap_or_default();
+            let builder = || MetricBuilder::new(&self.metrics);
+            let count = |prefix: &str, value: u64| {
+                builder()
+                    .counter(format!("{}.{}", prefix, name), self.partition)
+                    .add(value as usize);
+            };
+            count("compressed_bytes", stats.compressed_bytes);
+            count("uncompressed_bytes", stats.uncompressed_bytes);
+            count("dictionary_chunks", stats.dictionary_chunks);
+            count("plain_chunks", stats.plain_chunks);
+            count("rle_chunks", stats.rle_chunks);
+            count("other_chunks", stats.other_chunks);
+
+            if total_uncompressed_bytes > 0 {
+                let share =
+                    stats.uncompressed_bytes as f64 / total_uncompressed_bytes as f64;
+                builder()
+                    .subset_time(format!("decode_time.{}", name), self.partition)
+                    .add_duration(self.wait_time.mul_f64(share));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl RecordBatchStream for ParquetColumnMetricsStream {
+    fn schema(&self) -> SchemaRef {
+        self.input.schema()
+    }
+}
+
+impl Stream for ParquetColumnMetricsStream {
+    type Item = ArrowResult<RecordBatch>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        let start_time = Instant::now();
+        let poll = self.input.poll_next_unpin(cx);
+        self.wait_time += start_time.elapsed();
+
+        if let Poll::Ready(None) = poll {
+            if !self.finished {
+                self.finished = true;
+
+                // metrics are informational, failing to read footers does not
+                // fail the scan
+                if let Err(err) = self.record_column_metrics() {
+                    log::warn!("error collecting parquet column metrics: {}", err);
+                }
+            }
+        }
+        poll
+    }
+}
+
+/// statistics of all column chunks of a top-level column
+#[derive(Debug, Clone, Default)]
+struct ColumnStats {
+    compressed_bytes: u64,
+    uncompressed_bytes: u64,
+    dictionary_chunks: u64,
+    plain_chunks: u64,
+    rle_chunks: u64,
+    other_chunks: u64,
+}
+
+fn collect_file_column_stats(
+    base_config: &FileScanConfig,
+    file: &PartitionedFile,
+    stats: &mut HashMap<String, ColumnStats>,
+) -> Result<()> {
+    let object_reader = base_config
+        .object_store
+        .file_reader(file.file_meta.sized_file.clone())?;
+    let file_reader = SerializedFileReader::new(ChunkObjectReader(object_reader))?;
+
+    for row_group in file_reader.metadata().row_groups() {
+        if !row_group_in_range(row_group, file) {
+            continue;
+        }
+        for column in row_group.columns() {
+            // leaves of nested columns are accounted to their top-level columns
+            let name = column.column_path().parts()[0].clone();
+            let stats = stats.entry(name).or_default();
+            stats.compressed_bytes += column.compressed_size() as u64;
+            stats.uncompressed_bytes += column.uncompressed_size() as u64;
+
+            // levels are always RLE encoded, so data encodings are checked first
+            let encodings = column.encodings();
+            if encodings.iter().any(|encoding| {
+                matches!(
+                    encoding,
+                    Encoding::PLAIN_DICTIONARY | Encoding::RLE_DICTIONARY
+                )
+            }) {
+                stats.dictionary_chunks += 1;
+            } else if encodings.contains(&Encoding::PLAIN) {
+                stats.plain_chunks += 1;
+            } else if encodings.iter().all(|encoding| *encoding == Encoding::RLE) {
+                stats.rle_chunks += 1;
+            } else {
+                stats.other_chunks += 1;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// a row group belongs to the file split containing its midpoint, the same as
+/// the parquet reader
+pub(crate) fn row_group_in_range(
+    row_group: &RowGroupMetaData,
+    file: &PartitionedFile,
+) -> bool {
+    let range = match &file.range {
+        Some(range) => range,
+        None => return true,
+    };
+    let start_offset = match row_group.columns().first() {
+        Some(column) => column
+            .dictionary_page_offset()
+            .unwrap_or_else(|| column.data_page_offset()),
+        None => return false,
+    };
+    let midpoint = start_offset + row_group.compressed_size() / 2;
+    range.start <= midpoint && midpoint < range.end
+}
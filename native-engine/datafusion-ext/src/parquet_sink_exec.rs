@@ -0,0 +1,688 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Defines the native write path of spark's InsertIntoHadoopFsRelation for
+//! parquet.
+//!
+//! rows of a task are written into a staging directory of the task, laid out
+//! the way spark's DynamicPartitionDataWriter does: a `col=value` directory
+//! for each dynamic partition, a file for each bucket, and files rolled by
+//! size or number of records. files are not committed here, the written-file
+//! metadata is returned to JVM, which moves each file to the path given by
+//! the commit protocol (`part-<split>-<job id><ext>`) and commits the task.
+
+use std::any::Any;
+use std::collections::HashMap;
+use std::fmt::Formatter;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use datafusion::arrow::array::{Array, ArrayRef, Int64Array, StringArray, UInt32Array};
+use datafusion::arrow::compute::{lexicographical_partition_ranges, take, SortColumn};
+use datafusion::arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use datafusion::arrow::error::ArrowError;
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::arrow::util::display::array_value_to_string;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::execution::context::TaskContext;
+use datafusion::parquet::arrow::ArrowWriter;
+use datafusion::parquet::basic::Compression;
+use datafusion::parquet::file::metadata::KeyValue;
+use datafusion::parquet::file::properties::WriterProperties;
+use datafusion::physical_plan::expressions::PhysicalSortExpr;
+use datafusion::physical_plan::memory::MemoryStream;
+use datafusion::physical_plan::metrics::{
+    BaselineMetrics, ExecutionPlanMetricsSet, MetricsSet,
+};
+use datafusion::physical_plan::stream::RecordBatchStreamAdapter;
+use datafusion::physical_plan::{
+    DisplayFormatType, ExecutionPlan, Partitioning, SendableRecordBatchStream, Statistics,
+};
+use futures::{StreamExt, TryFutureExt, TryStreamExt};
+use once_cell::sync::OnceCell;
+
+use crate::hdfs_object_store::HDFSFileWriter;
+use crate::spark_hash::{create_hashes, pmod};
+
+/// name of the partition directory of null or empty partition values
+const DEFAULT_PARTITION_NAME: &str = "__HIVE_DEFAULT_PARTITION__";
+
+/// buffered rows of a file are flushed as a row group once reaching this,
+/// which bounds memory of wide rows and the granularity of rolling by size
+const MAX_ROW_GROUP_ROWS: usize = 1 << 17;
+
+#[derive(Debug, Clone)]
+pub struct ParquetSinkOptions {
+    /// dynamic partition columns, input must be sorted by them
+    pub partition_columns: Vec<String>,
+    pub bucket_columns: Vec<String>,
+    /// not bucketed if 0
+    pub num_buckets: usize,
+    pub compression: Compression,
+    /// extension of file names following file counters, e.g. .snappy.parquet
+    pub file_extension: String,
+    /// 0 for unlimited
+    pub max_file_bytes: u64,
+    /// 0 for unlimited
+    pub max_records_per_file: u64,
+    /// writes a file without rows if the input is empty
+    pub write_empty_file: bool,
+    pub metadata: Vec<(String, String)>,
+}
+
+/// Writes input rows into parquet files under `output_dir`, and outputs a row
+/// of metadata for each written file, see `written_files_schema()`.
+#[derive(Debug)]
+pub struct ParquetSinkExec {
+    input: Arc<dyn ExecutionPlan>,
+    output_dir: String,
+    options: ParquetSinkOptions,
+    metrics: ExecutionPlanMetricsSet,
+}
+
+impl ParquetSinkExec {
+    pub fn try_new(
+        input: Arc<dyn ExecutionPlan>,
+        output_dir: String,
+        options: ParquetSinkOptions,
+    ) -> Result<Self> {
+        let input_schema = input.schema();
+        for name in options
+            .partition_columns
+            .iter()
+            .chain(&options.bucket_columns)
+        {
+            input_schema.index_of(name)?;
+        }
+        if options.num_buckets == 0 && !options.bucket_columns.is_empty() {
+            return Err(DataFusionError::Plan(
+                "ParquetSinkExec: bucket columns require positive num_buckets".to_owned(),
+            ));
+        }
+        Ok(Self {
+            input,
+            output_dir,
+            options,
+            metrics: ExecutionPlanMetricsSet::new(),
+        })
+    }
+
+    pub fn output_dir(&self) -> &str {
+        &self.output_dir
+    }
+
+    pub fn options(&self) -> &ParquetSinkOptions {
+        &self.options
+    }
+}
+
+/// schema of written-file metadata: partition directory (null if not
+/// partitioned), path relative to the output directory, file name extension
+/// for the commit protocol, number of rows and bytes
+pub fn written_files_schema() -> SchemaRef {
+    static SCHEMA: OnceCell<SchemaRef> = OnceCell::new();
+    SCHEMA
+        .get_or_init(|| {
+            Arc::new(Schema::new(vec![
+                Field::new("partition", DataType::Utf8, true),
+                Field::new("path", DataType::Utf8, false),
+                Field::new("ext", DataType::Utf8, false),
+                Field::new("num_rows", DataType::Int64, false),
+                Field::new("num_bytes", DataType::Int64, false),
+            ]))
+        })
+        .clone()
+}
+
+#[async_trait]
+impl ExecutionPlan for ParquetSinkExec {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        written_files_schema()
+    }
+
+    fn output_partitioning(&self) -> Partitioning {
+        self.input.output_partitioning()
+    }
+
+    fn output_ordering(&self) -> Option<&[PhysicalSortExpr]> {
+        None
+    }
+
+    fn children(&self) -> Vec<Arc<dyn ExecutionPlan>> {
+        vec![self.input.clone()]
+    }
+
+    fn with_new_children(
+        self: Arc<Self>,
+        children: Vec<Arc<dyn ExecutionPlan>>,
+    ) -> Result<Arc<dyn ExecutionPlan>> {
+        match children.len() {
+            1 => Ok(Arc::new(Self::try_new(
+                children[0].clone(),
+                self.output_dir.clone(),
+                self.options.clone(),
+            )?)),
+            _ => Err(DataFusionError::Internal(
+                "ParquetSinkExec wrong number of children".to_owned(),
+            )),
+        }
+    }
+
+    fn execute(
+        &self,
+        partition: usize,
+        context: Arc<TaskContext>,
+    ) -> Result<SendableRecordBatchStream> {
+        let input = self.input.execute(partition, context)?;
+        let metrics = BaselineMetrics::new(&self.metrics, partition);
+        let writer = PartitionedWriter::try_new(
+            &input.schema(),
+            self.output_dir.clone(),
+            self.options.clone(),
+        )?;
+
+        Ok(Box::pin(RecordBatchStreamAdapter::new(
+            self.schema(),
+            futures::stream::once(
+                write_parquet(input, writer, metrics)
+                    .map_err(|e| ArrowError::ExternalError(Box::new(e))),
+            )
+            .try_flatten(),
+        )))
+    }
+
+    fn metrics(&self) -> Option<MetricsSet> {
+        Some(self.metrics.clone_inner())
+    }
+
+    fn fmt_as(&self, _t: DisplayFormatType, f: &mut Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "ParquetSinkExec: output_dir={}, partition_columns={:?}, \
+             bucket_columns={:?}, num_buckets={}",
+            self.output_dir,
+            self.options.partition_columns,
+            self.options.bucket_columns,
+            self.options.num_buckets,
+        )
+    }
+
+    fn statistics(&self) -> Statistics {
+        Statistics::default()
+    }
+}
+
+async fn write_parquet(
+    mut input: SendableRecordBatchStream,
+    mut writer: PartitionedWriter,
+    metrics: BaselineMetrics,
+) -> Result<SendableRecordBatchStream> {
+    while let Some(batch) = input.next().await.transpose()? {
+        let _timer = metrics.elapsed_compute().timer();
+        writer.write(&batch)?;
+    }
+
+    let _timer = metrics.elapsed_compute().timer();
+    let written_files = writer.finish()?;
+    metrics.record_output(written_files.num_rows());
+    Ok(Box::pin(MemoryStream::try_new(
+        vec![written_files],
+        written_files_schema(),
+        None,
+    )?))
+}
+
+/// a file being written, shared with the ArrowWriter so that bytes written
+/// and errors of closing are available after the ArrowWriter is closed
+#[derive(Clone)]
+struct SharedFileWriter(Arc<Mutex<HDFSFileWriter>>);
+
+impl Write for SharedFileWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+}
+
+struct WrittenFile {
+    partition: Option<String>,
+    path: String,
+    ext: String,
+    num_rows: u64,
+    num_bytes: u64,
+}
+
+struct OpenFile {
+    writer: ArrowWriter<SharedFileWriter>,
+    file: SharedFileWriter,
+    written: WrittenFile,
+}
+
+impl OpenFile {
+    fn close(self) -> Result<WrittenFile> {
+        let mut written = self.written;
+        self.writer.close()?;
+        let mut file = self.file.0.lock().unwrap();
+        file.close()?;
+        written.num_bytes = file.bytes_written();
+        Ok(written)
+    }
+}
+
+/// writes rows of a task, keeping a file open for each bucket of the current
+/// dynamic partition as spark's DynamicPartitionDataWriter does
+struct PartitionedWriter {
+    output_dir: String,
+    options: ParquetSinkOptions,
+    partition_indices: Vec<usize>,
+    bucket_indices: Vec<usize>,
+    data_indices: Vec<usize>,
+    data_schema: SchemaRef,
+    props: WriterProperties,
+    current_partition: Option<Option<String>>,
+    open_files: HashMap<Option<usize>, OpenFile>,
+    file_counters: HashMap<Option<usize>, usize>,
+    written_files: Vec<WrittenFile>,
+}
+
+impl PartitionedWriter {
+    fn try_new(
+        input_schema: &SchemaRef,
+        output_dir: String,
+        options: ParquetSinkOptions,
+    ) -> Result<Self> {
+        let index_of = |names: &[String]| -> Result<Vec<usize>> {
+            Ok(names
+                .iter()
+                .map(|name| input_schema.index_of(name))
+                .collect::<std::result::Result<_, ArrowError>>()?)
+        };
+        let partition_indices = index_of(&options.partition_columns)?;
+        let bucket_indices = index_of(&options.bucket_columns)?;
+        let data_indices = (0..input_schema.fields().len())
+            .filter(|i| !partition_indices.contains(i))
+            .collect::<Vec<_>>();
+        let data_schema = Arc::new(Schema::new(
+            data_indices
+                .iter()
+                .map(|&i| input_schema.field(i).clone())
+                .collect(),
+        ));
+        let props = WriterProperties::builder()
+            .set_compression(options.compression)
+            .set_max_row_group_size(MAX_ROW_GROUP_ROWS)
+            .set_key_value_metadata(Some(
+                options
+                    .metadata
+                    .iter()
+                    .map(|(key, value)| KeyValue {
+                        key: key.clone(),
+                        value: Some(value.clone()),
+                    })
+                    .collect(),
+            ))
+            .build();
+
+        Ok(Self {
+            output_dir,
+            options,
+            partition_indices,
+            bucket_indices,
+            data_indices,
+            data_schema,
+            props,
+            current_partition: None,
+            open_files: HashMap::new(),
+            file_counters: HashMap::new(),
+            written_files: vec![],
+        })
+    }
+
+    fn write(&mut self, batch: &RecordBatch) -> Result<()> {
+        if batch.num_rows() == 0 {
+            return Ok(());
+        }
+        let ranges = if self.partition_indices.is_empty() {
+            vec![0..batch.num_rows()]
+        } else {
+            let sort_columns = self
+                .partition_indices
+                .iter()
+                .map(|&i| SortColumn {
+                    values: batch.column(i).clone(),
+                    options: None,
+                })
+                .collect::<Vec<_>>();
+            lexicographical_partition_ranges(&sort_columns)?
+                .into_iter()
+                .collect::<Vec<_>>()
+        };
+
+        for range in ranges {
+            let rows = batch.slice(range.start, range.end - range.start);
+            let partition = self.partition_path(&rows)?;
+            if self.current_partition.as_ref() != Some(&partition) {
+                self.close_files()?;
+                self.file_counters.clear();
+                self.current_partition = Some(partition);
+            }
+
+            let data = RecordBatch::try_new(
+                self.data_schema.clone(),
+                self.data_indices
+                    .iter()
+                    .map(|&i| rows.column(i).clone())
+                    .collect(),
+            )?;
+            if self.options.num_buckets == 0 {
+                self.write_rows(None, data)?;
+                continue;
+            }
+
+            let bucket_ids = self.bucket_ids(&rows)?;
+            let mut bucket_rows: HashMap<usize, Vec<u32>> = HashMap::new();
+            for (row, &bucket_id) in bucket_ids.iter().enumerate() {
+                bucket_rows.entry(bucket_id).or_default().push(row as u32);
+            }
+            let mut bucket_rows = bucket_rows.into_iter().collect::<Vec<_>>();
+            bucket_rows.sort_unstable_by_key(|(bucket_id, _)| *bucket_id);
+            for (bucket_id, indices) in bucket_rows {
+                let indices = UInt32Array::from(indices);
+                let columns = data
+                    .columns()
+                    .iter()
+                    .map(|column| take(column.as_ref(), &indices, None))
+                    .collect::<std::result::Result<Vec<_>, _>>()?;
+                let bucket_data =
+                    RecordBatch::try_new(self.data_schema.clone(), columns)?;
+                self.write_rows(Some(bucket_id), bucket_data)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// writes rows into the open file of the bucket, rolling files by size or
+    /// number of records
+    fn write_rows(&mut self, bucket_id: Option<usize>, rows: RecordBatch) -> Result<()> {
+        let max_records = self.options.max_records_per_file;
+        let mut offset = 0;
+        while offset < rows.num_rows() {
+            if !self.open_files.contains_key(&bucket_id) {
+                let file = self.open_file(bucket_id)?;
+                self.open_files.insert(bucket_id, file);
+            }
+            let file = self.open_files.get_mut(&bucket_id).unwrap();
+
+            let mut len = rows.num_rows() - offset;
+            if max_records > 0 {
+                len = len.min((max_records - file.written.num_rows) as usize);
+            }
+            file.writer.write(&rows.slice(offset, len))?;
+            file.written.num_rows += len as u64;
+            offset += len;
+
+            let max_bytes = self.options.max_file_bytes;
+            let bytes_written = file.file.0.lock().unwrap().bytes_written();
+            if (max_records > 0 && file.written.num_rows >= max_records)
+                || (max_bytes > 0 && bytes_written >= max_bytes)
+            {
+                let file = self.open_files.remove(&bucket_id).unwrap();
+                self.written_files.push(file.close()?);
+                *self.file_counters.entry(bucket_id).or_default() += 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn open_file(&mut self, bucket_id: Option<usize>) -> Result<OpenFile> {
+        let partition = self.current_partition.clone().flatten();
+        let file_counter = self.file_counters.get(&bucket_id).cloned().unwrap_or(0);
+        let ext = file_name_extension(
+            partition.is_some() || bucket_id.is_some(),
+            bucket_id,
+            file_counter,
+            &self.options.file_extension,
+        );
+        let path = match &partition {
+            Some(partition) => format!("{}/part{}", partition, ext),
+            None => format!("part{}", ext),
+        };
+
+        let file = SharedFileWriter(Arc::new(Mutex::new(HDFSFileWriter::try_new(
+            &format!("{}/{}", self.output_dir, path),
+        )?)));
+        let writer = ArrowWriter::try_new(
+            file.clone(),
+            self.data_schema.clone(),
+            Some(self.props.clone()),
+        )?;
+        Ok(OpenFile {
+            writer,
+            file,
+            written: WrittenFile {
+                partition,
+                path,
+                ext,
+                num_rows: 0,
+                num_bytes: 0,
+            },
+        })
+    }
+
+    fn close_files(&mut self) -> Result<()> {
+        let mut open_files = std::mem::take(&mut self.open_files)
+            .into_iter()
+            .collect::<Vec<_>>();
+        open_files.sort_unstable_by_key(|(bucket_id, _)| *bucket_id);
+        for (_, file) in open_files {
+            self.written_files.push(file.close()?);
+        }
+        Ok(())
+    }
+
+    /// returns the partition directory of the first row, rows must be of the
+    /// same partition
+    fn partition_path(&self, rows: &RecordBatch) -> Result<Option<String>> {
+        if self.partition_indices.is_empty() {
+            return Ok(None);
+        }
+        let fragments = self
+            .partition_indices
+            .iter()
+            .map(|&i| {
+                let name = rows.schema().field(i).name().clone();
+                let value = partition_value(rows.column(i), 0)?;
+                Ok(format!("{}={}", escape_path_name(&name), value))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Some(fragments.join("/")))
+    }
+
+    /// computes bucket ids as spark's HashPartitioning(bucket columns)
+    fn bucket_ids(&self, rows: &RecordBatch) -> Result<Vec<usize>> {
+        let arrays = self
+            .bucket_indices
+            .iter()
+            .map(|&i| rows.column(i).clone())
+            .collect::<Vec<ArrayRef>>();
+        let mut hashes = vec![42; rows.num_rows()];
+        create_hashes(&arrays, &mut hashes)?;
+        Ok(hashes
+            .iter()
+            .map(|&hash| pmod(hash, self.options.num_buckets))
+            .collect())
+    }
+
+    fn finish(mut self) -> Result<RecordBatch> {
+        self.close_files()?;
+        if self.written_files.is_empty() && self.options.write_empty_file {
+            self.current_partition = Some(None);
+            let file = self.open_file(None)?;
+            self.written_files.push(file.close()?);
+        }
+
+        let files = &self.written_files;
+        Ok(RecordBatch::try_new(
+            written_files_schema(),
+            vec![
+                Arc::new(
+                    files
+                        .iter()
+                        .map(|file| file.partition.as_deref())
+                        .collect::<StringArray>(),
+                ),
+                Arc::new(
+                    files
+                        .iter()
+                        .map(|file| Some(file.path.as_str()))
+                        .collect::<StringArray>(),
+                ),
+                Arc::new(
+                    files
+                        .iter()
+                        .map(|file| Some(file.ext.as_str()))
+                        .collect::<StringArray>(),
+                ),
+                Arc::new(
+                    files
+                        .iter()
+                        .map(|file| file.num_rows as i64)
+                        .collect::<Int64Array>(),
+                ),
+                Arc::new(
+                    files
+                        .iter()
+                        .map(|file| file.num_bytes as i64)
+                        .collect::<Int64Array>(),
+                ),
+            ],
+        )?)
+    }
+}
+
+/// extension of file names as spark's writers: `-c000<ext>` for a single
+/// directory, or `_<bucket id>.c000<ext>` for partitioned or bucketed writes
+fn file_name_extension(
+    dynamic: bool,
+    bucket_id: Option<usize>,
+    file_counter: usize,
+    file_extension: &str,
+) -> String {
+    if !dynamic {
+        return format!("-c{:03}{}", file_counter, file_extension);
+    }
+    let bucket_id_str = bucket_id
+        .map(|bucket_id| format!("_{:05}", bucket_id))
+        .unwrap_or_default();
+    format!("{}.c{:03}{}", bucket_id_str, file_counter, file_extension)
+}
+
+/// formats a partition value as spark's cast to string, escaped for paths
+fn partition_value(column: &ArrayRef, row: usize) -> Result<String> {
+    if column.is_null(row) {
+        return Ok(DEFAULT_PARTITION_NAME.to_owned());
+    }
+    let value = match column.data_type() {
+        DataType::Boolean
+        | DataType::Int8
+        | DataType::Int16
+        | DataType::Int32
+        | DataType::Int64
+        | DataType::Utf8
+        | DataType::Date32 => array_value_to_string(column, row)?,
+        other => {
+            return Err(DataFusionError::NotImplemented(format!(
+                "ParquetSinkExec: unsupported partition column type {:?}",
+                other
+            )))
+        }
+    };
+    if value.is_empty() {
+        return Ok(DEFAULT_PARTITION_NAME.to_owned());
+    }
+    Ok(escape_path_name(&value))
+}
+
+/// escapes characters of a partition column name or value as spark's
+/// ExternalCatalogUtils.escapePathName
+fn escape_path_name(path: &str) -> String {
+    let mut escaped = String::with_capacity(path.len());
+    for c in path.chars() {
+        let needs_escape = matches!(
+            c,
+            '\u{01}'
+                ..='\u{1F}'
+                    | '"'
+                    | '#'
+                    | '%'
+                    | '\''
+                    | '*'
+                    | '/'
+                    | ':'
+                    | '='
+                    | '?'
+                    | '\\'
+                    | '\u{7F}'
+                    | '{'
+                    | '['
+                    | ']'
+                    | '^'
+        );
+        if needs_escape {
+            escaped.push_str(&format!("%{:02X}", c as u32));
+        } else {
+            escaped.push(c);
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spark_compatible_file_names() {
+        assert_eq!(
+            file_name_extension(false, None, 0, ".snappy.parquet"),
+            "-c000.snappy.parquet"
+        );
+        assert_eq!(
+            file_name_extension(true, Some(3), 1, ".snappy.parquet"),
+            "_00003.c001.snappy.parquet"
+        );
+        assert_eq!(
+            file_name_extension(true, None, 0, ".parquet"),
+            ".c000.parquet"
+        );
+
+        assert_eq!(escape_path_name("2022-06-01"), "2022-06-01");
+        assert_eq!(escape_path_name("a=b/c:d%"), "a%3Db%2Fc%3Ad%25");
+        assert_eq!(escape_path_name("中文 x"), "中文 x");
+
+        let column: ArrayRef =
+            Arc::new(StringArray::from(vec![Some(""), None, Some("x#")]));
+        assert_eq!(partition_value(&column, 0).unwrap(), DEFAULT_PARTITION_NAME);
+        assert_eq!(partition_value(&column, 1).unwrap(), DEFAULT_PARTITION_NAME);
+        assert_eq!(partition_value(&column, 2).unwrap(), "x%23");
+    }
+}
@@ -0,0 +1,409 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Defines an adaptive partial aggregation, which skips aggregating inputs of
+//! high cardinality grouping keys
+//!
+//! partial aggregations only reduce rows shuffled to final aggregations, which
+//! is a waste of time and memory when most grouping keys are distinct. the
+//! first `min_rows` input rows are aggregated to observe the reduction ratio.
+//! if the aggregated rows are at least `min_ratio` of the input rows, the rest
+//! of the input is aggregated batch by batch instead of in a whole hash table,
+//! passing rows to the final aggregation almost as they are, like the partial
+//! aggregation skipping of spark 3.4+.
+
+use std::any::Any;
+use std::fmt::{Debug, Formatter};
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use datafusion::arrow::datatypes::SchemaRef;
+use datafusion::arrow::error::{ArrowError, Result as ArrowResult};
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::execution::context::TaskContext;
+use datafusion::physical_expr::PhysicalSortExpr;
+use datafusion::physical_plan::aggregates::{AggregateExec, AggregateMode};
+use datafusion::physical_plan::common::collect;
+use datafusion::physical_plan::memory::MemoryExec;
+use datafusion::physical_plan::metrics::{
+    BaselineMetrics, ExecutionPlanMetricsSet, MetricsSet,
+};
+use datafusion::physical_plan::stream::RecordBatchStreamAdapter;
+use datafusion::physical_plan::{
+    DisplayFormatType, ExecutionPlan, Partitioning, SendableRecordBatchStream, Statistics,
+};
+use futures::stream::BoxStream;
+use futures::{StreamExt, TryStreamExt};
+
+/// wraps partial aggregations with PartialAggSkippingExec if enabled by native
+/// conf. aggregations without grouping keys output only one row and are not
+/// wrapped.
+pub fn skip_partial_agg(
+    agg: Arc<AggregateExec>,
+    min_rows: usize,
+    min_ratio: f64,
+) -> Result<Arc<dyn ExecutionPlan>> {
+    if min_rows == 0
+        || !matches!(agg.mode(), AggregateMode::Partial)
+        || agg.group_expr().is_empty()
+    {
+        return Ok(agg as Arc<dyn ExecutionPlan>);
+    }
+    Ok(Arc::new(PartialAggSkippingExec::try_new(
+        agg, min_rows, min_ratio,
+    )?))
+}
+
+/// Executes a partial aggregation, switching to aggregate batch by batch once
+/// the observed reduction ratio shows that most grouping keys are distinct.
+///
+/// the operator replaces the aggregation in the plan tree: children are those
+/// of the aggregation, so that the native metric tree still matches the JVM
+/// side.
+#[derive(Debug)]
+pub struct PartialAggSkippingExec {
+    agg: Arc<dyn ExecutionPlan>,
+    min_rows: usize,
+    min_ratio: f64,
+    metrics: ExecutionPlanMetricsSet,
+}
+
+impl PartialAggSkippingExec {
+    pub fn try_new(
+        agg: Arc<AggregateExec>,
+        min_rows: usize,
+        min_ratio: f64,
+    ) -> Result<Self> {
+        if !matches!(agg.mode(), AggregateMode::Partial) {
+            return Err(DataFusionError::Plan(format!(
+                "PartialAggSkippingExec expects a partial aggregation, found {:?}",
+                agg.mode()
+            )));
+        }
+        Ok(Self {
+            agg,
+            min_rows,
+            min_ratio,
+            metrics: ExecutionPlanMetricsSet::new(),
+        })
+    }
+
+    pub fn agg(&self) -> &Arc<dyn ExecutionPlan> {
+        &self.agg
+    }
+}
+
+#[async_trait]
+impl ExecutionPlan for PartialAggSkippingExec {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.agg.schema()
+    }
+
+    fn output_partitioning(&self) -> Partitioning {
+        self.agg.output_partitioning()
+    }
+
+    fn output_ordering(&self) -> Option<&[PhysicalSortExpr]> {
+        None
+    }
+
+    fn children(&self) -> Vec<Arc<dyn ExecutionPlan>> {
+        self.agg.children()
+    }
+
+    fn with_new_children(
+        self: Arc<Self>,
+        children: Vec<Arc<dyn ExecutionPlan>>,
+    ) -> Result<Arc<dyn ExecutionPlan>> {
+        Ok(Arc::new(Self {
+            agg: self.agg.clone().with_new_children(children)?,
+            min_rows: self.min_rows,
+            min_ratio: self.min_ratio,
+            metrics: ExecutionPlanMetricsSet::new(),
+        }))
+    }
+
+    fn execute(
+        &self,
+        partition: usize,
+        context: Arc<TaskContext>,
+    ) -> Result<SendableRecordBatchStream> {
+        let input = self.agg.children()[0].execute(partition, context.clone())?;
+        let agg = self.agg.clone();
+        let min_rows = self.min_rows;
+        let min_ratio = self.min_ratio;
+        let baseline_metrics = BaselineMetrics::new(&self.metrics, partition);
+
+        let stream = futures::stream::once(async move {
+            execute_adaptive(agg, input, min_rows, min_ratio, context).await
+        })
+        .try_flatten()
+        .map(
+            move |batch: ArrowResult<RecordBatch>| -> ArrowResult<RecordBatch> {
+                let batch = batch?;
+                baseline_metrics.record_output(batch.num_rows());
+                Ok(batch)
+            },
+        );
+        Ok(Box::pin(RecordBatchStreamAdapter::new(
+            self.schema(),
+            stream,
+        )))
+    }
+
+    fn metrics(&self) -> Option<MetricsSet> {
+        Some(self.metrics.clone_inner())
+    }
+
+    fn fmt_as(&self, t: DisplayFormatType, f: &mut Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "PartialAggSkippingExec: min_rows={}, min_ratio={}, ",
+            self.min_rows, self.min_ratio
+        )?;
+        self.agg.fmt_as(t, f)
+    }
+
+    fn statistics(&self) -> Statistics {
+        self.agg.statistics()
+    }
+}
+
+async fn execute_adaptive(
+    agg: Arc<dyn ExecutionPlan>,
+    mut input: SendableRecordBatchStream,
+    min_rows: usize,
+    min_ratio: f64,
+    context: Arc<TaskContext>,
+) -> ArrowResult<BoxStream<'static, ArrowResult<RecordBatch>>> {
+    let input_schema = input.schema();
+    let mut sampled_batches = vec![];
+    let mut num_sampled_rows = 0;
+    while num_sampled_rows < min_rows {
+        match input.next().await {
+            Some(batch) => {
+                let batch = batch?;
+                num_sampled_rows += batch.num_rows();
+                sampled_batches.push(batch);
+            }
+            None => break,
+        }
+    }
+    let sampled_output = collect(aggregate_batches(
+        &agg,
+        sampled_batches,
+        input_schema.clone(),
+        context.clone(),
+    )?)
+    .await
+    .map_err(ArrowError::from)?;
+    let num_sampled_output_rows: usize =
+        sampled_output.iter().map(|batch| batch.num_rows()).sum();
+
+    // the final aggregation merges rows of the same grouping keys, so the
+    // remaining input may be aggregated separately from the sampled rows
+    let skipping = num_sampled_rows >= min_rows
+        && num_sampled_output_rows as f64 >= num_sampled_rows as f64 * min_ratio;
+    let remaining = if skipping {
+        log::info!(
+            "skipping partial aggregation: {} sampled rows aggregated into {} rows",
+            num_sampled_rows,
+            num_sampled_output_rows,
+        );
+        input
+            .map(move |batch| {
+                batch.and_then(|batch| {
+                    aggregate_batches(
+                        &agg,
+                        vec![batch],
+                        input_schema.clone(),
+                        context.clone(),
+                    )
+                    .map_err(ArrowError::from)
+                })
+            })
+            .try_flatten()
+            .boxed()
+    } else {
+        let input: Arc<dyn ExecutionPlan> = Arc::new(StreamExec::new(input));
+        agg.with_new_children(vec![input])?
+            .execute(0, context)?
+            .boxed()
+    };
+    Ok(futures::stream::iter(sampled_output.into_iter().map(Ok))
+        .chain(remaining)
+        .boxed())
+}
+
+fn aggregate_batches(
+    agg: &Arc<dyn ExecutionPlan>,
+    batches: Vec<RecordBatch>,
+    schema: SchemaRef,
+    context: Arc<TaskContext>,
+) -> Result<SendableRecordBatchStream> {
+    let input: Arc<dyn ExecutionPlan> =
+        Arc::new(MemoryExec::try_new(&[batches], schema, None)?);
+    agg.clone()
+        .with_new_children(vec![input])?
+        .execute(0, context)
+}
+
+/// A single partition plan of an executing stream, which can be executed
+/// only once.
+pub(crate) struct StreamExec {
+    schema: SchemaRef,
+    stream: Mutex<Option<SendableRecordBatchStream>>,
+}
+
+impl StreamExec {
+    pub(crate) fn new(stream: SendableRecordBatchStream) -> Self {
+        Self {
+            schema: stream.schema(),
+            stream: Mutex::new(Some(stream)),
+        }
+    }
+}
+
+impl Debug for StreamExec {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "StreamExec")
+    }
+}
+
+#[async_trait]
+impl ExecutionPlan for StreamExec {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+
+    fn output_partitioning(&self) -> Partitioning {
+        Partitioning::UnknownPartitioning(1)
+    }
+
+    fn output_ordering(&self) -> Option<&[PhysicalSortExpr]> {
+        None
+    }
+
+    fn children(&self) -> Vec<Arc<dyn ExecutionPlan>> {
+        vec![]
+    }
+
+    fn with_new_children(
+        self: Arc<Self>,
+        _children: Vec<Arc<dyn ExecutionPlan>>,
+    ) -> Result<Arc<dyn ExecutionPlan>> {
+        Err(DataFusionError::Plan(
+            "StreamExec does not support with_new_children()".to_string(),
+        ))
+    }
+
+    fn execute(
+        &self,
+        _partition: usize,
+        _context: Arc<TaskContext>,
+    ) -> Result<SendableRecordBatchStream> {
+        self.stream.lock().unwrap().take().ok_or_else(|| {
+            DataFusionError::Execution("StreamExec is already executed".to_string())
+        })
+    }
+
+    fn fmt_as(&self, _t: DisplayFormatType, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "StreamExec")
+    }
+
+    fn statistics(&self) -> Statistics {
+        Statistics::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use datafusion::arrow::array::{Int32Array, Int64Array};
+    use datafusion::arrow::datatypes::{DataType, Field, Schema};
+    use datafusion::physical_plan::aggregates::{
+        create_aggregate_expr, AggregateFunction,
+    };
+    use datafusion::physical_plan::expressions::col;
+    use datafusion::prelude::SessionContext;
+
+    use super::*;
+
+    #[test]
+    fn test_partial_agg_skipping() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("k", DataType::Int32, false),
+            Field::new("v", DataType::Int64, false),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![
+                Arc::new(Int32Array::from(vec![0, 1, 2, 3])),
+                Arc::new(Int64Array::from(vec![1, 2, 3, 4])),
+            ],
+        )?;
+        let input = Arc::new(MemoryExec::try_new(
+            &[vec![batch.clone(), batch.clone(), batch]],
+            schema.clone(),
+            None,
+        )?);
+        let agg = Arc::new(AggregateExec::try_new(
+            AggregateMode::Partial,
+            vec![(col("k", &schema)?, "k".to_string())],
+            vec![create_aggregate_expr(
+                &AggregateFunction::Sum,
+                false,
+                &[col("v", &schema)?],
+                &schema,
+                "sum",
+            )?],
+            input,
+            schema.clone(),
+        )?);
+
+        let task_ctx = SessionContext::new().task_ctx();
+        let runtime = tokio::runtime::Runtime::new()?;
+        let run = |min_rows: usize, min_ratio: f64| -> Result<(usize, i64)> {
+            let exec = PartialAggSkippingExec::try_new(agg.clone(), min_rows, min_ratio)?;
+            let stream = exec.execute(0, task_ctx.clone())?;
+            let batches = runtime.block_on(collect(stream))?;
+            let num_rows = batches.iter().map(|batch| batch.num_rows()).sum();
+            let sum = batches
+                .iter()
+                .flat_map(|batch| {
+                    let sums = batch.column(1).as_any().downcast_ref::<Int64Array>();
+                    sums.unwrap().iter().flatten().collect::<Vec<_>>()
+                })
+                .sum();
+            Ok((num_rows, sum))
+        };
+
+        // all keys of the sampled batch are distinct, so later batches are
+        // aggregated one by one
+        assert_eq!(run(4, 0.9)?, (12, 30));
+        // sampled and remaining rows are aggregated separately
+        assert_eq!(run(4, 1.1)?, (8, 30));
+        // the whole input is sampled
+        assert_eq!(run(100, 0.9)?, (4, 30));
+        Ok(())
+    }
+}
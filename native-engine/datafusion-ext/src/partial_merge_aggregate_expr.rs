@@ -0,0 +1,148 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Defines an adapter of aggregate expressions for spark's PartialMerge mode
+//!
+//! spark inserts PartialMerge aggregations in multi-distinct plans, which
+//! merge partial aggregation buffers and output the merged buffers instead of
+//! final results. datafusion has no such mode, so a PartialMerge aggregation
+//! is executed as a Partial aggregation of adapted expressions, whose inputs
+//! are the state columns of the inner expressions and whose accumulators merge
+//! instead of update.
+
+use std::any::Any;
+use std::sync::Arc;
+
+use datafusion::arrow::array::ArrayRef;
+use datafusion::arrow::datatypes::{Field, Schema};
+use datafusion::error::Result;
+use datafusion::physical_plan::expressions::Column;
+use datafusion::physical_plan::{Accumulator, AggregateExpr, PhysicalExpr};
+use datafusion::scalar::ScalarValue;
+
+/// Merges aggregation buffers of the inner expression, taking the buffers
+/// from the input columns named as the inner expression's state fields.
+#[derive(Debug)]
+pub struct PartialMergeAggregateExpr {
+    inner: Arc<dyn AggregateExpr>,
+    state_columns: Vec<Arc<dyn PhysicalExpr>>,
+}
+
+impl PartialMergeAggregateExpr {
+    pub fn try_new(inner: Arc<dyn AggregateExpr>, input_schema: &Schema) -> Result<Self> {
+        let state_columns = inner
+            .state_fields()?
+            .iter()
+            .map(|field| {
+                Ok(
+                    Arc::new(Column::new_with_schema(field.name(), input_schema)?)
+                        as Arc<dyn PhysicalExpr>,
+                )
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            inner,
+            state_columns,
+        })
+    }
+}
+
+impl AggregateExpr for PartialMergeAggregateExpr {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn field(&self) -> Result<Field> {
+        self.inner.field()
+    }
+
+    fn create_accumulator(&self) -> Result<Box<dyn Accumulator>> {
+        Ok(Box::new(PartialMergeAccumulator {
+            inner: self.inner.create_accumulator()?,
+        }))
+    }
+
+    fn state_fields(&self) -> Result<Vec<Field>> {
+        self.inner.state_fields()
+    }
+
+    fn expressions(&self) -> Vec<Arc<dyn PhysicalExpr>> {
+        self.state_columns.clone()
+    }
+
+    fn name(&self) -> &str {
+        self.inner.name()
+    }
+}
+
+#[derive(Debug)]
+struct PartialMergeAccumulator {
+    inner: Box<dyn Accumulator>,
+}
+
+impl Accumulator for PartialMergeAccumulator {
+    fn state(&self) -> Result<Vec<ScalarValue>> {
+        self.inner.state()
+    }
+
+    fn update_batch(&mut self, values: &[ArrayRef]) -> Result<()> {
+        // input values of a partial merge are buffers of partial aggregations
+        self.inner.merge_batch(values)
+    }
+
+    fn merge_batch(&mut self, states: &[ArrayRef]) -> Result<()> {
+        self.inner.merge_batch(states)
+    }
+
+    fn evaluate(&self) -> Result<ScalarValue> {
+        self.inner.evaluate()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use datafusion::arrow::array::{ArrayRef, Int64Array};
+    use datafusion::arrow::datatypes::{DataType, Field, Schema};
+    use datafusion::error::Result;
+    use datafusion::physical_plan::expressions::{Column, Sum};
+    use datafusion::physical_plan::AggregateExpr;
+    use datafusion::scalar::ScalarValue;
+
+    use super::PartialMergeAggregateExpr;
+
+    #[test]
+    fn test_partial_merge_sum() -> Result<()> {
+        let sum: Arc<dyn AggregateExpr> = Arc::new(Sum::new(
+            Arc::new(Column::new("v", 0)),
+            "sum_v",
+            DataType::Int64,
+        ));
+        let state_schema = Schema::new(sum.state_fields()?);
+        let partial_merge = PartialMergeAggregateExpr::try_new(sum, &state_schema)?;
+        assert_eq!(partial_merge.expressions().len(), 1);
+
+        // buffers are merged and the merged buffer is output
+        let mut accumulator = partial_merge.create_accumulator()?;
+        let buffers: ArrayRef = Arc::new(Int64Array::from(vec![Some(1), None, Some(5)]));
+        accumulator.update_batch(&[buffers])?;
+        assert_eq!(accumulator.state()?, vec![ScalarValue::Int64(Some(6))]);
+        assert_eq!(
+            partial_merge.field()?,
+            Field::new("sum_v", DataType::Int64, true)
+        );
+        Ok(())
+    }
+}
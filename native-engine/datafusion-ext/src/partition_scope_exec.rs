@@ -0,0 +1,189 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Defines PartitionScopeExec, evaluating expressions of the wrapped operator
+//! in the scope of the executed partition.
+//!
+//! expressions depending on the partition, e.g. spark_partition_id() and
+//! rand(), are only given the batch in evaluate(). like spark initializing
+//! them with the partition index of the operator, they are evaluated with the
+//! partition passed to execute() of the operator, which differs from the
+//! partition of the spark task under unions and in tasks executing multiple
+//! partitions.
+//!
+//! each execution of a partition has its own scope, holding the partition and
+//! the states of the expressions, e.g. row numbers and random generators. the
+//! scope is set for the current thread while the stream of the operator is
+//! polled, so the wrapper must be placed directly above an operator
+//! evaluating its expressions in poll_next(), e.g. projections and filters.
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::Formatter;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+use datafusion::arrow::datatypes::SchemaRef;
+use datafusion::arrow::error::Result as ArrowResult;
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::error::Result;
+use datafusion::execution::context::TaskContext;
+use datafusion::physical_plan::expressions::PhysicalSortExpr;
+use datafusion::physical_plan::{
+    DisplayFormatType, ExecutionPlan, Partitioning, RecordBatchStream,
+    SendableRecordBatchStream, Statistics,
+};
+use futures::{Stream, StreamExt};
+
+thread_local! {
+    static CURRENT_SCOPE: RefCell<Option<Arc<PartitionScope>>> = RefCell::new(None);
+}
+
+/// The executed partition of an operator, with the states of its expressions
+#[derive(Debug)]
+pub struct PartitionScope {
+    partition: usize,
+    states: Mutex<HashMap<usize, Box<dyn Any + Send>>>,
+}
+
+impl PartitionScope {
+    pub fn new(partition: usize) -> Self {
+        Self {
+            partition,
+            states: Mutex::default(),
+        }
+    }
+
+    /// returns the scope of the operator evaluating expressions in the current
+    /// thread, or None outside of a PartitionScopeExec
+    pub fn current() -> Option<Arc<PartitionScope>> {
+        CURRENT_SCOPE.with(|current| current.borrow().clone())
+    }
+
+    pub fn partition(&self) -> usize {
+        self.partition
+    }
+
+    /// calls `f` with the state of the expression in this scope, created by
+    /// `init` at the first call
+    pub fn with_state<S: Send + 'static, T>(
+        &self,
+        expr_id: usize,
+        init: impl FnOnce() -> S,
+        f: impl FnOnce(&mut S) -> T,
+    ) -> T {
+        let mut states = self.states.lock().unwrap();
+        let state = states
+            .entry(expr_id)
+            .or_insert_with(|| Box::new(init()))
+            .downcast_mut::<S>()
+            .expect("expression state of another type");
+        f(state)
+    }
+
+    /// calls `f` with this scope as the current scope of the thread
+    pub fn enter<T>(self: &Arc<Self>, f: impl FnOnce() -> T) -> T {
+        struct Restore(Option<Arc<PartitionScope>>);
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                let outer = self.0.take();
+                CURRENT_SCOPE.with(|current| *current.borrow_mut() = outer);
+            }
+        }
+
+        let outer = CURRENT_SCOPE.with(|current| current.replace(Some(self.clone())));
+        let _restore = Restore(outer);
+        f()
+    }
+}
+
+/// Evaluates expressions of the input operator in the scope of the executed
+/// partition.
+#[derive(Debug)]
+pub struct PartitionScopeExec {
+    input: Arc<dyn ExecutionPlan>,
+}
+
+impl PartitionScopeExec {
+    pub fn new(input: Arc<dyn ExecutionPlan>) -> Self {
+        Self { input }
+    }
+
+    pub fn input(&self) -> &Arc<dyn ExecutionPlan> {
+        &self.input
+    }
+}
+
+impl ExecutionPlan for PartitionScopeExec {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.input.schema()
+    }
+
+    fn output_partitioning(&self) -> Partitioning {
+        self.input.output_partitioning()
+    }
+
+    fn output_ordering(&self) -> Option<&[PhysicalSortExpr]> {
+        self.input.output_ordering()
+    }
+
+    crate::transparent_plan_tree!(input, |self, input| Self { input });
+
+    fn execute(
+        &self,
+        partition: usize,
+        context: Arc<TaskContext>,
+    ) -> Result<SendableRecordBatchStream> {
+        let scope = Arc::new(PartitionScope::new(partition));
+        let input = scope.enter(|| self.input.execute(partition, context))?;
+        Ok(Box::pin(PartitionScopeStream { input, scope }))
+    }
+
+    fn fmt_as(&self, t: DisplayFormatType, f: &mut Formatter) -> std::fmt::Result {
+        self.input.fmt_as(t, f)
+    }
+
+    fn statistics(&self) -> Statistics {
+        self.input.statistics()
+    }
+}
+
+struct PartitionScopeStream {
+    input: SendableRecordBatchStream,
+    scope: Arc<PartitionScope>,
+}
+
+impl Stream for PartitionScopeStream {
+    type Item = ArrowResult<RecordBatch>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        let scope = self.scope.clone();
+        scope.enter(|| self.input.poll_next_unpin(cx))
+    }
+}
+
+impl RecordBatchStream for PartitionScopeStream {
+    fn schema(&self) -> SchemaRef {
+        self.input.schema()
+    }
+}
@@ -0,0 +1,395 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Defines a parquet scan applying row deletes of lakehouse tables.
+//!
+//! delta deletion vectors and iceberg positional delete files both delete rows
+//! of a data file by their positions in the file. positions of deletion vectors
+//! are decoded on JVM side by the table reader, while positional delete files
+//! are read here, keeping the rows referencing the scanned data file.
+
+use std::any::Any;
+use std::fmt::Formatter;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use datafusion::arrow::array::{Array, BooleanArray, Int64Array, StringArray};
+use datafusion::arrow::compute::filter_record_batch;
+use datafusion::arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use datafusion::arrow::error::{ArrowError, Result as ArrowResult};
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::datafusion_data_access::{FileMeta, SizedFile};
+use datafusion::datasource::file_format::parquet::ChunkObjectReader;
+use datafusion::datasource::listing::PartitionedFile;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::execution::context::TaskContext;
+use datafusion::logical_plan::{col, lit, Expr};
+use datafusion::parquet::file::reader::{FileReader, SerializedFileReader};
+use datafusion::physical_expr::PhysicalSortExpr;
+use datafusion::physical_plan::file_format::{FileScanConfig, ParquetExec};
+use datafusion::physical_plan::metrics::{
+    BaselineMetrics, Count, ExecutionPlanMetricsSet, MetricBuilder, MetricsSet,
+};
+use datafusion::physical_plan::stream::RecordBatchStreamAdapter;
+use datafusion::physical_plan::{
+    DisplayFormatType, ExecutionPlan, Partitioning, SendableRecordBatchStream, Statistics,
+};
+use futures::{StreamExt, TryStreamExt};
+use once_cell::sync::OnceCell;
+
+use crate::parquet_column_metrics_exec::row_group_in_range;
+use crate::schema_adapted_parquet_exec::execute_parquet_split;
+
+/// rows deleted from a data file, by positions of rows in the file
+#[derive(Debug, Clone, Default)]
+pub struct RowDeletes {
+    /// deleted positions, e.g. decoded from delta deletion vectors
+    pub deleted_positions: Vec<u64>,
+    /// iceberg positional delete files, of (file_path, pos) rows
+    pub positional_delete_files: Vec<SizedFile>,
+    /// path of the data file in positional delete files, which may differ from
+    /// the scanned path in its scheme. the scanned path is used if None
+    pub referenced_path: Option<String>,
+}
+
+/// A parquet scan removing deleted rows of its file splits, so that splits of
+/// lakehouse tables with row deletes can be scanned natively.
+///
+/// splits are opened one by one. splits with deletes are scanned without the
+/// pruning predicate, so that rows are read contiguously from the first row
+/// group of the split, whose position in the file is read from the footer.
+#[derive(Debug)]
+pub struct PositionalDeleteParquetExec {
+    parquet_exec: ParquetExec,
+    predicate: Option<Expr>,
+    /// deletes of each file split in file groups of the scan
+    deletes: Vec<Vec<Option<Arc<RowDeletes>>>>,
+    metrics: ExecutionPlanMetricsSet,
+}
+
+impl PositionalDeleteParquetExec {
+    pub fn try_new(
+        base_config: FileScanConfig,
+        predicate: Option<Expr>,
+        deletes: Vec<Vec<Option<RowDeletes>>>,
+    ) -> Result<Self> {
+        if base_config.limit.is_some() {
+            return Err(DataFusionError::Plan(
+                "PositionalDeleteParquetExec does not support scanning with limit"
+                    .to_string(),
+            ));
+        }
+        let shape_matched = deletes.len() == base_config.file_groups.len()
+            && deletes
+                .iter()
+                .zip(&base_config.file_groups)
+                .all(|(deletes, files)| deletes.len() == files.len());
+        if !shape_matched {
+            return Err(DataFusionError::Plan(
+                "PositionalDeleteParquetExec: deletes do not match file groups"
+                    .to_string(),
+            ));
+        }
+
+        let deletes = deletes
+            .into_iter()
+            .map(|deletes| deletes.into_iter().map(|d| d.map(Arc::new)).collect())
+            .collect();
+        Ok(Self {
+            parquet_exec: ParquetExec::new(base_config, predicate.clone()),
+            predicate,
+            deletes,
+            metrics: ExecutionPlanMetricsSet::new(),
+        })
+    }
+}
+
+#[async_trait]
+impl ExecutionPlan for PositionalDeleteParquetExec {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.parquet_exec.schema()
+    }
+
+    fn output_partitioning(&self) -> Partitioning {
+        self.parquet_exec.output_partitioning()
+    }
+
+    fn output_ordering(&self) -> Option<&[PhysicalSortExpr]> {
+        None
+    }
+
+    fn children(&self) -> Vec<Arc<dyn ExecutionPlan>> {
+        vec![]
+    }
+
+    fn with_new_children(
+        self: Arc<Self>,
+        _children: Vec<Arc<dyn ExecutionPlan>>,
+    ) -> Result<Arc<dyn ExecutionPlan>> {
+        Err(DataFusionError::Plan(
+            "Blaze PositionalDeleteParquetExec does not support with_new_children()"
+                .to_owned(),
+        ))
+    }
+
+    fn execute(
+        &self,
+        partition: usize,
+        context: Arc<TaskContext>,
+    ) -> Result<SendableRecordBatchStream> {
+        let base_config = self.parquet_exec.base_config().clone();
+        let files = base_config
+            .file_groups
+            .get(partition)
+            .cloned()
+            .unwrap_or_default();
+        let deletes = self.deletes.get(partition).cloned().unwrap_or_default();
+        let predicate = self.predicate.clone();
+        let baseline_metrics = BaselineMetrics::new(&self.metrics, partition);
+        let deleted_rows =
+            MetricBuilder::new(&self.metrics).counter("deleted_rows", partition);
+
+        let stream = futures::stream::iter(files.into_iter().zip(deletes))
+            .then(move |(file, deletes)| {
+                let split = open_split(
+                    base_config.clone(),
+                    predicate.clone(),
+                    file,
+                    deletes,
+                    context.clone(),
+                    deleted_rows.clone(),
+                );
+                async move { split.await.map_err(ArrowError::from) }
+            })
+            .try_flatten()
+            .map(
+                move |batch: ArrowResult<RecordBatch>| -> ArrowResult<RecordBatch> {
+                    let batch = batch?;
+                    baseline_metrics.record_output(batch.num_rows());
+                    Ok(batch)
+                },
+            );
+        Ok(Box::pin(RecordBatchStreamAdapter::new(
+            self.schema(),
+            stream,
+        )))
+    }
+
+    fn metrics(&self) -> Option<MetricsSet> {
+        Some(self.metrics.clone_inner())
+    }
+
+    fn fmt_as(&self, t: DisplayFormatType, f: &mut Formatter) -> std::fmt::Result {
+        match t {
+            DisplayFormatType::Default => {
+                let num_deleting_splits = self.deletes.iter().flatten().flatten().count();
+                write!(
+                    f,
+                    "PositionalDeleteParquetExec: splits_with_deletes={}, ",
+                    num_deleting_splits
+                )?;
+                self.parquet_exec.fmt_as(t, f)
+            }
+        }
+    }
+
+    fn statistics(&self) -> Statistics {
+        Statistics::default()
+    }
+}
+
+async fn open_split(
+    base_config: FileScanConfig,
+    predicate: Option<Expr>,
+    file: PartitionedFile,
+    deletes: Option<Arc<RowDeletes>>,
+    context: Arc<TaskContext>,
+    deleted_rows: Count,
+) -> Result<SendableRecordBatchStream> {
+    let deletes = match deletes {
+        Some(deletes) => deletes,
+        None => return execute_parquet_split(&base_config, predicate, file, context),
+    };
+    let deleted_positions =
+        load_deleted_positions(&base_config, &file, &deletes, context.clone()).await?;
+    let mut position = first_row_position(&base_config, &file)?;
+
+    let stream = execute_parquet_split(&base_config, None, file, context)?;
+    let schema = stream.schema();
+    Ok(Box::pin(RecordBatchStreamAdapter::new(
+        schema,
+        stream.map(
+            move |batch: ArrowResult<RecordBatch>| -> ArrowResult<RecordBatch> {
+                let batch = batch?;
+                let start = position;
+                position += batch.num_rows() as u64;
+                let remaining = remove_deleted_rows(&batch, start, &deleted_positions)?;
+                deleted_rows.add(batch.num_rows() - remaining.num_rows());
+                Ok(remaining)
+            },
+        ),
+    )))
+}
+
+/// schema of iceberg positional delete files, other columns are not read
+fn positional_delete_schema() -> SchemaRef {
+    static SCHEMA: OnceCell<SchemaRef> = OnceCell::new();
+    SCHEMA
+        .get_or_init(|| {
+            Arc::new(Schema::new(vec![
+                Field::new("file_path", DataType::Utf8, false),
+                Field::new("pos", DataType::Int64, false),
+            ]))
+        })
+        .clone()
+}
+
+/// returns sorted and deduplicated positions deleted from the file
+async fn load_deleted_positions(
+    base_config: &FileScanConfig,
+    file: &PartitionedFile,
+    deletes: &RowDeletes,
+    context: Arc<TaskContext>,
+) -> Result<Vec<u64>> {
+    let mut positions = deletes.deleted_positions.clone();
+    let data_path = deletes
+        .referenced_path
+        .as_deref()
+        .unwrap_or(&file.file_meta.sized_file.path);
+
+    for delete_file in &deletes.positional_delete_files {
+        let delete_config = FileScanConfig {
+            object_store: base_config.object_store.clone(),
+            file_schema: positional_delete_schema(),
+            file_groups: vec![vec![PartitionedFile {
+                file_meta: FileMeta {
+                    sized_file: delete_file.clone(),
+                    last_modified: None,
+                },
+                partition_values: vec![],
+                range: None,
+            }]],
+            statistics: Statistics::default(),
+            projection: None,
+            limit: None,
+            table_partition_cols: vec![],
+        };
+
+        // delete files are sorted by file_path, so most row groups are pruned
+        let predicate = col("file_path").eq(lit(data_path));
+        let mut stream = ParquetExec::new(delete_config, Some(predicate))
+            .execute(0, context.clone())?;
+        while let Some(batch) = stream.next().await.transpose()? {
+            let file_paths = batch
+                .column(0)
+                .as_any()
+                .downcast_ref::<StringArray>()
+                .unwrap();
+            let delete_positions = batch
+                .column(1)
+                .as_any()
+                .downcast_ref::<Int64Array>()
+                .unwrap();
+            for i in 0..batch.num_rows() {
+                if file_paths.is_valid(i) && file_paths.value(i) == data_path {
+                    positions.push(delete_positions.value(i) as u64);
+                }
+            }
+        }
+    }
+    positions.sort_unstable();
+    positions.dedup();
+    Ok(positions)
+}
+
+/// returns the position of the first row of the split in the file, which is
+/// the number of rows of row groups before the split
+fn first_row_position(
+    base_config: &FileScanConfig,
+    file: &PartitionedFile,
+) -> Result<u64> {
+    if file.range.is_none() {
+        return Ok(0);
+    }
+    let object_reader = base_config
+        .object_store
+        .file_reader(file.file_meta.sized_file.clone())?;
+    let file_reader = SerializedFileReader::new(ChunkObjectReader(object_reader))?;
+    Ok(file_reader
+        .metadata()
+        .row_groups()
+        .iter()
+        .take_while(|row_group| !row_group_in_range(row_group, file))
+        .map(|row_group| row_group.num_rows() as u64)
+        .sum())
+}
+
+/// removes rows of the batch whose positions are deleted, the batch starts at
+/// position `start` of the file
+fn remove_deleted_rows(
+    batch: &RecordBatch,
+    start: u64,
+    deleted_positions: &[u64],
+) -> ArrowResult<RecordBatch> {
+    let end = start + batch.num_rows() as u64;
+    let from = deleted_positions.partition_point(|&pos| pos < start);
+    let to = deleted_positions.partition_point(|&pos| pos < end);
+    if from == to {
+        return Ok(batch.clone());
+    }
+
+    let mut retained = vec![true; batch.num_rows()];
+    for &pos in &deleted_positions[from..to] {
+        retained[(pos - start) as usize] = false;
+    }
+    filter_record_batch(batch, &BooleanArray::from(retained))
+}
+
+#[cfg(test)]
+mod tests {
+    use datafusion::arrow::array::Int32Array;
+
+    use super::*;
+
+    #[test]
+    fn test_remove_deleted_rows() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int32, false)]));
+        let batch = RecordBatch::try_new(
+            schema,
+            vec![Arc::new(Int32Array::from(vec![10, 11, 12, 13, 14]))],
+        )?;
+        let values = |batch: &RecordBatch| -> Vec<i32> {
+            let array = batch
+                .column(0)
+                .as_any()
+                .downcast_ref::<Int32Array>()
+                .unwrap();
+            array.values().to_vec()
+        };
+
+        // the batch covers positions 10..15 of the file
+        let deleted_positions = vec![3, 10, 12, 14, 20];
+        let remaining = remove_deleted_rows(&batch, 10, &deleted_positions)?;
+        assert_eq!(values(&remaining), vec![11, 13]);
+
+        // no deletes in positions of the batch
+        let remaining = remove_deleted_rows(&batch, 100, &deleted_positions)?;
+        assert_eq!(values(&remaining), vec![10, 11, 12, 13, 14]);
+        Ok(())
+    }
+}
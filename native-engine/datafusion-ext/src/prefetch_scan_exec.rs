@@ -0,0 +1,118 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Defines an operator prefetching batches of a scan ahead of its parent
+
+use std::any::Any;
+use std::fmt::Formatter;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use datafusion::arrow::datatypes::SchemaRef;
+use datafusion::error::Result;
+use datafusion::execution::context::TaskContext;
+use datafusion::physical_plan::expressions::PhysicalSortExpr;
+use datafusion::physical_plan::{
+    DisplayFormatType, ExecutionPlan, Partitioning, SendableRecordBatchStream, Statistics,
+};
+
+use crate::native_conf::native_conf;
+use crate::prefetch_stream::PrefetchStream;
+
+/// wraps the scan with PrefetchScanExec if enabled by native conf
+pub fn prefetch_scan(input: Arc<dyn ExecutionPlan>) -> Arc<dyn ExecutionPlan> {
+    let conf = native_conf();
+    match (conf.scan_prefetch_batches, conf.scan_prefetch_bytes) {
+        (0, _) | (_, 0) => input,
+        (max_batches, max_bytes) => {
+            Arc::new(PrefetchScanExec::new(input, max_batches, max_bytes))
+        }
+    }
+}
+
+/// Reads batches of the scan in a spawned task, keeping at most `max_batches`
+/// batches and `max_bytes` bytes prefetched ahead of the parent operator. IO
+/// latency of the scan then overlaps with computation of its parents, while a
+/// slow parent still blocks the scan once the queue is full.
+#[derive(Debug)]
+pub struct PrefetchScanExec {
+    input: Arc<dyn ExecutionPlan>,
+    max_batches: usize,
+    max_bytes: usize,
+}
+
+impl PrefetchScanExec {
+    pub fn new(
+        input: Arc<dyn ExecutionPlan>,
+        max_batches: usize,
+        max_bytes: usize,
+    ) -> Self {
+        Self {
+            input,
+            max_batches,
+            max_bytes,
+        }
+    }
+}
+
+#[async_trait]
+impl ExecutionPlan for PrefetchScanExec {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.input.schema()
+    }
+
+    fn output_partitioning(&self) -> Partitioning {
+        self.input.output_partitioning()
+    }
+
+    fn output_ordering(&self) -> Option<&[PhysicalSortExpr]> {
+        self.input.output_ordering()
+    }
+
+    crate::transparent_plan_tree!(input, |self, input| Self::new(
+        input,
+        self.max_batches,
+        self.max_bytes
+    ));
+
+    fn execute(
+        &self,
+        partition: usize,
+        context: Arc<TaskContext>,
+    ) -> Result<SendableRecordBatchStream> {
+        let input = self.input.execute(partition, context)?;
+        Ok(Box::pin(PrefetchStream::with_max_batches(
+            input,
+            self.max_bytes,
+            Some(self.max_batches),
+        )))
+    }
+
+    fn fmt_as(&self, t: DisplayFormatType, f: &mut Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "PrefetchScanExec: max_batches={}, max_bytes={}, ",
+            self.max_batches, self.max_bytes
+        )?;
+        self.input.fmt_as(t, f)
+    }
+
+    fn statistics(&self) -> Statistics {
+        self.input.statistics()
+    }
+}
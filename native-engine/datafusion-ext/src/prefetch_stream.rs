@@ -0,0 +1,207 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Defines a stream which prefetches batches in background under a byte budget
+//! and an optional batch count budget
+
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use datafusion::arrow::datatypes::SchemaRef;
+use datafusion::arrow::error::Result as ArrowResult;
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::physical_plan::common::batch_byte_size;
+use datafusion::physical_plan::{RecordBatchStream, SendableRecordBatchStream};
+use futures::{Stream, StreamExt};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// budgets held by an in-flight batch
+type InFlightPermits = (OwnedSemaphorePermit, Option<OwnedSemaphorePermit>);
+
+/// Polls the input stream in a spawned task, keeping at most `budget_bytes` of
+/// batches in flight. a batch is counted as in flight from being produced until
+/// the consumer polls for the next one, so slow consumers block the producer
+/// instead of letting prefetched batches pile up in memory.
+pub struct PrefetchStream {
+    schema: SchemaRef,
+    prefetched: UnboundedReceiver<(ArrowResult<RecordBatch>, InFlightPermits)>,
+    consuming_permit: Option<InFlightPermits>,
+}
+
+impl PrefetchStream {
+    pub fn new(input: SendableRecordBatchStream, budget_bytes: usize) -> Self {
+        Self::with_max_batches(input, budget_bytes, None)
+    }
+
+    /// additionally keeps at most `max_batches` batches in flight if specified
+    pub fn with_max_batches(
+        mut input: SendableRecordBatchStream,
+        budget_bytes: usize,
+        max_batches: Option<usize>,
+    ) -> Self {
+        let schema = input.schema();
+        let budget_bytes = budget_bytes.clamp(1, u32::MAX as usize);
+        let budget = Arc::new(Semaphore::new(budget_bytes));
+        let batch_budget = max_batches.map(|max_batches| {
+            Arc::new(Semaphore::new(max_batches.clamp(1, u32::MAX as usize)))
+        });
+        let (sender, prefetched) = unbounded_channel();
+
+        tokio::spawn(async move {
+            while let Some(batch) = input.next().await {
+                // oversized batch takes the whole budget and is still let through
+                let batch_bytes = match &batch {
+                    Ok(batch) => batch_byte_size(batch),
+                    Err(_) => 0,
+                };
+                let permits = batch_bytes.min(budget_bytes) as u32;
+                let permit = match budget.clone().acquire_many_owned(permits).await {
+                    Ok(permit) => permit,
+                    Err(_) => break, // semaphore closed
+                };
+                let batch_permit = match batch_budget.clone() {
+                    Some(batch_budget) => match batch_budget.acquire_owned().await {
+                        Ok(permit) => Some(permit),
+                        Err(_) => break, // semaphore closed
+                    },
+                    None => None,
+                };
+                if sender.send((batch, (permit, batch_permit))).is_err() {
+                    break; // consumer dropped
+                }
+            }
+        });
+
+        Self {
+            schema,
+            prefetched,
+            consuming_permit: None,
+        }
+    }
+}
+
+impl Stream for PrefetchStream {
+    type Item = ArrowResult<RecordBatch>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        // the previous batch has been consumed, release its budget
+        self.consuming_permit = None;
+
+        match self.prefetched.poll_recv(cx) {
+            Poll::Ready(Some((batch, permit))) => {
+                self.consuming_permit = Some(permit);
+                Poll::Ready(Some(batch))
+            }
+            Poll::Ready(None) => Poll::Ready(None),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl RecordBatchStream for PrefetchStream {
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+}
+
+/// runs a consumer of prefetched batches which blocks its thread, e.g. waiting
+/// for the JVM side to take the batches. the prefetching producers are tokio
+/// tasks that may be queued on the blocked worker thread, so the worker is
+/// handed over to another thread meanwhile to keep the producers running.
+///
+/// must be called within a multi-threaded runtime.
+pub fn consume_blocking<R>(f: impl FnOnce() -> R) -> R {
+    tokio::task::block_in_place(f)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use datafusion::arrow::array::Int32Array;
+    use datafusion::arrow::datatypes::{DataType, Field, Schema};
+    use datafusion::error::Result;
+
+    use super::*;
+
+    /// yields the batches and counts the yielded ones
+    struct CountingStream {
+        schema: SchemaRef,
+        batches: Vec<RecordBatch>,
+        produced: Arc<AtomicUsize>,
+    }
+
+    impl Stream for CountingStream {
+        type Item = ArrowResult<RecordBatch>;
+
+        fn poll_next(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Option<Self::Item>> {
+            if self.batches.is_empty() {
+                return Poll::Ready(None);
+            }
+            self.produced.fetch_add(1, Ordering::SeqCst);
+            Poll::Ready(Some(Ok(self.batches.remove(0))))
+        }
+    }
+
+    impl RecordBatchStream for CountingStream {
+        fn schema(&self) -> SchemaRef {
+            self.schema.clone()
+        }
+    }
+
+    #[test]
+    fn test_prefetch_while_consumer_blocks() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int32, false)]));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![Arc::new(Int32Array::from(vec![1, 2, 3]))],
+        )?;
+        let produced = Arc::new(AtomicUsize::new(0));
+        let input = Box::pin(CountingStream {
+            schema,
+            batches: vec![batch; 3],
+            produced: produced.clone(),
+        });
+
+        // a single worker thread, which is blocked by the consumer
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .build()?;
+        let prefetched = runtime.block_on(runtime.spawn(async move {
+            let mut stream = PrefetchStream::new(input, usize::MAX);
+            assert!(stream.next().await.is_some());
+            consume_blocking(|| {
+                let start_time = Instant::now();
+                while produced.load(Ordering::SeqCst) < 3
+                    && start_time.elapsed() < Duration::from_secs(10)
+                {
+                    std::thread::sleep(Duration::from_millis(1));
+                }
+                produced.load(Ordering::SeqCst)
+            })
+        }));
+        assert_eq!(prefetched.unwrap(), 3);
+        Ok(())
+    }
+}
@@ -0,0 +1,579 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Defines a radix sort of inputs keyed by a single fixed-width column
+//!
+//! sorts by a single integer, date or timestamp column (e.g. ORDER BY id) are
+//! common. the key values are converted to unsigned integers of the same order
+//! and sorted with an LSD radix sort, skipping bytes shared by all keys,
+//! instead of comparing rows. null keys are placed first or last without
+//! sorting.
+//!
+//! the input of a partition is sorted in memory reserved from the memory
+//! manager. once it exceeds `max_bytes` or the reservation is denied, the
+//! buffered and remaining batches are sorted by SortExec instead, which may
+//! spill.
+
+use std::any::Any;
+use std::fmt;
+use std::fmt::{Debug, Formatter};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use datafusion::arrow::array::{as_primitive_array, Array, ArrayRef, UInt32Array};
+use datafusion::arrow::compute::{take, SortOptions};
+use datafusion::arrow::datatypes::{
+    DataType, Date32Type, Date64Type, Int16Type, Int32Type, Int64Type, Int8Type,
+    SchemaRef, TimeUnit, TimestampMicrosecondType, TimestampMillisecondType,
+    TimestampNanosecondType, TimestampSecondType, UInt16Type, UInt32Type, UInt64Type,
+    UInt8Type,
+};
+use datafusion::arrow::error::Result as ArrowResult;
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::execution::context::TaskContext;
+use datafusion::execution::memory_manager::{
+    ConsumerType, MemoryConsumer, MemoryConsumerId, MemoryManager,
+};
+use datafusion::execution::runtime_env::RuntimeEnv;
+use datafusion::physical_expr::PhysicalSortExpr;
+use datafusion::physical_plan::common::batch_byte_size;
+use datafusion::physical_plan::expressions::Column;
+use datafusion::physical_plan::metrics::{
+    BaselineMetrics, ExecutionPlanMetricsSet, MetricsSet, Time,
+};
+use datafusion::physical_plan::sorts::sort::SortExec;
+use datafusion::physical_plan::stream::RecordBatchStreamAdapter;
+use datafusion::physical_plan::{
+    DisplayFormatType, ExecutionPlan, Partitioning, SendableRecordBatchStream, Statistics,
+};
+use futures::stream::BoxStream;
+use futures::{StreamExt, TryStreamExt};
+
+use crate::partial_agg_skipping_exec::StreamExec;
+
+/// replaces the sort with RadixSortExec if it is keyed by a single fixed-width
+/// column and preserves partitioning. 0 max_bytes to disable.
+pub fn radix_sort(
+    sort: Arc<SortExec>,
+    max_bytes: usize,
+) -> Result<Arc<dyn ExecutionPlan>> {
+    let input = sort.input().clone();
+    let preserve_partitioning = sort.output_partitioning().partition_count()
+        == input.output_partitioning().partition_count();
+    if max_bytes == 0 || !preserve_partitioning || sort.expr().len() != 1 {
+        return Ok(sort as Arc<dyn ExecutionPlan>);
+    }
+    let sort_expr = sort.expr()[0].clone();
+    let column = match sort_expr.expr.as_any().downcast_ref::<Column>() {
+        Some(column) => column.index(),
+        None => return Ok(sort as Arc<dyn ExecutionPlan>),
+    };
+    if key_width(input.schema().field(column).data_type()).is_none() {
+        return Ok(sort as Arc<dyn ExecutionPlan>);
+    }
+    Ok(Arc::new(RadixSortExec::try_new(
+        input, sort_expr, max_bytes,
+    )?))
+}
+
+/// Sorts each partition of the input by a single fixed-width key column with
+/// a radix sort, like SortExec preserving partitioning.
+#[derive(Debug)]
+pub struct RadixSortExec {
+    input: Arc<dyn ExecutionPlan>,
+    sort_expr: PhysicalSortExpr,
+    column: usize,
+    max_bytes: usize,
+    metrics: ExecutionPlanMetricsSet,
+}
+
+impl RadixSortExec {
+    pub fn try_new(
+        input: Arc<dyn ExecutionPlan>,
+        sort_expr: PhysicalSortExpr,
+        max_bytes: usize,
+    ) -> Result<Self> {
+        let column = sort_expr
+            .expr
+            .as_any()
+            .downcast_ref::<Column>()
+            .map(|column| column.index())
+            .ok_or_else(|| {
+                DataFusionError::Plan(format!(
+                    "RadixSortExec expects a column key, found {}",
+                    sort_expr.expr
+                ))
+            })?;
+        let data_type = input.schema().field(column).data_type().clone();
+        if key_width(&data_type).is_none() {
+            return Err(DataFusionError::Plan(format!(
+                "RadixSortExec expects a fixed-width key, found {:?}",
+                data_type
+            )));
+        }
+        Ok(Self {
+            input,
+            sort_expr,
+            column,
+            max_bytes,
+            metrics: ExecutionPlanMetricsSet::new(),
+        })
+    }
+
+    pub fn sort_expr(&self) -> &PhysicalSortExpr {
+        &self.sort_expr
+    }
+}
+
+#[async_trait]
+impl ExecutionPlan for RadixSortExec {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.input.schema()
+    }
+
+    fn output_partitioning(&self) -> Partitioning {
+        self.input.output_partitioning()
+    }
+
+    fn output_ordering(&self) -> Option<&[PhysicalSortExpr]> {
+        Some(std::slice::from_ref(&self.sort_expr))
+    }
+
+    fn children(&self) -> Vec<Arc<dyn ExecutionPlan>> {
+        vec![self.input.clone()]
+    }
+
+    fn with_new_children(
+        self: Arc<Self>,
+        children: Vec<Arc<dyn ExecutionPlan>>,
+    ) -> Result<Arc<dyn ExecutionPlan>> {
+        if children.len() != 1 {
+            return Err(DataFusionError::Plan(
+                "RadixSortExec expects one children".to_string(),
+            ));
+        }
+        Ok(Arc::new(RadixSortExec::try_new(
+            children[0].clone(),
+            self.sort_expr.clone(),
+            self.max_bytes,
+        )?))
+    }
+
+    fn execute(
+        &self,
+        partition: usize,
+        context: Arc<TaskContext>,
+    ) -> Result<SendableRecordBatchStream> {
+        let input = self.input.execute(partition, context.clone())?;
+        let sort_expr = self.sort_expr.clone();
+        let column = self.column;
+        let max_bytes = self.max_bytes;
+        let baseline_metrics = BaselineMetrics::new(&self.metrics, partition);
+        let elapsed_compute = baseline_metrics.elapsed_compute().clone();
+
+        let stream = futures::stream::once(async move {
+            execute_radix_sort(
+                input,
+                partition,
+                sort_expr,
+                column,
+                max_bytes,
+                context,
+                elapsed_compute,
+            )
+            .await
+        })
+        .try_flatten()
+        .map(
+            move |batch: ArrowResult<RecordBatch>| -> ArrowResult<RecordBatch> {
+                let batch = batch?;
+                baseline_metrics.record_output(batch.num_rows());
+                Ok(batch)
+            },
+        );
+        Ok(Box::pin(RecordBatchStreamAdapter::new(
+            self.schema(),
+            stream,
+        )))
+    }
+
+    fn metrics(&self) -> Option<MetricsSet> {
+        Some(self.metrics.clone_inner())
+    }
+
+    fn fmt_as(&self, t: DisplayFormatType, f: &mut Formatter) -> std::fmt::Result {
+        match t {
+            DisplayFormatType::Default => {
+                write!(
+                    f,
+                    "RadixSortExec: [{}], max_bytes={}",
+                    self.sort_expr, self.max_bytes
+                )
+            }
+        }
+    }
+
+    fn statistics(&self) -> Statistics {
+        self.input.statistics()
+    }
+}
+
+async fn execute_radix_sort(
+    mut input: SendableRecordBatchStream,
+    partition: usize,
+    sort_expr: PhysicalSortExpr,
+    column: usize,
+    max_bytes: usize,
+    context: Arc<TaskContext>,
+    elapsed_compute: Time,
+) -> ArrowResult<BoxStream<'static, ArrowResult<RecordBatch>>> {
+    let schema = input.schema();
+    let memory = RadixSortMemory::new(partition, context.runtime_env());
+    context.runtime_env().register_requester(memory.id());
+
+    let mut batches = vec![];
+    let mut num_bytes = 0;
+    while let Some(batch) = input.next().await {
+        let batch = batch?;
+        let batch_bytes = batch_byte_size(&batch);
+        num_bytes += batch_bytes;
+        batches.push(batch);
+
+        if num_bytes > max_bytes || !memory.grow(batch_bytes).await? {
+            log::info!(
+                "radix sort input of {} bytes exceeds {} bytes or available memory, \
+                 falling back to SortExec",
+                num_bytes,
+                max_bytes
+            );
+            // SortExec reserves memory of the buffered batches by itself
+            drop(memory);
+            let buffered = futures::stream::iter(batches.into_iter().map(Ok));
+            let input: Arc<dyn ExecutionPlan> = Arc::new(StreamExec::new(Box::pin(
+                RecordBatchStreamAdapter::new(schema, buffered.chain(input)),
+            )));
+            let sort = SortExec::new_with_partitioning(vec![sort_expr], input, true);
+            return Ok(sort.execute(0, context)?.boxed());
+        }
+    }
+
+    let timer = elapsed_compute.timer();
+    let batch = RecordBatch::concat(&schema, &batches)?;
+    drop(batches);
+    let indices = sort_indices(batch.column(column), sort_expr.options);
+    timer.done();
+
+    // sorted rows are taken lazily to avoid holding two copies of the input,
+    // the reservation is released once the output is dropped
+    let batch_size = context.session_config().batch_size.max(1);
+    let num_chunks = (indices.len() + batch_size - 1) / batch_size;
+    let output = (0..num_chunks).map(move |i| {
+        let _memory = &memory;
+        let _timer = elapsed_compute.timer();
+        let end = ((i + 1) * batch_size).min(indices.len());
+        let chunk = UInt32Array::from(indices[i * batch_size..end].to_vec());
+        let columns = batch
+            .columns()
+            .iter()
+            .map(|column| take(column.as_ref(), &chunk, None))
+            .collect::<ArrowResult<Vec<_>>>()?;
+        RecordBatch::try_new(batch.schema(), columns)
+    });
+    Ok(futures::stream::iter(output).boxed())
+}
+
+/// Reserves memory of the input buffered by a radix sort. buffered batches
+/// cannot be spilled, a denied reservation makes the sort fall back to SortExec.
+struct RadixSortMemory {
+    id: MemoryConsumerId,
+    runtime: Arc<RuntimeEnv>,
+    used: AtomicUsize,
+    denied: AtomicBool,
+}
+
+impl RadixSortMemory {
+    fn new(partition: usize, runtime: Arc<RuntimeEnv>) -> Self {
+        Self {
+            id: MemoryConsumerId::new(partition),
+            runtime,
+            used: AtomicUsize::new(0),
+            denied: AtomicBool::new(false),
+        }
+    }
+
+    /// reserves memory of a buffered batch, returns false if the memory manager
+    /// could not grant it without spilling
+    async fn grow(&self, required: usize) -> Result<bool> {
+        self.try_grow(required).await?;
+        self.used.fetch_add(required, Ordering::SeqCst);
+        Ok(!self.denied.load(Ordering::SeqCst))
+    }
+}
+
+impl Debug for RadixSortMemory {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RadixSortMemory")
+            .field("id", &self.id())
+            .field("memory_used", &self.mem_used())
+            .finish()
+    }
+}
+
+#[async_trait]
+impl MemoryConsumer for RadixSortMemory {
+    fn name(&self) -> String {
+        "RadixSortExec".to_owned()
+    }
+
+    fn id(&self) -> &MemoryConsumerId {
+        &self.id
+    }
+
+    fn memory_manager(&self) -> Arc<MemoryManager> {
+        self.runtime.memory_manager.clone()
+    }
+
+    fn type_(&self) -> &ConsumerType {
+        &ConsumerType::Requesting
+    }
+
+    async fn spill(&self) -> Result<usize> {
+        // nothing is freed here, the buffered batches are handed over to SortExec
+        self.denied.store(true, Ordering::SeqCst);
+        Ok(0)
+    }
+
+    fn mem_used(&self) -> usize {
+        self.used.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for RadixSortMemory {
+    fn drop(&mut self) {
+        self.runtime.drop_consumer(self.id(), self.mem_used());
+    }
+}
+
+/// returns number of bytes of keys of the type, None if not supported
+fn key_width(data_type: &DataType) -> Option<usize> {
+    match data_type {
+        DataType::Int8 | DataType::UInt8 => Some(1),
+        DataType::Int16 | DataType::UInt16 => Some(2),
+        DataType::Int32 | DataType::UInt32 | DataType::Date32 => Some(4),
+        DataType::Int64
+        | DataType::UInt64
+        | DataType::Date64
+        | DataType::Timestamp(_, _) => Some(8),
+        _ => None,
+    }
+}
+
+macro_rules! keys_of {
+    ($array:expr, $arrow_type:ty, |$v:ident| $key:expr) => {
+        as_primitive_array::<$arrow_type>($array)
+            .values()
+            .iter()
+            .map(|&$v| $key)
+            .collect()
+    };
+}
+
+/// converts key values to unsigned integers of the same order, values of null
+/// keys are unspecified
+fn sortable_keys(array: &ArrayRef) -> Vec<u64> {
+    const SIGN64: u64 = 1 << 63;
+    match array.data_type() {
+        DataType::Int8 => keys_of!(array, Int8Type, |v| (v as u8 ^ 0x80) as u64),
+        DataType::Int16 => keys_of!(array, Int16Type, |v| (v as u16 ^ 0x8000) as u64),
+        DataType::Int32 => keys_of!(array, Int32Type, |v| (v as u32 ^ 1 << 31) as u64),
+        DataType::Date32 => keys_of!(array, Date32Type, |v| (v as u32 ^ 1 << 31) as u64),
+        DataType::Int64 => keys_of!(array, Int64Type, |v| v as u64 ^ SIGN64),
+        DataType::Date64 => keys_of!(array, Date64Type, |v| v as u64 ^ SIGN64),
+        DataType::Timestamp(TimeUnit::Second, _) => {
+            keys_of!(array, TimestampSecondType, |v| v as u64 ^ SIGN64)
+        }
+        DataType::Timestamp(TimeUnit::Millisecond, _) => {
+            keys_of!(array, TimestampMillisecondType, |v| v as u64 ^ SIGN64)
+        }
+        DataType::Timestamp(TimeUnit::Microsecond, _) => {
+            keys_of!(array, TimestampMicrosecondType, |v| v as u64 ^ SIGN64)
+        }
+        DataType::Timestamp(TimeUnit::Nanosecond, _) => {
+            keys_of!(array, TimestampNanosecondType, |v| v as u64 ^ SIGN64)
+        }
+        DataType::UInt8 => keys_of!(array, UInt8Type, |v| v as u64),
+        DataType::UInt16 => keys_of!(array, UInt16Type, |v| v as u64),
+        DataType::UInt32 => keys_of!(array, UInt32Type, |v| v as u64),
+        DataType::UInt64 => keys_of!(array, UInt64Type, |v| v),
+        other => unreachable!("radix sort of unsupported type {:?}", other),
+    }
+}
+
+/// returns indices of rows sorted by the keys
+fn sort_indices(keys: &ArrayRef, options: SortOptions) -> Vec<u32> {
+    let width = key_width(keys.data_type()).unwrap_or(8);
+    let flip = if options.descending {
+        u64::MAX >> (64 - width * 8)
+    } else {
+        0
+    };
+
+    let mut nulls = vec![];
+    let mut entries = Vec::with_capacity(keys.len() - keys.null_count());
+    for (i, key) in sortable_keys(keys).into_iter().enumerate() {
+        if keys.is_null(i) {
+            nulls.push(i as u32);
+        } else {
+            entries.push((key ^ flip, i as u32));
+        }
+    }
+
+    let mut sorted = radix_sort_entries(entries, width);
+    if options.nulls_first {
+        nulls.extend(sorted);
+        return nulls;
+    }
+    sorted.extend(nulls);
+    sorted
+}
+
+/// sorts (key, index) entries by the lowest `width` bytes of keys with an LSD
+/// radix sort, entries of equal keys keep their order
+fn radix_sort_entries(mut entries: Vec<(u64, u32)>, width: usize) -> Vec<u32> {
+    let mut counts = vec![[0usize; 256]; width];
+    for &(key, _) in &entries {
+        for (byte, counts) in counts.iter_mut().enumerate() {
+            counts[(key >> (byte * 8)) as u8 as usize] += 1;
+        }
+    }
+
+    let mut sorted = vec![(0, 0); entries.len()];
+    for (byte, counts) in counts.iter().enumerate() {
+        // all keys share the same byte
+        if counts.iter().any(|&count| count == entries.len()) {
+            continue;
+        }
+        let mut offsets = [0usize; 256];
+        let mut offset = 0;
+        for (digit, &count) in counts.iter().enumerate() {
+            offsets[digit] = offset;
+            offset += count;
+        }
+        for &entry in &entries {
+            let digit = (entry.0 >> (byte * 8)) as u8 as usize;
+            sorted[offsets[digit]] = entry;
+            offsets[digit] += 1;
+        }
+        std::mem::swap(&mut entries, &mut sorted);
+    }
+    entries.into_iter().map(|(_, i)| i).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use datafusion::arrow::datatypes::{Field, Schema};
+    use datafusion::execution::memory_manager::MemoryManagerConfig;
+    use datafusion::execution::runtime_env::RuntimeConfig;
+    use datafusion::physical_plan::common::collect;
+    use datafusion::physical_plan::expressions::col;
+    use datafusion::physical_plan::memory::MemoryExec;
+    use datafusion::prelude::{SessionConfig, SessionContext};
+
+    use super::*;
+    use crate::test_util::build_batch;
+
+    #[test]
+    fn test_radix_sort() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("k", DataType::Int64, true),
+            Field::new("s", DataType::Utf8, true),
+        ]));
+        let input = Arc::new(MemoryExec::try_new(
+            &[vec![
+                build_batch!(
+                    schema,
+                    vec![Some(3), None, Some(i64::MIN)],
+                    vec!["a", "b", "c"]
+                )?,
+                build_batch!(
+                    schema,
+                    vec![Some(-1), Some(i64::MAX), Some(256)],
+                    vec!["d", "e", "f"],
+                )?,
+            ]],
+            schema.clone(),
+            None,
+        )?);
+
+        let sort_expr = PhysicalSortExpr {
+            expr: col("k", &schema)?,
+            options: SortOptions {
+                descending: true,
+                nulls_first: true,
+            },
+        };
+        let sort = Arc::new(SortExec::new_with_partitioning(
+            vec![sort_expr],
+            input,
+            true,
+        ));
+        let expected = build_batch!(
+            schema,
+            vec![
+                None,
+                Some(i64::MAX),
+                Some(256),
+                Some(3),
+                Some(-1),
+                Some(i64::MIN),
+            ],
+            vec!["b", "e", "f", "a", "d", "c"],
+        )?;
+
+        let config = SessionConfig::new().with_batch_size(4);
+        let task_ctx = SessionContext::with_config(config.clone()).task_ctx();
+        let runtime = tokio::runtime::Runtime::new()?;
+
+        // sorted in memory, output in batches of batch_size
+        let plan = radix_sort(sort.clone(), 1 << 20)?;
+        assert!(plan.as_any().is::<RadixSortExec>());
+        let batches = runtime.block_on(collect(plan.execute(0, task_ctx.clone())?))?;
+        assert_eq!(batches.len(), 2);
+        assert_eq!(RecordBatch::concat(&schema, &batches)?, expected);
+
+        // falls back to SortExec
+        let plan = radix_sort(sort.clone(), 1)?;
+        let batches = runtime.block_on(collect(plan.execute(0, task_ctx)?))?;
+        assert_eq!(RecordBatch::concat(&schema, &batches)?, expected);
+
+        // falls back to SortExec if the memory manager denies the reservation
+        let runtime_env = Arc::new(RuntimeEnv::new(
+            RuntimeConfig::new().with_memory_manager(MemoryManagerConfig::New {
+                max_memory: 1,
+                memory_fraction: 1.0,
+            }),
+        )?);
+        let task_ctx = SessionContext::with_config_rt(config, runtime_env).task_ctx();
+        let plan = radix_sort(sort, 1 << 20)?;
+        let batches = runtime.block_on(collect(plan.execute(0, task_ctx)?))?;
+        assert_eq!(RecordBatch::concat(&schema, &batches)?, expected);
+        Ok(())
+    }
+}
@@ -0,0 +1,371 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Defines an operator sharing output of an exchange referenced multiple times
+//! in a native plan
+
+use std::any::Any;
+use std::collections::HashMap;
+use std::fmt;
+use std::fmt::{Debug, Formatter};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+use async_trait::async_trait;
+use datafusion::arrow::datatypes::SchemaRef;
+use datafusion::arrow::error::ArrowError;
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::execution::context::TaskContext;
+use datafusion::execution::memory_manager::{
+    ConsumerType, MemoryConsumer, MemoryConsumerId, MemoryManager,
+};
+use datafusion::execution::runtime_env::RuntimeEnv;
+use datafusion::physical_plan::common::batch_byte_size;
+use datafusion::physical_plan::expressions::PhysicalSortExpr;
+use datafusion::physical_plan::metrics::{ExecutionPlanMetricsSet, MetricsSet};
+use datafusion::physical_plan::stream::RecordBatchStreamAdapter;
+use datafusion::physical_plan::{
+    DisplayFormatType, ExecutionPlan, Partitioning, SendableRecordBatchStream, Statistics,
+};
+use futures::{StreamExt, TryStreamExt};
+use once_cell::sync::OnceCell;
+
+struct SharedExchange {
+    input: Mutex<Arc<dyn ExecutionPlan>>,
+    partitions: Mutex<HashMap<usize, Arc<SharedPartition>>>,
+}
+
+/// Output of a partition buffered for all references of the exchange. it is
+/// evicted from the exchange once all references have started reading it, and
+/// released once they have all dropped their streams. partitions not read by
+/// every reference (e.g. under a limit) are released with the plan when the
+/// task completes.
+struct SharedPartition {
+    unread_references: AtomicUsize,
+    buffered: tokio::sync::OnceCell<BufferedPartition>,
+}
+
+struct BufferedPartition {
+    batches: Vec<RecordBatch>,
+    _memory: SharedPartitionMemory,
+}
+
+fn shared_exchanges() -> &'static Mutex<HashMap<String, Weak<SharedExchange>>> {
+    static SHARED_EXCHANGES: OnceCell<Mutex<HashMap<String, Weak<SharedExchange>>>> =
+        OnceCell::new();
+    SHARED_EXCHANGES.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// References an exchange which may be referenced more than once in the same
+/// native plan, like spark's ReusedExchangeExec.
+///
+/// all references with the same exchange id share the input decoded first,
+/// inputs of other references are discarded. if there is more than one living
+/// reference, output batches of each partition are computed once and buffered
+/// for all references, in memory reserved from the memory manager.
+pub struct ReusedExchangeExec {
+    exchange_id: String,
+    shared: Arc<SharedExchange>,
+    reused: bool,
+    metrics: ExecutionPlanMetricsSet,
+}
+
+impl ReusedExchangeExec {
+    pub fn new(exchange_id: String, input: Arc<dyn ExecutionPlan>) -> Self {
+        let mut exchanges = shared_exchanges().lock().unwrap();
+        exchanges.retain(|_, shared| shared.strong_count() > 0);
+
+        let (shared, reused) = match exchanges.get(&exchange_id).and_then(Weak::upgrade) {
+            Some(shared) => (shared, true),
+            None => {
+                let shared = Arc::new(SharedExchange {
+                    input: Mutex::new(input),
+                    partitions: Mutex::new(HashMap::new()),
+                });
+                exchanges.insert(exchange_id.clone(), Arc::downgrade(&shared));
+                (shared, false)
+            }
+        };
+        Self {
+            exchange_id,
+            shared,
+            reused,
+            metrics: ExecutionPlanMetricsSet::new(),
+        }
+    }
+
+    fn input(&self) -> Arc<dyn ExecutionPlan> {
+        self.shared.input.lock().unwrap().clone()
+    }
+}
+
+impl Debug for ReusedExchangeExec {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "ReusedExchangeExec {{ exchange_id: {}, reused: {} }}",
+            self.exchange_id, self.reused
+        )
+    }
+}
+
+#[async_trait]
+impl ExecutionPlan for ReusedExchangeExec {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.input().schema()
+    }
+
+    fn output_partitioning(&self) -> Partitioning {
+        self.input().output_partitioning()
+    }
+
+    fn output_ordering(&self) -> Option<&[PhysicalSortExpr]> {
+        None
+    }
+
+    fn children(&self) -> Vec<Arc<dyn ExecutionPlan>> {
+        // the shared input is only a child of the first reference, so that its
+        // metrics are not reported multiple times
+        if self.reused {
+            vec![]
+        } else {
+            vec![self.input()]
+        }
+    }
+
+    fn with_new_children(
+        self: Arc<Self>,
+        children: Vec<Arc<dyn ExecutionPlan>>,
+    ) -> Result<Arc<dyn ExecutionPlan>> {
+        // rewriting the plan (e.g. for cancellation) replaces the shared input
+        // in place, so that it is still shared by the other references
+        match (self.reused, &children[..]) {
+            (false, [input]) => {
+                *self.shared.input.lock().unwrap() = input.clone();
+                Ok(self)
+            }
+            (true, []) => Ok(self),
+            _ => Err(DataFusionError::Plan(format!(
+                "ReusedExchangeExec: unexpected number of children: {}",
+                children.len()
+            ))),
+        }
+    }
+
+    fn execute(
+        &self,
+        partition: usize,
+        context: Arc<TaskContext>,
+    ) -> Result<SendableRecordBatchStream> {
+        // no need to buffer if the exchange is not actually reused
+        let input = self.input();
+        let num_references = Arc::strong_count(&self.shared);
+        if num_references <= 1 {
+            return input.execute(partition, context);
+        }
+
+        let shared_partition = {
+            let mut partitions = self.shared.partitions.lock().unwrap();
+            let shared_partition = partitions
+                .entry(partition)
+                .or_insert_with(|| {
+                    Arc::new(SharedPartition {
+                        unread_references: AtomicUsize::new(num_references),
+                        buffered: tokio::sync::OnceCell::new(),
+                    })
+                })
+                .clone();
+            if shared_partition
+                .unread_references
+                .fetch_sub(1, Ordering::SeqCst)
+                == 1
+            {
+                partitions.remove(&partition);
+            }
+            shared_partition
+        };
+
+        let batches = futures::stream::once(async move {
+            let num_batches = shared_partition
+                .buffered
+                .get_or_try_init(|| buffer_partition(input, partition, context))
+                .await?
+                .batches
+                .len();
+            Ok::<_, DataFusionError>(futures::stream::iter((0..num_batches).map(
+                move |i| {
+                    let buffered = shared_partition.buffered.get().unwrap();
+                    Ok(buffered.batches[i].clone())
+                },
+            )))
+        })
+        .try_flatten()
+        .map_err(ArrowError::from);
+
+        Ok(Box::pin(RecordBatchStreamAdapter::new(
+            self.schema(),
+            batches,
+        )))
+    }
+
+    fn metrics(&self) -> Option<MetricsSet> {
+        Some(self.metrics.clone_inner())
+    }
+
+    fn fmt_as(&self, _t: DisplayFormatType, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "ReusedExchangeExec: exchange_id={}{}",
+            self.exchange_id,
+            if self.reused { ", reused" } else { "" }
+        )
+    }
+
+    fn statistics(&self) -> Statistics {
+        self.input().statistics()
+    }
+}
+
+async fn buffer_partition(
+    input: Arc<dyn ExecutionPlan>,
+    partition: usize,
+    context: Arc<TaskContext>,
+) -> Result<BufferedPartition> {
+    let memory = SharedPartitionMemory::new(partition, context.runtime_env());
+    context.runtime_env().register_requester(memory.id());
+
+    let mut stream = input.execute(partition, context)?;
+    let mut batches = vec![];
+    while let Some(batch) = stream.next().await {
+        let batch = batch?;
+        memory.grow(batch_byte_size(&batch)).await?;
+        batches.push(batch);
+    }
+    Ok(BufferedPartition {
+        batches,
+        _memory: memory,
+    })
+}
+
+struct SharedPartitionMemory {
+    id: MemoryConsumerId,
+    runtime: Arc<RuntimeEnv>,
+    used: AtomicUsize,
+}
+
+impl SharedPartitionMemory {
+    fn new(partition: usize, runtime: Arc<RuntimeEnv>) -> Self {
+        Self {
+            id: MemoryConsumerId::new(partition),
+            runtime,
+            used: AtomicUsize::new(0),
+        }
+    }
+
+    /// reserves memory of a buffered batch
+    async fn grow(&self, required: usize) -> Result<()> {
+        self.try_grow(required).await?;
+        self.used.fetch_add(required, Ordering::SeqCst);
+        Ok(())
+    }
+}
+
+impl Debug for SharedPartitionMemory {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharedPartitionMemory")
+            .field("id", &self.id())
+            .field("memory_used", &self.mem_used())
+            .finish()
+    }
+}
+
+#[async_trait]
+impl MemoryConsumer for SharedPartitionMemory {
+    fn name(&self) -> String {
+        "ReusedExchangeExec".to_owned()
+    }
+
+    fn id(&self) -> &MemoryConsumerId {
+        &self.id
+    }
+
+    fn memory_manager(&self) -> Arc<MemoryManager> {
+        self.runtime.memory_manager.clone()
+    }
+
+    fn type_(&self) -> &ConsumerType {
+        &ConsumerType::Requesting
+    }
+
+    async fn spill(&self) -> Result<usize> {
+        // buffered batches are read by other references and cannot be spilled
+        Ok(0)
+    }
+
+    fn mem_used(&self) -> usize {
+        self.used.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for SharedPartitionMemory {
+    fn drop(&mut self) {
+        self.runtime.drop_consumer(self.id(), self.mem_used());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use datafusion::physical_plan::common::collect;
+    use datafusion::prelude::SessionContext;
+
+    use super::*;
+    use crate::test_util::memory_exec;
+
+    #[test]
+    fn test_reused_exchange() -> Result<()> {
+        let task_ctx = SessionContext::new().task_ctx();
+        let runtime = tokio::runtime::Runtime::new()?;
+
+        let input = memory_exec("a", vec![Some(1), None, Some(3)])?;
+        let expected = runtime.block_on(collect(input.execute(0, task_ctx.clone())?))?;
+        let first = ReusedExchangeExec::new("test_reused_exchange".to_owned(), input);
+        let second = ReusedExchangeExec::new(
+            "test_reused_exchange".to_owned(),
+            memory_exec("a", vec![Some(4)])?,
+        );
+        assert!(!first.reused && second.reused);
+        assert_eq!(second.children().len(), 0);
+
+        // buffered once for both references, in reserved memory
+        let batches = runtime.block_on(collect(first.execute(0, task_ctx.clone())?))?;
+        assert_eq!(batches, expected);
+        let shared_partition = first.shared.partitions.lock().unwrap()[&0].clone();
+        let buffered = shared_partition.buffered.get().unwrap();
+        assert_eq!(
+            buffered._memory.mem_used(),
+            expected.iter().map(batch_byte_size).sum::<usize>()
+        );
+
+        // evicted once read by all references
+        let stream = second.execute(0, task_ctx)?;
+        assert!(first.shared.partitions.lock().unwrap().is_empty());
+        assert_eq!(runtime.block_on(collect(stream))?, expected);
+        Ok(())
+    }
+}
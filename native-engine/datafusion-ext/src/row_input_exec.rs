@@ -0,0 +1,192 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Defines an operator reading UnsafeRows pushed from JVM
+
+use std::any::Any;
+use std::fmt::Formatter;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use async_trait::async_trait;
+use datafusion::arrow::datatypes::SchemaRef;
+use datafusion::arrow::error::Result as ArrowResult;
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::execution::context::TaskContext;
+use datafusion::physical_plan::expressions::PhysicalSortExpr;
+use datafusion::physical_plan::metrics::{
+    BaselineMetrics, ExecutionPlanMetricsSet, MetricsSet,
+};
+use datafusion::physical_plan::Partitioning::UnknownPartitioning;
+use datafusion::physical_plan::{
+    DisplayFormatType, ExecutionPlan, Partitioning, RecordBatchStream,
+    SendableRecordBatchStream, Statistics,
+};
+use futures::Stream;
+use jni::objects::{GlobalRef, JByteBuffer, JObject};
+use jni::sys::{jboolean, JNI_TRUE};
+
+use crate::jni_bridge::get_resource;
+use crate::jni_call;
+use crate::jni_delete_local_ref;
+use crate::jni_map_error_with_env;
+use crate::jni_new_global_ref;
+use crate::unsafe_row::{is_unsafe_row_convertible, read_unsafe_rows};
+
+/// Reads batches from a JVM iterator of direct ByteBuffers, each containing
+/// size-prefixed UnsafeRows. this lets native plans consume outputs of row-based
+/// spark operators without converting them to arrow on the JVM side.
+#[derive(Debug, Clone)]
+pub struct RowInputExec {
+    pub num_partitions: usize,
+    pub native_resource_id: String,
+    pub schema: SchemaRef,
+    pub metrics: ExecutionPlanMetricsSet,
+}
+
+impl RowInputExec {
+    pub fn try_new(
+        num_partitions: usize,
+        native_resource_id: String,
+        schema: SchemaRef,
+    ) -> Result<RowInputExec> {
+        if !is_unsafe_row_convertible(&schema) {
+            return Err(DataFusionError::Plan(format!(
+                "RowInputExec: unsupported schema: {:?}",
+                schema
+            )));
+        }
+        Ok(RowInputExec {
+            num_partitions,
+            native_resource_id,
+            schema,
+            metrics: ExecutionPlanMetricsSet::new(),
+        })
+    }
+}
+
+#[async_trait]
+impl ExecutionPlan for RowInputExec {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+
+    fn output_partitioning(&self) -> Partitioning {
+        UnknownPartitioning(self.num_partitions)
+    }
+
+    fn output_ordering(&self) -> Option<&[PhysicalSortExpr]> {
+        None
+    }
+
+    fn children(&self) -> Vec<Arc<dyn ExecutionPlan>> {
+        vec![]
+    }
+
+    fn with_new_children(
+        self: Arc<Self>,
+        _children: Vec<Arc<dyn ExecutionPlan>>,
+    ) -> Result<Arc<dyn ExecutionPlan>> {
+        Err(DataFusionError::Plan(
+            "Blaze RowInputExec does not support with_new_children()".to_owned(),
+        ))
+    }
+
+    fn execute(
+        &self,
+        _partition: usize,
+        _context: Arc<TaskContext>,
+    ) -> Result<SendableRecordBatchStream> {
+        let baseline_metrics = BaselineMetrics::new(&self.metrics, 0);
+        let buffers_provider = get_resource(&self.native_resource_id)?;
+        let buffers = jni_new_global_ref!(
+            jni_call!(ScalaFunction0(buffers_provider).apply() -> JObject)?
+        )?;
+
+        Ok(Box::pin(RowInputStream {
+            schema: self.schema.clone(),
+            buffers,
+            baseline_metrics,
+        }))
+    }
+
+    fn metrics(&self) -> Option<MetricsSet> {
+        Some(self.metrics.clone_inner())
+    }
+
+    fn fmt_as(&self, _t: DisplayFormatType, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "RowInputExec: resource_id={}", self.native_resource_id)
+    }
+
+    fn statistics(&self) -> Statistics {
+        Statistics::default()
+    }
+}
+
+struct RowInputStream {
+    schema: SchemaRef,
+    buffers: GlobalRef,
+    baseline_metrics: BaselineMetrics,
+}
+
+impl RowInputStream {
+    fn next_batch(&mut self) -> Result<Option<RecordBatch>> {
+        if jni_call!(
+            ScalaIterator(self.buffers.as_obj()).hasNext() -> jboolean
+        )? != JNI_TRUE
+        {
+            return Ok(None);
+        }
+        let buffer = jni_call!(
+            ScalaIterator(self.buffers.as_obj()).next() -> JObject
+        )?;
+
+        let _timer = self.baseline_metrics.elapsed_compute().timer();
+        let batch = crate::jni_bridge::THREAD_JNIENV.with(|env| {
+            let data = jni_map_error_with_env!(
+                env,
+                env.get_direct_buffer_address(JByteBuffer::from(buffer))
+            )?;
+            read_unsafe_rows(data, &self.schema)
+        })?;
+
+        // buffer ref must be explicitly deleted to avoid OOM
+        jni_delete_local_ref!(buffer)?;
+        Ok(Some(batch))
+    }
+}
+
+impl Stream for RowInputStream {
+    type Item = ArrowResult<RecordBatch>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        let batch = self.next_batch().map_err(|err| err.into()).transpose();
+        self.baseline_metrics.record_poll(Poll::Ready(batch))
+    }
+}
+
+impl RecordBatchStream for RowInputStream {
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+}
@@ -0,0 +1,489 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Defines a parquet scan adapting schemas of the scanned files to the table
+//! schema, following spark's parquet schema evolution rules:
+//!
+//! - columns are matched by names, falling back to case-insensitive matching
+//!   when the file has no column of the exact name
+//! - columns missing in the file are read as nulls, extra columns are ignored
+//! - columns of narrower types are widened, i.e. integers to wider integers
+//!   and float to double. other type mismatches fail the scan.
+//!
+//! partition columns of partitioned tables are the trailing fields of the
+//! table schema, which are not read from files but filled with the partition
+//! values of each split.
+
+use std::any::Any;
+use std::fmt::Formatter;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use datafusion::arrow::compute::cast;
+use datafusion::arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use datafusion::arrow::error::{ArrowError, Result as ArrowResult};
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::datasource::file_format::parquet::ChunkObjectReader;
+use datafusion::datasource::listing::PartitionedFile;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::execution::context::TaskContext;
+use datafusion::logical_plan::Expr;
+use datafusion::parquet::arrow::{ArrowReader, ParquetFileArrowReader};
+use datafusion::parquet::file::reader::SerializedFileReader;
+use datafusion::physical_expr::PhysicalSortExpr;
+use datafusion::physical_plan::file_format::{FileScanConfig, ParquetExec};
+use datafusion::physical_plan::metrics::{
+    BaselineMetrics, ExecutionPlanMetricsSet, MetricsSet,
+};
+use datafusion::physical_plan::stream::RecordBatchStreamAdapter;
+use datafusion::physical_plan::{
+    DisplayFormatType, ExecutionPlan, Partitioning, SendableRecordBatchStream, Statistics,
+};
+use datafusion::scalar::ScalarValue;
+use futures::{StreamExt, TryStreamExt};
+
+/// A parquet scan which opens its file splits one by one, each adapted to
+/// the table schema with `execute_parquet_split()`.
+#[derive(Debug)]
+pub struct SchemaAdaptedParquetExec {
+    parquet_exec: ParquetExec,
+    predicate: Option<Expr>,
+    metrics: ExecutionPlanMetricsSet,
+}
+
+impl SchemaAdaptedParquetExec {
+    pub fn try_new(base_config: FileScanConfig, predicate: Option<Expr>) -> Result<Self> {
+        if base_config.limit.is_some() {
+            return Err(DataFusionError::Plan(
+                "SchemaAdaptedParquetExec does not support scanning with limit"
+                    .to_string(),
+            ));
+        }
+        Ok(Self {
+            parquet_exec: ParquetExec::new(base_config, predicate.clone()),
+            predicate,
+            metrics: ExecutionPlanMetricsSet::new(),
+        })
+    }
+}
+
+#[async_trait]
+impl ExecutionPlan for SchemaAdaptedParquetExec {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.parquet_exec.schema()
+    }
+
+    fn output_partitioning(&self) -> Partitioning {
+        self.parquet_exec.output_partitioning()
+    }
+
+    fn output_ordering(&self) -> Option<&[PhysicalSortExpr]> {
+        None
+    }
+
+    fn children(&self) -> Vec<Arc<dyn ExecutionPlan>> {
+        vec![]
+    }
+
+    fn with_new_children(
+        self: Arc<Self>,
+        _children: Vec<Arc<dyn ExecutionPlan>>,
+    ) -> Result<Arc<dyn ExecutionPlan>> {
+        Err(DataFusionError::Plan(
+            "Blaze SchemaAdaptedParquetExec does not support with_new_children()"
+                .to_owned(),
+        ))
+    }
+
+    fn execute(
+        &self,
+        partition: usize,
+        context: Arc<TaskContext>,
+    ) -> Result<SendableRecordBatchStream> {
+        let base_config = self.parquet_exec.base_config().clone();
+        let files = base_config
+            .file_groups
+            .get(partition)
+            .cloned()
+            .unwrap_or_default();
+        let predicate = self.predicate.clone();
+        let baseline_metrics = BaselineMetrics::new(&self.metrics, partition);
+
+        let stream = futures::stream::iter(files)
+            .map(move |file| {
+                execute_parquet_split(
+                    &base_config,
+                    predicate.clone(),
+                    file,
+                    context.clone(),
+                )
+                .map_err(ArrowError::from)
+            })
+            .try_flatten()
+            .map(
+                move |batch: ArrowResult<RecordBatch>| -> ArrowResult<RecordBatch> {
+                    let batch = batch?;
+                    baseline_metrics.record_output(batch.num_rows());
+                    Ok(batch)
+                },
+            );
+        Ok(Box::pin(RecordBatchStreamAdapter::new(
+            self.schema(),
+            stream,
+        )))
+    }
+
+    fn metrics(&self) -> Option<MetricsSet> {
+        Some(self.metrics.clone_inner())
+    }
+
+    fn fmt_as(&self, t: DisplayFormatType, f: &mut Formatter) -> std::fmt::Result {
+        match t {
+            DisplayFormatType::Default => {
+                write!(f, "SchemaAdaptedParquetExec: ")?;
+                self.parquet_exec.fmt_as(t, f)
+            }
+        }
+    }
+
+    fn statistics(&self) -> Statistics {
+        self.parquet_exec.statistics()
+    }
+}
+
+/// hive writes null partition values as this directory name
+const HIVE_DEFAULT_PARTITION: &str = "__HIVE_DEFAULT_PARTITION__";
+
+/// Scans a single file split of the parquet scan described by `base_config`.
+/// the footer of the file is read to find out how its schema differs from the
+/// table schema. the split is scanned as is if there is no difference other
+/// than missing or extra columns, otherwise columns are read by their names
+/// and types in the file, then renamed and widened to the table schema.
+pub fn execute_parquet_split(
+    base_config: &FileScanConfig,
+    predicate: Option<Expr>,
+    file: PartitionedFile,
+    context: Arc<TaskContext>,
+) -> Result<SendableRecordBatchStream> {
+    if !file.partition_values.is_empty() {
+        return execute_partitioned_split(base_config, predicate, file, context);
+    }
+    let file_schema = read_file_schema(base_config, &file)?;
+    let read_schema = adapt_file_schema(
+        &base_config.file_schema,
+        &file_schema,
+        &file.file_meta.sized_file.path,
+    )?;
+    let split_config = FileScanConfig {
+        file_groups: vec![vec![file]],
+        ..base_config.clone()
+    };
+    let read_schema = match read_schema {
+        Some(read_schema) => read_schema,
+        None => return ParquetExec::new(split_config, predicate).execute(0, context),
+    };
+
+    // the pruning predicate is bound to names and types of the table schema,
+    // so row groups of adapted files are not pruned
+    let output_schema = ParquetExec::new(split_config.clone(), None).schema();
+    let read_config = FileScanConfig {
+        file_schema: read_schema,
+        ..split_config
+    };
+    let stream = ParquetExec::new(read_config, None).execute(0, context)?;
+    Ok(Box::pin(RecordBatchStreamAdapter::new(
+        output_schema.clone(),
+        stream.map(move |batch: ArrowResult<RecordBatch>| {
+            adapt_batch(batch?, &output_schema)
+        }),
+    )))
+}
+
+/// scans a file split with partition values, whose partition columns are
+/// appended as constant columns after reading the data columns
+fn execute_partitioned_split(
+    base_config: &FileScanConfig,
+    predicate: Option<Expr>,
+    file: PartitionedFile,
+    context: Arc<TaskContext>,
+) -> Result<SendableRecordBatchStream> {
+    let table_schema = &base_config.file_schema;
+    let num_fields = table_schema.fields().len();
+    let num_data_fields = num_fields
+        .checked_sub(file.partition_values.len())
+        .ok_or_else(|| {
+            DataFusionError::Plan(format!(
+                "{} partition values exceed {} fields of the table schema",
+                file.partition_values.len(),
+                num_fields,
+            ))
+        })?;
+    let partition_values = file
+        .partition_values
+        .iter()
+        .zip(&table_schema.fields()[num_data_fields..])
+        .map(|(value, field)| partition_value(value, field.data_type()))
+        .collect::<Result<Vec<_>>>()?;
+
+    let projection = base_config
+        .projection
+        .clone()
+        .unwrap_or_else(|| (0..num_fields).collect());
+    let output_schema = Arc::new(table_schema.project(&projection)?);
+
+    // reads at least one data column so that batches have the number of rows
+    let mut data_projection = projection
+        .iter()
+        .copied()
+        .filter(|&i| i < num_data_fields)
+        .collect::<Vec<_>>();
+    if data_projection.is_empty() && num_data_fields > 0 {
+        data_projection.push(0);
+    }
+    let data_config = FileScanConfig {
+        file_schema: Arc::new(Schema::new_with_metadata(
+            table_schema.fields()[..num_data_fields].to_vec(),
+            table_schema.metadata().clone(),
+        )),
+        projection: Some(data_projection),
+        ..base_config.clone()
+    };
+    let file = PartitionedFile {
+        partition_values: vec![],
+        ..file
+    };
+    let stream = execute_parquet_split(&data_config, predicate, file, context)?;
+
+    Ok(Box::pin(RecordBatchStreamAdapter::new(
+        output_schema.clone(),
+        stream.map(move |batch: ArrowResult<RecordBatch>| {
+            let batch = batch?;
+            let mut data_columns = batch.columns().iter();
+            let columns = projection
+                .iter()
+                .map(|&i| match i.checked_sub(num_data_fields) {
+                    Some(partition_idx) => {
+                        partition_values[partition_idx].to_array_of_size(batch.num_rows())
+                    }
+                    None => data_columns.next().unwrap().clone(),
+                })
+                .collect();
+            RecordBatch::try_new(output_schema.clone(), columns)
+        }),
+    )))
+}
+
+/// converts a partition value to the type of its partition column
+fn partition_value(value: &ScalarValue, data_type: &DataType) -> Result<ScalarValue> {
+    let value = match value {
+        ScalarValue::Utf8(Some(value)) if value == HIVE_DEFAULT_PARTITION => {
+            ScalarValue::Utf8(None)
+        }
+        value => value.clone(),
+    };
+    if &value.get_datatype() == data_type {
+        return Ok(value);
+    }
+    let casted = cast(&value.to_array(), data_type)?;
+    ScalarValue::try_from_array(&casted, 0)
+}
+
+fn read_file_schema(
+    base_config: &FileScanConfig,
+    file: &PartitionedFile,
+) -> Result<Schema> {
+    let object_reader = base_config
+        .object_store
+        .file_reader(file.file_meta.sized_file.clone())?;
+    let file_reader = SerializedFileReader::new(ChunkObjectReader(object_reader))?;
+    let mut arrow_reader = ParquetFileArrowReader::new(Arc::new(file_reader));
+    Ok(arrow_reader.get_schema()?)
+}
+
+/// returns the schema to read the file with, which has the same fields as
+/// the table schema except names and types of the columns to adapt, or None
+/// if the file can be read with the table schema directly
+fn adapt_file_schema(
+    table_schema: &Schema,
+    file_schema: &Schema,
+    path: &str,
+) -> Result<Option<SchemaRef>> {
+    let mut adapted = false;
+    let fields = table_schema
+        .fields()
+        .iter()
+        .map(|table_field| {
+            let file_field = match file_schema.field_with_name(table_field.name()) {
+                Ok(file_field) => Some(file_field),
+                Err(_) => {
+                    let mut matched = file_schema.fields().iter().filter(|field| {
+                        field.name().eq_ignore_ascii_case(table_field.name())
+                    });
+                    match (matched.next(), matched.next()) {
+                        (Some(_), Some(_)) => {
+                            return Err(DataFusionError::Execution(format!(
+                                "Found duplicate field(s) \"{}\" in case-insensitive \
+                                 mode in parquet file {}",
+                                table_field.name(),
+                                path,
+                            )));
+                        }
+                        (file_field, _) => file_field,
+                    }
+                }
+            };
+            let file_field = match file_field {
+                Some(file_field)
+                    if file_field.name() != table_field.name()
+                        || file_field.data_type() != table_field.data_type() =>
+                {
+                    file_field
+                }
+                _ => return Ok(table_field.clone()),
+            };
+            if file_field.data_type() != table_field.data_type()
+                && !can_widen(file_field.data_type(), table_field.data_type())
+            {
+                return Err(DataFusionError::Execution(format!(
+                    "Parquet column cannot be converted in file {}. \
+                     Column: [{}], Expected: {:?}, Found: {:?}",
+                    path,
+                    table_field.name(),
+                    table_field.data_type(),
+                    file_field.data_type(),
+                )));
+            }
+            adapted = true;
+            Ok(Field::new(
+                file_field.name(),
+                file_field.data_type().clone(),
+                table_field.is_nullable(),
+            ))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    if !adapted {
+        return Ok(None);
+    }
+    Ok(Some(Arc::new(Schema::new_with_metadata(
+        fields,
+        table_schema.metadata().clone(),
+    ))))
+}
+
+/// widenings of column types supported when reading parquet files
+fn can_widen(from: &DataType, to: &DataType) -> bool {
+    use DataType::*;
+    matches!(
+        (from, to),
+        (Int8, Int16 | Int32 | Int64)
+            | (Int16, Int32 | Int64)
+            | (Int32, Int64)
+            | (Float32, Float64)
+    )
+}
+
+/// renames and widens columns of a batch read with the adapted schema, whose
+/// columns are in the same order as `output_schema`
+fn adapt_batch(
+    batch: RecordBatch,
+    output_schema: &SchemaRef,
+) -> ArrowResult<RecordBatch> {
+    let columns = batch
+        .columns()
+        .iter()
+        .zip(output_schema.fields())
+        .map(|(column, field)| {
+            if column.data_type() == field.data_type() {
+                Ok(column.clone())
+            } else {
+                cast(column, field.data_type())
+            }
+        })
+        .collect::<ArrowResult<Vec<_>>>()?;
+    RecordBatch::try_new(output_schema.clone(), columns)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_adapt_file_schema() -> Result<()> {
+        let table_schema = Schema::new(vec![
+            Field::new("a", DataType::Int64, true),
+            Field::new("b", DataType::Float64, true),
+            Field::new("c", DataType::Utf8, true),
+            Field::new("d", DataType::Int32, true),
+        ]);
+
+        // reordered, extra and missing columns are read with the table schema
+        let file_schema = Schema::new(vec![
+            Field::new("c", DataType::Utf8, true),
+            Field::new("x", DataType::Boolean, true),
+            Field::new("a", DataType::Int64, true),
+        ]);
+        assert!(adapt_file_schema(&table_schema, &file_schema, "f")?.is_none());
+
+        // case-insensitive matching and widening
+        let file_schema = Schema::new(vec![
+            Field::new("A", DataType::Int32, true),
+            Field::new("b", DataType::Float32, true),
+            Field::new("d", DataType::Int16, true),
+        ]);
+        let read_schema = adapt_file_schema(&table_schema, &file_schema, "f")?.unwrap();
+        assert_eq!(
+            read_schema.fields(),
+            &vec![
+                Field::new("A", DataType::Int32, true),
+                Field::new("b", DataType::Float32, true),
+                Field::new("c", DataType::Utf8, true),
+                Field::new("d", DataType::Int16, true),
+            ]
+        );
+
+        // narrowing and ambiguous case-insensitive matching are rejected
+        let file_schema = Schema::new(vec![Field::new("d", DataType::Int64, true)]);
+        assert!(adapt_file_schema(&table_schema, &file_schema, "f").is_err());
+        let file_schema = Schema::new(vec![
+            Field::new("C", DataType::Utf8, true),
+            Field::new("C", DataType::Utf8, true),
+        ]);
+        assert!(adapt_file_schema(&table_schema, &file_schema, "f").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_partition_value() -> Result<()> {
+        let value = ScalarValue::Utf8(Some("2022".to_owned()));
+        assert_eq!(
+            partition_value(&value, &DataType::Int32)?,
+            ScalarValue::Int32(Some(2022))
+        );
+        let value = ScalarValue::Utf8(Some(HIVE_DEFAULT_PARTITION.to_owned()));
+        assert_eq!(
+            partition_value(&value, &DataType::Int32)?,
+            ScalarValue::Int32(None)
+        );
+        assert_eq!(
+            partition_value(&value, &DataType::Utf8)?,
+            ScalarValue::Utf8(None)
+        );
+        Ok(())
+    }
+}
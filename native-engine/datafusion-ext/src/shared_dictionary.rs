@@ -0,0 +1,441 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Executor-scoped dictionaries of designated low-cardinality string columns.
+//!
+//! columns are designated by name with native conf `shared_dictionary_columns`,
+//! a comma-separated list, e.g. `country,status`. shuffle segments carry these
+//! columns dictionary-encoded against one dictionary per column shared by all
+//! tasks of the executor, so the dictionary is built once instead of for every
+//! task and the segment payload holds the dictionary once plus integer keys.
+//! readers decode the columns back to plain strings.
+//!
+//! a dictionary grows up to `shared_dictionary_max_values` values, segments
+//! needing more values than that carry the column plain.
+//!
+//! columns already dictionary-encoded in shuffle inputs, e.g. by scans, are
+//! shuffled without materializing their values. batches of a segment carry
+//! different dictionaries, which are unified into one dictionary per segment
+//! holding only values referenced by the segment, since the IPC file format
+//! does not allow replacing dictionaries.
+
+use std::collections::{HashMap, HashSet};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use datafusion::arrow::array::{
+    Array, ArrayRef, DictionaryArray, Int32Array, Int32Builder, StringArray,
+};
+use datafusion::arrow::compute::cast;
+use datafusion::arrow::datatypes::{DataType, Field, Int32Type, Schema, SchemaRef};
+use datafusion::arrow::error::Result as ArrowResult;
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::error::{DataFusionError, Result};
+use once_cell::sync::OnceCell;
+
+use crate::native_conf::native_conf;
+
+/// Names of columns with shared dictionaries, empty to disable
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SharedDictionaryColumns(pub Vec<String>);
+
+impl SharedDictionaryColumns {
+    /// returns the designated name of the column. native column names are
+    /// suffixed with spark's expression ids, which are ignored
+    fn designated_name(&self, column_name: &str) -> Option<&str> {
+        let name = column_name.split('#').next().unwrap_or_default();
+        self.0
+            .iter()
+            .find(|designated| designated.as_str() == name)
+            .map(|designated| designated.as_str())
+    }
+}
+
+impl FromStr for SharedDictionaryColumns {
+    type Err = DataFusionError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(Self(
+            s.split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(str::to_owned)
+                .collect(),
+        ))
+    }
+}
+
+/// Append-only dictionary of a column, keys of added values never change
+#[derive(Debug, Default)]
+struct SharedDictionary {
+    keys: HashMap<String, i32>,
+    values: Vec<String>,
+    /// values as an array, shared by encoded arrays until the dictionary grows
+    values_array: Option<ArrayRef>,
+}
+
+impl SharedDictionary {
+    /// encodes the arrays with one snapshot of the dictionary, returns None if
+    /// the dictionary would exceed max_values
+    fn encode(
+        &mut self,
+        arrays: &[&StringArray],
+        max_values: usize,
+    ) -> ArrowResult<Option<Vec<ArrayRef>>> {
+        let mut new_values = vec![];
+        let mut seen = HashSet::new();
+        for value in arrays.iter().flat_map(|array| array.iter().flatten()) {
+            if !self.keys.contains_key(value) && seen.insert(value) {
+                new_values.push(value);
+            }
+        }
+        if self.values.len() + new_values.len() > max_values {
+            return Ok(None);
+        }
+        if !new_values.is_empty() {
+            for value in new_values {
+                self.keys.insert(value.to_owned(), self.values.len() as i32);
+                self.values.push(value.to_owned());
+            }
+            self.values_array = None;
+        }
+        let values = &self.values;
+        let values_array = self
+            .values_array
+            .get_or_insert_with(|| Arc::new(StringArray::from_iter_values(values)))
+            .clone();
+
+        let encoded = arrays
+            .iter()
+            .map(|array| {
+                let keys = array
+                    .iter()
+                    .map(|value| value.map(|value| self.keys[value]))
+                    .collect::<Int32Array>();
+                let dict =
+                    DictionaryArray::<Int32Type>::try_new(&keys, values_array.as_ref())?;
+                Ok(Arc::new(dict) as ArrayRef)
+            })
+            .collect::<ArrowResult<Vec<_>>>()?;
+        Ok(Some(encoded))
+    }
+}
+
+fn dictionary_type() -> DataType {
+    DataType::Dictionary(Box::new(DataType::Int32), Box::new(DataType::Utf8))
+}
+
+fn shared_dictionaries() -> &'static Mutex<HashMap<String, SharedDictionary>> {
+    static DICTIONARIES: OnceCell<Mutex<HashMap<String, SharedDictionary>>> =
+        OnceCell::new();
+    DICTIONARIES.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// encodes designated string columns of the batches with shared dictionaries,
+/// all batches are encoded with the same dictionaries so that they can be
+/// written into one IPC file. returns the batches unchanged if no columns are
+/// designated.
+pub fn encode_shared_dictionaries(
+    schema: SchemaRef,
+    batches: &[RecordBatch],
+) -> Result<(SchemaRef, Vec<RecordBatch>)> {
+    let conf = native_conf();
+    let designated = schema
+        .fields()
+        .iter()
+        .enumerate()
+        .filter(|(_, field)| field.data_type() == &DataType::Utf8)
+        .filter_map(|(i, field)| {
+            let columns = &conf.shared_dictionary_columns;
+            Some((i, columns.designated_name(field.name())?.to_owned()))
+        })
+        .collect::<Vec<_>>();
+    if designated.is_empty() {
+        return Ok((schema, batches.to_vec()));
+    }
+
+    let mut fields = schema.fields().clone();
+    let mut columns = batches
+        .iter()
+        .map(|batch| batch.columns().to_vec())
+        .collect::<Vec<_>>();
+    let mut dictionaries = shared_dictionaries().lock().unwrap();
+    for (i, name) in designated {
+        let arrays = batches
+            .iter()
+            .map(|batch| {
+                batch
+                    .column(i)
+                    .as_any()
+                    .downcast_ref::<StringArray>()
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        let field = &schema.fields()[i];
+        let dictionary = dictionaries.entry(name).or_default();
+        if let Some(encoded) =
+            dictionary.encode(&arrays, conf.shared_dictionary_max_values)?
+        {
+            fields[i] = Field::new_dict(
+                field.name(),
+                dictionary_type(),
+                field.is_nullable(),
+                i as i64,
+                false,
+            );
+            for (batch_columns, array) in columns.iter_mut().zip(encoded) {
+                batch_columns[i] = array;
+            }
+        }
+    }
+    drop(dictionaries);
+
+    let schema = Arc::new(Schema::new_with_metadata(fields, schema.metadata().clone()));
+    let batches = columns
+        .into_iter()
+        .map(|columns| RecordBatch::try_new(schema.clone(), columns))
+        .collect::<ArrowResult<Vec<_>>>()?;
+    Ok((schema, batches))
+}
+
+/// unifies dictionaries of dictionary-encoded columns of the batches, so that
+/// the batches can be written into one IPC file. string dictionaries are
+/// merged, other dictionary-encoded columns are written plain. returns the
+/// batches unchanged if there are no dictionary-encoded columns.
+pub fn unify_dictionaries(
+    schema: SchemaRef,
+    batches: &[RecordBatch],
+) -> Result<(SchemaRef, Vec<RecordBatch>)> {
+    let dictionary_columns = schema
+        .fields()
+        .iter()
+        .enumerate()
+        .filter(|(_, field)| matches!(field.data_type(), DataType::Dictionary(_, _)))
+        .map(|(i, _)| i)
+        .collect::<Vec<_>>();
+    if dictionary_columns.is_empty() {
+        return Ok((schema, batches.to_vec()));
+    }
+
+    let mut fields = schema.fields().clone();
+    let mut columns = batches
+        .iter()
+        .map(|batch| batch.columns().to_vec())
+        .collect::<Vec<_>>();
+    for i in dictionary_columns {
+        let field = &schema.fields()[i];
+        let unified = match field.data_type() {
+            dt if dt == &dictionary_type() => {
+                let arrays = batches
+                    .iter()
+                    .map(|batch| {
+                        batch
+                            .column(i)
+                            .as_any()
+                            .downcast_ref::<DictionaryArray<Int32Type>>()
+                            .unwrap()
+                    })
+                    .collect::<Vec<_>>();
+                fields[i] = Field::new_dict(
+                    field.name(),
+                    dictionary_type(),
+                    field.is_nullable(),
+                    i as i64,
+                    false,
+                );
+                merge_string_dictionaries(&arrays)?
+            }
+            DataType::Dictionary(_, value_type) => {
+                fields[i] =
+                    Field::new(field.name(), *value_type.clone(), field.is_nullable());
+                batches
+                    .iter()
+                    .map(|batch| cast(batch.column(i), value_type))
+                    .collect::<ArrowResult<Vec<_>>>()?
+            }
+            _ => unreachable!(),
+        };
+        for (batch_columns, array) in columns.iter_mut().zip(unified) {
+            batch_columns[i] = array;
+        }
+    }
+
+    let schema = Arc::new(Schema::new_with_metadata(fields, schema.metadata().clone()));
+    let batches = columns
+        .into_iter()
+        .map(|columns| RecordBatch::try_new(schema.clone(), columns))
+        .collect::<ArrowResult<Vec<_>>>()?;
+    Ok((schema, batches))
+}
+
+/// re-encodes the arrays against one dictionary of their referenced values
+fn merge_string_dictionaries(
+    arrays: &[&DictionaryArray<Int32Type>],
+) -> ArrowResult<Vec<ArrayRef>> {
+    let mut merged_keys: HashMap<&str, i32> = HashMap::new();
+    let mut merged_values: Vec<&str> = vec![];
+    let mut remapped_keys = vec![];
+    for array in arrays {
+        let values = array
+            .values()
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .unwrap();
+        let mut keys = Int32Builder::new(array.len());
+        for key in array.keys().iter() {
+            match key {
+                Some(key) if values.is_valid(key as usize) => {
+                    let value = values.value(key as usize);
+                    let merged_key = *merged_keys.entry(value).or_insert_with(|| {
+                        merged_values.push(value);
+                        merged_values.len() as i32 - 1
+                    });
+                    keys.append_value(merged_key)?;
+                }
+                _ => keys.append_null()?,
+            }
+        }
+        remapped_keys.push(keys.finish());
+    }
+
+    let values_array: ArrayRef = Arc::new(StringArray::from_iter_values(merged_values));
+    remapped_keys
+        .iter()
+        .map(|keys| {
+            let dict =
+                DictionaryArray::<Int32Type>::try_new(keys, values_array.as_ref())?;
+            Ok(Arc::new(dict) as ArrayRef)
+        })
+        .collect()
+}
+
+/// decodes dictionary-encoded columns of the batch back to types of the schema
+pub fn decode_shared_dictionaries(
+    batch: RecordBatch,
+    schema: &SchemaRef,
+) -> ArrowResult<RecordBatch> {
+    if batch.schema().fields() == schema.fields() {
+        return Ok(batch);
+    }
+    let columns = batch
+        .columns()
+        .iter()
+        .zip(schema.fields())
+        .map(|(column, field)| match column.data_type() {
+            DataType::Dictionary(_, _) if column.data_type() != field.data_type() => {
+                cast(column, field.data_type())
+            }
+            _ => Ok(column.clone()),
+        })
+        .collect::<ArrowResult<Vec<_>>>()?;
+    RecordBatch::try_new(schema.clone(), columns)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use datafusion::arrow::array::{Array, DictionaryArray, StringArray};
+    use datafusion::arrow::datatypes::{DataType, Field, Schema};
+    use datafusion::arrow::record_batch::RecordBatch;
+    use datafusion::error::Result;
+
+    use super::*;
+
+    #[test]
+    fn test_shared_dictionary() -> Result<()> {
+        let mut dictionary = SharedDictionary::default();
+        let array1 = StringArray::from(vec![Some("cn"), None, Some("us"), Some("cn")]);
+        let array2 = StringArray::from(vec![Some("us"), Some("uk")]);
+        let encoded = dictionary.encode(&[&array1, &array2], 3)?.unwrap();
+        assert_eq!(dictionary.values, vec!["cn", "us", "uk"]);
+
+        // keys are stable across encodings and overflowing keeps the dictionary
+        let array3 = StringArray::from(vec![Some("uk"), Some("fr")]);
+        assert!(dictionary.encode(&[&array3], 3)?.is_none());
+        assert_eq!(dictionary.values, vec!["cn", "us", "uk"]);
+
+        let schema = Arc::new(Schema::new(vec![Field::new("c#1", DataType::Utf8, true)]));
+        let encoded_schema = Arc::new(Schema::new(vec![Field::new_dict(
+            "c#1",
+            dictionary_type(),
+            true,
+            0,
+            false,
+        )]));
+        let batch = RecordBatch::try_new(encoded_schema, vec![encoded[0].clone()])?;
+        let decoded = decode_shared_dictionaries(batch, &schema)?;
+        assert_eq!(decoded.schema(), schema);
+        assert_eq!(
+            decoded
+                .column(0)
+                .as_any()
+                .downcast_ref::<StringArray>()
+                .unwrap(),
+            &array1
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_unify_dictionaries() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![Field::new(
+            "c#1",
+            dictionary_type(),
+            true,
+        )]));
+        let dict = |values: Vec<Option<&str>>| -> Result<RecordBatch> {
+            let array: DictionaryArray<Int32Type> = values.into_iter().collect();
+            Ok(RecordBatch::try_new(schema.clone(), vec![Arc::new(array)])?)
+        };
+        let batches = vec![
+            dict(vec![Some("cn"), None, Some("us")])?.slice(0, 2),
+            dict(vec![Some("uk"), Some("cn")])?,
+        ];
+
+        // unused values are dropped and both batches share one dictionary
+        let (unified_schema, unified) = unify_dictionaries(schema.clone(), &batches)?;
+        assert_eq!(unified_schema.field(0).dict_id(), Some(0));
+        for batch in &unified {
+            let dict_array = batch
+                .column(0)
+                .as_any()
+                .downcast_ref::<DictionaryArray<Int32Type>>()
+                .unwrap();
+            assert_eq!(
+                dict_array
+                    .values()
+                    .as_any()
+                    .downcast_ref::<StringArray>()
+                    .unwrap(),
+                &StringArray::from(vec!["cn", "uk"])
+            );
+        }
+
+        let plain = Arc::new(Schema::new(vec![Field::new("c#1", DataType::Utf8, true)]));
+        let decoded = unified
+            .into_iter()
+            .map(|batch| decode_shared_dictionaries(batch, &plain))
+            .collect::<ArrowResult<Vec<_>>>()?;
+        assert_eq!(
+            decoded[1]
+                .column(0)
+                .as_any()
+                .downcast_ref::<StringArray>()
+                .unwrap(),
+            &StringArray::from(vec![Some("uk"), Some("cn")])
+        );
+        Ok(())
+    }
+}
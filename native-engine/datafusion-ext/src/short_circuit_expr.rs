@@ -0,0 +1,273 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Defines AND/OR expressions with spark's left-to-right short-circuit semantics
+
+use std::any::Any;
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+
+use datafusion::arrow::array::{Array, BooleanArray};
+use datafusion::arrow::compute::filter_record_batch;
+use datafusion::arrow::datatypes::{DataType, Schema};
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::logical_plan::Operator;
+use datafusion::physical_plan::{ColumnarValue, PhysicalExpr};
+
+/// A boolean AND/OR whose right side is only evaluated on rows where the left
+/// side does not already determine the result. unlike BinaryExpr, the evaluation
+/// order is fixed, so errors and side effects of the right side never show up
+/// on rows spark would have skipped.
+#[derive(Debug)]
+pub struct ShortCircuitBinaryExpr {
+    left: Arc<dyn PhysicalExpr>,
+    op: Operator,
+    right: Arc<dyn PhysicalExpr>,
+}
+
+impl ShortCircuitBinaryExpr {
+    pub fn try_new(
+        left: Arc<dyn PhysicalExpr>,
+        op: Operator,
+        right: Arc<dyn PhysicalExpr>,
+    ) -> Result<Self> {
+        match op {
+            Operator::And | Operator::Or => Ok(Self { left, op, right }),
+            other => Err(DataFusionError::Plan(format!(
+                "ShortCircuitBinaryExpr does not support operator: {}",
+                other
+            ))),
+        }
+    }
+
+    pub fn left(&self) -> &Arc<dyn PhysicalExpr> {
+        &self.left
+    }
+
+    pub fn op(&self) -> &Operator {
+        &self.op
+    }
+
+    pub fn right(&self) -> &Arc<dyn PhysicalExpr> {
+        &self.right
+    }
+
+    /// the value of left side which decides the result without evaluating right side
+    fn short_circuit_value(&self) -> bool {
+        self.op == Operator::Or
+    }
+}
+
+impl Display for ShortCircuitBinaryExpr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} SC_{} {}", self.left, self.op, self.right)
+    }
+}
+
+impl PhysicalExpr for ShortCircuitBinaryExpr {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn data_type(&self, _input_schema: &Schema) -> Result<DataType> {
+        Ok(DataType::Boolean)
+    }
+
+    fn nullable(&self, input_schema: &Schema) -> Result<bool> {
+        Ok(self.left.nullable(input_schema)? || self.right.nullable(input_schema)?)
+    }
+
+    fn evaluate(&self, batch: &RecordBatch) -> Result<ColumnarValue> {
+        let short_circuit_value = self.short_circuit_value();
+        let left = self.left.evaluate(batch)?.into_array(batch.num_rows());
+        let left = as_boolean_array(&left)?;
+
+        // right side is only evaluated on rows not decided by left side
+        let selected = BooleanArray::from(
+            left.iter()
+                .map(|v| v != Some(short_circuit_value))
+                .collect::<Vec<_>>(),
+        );
+        let num_selected = (0..selected.len()).filter(|&i| selected.value(i)).count();
+        if num_selected == 0 {
+            return Ok(ColumnarValue::Array(Arc::new(left.clone())));
+        }
+
+        let right = if num_selected == batch.num_rows() {
+            self.right.evaluate(batch)?.into_array(batch.num_rows())
+        } else {
+            let selected_batch = filter_record_batch(batch, &selected)?;
+            self.right
+                .evaluate(&selected_batch)?
+                .into_array(selected_batch.num_rows())
+        };
+        let right = as_boolean_array(&right)?;
+
+        let mut right_values = right.iter();
+        let result = left
+            .iter()
+            .map(|l| match l {
+                Some(l) if l == short_circuit_value => Some(l),
+                l => {
+                    let r = right_values.next().unwrap_or(None);
+                    match (l, r) {
+                        (_, Some(r)) if r == short_circuit_value => Some(r),
+                        (Some(_), Some(r)) => Some(r),
+                        _ => None, // kleene logic: null unless decided by any side
+                    }
+                }
+            })
+            .collect::<BooleanArray>();
+        Ok(ColumnarValue::Array(Arc::new(result)))
+    }
+}
+
+fn as_boolean_array(array: &Arc<dyn Array>) -> Result<&BooleanArray> {
+    array
+        .as_any()
+        .downcast_ref::<BooleanArray>()
+        .ok_or_else(|| {
+            DataFusionError::Execution(format!(
+                "ShortCircuitBinaryExpr expects boolean operands, got {:?}",
+                array.data_type()
+            ))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use datafusion::arrow::array::Int32Array;
+    use datafusion::arrow::datatypes::Field;
+    use datafusion::physical_plan::expressions::{col, lit, BinaryExpr};
+    use datafusion::scalar::ScalarValue;
+
+    /// `i > 1`, recording ids of the rows it is evaluated on
+    #[derive(Debug, Default)]
+    struct RecordingExpr {
+        evaluated_ids: Mutex<Vec<Option<i32>>>,
+    }
+
+    impl Display for RecordingExpr {
+        fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+            write!(f, "recording(i > 1)")
+        }
+    }
+
+    impl PhysicalExpr for RecordingExpr {
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+
+        fn data_type(&self, _input_schema: &Schema) -> Result<DataType> {
+            Ok(DataType::Boolean)
+        }
+
+        fn nullable(&self, _input_schema: &Schema) -> Result<bool> {
+            Ok(false)
+        }
+
+        fn evaluate(&self, batch: &RecordBatch) -> Result<ColumnarValue> {
+            let schema = batch.schema();
+            let ids = batch.column(schema.index_of("id")?);
+            let ids = ids.as_any().downcast_ref::<Int32Array>().unwrap();
+            self.evaluated_ids.lock().unwrap().extend(ids.iter());
+
+            BinaryExpr::new(col("i", &schema)?, Operator::Gt, lit(ScalarValue::from(1)))
+                .evaluate(batch)
+        }
+    }
+
+    fn test_batch() -> RecordBatch {
+        let schema = Schema::new(vec![
+            Field::new("b", DataType::Boolean, true),
+            Field::new("i", DataType::Int32, false),
+            Field::new("id", DataType::Int32, false),
+        ]);
+        RecordBatch::try_new(
+            Arc::new(schema),
+            vec![
+                Arc::new(BooleanArray::from(vec![
+                    Some(true),
+                    Some(false),
+                    None,
+                    Some(true),
+                ])),
+                Arc::new(Int32Array::from(vec![1, 0, 0, 2])),
+                Arc::new(Int32Array::from(vec![0, 1, 2, 3])),
+            ],
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_sc_and() -> Result<()> {
+        let batch = test_batch();
+        let schema = batch.schema();
+        // `i > 1` is only evaluated on rows where `b` is not false
+        let right = Arc::new(RecordingExpr::default());
+        let expr = ShortCircuitBinaryExpr::try_new(
+            col("b", &schema)?,
+            Operator::And,
+            right.clone(),
+        )?;
+        let result = expr.evaluate(&batch)?.into_array(batch.num_rows());
+        assert_eq!(
+            as_boolean_array(&result)?,
+            &BooleanArray::from(vec![Some(false), Some(false), Some(false), Some(true)])
+        );
+        assert_eq!(
+            *right.evaluated_ids.lock().unwrap(),
+            vec![Some(0), Some(2), Some(3)]
+        );
+
+        // not evaluated at all if all rows are decided by left side
+        let right = Arc::new(RecordingExpr::default());
+        let expr = ShortCircuitBinaryExpr::try_new(
+            lit(ScalarValue::from(false)),
+            Operator::And,
+            right.clone(),
+        )?;
+        let result = expr.evaluate(&batch)?.into_array(batch.num_rows());
+        assert_eq!(
+            as_boolean_array(&result)?,
+            &BooleanArray::from(vec![false; 4])
+        );
+        assert!(right.evaluated_ids.lock().unwrap().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_sc_or() -> Result<()> {
+        let batch = test_batch();
+        let schema = batch.schema();
+        // `i > 1` is only evaluated on rows where `b` is not true
+        let right = Arc::new(RecordingExpr::default());
+        let expr = ShortCircuitBinaryExpr::try_new(
+            col("b", &schema)?,
+            Operator::Or,
+            right.clone(),
+        )?;
+        let result = expr.evaluate(&batch)?.into_array(batch.num_rows());
+        assert_eq!(
+            as_boolean_array(&result)?,
+            &BooleanArray::from(vec![Some(true), Some(false), None, Some(true)])
+        );
+        assert_eq!(*right.evaluated_ids.lock().unwrap(), vec![Some(1), Some(2)]);
+        Ok(())
+    }
+}
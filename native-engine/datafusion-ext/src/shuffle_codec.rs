@@ -0,0 +1,687 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Compression of shuffle segments with per-stage adaptive codec selection
+//!
+//! A shuffle segment is one compressed arrow IPC file followed by an 8-byte
+//! little-endian length trailer. The compressed data is self-describing:
+//! * zstd: a single zstd frame, identified by the zstd frame magic. this is
+//!   also the format written by JVM-side shuffle writers.
+//! * lz4: `BLZ4` magic, 4-byte little-endian length of the following lz4
+//!   block, and the lz4 block prefixed with its uncompressed size.
+//! * zstd with dictionary: `BZDC` magic, 4-byte little-endian id of the
+//!   dictionary, and a single zstd frame compressed with the dictionary.
+//!
+//! A map task may train a zstd dictionary on its first small segments, the
+//! number of which is set by `shuffle_zstd_dictionary_samples`. The
+//! dictionary is embedded as a dictionary segment (`BZDD` magic, 4-byte
+//! little-endian length and the dictionary, decompressing to nothing) at the
+//! beginning of each partition block of the output file, so that every block
+//! fetched by reducers carries the dictionary of its segments.
+//!
+//! Segments written with checksums are wrapped with `BCRC` magic and the
+//! 4-byte little-endian crc32 of the wrapped segment data. A mismatch is
+//! reported as [`CorruptedSegmentError`], so that the map output can be
+//! fetched again or regenerated instead of decoding corrupted data.
+
+use std::fmt;
+use std::io::{BufRead, Cursor, Read, Write};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+use datafusion::error::{DataFusionError, Result};
+use once_cell::sync::OnceCell;
+
+use crate::native_conf::native_conf;
+
+const ZSTD_FRAME_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+const LZ4_SEGMENT_MAGIC: [u8; 4] = *b"BLZ4";
+const ZSTD_DICTIONARY_SEGMENT_MAGIC: [u8; 4] = *b"BZDD";
+const ZSTD_DICTIONARY_COMPRESSED_MAGIC: [u8; 4] = *b"BZDC";
+const CHECKSUM_SEGMENT_MAGIC: [u8; 4] = *b"BCRC";
+
+/// number of segments compressed with both codecs before a stage decides
+const ADAPTIVE_SAMPLE_SEGMENTS: usize = 8;
+/// smaller segments are not sampled since their ratio is dominated by headers
+const ADAPTIVE_MIN_SAMPLE_BYTES: usize = 16384;
+/// fraction of lz4 output zstd must save to be chosen on idle/saturated cpus
+const ADAPTIVE_MIN_ZSTD_SAVING: f64 = 0.05;
+const ADAPTIVE_MAX_ZSTD_SAVING: f64 = 0.25;
+/// decisions are forgotten when too many stages are tracked
+const ADAPTIVE_MAX_TRACKED_STAGES: usize = 4096;
+
+/// only segments smaller than this are sampled and compressed with dictionary
+const DICTIONARY_MAX_SEGMENT_BYTES: usize = 16384;
+
+/// Compression codec of a shuffle segment
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShuffleCodec {
+    Zstd,
+    Lz4,
+}
+
+/// How shuffle writers choose the codec of segments
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShuffleCodecPolicy {
+    Zstd,
+    Lz4,
+    /// chosen per stage from the compression ratio and cpu headroom observed
+    /// on the first few segments
+    Adaptive,
+}
+
+impl Default for ShuffleCodecPolicy {
+    fn default() -> Self {
+        ShuffleCodecPolicy::Adaptive
+    }
+}
+
+impl FromStr for ShuffleCodecPolicy {
+    type Err = DataFusionError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "zstd" => Ok(ShuffleCodecPolicy::Zstd),
+            "lz4" => Ok(ShuffleCodecPolicy::Lz4),
+            "adaptive" | "auto" => Ok(ShuffleCodecPolicy::Adaptive),
+            other => Err(DataFusionError::Plan(format!(
+                "unsupported shuffle codec: {}",
+                other
+            ))),
+        }
+    }
+}
+
+/// Compresses shuffle segments of one shuffle writer
+pub struct ShuffleCodecSelector {
+    policy: ShuffleCodecPolicy,
+    zstd_level: i32,
+    checksum: bool,
+    stage: Arc<StageCodecState>,
+    dictionary_max_bytes: usize,
+    /// number of small segments sampled before training the dictionary
+    dictionary_samples: usize,
+    dictionary: Mutex<DictionaryState>,
+}
+
+enum DictionaryState {
+    Sampling(Vec<Vec<u8>>),
+    Trained {
+        id: u32,
+        data: Vec<u8>,
+        compressor: zstd::bulk::Compressor<'static>,
+    },
+    Disabled,
+}
+
+impl DictionaryState {
+    fn new(policy: ShuffleCodecPolicy, dictionary_max_bytes: usize) -> Self {
+        if dictionary_max_bytes > 0 && policy != ShuffleCodecPolicy::Lz4 {
+            DictionaryState::Sampling(vec![])
+        } else {
+            DictionaryState::Disabled
+        }
+    }
+}
+
+impl ShuffleCodecSelector {
+    /// writers with the same non-empty `stage_key` share the adaptive codec
+    /// decision, an empty key makes the writer decide on its own
+    pub fn new(stage_key: &str) -> Self {
+        let conf = native_conf();
+        Self {
+            policy: conf.shuffle_codec,
+            zstd_level: conf.shuffle_compression_level,
+            checksum: conf.shuffle_checksum,
+            stage: stage_codec_state(stage_key),
+            dictionary_max_bytes: conf.shuffle_zstd_dictionary_bytes,
+            dictionary_samples: conf.shuffle_zstd_dictionary_samples,
+            dictionary: Mutex::new(DictionaryState::new(
+                conf.shuffle_codec,
+                conf.shuffle_zstd_dictionary_bytes,
+            )),
+        }
+    }
+
+    /// compresses an arrow IPC file into a segment, including the trailer
+    pub fn write_segment<W: Write>(&self, ipc_data: &[u8], output: &mut W) -> Result<()> {
+        let zdata = match self.compress_with_dictionary(ipc_data)? {
+            Some(zdata) => zdata,
+            None => match self.policy {
+                ShuffleCodecPolicy::Zstd => {
+                    compress(ShuffleCodec::Zstd, ipc_data, self.zstd_level)?
+                }
+                ShuffleCodecPolicy::Lz4 => {
+                    compress(ShuffleCodec::Lz4, ipc_data, self.zstd_level)?
+                }
+                ShuffleCodecPolicy::Adaptive => self.compress_adaptive(ipc_data)?,
+            },
+        };
+        self.write_segment_data(&zdata, output)
+    }
+
+    /// returns the dictionary segment including the trailer if a dictionary has
+    /// been trained. it must be written ahead of the segments of each block.
+    pub fn dictionary_segment(&self) -> Option<Vec<u8>> {
+        match &*self.dictionary.lock().unwrap() {
+            DictionaryState::Trained { data, .. } => {
+                let mut zdata = Vec::with_capacity(data.len() + 8);
+                zdata.extend_from_slice(&ZSTD_DICTIONARY_SEGMENT_MAGIC);
+                zdata.extend_from_slice(&(data.len() as u32).to_le_bytes());
+                zdata.extend_from_slice(data);
+
+                let mut segment = Vec::with_capacity(zdata.len() + 8);
+                self.write_segment_data(&zdata, &mut segment).ok()?;
+                Some(segment)
+            }
+            _ => None,
+        }
+    }
+
+    fn write_segment_data<W: Write>(&self, zdata: &[u8], output: &mut W) -> Result<()> {
+        if !self.checksum {
+            return write_segment_data(zdata, output);
+        }
+        let mut wrapped = Vec::with_capacity(zdata.len() + 8);
+        wrapped.extend_from_slice(&CHECKSUM_SEGMENT_MAGIC);
+        wrapped.extend_from_slice(&crc32fast::hash(zdata).to_le_bytes());
+        wrapped.extend_from_slice(zdata);
+        write_segment_data(&wrapped, output)
+    }
+
+    /// compresses small segments with the dictionary trained on the first small
+    /// segments, returns None for segments left to the codec policy
+    fn compress_with_dictionary(&self, ipc_data: &[u8]) -> Result<Option<Vec<u8>>> {
+        // stages which have chosen lz4 do not trade cpu for compression ratio
+        if ipc_data.len() >= DICTIONARY_MAX_SEGMENT_BYTES
+            || self.stage.decided.get() == Some(&ShuffleCodec::Lz4)
+        {
+            return Ok(None);
+        }
+
+        let mut dictionary = self.dictionary.lock().unwrap();
+        match &mut *dictionary {
+            DictionaryState::Disabled => Ok(None),
+            DictionaryState::Sampling(samples) => {
+                samples.push(ipc_data.to_vec());
+                if samples.len() >= self.dictionary_samples {
+                    let trained =
+                        zstd::dict::from_samples(samples, self.dictionary_max_bytes)
+                            .and_then(|data| {
+                                let compressor = zstd::bulk::Compressor::with_dictionary(
+                                    self.zstd_level,
+                                    &data,
+                                )?;
+                                Ok((data, compressor))
+                            });
+                    *dictionary = match trained {
+                        Ok((data, compressor)) => match dictionary_id(&data) {
+                            Some(id) => {
+                                log::info!(
+                                    "Trained zstd dictionary of {} bytes for shuffle",
+                                    data.len()
+                                );
+                                DictionaryState::Trained {
+                                    id,
+                                    data,
+                                    compressor,
+                                }
+                            }
+                            None => DictionaryState::Disabled,
+                        },
+                        Err(err) => {
+                            log::warn!("Training zstd dictionary failed: {}", err);
+                            DictionaryState::Disabled
+                        }
+                    };
+                }
+                Ok(None)
+            }
+            DictionaryState::Trained { id, compressor, .. } => {
+                let frame = compressor.compress(ipc_data)?;
+                let mut zdata = Vec::with_capacity(frame.len() + 8);
+                zdata.extend_from_slice(&ZSTD_DICTIONARY_COMPRESSED_MAGIC);
+                zdata.extend_from_slice(&id.to_le_bytes());
+                zdata.extend_from_slice(&frame);
+                Ok(Some(zdata))
+            }
+        }
+    }
+
+    fn compress_adaptive(&self, ipc_data: &[u8]) -> Result<Vec<u8>> {
+        if let Some(&codec) = self.stage.decided.get() {
+            return compress(codec, ipc_data, self.zstd_level);
+        }
+        if ipc_data.len() < ADAPTIVE_MIN_SAMPLE_BYTES {
+            return compress(ShuffleCodec::Zstd, ipc_data, self.zstd_level);
+        }
+
+        // sample with both codecs and keep the smaller output
+        let start_time = Instant::now();
+        let lz4_data = compress(ShuffleCodec::Lz4, ipc_data, self.zstd_level)?;
+        let lz4_time = start_time.elapsed();
+        let start_time = Instant::now();
+        let zstd_data = compress(ShuffleCodec::Zstd, ipc_data, self.zstd_level)?;
+        let zstd_time = start_time.elapsed();
+
+        let mut samples = self.stage.samples.lock().unwrap();
+        samples.num_segments += 1;
+        samples.uncompressed_bytes += ipc_data.len();
+        samples.lz4_bytes += lz4_data.len();
+        samples.zstd_bytes += zstd_data.len();
+        samples.lz4_time += lz4_time;
+        samples.zstd_time += zstd_time;
+        if samples.num_segments >= ADAPTIVE_SAMPLE_SEGMENTS {
+            let codec = choose_codec(&samples, cpu_headroom());
+            if self.stage.decided.set(codec).is_ok() {
+                log::info!("Adaptive shuffle codec chose {:?}: {:?}", codec, samples);
+            }
+        }
+        drop(samples);
+
+        Ok(if zstd_data.len() <= lz4_data.len() {
+            zstd_data
+        } else {
+            lz4_data
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+struct CodecSamples {
+    num_segments: usize,
+    uncompressed_bytes: usize,
+    lz4_bytes: usize,
+    zstd_bytes: usize,
+    lz4_time: Duration,
+    zstd_time: Duration,
+}
+
+#[derive(Default)]
+struct StageCodecState {
+    decided: OnceCell<ShuffleCodec>,
+    samples: Mutex<CodecSamples>,
+}
+
+fn stage_codec_state(stage_key: &str) -> Arc<StageCodecState> {
+    static STAGES: OnceCell<DashMap<String, Arc<StageCodecState>>> = OnceCell::new();
+    if stage_key.is_empty() {
+        return Arc::default();
+    }
+    let stages = STAGES.get_or_init(DashMap::new);
+    if stages.len() >= ADAPTIVE_MAX_TRACKED_STAGES {
+        stages.clear();
+    }
+    stages.entry(stage_key.to_owned()).or_default().clone()
+}
+
+/// chooses zstd only if it saves enough bytes over lz4, requiring larger
+/// savings when there is less cpu headroom
+fn choose_codec(samples: &CodecSamples, cpu_headroom: f64) -> ShuffleCodec {
+    let zstd_saving = 1.0 - samples.zstd_bytes as f64 / samples.lz4_bytes.max(1) as f64;
+    let required_saving = ADAPTIVE_MIN_ZSTD_SAVING
+        + (1.0 - cpu_headroom) * (ADAPTIVE_MAX_ZSTD_SAVING - ADAPTIVE_MIN_ZSTD_SAVING);
+    if zstd_saving >= required_saving {
+        ShuffleCodec::Zstd
+    } else {
+        ShuffleCodec::Lz4
+    }
+}
+
+/// fraction of idle cpus estimated from system load, 1.0 if unknown
+fn cpu_headroom() -> f64 {
+    let num_cpus = std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1) as f64;
+    let load = std::fs::read_to_string("/proc/loadavg")
+        .ok()
+        .and_then(|loadavg| loadavg.split_whitespace().next()?.parse::<f64>().ok());
+    match load {
+        Some(load) => (1.0 - load / num_cpus).clamp(0.0, 1.0),
+        None => 1.0,
+    }
+}
+
+fn write_segment_data<W: Write>(zdata: &[u8], output: &mut W) -> Result<()> {
+    output.write_all(zdata)?;
+    output.write_all(&(zdata.len() as u64).to_le_bytes()[..])?;
+    Ok(())
+}
+
+/// returns the id in the header of a zstd dictionary
+fn dictionary_id(data: &[u8]) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(4..8)?.try_into().ok()?))
+}
+
+fn compress(codec: ShuffleCodec, ipc_data: &[u8], zstd_level: i32) -> Result<Vec<u8>> {
+    Ok(match codec {
+        ShuffleCodec::Zstd => zstd::encode_all(ipc_data, zstd_level)?,
+        ShuffleCodec::Lz4 => {
+            let block = lz4::block::compress(ipc_data, None, true)?;
+            let mut zdata = Vec::with_capacity(block.len() + 8);
+            zdata.extend_from_slice(&LZ4_SEGMENT_MAGIC);
+            zdata.extend_from_slice(&(block.len() as u32).to_le_bytes());
+            zdata.extend_from_slice(&block);
+            zdata
+        }
+    })
+}
+
+/// returns codec of the compressed segment data
+pub fn segment_codec(zdata: &[u8]) -> Result<ShuffleCodec> {
+    match zdata.get(0..4) {
+        Some(magic) if magic == ZSTD_FRAME_MAGIC => Ok(ShuffleCodec::Zstd),
+        Some(magic) if magic == LZ4_SEGMENT_MAGIC => Ok(ShuffleCodec::Lz4),
+        _ => Err(DataFusionError::Execution(
+            "unknown codec of shuffle segment".to_owned(),
+        )),
+    }
+}
+
+/// Decompresses the segments of shuffle blocks in order, keeping the zstd
+/// dictionary embedded ahead of the segments compressed with it
+#[derive(Default)]
+pub struct SegmentDecompressor {
+    dictionary: Option<(u32, Vec<u8>)>,
+}
+
+impl SegmentDecompressor {
+    /// decompresses segment data without the trailer into arrow IPC file data,
+    /// returns None for dictionary segments
+    pub fn decompress_segment(&mut self, zdata: &[u8]) -> Result<Option<Vec<u8>>> {
+        let mut reader = zdata;
+        self.read_segment(&mut reader)
+    }
+
+    /// reads and decompresses exactly one segment from the stream, leaving the
+    /// trailer unread. returns None for dictionary segments
+    pub fn read_segment<R: BufRead>(
+        &mut self,
+        reader: &mut R,
+    ) -> Result<Option<Vec<u8>>> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if magic != CHECKSUM_SEGMENT_MAGIC {
+            return self.decode_segment(magic, reader);
+        }
+
+        let checksum = read_u32(reader)?;
+        let mut checksum_reader = ChecksumReader {
+            inner: reader,
+            hasher: crc32fast::Hasher::new(),
+        };
+        // decoding errors of checksummed segments are caused by corruption
+        let decoded = checksum_reader
+            .read_exact(&mut magic)
+            .map_err(DataFusionError::from)
+            .and_then(|_| self.decode_segment(magic, &mut checksum_reader))
+            .map_err(|err| corrupted_segment_error(err.to_string()))?;
+        if checksum_reader.hasher.finalize() != checksum {
+            return Err(corrupted_segment_error("checksum mismatch".to_owned()));
+        }
+        Ok(decoded)
+    }
+
+    /// decompresses one unwrapped segment of which the magic has been read
+    fn decode_segment<R: BufRead>(
+        &mut self,
+        magic: [u8; 4],
+        reader: &mut R,
+    ) -> Result<Option<Vec<u8>>> {
+        let mut arrow_data = vec![];
+        if magic == ZSTD_DICTIONARY_SEGMENT_MAGIC {
+            let mut data = vec![0; read_u32(reader)? as usize];
+            reader.read_exact(&mut data)?;
+            let id = dictionary_id(&data).ok_or_else(|| {
+                DataFusionError::Execution("malformed zstd dictionary segment".to_owned())
+            })?;
+            self.dictionary = Some((id, data));
+            return Ok(None);
+        }
+        if magic == ZSTD_DICTIONARY_COMPRESSED_MAGIC {
+            let id = read_u32(reader)?;
+            let dictionary = match &self.dictionary {
+                Some((dictionary_id, data)) if *dictionary_id == id => data,
+                _ => {
+                    return Err(DataFusionError::Execution(format!(
+                        "missing zstd dictionary {} of shuffle segment",
+                        id
+                    )))
+                }
+            };
+            zstd::stream::read::Decoder::with_dictionary(reader, dictionary)?
+                .single_frame()
+                .read_to_end(&mut arrow_data)?;
+            return Ok(Some(arrow_data));
+        }
+
+        match segment_codec(&magic)? {
+            ShuffleCodec::Zstd => {
+                // the decoder stops exactly at the end of frame
+                let frame = Cursor::new(magic).chain(reader);
+                zstd::stream::read::Decoder::with_buffer(frame)?
+                    .single_frame()
+                    .read_to_end(&mut arrow_data)?;
+            }
+            ShuffleCodec::Lz4 => {
+                let mut block = vec![0; read_u32(reader)? as usize];
+                reader.read_exact(&mut block)?;
+                arrow_data = lz4::block::decompress(&block, None)?;
+            }
+        }
+        Ok(Some(arrow_data))
+    }
+}
+
+/// Error of shuffle segments failing checksum verification
+#[derive(Debug)]
+pub struct CorruptedSegmentError(String);
+
+impl fmt::Display for CorruptedSegmentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "corrupted shuffle segment: {}", self.0)
+    }
+}
+
+impl std::error::Error for CorruptedSegmentError {}
+
+fn corrupted_segment_error(reason: String) -> DataFusionError {
+    DataFusionError::External(Box::new(CorruptedSegmentError(reason)))
+}
+
+/// returns true if the error is caused by a corrupted shuffle segment
+pub fn is_corrupted_segment_error(err: &DataFusionError) -> bool {
+    matches!(err, DataFusionError::External(err) if err.is::<CorruptedSegmentError>())
+}
+
+/// Computes crc32 of all bytes read or consumed through it
+struct ChecksumReader<'a, R: BufRead> {
+    inner: &'a mut R,
+    hasher: crc32fast::Hasher,
+}
+
+impl<'a, R: BufRead> Read for ChecksumReader<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read_bytes = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read_bytes]);
+        Ok(read_bytes)
+    }
+}
+
+impl<'a, R: BufRead> BufRead for ChecksumReader<'a, R> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        // the buffer returned by last fill_buf() is returned again without IO
+        if let Ok(buf) = self.inner.fill_buf() {
+            self.hasher.update(&buf[..amt]);
+        }
+        self.inner.consume(amt);
+    }
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> Result<u32> {
+    let mut bytes = [0u8; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_segment_roundtrip() -> Result<()> {
+        let ipc_data: Vec<u8> = (0..100000u32)
+            .flat_map(|i| (i % 97).to_le_bytes())
+            .collect();
+        for policy in [ShuffleCodecPolicy::Zstd, ShuffleCodecPolicy::Lz4] {
+            let selector = test_selector(policy, 0);
+            let mut block = vec![];
+            selector.write_segment(&ipc_data, &mut block)?;
+            selector.write_segment(&ipc_data, &mut block)?;
+
+            // streaming reads stop exactly before the trailers
+            let mut decompressor = SegmentDecompressor::default();
+            let mut reader = &block[..];
+            for _ in 0..2 {
+                let arrow_data = decompressor.read_segment(&mut reader)?;
+                assert_eq!(arrow_data, Some(ipc_data.clone()));
+                reader = &reader[8..];
+            }
+            assert!(reader.is_empty());
+
+            let zdata_len = block.len() / 2 - 8;
+            assert_eq!(
+                decompressor.decompress_segment(&block[..zdata_len])?,
+                Some(ipc_data.clone())
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_segment_checksum() -> Result<()> {
+        let ipc_data: Vec<u8> =
+            (0..10000u32).flat_map(|i| (i % 97).to_le_bytes()).collect();
+        for policy in [ShuffleCodecPolicy::Zstd, ShuffleCodecPolicy::Lz4] {
+            let selector = ShuffleCodecSelector {
+                checksum: true,
+                ..test_selector(policy, 0)
+            };
+            let mut block = vec![];
+            selector.write_segment(&ipc_data, &mut block)?;
+            assert_eq!(&block[0..4], &CHECKSUM_SEGMENT_MAGIC);
+
+            let mut reader = &block[..];
+            let arrow_data = SegmentDecompressor::default().read_segment(&mut reader)?;
+            assert_eq!(arrow_data, Some(ipc_data.clone()));
+            assert_eq!(reader.len(), 8);
+
+            // corruption of the checksum or the compressed data is detected
+            let zdata = &block[..block.len() - 8];
+            for pos in [5, 12, zdata.len() - 1] {
+                let mut corrupted = zdata.to_vec();
+                corrupted[pos] ^= 0x10;
+                let err = SegmentDecompressor::default()
+                    .decompress_segment(&corrupted)
+                    .unwrap_err();
+                assert!(is_corrupted_segment_error(&err), "{}", err);
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_dictionary_segments() -> Result<()> {
+        let selector = test_selector(ShuffleCodecPolicy::Zstd, 4096);
+        let segments = (0..selector.dictionary_samples as u32 + 4)
+            .map(|seed| {
+                (0..500u32)
+                    .flat_map(|i| (i * seed % 97).to_le_bytes())
+                    .collect::<Vec<u8>>()
+            })
+            .collect::<Vec<_>>();
+        let mut block = vec![];
+        for segment in &segments {
+            selector.write_segment(segment, &mut block)?;
+        }
+
+        // the dictionary is embedded ahead of the segments compressed with it
+        let dictionary_segment = selector.dictionary_segment().unwrap();
+        assert_eq!(&dictionary_segment[0..4], &ZSTD_DICTIONARY_SEGMENT_MAGIC);
+        let block = [dictionary_segment, block].concat();
+
+        let mut decompressor = SegmentDecompressor::default();
+        let mut reader = &block[..];
+        assert_eq!(decompressor.read_segment(&mut reader)?, None);
+        reader = &reader[8..];
+        for segment in &segments {
+            assert_eq!(
+                decompressor.read_segment(&mut reader)?.as_ref(),
+                Some(segment)
+            );
+            reader = &reader[8..];
+        }
+        assert!(reader.is_empty());
+
+        // segments cannot be decompressed without their dictionary
+        let mut segment = vec![];
+        selector.write_segment(&segments[0], &mut segment)?;
+        let zdata = &segment[..segment.len() - 8];
+        assert_eq!(&zdata[0..4], &ZSTD_DICTIONARY_COMPRESSED_MAGIC);
+        assert!(SegmentDecompressor::default()
+            .decompress_segment(zdata)
+            .is_err());
+        Ok(())
+    }
+
+    fn test_selector(
+        policy: ShuffleCodecPolicy,
+        dictionary_max_bytes: usize,
+    ) -> ShuffleCodecSelector {
+        ShuffleCodecSelector {
+            policy,
+            zstd_level: 1,
+            checksum: false,
+            stage: Arc::default(),
+            dictionary_max_bytes,
+            dictionary_samples: 32,
+            dictionary: Mutex::new(DictionaryState::new(policy, dictionary_max_bytes)),
+        }
+    }
+
+    #[test]
+    fn test_choose_codec() {
+        let samples = CodecSamples {
+            lz4_bytes: 1000,
+            zstd_bytes: 850,
+            ..Default::default()
+        };
+        assert_eq!(choose_codec(&samples, 1.0), ShuffleCodec::Zstd);
+        assert_eq!(choose_codec(&samples, 0.0), ShuffleCodec::Lz4);
+
+        // incompressible data
+        let samples = CodecSamples {
+            lz4_bytes: 1000,
+            zstd_bytes: 990,
+            ..Default::default()
+        };
+        assert_eq!(choose_codec(&samples, 1.0), ShuffleCodec::Lz4);
+    }
+}
@@ -0,0 +1,345 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Defines the destinations of shuffle writer output
+
+use std::fs::File;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::sync::Mutex;
+
+use datafusion::error::{DataFusionError, Result};
+use jni::objects::{GlobalRef, JObject};
+use once_cell::sync::Lazy;
+
+use crate::jni_bridge::get_resource;
+use crate::jni_call;
+use crate::jni_new_direct_byte_buffer;
+use crate::jni_new_global_ref;
+
+/// Where the shuffle writer puts its partitioned output
+#[derive(Debug, Clone)]
+pub enum ShuffleWriterOutput {
+    /// spark's sort-shuffle layout: one data file and one index file of offsets
+    Local {
+        output_data_file: String,
+        output_index_file: String,
+    },
+    /// blocks are pushed to a remote shuffle service through a JVM-side
+    /// `RssPartitionWriterBase` registered in JniBridge.resourcesMap
+    Rss {
+        rss_partition_writer_resource_id: String,
+    },
+}
+
+impl ShuffleWriterOutput {
+    /// creates the writer of the task attempt, local output is written into
+    /// temp files of the attempt until committed by the JVM side
+    pub fn create_writer(
+        &self,
+        num_output_partitions: usize,
+        task_attempt_id: i64,
+    ) -> Result<Box<dyn ShuffleOutputWriter>> {
+        Ok(match self {
+            ShuffleWriterOutput::Local {
+                output_data_file,
+                output_index_file,
+            } => Box::new(LocalShuffleOutputWriter::try_new(
+                output_data_file,
+                output_index_file,
+                num_output_partitions,
+                task_attempt_id,
+            )?),
+            ShuffleWriterOutput::Rss {
+                rss_partition_writer_resource_id,
+            } => Box::new(RssShuffleOutputWriter::try_new(
+                rss_partition_writer_resource_id,
+            )?),
+        })
+    }
+}
+
+/// Receives compressed shuffle blocks of each output partition.
+/// Blocks must be written in ascending order of partition id.
+pub trait ShuffleOutputWriter: Send {
+    fn write_block(&mut self, partition_id: usize, block: &mut dyn Read) -> Result<()>;
+    fn finish(self: Box<Self>) -> Result<()>;
+}
+
+/// Writes spark's sort-shuffle layout into temp files scoped to the task
+/// attempt, so that concurrent attempts of the same map task (e.g. speculative
+/// ones) never write into the same files. the temp files are published by
+/// `commit_local_shuffle_output()`, or deleted if the writer is dropped
+/// without finishing.
+pub struct LocalShuffleOutputWriter {
+    output_data: File,
+    temp_data_file: String,
+    temp_index_file: String,
+    offsets: Vec<u64>,
+    next_partition_id: usize,
+    finished: bool,
+}
+
+impl LocalShuffleOutputWriter {
+    pub fn try_new(
+        output_data_file: &str,
+        output_index_file: &str,
+        num_output_partitions: usize,
+        task_attempt_id: i64,
+    ) -> Result<Self> {
+        let temp_data_file = attempt_temp_file(output_data_file, task_attempt_id);
+        let temp_index_file = attempt_temp_file(output_index_file, task_attempt_id);
+        Ok(Self {
+            output_data: File::create(&temp_data_file)?,
+            temp_data_file,
+            temp_index_file,
+            offsets: vec![0; num_output_partitions + 1],
+            next_partition_id: 0,
+            finished: false,
+        })
+    }
+
+    /// fill start offsets of all partitions before `partition_id`
+    fn advance_to(&mut self, partition_id: usize) -> Result<()> {
+        if self.next_partition_id <= partition_id {
+            let offset = self.output_data.seek(SeekFrom::Current(0))?;
+            self.offsets[self.next_partition_id..=partition_id].fill(offset);
+            self.next_partition_id = partition_id + 1;
+        }
+        Ok(())
+    }
+}
+
+impl ShuffleOutputWriter for LocalShuffleOutputWriter {
+    fn write_block(&mut self, partition_id: usize, block: &mut dyn Read) -> Result<()> {
+        self.advance_to(partition_id)?;
+        std::io::copy(block, &mut self.output_data)?;
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        // add one extra offset at last to ease partition length computation
+        let num_output_partitions = self.offsets.len() - 1;
+        self.advance_to(num_output_partitions)?;
+        self.output_data.flush()?;
+
+        let mut output_index = File::create(&self.temp_index_file)?;
+        for &offset in &self.offsets {
+            output_index.write_all(&(offset as i64).to_le_bytes()[..])?;
+        }
+        output_index.flush()?;
+        self.finished = true;
+        Ok(())
+    }
+}
+
+impl Drop for LocalShuffleOutputWriter {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = std::fs::remove_file(&self.temp_data_file);
+            let _ = std::fs::remove_file(&self.temp_index_file);
+        }
+    }
+}
+
+/// commits of all attempts in this executor are serialized
+static COMMIT_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+/// the temp file written by the task attempt in place of `path`
+pub fn attempt_temp_file(path: &str, task_attempt_id: i64) -> String {
+    format!("{}.attempt-{}.tmp", path, task_attempt_id)
+}
+
+/// publishes the local shuffle output of the task attempt by renaming its temp
+/// files, and returns the length of each output partition. if another attempt
+/// of the same map task has already committed a valid output, that output is
+/// kept and its partition lengths are returned, so committing is idempotent.
+pub fn commit_local_shuffle_output(
+    output_data_file: &str,
+    output_index_file: &str,
+    task_attempt_id: i64,
+) -> Result<Vec<u64>> {
+    let temp_data_file = attempt_temp_file(output_data_file, task_attempt_id);
+    let temp_index_file = attempt_temp_file(output_index_file, task_attempt_id);
+    let partition_lengths = read_partition_lengths(&temp_data_file, &temp_index_file)?
+        .ok_or_else(|| {
+            DataFusionError::Execution(format!(
+                "shuffle output of task attempt {} is missing or incomplete: {}",
+                task_attempt_id, temp_index_file
+            ))
+        })?;
+
+    let _lock = COMMIT_LOCK.lock().unwrap();
+    if let Some(committed_lengths) =
+        read_partition_lengths(output_data_file, output_index_file)?
+    {
+        if committed_lengths.len() == partition_lengths.len() {
+            log::info!(
+                "Shuffle output already committed by another attempt: {}",
+                output_data_file
+            );
+            abort_local_shuffle_output(
+                output_data_file,
+                output_index_file,
+                task_attempt_id,
+            )?;
+            return Ok(committed_lengths);
+        }
+    }
+
+    // the index file is published first, the same as spark's IndexShuffleBlockResolver
+    std::fs::rename(&temp_index_file, output_index_file)?;
+    std::fs::rename(&temp_data_file, output_data_file)?;
+    Ok(partition_lengths)
+}
+
+/// deletes the temp files of local shuffle output of the task attempt
+pub fn abort_local_shuffle_output(
+    output_data_file: &str,
+    output_index_file: &str,
+    task_attempt_id: i64,
+) -> Result<()> {
+    for path in [output_data_file, output_index_file] {
+        match std::fs::remove_file(attempt_temp_file(path, task_attempt_id)) {
+            Err(err) if err.kind() != ErrorKind::NotFound => return Err(err.into()),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// reads partition lengths from the index file, returns None if the files are
+/// missing or do not match each other
+fn read_partition_lengths(data_file: &str, index_file: &str) -> Result<Option<Vec<u64>>> {
+    let index = std::fs::read(index_file);
+    let data_metadata = std::fs::metadata(data_file);
+    let (index, data_len) = match (index, data_metadata) {
+        (Ok(index), Ok(data_metadata)) => (index, data_metadata.len()),
+        (Err(err), _) | (_, Err(err)) if err.kind() == ErrorKind::NotFound => {
+            return Ok(None);
+        }
+        (Err(err), _) | (_, Err(err)) => return Err(err.into()),
+    };
+    if index.len() < 8 || index.len() % 8 != 0 {
+        return Ok(None);
+    }
+    let offsets = index
+        .chunks(8)
+        .map(|bytes| i64::from_le_bytes(bytes.try_into().unwrap()) as u64)
+        .collect::<Vec<_>>();
+    if offsets[0] != 0
+        || offsets[offsets.len() - 1] != data_len
+        || offsets.windows(2).any(|w| w[0] > w[1])
+    {
+        return Ok(None);
+    }
+    Ok(Some(offsets.windows(2).map(|w| w[1] - w[0]).collect()))
+}
+
+pub struct RssShuffleOutputWriter {
+    rss_partition_writer: GlobalRef,
+}
+
+impl RssShuffleOutputWriter {
+    pub fn try_new(rss_partition_writer_resource_id: &str) -> Result<Self> {
+        let rss_partition_writer_provider =
+            get_resource(rss_partition_writer_resource_id)?;
+        let rss_partition_writer = jni_new_global_ref!(
+            jni_call!(ScalaFunction0(rss_partition_writer_provider).apply() -> JObject)?
+        )?;
+        Ok(Self {
+            rss_partition_writer,
+        })
+    }
+}
+
+impl ShuffleOutputWriter for RssShuffleOutputWriter {
+    fn write_block(&mut self, partition_id: usize, block: &mut dyn Read) -> Result<()> {
+        let mut data = vec![];
+        block.read_to_end(&mut data)?;
+        if data.is_empty() {
+            return Ok(());
+        }
+        let buf = jni_new_direct_byte_buffer!(&mut data)?;
+        jni_call!(
+            BlazeRssPartitionWriterBase(self.rss_partition_writer.as_obj())
+                .write(partition_id as i32, buf) -> ()
+        )?;
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<()> {
+        jni_call!(
+            BlazeRssPartitionWriterBase(self.rss_partition_writer.as_obj()).flush() -> ()
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use datafusion::error::Result;
+
+    use super::*;
+
+    #[test]
+    fn test_commit_local_shuffle_output() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let data_file = dir.path().join("shuffle_0_0_0.data");
+        let index_file = dir.path().join("shuffle_0_0_0.index");
+        let data_file = data_file.to_str().unwrap();
+        let index_file = index_file.to_str().unwrap();
+        let write = |task_attempt_id: i64, blocks: &[&[u8]]| -> Result<()> {
+            let mut writer = LocalShuffleOutputWriter::try_new(
+                data_file,
+                index_file,
+                3,
+                task_attempt_id,
+            )?;
+            for (partition_id, block) in blocks.iter().enumerate() {
+                writer.write_block(partition_id, &mut Cursor::new(block))?;
+            }
+            Box::new(writer).finish()
+        };
+
+        // attempts of the same map task write their own temp files
+        write(1, &[b"a", b"", b"bc"])?;
+        write(2, &[b"xy", b"z", b"w"])?;
+        assert!(std::fs::metadata(data_file).is_err());
+
+        // the first committed attempt wins
+        assert_eq!(
+            commit_local_shuffle_output(data_file, index_file, 2)?,
+            vec![2, 1, 1]
+        );
+        assert_eq!(
+            commit_local_shuffle_output(data_file, index_file, 1)?,
+            vec![2, 1, 1]
+        );
+        assert_eq!(std::fs::read(data_file)?, b"xyzw");
+        assert_eq!(std::fs::read_dir(dir.path())?.count(), 2);
+
+        // unfinished writers leave nothing behind
+        let writer = LocalShuffleOutputWriter::try_new(data_file, index_file, 3, 3)?;
+        std::mem::drop(writer);
+        assert_eq!(std::fs::read_dir(dir.path())?.count(), 2);
+        assert!(commit_local_shuffle_output(data_file, index_file, 3).is_err());
+        Ok(())
+    }
+}
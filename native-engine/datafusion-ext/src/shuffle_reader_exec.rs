@@ -15,17 +15,18 @@
 use std::any::Any;
 use std::fmt::Debug;
 use std::fmt::Formatter;
-use std::io::ErrorKind::InvalidData;
 
-use std::io::{Cursor, Read};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Cursor, Read};
 use std::pin::Pin;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::task::Context;
 use std::task::Poll;
 
 use async_trait::async_trait;
 use datafusion::arrow::datatypes::SchemaRef;
-use datafusion::arrow::error::Result as ArrowResult;
+use datafusion::arrow::error::{ArrowError, Result as ArrowResult};
 use datafusion::arrow::ipc::reader::FileReader;
 use datafusion::arrow::record_batch::RecordBatch;
 use datafusion::error::{DataFusionError, Result};
@@ -42,21 +43,63 @@ use datafusion::physical_plan::RecordBatchStream;
 use datafusion::physical_plan::SendableRecordBatchStream;
 use datafusion::physical_plan::Statistics;
 use futures::Stream;
-use jni::objects::{GlobalRef, JObject};
+use jni::objects::{GlobalRef, JObject, JThrowable};
 use jni::sys::{jboolean, jint, jlong, JNI_TRUE};
+use memmap2::{Mmap, MmapOptions};
+use once_cell::sync::OnceCell;
 
+use crate::fault_injection::{corrupt_data, inject_fault, FaultPoint};
+use crate::jni_bridge::get_resource;
 use crate::jni_call;
 use crate::jni_call_static;
+use crate::jni_convert_byte_array;
 use crate::jni_delete_local_ref;
+use crate::jni_exception_check;
+use crate::jni_exception_clear;
+use crate::jni_exception_occurred;
+use crate::jni_get_array_length;
+use crate::jni_get_object_array_element;
 use crate::jni_new_direct_byte_buffer;
 use crate::jni_new_global_ref;
+use crate::jni_new_object;
 use crate::jni_new_string;
+use crate::jni_throw;
+use crate::native_conf::native_conf;
+use crate::shared_dictionary::decode_shared_dictionaries;
+use crate::shuffle_codec::SegmentDecompressor;
+use crate::topk_groups_exec::TopKThreshold;
+use crate::ResultExt;
+
+/// Kind of JVM objects the shuffle segments are read from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShuffleSegmentSource {
+    /// scala iterator of SeekableByteChannel, each channel holds exactly one
+    /// segment (blocks fetched by spark's block manager or external shuffle service)
+    SegmentChannels,
+    /// scala iterator of ReadableByteChannel, each channel is a stream of
+    /// concatenated segments (blocks fetched from remote shuffle service)
+    BlockStreams,
+    /// scala iterator of Tuple2(java.lang.Long, SeekableByteChannel), same as
+    /// SegmentChannels with upstream reduce partition id of each segment
+    PartitionedSegmentChannels,
+}
 
 #[derive(Debug, Clone)]
 pub struct ShuffleReaderExec {
     pub num_partitions: usize,
     pub native_shuffle_id: String,
     pub schema: SchemaRef,
+    pub segment_source: ShuffleSegmentSource,
+    /// ranges of upstream shuffle outputs read by an AQE shuffle read, all of
+    /// the same kind, in which case the resource is a scala function of the
+    /// range bounds as java.lang.Longs providing the segments of each range.
+    /// empty if the resource is a Function0 providing all segments.
+    pub read_ranges: Vec<ShuffleReadRange>,
+    /// estimated from map output sizes by the JVM side, default if unknown
+    pub statistics: Statistics,
+    /// sort keys and limit of a limited sort over the reader, rows of decoded
+    /// segments sorting after the first `limit` rows read so far are pruned
+    pub topk: Option<(Vec<PhysicalSortExpr>, usize)>,
     pub metrics: ExecutionPlanMetricsSet,
 }
 impl ShuffleReaderExec {
@@ -64,14 +107,49 @@ impl ShuffleReaderExec {
         num_partitions: usize,
         native_shuffle_id: String,
         schema: SchemaRef,
+        segment_source: ShuffleSegmentSource,
+        read_ranges: Vec<ShuffleReadRange>,
+        statistics: Statistics,
     ) -> ShuffleReaderExec {
         ShuffleReaderExec {
             num_partitions,
             native_shuffle_id,
             schema,
+            segment_source,
+            read_ranges,
+            statistics,
+            topk: None,
+            metrics: ExecutionPlanMetricsSet::new(),
+        }
+    }
+
+    /// returns the reader pruning rows not in the first `limit` rows sorted by
+    /// `keys`, see `topk_groups_exec::topk_shuffle_read()`
+    pub fn with_topk(&self, keys: Vec<PhysicalSortExpr>, limit: usize) -> Self {
+        ShuffleReaderExec {
+            topk: Some((keys, limit)),
             metrics: ExecutionPlanMetricsSet::new(),
+            ..self.clone()
+        }
+    }
+
+    /// returns the reader converted again for another task by the plan cache,
+    /// with the pruning of this reader, which is set by the conversion of its
+    /// parent instead of the reader node
+    pub fn rebind(&self, converted: &ShuffleReaderExec) -> Self {
+        ShuffleReaderExec {
+            topk: self.topk.clone(),
+            ..converted.clone()
         }
     }
+
+    fn read_range_segments(&self) -> Result<ReadRangeSegments> {
+        let segments_provider = get_resource(&self.native_shuffle_id)?;
+        Ok(ReadRangeSegments {
+            segments_provider: jni_new_global_ref!(segments_provider)?,
+            ranges: self.read_ranges.iter().cloned().collect(),
+        })
+    }
 }
 
 #[async_trait]
@@ -114,19 +192,30 @@ impl ExecutionPlan for ShuffleReaderExec {
         let elapsed_compute = baseline_metrics.elapsed_compute().clone();
         let _timer = elapsed_compute.timer();
 
-        let segments_provider = jni_call_static!(
-            JniBridge.getResource(
-                jni_new_string!(&self.native_shuffle_id)?
-            ) -> JObject
-        )?;
-        let segments = jni_new_global_ref!(
-            jni_call!(ScalaFunction0(segments_provider).apply() -> JObject)?
-        )?;
+        let segment_provider: Box<dyn ShuffleSegmentProvider> =
+            if self.read_ranges.is_empty() {
+                let segments_provider = get_resource(&self.native_shuffle_id)?;
+                let segments = jni_new_global_ref!(
+                    jni_call!(ScalaFunction0(segments_provider).apply() -> JObject)?
+                )?;
+                new_segment_provider(self.segment_source, segments)
+            } else {
+                Box::new(ReadRangesProvider {
+                    ranges: self.read_range_segments()?,
+                    segment_source: self.segment_source,
+                    current: None,
+                })
+            };
 
         let schema = self.schema.clone();
+        let topk = self
+            .topk
+            .as_ref()
+            .map(|(keys, limit)| TopKThreshold::new(keys.clone(), *limit, false));
         Ok(Box::pin(ShuffleReaderStream::new(
             schema,
-            segments,
+            segment_provider,
+            topk,
             baseline_metrics,
         )))
     }
@@ -140,14 +229,577 @@ impl ExecutionPlan for ShuffleReaderExec {
     }
 
     fn statistics(&self) -> Statistics {
-        Statistics::default()
+        self.statistics.clone()
+    }
+}
+
+impl ShuffleReaderExec {
+    /// Reads compressed segments without decoding, for forwarding them directly
+    /// to a shuffle writer with identical partitioning
+    pub fn read_partitioned_segments(
+        &self,
+    ) -> Result<PartitionedSegmentChannelsProvider> {
+        if self.segment_source != ShuffleSegmentSource::PartitionedSegmentChannels {
+            return Err(DataFusionError::Plan(format!(
+                "Blaze ShuffleReaderExec cannot read partitioned segments from {:?}",
+                self.segment_source
+            )));
+        }
+        if !self.read_ranges.is_empty() {
+            let mut ranges = self.read_range_segments()?;
+            let segments = ranges.next_range_segments()?.unwrap();
+            let mut provider = PartitionedSegmentChannelsProvider::new(segments);
+            provider.ranges = Some(ranges);
+            return Ok(provider);
+        }
+        let segments_provider = get_resource(&self.native_shuffle_id)?;
+        let segments = jni_new_global_ref!(
+            jni_call!(ScalaFunction0(segments_provider).apply() -> JObject)?
+        )?;
+        Ok(PartitionedSegmentChannelsProvider::new(segments))
+    }
+}
+
+/// Provides decompressed arrow IPC file data of each shuffle segment
+pub trait ShuffleSegmentProvider: Send {
+    fn next_segment(&mut self) -> Result<Option<Vec<u8>>>;
+}
+
+fn new_segment_provider(
+    segment_source: ShuffleSegmentSource,
+    segments: GlobalRef,
+) -> Box<dyn ShuffleSegmentProvider> {
+    match segment_source {
+        ShuffleSegmentSource::SegmentChannels => {
+            let conf = native_conf();
+            Box::new(SegmentChannelsProvider {
+                segments,
+                fetched: VecDeque::new(),
+                num_consumed: 0,
+                exhausted: false,
+                fetch_batch_size: conf.shuffle_fetch_batch_size,
+                fetch_batch_bytes: conf.shuffle_fetch_batch_bytes,
+                local_mmap: conf.shuffle_local_mmap,
+                mapped: None,
+                decompressor: SegmentDecompressor::default(),
+            })
+        }
+        ShuffleSegmentSource::PartitionedSegmentChannels => {
+            Box::new(PartitionedSegmentChannelsProvider::new(segments))
+        }
+        ShuffleSegmentSource::BlockStreams => Box::new(BlockStreamsProvider {
+            blocks: segments,
+            current_block: None,
+            decompressor: SegmentDecompressor::default(),
+        }),
+    }
+}
+
+/// Range of upstream shuffle outputs read by an AQE shuffle read
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShuffleReadRange {
+    /// reduce partitions [start, end) of all mappers, read by coalesced
+    /// shuffle reads. provided by a Function2 of (start, end).
+    Reduce(usize, usize),
+    /// outputs of mappers [start_map_index, end_map_index) of a single reduce
+    /// partition, read by splits of skewed partitions in skew joins. provided
+    /// by a Function3 of (reduce_id, start_map_index, end_map_index).
+    Map {
+        reduce_id: usize,
+        start_map_index: usize,
+        end_map_index: usize,
+    },
+}
+
+/// Segments of the ranges of an AQE shuffle read, taken from the JVM side one
+/// range at a time, so that segments of all ranges are read without merging
+/// them into a single JVM iterator.
+struct ReadRangeSegments {
+    segments_provider: GlobalRef,
+    ranges: VecDeque<ShuffleReadRange>,
+}
+
+impl ReadRangeSegments {
+    /// returns the segments of the next range, or None if all ranges are read
+    fn next_range_segments(&mut self) -> Result<Option<GlobalRef>> {
+        let provider = self.segments_provider.as_obj();
+        let segments = match self.ranges.pop_front() {
+            Some(ShuffleReadRange::Reduce(start_reduce_id, end_reduce_id)) => jni_call!(
+                ScalaFunction2(provider).apply(
+                    jni_new_object!(JavaLong, start_reduce_id as jlong)?,
+                    jni_new_object!(JavaLong, end_reduce_id as jlong)?,
+                ) -> JObject
+            )?,
+            Some(ShuffleReadRange::Map {
+                reduce_id,
+                start_map_index,
+                end_map_index,
+            }) => jni_call!(
+                ScalaFunction3(provider).apply(
+                    jni_new_object!(JavaLong, reduce_id as jlong)?,
+                    jni_new_object!(JavaLong, start_map_index as jlong)?,
+                    jni_new_object!(JavaLong, end_map_index as jlong)?,
+                ) -> JObject
+            )?,
+            None => return Ok(None),
+        };
+        Ok(Some(jni_new_global_ref!(segments)?))
+    }
+}
+
+/// Reads segments of each read range with a provider of the segment source
+struct ReadRangesProvider {
+    ranges: ReadRangeSegments,
+    segment_source: ShuffleSegmentSource,
+    current: Option<Box<dyn ShuffleSegmentProvider>>,
+}
+
+impl ShuffleSegmentProvider for ReadRangesProvider {
+    fn next_segment(&mut self) -> Result<Option<Vec<u8>>> {
+        loop {
+            if let Some(current) = &mut self.current {
+                if let Some(arrow_data) = current.next_segment()? {
+                    return Ok(Some(arrow_data));
+                }
+            }
+            // providers are dropped once exhausted, releasing their buffers
+            self.current = None;
+            match self.ranges.next_range_segments()? {
+                Some(segments) => {
+                    self.current =
+                        Some(new_segment_provider(self.segment_source, segments));
+                }
+                None => return Ok(None),
+            }
+        }
+    }
+}
+
+struct SegmentChannelsProvider {
+    segments: GlobalRef,
+    fetched: VecDeque<Vec<u8>>,
+    /// number of segments taken from the JVM iterator and decompressed
+    num_consumed: usize,
+    exhausted: bool,
+    fetch_batch_size: usize,
+    fetch_batch_bytes: usize,
+    local_mmap: bool,
+    mapped: Option<Mmap>,
+    decompressor: SegmentDecompressor,
+}
+
+impl SegmentChannelsProvider {
+    /// fetches records of next batch of segments in a single JNI call, segments
+    /// of the batch are read concurrently by JVM side
+    fn fetch_segments(&mut self) -> Result<()> {
+        let batch = jni_call_static!(
+            JniBridge.fetchSegments(
+                self.segments.as_obj(),
+                self.fetch_batch_size as jint,
+                self.fetch_batch_bytes as jlong,
+                self.local_mmap as jboolean,
+            ) -> JObject
+        )?;
+        let num_segments = jni_get_array_length!(batch.into_inner())?;
+        if num_segments == 0 {
+            self.exhausted = true;
+        }
+        for i in 0..num_segments {
+            let segment = jni_get_object_array_element!(batch.into_inner(), i)?;
+            self.fetched
+                .push_back(jni_convert_byte_array!(segment.into_inner())?);
+
+            // segment ref must be explicitly deleted to avoid OOM
+            jni_delete_local_ref!(segment)?;
+        }
+        jni_delete_local_ref!(batch)?;
+        Ok(())
+    }
+}
+
+impl ShuffleSegmentProvider for SegmentChannelsProvider {
+    fn next_segment(&mut self) -> Result<Option<Vec<u8>>> {
+        loop {
+            if self.fetched.is_empty() && !self.exhausted {
+                let ordinal = self.num_consumed;
+                self.fetch_segments().map_err(|err| {
+                    throw_shuffle_read_exception(&self.segments, Some(ordinal), err)
+                })?;
+            }
+            match self.fetched.pop_front() {
+                Some(record) => {
+                    let ordinal = self.num_consumed;
+                    self.num_consumed += 1;
+
+                    // dictionary segments only carry the zstd dictionary
+                    let segments = &self.segments;
+                    let decompressed = resolve_segment_record(&record, &mut self.mapped)
+                        .and_then(|zdata| self.decompressor.decompress_segment(zdata))
+                        .map_err(|err| {
+                            throw_shuffle_read_exception(segments, Some(ordinal), err)
+                        })?;
+                    if let Some(arrow_data) = decompressed {
+                        return Ok(Some(arrow_data));
+                    }
+                }
+                None => return Ok(None),
+            }
+        }
+    }
+}
+
+pub struct PartitionedSegmentChannelsProvider {
+    segments: GlobalRef,
+    num_consumed: usize,
+    buffer: SegmentBuffer,
+    local_mmap: bool,
+    mapped: Option<Mmap>,
+    decompressor: SegmentDecompressor,
+    /// remaining ranges of an AQE shuffle read, whose segments are read after
+    /// the current ones
+    ranges: Option<ReadRangeSegments>,
+}
+
+impl PartitionedSegmentChannelsProvider {
+    fn new(segments: GlobalRef) -> Self {
+        Self {
+            segments,
+            num_consumed: 0,
+            buffer: SegmentBuffer::take_pooled(),
+            local_mmap: native_conf().shuffle_local_mmap,
+            mapped: None,
+            decompressor: SegmentDecompressor::default(),
+            ranges: None,
+        }
+    }
+
+    /// returns the upstream partition id and compressed data of next segment
+    pub fn next_compressed_segment(&mut self) -> Result<Option<(usize, &[u8])>> {
+        if let Some(ranges) = &mut self.ranges {
+            // moves on to the next range once the current segments are
+            // exhausted, ordinals of segments are counted in each range
+            while jni_call!(ScalaIterator(self.segments.as_obj()).hasNext() -> jboolean)?
+                != JNI_TRUE
+            {
+                match ranges.next_range_segments()? {
+                    Some(segments) => {
+                        self.segments = segments;
+                        self.num_consumed = 0;
+                    }
+                    None => return Ok(None),
+                }
+            }
+        }
+        let next = take_partitioned_segment(
+            &self.segments,
+            self.num_consumed,
+            &mut self.buffer,
+            self.local_mmap,
+            &mut self.mapped,
+        )?;
+        if next.is_some() {
+            self.num_consumed += 1;
+        }
+        Ok(next)
+    }
+}
+
+impl ShuffleSegmentProvider for PartitionedSegmentChannelsProvider {
+    fn next_segment(&mut self) -> Result<Option<Vec<u8>>> {
+        loop {
+            let ordinal = self.num_consumed;
+            let segments = &self.segments;
+            let next = take_partitioned_segment(
+                segments,
+                ordinal,
+                &mut self.buffer,
+                self.local_mmap,
+                &mut self.mapped,
+            )?;
+            let zdata = match next {
+                Some((_, zdata)) => zdata,
+                None => return Ok(None),
+            };
+            self.num_consumed += 1;
+
+            let decompressed =
+                self.decompressor.decompress_segment(zdata).map_err(|err| {
+                    throw_shuffle_read_exception(segments, Some(ordinal), err)
+                })?;
+            if let Some(arrow_data) = decompressed {
+                return Ok(Some(arrow_data));
+            }
+        }
+    }
+}
+
+impl Drop for PartitionedSegmentChannelsProvider {
+    fn drop(&mut self) {
+        std::mem::take(&mut self.buffer).release();
+    }
+}
+
+/// reads the segment at `ordinal` of the iterator of partitioned segments,
+/// returns its upstream partition id and compressed data
+fn take_partitioned_segment<'a>(
+    segments: &GlobalRef,
+    ordinal: usize,
+    buffer: &'a mut SegmentBuffer,
+    local_mmap: bool,
+    mapped: &'a mut Option<Mmap>,
+) -> Result<Option<(usize, &'a [u8])>> {
+    let next = match buffer.read_next_segment(segments, local_mmap) {
+        Ok(Some(record)) if record.len() >= 8 => {
+            // each record is prefixed by the 8-byte partition id
+            let partition_id = u64::from_le_bytes(record[..8].try_into().unwrap());
+            resolve_segment_record(&record[8..], mapped)
+                .map(|zdata| Some((partition_id as usize, zdata)))
+        }
+        Ok(Some(record)) => Err(DataFusionError::Execution(format!(
+            "partitioned shuffle segment record of {} bytes is malformed",
+            record.len()
+        ))),
+        Ok(None) => Ok(None),
+        Err(err) => Err(err),
+    };
+    next.map_err(|err| throw_shuffle_read_exception(segments, Some(ordinal), err))
+}
+
+/// kinds of segment records returned by JniBridge.fetchSegments() and
+/// JniBridge.nextSegmentFully(), see resolve_segment_record()
+const SEGMENT_RECORD_DATA: u8 = 0;
+const SEGMENT_RECORD_LOCAL_FILE: u8 = 1;
+
+/// resolves a segment record into compressed segment data. data records carry
+/// the data read by JVM side, while local file records carry the offset, length
+/// and path of the segment in a local shuffle file, which is memory-mapped
+/// directly instead of being read through JVM channels.
+fn resolve_segment_record<'a>(
+    record: &'a [u8],
+    mapped: &'a mut Option<Mmap>,
+) -> Result<&'a [u8]> {
+    match record.first() {
+        Some(&SEGMENT_RECORD_DATA) => Ok(&record[1..]),
+        Some(&SEGMENT_RECORD_LOCAL_FILE) if record.len() >= 17 => {
+            let offset = u64::from_le_bytes(record[1..9].try_into().unwrap());
+            let len = u64::from_le_bytes(record[9..17].try_into().unwrap()) as usize;
+            let path = std::str::from_utf8(&record[17..]).map_err(|err| {
+                DataFusionError::Execution(format!(
+                    "malformed path of local shuffle segment: {}",
+                    err
+                ))
+            })?;
+            if len == 0 {
+                return Ok(&[]);
+            }
+            let file = File::open(path)?;
+            let mmap = unsafe {
+                // safety: shuffle files are not modified once committed
+                MmapOptions::new().offset(offset).len(len).map(&file)?
+            };
+            Ok(&mapped.insert(mmap)[..])
+        }
+        _ => Err(DataFusionError::Execution(format!(
+            "malformed shuffle segment record of {} bytes",
+            record.len()
+        ))),
+    }
+}
+
+const SEGMENT_BUFFER_MIN_CAPACITY: usize = 1 << 20;
+const SEGMENT_BUFFER_MAX_POOLED: usize = 16;
+
+/// Direct buffer each shuffle segment is read into by a single JNI call of
+/// JniBridge.nextSegmentFully(). buffers are grown when a segment does not
+/// fit, and pooled for shuffle reads of subsequent tasks.
+#[derive(Default)]
+struct SegmentBuffer {
+    data: Vec<u8>,
+    jbuffer: Option<GlobalRef>,
+}
+
+fn segment_buffer_pool() -> &'static Mutex<Vec<SegmentBuffer>> {
+    static POOL: OnceCell<Mutex<Vec<SegmentBuffer>>> = OnceCell::new();
+    POOL.get_or_init(|| Mutex::new(vec![]))
+}
+
+impl SegmentBuffer {
+    fn take_pooled() -> Self {
+        segment_buffer_pool()
+            .lock()
+            .unwrap()
+            .pop()
+            .unwrap_or_default()
+    }
+
+    fn release(self) {
+        let mut pool = segment_buffer_pool().lock().unwrap();
+        if self.jbuffer.is_some() && pool.len() < SEGMENT_BUFFER_MAX_POOLED {
+            pool.push(self);
+        }
+    }
+
+    fn reserve(&mut self, capacity: usize) -> Result<()> {
+        if self.jbuffer.is_some() && self.data.len() >= capacity {
+            return Ok(());
+        }
+        let capacity = capacity
+            .max(SEGMENT_BUFFER_MIN_CAPACITY)
+            .next_power_of_two();
+        let mut data = vec![0u8; capacity];
+        let jbuffer = jni_new_direct_byte_buffer!(&mut data[..])?;
+        let jbuffer_ref = jni_new_global_ref!(jbuffer.into())?;
+        jni_delete_local_ref!(jbuffer.into())?;
+
+        // the old direct buffer must be released before its memory
+        self.jbuffer = Some(jbuffer_ref);
+        self.data = data;
+        Ok(())
+    }
+
+    /// reads the record of the next segment of the iterator into the buffer,
+    /// returns None if the iterator is exhausted
+    fn read_next_segment(
+        &mut self,
+        segments: &GlobalRef,
+        local_mmap: bool,
+    ) -> Result<Option<&[u8]>> {
+        self.reserve(0)?;
+        loop {
+            let jbuffer = self.jbuffer.as_ref().unwrap().as_obj();
+            let len = jni_call_static!(
+                JniBridge.nextSegmentFully(
+                    segments.as_obj(),
+                    jbuffer,
+                    local_mmap as jboolean,
+                ) -> jlong
+            )?;
+            match len {
+                -1 => return Ok(None),
+                len if len >= 0 => return Ok(Some(&self.data[..len as usize])),
+                len => self.reserve((-2 - len) as usize)?, // segment kept by JVM side
+            }
+        }
+    }
+}
+
+/// makes the error of reading the segment at `ordinal` of the JVM iterator a
+/// pending BlazeShuffleReadException, caused by the pending java exception if
+/// any. JVM side converts it into a fetch failure of the map output the segment
+/// belongs to, so that spark retries the map stage instead of the task.
+fn throw_shuffle_read_exception(
+    segments: &GlobalRef,
+    ordinal: Option<usize>,
+    err: DataFusionError,
+) -> DataFusionError {
+    let throw = || -> Result<()> {
+        let cause = if jni_exception_check!()? {
+            let cause: JObject = jni_exception_occurred!()?.into();
+            jni_exception_clear!()?;
+            cause
+        } else {
+            JObject::null()
+        };
+        let e = jni_new_object!(
+            BlazeShuffleReadException,
+            jni_new_string!(err.to_string())?,
+            segments.as_obj(),
+            ordinal.map(|ordinal| ordinal as jlong).unwrap_or(-1),
+            cause
+        )?;
+        jni_throw!(JThrowable::from(e))?;
+        Ok(())
+    };
+    log::error!("error reading shuffle segment #{:?}: {}", ordinal, err);
+    if let Err(throw_err) = throw() {
+        log::error!("error throwing BlazeShuffleReadException: {}", throw_err);
+    }
+    err
+}
+
+struct BlockStreamsProvider {
+    blocks: GlobalRef,
+    current_block: Option<BufReader<ReadableByteChannelReader>>,
+    decompressor: SegmentDecompressor,
+}
+
+impl ShuffleSegmentProvider for BlockStreamsProvider {
+    fn next_segment(&mut self) -> Result<Option<Vec<u8>>> {
+        loop {
+            if let Some(block) = &mut self.current_block {
+                // segments of remote shuffle services are not identified
+                let blocks = &self.blocks;
+                let next = read_block_segment(block, &mut self.decompressor)
+                    .map_err(|err| throw_shuffle_read_exception(blocks, None, err))?;
+                match next {
+                    Some(Some(arrow_data)) => return Ok(Some(arrow_data)),
+                    Some(None) => continue, // dictionary segment
+                    None => self.current_block = None,
+                }
+            }
+
+            if jni_call!(
+                ScalaIterator(self.blocks.as_obj()).hasNext() -> jboolean
+            )? != JNI_TRUE
+            {
+                return Ok(None);
+            }
+            let channel =
+                jni_call!(ScalaIterator(self.blocks.as_obj()).next() -> JObject)?;
+            self.current_block = Some(BufReader::new(ReadableByteChannelReader(
+                jni_new_global_ref!(channel)?,
+            )));
+
+            // channel ref must be explicitly deleted to avoid OOM
+            jni_delete_local_ref!(channel)?;
+        }
+    }
+}
+
+/// reads the next segment of the block, returns None at the end of block and
+/// Some(None) for dictionary segments
+fn read_block_segment<R: BufRead>(
+    block: &mut R,
+    decompressor: &mut SegmentDecompressor,
+) -> Result<Option<Option<Vec<u8>>>> {
+    if block.fill_buf()?.is_empty() {
+        return Ok(None);
+    }
+    // each segment is followed by its length
+    let arrow_data = decompressor.read_segment(block)?;
+
+    let mut segment_length_trailer = [0u8; 8];
+    block.read_exact(&mut segment_length_trailer)?;
+    Ok(Some(arrow_data))
+}
+
+struct ReadableByteChannelReader(GlobalRef);
+
+impl Read for ReadableByteChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read_bytes = jni_call!(
+            JavaReadableByteChannel(self.0.as_obj()).read(
+                jni_new_direct_byte_buffer!(buf).to_io_result()?
+            ) -> jint
+        )
+        .to_io_result()?;
+
+        // ReadableByteChannel returns -1 on EOF
+        Ok(read_bytes.max(0) as usize)
+    }
+}
+
+impl Drop for ReadableByteChannelReader {
+    fn drop(&mut self) {
+        let _ = jni_call!(JavaReadableByteChannel(self.0.as_obj()).close() -> ());
     }
 }
 
 struct ShuffleReaderStream {
     schema: SchemaRef,
-    segments: GlobalRef,
+    segment_provider: Box<dyn ShuffleSegmentProvider>,
     arrow_file_reader: Option<FileReader<Cursor<Vec<u8>>>>,
+    topk: Option<TopKThreshold>,
     baseline_metrics: BaselineMetrics,
 }
 unsafe impl Sync for ShuffleReaderStream {} // safety: segments is safe to be shared
@@ -157,57 +809,34 @@ unsafe impl Send for ShuffleReaderStream {}
 impl ShuffleReaderStream {
     pub fn new(
         schema: SchemaRef,
-        segments: GlobalRef,
+        segment_provider: Box<dyn ShuffleSegmentProvider>,
+        topk: Option<TopKThreshold>,
         baseline_metrics: BaselineMetrics,
     ) -> ShuffleReaderStream {
         ShuffleReaderStream {
             schema,
-            segments,
+            segment_provider,
             arrow_file_reader: None,
+            topk,
             baseline_metrics,
         }
     }
 
     fn next_segment(&mut self) -> Result<bool> {
-        if jni_call!(
-            ScalaIterator(self.segments.as_obj()).hasNext() -> jboolean
-        )? != JNI_TRUE
-        {
-            self.arrow_file_reader = None;
-            return Ok(false);
-        }
-
-        let channel = jni_call!(ScalaIterator(self.segments.as_obj()).next() -> JObject)?;
-        let len = jni_call!(JavaSeekableByteChannel(channel).size() -> jlong)? as u64;
-
-        // read compressed data
-        let mut zdata = vec![0; len as usize];
-        let mut zdata_read_bytes = 0;
-        while zdata_read_bytes < len as usize {
-            let buf = jni_new_direct_byte_buffer!(&mut zdata[zdata_read_bytes..])?;
-            let read_bytes = jni_call!(
-                JavaSeekableByteChannel(channel).read(buf) -> jint
-            )?;
-            if read_bytes < 0 {
-                return Err(DataFusionError::IoError(std::io::Error::new(
-                    InvalidData,
-                    "unexpected EOF",
-                )));
+        match self.segment_provider.next_segment()? {
+            Some(mut arrow_data) => {
+                if inject_fault(FaultPoint::ShuffleRead)? {
+                    corrupt_data(&mut arrow_data);
+                }
+                self.arrow_file_reader =
+                    Some(FileReader::try_new(Cursor::new(arrow_data), None)?);
+                Ok(true)
+            }
+            None => {
+                self.arrow_file_reader = None;
+                Ok(false)
             }
-            zdata_read_bytes += read_bytes as usize;
         }
-
-        // decompress one segment of IPC into memory
-        let mut arrow_data = vec![];
-        let mut zreader = zstd::stream::Decoder::new(&zdata[..])?;
-        zreader.read_to_end(&mut arrow_data)?;
-
-        self.arrow_file_reader =
-            Some(FileReader::try_new(Cursor::new(arrow_data), None)?);
-
-        // channel ref must be explicitly deleted to avoid OOM
-        jni_delete_local_ref!(channel)?;
-        Ok(true)
     }
 }
 
@@ -216,24 +845,36 @@ impl Stream for ShuffleReaderStream {
 
     fn poll_next(
         mut self: Pin<&mut Self>,
-        cx: &mut Context<'_>,
+        _cx: &mut Context<'_>,
     ) -> Poll<Option<Self::Item>> {
         let elapsed_compute = self.baseline_metrics.elapsed_compute().clone();
         let _timer = elapsed_compute.timer();
 
-        if let Some(arrow_file_reader) = &mut self.arrow_file_reader {
-            if let Some(record_batch) = arrow_file_reader.next() {
-                return self
-                    .baseline_metrics
-                    .record_poll(Poll::Ready(Some(record_batch)));
+        loop {
+            if let Some(arrow_file_reader) = &mut self.arrow_file_reader {
+                if let Some(record_batch) = arrow_file_reader.next() {
+                    let record_batch = record_batch.and_then(|batch| {
+                        decode_shared_dictionaries(batch, &self.schema)
+                    });
+                    let record_batch = match (record_batch, &mut self.topk) {
+                        (Ok(batch), Some(topk)) => match topk.prune(batch) {
+                            Ok(batch) if batch.num_rows() == 0 => continue,
+                            Ok(batch) => Ok(batch),
+                            Err(err) => Err(ArrowError::ExternalError(Box::new(err))),
+                        },
+                        (record_batch, _) => record_batch,
+                    };
+                    return self
+                        .baseline_metrics
+                        .record_poll(Poll::Ready(Some(record_batch)));
+                }
             }
-        }
 
-        // current arrow file reader reaches EOF, try next ipc
-        if self.next_segment()? {
-            return self.poll_next(cx);
+            // current arrow file reader reaches EOF, try next ipc
+            if !self.next_segment()? {
+                return Poll::Ready(None);
+            }
         }
-        Poll::Ready(None)
     }
 }
 impl RecordBatchStream for ShuffleReaderStream {
@@ -0,0 +1,71 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Pushes shuffle write metrics to spark during execution
+
+use datafusion::error::Result;
+use jni::objects::GlobalRef;
+use jni::sys::jlong;
+
+use crate::jni_bridge::get_resource;
+use crate::jni_call;
+use crate::jni_new_global_ref;
+
+/// Deltas of shuffle write metrics since the last push
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ShuffleWriteMetricsUpdate {
+    pub bytes_written: usize,
+    pub records_written: usize,
+    pub write_time_ns: usize,
+    pub memory_bytes_spilled: usize,
+    pub disk_bytes_spilled: usize,
+    /// current memory used by the shuffle writer, peak memory of the task is
+    /// maintained by JVM side
+    pub mem_used: usize,
+}
+
+/// Updates spark's ShuffleWriteMetrics and task-level spill metrics through a
+/// JVM-side `NativeShuffleWriteMetrics` registered in JniBridge.resourcesMap,
+/// so that progress of native shuffle writes is visible in spark UI before
+/// the task completes.
+pub struct SparkShuffleWriteMetrics {
+    updater: Option<GlobalRef>,
+}
+
+impl SparkShuffleWriteMetrics {
+    /// metrics are not pushed if `resource_id` is empty
+    pub fn try_new(resource_id: &str) -> Result<Self> {
+        if resource_id.is_empty() {
+            return Ok(Self { updater: None });
+        }
+        let updater = get_resource(resource_id)?;
+        Ok(Self {
+            updater: Some(jni_new_global_ref!(updater)?),
+        })
+    }
+
+    pub fn push(&self, update: ShuffleWriteMetricsUpdate) -> Result<()> {
+        if let Some(updater) = &self.updater {
+            jni_call!(BlazeNativeShuffleWriteMetrics(updater.as_obj()).update(
+                update.bytes_written as jlong,
+                update.records_written as jlong,
+                update.write_time_ns as jlong,
+                update.memory_bytes_spilled as jlong,
+                update.disk_bytes_spilled as jlong,
+                update.mem_used as jlong,
+            ) -> ())?;
+        }
+        Ok(())
+    }
+}
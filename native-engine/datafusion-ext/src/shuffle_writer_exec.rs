@@ -26,13 +26,12 @@ use std::io::SeekFrom;
 use std::io::Write;
 use std::path::Path;
 use std::sync::Arc;
+use std::time::Instant;
 
 use async_trait::async_trait;
 use datafusion::arrow::array::*;
 use datafusion::arrow::compute::take;
-use datafusion::arrow::datatypes::DataType;
 use datafusion::arrow::datatypes::SchemaRef;
-use datafusion::arrow::datatypes::TimeUnit;
 use datafusion::arrow::error::ArrowError;
 use datafusion::arrow::ipc::writer::FileWriter;
 use datafusion::arrow::record_batch::RecordBatch;
@@ -58,114 +57,130 @@ use datafusion::physical_plan::SendableRecordBatchStream;
 use datafusion::physical_plan::Statistics;
 use futures::lock::Mutex;
 use futures::{StreamExt, TryFutureExt, TryStreamExt};
-use tempfile::NamedTempFile;
+use jni::sys::jlong;
 use tokio::task;
 
-use crate::batch_buffer::MutableRecordBatch;
+use crate::jni_call_static;
+use crate::native_conf::native_conf;
+use crate::shared_dictionary::{encode_shared_dictionaries, unify_dictionaries};
+use crate::shuffle_codec::ShuffleCodecSelector;
+use crate::shuffle_output_writer::ShuffleWriterOutput;
+use crate::shuffle_reader_exec::{PartitionedSegmentChannelsProvider, ShuffleReaderExec};
+use crate::shuffle_write_metrics::{ShuffleWriteMetricsUpdate, SparkShuffleWriteMetrics};
 use crate::spark_hash::{create_hashes, pmod};
-
+use crate::spark_memory::SparkMemoryReservation;
+use crate::spill_manager::{SpillFile, SpillManager};
+use crate::split_oversized_batches_exec::SplitOversizedBatchesExec;
+use crate::task_cancellation::TaskCancellation;
+
+/// Rows buffered for one output partition. rows are staged as indices into the
+/// input batches and gathered only once a full batch is staged, so appending
+/// small runs of rows never copies the rows buffered before them.
+///
+/// staged input batches are kept alive until gathered by all partitions
+/// staging them, so their memory is counted until then, along with the memory
+/// of gathered batches.
 #[derive(Default)]
 struct PartitionBuffer {
     frozen: Vec<RecordBatch>,
-    active: Option<MutableRecordBatch>,
+    staging: Vec<(Arc<RecordBatch>, Vec<usize>)>,
+    num_staging_rows: usize,
 }
 
 impl PartitionBuffer {
-    fn output_all(&mut self) -> Result<Vec<RecordBatch>> {
-        let mut output: Vec<RecordBatch> = vec![];
-        output.append(&mut self.frozen);
-        if let Some(mut mutable) = self.active.take() {
-            let result = mutable.output_and_reset()?;
-            output.push(result);
-            self.active = Some(mutable);
+    /// stages rows of the input, returns bytes of the gathered batch and of the
+    /// released input batches if the staged rows are gathered
+    fn append(
+        &mut self,
+        input: &Arc<RecordBatch>,
+        indices: Vec<usize>,
+        batch_size: usize,
+    ) -> Result<(usize, usize)> {
+        self.num_staging_rows += indices.len();
+        self.staging.push((input.clone(), indices));
+        if self.num_staging_rows >= batch_size {
+            return self.flush();
         }
-        Ok(output)
+        Ok((0, 0))
     }
 
-    fn output_clean(&mut self) -> Result<Vec<RecordBatch>> {
-        let mut output: Vec<RecordBatch> = vec![];
-        output.append(&mut self.frozen);
-        if let Some(mut mutable) = self.active.take() {
-            let result = mutable.output()?;
-            output.push(result);
+    /// gathers the staged rows, returns bytes of the gathered batch and of the
+    /// input batches no longer staged by any partition
+    fn flush(&mut self) -> Result<(usize, usize)> {
+        if self.staging.is_empty() {
+            return Ok((0, 0));
         }
-        Ok(output)
+        let staging = std::mem::take(&mut self.staging);
+        let num_rows = std::mem::take(&mut self.num_staging_rows);
+        let gathered = interleave_staging(&staging, num_rows)?;
+        let gathered_bytes = batch_byte_size(&gathered);
+        self.frozen.push(gathered);
+
+        let released_bytes = staging
+            .into_iter()
+            .filter_map(|(input, _)| Arc::try_unwrap(input).ok())
+            .map(|input| batch_byte_size(&input))
+            .sum();
+        Ok((gathered_bytes, released_bytes))
+    }
+
+    fn output_all(&mut self) -> Result<Vec<RecordBatch>> {
+        self.flush()?;
+        Ok(std::mem::take(&mut self.frozen))
     }
 }
 
-struct SpillInfo {
-    file: NamedTempFile,
-    offsets: Vec<u64>,
+/// gathers the staged rows into one batch, every output column is extended
+/// directly from the input arrays by runs of consecutive row indices
+fn interleave_staging(
+    staging: &[(Arc<RecordBatch>, Vec<usize>)],
+    num_rows: usize,
+) -> Result<RecordBatch> {
+    let schema = staging[0].0.schema();
+    let columns = (0..schema.fields().len())
+        .map(|col| {
+            let arrays = staging
+                .iter()
+                .map(|(batch, _)| batch.column(col).data())
+                .collect::<Vec<_>>();
+            let mut mutable = MutableArrayData::new(arrays, false, num_rows);
+            for (array_idx, (_, indices)) in staging.iter().enumerate() {
+                let mut start = 0;
+                while start < indices.len() {
+                    let mut end = start + 1;
+                    while end < indices.len() && indices[end] == indices[end - 1] + 1 {
+                        end += 1;
+                    }
+                    mutable.extend(array_idx, indices[start], indices[end - 1] + 1);
+                    start = end;
+                }
+            }
+            make_array(mutable.freeze())
+        })
+        .collect::<Vec<ArrayRef>>();
+    Ok(RecordBatch::try_new(schema, columns)?)
 }
 
-macro_rules! append {
-    ($TO:ty, $FROM:ty, $to: ident, $from: ident) => {{
-        let to = $to.as_any_mut().downcast_mut::<$TO>().unwrap();
-        let from = $from.as_any().downcast_ref::<$FROM>().unwrap();
-        for i in from.into_iter() {
-            to.append_option(i)?;
-        }
-    }};
+/// A batch of rows sorted by their output partition id, used in sort-based
+/// shuffle instead of keeping one open buffer for every output partition.
+/// rows of partition `i` are in `offsets[i]..offsets[i + 1]`.
+struct PartitionSortedBatch {
+    batch: RecordBatch,
+    offsets: Vec<usize>,
 }
 
-fn append_column(
-    to: &mut Box<dyn ArrayBuilder>,
-    from: &Arc<dyn Array>,
-    data_type: &DataType,
-) -> Result<()> {
-    // output buffered start `buffered_idx`, len `rows_to_output`
-    match data_type {
-        DataType::Null => unimplemented!(),
-        DataType::Boolean => append!(BooleanBuilder, BooleanArray, to, from),
-        DataType::Int8 => append!(Int8Builder, Int8Array, to, from),
-        DataType::Int16 => append!(Int16Builder, Int16Array, to, from),
-        DataType::Int32 => append!(Int32Builder, Int32Array, to, from),
-        DataType::Int64 => append!(Int64Builder, Int64Array, to, from),
-        DataType::UInt8 => append!(UInt8Builder, UInt8Array, to, from),
-        DataType::UInt16 => append!(UInt16Builder, UInt16Array, to, from),
-        DataType::UInt32 => append!(UInt32Builder, UInt32Array, to, from),
-        DataType::UInt64 => append!(UInt64Builder, UInt64Array, to, from),
-        DataType::Float32 => append!(Float32Builder, Float32Array, to, from),
-        DataType::Float64 => append!(Float64Builder, Float64Array, to, from),
-        DataType::Date32 => append!(Date32Builder, Date32Array, to, from),
-        DataType::Date64 => append!(Date64Builder, Date64Array, to, from),
-        DataType::Time32(TimeUnit::Second) => {
-            append!(Time32SecondBuilder, Time32SecondArray, to, from)
-        }
-        DataType::Time32(TimeUnit::Millisecond) => {
-            append!(Time32MillisecondBuilder, Time32SecondArray, to, from)
-        }
-        DataType::Time64(TimeUnit::Microsecond) => {
-            append!(Time64MicrosecondBuilder, Time64MicrosecondArray, to, from)
-        }
-        DataType::Time64(TimeUnit::Nanosecond) => {
-            append!(Time64NanosecondBuilder, Time64NanosecondArray, to, from)
-        }
-        DataType::Utf8 => append!(StringBuilder, StringArray, to, from),
-        DataType::LargeUtf8 => append!(LargeStringBuilder, LargeStringArray, to, from),
-        DataType::Decimal(_precision, _scale) => {
-            let decimal_builder =
-                to.as_any_mut().downcast_mut::<DecimalBuilder>().unwrap();
-            let decimal_array = from.as_any().downcast_ref::<DecimalArray>().unwrap();
-            for i in 0..decimal_array.len() {
-                if decimal_array.is_valid(i) {
-                    decimal_builder.append_value(decimal_array.value(i))?;
-                } else {
-                    decimal_builder.append_null()?;
-                }
-            }
-        }
-        _ => todo!(),
-    }
-    Ok(())
+struct SpillInfo {
+    file: SpillFile,
+    offsets: Vec<u64>,
 }
 
 struct ShuffleRepartitioner {
     id: MemoryConsumerId,
-    output_data_file: String,
-    output_index_file: String,
+    output: ShuffleWriterOutput,
     schema: SchemaRef,
     buffered_partitions: Mutex<Vec<PartitionBuffer>>,
+    sorted_batches: Mutex<Vec<PartitionSortedBatch>>,
+    sort_based: bool,
     spills: Mutex<Vec<SpillInfo>>,
     /// Sort expressions
     /// Partitioning scheme to use
@@ -174,37 +189,50 @@ struct ShuffleRepartitioner {
     runtime: Arc<RuntimeEnv>,
     metrics: BaselineMetrics,
     batch_size: usize,
+    spark_memory: Option<SparkMemoryReservation>,
+    write_metrics: Arc<SparkShuffleWriteMetrics>,
+    codec: Arc<ShuffleCodecSelector>,
 }
 
 impl ShuffleRepartitioner {
     #[allow(clippy::too_many_arguments)]
     pub fn new(
         partition_id: usize,
-        output_data_file: String,
-        output_index_file: String,
+        output: ShuffleWriterOutput,
         schema: SchemaRef,
         partitioning: Partitioning,
         metrics: BaselineMetrics,
         runtime: Arc<RuntimeEnv>,
         batch_size: usize,
+        spark_memory: Option<SparkMemoryReservation>,
+        write_metrics: Arc<SparkShuffleWriteMetrics>,
+        codec: Arc<ShuffleCodecSelector>,
     ) -> Self {
         let num_output_partitions = partitioning.partition_count();
+        let sort_based =
+            num_output_partitions >= native_conf().shuffle_sort_based_min_partitions;
         Self {
             id: MemoryConsumerId::new(partition_id),
-            output_data_file,
-            output_index_file,
+            output,
             schema,
-            buffered_partitions: Mutex::new(
+            buffered_partitions: Mutex::new(if sort_based {
+                vec![]
+            } else {
                 (0..num_output_partitions)
                     .map(|_| Default::default())
-                    .collect::<Vec<_>>(),
-            ),
+                    .collect::<Vec<_>>()
+            }),
+            sorted_batches: Mutex::new(vec![]),
+            sort_based,
             spills: Mutex::new(vec![]),
             partitioning,
             num_output_partitions,
             runtime,
             metrics,
             batch_size,
+            spark_memory,
+            write_metrics,
+            codec,
         }
     }
 
@@ -220,8 +248,16 @@ impl ShuffleRepartitioner {
         // as we encountered in this batch, thus the memory consumption is `rough`.
         let size = batch_byte_size(&input);
         self.try_grow(size).await?;
+        self.acquire_spark_memory(size).await?;
         self.metrics.mem_used().add(size);
 
+        // records are counted once buffered, like spark's sort-based shuffle writer
+        self.write_metrics.push(ShuffleWriteMetricsUpdate {
+            records_written: input.num_rows(),
+            mem_used: self.used(),
+            ..Default::default()
+        })?;
+
         let num_output_partitions = self.num_output_partitions;
         match &self.partitioning {
             Partitioning::Hash(exprs, _) => {
@@ -234,60 +270,46 @@ impl ShuffleRepartitioner {
                 hashes_buf.resize(arrays[0].len(), 42);
                 // Hash arrays and compute buckets based on number of partitions
                 let hashes = create_hashes(&arrays, hashes_buf)?;
+                if self.sort_based {
+                    let partition_ids = hashes
+                        .iter()
+                        .map(|hash| pmod(*hash, num_output_partitions))
+                        .collect::<Vec<_>>();
+                    let sorted = sort_by_partition_id(
+                        &input,
+                        &partition_ids,
+                        num_output_partitions,
+                    )?;
+                    self.sorted_batches.lock().await.push(sorted);
+                    return Ok(());
+                }
+
                 let mut indices = vec![vec![]; num_output_partitions];
                 for (index, hash) in hashes.iter().enumerate() {
-                    indices[pmod(*hash, num_output_partitions)].push(index as u64)
+                    indices[pmod(*hash, num_output_partitions)].push(index)
                 }
 
+                let input = Arc::new(input);
+                let (mut gathered_bytes, mut released_bytes) = (0, 0);
+                let mut buffered_partitions = self.buffered_partitions.lock().await;
                 for (num_output_partition, partition_indices) in indices
                     .into_iter()
                     .enumerate()
                     .filter(|(_, indices)| !indices.is_empty())
                 {
-                    let mut buffered_partitions = self.buffered_partitions.lock().await;
-                    let output = &mut buffered_partitions[num_output_partition];
-                    let indices = UInt64Array::from_slice(&partition_indices);
-                    // Produce batches based on indices
-                    let columns = input
-                        .columns()
-                        .iter()
-                        .map(|c| {
-                            take(c.as_ref(), &indices, None)
-                                .map_err(|e| DataFusionError::Execution(e.to_string()))
-                        })
-                        .collect::<Result<Vec<Arc<dyn Array>>>>()?;
-
-                    if partition_indices.len() > self.batch_size {
-                        let output_batch =
-                            RecordBatch::try_new(input.schema().clone(), columns)?;
-                        output.frozen.push(output_batch);
-                    } else {
-                        if output.active.is_none() {
-                            let buffer = MutableRecordBatch::new(
-                                self.batch_size,
-                                self.schema.clone(),
-                            );
-                            output.active = Some(buffer);
-                        };
-
-                        let mut batch = output.active.take().unwrap();
-                        batch
-                            .arrays
-                            .iter_mut()
-                            .zip(columns.iter())
-                            .zip(self.schema.fields().iter().map(|f| f.data_type()))
-                            .for_each(|((to, from), dt)| {
-                                append_column(to, from, dt).unwrap()
-                            });
-                        batch.append(partition_indices.len());
-
-                        if batch.is_full() {
-                            let result = batch.output_and_reset()?;
-                            output.frozen.push(result);
-                        }
-                        output.active = Some(batch);
-                    }
+                    let (gathered, released) = buffered_partitions[num_output_partition]
+                        .append(&input, partition_indices, self.batch_size)?;
+                    gathered_bytes += gathered;
+                    released_bytes += released;
+                }
+                drop(buffered_partitions);
+
+                // the input is released if all its rows are already gathered
+                if Arc::try_unwrap(input).is_ok() {
+                    released_bytes += size;
                 }
+                self.update_gathered_memory(gathered_bytes, released_bytes)
+                    .await?;
             }
             other => {
                 // this should be unreachable as long as the validation logic
@@ -304,59 +326,122 @@ impl ShuffleRepartitioner {
     async fn shuffle_write(&self) -> Result<SendableRecordBatchStream> {
         let _timer = self.metrics.elapsed_compute().timer();
         let num_output_partitions = self.num_output_partitions;
-        let mut buffered_partitions = self.buffered_partitions.lock().await;
-        let mut output_batches: Vec<Vec<RecordBatch>> =
-            vec![vec![]; num_output_partitions];
+        let output_batches = if self.sort_based {
+            let mut sorted_batches = self.sorted_batches.lock().await;
+            output_sorted_batches(
+                &mut sorted_batches,
+                &self.schema,
+                num_output_partitions,
+            )?
+        } else {
+            let mut buffered_partitions = self.buffered_partitions.lock().await;
+            let mut output_batches: Vec<Vec<RecordBatch>> =
+                vec![vec![]; num_output_partitions];
 
-        for i in 0..num_output_partitions {
-            let partition_batches = buffered_partitions[i].output_clean()?;
-            output_batches[i] = partition_batches;
-        }
+            for i in 0..num_output_partitions {
+                let partition_batches = buffered_partitions[i].output_all()?;
+                output_batches[i] = partition_batches;
+            }
+            output_batches
+        };
 
         let mut spills = self.spills.lock().await;
         let output_spills = spills.drain(..).collect::<Vec<_>>();
 
-        let data_file = self.output_data_file.clone();
-        let index_file = self.output_index_file.clone();
+        let output = self.output.clone();
         let input_schema = self.schema.clone();
+        let write_metrics = self.write_metrics.clone();
+        let codec = self.codec.clone();
+        let spill_mmap = native_conf().spill_mmap;
 
         std::mem::drop(_timer);
         let elapsed_compute = self.metrics.elapsed_compute().clone();
+        let cancellation = TaskCancellation::current();
+        let task_attempt_id =
+            jni_call_static!(JniBridge.getTaskAttemptId() -> jlong)? as i64;
 
         task::spawn_blocking(move || {
             let _timer = elapsed_compute.timer();
-            let mut offsets = vec![0; num_output_partitions + 1];
-            let mut output_data = File::create(data_file)?;
+            let mut output_writer =
+                output.create_writer(num_output_partitions, task_attempt_id)?;
+
+            // spills are mapped once instead of opened for every partition, their
+            // pages are loaded lazily and left to the OS page cache
+            let mapped_spills = if spill_mmap {
+                output_spills
+                    .iter()
+                    .map(|spill| spill.file.map().map(Some))
+                    .collect::<Result<Vec<_>>>()?
+            } else {
+                output_spills.iter().map(|_| None).collect()
+            };
 
             for i in 0..num_output_partitions {
-                offsets[i] = output_data.seek(SeekFrom::Current(0))?;
+                cancellation.check()?;
+                let mut update = ShuffleWriteMetricsUpdate::default();
                 let in_mem_batches = &output_batches[i];
+                let mut block = vec![];
                 if in_mem_batches.iter().any(|batch| batch.num_rows() > 0) {
                     write_compressed_ipc(
                         input_schema.clone(),
                         in_mem_batches,
-                        &mut output_data,
+                        &mut block,
+                        &codec,
                     )?;
                 }
 
+                // blocks are fetched independently, so each one carries the
+                // dictionary ahead of its segments
+                let has_spilled_data = output_spills
+                    .iter()
+                    .any(|spill| spill.offsets[i + 1] > spill.offsets[i]);
+                if !block.is_empty() || has_spilled_data {
+                    if let Some(dictionary_segment) = codec.dictionary_segment() {
+                        let start_time = Instant::now();
+                        output_writer.write_block(i, &mut &dictionary_segment[..])?;
+                        update.write_time_ns += start_time.elapsed().as_nanos() as usize;
+                        update.bytes_written += dictionary_segment.len();
+                    }
+                }
+
+                if !block.is_empty() {
+                    let start_time = Instant::now();
+                    output_writer.write_block(i, &mut &block[..])?;
+                    update.write_time_ns += start_time.elapsed().as_nanos() as usize;
+                    update.bytes_written += block.len();
+                }
+
                 // append partition in each spills
-                for spill in &output_spills {
+                for (spill, mapped) in output_spills.iter().zip(&mapped_spills) {
                     let length = spill.offsets[i + 1] - spill.offsets[i];
                     if length > 0 {
-                        let mut spill_file = File::open(&spill.file.path())?;
-                        spill_file.seek(SeekFrom::Start(spill.offsets[i]))?;
-                        std::io::copy(&mut spill_file.take(length), &mut output_data)?;
-                        output_data.flush()?;
+                        let start_time = Instant::now();
+                        if let Some(mapped) = mapped {
+                            let start = spill.offsets[i] as usize;
+                            let mut block = &mapped[start..start + length as usize];
+                            output_writer.write_block(i, &mut block)?;
+                        } else {
+                            let mut spill_file = File::open(&spill.file.path())?;
+                            spill_file.seek(SeekFrom::Start(spill.offsets[i]))?;
+                            output_writer.write_block(i, &mut spill_file.take(length))?;
+                        }
+                        update.write_time_ns += start_time.elapsed().as_nanos() as usize;
+                        update.bytes_written += length as usize;
                     }
                 }
+
+                // push after each partition so that progress is visible in spark UI
+                if update.bytes_written > 0 {
+                    write_metrics.push(update)?;
+                }
             }
-            // add one extra offset at last to ease partition length computation
-            offsets[num_output_partitions] = output_data.seek(SeekFrom::Current(0))?;
-            let mut output_index = File::create(index_file)?;
-            for offset in offsets {
-                output_index.write_all(&(offset as i64).to_le_bytes()[..])?;
-            }
-            output_index.flush()?;
+
+            let start_time = Instant::now();
+            output_writer.finish()?;
+            write_metrics.push(ShuffleWriteMetricsUpdate {
+                write_time_ns: start_time.elapsed().as_nanos() as usize,
+                ..Default::default()
+            })?;
             Ok::<(), DataFusionError>(())
         })
         .await
@@ -366,6 +451,9 @@ impl ShuffleRepartitioner {
 
         let used = self.metrics.mem_used().set(0);
         self.shrink(used);
+        if let Some(spark_memory) = &self.spark_memory {
+            spark_memory.release_all()?;
+        }
 
         // shuffle writer always has empty output
         Ok(Box::pin(MemoryStream::try_new(
@@ -375,6 +463,50 @@ impl ShuffleRepartitioner {
         )?))
     }
 
+    /// counts memory of batches gathered from staged rows, and releases memory of
+    /// input batches no longer staged. gathered batches are counted before
+    /// being reserved, so that a spill triggered by the reservation frees them.
+    async fn update_gathered_memory(
+        &self,
+        gathered_bytes: usize,
+        released_bytes: usize,
+    ) -> Result<()> {
+        if gathered_bytes > released_bytes {
+            let size = gathered_bytes - released_bytes;
+            self.metrics.mem_used().add(size);
+            self.try_grow(size).await?;
+            self.acquire_spark_memory(size).await?;
+        } else if released_bytes > gathered_bytes {
+            let size = released_bytes - gathered_bytes;
+            self.metrics.mem_used().sub(size);
+            self.shrink(size);
+        }
+        Ok(())
+    }
+
+    /// acquires memory from spark's TaskMemoryManager, spills buffered data
+    /// if it is not fully granted or spark has requested a spill
+    async fn acquire_spark_memory(&self, size: usize) -> Result<()> {
+        let spark_memory = match &self.spark_memory {
+            Some(spark_memory) => spark_memory,
+            None => return Ok(()),
+        };
+        if spark_memory.acquire(size)? {
+            return Ok(());
+        }
+
+        let freed = self.spill().await?;
+        self.shrink(freed);
+        spark_memory.record_spill();
+
+        // all buffered data is spilled, only memory of the incoming batch is needed
+        spark_memory.release_all()?;
+        if !spark_memory.acquire(size)? {
+            spark_memory.report_overcommit(size)?;
+        }
+        Ok(())
+    }
+
     fn used(&self) -> usize {
         self.metrics.mem_used().value()
     }
@@ -388,30 +520,109 @@ impl ShuffleRepartitioner {
     }
 }
 
-/// consume the `buffered_partitions` and do spill into a single temp shuffle output file
-async fn spill_into(
+/// reorder rows of `input` so that rows of the same output partition are contiguous,
+/// using a counting sort since partition ids are dense and bounded
+fn sort_by_partition_id(
+    input: &RecordBatch,
+    partition_ids: &[usize],
+    num_output_partitions: usize,
+) -> Result<PartitionSortedBatch> {
+    let mut offsets = vec![0usize; num_output_partitions + 1];
+    for &partition_id in partition_ids {
+        offsets[partition_id + 1] += 1;
+    }
+    for i in 0..num_output_partitions {
+        offsets[i + 1] += offsets[i];
+    }
+
+    let mut positions = offsets[..num_output_partitions].to_vec();
+    let mut sorted_indices = vec![0u64; partition_ids.len()];
+    for (index, &partition_id) in partition_ids.iter().enumerate() {
+        sorted_indices[positions[partition_id]] = index as u64;
+        positions[partition_id] += 1;
+    }
+
+    let indices = UInt64Array::from_slice(&sorted_indices);
+    let columns = input
+        .columns()
+        .iter()
+        .map(|c| {
+            take(c.as_ref(), &indices, None)
+                .map_err(|e| DataFusionError::Execution(e.to_string()))
+        })
+        .collect::<Result<Vec<Arc<dyn Array>>>>()?;
+    Ok(PartitionSortedBatch {
+        batch: RecordBatch::try_new(input.schema(), columns)?,
+        offsets,
+    })
+}
+
+/// consume the `sorted_batches` and collect rows of each output partition into one batch
+fn output_sorted_batches(
+    sorted_batches: &mut Vec<PartitionSortedBatch>,
+    schema: &SchemaRef,
+    num_output_partitions: usize,
+) -> Result<Vec<Vec<RecordBatch>>> {
+    let sorted_batches = std::mem::take(sorted_batches);
+    (0..num_output_partitions)
+        .map(|i| {
+            let slices = sorted_batches
+                .iter()
+                .filter(|sorted| sorted.offsets[i + 1] > sorted.offsets[i])
+                .map(|sorted| {
+                    let len = sorted.offsets[i + 1] - sorted.offsets[i];
+                    sorted.batch.slice(sorted.offsets[i], len)
+                })
+                .collect::<Vec<_>>();
+            if slices.is_empty() {
+                return Ok(vec![]);
+            }
+            // concat copies the slices, so the ipc writer never sees sliced arrays
+            Ok(vec![RecordBatch::concat(schema, &slices)?])
+        })
+        .collect()
+}
+
+/// consume the `buffered_partitions` and collect batches of each output partition
+fn output_buffered_partitions(
     buffered_partitions: &mut [PartitionBuffer],
-    schema: SchemaRef,
-    path: &Path,
     num_output_partitions: usize,
-) -> Result<Vec<u64>> {
+) -> Result<Vec<Vec<RecordBatch>>> {
     let mut output_batches: Vec<Vec<RecordBatch>> = vec![vec![]; num_output_partitions];
 
     for i in 0..num_output_partitions {
         let partition_batches = buffered_partitions[i].output_all()?;
         output_batches[i] = partition_batches;
     }
+    Ok(output_batches)
+}
+
+/// consume the `output_batches` and do spill into a single temp shuffle output file
+async fn spill_into(
+    output_batches: Vec<Vec<RecordBatch>>,
+    schema: SchemaRef,
+    path: &Path,
+    num_output_partitions: usize,
+    codec: Arc<ShuffleCodecSelector>,
+) -> Result<Vec<u64>> {
     let path = path.to_owned();
+    let cancellation = TaskCancellation::current();
 
     let res = task::spawn_blocking(move || {
         let mut offsets = vec![0; num_output_partitions + 1];
         let mut spill_data = OpenOptions::new().read(true).append(true).open(path)?;
 
         for i in 0..num_output_partitions {
+            cancellation.check()?;
             offsets[i] = spill_data.seek(SeekFrom::Current(0))?;
             let partition_batches = &output_batches[i];
             if partition_batches.iter().any(|batch| batch.num_rows() > 0) {
-                write_compressed_ipc(schema.clone(), partition_batches, &mut spill_data)?;
+                write_compressed_ipc(
+                    schema.clone(),
+                    partition_batches,
+                    &mut spill_data,
+                    &codec,
+                )?;
             }
         }
         // add one extra offset at last to ease partition length computation
@@ -464,21 +675,40 @@ impl MemoryConsumer for ShuffleRepartitioner {
             self.spill_count()
         );
 
-        let mut buffered_partitions = self.buffered_partitions.lock().await;
-        // we could always get a chance to free some memory as long as we are holding some
-        if buffered_partitions.len() == 0 {
-            return Ok(0);
-        }
+        let output_batches = if self.sort_based {
+            let mut sorted_batches = self.sorted_batches.lock().await;
+            if sorted_batches.is_empty() {
+                return Ok(0);
+            }
+            output_sorted_batches(
+                &mut sorted_batches,
+                &self.schema,
+                self.num_output_partitions,
+            )?
+        } else {
+            let mut buffered_partitions = self.buffered_partitions.lock().await;
+            // we could always get a chance to free some memory as long as we are holding some
+            if buffered_partitions.len() == 0 {
+                return Ok(0);
+            }
+            output_buffered_partitions(
+                &mut buffered_partitions,
+                self.num_output_partitions,
+            )?
+        };
 
-        let spillfile = self.runtime.disk_manager.create_tmp_file()?;
+        let spillfile = SpillManager::get().create_spill_file()?;
         let offsets = spill_into(
-            &mut *buffered_partitions,
+            output_batches,
             self.schema.clone(),
             spillfile.path(),
             self.num_output_partitions,
+            self.codec.clone(),
         )
         .await?;
 
+        let disk_bytes_spilled = offsets[self.num_output_partitions] as usize;
+
         let mut spills = self.spills.lock().await;
         let freed = self.metrics.mem_used().set(0);
         self.metrics.record_spill(freed);
@@ -486,6 +716,11 @@ impl MemoryConsumer for ShuffleRepartitioner {
             file: spillfile,
             offsets,
         });
+        self.write_metrics.push(ShuffleWriteMetricsUpdate {
+            memory_bytes_spilled: freed,
+            disk_bytes_spilled,
+            ..Default::default()
+        })?;
         Ok(freed)
     }
 
@@ -508,10 +743,16 @@ pub struct ShuffleWriterExec {
     input: Arc<dyn ExecutionPlan>,
     /// Partitioning scheme to use
     partitioning: Partitioning,
-    /// Output data file path
-    output_data_file: String,
-    /// Output index file path
-    output_index_file: String,
+    /// Output destination of partitioned data
+    output: ShuffleWriterOutput,
+    /// Forward compressed segments of the input shuffle reader without decoding,
+    /// only valid if input is already partitioned identically
+    segment_passthrough: bool,
+    /// Resource id of the JVM-side updater of spark's shuffle write metrics,
+    /// metrics are only reported on completion if empty
+    write_metrics_resource_id: String,
+    /// Writers of the same stage share the adaptive codec decision
+    codec_stage_key: String,
     /// Containing all metrics set created during sort
     all_metrics: CompositeMetricsSet,
 }
@@ -548,8 +789,10 @@ impl ExecutionPlan for ShuffleWriterExec {
             1 => Ok(Arc::new(ShuffleWriterExec::try_new(
                 children[0].clone(),
                 self.partitioning.clone(),
-                self.output_data_file.clone(),
-                self.output_index_file.clone(),
+                self.output.clone(),
+                self.segment_passthrough,
+                self.write_metrics_resource_id.clone(),
+                self.codec_stage_key.clone(),
             )?)),
             _ => Err(DataFusionError::Internal(
                 "RepartitionExec wrong number of children".to_string(),
@@ -562,8 +805,52 @@ impl ExecutionPlan for ShuffleWriterExec {
         partition: usize,
         context: Arc<TaskContext>,
     ) -> Result<SendableRecordBatchStream> {
-        let input = self.input.execute(partition, context.clone())?;
         let metrics = self.all_metrics.new_intermediate_baseline(partition);
+        let write_metrics = Arc::new(SparkShuffleWriteMetrics::try_new(
+            &self.write_metrics_resource_id,
+        )?);
+
+        if self.segment_passthrough {
+            // the reader may be wrapped to split its batches, which are not
+            // decoded by passthrough
+            let input = match self
+                .input
+                .as_any()
+                .downcast_ref::<SplitOversizedBatchesExec>()
+            {
+                Some(split) => split.input(),
+                None => &self.input,
+            };
+            let shuffle_reader = input
+                .as_any()
+                .downcast_ref::<ShuffleReaderExec>()
+                .ok_or_else(|| {
+                    DataFusionError::Plan(
+                        "ShuffleWriterExec: segment passthrough requires ShuffleReaderExec input"
+                            .to_owned(),
+                    )
+                })?;
+            let segments = shuffle_reader.read_partitioned_segments()?;
+
+            return Ok(Box::pin(RecordBatchStreamAdapter::new(
+                self.schema(),
+                futures::stream::once(
+                    passthrough_shuffle(
+                        segments,
+                        self.schema(),
+                        self.output.clone(),
+                        self.partitioning.partition_count(),
+                        metrics,
+                        write_metrics,
+                        context,
+                    )
+                    .map_err(|e| ArrowError::ExternalError(Box::new(e))),
+                )
+                .try_flatten(),
+            )));
+        }
+
+        let input = self.input.execute(partition, context.clone())?;
 
         Ok(Box::pin(RecordBatchStreamAdapter::new(
             self.schema(),
@@ -571,10 +858,11 @@ impl ExecutionPlan for ShuffleWriterExec {
                 external_shuffle(
                     input,
                     partition,
-                    self.output_data_file.clone(),
-                    self.output_index_file.clone(),
+                    self.output.clone(),
                     self.partitioning.clone(),
                     metrics,
+                    write_metrics,
+                    Arc::new(ShuffleCodecSelector::new(&self.codec_stage_key)),
                     context,
                 )
                 .map_err(|e| ArrowError::ExternalError(Box::new(e))),
@@ -594,7 +882,11 @@ impl ExecutionPlan for ShuffleWriterExec {
     ) -> std::fmt::Result {
         match t {
             DisplayFormatType::Default => {
-                write!(f, "ShuffleWriterExec: partitioning={:?}", self.partitioning)
+                write!(
+                    f,
+                    "ShuffleWriterExec: partitioning={:?}, segment_passthrough={}",
+                    self.partitioning, self.segment_passthrough
+                )
             }
         }
     }
@@ -609,39 +901,66 @@ impl ShuffleWriterExec {
     pub fn try_new(
         input: Arc<dyn ExecutionPlan>,
         partitioning: Partitioning,
-        output_data_file: String,
-        output_index_file: String,
+        output: ShuffleWriterOutput,
+        segment_passthrough: bool,
+        write_metrics_resource_id: String,
+        codec_stage_key: String,
     ) -> Result<Self> {
         Ok(ShuffleWriterExec {
             input,
             partitioning,
             all_metrics: CompositeMetricsSet::new(),
-            output_data_file,
-            output_index_file,
+            output,
+            segment_passthrough,
+            write_metrics_resource_id,
+            codec_stage_key,
         })
     }
+
+    /// creates a writer of the same stage for another task, writing the new
+    /// input into another output
+    pub fn with_new_output(
+        &self,
+        input: Arc<dyn ExecutionPlan>,
+        output: ShuffleWriterOutput,
+        write_metrics_resource_id: String,
+    ) -> Result<Self> {
+        Self::try_new(
+            input,
+            self.partitioning.clone(),
+            output,
+            self.segment_passthrough,
+            write_metrics_resource_id,
+            self.codec_stage_key.clone(),
+        )
+    }
 }
 
 // TODO: reconsider memory consumption for shuffle buffers, unrevealed usage?
 pub async fn external_shuffle(
     mut input: SendableRecordBatchStream,
     partition_id: usize,
-    output_data_file: String,
-    output_index_file: String,
+    output: ShuffleWriterOutput,
     partitioning: Partitioning,
     metrics: BaselineMetrics,
+    write_metrics: Arc<SparkShuffleWriteMetrics>,
+    codec: Arc<ShuffleCodecSelector>,
     context: Arc<TaskContext>,
 ) -> Result<SendableRecordBatchStream> {
     let schema = input.schema();
+    let spark_memory_name = format!("ShuffleRepartitioner[{}]", partition_id);
+    let spark_memory = SparkMemoryReservation::try_new(spark_memory_name)?;
     let repartitioner = ShuffleRepartitioner::new(
         partition_id,
-        output_data_file,
-        output_index_file,
+        output,
         schema.clone(),
         partitioning,
         metrics,
         context.runtime_env(),
         context.session_config().batch_size,
+        spark_memory,
+        write_metrics,
+        codec,
     );
     context.runtime_env().register_requester(repartitioner.id());
 
@@ -653,29 +972,166 @@ pub async fn external_shuffle(
     repartitioner.shuffle_write().await
 }
 
-fn write_compressed_ipc(
+/// forward compressed segments to output partitions of the same id. segments come in
+/// fetching order, so they are staged in a temp file and written out in ascending
+/// order of partition id.
+pub async fn passthrough_shuffle(
+    mut segments: PartitionedSegmentChannelsProvider,
+    schema: SchemaRef,
+    output: ShuffleWriterOutput,
+    num_output_partitions: usize,
+    metrics: BaselineMetrics,
+    write_metrics: Arc<SparkShuffleWriteMetrics>,
+    context: Arc<TaskContext>,
+) -> Result<SendableRecordBatchStream> {
+    let mut staging_file = SpillManager::get().create_spill_file()?;
+    let elapsed_compute = metrics.elapsed_compute().clone();
+    let cancellation = TaskCancellation::current();
+    let task_attempt_id = jni_call_static!(JniBridge.getTaskAttemptId() -> jlong)? as i64;
+
+    task::spawn_blocking(move || {
+        let _timer = elapsed_compute.timer();
+        let staging = staging_file.as_file_mut();
+        let mut staged_segments = vec![];
+        let mut offset = 0;
+
+        while let Some((partition_id, zdata)) = segments.next_compressed_segment()? {
+            cancellation.check()?;
+            if partition_id >= num_output_partitions {
+                return Err(DataFusionError::Execution(format!(
+                    "passthrough segment of partition {} exceeds {} output partitions",
+                    partition_id, num_output_partitions
+                )));
+            }
+            // keep the same segment layout as write_compressed_ipc
+            staging.write_all(zdata)?;
+            staging.write_all(&(zdata.len() as u64).to_le_bytes()[..])?;
+            let len = zdata.len() as u64 + 8;
+            staged_segments.push((partition_id, offset, len));
+            offset += len;
+        }
+        staged_segments.sort_by_key(|&(partition_id, _, _)| partition_id);
+
+        let staged_bytes = offset as usize;
+        let start_time = Instant::now();
+        let mut output_writer =
+            output.create_writer(num_output_partitions, task_attempt_id)?;
+        for (partition_id, offset, len) in staged_segments {
+            staging.seek(SeekFrom::Start(offset))?;
+            output_writer.write_block(partition_id, &mut (&mut *staging).take(len))?;
+        }
+        output_writer.finish()?;
+
+        // number of records is unknown since segments are not decoded
+        write_metrics.push(ShuffleWriteMetricsUpdate {
+            bytes_written: staged_bytes,
+            write_time_ns: start_time.elapsed().as_nanos() as usize,
+            ..Default::default()
+        })?;
+        Ok::<(), DataFusionError>(())
+    })
+    .await
+    .map_err(|e| {
+        DataFusionError::Execution(format!("shuffle passthrough error: {:?}", e))
+    })??;
+
+    // shuffle writer always has empty output
+    Ok(Box::pin(MemoryStream::try_new(vec![], schema, None)?))
+}
+
+fn write_compressed_ipc<W: Write>(
     schema: SchemaRef,
     batches: &[RecordBatch],
-    output: &mut File,
+    output: &mut W,
+    codec: &ShuffleCodecSelector,
 ) -> Result<()> {
-    let start = output.seek(SeekFrom::Current(0))?;
-
-    let mut arrow_writer = FileWriter::try_new(
-        zstd::Encoder::new(output.try_clone()?, 1)?,
-        schema.as_ref(),
-    )?;
-    for batch in batches {
+    let (schema, batches) = unify_dictionaries(schema, batches)?;
+    let (schema, batches) = encode_shared_dictionaries(schema, &batches)?;
+    let mut arrow_writer = FileWriter::try_new(vec![], schema.as_ref())?;
+    for batch in &batches {
         if batch.num_rows() > 0 {
             arrow_writer.write(batch)?;
         }
     }
     arrow_writer.finish()?;
-    let mut zwriter = arrow_writer.into_inner()?;
-    zwriter.flush()?;
-    zwriter.finish()?;
+    let ipc_data = arrow_writer.into_inner()?;
 
-    let ipc_length = output.seek(SeekFrom::Current(0))? - start;
-    output.write_all(&ipc_length.to_le_bytes()[..])?;
+    codec.write_segment(&ipc_data, output)?;
     output.flush()?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use datafusion::arrow::datatypes::{DataType, Field, Schema};
+
+    use super::*;
+    use crate::test_util::build_batch;
+
+    #[test]
+    fn test_partition_buffer() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("i", DataType::Int32, true),
+            Field::new("s", DataType::Utf8, true),
+        ]));
+        let batch1 = Arc::new(build_batch!(
+            schema,
+            vec![Some(0), Some(1), None, Some(3)],
+            vec![Some("a"), None, Some("c"), Some("d")],
+        )?);
+        let batch2 = Arc::new(build_batch!(
+            schema,
+            vec![Some(4), Some(5)],
+            vec![Some("e"), Some("f")]
+        )?);
+
+        let mut buffer = PartitionBuffer::default();
+        buffer.append(&batch1, vec![1, 2], 4)?;
+        assert!(buffer.frozen.is_empty());
+        buffer.append(&batch2, vec![1], 4)?;
+        buffer.append(&batch1, vec![0, 3], 4)?;
+        assert_eq!(buffer.frozen.len(), 1);
+        assert_eq!(buffer.num_staging_rows, 0);
+        buffer.append(&batch2, vec![0], 4)?;
+
+        let output = buffer.output_all()?;
+        assert_eq!(output.len(), 2);
+        assert_eq!(
+            output[0],
+            build_batch!(
+                schema,
+                vec![Some(1), None, Some(5), Some(0), Some(3)],
+                vec![None, Some("c"), Some("f"), Some("a"), Some("d")],
+            )?
+        );
+        assert_eq!(
+            output[1],
+            build_batch!(schema, vec![Some(4)], vec![Some("e")])?
+        );
+        assert!(buffer.output_all()?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_partition_buffer_memory() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![Field::new("i", DataType::Int32, true)]));
+        let input = build_batch!(schema, vec![Some(0), Some(1), None, Some(3)])?;
+        let input_bytes = batch_byte_size(&input);
+        let input = Arc::new(input);
+
+        // the input is kept alive by the rows staged in `first`
+        let mut first = PartitionBuffer::default();
+        let mut second = PartitionBuffer::default();
+        assert_eq!(first.append(&input, vec![0, 1], 4)?, (0, 0));
+        let (gathered, released) = second.append(&input, vec![2, 3], 2)?;
+        assert_eq!(gathered, batch_byte_size(&second.frozen[0]));
+        assert_eq!(released, 0);
+
+        // released once gathered by all partitions staging it
+        drop(input);
+        let (gathered, released) = first.flush()?;
+        assert_eq!(gathered, batch_byte_size(&first.frozen[0]));
+        assert_eq!(released, input_bytes);
+        Ok(())
+    }
+}
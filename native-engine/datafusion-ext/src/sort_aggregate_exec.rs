@@ -0,0 +1,486 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Defines a streaming aggregation of inputs sorted by the grouping keys, see
+//! spark's SortAggregateExec
+//!
+//! rows of the same grouping keys are adjacent in sorted inputs, so only the
+//! accumulators of the current group are kept, and a group is output as soon
+//! as rows of the next group arrive. unlike hash aggregations, memory usage
+//! does not grow with the number of groups and nothing is spilled, e.g. when
+//! aggregating outputs of sort-merge joins on the grouping keys.
+
+use std::any::Any;
+use std::fmt::Formatter;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use datafusion::arrow::array::ArrayRef;
+use datafusion::arrow::compute::{cast, lexicographical_partition_ranges, SortColumn};
+use datafusion::arrow::datatypes::{Field, Schema, SchemaRef};
+use datafusion::arrow::error::ArrowError;
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::execution::context::TaskContext;
+use datafusion::physical_expr::PhysicalSortExpr;
+use datafusion::physical_plan::aggregates::AggregateMode;
+use datafusion::physical_plan::expressions::Column;
+use datafusion::physical_plan::metrics::{
+    BaselineMetrics, ExecutionPlanMetricsSet, MetricsSet,
+};
+use datafusion::physical_plan::stream::RecordBatchStreamAdapter;
+use datafusion::physical_plan::{
+    Accumulator, AggregateExpr, DisplayFormatType, ExecutionPlan, Partitioning,
+    PhysicalExpr, SendableRecordBatchStream, Statistics,
+};
+use datafusion::scalar::ScalarValue;
+use futures::{StreamExt, TryStreamExt};
+
+use crate::split_oversized_batches_exec::OutputBatchBudget;
+
+/// Aggregates an input sorted by the grouping keys, outputting the same
+/// schema as AggregateExec of the same mode.
+#[derive(Debug)]
+pub struct SortAggregateExec {
+    mode: AggregateMode,
+    group_expr: Vec<(Arc<dyn PhysicalExpr>, String)>,
+    aggr_expr: Vec<Arc<dyn AggregateExpr>>,
+    input: Arc<dyn ExecutionPlan>,
+    schema: SchemaRef,
+    metrics: ExecutionPlanMetricsSet,
+}
+
+impl SortAggregateExec {
+    pub fn try_new(
+        mode: AggregateMode,
+        group_expr: Vec<(Arc<dyn PhysicalExpr>, String)>,
+        aggr_expr: Vec<Arc<dyn AggregateExpr>>,
+        input: Arc<dyn ExecutionPlan>,
+    ) -> Result<Self> {
+        if group_expr.is_empty() {
+            return Err(DataFusionError::Plan(
+                "SortAggregateExec expects at least one grouping key".to_string(),
+            ));
+        }
+        let input_schema = input.schema();
+        let mut fields = group_expr
+            .iter()
+            .map(|(expr, name)| {
+                Ok(Field::new(
+                    name,
+                    expr.data_type(&input_schema)?,
+                    expr.nullable(&input_schema)?,
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        for expr in &aggr_expr {
+            match mode {
+                AggregateMode::Partial => fields.extend(expr.state_fields()?),
+                AggregateMode::Final | AggregateMode::FinalPartitioned => {
+                    fields.push(expr.field()?)
+                }
+            }
+        }
+
+        Ok(Self {
+            mode,
+            group_expr,
+            aggr_expr,
+            input,
+            schema: Arc::new(Schema::new(fields)),
+            metrics: ExecutionPlanMetricsSet::new(),
+        })
+    }
+
+    /// input exprs of each aggregation, which are the state columns following
+    /// the grouping keys in final modes
+    fn aggr_inputs(&self) -> Result<Vec<Vec<Arc<dyn PhysicalExpr>>>> {
+        if let AggregateMode::Partial = self.mode {
+            let inputs = self.aggr_expr.iter().map(|expr| expr.expressions());
+            return Ok(inputs.collect());
+        }
+        let mut col_idx = self.group_expr.len();
+        self.aggr_expr
+            .iter()
+            .map(|expr| {
+                Ok(expr
+                    .state_fields()?
+                    .iter()
+                    .map(|field| {
+                        col_idx += 1;
+                        Arc::new(Column::new(field.name(), col_idx - 1))
+                            as Arc<dyn PhysicalExpr>
+                    })
+                    .collect())
+            })
+            .collect()
+    }
+}
+
+#[async_trait]
+impl ExecutionPlan for SortAggregateExec {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+
+    fn output_partitioning(&self) -> Partitioning {
+        self.input.output_partitioning()
+    }
+
+    fn output_ordering(&self) -> Option<&[PhysicalSortExpr]> {
+        None
+    }
+
+    fn children(&self) -> Vec<Arc<dyn ExecutionPlan>> {
+        vec![self.input.clone()]
+    }
+
+    fn with_new_children(
+        self: Arc<Self>,
+        children: Vec<Arc<dyn ExecutionPlan>>,
+    ) -> Result<Arc<dyn ExecutionPlan>> {
+        if children.len() != 1 {
+            return Err(DataFusionError::Plan(
+                "SortAggregateExec expects one children".to_string(),
+            ));
+        }
+        Ok(Arc::new(SortAggregateExec::try_new(
+            self.mode,
+            self.group_expr.clone(),
+            self.aggr_expr.clone(),
+            children[0].clone(),
+        )?))
+    }
+
+    fn execute(
+        &self,
+        partition: usize,
+        context: Arc<TaskContext>,
+    ) -> Result<SendableRecordBatchStream> {
+        let aggregator = SortAggregator {
+            mode: self.mode,
+            group_expr: self
+                .group_expr
+                .iter()
+                .map(|(expr, _)| expr.clone())
+                .collect(),
+            aggr_expr: self.aggr_expr.clone(),
+            aggr_inputs: self.aggr_inputs()?,
+            schema: self.schema.clone(),
+            budget: OutputBatchBudget::new(context.session_config().batch_size),
+            current: None,
+            staged: vec![vec![]; self.schema.fields().len()],
+            num_staged_rows: 0,
+            num_staged_bytes: 0,
+            baseline_metrics: BaselineMetrics::new(&self.metrics, partition),
+        };
+        let state = SortAggregateState {
+            aggregator,
+            input: self.input.execute(partition, context)?,
+            finished: false,
+        };
+
+        let output = futures::stream::try_unfold(state, |mut state| async move {
+            while !state.finished {
+                match state.input.next().await {
+                    Some(batch) => {
+                        if let Some(output) = state.aggregator.aggregate_batch(&batch?)? {
+                            return Ok(Some((output, state)));
+                        }
+                    }
+                    None => {
+                        state.finished = true;
+                        if let Some(output) = state.aggregator.finish()? {
+                            return Ok(Some((output, state)));
+                        }
+                    }
+                }
+            }
+            Ok::<_, DataFusionError>(None)
+        })
+        .map_err(|err| ArrowError::ExternalError(Box::new(err)));
+        Ok(Box::pin(RecordBatchStreamAdapter::new(
+            self.schema(),
+            output,
+        )))
+    }
+
+    fn metrics(&self) -> Option<MetricsSet> {
+        Some(self.metrics.clone_inner())
+    }
+
+    fn fmt_as(&self, t: DisplayFormatType, f: &mut Formatter) -> std::fmt::Result {
+        match t {
+            DisplayFormatType::Default => {
+                let group_names = self
+                    .group_expr
+                    .iter()
+                    .map(|(_, name)| name.as_str())
+                    .collect::<Vec<_>>();
+                let aggr_names = self
+                    .aggr_expr
+                    .iter()
+                    .map(|expr| expr.name())
+                    .collect::<Vec<_>>();
+                write!(
+                    f,
+                    "SortAggregateExec: mode={:?}, gby={:?}, aggr={:?}",
+                    self.mode, group_names, aggr_names
+                )
+            }
+        }
+    }
+
+    fn statistics(&self) -> Statistics {
+        Statistics::default()
+    }
+}
+
+struct SortAggregateState {
+    aggregator: SortAggregator,
+    input: SendableRecordBatchStream,
+    finished: bool,
+}
+
+struct SortAggregator {
+    mode: AggregateMode,
+    group_expr: Vec<Arc<dyn PhysicalExpr>>,
+    aggr_expr: Vec<Arc<dyn AggregateExpr>>,
+    aggr_inputs: Vec<Vec<Arc<dyn PhysicalExpr>>>,
+    schema: SchemaRef,
+    budget: OutputBatchBudget,
+    /// grouping keys and accumulators of the current group
+    current: Option<(Vec<ScalarValue>, Vec<Box<dyn Accumulator>>)>,
+    /// output values of finished groups, column by column
+    staged: Vec<Vec<ScalarValue>>,
+    num_staged_rows: usize,
+    num_staged_bytes: usize,
+    baseline_metrics: BaselineMetrics,
+}
+
+impl SortAggregator {
+    /// aggregates rows of the batch, returns an output batch if enough groups
+    /// are finished
+    fn aggregate_batch(&mut self, batch: &RecordBatch) -> Result<Option<RecordBatch>> {
+        if batch.num_rows() == 0 {
+            return Ok(None);
+        }
+        let elapsed_compute = self.baseline_metrics.elapsed_compute().clone();
+        let timer = elapsed_compute.timer();
+        let evaluate = |expr: &Arc<dyn PhysicalExpr>| -> Result<ArrayRef> {
+            Ok(expr.evaluate(batch)?.into_array(batch.num_rows()))
+        };
+        let keys = self
+            .group_expr
+            .iter()
+            .map(evaluate)
+            .collect::<Result<Vec<_>>>()?;
+        let values = self
+            .aggr_inputs
+            .iter()
+            .map(|exprs| exprs.iter().map(evaluate).collect::<Result<Vec<_>>>())
+            .collect::<Result<Vec<_>>>()?;
+        let sort_columns = keys
+            .iter()
+            .map(|key| SortColumn {
+                values: key.clone(),
+                options: None,
+            })
+            .collect::<Vec<_>>();
+
+        for range in lexicographical_partition_ranges(&sort_columns)? {
+            let group_key = keys
+                .iter()
+                .map(|key| ScalarValue::try_from_array(key, range.start))
+                .collect::<Result<Vec<_>>>()?;
+
+            // the first group of the batch may continue the current group
+            if !matches!(&self.current, Some((key, _)) if key == &group_key) {
+                self.finish_group()?;
+                let accumulators = self
+                    .aggr_expr
+                    .iter()
+                    .map(|expr| expr.create_accumulator())
+                    .collect::<Result<Vec<_>>>()?;
+                self.current = Some((group_key, accumulators));
+            }
+
+            let (_, accumulators) = self.current.as_mut().unwrap();
+            for (accumulator, values) in accumulators.iter_mut().zip(&values) {
+                let values = values
+                    .iter()
+                    .map(|value| value.slice(range.start, range.end - range.start))
+                    .collect::<Vec<_>>();
+                match self.mode {
+                    AggregateMode::Partial => accumulator.update_batch(&values)?,
+                    AggregateMode::Final | AggregateMode::FinalPartitioned => {
+                        accumulator.merge_batch(&values)?
+                    }
+                }
+            }
+        }
+        timer.done();
+
+        if self
+            .budget
+            .is_full(self.num_staged_rows, self.num_staged_bytes)
+        {
+            return self.take_staged().map(Some);
+        }
+        Ok(None)
+    }
+
+    /// finishes the last group, returns the remaining output
+    fn finish(&mut self) -> Result<Option<RecordBatch>> {
+        self.finish_group()?;
+        if self.num_staged_rows == 0 {
+            return Ok(None);
+        }
+        self.take_staged().map(Some)
+    }
+
+    fn finish_group(&mut self) -> Result<()> {
+        let (group_key, accumulators) = match self.current.take() {
+            Some(current) => current,
+            None => return Ok(()),
+        };
+        let mut output = group_key;
+        for accumulator in &accumulators {
+            match self.mode {
+                AggregateMode::Partial => output.extend(accumulator.state()?),
+                AggregateMode::Final | AggregateMode::FinalPartitioned => {
+                    output.push(accumulator.evaluate()?)
+                }
+            }
+        }
+        self.num_staged_bytes += output.iter().map(scalar_bytes).sum::<usize>();
+        for (staged, value) in self.staged.iter_mut().zip(output) {
+            staged.push(value);
+        }
+        self.num_staged_rows += 1;
+        Ok(())
+    }
+
+    fn take_staged(&mut self) -> Result<RecordBatch> {
+        let columns = self
+            .staged
+            .iter_mut()
+            .zip(self.schema.fields())
+            .map(|(staged, field)| {
+                let array = ScalarValue::iter_to_array(std::mem::take(staged))?;
+                if array.data_type() == field.data_type() {
+                    return Ok(array);
+                }
+                Ok(cast(&array, field.data_type())?)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        self.num_staged_rows = 0;
+        self.num_staged_bytes = 0;
+        let batch = RecordBatch::try_new(self.schema.clone(), columns)?;
+        self.baseline_metrics.record_output(batch.num_rows());
+        Ok(batch)
+    }
+}
+
+/// estimated bytes of the value in an output array, e.g. large strings or
+/// lists collected for a hot grouping key
+fn scalar_bytes(value: &ScalarValue) -> usize {
+    let data_bytes = match value {
+        ScalarValue::Utf8(Some(v)) | ScalarValue::LargeUtf8(Some(v)) => v.len(),
+        ScalarValue::Binary(Some(v)) | ScalarValue::LargeBinary(Some(v)) => v.len(),
+        ScalarValue::List(Some(values), _) => values.iter().map(scalar_bytes).sum(),
+        _ => 0,
+    };
+    data_bytes + std::mem::size_of::<u64>()
+}
+
+#[cfg(test)]
+mod tests {
+    use datafusion::arrow::array::{Int32Array, Int64Array};
+    use datafusion::arrow::datatypes::DataType;
+    use datafusion::physical_plan::aggregates::{
+        create_aggregate_expr, AggregateFunction,
+    };
+    use datafusion::physical_plan::common::collect;
+    use datafusion::physical_plan::expressions::col;
+    use datafusion::physical_plan::memory::MemoryExec;
+    use datafusion::prelude::SessionContext;
+
+    use super::*;
+    use crate::test_util::build_batch;
+
+    #[test]
+    fn test_sort_aggregate() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("k", DataType::Int32, true),
+            Field::new("v", DataType::Int64, false),
+        ]));
+        // groups span across batches
+        let input = Arc::new(MemoryExec::try_new(
+            &[vec![
+                build_batch!(schema, vec![None, Some(1), Some(1)], vec![1, 2, 3])?,
+                build_batch!(schema, vec![Some(1), Some(2)], vec![4, 5])?,
+                build_batch!(schema, vec![Some(2), Some(3), Some(3)], vec![6, 7, 8])?,
+            ]],
+            schema.clone(),
+            None,
+        )?);
+        let sum = create_aggregate_expr(
+            &AggregateFunction::Sum,
+            false,
+            &[col("v", &schema)?],
+            &schema,
+            "sum",
+        )?;
+        let partial = Arc::new(SortAggregateExec::try_new(
+            AggregateMode::Partial,
+            vec![(col("k", &schema)?, "k".to_string())],
+            vec![sum.clone()],
+            input,
+        )?);
+        let partial_schema = partial.schema();
+        let final_agg = SortAggregateExec::try_new(
+            AggregateMode::Final,
+            vec![(col("k", &partial_schema)?, "k".to_string())],
+            vec![sum],
+            partial,
+        )?;
+
+        let task_ctx = SessionContext::new().task_ctx();
+        let runtime = tokio::runtime::Runtime::new()?;
+        let batches = runtime.block_on(collect(final_agg.execute(0, task_ctx)?))?;
+        let output = RecordBatch::concat(&final_agg.schema(), &batches)?;
+        assert_eq!(
+            output
+                .column(0)
+                .as_any()
+                .downcast_ref::<Int32Array>()
+                .unwrap(),
+            &Int32Array::from(vec![None, Some(1), Some(2), Some(3)])
+        );
+        assert_eq!(
+            output
+                .column(1)
+                .as_any()
+                .downcast_ref::<Int64Array>()
+                .unwrap(),
+            &Int64Array::from(vec![1, 9, 11, 15])
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,584 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Defines spark compatible percentile_approx aggregation
+//!
+//! the sketch is a port of spark's QuantileSummaries (Greenwald-Khanna), and the
+//! partial aggregation state is serialized exactly as spark's
+//! ApproximatePercentile.PercentileDigestSerializer does, so that partial and
+//! final aggregations can be freely mixed between native and JVM across a
+//! shuffle boundary.
+
+use std::any::Any;
+use std::sync::Arc;
+
+use datafusion::arrow::array::{Array, ArrayRef, BinaryArray, Float64Array};
+use datafusion::arrow::compute::cast;
+use datafusion::arrow::datatypes::{DataType, Field, TimeUnit};
+use datafusion::error::{DataFusionError, Result};
+use datafusion::physical_plan::expressions::format_state_name;
+use datafusion::physical_plan::{Accumulator, AggregateExpr, PhysicalExpr};
+use datafusion::scalar::ScalarValue;
+
+/// same as spark's QuantileSummaries.defaultCompressThreshold
+pub const DEFAULT_COMPRESS_THRESHOLD: i32 = 10000;
+
+/// same as spark's QuantileSummaries.defaultHeadSize
+const DEFAULT_HEAD_SIZE: usize = 50000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Stats {
+    value: f64,
+    g: i64,
+    delta: i64,
+}
+
+#[derive(Debug, Clone)]
+struct QuantileSummaries {
+    compress_threshold: i32,
+    relative_error: f64,
+    sampled: Vec<Stats>,
+    count: i64,
+    head_sampled: Vec<f64>,
+}
+
+impl QuantileSummaries {
+    fn new(compress_threshold: i32, relative_error: f64) -> Self {
+        Self {
+            compress_threshold,
+            relative_error,
+            sampled: vec![],
+            count: 0,
+            head_sampled: vec![],
+        }
+    }
+
+    fn insert(&mut self, x: f64) {
+        self.head_sampled.push(x);
+        if self.head_sampled.len() >= DEFAULT_HEAD_SIZE {
+            self.insert_head_buffer();
+            if self.sampled.len() >= self.compress_threshold as usize {
+                self.compress();
+            }
+        }
+    }
+
+    fn insert_head_buffer(&mut self) {
+        if self.head_sampled.is_empty() {
+            return;
+        }
+        let mut sorted = std::mem::take(&mut self.head_sampled);
+        sorted.sort_by(|a, b| a.total_cmp(b));
+
+        let mut current_count = self.count;
+        let mut new_samples = Vec::with_capacity(self.sampled.len() + sorted.len());
+        let mut sample_idx = 0;
+        for (ops_idx, &current_sample) in sorted.iter().enumerate() {
+            while sample_idx < self.sampled.len()
+                && self.sampled[sample_idx].value <= current_sample
+            {
+                new_samples.push(self.sampled[sample_idx]);
+                sample_idx += 1;
+            }
+
+            // the first and the last inserted samples have no uncertainty
+            current_count += 1;
+            let delta = if new_samples.is_empty()
+                || (sample_idx == self.sampled.len() && ops_idx == sorted.len() - 1)
+            {
+                0
+            } else {
+                (2.0 * self.relative_error * current_count as f64).floor() as i64
+            };
+            new_samples.push(Stats {
+                value: current_sample,
+                g: 1,
+                delta,
+            });
+        }
+        new_samples.extend_from_slice(&self.sampled[sample_idx..]);
+        self.sampled = new_samples;
+        self.count = current_count;
+    }
+
+    fn compress(&mut self) {
+        self.insert_head_buffer();
+        let merge_threshold = 2.0 * self.relative_error * self.count as f64;
+        self.sampled = compress_samples(&self.sampled, merge_threshold);
+    }
+
+    /// merges another summary into this one, both must be compressed
+    fn merge(&mut self, other: &QuantileSummaries) {
+        if other.count == 0 {
+            return;
+        }
+        if self.count == 0 {
+            *self = other.clone();
+            return;
+        }
+
+        // samples interleaving the other side suffer from its lack of precision
+        let merged_relative_error = self.relative_error.max(other.relative_error);
+        let merged_count = self.count + other.count;
+        let additional_self_delta =
+            (2.0 * other.relative_error * other.count as f64).floor() as i64;
+        let additional_other_delta =
+            (2.0 * self.relative_error * self.count as f64).floor() as i64;
+
+        let mut merged = Vec::with_capacity(self.sampled.len() + other.sampled.len());
+        let mut self_idx = 0;
+        let mut other_idx = 0;
+        while self_idx < self.sampled.len() && other_idx < other.sampled.len() {
+            let self_sample = self.sampled[self_idx];
+            let other_sample = other.sampled[other_idx];
+            let (next_sample, additional_delta) =
+                if self_sample.value < other_sample.value {
+                    self_idx += 1;
+                    let d = if other_idx > 0 {
+                        additional_self_delta
+                    } else {
+                        0
+                    };
+                    (self_sample, d)
+                } else {
+                    other_idx += 1;
+                    let d = if self_idx > 0 {
+                        additional_other_delta
+                    } else {
+                        0
+                    };
+                    (other_sample, d)
+                };
+            merged.push(Stats {
+                delta: next_sample.delta + additional_delta,
+                ..next_sample
+            });
+        }
+        merged.extend_from_slice(&self.sampled[self_idx..]);
+        merged.extend_from_slice(&other.sampled[other_idx..]);
+
+        self.sampled =
+            compress_samples(&merged, 2.0 * merged_relative_error * merged_count as f64);
+        self.compress_threshold = other.compress_threshold;
+        self.relative_error = merged_relative_error;
+        self.count = merged_count;
+    }
+
+    /// queries a compressed summary
+    fn query(&self, quantile: f64) -> Option<f64> {
+        let first = self.sampled.first()?;
+        let last = self.sampled.last()?;
+        if quantile <= self.relative_error {
+            return Some(first.value);
+        }
+        if quantile >= 1.0 - self.relative_error {
+            return Some(last.value);
+        }
+
+        let rank = (quantile * self.count as f64).ceil() as i64;
+        let target_error = self.relative_error * self.count as f64;
+        let mut min_rank = 0;
+        for sample in &self.sampled[..self.sampled.len() - 1] {
+            min_rank += sample.g;
+            let max_rank = min_rank + sample.delta;
+            if (max_rank as f64 - target_error) <= rank as f64
+                && rank as f64 <= (min_rank as f64 + target_error)
+            {
+                return Some(sample.value);
+            }
+        }
+        Some(last.value)
+    }
+}
+
+/// same as spark's QuantileSummaries.compressImmut()
+fn compress_samples(samples: &[Stats], merge_threshold: f64) -> Vec<Stats> {
+    if samples.is_empty() {
+        return vec![];
+    }
+
+    // merge from the last element, which is always kept
+    let mut reversed = vec![];
+    let mut head = samples[samples.len() - 1];
+    for &sample in samples[1..samples.len().max(2) - 1].iter().rev() {
+        if ((sample.g + head.g + head.delta) as f64) < merge_threshold {
+            head.g += sample.g;
+        } else {
+            reversed.push(head);
+            head = sample;
+        }
+    }
+    reversed.push(head);
+
+    // keep the minimum element
+    if samples[0].value <= head.value && samples.len() > 1 {
+        reversed.push(samples[0]);
+    }
+    reversed.reverse();
+    reversed
+}
+
+/// Same as spark's ApproximatePercentile.PercentileDigest
+#[derive(Debug, Clone)]
+pub struct PercentileDigest {
+    summaries: QuantileSummaries,
+    is_compressed: bool,
+}
+
+impl PercentileDigest {
+    pub fn new(relative_error: f64) -> Self {
+        Self {
+            summaries: QuantileSummaries::new(DEFAULT_COMPRESS_THRESHOLD, relative_error),
+            is_compressed: true,
+        }
+    }
+
+    pub fn add(&mut self, value: f64) {
+        self.is_compressed = false;
+        self.summaries.insert(value);
+    }
+
+    pub fn merge(&mut self, other: &PercentileDigest) {
+        self.compress();
+        self.summaries.merge(&other.compressed_summaries());
+    }
+
+    /// returns the percentiles, or empty if no values are added
+    pub fn get_percentiles(&mut self, percentages: &[f64]) -> Vec<f64> {
+        self.compress();
+        if self.summaries.count == 0 {
+            return vec![];
+        }
+        percentages
+            .iter()
+            .map(|&p| self.summaries.query(p).unwrap())
+            .collect()
+    }
+
+    /// serializes into the layout of spark's PercentileDigestSerializer
+    pub fn serialize(&self) -> Vec<u8> {
+        let summaries = self.compressed_summaries();
+        let mut bytes = Vec::with_capacity(24 + summaries.sampled.len() * 24);
+        bytes.extend_from_slice(&summaries.compress_threshold.to_be_bytes());
+        bytes.extend_from_slice(&summaries.relative_error.to_be_bytes());
+        bytes.extend_from_slice(&summaries.count.to_be_bytes());
+        bytes.extend_from_slice(&(summaries.sampled.len() as i32).to_be_bytes());
+        for stats in &summaries.sampled {
+            bytes.extend_from_slice(&stats.value.to_be_bytes());
+            bytes.extend_from_slice(&stats.g.to_be_bytes());
+            bytes.extend_from_slice(&stats.delta.to_be_bytes());
+        }
+        bytes
+    }
+
+    /// deserializes from the layout of spark's PercentileDigestSerializer
+    pub fn deserialize(bytes: &[u8]) -> Result<Self> {
+        let mut reader = BigEndianReader { bytes, pos: 0 };
+        let compress_threshold = i32::from_be_bytes(reader.read()?);
+        let relative_error = f64::from_be_bytes(reader.read()?);
+        let count = i64::from_be_bytes(reader.read()?);
+        let num_sampled = i32::from_be_bytes(reader.read()?);
+        let sampled = (0..num_sampled)
+            .map(|_| {
+                Ok(Stats {
+                    value: f64::from_be_bytes(reader.read()?),
+                    g: i64::from_be_bytes(reader.read()?),
+                    delta: i64::from_be_bytes(reader.read()?),
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            summaries: QuantileSummaries {
+                compress_threshold,
+                relative_error,
+                sampled,
+                count,
+                head_sampled: vec![],
+            },
+            is_compressed: true,
+        })
+    }
+
+    fn compress(&mut self) {
+        if !self.is_compressed {
+            self.summaries.compress();
+            self.is_compressed = true;
+        }
+    }
+
+    fn compressed_summaries(&self) -> QuantileSummaries {
+        let mut summaries = self.summaries.clone();
+        if !self.is_compressed {
+            summaries.compress();
+        }
+        summaries
+    }
+}
+
+struct BigEndianReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BigEndianReader<'a> {
+    fn read<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let end = self.pos + N;
+        let value = self.bytes.get(self.pos..end).ok_or_else(|| {
+            DataFusionError::Execution(
+                "PercentileDigest: unexpected end of serialized bytes".to_string(),
+            )
+        })?;
+        self.pos = end;
+        Ok(value.try_into().unwrap())
+    }
+}
+
+/// Spark's percentile_approx(expr, percentage, accuracy), returning values of
+/// the input data type.
+#[derive(Debug)]
+pub struct SparkApproxPercentile {
+    name: String,
+    expr: Arc<dyn PhysicalExpr>,
+    data_type: DataType,
+    percentages: Vec<f64>,
+    return_percentile_array: bool,
+    relative_error: f64,
+}
+
+impl SparkApproxPercentile {
+    pub fn try_new(
+        expr: Arc<dyn PhysicalExpr>,
+        name: impl Into<String>,
+        data_type: DataType,
+        percentages: Vec<f64>,
+        return_percentile_array: bool,
+        accuracy: i32,
+    ) -> Result<Self> {
+        if !is_supported_data_type(&data_type) {
+            return Err(DataFusionError::Plan(format!(
+                "SparkApproxPercentile: unsupported data type: {:?}",
+                data_type
+            )));
+        }
+        if accuracy <= 0 {
+            return Err(DataFusionError::Plan(format!(
+                "SparkApproxPercentile: accuracy must be positive, got {}",
+                accuracy
+            )));
+        }
+        if let Some(p) = percentages.iter().find(|p| !(0.0..=1.0).contains(*p)) {
+            return Err(DataFusionError::Plan(format!(
+                "SparkApproxPercentile: percentage must be in [0.0, 1.0], got {}",
+                p
+            )));
+        }
+        Ok(Self {
+            name: name.into(),
+            expr,
+            data_type,
+            percentages,
+            return_percentile_array,
+            relative_error: 1.0 / accuracy as f64,
+        })
+    }
+
+    fn return_type(&self) -> DataType {
+        if self.return_percentile_array {
+            DataType::List(Box::new(Field::new("item", self.data_type.clone(), true)))
+        } else {
+            self.data_type.clone()
+        }
+    }
+}
+
+fn is_supported_data_type(data_type: &DataType) -> bool {
+    matches!(
+        data_type,
+        DataType::Int8
+            | DataType::Int16
+            | DataType::Int32
+            | DataType::Int64
+            | DataType::Float32
+            | DataType::Float64
+            | DataType::Date32
+            | DataType::Timestamp(TimeUnit::Microsecond, _)
+    )
+}
+
+impl AggregateExpr for SparkApproxPercentile {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn field(&self) -> Result<Field> {
+        Ok(Field::new(&self.name, self.return_type(), true))
+    }
+
+    fn create_accumulator(&self) -> Result<Box<dyn Accumulator>> {
+        Ok(Box::new(SparkApproxPercentileAccumulator {
+            digest: PercentileDigest::new(self.relative_error),
+            data_type: self.data_type.clone(),
+            percentages: self.percentages.clone(),
+            return_percentile_array: self.return_percentile_array,
+        }))
+    }
+
+    fn state_fields(&self) -> Result<Vec<Field>> {
+        Ok(vec![Field::new(
+            &format_state_name(&self.name, "percentile_digest"),
+            DataType::Binary,
+            true,
+        )])
+    }
+
+    fn expressions(&self) -> Vec<Arc<dyn PhysicalExpr>> {
+        vec![self.expr.clone()]
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+#[derive(Debug)]
+struct SparkApproxPercentileAccumulator {
+    digest: PercentileDigest,
+    data_type: DataType,
+    percentages: Vec<f64>,
+    return_percentile_array: bool,
+}
+
+impl SparkApproxPercentileAccumulator {
+    fn to_scalar(&self, value: Option<f64>) -> ScalarValue {
+        // follows spark's conversion from double results
+        match &self.data_type {
+            DataType::Int8 => ScalarValue::Int8(value.map(|v| v as i32 as i8)),
+            DataType::Int16 => ScalarValue::Int16(value.map(|v| v as i32 as i16)),
+            DataType::Int32 => ScalarValue::Int32(value.map(|v| v as i32)),
+            DataType::Int64 => ScalarValue::Int64(value.map(|v| v as i64)),
+            DataType::Float32 => ScalarValue::Float32(value.map(|v| v as f32)),
+            DataType::Float64 => ScalarValue::Float64(value),
+            DataType::Date32 => ScalarValue::Date32(value.map(|v| v as i32)),
+            DataType::Timestamp(TimeUnit::Microsecond, tz) => {
+                ScalarValue::TimestampMicrosecond(value.map(|v| v as i64), tz.clone())
+            }
+            _ => unreachable!(), // checked by is_supported_data_type()
+        }
+    }
+}
+
+impl Accumulator for SparkApproxPercentileAccumulator {
+    fn state(&self) -> Result<Vec<ScalarValue>> {
+        Ok(vec![ScalarValue::Binary(Some(self.digest.serialize()))])
+    }
+
+    fn update_batch(&mut self, values: &[ArrayRef]) -> Result<()> {
+        // dates and timestamps are added as their underlying integers
+        let values = match values[0].data_type() {
+            DataType::Date32 => cast(&values[0], &DataType::Int32)?,
+            DataType::Timestamp(_, _) => cast(&values[0], &DataType::Int64)?,
+            _ => values[0].clone(),
+        };
+        let values = cast(&values, &DataType::Float64)?;
+        let values = values.as_any().downcast_ref::<Float64Array>().unwrap();
+        values.iter().flatten().for_each(|v| self.digest.add(v));
+        Ok(())
+    }
+
+    fn merge_batch(&mut self, states: &[ArrayRef]) -> Result<()> {
+        let states = states[0]
+            .as_any()
+            .downcast_ref::<BinaryArray>()
+            .ok_or_else(|| {
+                DataFusionError::Execution(format!(
+                    "SparkApproxPercentile: expect binary state, got {:?}",
+                    states[0].data_type()
+                ))
+            })?;
+        for state in states.iter().flatten() {
+            self.digest.merge(&PercentileDigest::deserialize(state)?);
+        }
+        Ok(())
+    }
+
+    fn evaluate(&self) -> Result<ScalarValue> {
+        let mut digest = self.digest.clone();
+        let results = digest.get_percentiles(&self.percentages);
+        if results.is_empty() {
+            return Ok(if self.return_percentile_array {
+                ScalarValue::List(None, Box::new(self.data_type.clone()))
+            } else {
+                self.to_scalar(None)
+            });
+        }
+        if self.return_percentile_array {
+            let values = results
+                .into_iter()
+                .map(|v| self.to_scalar(Some(v)))
+                .collect();
+            return Ok(ScalarValue::List(
+                Some(Box::new(values)),
+                Box::new(self.data_type.clone()),
+            ));
+        }
+        Ok(self.to_scalar(Some(results[0])))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percentile_digest_serde() -> Result<()> {
+        let mut digest = PercentileDigest::new(0.01);
+        digest.add(3.0);
+        digest.add(1.0);
+        digest.add(2.0);
+
+        let bytes = digest.serialize();
+        assert_eq!(bytes.len(), 4 + 8 + 8 + 4 + 3 * 24);
+        assert_eq!(&bytes[0..4], &DEFAULT_COMPRESS_THRESHOLD.to_be_bytes());
+        assert_eq!(&bytes[4..12], &0.01f64.to_be_bytes());
+        assert_eq!(&bytes[12..20], &3i64.to_be_bytes());
+        assert_eq!(&bytes[20..24], &3i32.to_be_bytes());
+        assert_eq!(&bytes[24..32], &1.0f64.to_be_bytes());
+
+        let mut deserialized = PercentileDigest::deserialize(&bytes)?;
+        assert_eq!(deserialized.serialize(), bytes);
+        assert_eq!(
+            deserialized.get_percentiles(&[0.0, 0.5, 1.0]),
+            vec![1.0, 2.0, 3.0]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_percentile_digest_merge() -> Result<()> {
+        let mut digest1 = PercentileDigest::new(0.001);
+        let mut digest2 = PercentileDigest::new(0.001);
+        (1..=500).for_each(|v| digest1.add(v as f64));
+        (501..=1000).for_each(|v| digest2.add(v as f64));
+
+        let mut merged = PercentileDigest::new(0.001);
+        merged.merge(&PercentileDigest::deserialize(&digest1.serialize())?);
+        merged.merge(&PercentileDigest::deserialize(&digest2.serialize())?);
+        let percentiles = merged.get_percentiles(&[0.25, 0.5, 0.75]);
+        for (percentile, expected) in percentiles.iter().zip([250.0, 500.0, 750.0]) {
+            assert!((percentile - expected).abs() <= 1.0);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,239 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Defines spark compatible conditional aggregations: count_if, bool_and
+//! (every) and bool_or (any/some).
+//!
+//! nulls are ignored as spark does: count_if never returns null and returns 0
+//! on empty input, bool_and/bool_or return null if there are no non-null
+//! inputs. partial states are the same as spark's aggregation buffers (a long
+//! count, or a nullable boolean), so that partial and final aggregations can be
+//! mixed between native and JVM across a shuffle boundary.
+
+use std::any::Any;
+use std::sync::Arc;
+
+use datafusion::arrow::array::{ArrayRef, BooleanArray, Int64Array};
+use datafusion::arrow::datatypes::{DataType, Field};
+use datafusion::error::{DataFusionError, Result};
+use datafusion::physical_plan::expressions::format_state_name;
+use datafusion::physical_plan::{Accumulator, AggregateExpr, PhysicalExpr};
+use datafusion::scalar::ScalarValue;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SparkBoolAggregateFunction {
+    CountIf,
+    BoolAnd,
+    BoolOr,
+}
+
+/// Spark's count_if(expr), bool_and(expr) and bool_or(expr) of a boolean input
+#[derive(Debug)]
+pub struct SparkBoolAggregate {
+    name: String,
+    expr: Arc<dyn PhysicalExpr>,
+    function: SparkBoolAggregateFunction,
+}
+
+impl SparkBoolAggregate {
+    pub fn try_new(
+        expr: Arc<dyn PhysicalExpr>,
+        name: impl Into<String>,
+        function: SparkBoolAggregateFunction,
+        data_type: DataType,
+    ) -> Result<Self> {
+        if data_type != DataType::Boolean {
+            return Err(DataFusionError::Plan(format!(
+                "SparkBoolAggregate: {:?} expects boolean input, got {:?}",
+                function, data_type
+            )));
+        }
+        Ok(Self {
+            name: name.into(),
+            expr,
+            function,
+        })
+    }
+}
+
+impl AggregateExpr for SparkBoolAggregate {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn field(&self) -> Result<Field> {
+        Ok(match self.function {
+            SparkBoolAggregateFunction::CountIf => {
+                Field::new(&self.name, DataType::Int64, false)
+            }
+            _ => Field::new(&self.name, DataType::Boolean, true),
+        })
+    }
+
+    fn create_accumulator(&self) -> Result<Box<dyn Accumulator>> {
+        Ok(Box::new(SparkBoolAggregateAccumulator {
+            function: self.function,
+            count: 0,
+            value: None,
+        }))
+    }
+
+    fn state_fields(&self) -> Result<Vec<Field>> {
+        Ok(vec![match self.function {
+            SparkBoolAggregateFunction::CountIf => Field::new(
+                &format_state_name(&self.name, "count"),
+                DataType::Int64,
+                false,
+            ),
+            SparkBoolAggregateFunction::BoolAnd => Field::new(
+                &format_state_name(&self.name, "bool_and"),
+                DataType::Boolean,
+                true,
+            ),
+            SparkBoolAggregateFunction::BoolOr => Field::new(
+                &format_state_name(&self.name, "bool_or"),
+                DataType::Boolean,
+                true,
+            ),
+        }])
+    }
+
+    fn expressions(&self) -> Vec<Arc<dyn PhysicalExpr>> {
+        vec![self.expr.clone()]
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+#[derive(Debug)]
+struct SparkBoolAggregateAccumulator {
+    function: SparkBoolAggregateFunction,
+    count: i64,
+    value: Option<bool>,
+}
+
+impl SparkBoolAggregateAccumulator {
+    /// folds non-null booleans into the value of bool_and/bool_or
+    fn update_value(&mut self, values: &BooleanArray) {
+        for v in values.iter().flatten() {
+            self.value = Some(match self.function {
+                SparkBoolAggregateFunction::BoolAnd => self.value.unwrap_or(true) && v,
+                _ => self.value.unwrap_or(false) || v,
+            });
+        }
+    }
+}
+
+fn downcast_array<'a, T: 'static>(array: &'a ArrayRef, expected: &str) -> Result<&'a T> {
+    array.as_any().downcast_ref::<T>().ok_or_else(|| {
+        DataFusionError::Execution(format!(
+            "SparkBoolAggregate: expect {} array, got {:?}",
+            expected,
+            array.data_type()
+        ))
+    })
+}
+
+impl Accumulator for SparkBoolAggregateAccumulator {
+    fn state(&self) -> Result<Vec<ScalarValue>> {
+        Ok(vec![self.evaluate()?])
+    }
+
+    fn update_batch(&mut self, values: &[ArrayRef]) -> Result<()> {
+        let values = downcast_array::<BooleanArray>(&values[0], "boolean")?;
+        match self.function {
+            SparkBoolAggregateFunction::CountIf => {
+                self.count += values.iter().filter(|v| *v == Some(true)).count() as i64;
+            }
+            _ => self.update_value(values),
+        }
+        Ok(())
+    }
+
+    fn merge_batch(&mut self, states: &[ArrayRef]) -> Result<()> {
+        match self.function {
+            SparkBoolAggregateFunction::CountIf => {
+                let states = downcast_array::<Int64Array>(&states[0], "int64")?;
+                self.count += states.iter().flatten().sum::<i64>();
+            }
+            _ => {
+                // null states have seen no non-null inputs
+                let states = downcast_array::<BooleanArray>(&states[0], "boolean")?;
+                self.update_value(states);
+            }
+        }
+        Ok(())
+    }
+
+    fn evaluate(&self) -> Result<ScalarValue> {
+        Ok(match self.function {
+            SparkBoolAggregateFunction::CountIf => ScalarValue::Int64(Some(self.count)),
+            _ => ScalarValue::Boolean(self.value),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use datafusion::arrow::datatypes::{DataType, Field, Schema};
+    use datafusion::physical_plan::expressions::Column;
+
+    use super::*;
+
+    #[test]
+    fn test_bool_aggregate() -> Result<()> {
+        let schema = Schema::new(vec![Field::new("c", DataType::Boolean, true)]);
+        let expr = Arc::new(Column::new_with_schema("c", &schema)?);
+        let values: ArrayRef =
+            Arc::new(BooleanArray::from(vec![Some(true), None, Some(false)]));
+        let nulls: ArrayRef = Arc::new(BooleanArray::from(vec![None, None]));
+
+        let evaluate = |function, batches: &[&ArrayRef]| -> Result<ScalarValue> {
+            let aggr = SparkBoolAggregate::try_new(
+                expr.clone(),
+                "aggr",
+                function,
+                DataType::Boolean,
+            )?;
+            let mut partial = aggr.create_accumulator()?;
+            let mut final_ = aggr.create_accumulator()?;
+            for &batch in batches {
+                partial.update_batch(&[batch.clone()])?;
+            }
+            let state = partial.state()?[0].to_array();
+            final_.merge_batch(&[state])?;
+            final_.evaluate()
+        };
+
+        use SparkBoolAggregateFunction::*;
+        assert_eq!(
+            evaluate(CountIf, &[&values, &values])?,
+            ScalarValue::Int64(Some(2))
+        );
+        assert_eq!(evaluate(CountIf, &[])?, ScalarValue::Int64(Some(0)));
+        assert_eq!(
+            evaluate(BoolAnd, &[&values])?,
+            ScalarValue::Boolean(Some(false))
+        );
+        assert_eq!(
+            evaluate(BoolOr, &[&values])?,
+            ScalarValue::Boolean(Some(true))
+        );
+        assert_eq!(evaluate(BoolAnd, &[&nulls])?, ScalarValue::Boolean(None));
+        assert_eq!(evaluate(BoolOr, &[])?, ScalarValue::Boolean(None));
+        Ok(())
+    }
+}
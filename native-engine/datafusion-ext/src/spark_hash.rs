@@ -29,34 +29,34 @@ use datafusion::arrow::datatypes::{
 use datafusion::error::{DataFusionError, Result};
 
 #[inline]
-fn spark_compatible_murmur3_hash<T: AsRef<[u8]>>(data: T, seed: u32) -> u32 {
-    #[inline]
-    fn mix_k1(mut k1: i32) -> i32 {
-        k1 *= 0xcc9e2d51u32 as i32;
-        k1 = k1.rotate_left(15);
-        k1 *= 0x1b873593u32 as i32;
-        k1
-    }
+fn mix_k1(mut k1: i32) -> i32 {
+    k1 *= 0xcc9e2d51u32 as i32;
+    k1 = k1.rotate_left(15);
+    k1 *= 0x1b873593u32 as i32;
+    k1
+}
 
-    #[inline]
-    fn mix_h1(mut h1: i32, k1: i32) -> i32 {
-        h1 ^= k1;
-        h1 = h1.rotate_left(13);
-        h1 = h1 * 5 + 0xe6546b64u32 as i32;
-        h1
-    }
+#[inline]
+fn mix_h1(mut h1: i32, k1: i32) -> i32 {
+    h1 ^= k1;
+    h1 = h1.rotate_left(13);
+    h1 = h1 * 5 + 0xe6546b64u32 as i32;
+    h1
+}
 
-    #[inline]
-    fn fmix(mut h1: i32, len: i32) -> i32 {
-        h1 ^= len;
-        h1 ^= (h1 as u32 >> 16) as i32;
-        h1 *= 0x85ebca6bu32 as i32;
-        h1 ^= (h1 as u32 >> 13) as i32;
-        h1 *= 0xc2b2ae35u32 as i32;
-        h1 ^= (h1 as u32 >> 16) as i32;
-        h1
-    }
+#[inline]
+fn fmix(mut h1: i32, len: i32) -> i32 {
+    h1 ^= len;
+    h1 ^= (h1 as u32 >> 16) as i32;
+    h1 *= 0x85ebca6bu32 as i32;
+    h1 ^= (h1 as u32 >> 13) as i32;
+    h1 *= 0xc2b2ae35u32 as i32;
+    h1 ^= (h1 as u32 >> 16) as i32;
+    h1
+}
 
+#[inline]
+pub(crate) fn spark_compatible_murmur3_hash<T: AsRef<[u8]>>(data: T, seed: u32) -> u32 {
     #[inline]
     unsafe fn hash_bytes_by_int(data: &[u8], seed: u32) -> i32 {
         // safety: data length must be aligned to 4 bytes
@@ -91,6 +91,48 @@ fn spark_compatible_murmur3_hash<T: AsRef<[u8]>>(data: T, seed: u32) -> u32 {
     }
 }
 
+/// same as hashing the 4 little-endian bytes, see Murmur3_x86_32.hashInt
+#[inline]
+fn spark_compatible_murmur3_hash_int(value: i32, seed: u32) -> u32 {
+    fmix(mix_h1(seed as i32, mix_k1(value)), 4) as u32
+}
+
+/// same as hashing the 8 little-endian bytes, see Murmur3_x86_32.hashLong
+#[inline]
+fn spark_compatible_murmur3_hash_long(value: i64, seed: u32) -> u32 {
+    let h1 = mix_h1(seed as i32, mix_k1(value as i32));
+    let h1 = mix_h1(h1, mix_k1((value >> 32) as i32));
+    fmix(h1, 8) as u32
+}
+
+/// number of rows hashed in each iteration of hash_values_unrolled()
+const HASH_LANES: usize = 8;
+
+/// hashes fixed-width values without nulls into hashes. rows are hashed in
+/// fixed-size chunks without branches, so that the compiler can vectorize
+/// the hashing of lanes with SIMD instructions.
+#[inline]
+fn hash_values_unrolled<T: Copy>(
+    values: &[T],
+    hashes: &mut [u32],
+    hash: impl Fn(T, u32) -> u32,
+) {
+    let len = values.len().min(hashes.len());
+    let mut value_chunks = values[..len].chunks_exact(HASH_LANES);
+    let mut hash_chunks = hashes[..len].chunks_exact_mut(HASH_LANES);
+    for (values, hashes) in (&mut value_chunks).zip(&mut hash_chunks) {
+        let values: &[T; HASH_LANES] = values.try_into().unwrap();
+        let hashes: &mut [u32; HASH_LANES] = hashes.try_into().unwrap();
+        for lane in 0..HASH_LANES {
+            hashes[lane] = hash(values[lane], hashes[lane]);
+        }
+    }
+    let remainder = value_chunks.remainder();
+    for (value, h) in remainder.iter().zip(hash_chunks.into_remainder()) {
+        *h = hash(*value, *h);
+    }
+}
+
 #[test]
 fn test_murmur3() {
     let hashes = ["", "a", "ab", "abc", "abcd", "abcde"]
@@ -126,17 +168,13 @@ macro_rules! hash_array_primitive_i32 {
         let values = array.values();
 
         if array.null_count() == 0 {
-            for (hash, value) in $hashes.iter_mut().zip(values.iter()) {
-                *hash =
-                    spark_compatible_murmur3_hash((*value as i32).to_le_bytes(), *hash);
-            }
+            hash_values_unrolled(values, $hashes, |value: $ty, hash| {
+                spark_compatible_murmur3_hash_int(value as i32, hash)
+            });
         } else {
             for (i, (hash, value)) in $hashes.iter_mut().zip(values.iter()).enumerate() {
                 if !array.is_null(i) {
-                    *hash = spark_compatible_murmur3_hash(
-                        (*value as i32).to_le_bytes(),
-                        *hash,
-                    );
+                    *hash = spark_compatible_murmur3_hash_int(*value as i32, *hash);
                 }
             }
         }
@@ -149,17 +187,13 @@ macro_rules! hash_array_primitive_i64 {
         let values = array.values();
 
         if array.null_count() == 0 {
-            for (hash, value) in $hashes.iter_mut().zip(values.iter()) {
-                *hash =
-                    spark_compatible_murmur3_hash((*value as i64).to_le_bytes(), *hash);
-            }
+            hash_values_unrolled(values, $hashes, |value: $ty, hash| {
+                spark_compatible_murmur3_hash_long(value as i64, hash)
+            });
         } else {
             for (i, (hash, value)) in $hashes.iter_mut().zip(values.iter()).enumerate() {
                 if !array.is_null(i) {
-                    *hash = spark_compatible_murmur3_hash(
-                        (*value as i64).to_le_bytes(),
-                        *hash,
-                    );
+                    *hash = spark_compatible_murmur3_hash_long(*value as i64, *hash);
                 }
             }
         }
@@ -210,17 +244,16 @@ pub fn create_hashes<'a>(
                 let array = col.as_any().downcast_ref::<BooleanArray>().unwrap();
                 if array.null_count() == 0 {
                     for (i, hash) in hashes_buffer.iter_mut().enumerate() {
-                        *hash = spark_compatible_murmur3_hash(
-                            ((if array.value(i) { 1 } else { 0 }) as i32).to_le_bytes(),
+                        *hash = spark_compatible_murmur3_hash_int(
+                            array.value(i) as i32,
                             *hash,
                         );
                     }
                 } else {
                     for (i, hash) in hashes_buffer.iter_mut().enumerate() {
                         if !array.is_null(i) {
-                            *hash = spark_compatible_murmur3_hash(
-                                ((if array.value(i) { 1 } else { 0 }) as i32)
-                                    .to_le_bytes(),
+                            *hash = spark_compatible_murmur3_hash_int(
+                                array.value(i) as i32,
                                 *hash,
                             );
                         }
@@ -327,7 +360,7 @@ mod tests {
     };
     use datafusion::from_slice::FromSlice;
 
-    use crate::spark_hash::{create_hashes, pmod};
+    use crate::spark_hash::{create_hashes, pmod, spark_compatible_murmur3_hash};
 
     #[test]
     fn test_i8() {
@@ -391,6 +424,33 @@ mod tests {
         assert_eq!(hashes, expected);
     }
 
+    #[test]
+    fn test_hash_unrolled() {
+        // unrolled hashing of fixed-width values is the same as hashing bytes,
+        // including the rows not filling a whole chunk
+        let values = (0..19)
+            .map(|i| i * 0x12345679 - (1 << 30))
+            .collect::<Vec<i64>>();
+        let i32_values = values.iter().map(|&v| v as i32).collect::<Vec<_>>();
+        let i = Arc::new(Int32Array::from(i32_values.clone())) as ArrayRef;
+        let mut hashes = vec![42; 19];
+        create_hashes(&[i], &mut hashes).unwrap();
+        let expected = i32_values
+            .iter()
+            .map(|v| spark_compatible_murmur3_hash(v.to_le_bytes(), 42))
+            .collect::<Vec<_>>();
+        assert_eq!(hashes, expected);
+
+        let i = Arc::new(Int64Array::from(values.clone())) as ArrayRef;
+        let mut hashes = vec![42; 19];
+        create_hashes(&[i], &mut hashes).unwrap();
+        let expected = values
+            .iter()
+            .map(|v| spark_compatible_murmur3_hash(v.to_le_bytes(), 42))
+            .collect::<Vec<_>>();
+        assert_eq!(hashes, expected);
+    }
+
     #[test]
     fn test_pmod() {
         let i: Vec<u32> =
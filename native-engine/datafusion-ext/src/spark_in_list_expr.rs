@@ -0,0 +1,297 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Defines spark's `value IN (literals...)` against a hash set of the
+//! literals, instead of comparing each row with every literal.
+//!
+//! like spark, the result is null if the value is null, or if the value is
+//! not found and the list contains null. integers and dates, floats and
+//! strings are supported, float values are normalized so that NaN equals NaN
+//! and -0.0 equals 0.0 as in spark.
+
+use std::any::Any;
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+
+use datafusion::arrow::array::{
+    Array, BooleanArray, Date32Array, Date64Array, Float32Array, Float64Array,
+    Int16Array, Int32Array, Int64Array, Int8Array, StringArray,
+};
+use datafusion::arrow::datatypes::{DataType, Schema};
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::physical_plan::expressions::Literal;
+use datafusion::physical_plan::{ColumnarValue, PhysicalExpr};
+use datafusion::scalar::ScalarValue;
+
+#[derive(Debug)]
+enum InListSet {
+    Int(HashSet<i64>),
+    Float(HashSet<u64>),
+    Utf8(HashSet<String>),
+}
+
+/// Spark's In/InSet with a list of literals
+#[derive(Debug)]
+pub struct SparkInListExpr {
+    expr: Arc<dyn PhysicalExpr>,
+    set: Arc<InListSet>,
+    list_has_null: bool,
+    negated: bool,
+}
+
+impl SparkInListExpr {
+    /// returns None if the list contains non-literals or literals of
+    /// unsupported types, which are evaluated with datafusion's InListExpr
+    pub fn try_new_with_literals(
+        expr: Arc<dyn PhysicalExpr>,
+        list: &[Arc<dyn PhysicalExpr>],
+        negated: bool,
+    ) -> Option<Self> {
+        let values = list
+            .iter()
+            .map(|e| Some(e.as_any().downcast_ref::<Literal>()?.value()))
+            .collect::<Option<Vec<_>>>()?;
+
+        let set = if let Some(values) = collect_values(&values, int_value) {
+            InListSet::Int(values)
+        } else if let Some(values) = collect_values(&values, float_value) {
+            InListSet::Float(values)
+        } else if let Some(values) = collect_values(&values, utf8_value) {
+            InListSet::Utf8(values)
+        } else {
+            return None;
+        };
+        Some(Self {
+            expr,
+            set: Arc::new(set),
+            list_has_null: values.iter().any(|value| value.is_null()),
+            negated,
+        })
+    }
+
+    pub fn expr(&self) -> &Arc<dyn PhysicalExpr> {
+        &self.expr
+    }
+
+    /// returns the same lookup of another value expression
+    pub fn with_expr(&self, expr: Arc<dyn PhysicalExpr>) -> Self {
+        Self {
+            expr,
+            set: self.set.clone(),
+            list_has_null: self.list_has_null,
+            negated: self.negated,
+        }
+    }
+}
+
+/// collects non-null values, or returns None if any value (including nulls)
+/// is not of the kind
+fn collect_values<T: std::hash::Hash + Eq>(
+    values: &[&ScalarValue],
+    value_of: impl Fn(&ScalarValue) -> Option<Option<T>>,
+) -> Option<HashSet<T>> {
+    let mut set = HashSet::new();
+    for value in values {
+        set.extend(value_of(value)?);
+    }
+    Some(set)
+}
+
+fn int_value(value: &ScalarValue) -> Option<Option<i64>> {
+    Some(match value {
+        ScalarValue::Int8(v) => v.map(|v| v as i64),
+        ScalarValue::Int16(v) => v.map(|v| v as i64),
+        ScalarValue::Int32(v) | ScalarValue::Date32(v) => v.map(|v| v as i64),
+        ScalarValue::Int64(v) | ScalarValue::Date64(v) => *v,
+        _ => return None,
+    })
+}
+
+fn float_value(value: &ScalarValue) -> Option<Option<u64>> {
+    Some(match value {
+        ScalarValue::Float32(v) => v.map(|v| normalized_float_bits(v as f64)),
+        ScalarValue::Float64(v) => v.map(normalized_float_bits),
+        _ => return None,
+    })
+}
+
+fn utf8_value(value: &ScalarValue) -> Option<Option<String>> {
+    match value {
+        ScalarValue::Utf8(v) => Some(v.clone()),
+        _ => None,
+    }
+}
+
+fn normalized_float_bits(value: f64) -> u64 {
+    if value.is_nan() {
+        f64::NAN.to_bits()
+    } else if value == 0.0 {
+        0.0f64.to_bits()
+    } else {
+        value.to_bits()
+    }
+}
+
+impl Display for SparkInListExpr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let num_values = match self.set.as_ref() {
+            InListSet::Int(set) => set.len(),
+            InListSet::Float(set) => set.len(),
+            InListSet::Utf8(set) => set.len(),
+        };
+        let not = if self.negated { "NOT " } else { "" };
+        write!(f, "{} {}IN SET ({} values)", self.expr, not, num_values)
+    }
+}
+
+impl PhysicalExpr for SparkInListExpr {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn data_type(&self, _input_schema: &Schema) -> Result<DataType> {
+        Ok(DataType::Boolean)
+    }
+
+    fn nullable(&self, input_schema: &Schema) -> Result<bool> {
+        Ok(self.list_has_null || self.expr.nullable(input_schema)?)
+    }
+
+    fn evaluate(&self, batch: &RecordBatch) -> Result<ColumnarValue> {
+        let value = self.expr.evaluate(batch)?.into_array(batch.num_rows());
+        let result_of = |contained: Option<bool>| match contained {
+            Some(true) => Some(!self.negated),
+            Some(false) if !self.list_has_null => Some(self.negated),
+            _ => None,
+        };
+
+        macro_rules! lookup {
+            ($array_ty:ty, $contains:expr) => {{
+                let value = value.as_any().downcast_ref::<$array_ty>().unwrap();
+                value
+                    .iter()
+                    .map(|v| result_of(v.map($contains)))
+                    .collect::<BooleanArray>()
+            }};
+        }
+        let result = match (self.set.as_ref(), value.data_type()) {
+            (InListSet::Int(set), DataType::Int8) => {
+                lookup!(Int8Array, |v| set.contains(&(v as i64)))
+            }
+            (InListSet::Int(set), DataType::Int16) => {
+                lookup!(Int16Array, |v| set.contains(&(v as i64)))
+            }
+            (InListSet::Int(set), DataType::Int32) => {
+                lookup!(Int32Array, |v| set.contains(&(v as i64)))
+            }
+            (InListSet::Int(set), DataType::Int64) => {
+                lookup!(Int64Array, |v| set.contains(&v))
+            }
+            (InListSet::Int(set), DataType::Date32) => {
+                lookup!(Date32Array, |v| set.contains(&(v as i64)))
+            }
+            (InListSet::Int(set), DataType::Date64) => {
+                lookup!(Date64Array, |v| set.contains(&v))
+            }
+            (InListSet::Float(set), DataType::Float32) => {
+                lookup!(Float32Array, |v| set
+                    .contains(&normalized_float_bits(v as f64)))
+            }
+            (InListSet::Float(set), DataType::Float64) => {
+                lookup!(Float64Array, |v| set.contains(&normalized_float_bits(v)))
+            }
+            (InListSet::Utf8(set), DataType::Utf8) => {
+                lookup!(StringArray, |v| set.contains(v))
+            }
+            (_, data_type) => {
+                return Err(DataFusionError::Execution(format!(
+                    "SparkInListExpr: unsupported value type {:?} of {}",
+                    data_type, self
+                )));
+            }
+        };
+        Ok(ColumnarValue::Array(Arc::new(result)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use datafusion::arrow::datatypes::Field;
+    use datafusion::physical_plan::expressions::{col, lit};
+
+    use super::*;
+
+    #[test]
+    fn test_spark_in_list() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int32, true)]));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![Arc::new(Int32Array::from(vec![Some(1), Some(2), None]))],
+        )?;
+        let evaluate = |list: Vec<ScalarValue>, negated| -> Result<BooleanArray> {
+            let list = list.into_iter().map(lit).collect::<Vec<_>>();
+            let expr = SparkInListExpr::try_new_with_literals(
+                col("a", &schema)?,
+                &list,
+                negated,
+            )
+            .unwrap();
+            let result = expr.evaluate(&batch)?.into_array(batch.num_rows());
+            Ok(result
+                .as_any()
+                .downcast_ref::<BooleanArray>()
+                .unwrap()
+                .clone())
+        };
+
+        let list = vec![ScalarValue::Int32(Some(1)), ScalarValue::Int32(Some(3))];
+        assert_eq!(
+            evaluate(list.clone(), false)?,
+            BooleanArray::from(vec![Some(true), Some(false), None])
+        );
+        assert_eq!(
+            evaluate(list, true)?,
+            BooleanArray::from(vec![Some(false), Some(true), None])
+        );
+
+        // not found in a list with null yields null
+        let list = vec![ScalarValue::Int32(Some(1)), ScalarValue::Int32(None)];
+        assert_eq!(
+            evaluate(list, false)?,
+            BooleanArray::from(vec![Some(true), None, None])
+        );
+
+        // floats: NaN equals NaN like spark
+        let list = vec![lit(ScalarValue::Float64(Some(f64::NAN)))];
+        let expr =
+            SparkInListExpr::try_new_with_literals(col("a", &schema)?, &list, false);
+        let contains_nan = match expr.unwrap().set.as_ref() {
+            InListSet::Float(set) => set.contains(&normalized_float_bits(-f64::NAN)),
+            _ => false,
+        };
+        assert!(contains_nan);
+        assert_eq!(normalized_float_bits(-0.0), normalized_float_bits(0.0));
+
+        // non-literals are not supported
+        assert!(SparkInListExpr::try_new_with_literals(
+            col("a", &schema)?,
+            &[col("a", &schema)?],
+            false
+        )
+        .is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,307 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Defines spark's `date + interval` (DateAddInterval) and
+//! `timestamp + interval` (TimeAdd) of literal calendar intervals.
+//!
+//! like DateTimeUtils.timestampAddInterval(), months and days are added to
+//! the local date time of the session time zone, with the day of month
+//! clamped to the last day of the resulting month, then microseconds are
+//! added to the resulting instant. natively supported time zones are fixed
+//! offsets, passed as offset seconds. dates do not depend on time zones and
+//! only support intervals without microseconds.
+
+use std::any::Any;
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+
+use datafusion::arrow::array::{
+    Array, Date32Array, TimestampMicrosecondArray, TimestampMillisecondArray,
+    TimestampNanosecondArray, TimestampSecondArray,
+};
+use datafusion::arrow::datatypes::{DataType, Schema, TimeUnit};
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::physical_plan::{ColumnarValue, PhysicalExpr};
+
+const MICROS_PER_DAY: i64 = 86_400_000_000;
+
+/// Spark's CalendarInterval
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SparkInterval {
+    pub months: i32,
+    pub days: i32,
+    pub microseconds: i64,
+}
+
+/// Adds a literal interval to a date or timestamp
+#[derive(Debug)]
+pub struct SparkIntervalAddExpr {
+    expr: Arc<dyn PhysicalExpr>,
+    interval: SparkInterval,
+    zone_offset_seconds: i32,
+}
+
+impl SparkIntervalAddExpr {
+    pub fn new(
+        expr: Arc<dyn PhysicalExpr>,
+        interval: SparkInterval,
+        zone_offset_seconds: i32,
+    ) -> Self {
+        Self {
+            expr,
+            interval,
+            zone_offset_seconds,
+        }
+    }
+
+    pub fn expr(&self) -> &Arc<dyn PhysicalExpr> {
+        &self.expr
+    }
+
+    pub fn interval(&self) -> SparkInterval {
+        self.interval
+    }
+
+    pub fn zone_offset_seconds(&self) -> i32 {
+        self.zone_offset_seconds
+    }
+
+    fn add_to_date(&self, epoch_day: i32) -> Result<i32> {
+        let SparkInterval { months, days, .. } = self.interval;
+        let result = add_months(epoch_day as i64, months) + days as i64;
+        i32::try_from(result).map_err(|_| overflow_error())
+    }
+
+    fn add_to_timestamp_micros(&self, micros: i64) -> Result<i64> {
+        let SparkInterval {
+            months,
+            days,
+            microseconds,
+        } = self.interval;
+        if months == 0 && days == 0 {
+            return micros.checked_add(microseconds).ok_or_else(overflow_error);
+        }
+        let offset_micros = self.zone_offset_seconds as i64 * 1_000_000;
+        let local_micros = micros + offset_micros;
+        let epoch_day = local_micros.div_euclid(MICROS_PER_DAY);
+        let micros_of_day = local_micros.rem_euclid(MICROS_PER_DAY);
+        let epoch_day = add_months(epoch_day, months) + days as i64;
+        epoch_day
+            .checked_mul(MICROS_PER_DAY)
+            .and_then(|local_micros| local_micros.checked_add(micros_of_day))
+            .and_then(|local_micros| local_micros.checked_sub(offset_micros))
+            .and_then(|micros| micros.checked_add(microseconds))
+            .ok_or_else(overflow_error)
+    }
+}
+
+fn overflow_error() -> DataFusionError {
+    DataFusionError::Execution("SparkIntervalAddExpr: result overflows".to_owned())
+}
+
+/// same as LocalDate.plusMonths(), clamping the day of month
+fn add_months(epoch_day: i64, months: i32) -> i64 {
+    if months == 0 {
+        return epoch_day;
+    }
+    let (year, month, day) = civil_from_days(epoch_day);
+    let total_months = year * 12 + (month as i64 - 1) + months as i64;
+    let year = total_months.div_euclid(12);
+    let month = (total_months.rem_euclid(12) + 1) as u32;
+    days_from_civil(year, month, day.min(days_in_month(year, month)))
+}
+
+fn days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// returns (year, month, day) of days since 1970-01-01 in the proleptic
+/// gregorian calendar, see http://howardhinnant.github.io/date_algorithms.html
+fn civil_from_days(epoch_day: i64) -> (i64, u32, u32) {
+    let z = epoch_day + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + (month <= 2) as i64;
+    (year, month, day)
+}
+
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = year - (month <= 2) as i64;
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = (month as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+impl Display for SparkIntervalAddExpr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let SparkInterval {
+            months,
+            days,
+            microseconds,
+        } = self.interval;
+        write!(
+            f,
+            "{} + interval({} months, {} days, {} microseconds)",
+            self.expr, months, days, microseconds
+        )
+    }
+}
+
+impl PhysicalExpr for SparkIntervalAddExpr {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn data_type(&self, input_schema: &Schema) -> Result<DataType> {
+        self.expr.data_type(input_schema)
+    }
+
+    fn nullable(&self, input_schema: &Schema) -> Result<bool> {
+        self.expr.nullable(input_schema)
+    }
+
+    fn evaluate(&self, batch: &RecordBatch) -> Result<ColumnarValue> {
+        let input = self.expr.evaluate(batch)?.into_array(batch.num_rows());
+
+        macro_rules! add_to_timestamps {
+            ($array_ty:ty, $to_micros:expr, $from_micros:expr, $tz:expr) => {{
+                let input = input.as_any().downcast_ref::<$array_ty>().unwrap();
+                let (to_micros, from_micros) = ($to_micros, $from_micros);
+                let values = input
+                    .iter()
+                    .map(|v| {
+                        v.map(|v| self.add_to_timestamp_micros(to_micros(v)))
+                            .transpose()
+                            .map(|v| v.map(from_micros))
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                Arc::new(<$array_ty>::from_opt_vec(values, $tz.clone()))
+            }};
+        }
+        let result: Arc<dyn Array> = match input.data_type() {
+            DataType::Date32 => {
+                if self.interval.microseconds != 0 {
+                    return Err(DataFusionError::Execution(format!(
+                        "{}: cannot add microseconds to dates",
+                        self
+                    )));
+                }
+                let input = input.as_any().downcast_ref::<Date32Array>().unwrap();
+                let result = input
+                    .iter()
+                    .map(|v| v.map(|v| self.add_to_date(v)).transpose())
+                    .collect::<Result<Date32Array>>()?;
+                Arc::new(result)
+            }
+            DataType::Timestamp(TimeUnit::Second, tz) => {
+                add_to_timestamps!(
+                    TimestampSecondArray,
+                    |v: i64| v * 1_000_000,
+                    |v: i64| v.div_euclid(1_000_000),
+                    tz
+                )
+            }
+            DataType::Timestamp(TimeUnit::Millisecond, tz) => {
+                add_to_timestamps!(
+                    TimestampMillisecondArray,
+                    |v: i64| v * 1_000,
+                    |v: i64| v.div_euclid(1_000),
+                    tz
+                )
+            }
+            DataType::Timestamp(TimeUnit::Microsecond, tz) => {
+                add_to_timestamps!(TimestampMicrosecondArray, |v| v, |v| v, tz)
+            }
+            DataType::Timestamp(TimeUnit::Nanosecond, tz) => {
+                add_to_timestamps!(
+                    TimestampNanosecondArray,
+                    |v: i64| v.div_euclid(1_000),
+                    |v: i64| v * 1_000,
+                    tz
+                )
+            }
+            other => {
+                return Err(DataFusionError::Execution(format!(
+                    "SparkIntervalAddExpr: unsupported input type {:?}",
+                    other
+                )));
+            }
+        };
+        Ok(ColumnarValue::Array(result))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use datafusion::physical_plan::expressions::Column;
+
+    use super::*;
+
+    #[test]
+    fn test_spark_interval_add() -> Result<()> {
+        let date = |y, m, d| days_from_civil(y, m, d);
+        assert_eq!(civil_from_days(0), (1970, 1, 1));
+        assert_eq!(civil_from_days(date(2020, 2, 29)), (2020, 2, 29));
+        assert_eq!(civil_from_days(date(1900, 3, 1) - 1), (1900, 2, 28));
+
+        // the day of month is clamped like LocalDate.plusMonths()
+        assert_eq!(add_months(date(2020, 1, 31), 1), date(2020, 2, 29));
+        assert_eq!(add_months(date(2021, 3, 31), -13), date(2020, 2, 29));
+        assert_eq!(add_months(date(1969, 12, 15), 14), date(1971, 2, 15));
+
+        let interval = |months, days, microseconds| SparkInterval {
+            months,
+            days,
+            microseconds,
+        };
+        let input = Arc::new(Column::new("a", 0));
+        let expr = SparkIntervalAddExpr::new(input.clone(), interval(1, 1, 0), 0);
+        assert_eq!(
+            expr.add_to_date(date(2020, 1, 31) as i32)?,
+            date(2020, 3, 1) as i32
+        );
+
+        // 2020-01-30T20:00Z is 2020-01-31 04:00 in +08:00, so that adding a
+        // month is clamped to different days
+        let hour = 3_600_000_000;
+        let micros = date(2020, 1, 30) * MICROS_PER_DAY + 20 * hour;
+        let utc = SparkIntervalAddExpr::new(input.clone(), interval(1, 0, hour), 0);
+        let utc8 = SparkIntervalAddExpr::new(input.clone(), interval(1, 0, hour), 28800);
+        assert_eq!(
+            utc.add_to_timestamp_micros(micros)?,
+            date(2020, 2, 29) * MICROS_PER_DAY + 21 * hour
+        );
+        assert_eq!(
+            utc8.add_to_timestamp_micros(micros)?,
+            date(2020, 2, 28) * MICROS_PER_DAY + 21 * hour
+        );
+        let utc8 = SparkIntervalAddExpr::new(input, interval(0, 0, hour), 28800);
+        assert_eq!(utc8.add_to_timestamp_micros(micros)?, micros + hour);
+        Ok(())
+    }
+}
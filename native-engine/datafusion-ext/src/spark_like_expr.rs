@@ -0,0 +1,355 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Defines spark's `str LIKE pattern ESCAPE c` and `str RLIKE regex`.
+//!
+//! LIKE patterns are translated into regexes the same way as spark's
+//! StringUtils.escapeLikeRegex(), and must match the whole string. RLIKE
+//! regexes are java regexes matching any substring. java's predefined
+//! classes \d, \w and \s are ASCII-only, so they are rewritten into ASCII
+//! classes. other java constructs not supported by the regex crate (e.g.
+//! backreferences and lookarounds) fail the conversion, so that the
+//! expression falls back to spark.
+//!
+//! literal patterns are compiled once. other patterns are compiled per
+//! distinct value and cached in the expression.
+
+use std::any::Any;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::sync::{Arc, Mutex};
+
+use datafusion::arrow::array::{Array, BooleanArray, StringArray};
+use datafusion::arrow::datatypes::{DataType, Schema};
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::physical_plan::expressions::Literal;
+use datafusion::physical_plan::{ColumnarValue, PhysicalExpr};
+use datafusion::scalar::ScalarValue;
+use regex::Regex;
+
+/// max number of cached regexes of non-literal patterns, the cache is
+/// cleared once exceeded
+const MAX_CACHED_REGEXES: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SparkLikeKind {
+    Like { escape_char: char },
+    RLike,
+}
+
+/// Spark's Like/RLike of a string against a pattern
+#[derive(Debug)]
+pub struct SparkLikeExpr {
+    expr: Arc<dyn PhysicalExpr>,
+    pattern: Arc<dyn PhysicalExpr>,
+    kind: SparkLikeKind,
+    literal_regex: Option<Arc<Regex>>,
+    cached_regexes: Mutex<HashMap<String, Arc<Regex>>>,
+}
+
+impl SparkLikeExpr {
+    /// compiles literal patterns, failing if the pattern is invalid or not
+    /// supported
+    pub fn try_new(
+        expr: Arc<dyn PhysicalExpr>,
+        pattern: Arc<dyn PhysicalExpr>,
+        kind: SparkLikeKind,
+    ) -> Result<Self> {
+        let literal_regex = match pattern.as_any().downcast_ref::<Literal>() {
+            Some(literal) => match literal.value() {
+                ScalarValue::Utf8(Some(pattern)) => Some(compile(pattern, kind)?),
+                _ => None,
+            },
+            None => None,
+        };
+        Ok(Self {
+            expr,
+            pattern,
+            kind,
+            literal_regex,
+            cached_regexes: Mutex::default(),
+        })
+    }
+
+    pub fn expr(&self) -> &Arc<dyn PhysicalExpr> {
+        &self.expr
+    }
+
+    pub fn pattern(&self) -> &Arc<dyn PhysicalExpr> {
+        &self.pattern
+    }
+
+    pub fn kind(&self) -> SparkLikeKind {
+        self.kind
+    }
+
+    /// returns the same expression of other inputs, e.g. bound to a schema,
+    /// keeping the compiled literal pattern
+    pub fn with_exprs(
+        &self,
+        expr: Arc<dyn PhysicalExpr>,
+        pattern: Arc<dyn PhysicalExpr>,
+    ) -> Self {
+        Self {
+            expr,
+            pattern,
+            kind: self.kind,
+            literal_regex: self.literal_regex.clone(),
+            cached_regexes: Mutex::default(),
+        }
+    }
+
+    fn regex_of(&self, pattern: &str) -> Result<Arc<Regex>> {
+        let mut cached_regexes = self.cached_regexes.lock().unwrap();
+        if let Some(regex) = cached_regexes.get(pattern) {
+            return Ok(regex.clone());
+        }
+        if cached_regexes.len() >= MAX_CACHED_REGEXES {
+            cached_regexes.clear();
+        }
+        let regex = compile(pattern, self.kind)?;
+        cached_regexes.insert(pattern.to_owned(), regex.clone());
+        Ok(regex)
+    }
+}
+
+fn compile(pattern: &str, kind: SparkLikeKind) -> Result<Arc<Regex>> {
+    let regex = match kind {
+        SparkLikeKind::Like { escape_char } => {
+            format!(r"\A(?s:{})\z", like_to_regex(pattern, escape_char)?)
+        }
+        SparkLikeKind::RLike => java_to_regex(pattern),
+    };
+    Regex::new(&regex).map(Arc::new).map_err(|err| {
+        DataFusionError::Plan(format!(
+            "unsupported pattern '{}' of {:?}: {}",
+            pattern, kind, err
+        ))
+    })
+}
+
+/// same as StringUtils.escapeLikeRegex() of spark
+fn like_to_regex(pattern: &str, escape_char: char) -> Result<String> {
+    let invalid = |message: String| {
+        DataFusionError::Execution(format!(
+            "the pattern '{}' is invalid, {}",
+            pattern, message
+        ))
+    };
+    let mut regex = String::with_capacity(pattern.len() * 2);
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if c == escape_char => match chars.next() {
+                Some(c) if c == '_' || c == '%' || c == escape_char => {
+                    regex.push_str(&regex::escape(&c.to_string()));
+                }
+                Some(c) => {
+                    return Err(invalid(format!(
+                        "the escape character is not allowed to precede '{}'",
+                        c
+                    )));
+                }
+                None => {
+                    return Err(invalid(
+                        "it is not allowed to end with the escape character".to_owned(),
+                    ));
+                }
+            },
+            '_' => regex.push('.'),
+            '%' => regex.push_str(".*"),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    Ok(regex)
+}
+
+/// rewrites java's ASCII-only predefined classes into ASCII classes
+fn java_to_regex(pattern: &str) -> String {
+    let mut regex = String::with_capacity(pattern.len());
+    let mut in_class = false;
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match (chars.next(), in_class) {
+                (Some('d'), false) => regex.push_str("[0-9]"),
+                (Some('d'), true) => regex.push_str("0-9"),
+                (Some('w'), false) => regex.push_str("[a-zA-Z0-9_]"),
+                (Some('w'), true) => regex.push_str("a-zA-Z0-9_"),
+                (Some('s'), false) => regex.push_str(r"[ \t\n\x0B\f\r]"),
+                (Some('s'), true) => regex.push_str(r" \t\n\x0B\f\r"),
+                (Some('D'), false) => regex.push_str("[^0-9]"),
+                (Some('W'), false) => regex.push_str("[^a-zA-Z0-9_]"),
+                (Some('S'), false) => regex.push_str(r"[^ \t\n\x0B\f\r]"),
+                (Some(c), _) => {
+                    regex.push('\\');
+                    regex.push(c);
+                }
+                (None, _) => regex.push('\\'),
+            },
+            '[' => {
+                in_class = true;
+                regex.push(c);
+            }
+            ']' => {
+                in_class = false;
+                regex.push(c);
+            }
+            c => regex.push(c),
+        }
+    }
+    regex
+}
+
+impl Display for SparkLikeExpr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.kind {
+            SparkLikeKind::Like { escape_char } => {
+                write!(
+                    f,
+                    "{} LIKE {} ESCAPE '{}'",
+                    self.expr, self.pattern, escape_char
+                )
+            }
+            SparkLikeKind::RLike => write!(f, "{} RLIKE {}", self.expr, self.pattern),
+        }
+    }
+}
+
+impl PhysicalExpr for SparkLikeExpr {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn data_type(&self, _input_schema: &Schema) -> Result<DataType> {
+        Ok(DataType::Boolean)
+    }
+
+    fn nullable(&self, input_schema: &Schema) -> Result<bool> {
+        Ok(self.expr.nullable(input_schema)? || self.pattern.nullable(input_schema)?)
+    }
+
+    fn evaluate(&self, batch: &RecordBatch) -> Result<ColumnarValue> {
+        let num_rows = batch.num_rows();
+        let input = self.expr.evaluate(batch)?.into_array(num_rows);
+        let input = downcast_string_array(&input)?;
+
+        let result = match &self.literal_regex {
+            Some(regex) => input
+                .iter()
+                .map(|s| s.map(|s| regex.is_match(s)))
+                .collect::<BooleanArray>(),
+            None => {
+                let patterns = self.pattern.evaluate(batch)?.into_array(num_rows);
+                let patterns = downcast_string_array(&patterns)?;
+                input
+                    .iter()
+                    .zip(patterns.iter())
+                    .map(|(s, pattern)| match (s, pattern) {
+                        (Some(s), Some(pattern)) => {
+                            Ok(Some(self.regex_of(pattern)?.is_match(s)))
+                        }
+                        _ => Ok(None),
+                    })
+                    .collect::<Result<BooleanArray>>()?
+            }
+        };
+        Ok(ColumnarValue::Array(Arc::new(result)))
+    }
+}
+
+fn downcast_string_array(array: &dyn Array) -> Result<&StringArray> {
+    array.as_any().downcast_ref::<StringArray>().ok_or_else(|| {
+        DataFusionError::Execution(format!(
+            "SparkLikeExpr: unsupported input type {:?}",
+            array.data_type()
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use datafusion::arrow::datatypes::Field;
+    use datafusion::physical_plan::expressions::{col, lit};
+
+    use super::*;
+
+    #[test]
+    fn test_spark_like() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("s", DataType::Utf8, true),
+            Field::new("p", DataType::Utf8, true),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![
+                Arc::new(StringArray::from(vec![
+                    Some("a_b%c"),
+                    Some("axbyc"),
+                    Some("a1\nc"),
+                    None,
+                ])),
+                Arc::new(StringArray::from(vec![
+                    Some("b%"),
+                    Some("c$"),
+                    None,
+                    Some("a"),
+                ])),
+            ],
+        )?;
+        let evaluate = |pattern, kind| -> Result<BooleanArray> {
+            let expr = SparkLikeExpr::try_new(col("s", &schema)?, pattern, kind)?;
+            let result = expr.evaluate(&batch)?.into_array(batch.num_rows());
+            Ok(result
+                .as_any()
+                .downcast_ref::<BooleanArray>()
+                .unwrap()
+                .clone())
+        };
+        let like = |escape_char| SparkLikeKind::Like { escape_char };
+        let utf8 = |s: &str| lit(ScalarValue::Utf8(Some(s.to_owned())));
+
+        assert_eq!(
+            evaluate(utf8("a_b%"), like('\\'))?,
+            BooleanArray::from(vec![Some(true), Some(true), Some(false), None])
+        );
+        assert_eq!(
+            evaluate(utf8("a#_b#%_"), like('#'))?,
+            BooleanArray::from(vec![Some(true), Some(false), Some(false), None])
+        );
+        assert_eq!(
+            evaluate(utf8("a__c"), like('\\'))?,
+            BooleanArray::from(vec![Some(false), Some(false), Some(true), None])
+        );
+        assert!(evaluate(utf8("a#b"), like('#')).is_err());
+        assert!(evaluate(utf8("ab#"), like('#')).is_err());
+
+        // rlike matches substrings, \d is ASCII-only like java
+        assert_eq!(
+            evaluate(utf8(r"b\d?y"), SparkLikeKind::RLike)?,
+            BooleanArray::from(vec![Some(false), Some(true), Some(false), None])
+        );
+        assert_eq!(java_to_regex(r"[\d]\s\D"), r"[0-9][ \t\n\x0B\f\r][^0-9]");
+        assert!(!compile(r"\d", SparkLikeKind::RLike)?.is_match("\u{0663}"));
+        assert!(evaluate(utf8(r"(a)\1"), SparkLikeKind::RLike).is_err());
+
+        // non-literal patterns
+        assert_eq!(
+            evaluate(col("p", &schema)?, SparkLikeKind::RLike)?,
+            BooleanArray::from(vec![Some(true), Some(true), None, None])
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,250 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Reserves memory of native operators from spark's TaskMemoryManager
+
+use std::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+use std::sync::{Arc, Weak};
+
+use dashmap::DashMap;
+use datafusion::error::{DataFusionError, Result};
+use jni::objects::{GlobalRef, JObject};
+use jni::sys::{jboolean, jlong, JNI_TRUE};
+use once_cell::sync::OnceCell;
+
+use crate::jni_call;
+use crate::jni_call_static;
+use crate::jni_new_global_ref;
+use crate::jni_new_string;
+
+/// Memory statistics of a reservation, reported when memory is overcommitted
+/// or dumped by the JVM side
+#[derive(Debug, Default)]
+struct ReservationStats {
+    name: String,
+    reserved: AtomicUsize,
+    peak: AtomicUsize,
+    spill_count: AtomicUsize,
+}
+
+/// stats of living reservations, grouped by task attempt id
+fn task_reservation_stats() -> &'static DashMap<i64, Vec<Weak<ReservationStats>>> {
+    static STATS: OnceCell<DashMap<i64, Vec<Weak<ReservationStats>>>> = OnceCell::new();
+    STATS.get_or_init(DashMap::new)
+}
+
+/// A reservation of memory in spark's TaskMemoryManager, backed by a
+/// JVM-side BlazeMemoryConsumer.
+///
+/// spark may ask the consumer to spill when other consumers of the task are
+/// short of memory. the JVM side only records the request (spilling on the
+/// calling thread may deadlock with the native operator), it is then taken
+/// by the next `acquire()` and the native operator spills cooperatively.
+pub struct SparkMemoryReservation {
+    consumer: GlobalRef,
+    task_attempt_id: i64,
+    stats: Arc<ReservationStats>,
+}
+
+impl SparkMemoryReservation {
+    /// creates a reservation for the current task, returns None if spark
+    /// memory integration is disabled or no task context is available
+    pub fn try_new(name: String) -> Result<Option<Self>> {
+        let consumer = jni_call_static!(JniBridge.newMemoryConsumer() -> JObject)?;
+        if consumer.is_null() {
+            return Ok(None);
+        }
+        let task_attempt_id =
+            jni_call_static!(JniBridge.getTaskAttemptId() -> jlong)? as i64;
+        let stats = Arc::new(ReservationStats {
+            name,
+            ..Default::default()
+        });
+        task_reservation_stats()
+            .entry(task_attempt_id)
+            .or_default()
+            .push(Arc::downgrade(&stats));
+
+        Ok(Some(Self {
+            consumer: jni_new_global_ref!(consumer)?,
+            task_attempt_id,
+            stats,
+        }))
+    }
+
+    /// acquires memory from spark, returns false if the native operator should
+    /// spill, either because the acquired memory is less than requested, or
+    /// spark has requested a spill since last call.
+    /// memory acquired is kept in the reservation even if not fully granted.
+    pub fn acquire(&self, bytes: usize) -> Result<bool> {
+        let consumer = self.consumer.as_obj();
+        let granted = jni_call!(BlazeMemoryConsumer(consumer).acquire(bytes as jlong) -> jlong)?
+            as usize;
+        let reserved = self.stats.reserved.fetch_add(granted, SeqCst) + granted;
+        self.stats.peak.fetch_max(reserved, SeqCst);
+
+        let spill_requested = jni_call!(BlazeMemoryConsumer(consumer).takeSpillRequest() -> jboolean)?
+            == JNI_TRUE;
+        Ok(granted >= bytes && !spill_requested)
+    }
+
+    /// releases all reserved memory back to spark
+    pub fn release_all(&self) -> Result<()> {
+        let reserved = self.stats.reserved.swap(0, SeqCst);
+        if reserved > 0 {
+            let consumer = self.consumer.as_obj();
+            jni_call!(BlazeMemoryConsumer(consumer).release(reserved as jlong) -> ())?;
+        }
+        Ok(())
+    }
+
+    pub fn reserved(&self) -> usize {
+        self.stats.reserved.load(SeqCst)
+    }
+
+    pub fn record_spill(&self) {
+        self.stats.spill_count.fetch_add(1, SeqCst);
+    }
+
+    /// reports memory of all reservations in the task to JVM side when the
+    /// requested memory cannot be acquired even after spilling.
+    /// returns an error if JVM side decides to retry the task with a smaller
+    /// batch size, otherwise the native operator continues with overcommitted
+    /// memory.
+    pub fn report_overcommit(&self, requested: usize) -> Result<()> {
+        let report = self.overcommit_report(requested);
+        log::warn!("native memory overcommitted: {}", report);
+
+        let consumer = self.consumer.as_obj();
+        let retry = jni_call!(
+            BlazeMemoryConsumer(consumer).reportOvercommit(
+                jni_new_string!(&report)?
+            ) -> jboolean
+        )? == JNI_TRUE;
+        if retry {
+            return Err(DataFusionError::ResourcesExhausted(format!(
+                "native memory overcommitted, retrying with smaller batch size: {}",
+                report
+            )));
+        }
+        Ok(())
+    }
+
+    /// returns a json report of all living reservations in the task
+    fn overcommit_report(&self, requested: usize) -> String {
+        format!(
+            concat!(
+                "{{\"task_attempt_id\":{},\"requester\":\"{}\",",
+                "\"requested\":{},\"operators\":[{}]}}",
+            ),
+            self.task_attempt_id,
+            self.stats.name.escape_default(),
+            requested,
+            operators_report(self.task_attempt_id),
+        )
+    }
+}
+
+/// returns a json report of living reservations of all tasks, sorted by task
+/// attempt id
+pub fn reservations_report() -> String {
+    let mut task_attempt_ids = task_reservation_stats()
+        .iter()
+        .map(|entry| *entry.key())
+        .collect::<Vec<_>>();
+    task_attempt_ids.sort_unstable();
+
+    let tasks = task_attempt_ids
+        .into_iter()
+        .map(|task_attempt_id| {
+            format!(
+                "{{\"task_attempt_id\":{},\"operators\":[{}]}}",
+                task_attempt_id,
+                operators_report(task_attempt_id),
+            )
+        })
+        .collect::<Vec<_>>();
+    format!("[{}]", tasks.join(","))
+}
+
+/// returns comma-separated json objects of living reservations in the task
+fn operators_report(task_attempt_id: i64) -> String {
+    task_reservation_stats()
+        .get(&task_attempt_id)
+        .map(|stats| {
+            stats
+                .iter()
+                .filter_map(Weak::upgrade)
+                .map(|stats| {
+                    format!(
+                        concat!(
+                            "{{\"name\":\"{}\",\"reserved\":{},",
+                            "\"peak\":{},\"spill_count\":{}}}",
+                        ),
+                        stats.name.escape_default(),
+                        stats.reserved.load(SeqCst),
+                        stats.peak.load(SeqCst),
+                        stats.spill_count.load(SeqCst),
+                    )
+                })
+                .collect::<Vec<_>>()
+                .join(",")
+        })
+        .unwrap_or_default()
+}
+
+impl Drop for SparkMemoryReservation {
+    fn drop(&mut self) {
+        if let Err(err) = self.release_all() {
+            log::warn!("failed to release spark memory reservation: {:?}", err);
+        }
+        let stats_ptr = Arc::as_ptr(&self.stats);
+        if let Some(mut stats) = task_reservation_stats().get_mut(&self.task_attempt_id) {
+            stats.retain(|stats| stats.strong_count() > 0 && stats.as_ptr() != stats_ptr);
+        }
+        task_reservation_stats()
+            .remove_if(&self.task_attempt_id, |_, stats| stats.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reservations_report() {
+        let stats = Arc::new(ReservationStats {
+            name: "SortExec".to_owned(),
+            reserved: AtomicUsize::new(100),
+            peak: AtomicUsize::new(200),
+            spill_count: AtomicUsize::new(1),
+        });
+        let dropped = Arc::new(ReservationStats::default());
+        task_reservation_stats()
+            .entry(-1)
+            .or_default()
+            .extend([Arc::downgrade(&stats), Arc::downgrade(&dropped)]);
+        std::mem::drop(dropped);
+
+        assert_eq!(
+            operators_report(-1),
+            "{\"name\":\"SortExec\",\"reserved\":100,\"peak\":200,\"spill_count\":1}",
+        );
+        assert!(reservations_report().contains(&format!(
+            "{{\"task_attempt_id\":-1,\"operators\":[{}]}}",
+            operators_report(-1),
+        )));
+        assert_eq!(operators_report(-2), "");
+    }
+}
@@ -0,0 +1,266 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Defines spark_partition_id() and monotonically_increasing_id().
+//!
+//! the partition id is the executed partition of the operator evaluating the
+//! expression, see partition_scope_exec. monotonically_increasing_id() puts
+//! the partition id in the upper 31 bits and the row number within the
+//! partition in the lower 33 bits, like spark. expressions are shared by
+//! tasks through the plan cache, so the row number is kept in the partition
+//! scope instead of the expression.
+
+use std::any::Any;
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use datafusion::arrow::array::Int64Array;
+use datafusion::arrow::datatypes::{DataType, Schema};
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::physical_plan::{ColumnarValue, PhysicalExpr};
+use datafusion::scalar::ScalarValue;
+
+use crate::partition_scope_exec::PartitionScope;
+
+static NEXT_EXPR_ID: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SparkPartitionExprKind {
+    SparkPartitionId,
+    MonotonicallyIncreasingId,
+}
+
+/// Evaluates spark_partition_id() or monotonically_increasing_id()
+#[derive(Debug)]
+pub struct SparkPartitionExpr {
+    kind: SparkPartitionExprKind,
+    expr_id: usize,
+}
+
+impl SparkPartitionExpr {
+    pub fn new(kind: SparkPartitionExprKind) -> Self {
+        Self {
+            kind,
+            expr_id: NEXT_EXPR_ID.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
+    pub fn kind(&self) -> SparkPartitionExprKind {
+        self.kind
+    }
+}
+
+impl Display for SparkPartitionExpr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.kind {
+            SparkPartitionExprKind::SparkPartitionId => write!(f, "spark_partition_id()"),
+            SparkPartitionExprKind::MonotonicallyIncreasingId => {
+                write!(f, "monotonically_increasing_id()")
+            }
+        }
+    }
+}
+
+impl PhysicalExpr for SparkPartitionExpr {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn data_type(&self, _input_schema: &Schema) -> Result<DataType> {
+        Ok(match self.kind {
+            SparkPartitionExprKind::SparkPartitionId => DataType::Int32,
+            SparkPartitionExprKind::MonotonicallyIncreasingId => DataType::Int64,
+        })
+    }
+
+    fn nullable(&self, _input_schema: &Schema) -> Result<bool> {
+        Ok(false)
+    }
+
+    fn evaluate(&self, batch: &RecordBatch) -> Result<ColumnarValue> {
+        let scope = PartitionScope::current().ok_or_else(|| {
+            DataFusionError::Execution(format!(
+                "{} is evaluated outside of a partition scope",
+                self
+            ))
+        })?;
+        let partition_id = scope.partition() as i32;
+
+        match self.kind {
+            SparkPartitionExprKind::SparkPartitionId => Ok(ColumnarValue::Scalar(
+                ScalarValue::Int32(Some(partition_id)),
+            )),
+            SparkPartitionExprKind::MonotonicallyIncreasingId => {
+                let num_rows = batch.num_rows();
+                let first_row = scope.with_state(
+                    self.expr_id,
+                    || 0i64,
+                    |next_row| {
+                        let first_row = *next_row;
+                        *next_row += num_rows as i64;
+                        first_row
+                    },
+                );
+                Ok(ColumnarValue::Array(Arc::new(
+                    monotonically_increasing_ids(partition_id, first_row, num_rows),
+                )))
+            }
+        }
+    }
+}
+
+fn monotonically_increasing_ids(
+    partition_id: i32,
+    first_row: i64,
+    num_rows: usize,
+) -> Int64Array {
+    let base = ((partition_id as i64) << 33) + first_row;
+    Int64Array::from_iter_values((0..num_rows as i64).map(|i| base + i))
+}
+
+#[cfg(test)]
+mod tests {
+    use datafusion::arrow::array::Int32Array;
+    use datafusion::arrow::datatypes::Field;
+    use datafusion::physical_plan::common::collect;
+    use datafusion::physical_plan::memory::MemoryExec;
+    use datafusion::physical_plan::projection::ProjectionExec;
+    use datafusion::physical_plan::union::UnionExec;
+    use datafusion::physical_plan::ExecutionPlan;
+    use datafusion::prelude::SessionContext;
+    use futures::StreamExt;
+
+    use crate::partition_scope_exec::PartitionScopeExec;
+
+    use super::*;
+
+    /// spark_partition_id() and monotonically_increasing_id() of 2 partitions
+    /// of 2 batches of 2 rows
+    fn partition_exprs_exec() -> Result<Arc<dyn ExecutionPlan>> {
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int32, false)]));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![Arc::new(Int32Array::from(vec![1, 2]))],
+        )?;
+        let partition = vec![batch.clone(), batch];
+        let input = Arc::new(MemoryExec::try_new(
+            &[partition.clone(), partition],
+            schema,
+            None,
+        )?);
+        let exprs: Vec<(Arc<dyn PhysicalExpr>, String)> = vec![
+            (
+                Arc::new(SparkPartitionExpr::new(
+                    SparkPartitionExprKind::SparkPartitionId,
+                )),
+                "partition_id".to_owned(),
+            ),
+            (
+                Arc::new(SparkPartitionExpr::new(
+                    SparkPartitionExprKind::MonotonicallyIncreasingId,
+                )),
+                "id".to_owned(),
+            ),
+        ];
+        let projection = Arc::new(ProjectionExec::try_new(exprs, input)?);
+        Ok(Arc::new(PartitionScopeExec::new(projection)))
+    }
+
+    /// returns (partition id, monotonically increasing id) of the rows
+    fn evaluated_ids(batches: &[RecordBatch]) -> Vec<(i32, i64)> {
+        batches
+            .iter()
+            .flat_map(|batch| {
+                let partition_ids = batch.column(0).as_any().downcast_ref::<Int32Array>();
+                let ids = batch.column(1).as_any().downcast_ref::<Int64Array>();
+                let partition_ids = partition_ids.unwrap().values().to_vec();
+                partition_ids
+                    .into_iter()
+                    .zip(ids.unwrap().values().to_vec())
+            })
+            .collect()
+    }
+
+    /// ids of the rows of a partition, numbered from 0
+    fn expected_ids(partition_id: i32, num_rows: i64) -> Vec<(i32, i64)> {
+        let base = (partition_id as i64) << 33;
+        (0..num_rows).map(|i| (partition_id, base + i)).collect()
+    }
+
+    #[test]
+    fn test_partition_exprs_under_union() -> Result<()> {
+        // the partitions of a union are those of its inputs in turn, and the
+        // ids are of the partition of the input, shared by both inputs
+        let input = partition_exprs_exec()?;
+        let union = UnionExec::new(vec![input.clone(), input]);
+        assert_eq!(union.output_partitioning().partition_count(), 4);
+
+        let runtime = tokio::runtime::Runtime::new()?;
+        let task_ctx = SessionContext::new().task_ctx();
+        for (partition, partition_id) in [0, 1, 0, 1].into_iter().enumerate() {
+            let stream = union.execute(partition, task_ctx.clone())?;
+            let batches = runtime.block_on(collect(stream))?;
+            assert_eq!(evaluated_ids(&batches), expected_ids(partition_id, 4));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_partition_exprs_of_multiple_partitions() -> Result<()> {
+        // partitions executed by the same task are interleaved, each counting
+        // its own rows
+        let plan = partition_exprs_exec()?;
+        let runtime = tokio::runtime::Runtime::new()?;
+        let task_ctx = SessionContext::new().task_ctx();
+        let mut streams = (0..2)
+            .map(|partition| plan.execute(partition, task_ctx.clone()))
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut ids = vec![vec![]; 2];
+        runtime.block_on(async {
+            for _ in 0..2 {
+                for (partition, stream) in streams.iter_mut().enumerate() {
+                    let batch = stream.next().await.unwrap()?;
+                    ids[partition].extend(evaluated_ids(&[batch]));
+                }
+            }
+            Ok::<_, DataFusionError>(())
+        })?;
+        assert_eq!(ids, vec![expected_ids(0, 4), expected_ids(1, 4)]);
+
+        // not evaluated outside of a partition scope
+        let batch = RecordBatch::new_empty(plan.schema());
+        let expr = SparkPartitionExpr::new(SparkPartitionExprKind::SparkPartitionId);
+        assert!(expr.evaluate(&batch).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_monotonically_increasing_ids() {
+        let ids = monotonically_increasing_ids(0, 0, 3);
+        assert_eq!(ids.values(), &[0, 1, 2]);
+
+        // same bit layout as spark: partition id << 33 | row number
+        let ids = monotonically_increasing_ids(2, 5, 2);
+        assert_eq!(ids.values(), &[17179869189, 17179869190]);
+
+        let expr = SparkPartitionExpr::new(SparkPartitionExprKind::SparkPartitionId);
+        let other = SparkPartitionExpr::new(SparkPartitionExprKind::SparkPartitionId);
+        assert_ne!(expr.expr_id, other.expr_id);
+        assert_eq!(expr.to_string(), "spark_partition_id()");
+        assert_eq!(expr.data_type(&Schema::empty()).unwrap(), DataType::Int32);
+    }
+}
@@ -0,0 +1,259 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Defines spark's rand(seed) and randn(seed), producing the same values as
+//! spark for the same partitions.
+//!
+//! like spark, each partition draws from an XORShiftRandom seeded with
+//! seed + partition id, where the partition is the executed partition of the
+//! operator evaluating the expression, see partition_scope_exec. expressions
+//! are shared by tasks through the plan cache, so the generators are kept in
+//! the partition scope instead of the expression.
+
+use std::any::Any;
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use datafusion::arrow::array::Float64Array;
+use datafusion::arrow::datatypes::{DataType, Schema};
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::physical_plan::{ColumnarValue, PhysicalExpr};
+
+use crate::partition_scope_exec::PartitionScope;
+use crate::spark_hash::spark_compatible_murmur3_hash;
+
+static NEXT_EXPR_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Evaluates rand(seed), or randn(seed) if gaussian
+#[derive(Debug)]
+pub struct SparkRandExpr {
+    seed: i64,
+    gaussian: bool,
+    expr_id: usize,
+}
+
+impl SparkRandExpr {
+    pub fn new(seed: i64, gaussian: bool) -> Self {
+        Self {
+            seed,
+            gaussian,
+            expr_id: NEXT_EXPR_ID.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+}
+
+impl Display for SparkRandExpr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = if self.gaussian { "randn" } else { "rand" };
+        write!(f, "{}({})", name, self.seed)
+    }
+}
+
+impl PhysicalExpr for SparkRandExpr {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn data_type(&self, _input_schema: &Schema) -> Result<DataType> {
+        Ok(DataType::Float64)
+    }
+
+    fn nullable(&self, _input_schema: &Schema) -> Result<bool> {
+        Ok(false)
+    }
+
+    fn evaluate(&self, batch: &RecordBatch) -> Result<ColumnarValue> {
+        let scope = PartitionScope::current().ok_or_else(|| {
+            DataFusionError::Execution(format!(
+                "{} is evaluated outside of a partition scope",
+                self
+            ))
+        })?;
+        let seed = self.seed.wrapping_add(scope.partition() as i64);
+        let values = scope.with_state(
+            self.expr_id,
+            || XorShiftRandom::new(seed),
+            |rng: &mut XorShiftRandom| {
+                (0..batch.num_rows())
+                    .map(|_| match self.gaussian {
+                        true => rng.next_gaussian(),
+                        false => rng.next_double(),
+                    })
+                    .collect::<Vec<_>>()
+            },
+        );
+        Ok(ColumnarValue::Array(Arc::new(Float64Array::from(values))))
+    }
+}
+
+/// Port of spark's XORShiftRandom, which is a java.util.Random overriding
+/// next(bits). the seed is hashed with scala's MurmurHash3.bytesHash.
+#[derive(Debug)]
+struct XorShiftRandom {
+    seed: i64,
+    next_next_gaussian: Option<f64>,
+}
+
+impl XorShiftRandom {
+    fn new(init: i64) -> Self {
+        Self {
+            seed: Self::hash_seed(init),
+            next_next_gaussian: None,
+        }
+    }
+
+    fn hash_seed(seed: i64) -> i64 {
+        // bytesHash of 8 bytes is the same as the spark compatible murmur3
+        const ARRAY_SEED: u32 = 0x3c074a61;
+        let bytes = seed.to_be_bytes();
+        let low_bits = spark_compatible_murmur3_hash(&bytes, ARRAY_SEED);
+        let high_bits = spark_compatible_murmur3_hash(&bytes, low_bits);
+        ((high_bits as i64) << 32) | (low_bits as i64)
+    }
+
+    fn next(&mut self, bits: u32) -> i64 {
+        let mut next_seed = self.seed ^ (self.seed << 21);
+        next_seed ^= ((next_seed as u64) >> 35) as i64;
+        next_seed ^= next_seed << 4;
+        self.seed = next_seed;
+        next_seed & ((1 << bits) - 1)
+    }
+
+    /// same as java.util.Random.nextDouble()
+    fn next_double(&mut self) -> f64 {
+        const DOUBLE_UNIT: f64 = 1.0 / (1u64 << 53) as f64;
+        ((self.next(26) << 27) + self.next(27)) as f64 * DOUBLE_UNIT
+    }
+
+    /// same as java.util.Random.nextGaussian(), which uses StrictMath.log().
+    /// ln() of the platform is expected to be correctly rounded as well.
+    fn next_gaussian(&mut self) -> f64 {
+        if let Some(next_next_gaussian) = self.next_next_gaussian.take() {
+            return next_next_gaussian;
+        }
+        loop {
+            let v1 = 2.0 * self.next_double() - 1.0;
+            let v2 = 2.0 * self.next_double() - 1.0;
+            let s = v1 * v1 + v2 * v2;
+            if s < 1.0 && s != 0.0 {
+                let multiplier = (-2.0 * s.ln() / s).sqrt();
+                self.next_next_gaussian = Some(v2 * multiplier);
+                return v1 * multiplier;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use datafusion::arrow::array::Int32Array;
+    use datafusion::arrow::datatypes::Field;
+    use datafusion::physical_plan::common::collect;
+    use datafusion::physical_plan::memory::MemoryExec;
+    use datafusion::physical_plan::projection::ProjectionExec;
+    use datafusion::physical_plan::union::UnionExec;
+    use datafusion::physical_plan::ExecutionPlan;
+    use datafusion::prelude::SessionContext;
+    use futures::StreamExt;
+
+    use crate::partition_scope_exec::PartitionScopeExec;
+
+    use super::*;
+
+    fn rand_values(batches: &[RecordBatch]) -> Vec<f64> {
+        batches
+            .iter()
+            .flat_map(|batch| {
+                let values = batch.column(0).as_any().downcast_ref::<Float64Array>();
+                values.unwrap().values().to_vec()
+            })
+            .collect()
+    }
+
+    /// values of rand(42) drawn from the generator of the partition
+    fn expected_values(partition_id: i64, num_rows: usize) -> Vec<f64> {
+        let mut rng = XorShiftRandom::new(42 + partition_id);
+        (0..num_rows).map(|_| rng.next_double()).collect()
+    }
+
+    #[test]
+    fn test_rand_of_executed_partitions() -> Result<()> {
+        // rand(42) of 2 partitions of 2 batches of 2 rows
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int32, false)]));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![Arc::new(Int32Array::from(vec![1, 2]))],
+        )?;
+        let partition = vec![batch.clone(), batch];
+        let input = Arc::new(MemoryExec::try_new(
+            &[partition.clone(), partition],
+            schema,
+            None,
+        )?);
+        let rand: Arc<dyn PhysicalExpr> = Arc::new(SparkRandExpr::new(42, false));
+        let projection = ProjectionExec::try_new(vec![(rand, "r".to_owned())], input)?;
+        let input: Arc<dyn ExecutionPlan> =
+            Arc::new(PartitionScopeExec::new(Arc::new(projection)));
+
+        // generators are seeded with the partitions of the inputs of a union
+        let union = UnionExec::new(vec![input.clone(), input.clone()]);
+        let runtime = tokio::runtime::Runtime::new()?;
+        let task_ctx = SessionContext::new().task_ctx();
+        for (partition, partition_id) in [0, 1, 0, 1].into_iter().enumerate() {
+            let stream = union.execute(partition, task_ctx.clone())?;
+            let batches = runtime.block_on(collect(stream))?;
+            assert_eq!(rand_values(&batches), expected_values(partition_id, 4));
+        }
+
+        // partitions executed by the same task are interleaved, each drawing
+        // from its own generator
+        let mut streams = (0..2)
+            .map(|partition| input.execute(partition, task_ctx.clone()))
+            .collect::<Result<Vec<_>>>()?;
+        let mut values = vec![vec![]; 2];
+        runtime.block_on(async {
+            for _ in 0..2 {
+                for (partition, stream) in streams.iter_mut().enumerate() {
+                    let batch = stream.next().await.unwrap()?;
+                    values[partition].extend(rand_values(&[batch]));
+                }
+            }
+            Ok::<_, DataFusionError>(())
+        })?;
+        assert_eq!(values, vec![expected_values(0, 4), expected_values(1, 4)]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_xor_shift_random() {
+        // values of spark's XORShiftRandom, e.g. rand(0) and randn(0) of the
+        // first partition are 0.7604953758285915 and 1.6034991609278433
+        let mut rng = XorShiftRandom::new(0);
+        assert_eq!(rng.next_double(), 0.7604953758285915);
+        assert_eq!(rng.next_double(), 0.5234194256885571);
+        assert_eq!(rng.next_double(), 0.0953472826424725);
+        assert_eq!(
+            XorShiftRandom::new(42 + 3).next_double(),
+            0.7971301351894658
+        );
+
+        let mut rng = XorShiftRandom::new(0);
+        assert_eq!(rng.next_gaussian(), 1.6034991609278433);
+        assert_eq!(rng.next_gaussian(), 0.14416006165776865);
+        assert_eq!(rng.next_gaussian(), -0.6253564498627744);
+        assert_eq!(XorShiftRandom::new(-7).next_gaussian(), 0.4619726516798273);
+    }
+}
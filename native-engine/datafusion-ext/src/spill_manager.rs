@@ -0,0 +1,426 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Manages spill files of native operators
+//!
+//! spill files of a task are created in its own subdirectory of the spill
+//! directories, which are removed when the task completes or is cancelled. a
+//! sweeper thread periodically removes directories of tasks without live
+//! spill files, e.g. left by tasks whose native executions kept running
+//! after release, so that long-lived executors do not leak disk.
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+use std::time::{Duration, SystemTime};
+
+use dashmap::DashMap;
+use datafusion::error::{DataFusionError, Result};
+use jni::sys::jlong;
+use memmap2::{Advice, Mmap};
+use once_cell::sync::OnceCell;
+use tempfile::NamedTempFile;
+
+use crate::jni_call_static;
+use crate::native_conf::native_conf;
+
+static SPILL_MANAGER: OnceCell<SpillManager> = OnceCell::new();
+
+const TASK_DIR_PREFIX: &str = "blaze-task-";
+
+/// Creates spill files round-robin across spill directories, and tracks them
+/// by the owner spark task, so that files leaked by cancelled tasks are still
+/// deleted when the task completes.
+pub struct SpillManager {
+    dirs: Vec<PathBuf>,
+    next_dir: AtomicUsize,
+    task_spills: DashMap<i64, HashSet<PathBuf>>,
+    mapped_bytes: AtomicUsize,
+}
+
+impl SpillManager {
+    /// initializes the global spill manager and starts the sweeper of orphan
+    /// task directories, must be called before the first call of get(),
+    /// otherwise the system temp dir is used
+    pub fn init(dirs: Vec<PathBuf>) {
+        let mut inited = false;
+        let manager = SPILL_MANAGER.get_or_init(|| {
+            inited = true;
+            Self::new(dirs)
+        });
+        if inited {
+            manager.start_sweeper();
+        }
+    }
+
+    pub fn get() -> &'static SpillManager {
+        SPILL_MANAGER.get_or_init(|| Self::new(vec![std::env::temp_dir()]))
+    }
+
+    fn new(dirs: Vec<PathBuf>) -> Self {
+        let dirs = if dirs.is_empty() {
+            vec![std::env::temp_dir()]
+        } else {
+            dirs
+        };
+        Self {
+            dirs,
+            next_dir: AtomicUsize::new(0),
+            task_spills: DashMap::new(),
+            mapped_bytes: AtomicUsize::new(0),
+        }
+    }
+
+    /// creates a spill file owned by the spark task of current thread
+    pub fn create_spill_file(&'static self) -> Result<SpillFile> {
+        let task_attempt_id =
+            jni_call_static!(JniBridge.getTaskAttemptId() -> jlong)? as i64;
+        self.create_spill_file_for_task(task_attempt_id)
+    }
+
+    pub fn create_spill_file_for_task(
+        &'static self,
+        task_attempt_id: i64,
+    ) -> Result<SpillFile> {
+        let dir = &self.dirs[self.next_dir.fetch_add(1, SeqCst) % self.dirs.len()];
+        let task_dir = task_dir(dir, task_attempt_id);
+        std::fs::create_dir_all(&task_dir)?;
+        let file = tempfile::Builder::new()
+            .prefix("blaze-spill-")
+            .tempfile_in(&task_dir)?;
+        self.task_spills
+            .entry(task_attempt_id)
+            .or_default()
+            .insert(file.path().to_owned());
+
+        Ok(SpillFile {
+            file,
+            task_attempt_id,
+            manager: self,
+        })
+    }
+
+    /// deletes all remaining spill files and directories of the task, returns
+    /// number of deleted files
+    pub fn release_task(&self, task_attempt_id: i64) -> usize {
+        let paths = self
+            .task_spills
+            .remove(&task_attempt_id)
+            .map(|(_, paths)| paths)
+            .unwrap_or_default();
+        let mut num_deleted = 0;
+        for path in paths {
+            match std::fs::remove_file(&path) {
+                Ok(()) => num_deleted += 1,
+                Err(err) => {
+                    log::warn!("failed to delete spill file {:?}: {}", path, err)
+                }
+            }
+        }
+        for dir in &self.dirs {
+            remove_task_dir(&task_dir(dir, task_attempt_id));
+        }
+        num_deleted
+    }
+
+    /// removes directories of the task if it has no live spill files, e.g.
+    /// once a native execution of the task is closed. returns true if there
+    /// were no live spill files.
+    pub fn release_unused_task_dirs(&self, task_attempt_id: i64) -> bool {
+        if self.num_task_spills(task_attempt_id) > 0 {
+            return false;
+        }
+        for dir in &self.dirs {
+            remove_task_dir(&task_dir(dir, task_attempt_id));
+        }
+        true
+    }
+
+    /// removes task directories without live spill files which are not
+    /// modified for at least min_age, returns number of removed directories.
+    /// recently modified directories are kept, since files may be being
+    /// created in them.
+    pub fn sweep_orphan_task_dirs(&self, min_age: Duration) -> usize {
+        let now = SystemTime::now();
+        let mut num_removed = 0;
+        for dir in &self.dirs {
+            let entries = match std::fs::read_dir(dir) {
+                Ok(entries) => entries,
+                Err(_) => continue,
+            };
+            for entry in entries.flatten() {
+                let file_name = entry.file_name();
+                let task_attempt_id = match file_name
+                    .to_str()
+                    .and_then(|name| name.strip_prefix(TASK_DIR_PREFIX))
+                    .and_then(|id| id.parse::<i64>().ok())
+                {
+                    Some(task_attempt_id) => task_attempt_id,
+                    None => continue,
+                };
+                if self.num_task_spills(task_attempt_id) > 0 {
+                    continue;
+                }
+                let age = entry
+                    .metadata()
+                    .and_then(|metadata| metadata.modified())
+                    .map(|modified| now.duration_since(modified).unwrap_or_default());
+                if matches!(age, Ok(age) if age >= min_age) {
+                    remove_task_dir(&entry.path());
+                    num_removed += 1;
+                }
+            }
+        }
+        num_removed
+    }
+
+    /// starts the thread sweeping orphan task directories every
+    /// spill_sweep_interval_secs, the interval is re-read every round
+    fn start_sweeper(&'static self) {
+        let spawned = std::thread::Builder::new()
+            .name("blaze-spill-sweeper".to_owned())
+            .spawn(move || loop {
+                let interval_secs = native_conf().spill_sweep_interval_secs;
+                let interval = Duration::from_secs(match interval_secs {
+                    0 => 60, // disabled, checks again later
+                    interval_secs => interval_secs,
+                });
+                std::thread::sleep(interval);
+                if interval_secs > 0 {
+                    let num_removed = self.sweep_orphan_task_dirs(interval);
+                    if num_removed > 0 {
+                        log::info!("Removed {} orphan task spill dirs", num_removed);
+                    }
+                }
+            });
+        if let Err(err) = spawned {
+            log::warn!("failed to start spill sweeper: {}", err);
+        }
+    }
+
+    /// total bytes of currently mapped spill files. mapped pages are managed by
+    /// the OS page cache, so they are accounted apart from the memory manager
+    pub fn mapped_bytes(&self) -> usize {
+        self.mapped_bytes.load(SeqCst)
+    }
+
+    pub fn num_task_spills(&self, task_attempt_id: i64) -> usize {
+        self.task_spills
+            .get(&task_attempt_id)
+            .map(|paths| paths.len())
+            .unwrap_or(0)
+    }
+}
+
+fn task_dir(dir: &Path, task_attempt_id: i64) -> PathBuf {
+    dir.join(format!("{}{}", TASK_DIR_PREFIX, task_attempt_id))
+}
+
+fn remove_task_dir(task_dir: &Path) {
+    match std::fs::remove_dir_all(task_dir) {
+        Ok(()) => {}
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) => log::warn!("failed to remove task dir {:?}: {}", task_dir, err),
+    }
+}
+
+/// A spill file which is deleted on dropping, or when the owner task completes
+pub struct SpillFile {
+    file: NamedTempFile,
+    task_attempt_id: i64,
+    manager: &'static SpillManager,
+}
+
+impl SpillFile {
+    pub fn path(&self) -> &Path {
+        self.file.path()
+    }
+
+    pub fn as_file(&self) -> &File {
+        self.file.as_file()
+    }
+
+    pub fn as_file_mut(&mut self) -> &mut File {
+        self.file.as_file_mut()
+    }
+
+    /// maps the spill file for reading back after it is completely written
+    pub fn map(&self) -> Result<MappedSpillFile> {
+        // safety: spill files are private to their owner and no longer
+        // modified once read back
+        let mmap = unsafe { Mmap::map(self.file.as_file())? };
+        if let Err(err) = mmap.advise(Advice::Sequential) {
+            log::debug!("madvise() on spill file {:?} failed: {}", self.path(), err);
+        }
+        self.manager.mapped_bytes.fetch_add(mmap.len(), SeqCst);
+        Ok(MappedSpillFile {
+            mmap,
+            manager: self.manager,
+        })
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        if let Some(mut paths) = self.manager.task_spills.get_mut(&self.task_attempt_id) {
+            paths.remove(self.file.path());
+        }
+        self.manager
+            .task_spills
+            .remove_if(&self.task_attempt_id, |_, paths| paths.is_empty());
+    }
+}
+
+/// A memory-mapped spill file. pages are loaded lazily on access and may be
+/// evicted by the OS under memory pressure, so reading back spills does not
+/// allocate memory from the memory manager.
+pub struct MappedSpillFile {
+    mmap: Mmap,
+    manager: &'static SpillManager,
+}
+
+impl Deref for MappedSpillFile {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.mmap
+    }
+}
+
+impl Drop for MappedSpillFile {
+    fn drop(&mut self) {
+        self.manager.mapped_bytes.fetch_sub(self.mmap.len(), SeqCst);
+    }
+}
+
+/// Compression codec of spill blocks. shuffle spills are not compressed with
+/// it since they are already in the compressed shuffle block format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpillCodec {
+    None,
+    Lz4,
+    Zstd,
+}
+
+impl Default for SpillCodec {
+    fn default() -> Self {
+        SpillCodec::Lz4
+    }
+}
+
+impl SpillCodec {
+    pub fn compress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        Ok(match self {
+            SpillCodec::None => data.to_vec(),
+            SpillCodec::Lz4 => lz4::block::compress(data, None, true)?,
+            SpillCodec::Zstd => zstd::encode_all(data, 1)?,
+        })
+    }
+
+    pub fn decompress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        Ok(match self {
+            SpillCodec::None => data.to_vec(),
+            SpillCodec::Lz4 => lz4::block::decompress(data, None)?,
+            SpillCodec::Zstd => zstd::decode_all(data)?,
+        })
+    }
+}
+
+impl FromStr for SpillCodec {
+    type Err = DataFusionError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(SpillCodec::None),
+            "lz4" => Ok(SpillCodec::Lz4),
+            "zstd" => Ok(SpillCodec::Zstd),
+            other => Err(DataFusionError::Plan(format!(
+                "unsupported spill codec: {}",
+                other
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_release_task_spills() -> Result<()> {
+        let manager = SpillManager::get();
+        let spill1 = manager.create_spill_file_for_task(-100)?;
+        let spill2 = manager.create_spill_file_for_task(-100)?;
+        let path2 = spill2.path().to_owned();
+        assert_eq!(manager.num_task_spills(-100), 2);
+
+        std::mem::drop(spill1);
+        assert_eq!(manager.num_task_spills(-100), 1);
+
+        // leaked spill file is deleted on task completion
+        std::mem::forget(spill2);
+        assert_eq!(manager.release_task(-100), 1);
+        assert!(!path2.exists());
+        assert!(!path2.parent().unwrap().exists());
+        assert_eq!(manager.num_task_spills(-100), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sweep_orphan_task_dirs() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let manager: &'static SpillManager =
+            Box::leak(Box::new(SpillManager::new(vec![dir.path().to_owned()])));
+        let spill = manager.create_spill_file_for_task(-400)?;
+        let task_dir = spill.path().parent().unwrap().to_owned();
+        assert_eq!(task_dir, dir.path().join("blaze-task--400"));
+        std::fs::create_dir(dir.path().join("unrelated"))?;
+
+        // directories of tasks with live spill files are kept
+        assert_eq!(manager.sweep_orphan_task_dirs(Duration::ZERO), 0);
+        assert!(task_dir.exists());
+
+        std::mem::drop(spill);
+        assert_eq!(manager.sweep_orphan_task_dirs(Duration::from_secs(3600)), 0);
+        assert_eq!(manager.sweep_orphan_task_dirs(Duration::ZERO), 1);
+        assert!(!task_dir.exists());
+        assert!(dir.path().join("unrelated").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_map_spill_file() -> Result<()> {
+        let manager = SpillManager::get();
+        let mut spill = manager.create_spill_file_for_task(-200)?;
+        spill.as_file_mut().write_all(b"blaze spill")?;
+
+        let mapped = spill.map()?;
+        assert_eq!(&mapped[..], b"blaze spill");
+        assert!(manager.mapped_bytes() >= mapped.len());
+        Ok(())
+    }
+
+    #[test]
+    fn test_spill_codec() -> Result<()> {
+        let data = b"blaze spill blocks blaze spill blocks".repeat(100);
+        for codec in [SpillCodec::None, SpillCodec::Lz4, SpillCodec::Zstd] {
+            assert_eq!(codec.decompress(&codec.compress(&data)?)?, data);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,1265 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Defines a sort-merge join resisting skewed join keys
+//!
+//! a sort-merge join buffers all rows of the right (buffered) side sharing
+//! the current join key, and joins each row of the left (streamed) side with
+//! the buffered group. a heavily skewed key, which AQE failed to split, makes
+//! a single group too large to fit in memory. this join keeps at most
+//! `max_buffered_group_bytes` of a group in memory and streams the rest of the
+//! group into a spill file, the spilled part is then read back in chunks for
+//! each streamed batch of the key.
+
+use std::any::Any;
+use std::cmp::Ordering;
+use std::fmt::Formatter;
+use std::fs::File;
+use std::io::{BufReader, Read, Write};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use datafusion::arrow::array::{
+    build_compare, new_null_array, Array, ArrayRef, DynComparator, UInt32Array,
+};
+use datafusion::arrow::compute::{take, SortOptions};
+use datafusion::arrow::datatypes::{Field, Schema, SchemaRef};
+use datafusion::arrow::error::{ArrowError, Result as ArrowResult};
+use datafusion::arrow::ipc::reader::StreamReader;
+use datafusion::arrow::ipc::writer::StreamWriter;
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::execution::context::TaskContext;
+use datafusion::physical_plan::common::batch_byte_size;
+use datafusion::physical_plan::expressions::{Column, PhysicalSortExpr};
+use datafusion::physical_plan::metrics::{
+    Count, ExecutionPlanMetricsSet, MetricBuilder, MetricsSet, Time,
+};
+use datafusion::physical_plan::{
+    DisplayFormatType, ExecutionPlan, Partitioning, RecordBatchStream,
+    SendableRecordBatchStream, Statistics,
+};
+use datafusion::prelude::JoinType;
+use futures::{Stream, StreamExt};
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::task::JoinHandle;
+
+use crate::spill_manager::{SpillCodec, SpillFile, SpillManager};
+use crate::split_oversized_batches_exec::{row_bytes, OutputBatchBudget};
+
+/// Sort-merge join of two inputs sorted by their join keys, spilling the part
+/// of a buffered key group exceeding `max_buffered_group_bytes`.
+#[derive(Debug)]
+pub struct SpillableSortMergeJoinExec {
+    left: Arc<dyn ExecutionPlan>,
+    right: Arc<dyn ExecutionPlan>,
+    on: Vec<(Column, Column)>,
+    join_type: JoinType,
+    sort_options: Vec<SortOptions>,
+    null_equals_null: bool,
+    max_buffered_group_bytes: usize,
+    schema: SchemaRef,
+    metrics: ExecutionPlanMetricsSet,
+}
+
+impl SpillableSortMergeJoinExec {
+    pub fn try_new(
+        left: Arc<dyn ExecutionPlan>,
+        right: Arc<dyn ExecutionPlan>,
+        on: Vec<(Column, Column)>,
+        join_type: JoinType,
+        sort_options: Vec<SortOptions>,
+        null_equals_null: bool,
+        max_buffered_group_bytes: usize,
+    ) -> Result<Self> {
+        if on.is_empty() {
+            return Err(DataFusionError::Plan(
+                "sort-merge join requires at least one join key".to_owned(),
+            ));
+        }
+        if sort_options.len() != on.len() {
+            return Err(DataFusionError::Plan(format!(
+                "sort-merge join has {} join keys but {} sort options",
+                on.len(),
+                sort_options.len()
+            )));
+        }
+        let (left_schema, right_schema) = (left.schema(), right.schema());
+        for (l, r) in &on {
+            let left_type = left_schema.field(l.index()).data_type();
+            let right_type = right_schema.field(r.index()).data_type();
+            if left_type != right_type {
+                return Err(DataFusionError::Plan(format!(
+                    "sort-merge join keys {} and {} have different types: {:?} vs {:?}",
+                    l, r, left_type, right_type
+                )));
+            }
+        }
+        let schema = Arc::new(build_join_schema(&left_schema, &right_schema, &join_type));
+
+        Ok(Self {
+            left,
+            right,
+            on,
+            join_type,
+            sort_options,
+            null_equals_null,
+            max_buffered_group_bytes,
+            schema,
+            metrics: ExecutionPlanMetricsSet::new(),
+        })
+    }
+}
+
+#[async_trait]
+impl ExecutionPlan for SpillableSortMergeJoinExec {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+
+    fn output_partitioning(&self) -> Partitioning {
+        match self.join_type {
+            JoinType::Right => self.right.output_partitioning(),
+            _ => self.left.output_partitioning(),
+        }
+    }
+
+    fn output_ordering(&self) -> Option<&[PhysicalSortExpr]> {
+        // unmatched right rows are output in the key order of the right side,
+        // interleaved with left rows
+        match self.join_type {
+            JoinType::Inner | JoinType::Left | JoinType::Semi | JoinType::Anti => {
+                self.left.output_ordering()
+            }
+            JoinType::Right | JoinType::Full => None,
+        }
+    }
+
+    fn children(&self) -> Vec<Arc<dyn ExecutionPlan>> {
+        vec![self.left.clone(), self.right.clone()]
+    }
+
+    fn with_new_children(
+        self: Arc<Self>,
+        children: Vec<Arc<dyn ExecutionPlan>>,
+    ) -> Result<Arc<dyn ExecutionPlan>> {
+        match children.len() {
+            2 => Ok(Arc::new(Self::try_new(
+                children[0].clone(),
+                children[1].clone(),
+                self.on.clone(),
+                self.join_type,
+                self.sort_options.clone(),
+                self.null_equals_null,
+                self.max_buffered_group_bytes,
+            )?)),
+            _ => Err(DataFusionError::Internal(
+                "SpillableSortMergeJoinExec wrong number of children".to_owned(),
+            )),
+        }
+    }
+
+    fn execute(
+        &self,
+        partition: usize,
+        context: Arc<TaskContext>,
+    ) -> Result<SendableRecordBatchStream> {
+        let batch_size = context.session_config().batch_size;
+        let streamed = JoinInput::new(
+            self.left.execute(partition, context.clone())?,
+            self.on.iter().map(|(l, _)| l.index()).collect(),
+        );
+        let buffered = JoinInput::new(
+            self.right.execute(partition, context)?,
+            self.on.iter().map(|(_, r)| r.index()).collect(),
+        );
+        let (sender, output) = channel(1);
+        let joiner = SortMergeJoiner {
+            join_type: self.join_type,
+            sort_options: self.sort_options.clone(),
+            null_equals_null: self.null_equals_null,
+            max_buffered_group_bytes: self.max_buffered_group_bytes,
+            streamed,
+            buffered,
+            heads_comparator: None,
+            output: JoinOutput::new(
+                self.schema.clone(),
+                self.join_type,
+                self.left.schema().fields().len(),
+                OutputBatchBudget::new(batch_size),
+            ),
+            sender,
+            next_batch_id: 0,
+            wait_time: Duration::ZERO,
+            metrics: SortMergeJoinMetrics::new(&self.metrics, partition),
+        };
+
+        let join_handle = tokio::spawn(async move {
+            let sender = joiner.sender.clone();
+            if let Err(err) = joiner.run().await {
+                let err = ArrowError::ExternalError(Box::new(err));
+                let _ = sender.send(Err(err)).await;
+            }
+        });
+        Ok(Box::pin(SortMergeJoinStream {
+            schema: self.schema.clone(),
+            output,
+            join_handle,
+        }))
+    }
+
+    fn metrics(&self) -> Option<MetricsSet> {
+        Some(self.metrics.clone_inner())
+    }
+
+    fn fmt_as(&self, t: DisplayFormatType, f: &mut Formatter) -> std::fmt::Result {
+        match t {
+            DisplayFormatType::Default => write!(
+                f,
+                "SpillableSortMergeJoinExec: join_type={:?}, on={:?}, \
+                 max_buffered_group_bytes={}",
+                self.join_type, self.on, self.max_buffered_group_bytes
+            ),
+        }
+    }
+
+    fn statistics(&self) -> Statistics {
+        Statistics::default()
+    }
+}
+
+/// left fields followed by right fields, fields of the side which may be
+/// absent in output rows are nullable. semi/anti joins output left fields only.
+pub(crate) fn build_join_schema(
+    left: &Schema,
+    right: &Schema,
+    join_type: &JoinType,
+) -> Schema {
+    let fields_of = |schema: &Schema, nullable: bool| {
+        schema
+            .fields()
+            .iter()
+            .map(|field| {
+                Field::new(
+                    field.name(),
+                    field.data_type().clone(),
+                    field.is_nullable() || nullable,
+                )
+            })
+            .collect::<Vec<_>>()
+    };
+    let fields = match join_type {
+        JoinType::Inner => [fields_of(left, false), fields_of(right, false)].concat(),
+        JoinType::Left => [fields_of(left, false), fields_of(right, true)].concat(),
+        JoinType::Right => [fields_of(left, true), fields_of(right, false)].concat(),
+        JoinType::Full => [fields_of(left, true), fields_of(right, true)].concat(),
+        JoinType::Semi | JoinType::Anti => fields_of(left, false),
+    };
+    Schema::new(fields)
+}
+
+struct SortMergeJoinMetrics {
+    output_rows: Count,
+    join_time: Time,
+    spill_count: Count,
+    spilled_bytes: Count,
+}
+
+impl SortMergeJoinMetrics {
+    fn new(metrics: &ExecutionPlanMetricsSet, partition: usize) -> Self {
+        Self {
+            output_rows: MetricBuilder::new(metrics).output_rows(partition),
+            join_time: MetricBuilder::new(metrics).subset_time("join_time", partition),
+            spill_count: MetricBuilder::new(metrics).counter("spill_count", partition),
+            spilled_bytes: MetricBuilder::new(metrics)
+                .counter("spilled_bytes", partition),
+        }
+    }
+}
+
+/// a batch with an id unique within a join, identifying the batch which
+/// output rows are taken from
+#[derive(Clone)]
+struct IdBatch {
+    id: usize,
+    batch: RecordBatch,
+}
+
+/// current batch of an input, whose rows before `idx` are consumed
+struct JoinInput {
+    stream: SendableRecordBatchStream,
+    key_indices: Vec<usize>,
+    batch: Option<IdBatch>,
+    keys: Vec<ArrayRef>,
+    idx: usize,
+}
+
+impl JoinInput {
+    fn new(stream: SendableRecordBatchStream, key_indices: Vec<usize>) -> Self {
+        Self {
+            stream,
+            key_indices,
+            batch: None,
+            keys: vec![],
+            idx: 0,
+        }
+    }
+
+    fn num_rows(&self) -> usize {
+        self.batch.as_ref().map(|b| b.batch.num_rows()).unwrap_or(0)
+    }
+}
+
+struct SortMergeJoiner {
+    join_type: JoinType,
+    sort_options: Vec<SortOptions>,
+    null_equals_null: bool,
+    max_buffered_group_bytes: usize,
+    streamed: JoinInput,
+    buffered: JoinInput,
+    /// comparator between current streamed and buffered batches, with ids of
+    /// the compared batches
+    heads_comparator: Option<(usize, usize, KeyComparator)>,
+    output: JoinOutput,
+    sender: Sender<ArrowResult<RecordBatch>>,
+    next_batch_id: usize,
+    /// time waited for inputs and the consumer, excluded from join time
+    wait_time: Duration,
+    metrics: SortMergeJoinMetrics,
+}
+
+impl SortMergeJoiner {
+    async fn run(mut self) -> Result<()> {
+        let start_time = Instant::now();
+        loop {
+            let has_streamed = self.poll_streamed().await?;
+            let has_buffered = self.poll_buffered().await?;
+            match (has_streamed, has_buffered) {
+                (false, false) => break,
+                (true, false) => {
+                    if !matches!(
+                        self.join_type,
+                        JoinType::Left | JoinType::Full | JoinType::Anti
+                    ) {
+                        break;
+                    }
+                    let (start, end) = (self.streamed.idx, self.streamed.num_rows());
+                    self.output_unmatched_streamed(start, end).await?;
+                    self.streamed.idx = end;
+                }
+                (false, true) => {
+                    if !matches!(self.join_type, JoinType::Right | JoinType::Full) {
+                        break;
+                    }
+                    let (start, end) = (self.buffered.idx, self.buffered.num_rows());
+                    self.output_unmatched_buffered(start, end).await?;
+                    self.buffered.idx = end;
+                }
+                (true, true) => {
+                    let heads_ordering = self.compare_heads()?;
+                    let streamed_head_has_null =
+                        has_null(&self.streamed.keys, self.streamed.idx);
+                    match heads_ordering {
+                        Ordering::Less => self.skip_streamed(Ordering::Less).await?,
+                        Ordering::Greater => {
+                            self.skip_buffered(Ordering::Greater).await?
+                        }
+                        Ordering::Equal
+                            if streamed_head_has_null && !self.null_equals_null =>
+                        {
+                            // null keys never match, buffered rows with null keys
+                            // are skipped once the streamed head passes them
+                            self.skip_streamed(Ordering::Equal).await?;
+                        }
+                        Ordering::Equal => self.join_group().await?,
+                    }
+                }
+            }
+        }
+        if let Some(batch) = self.output.finish()? {
+            self.send_output(batch).await?;
+        }
+        let join_time = start_time.elapsed().saturating_sub(self.wait_time);
+        self.metrics.join_time.add_duration(join_time);
+        Ok(())
+    }
+
+    /// makes sure the current streamed batch has unconsumed rows, returns false
+    /// if the streamed side is exhausted
+    async fn poll_streamed(&mut self) -> Result<bool> {
+        let start_time = Instant::now();
+        let polled = poll_input(&mut self.streamed, &mut self.next_batch_id).await;
+        self.wait_time += start_time.elapsed();
+        polled
+    }
+
+    /// makes sure the current buffered batch has unconsumed rows, returns false
+    /// if the buffered side is exhausted
+    async fn poll_buffered(&mut self) -> Result<bool> {
+        let start_time = Instant::now();
+        let polled = poll_input(&mut self.buffered, &mut self.next_batch_id).await;
+        self.wait_time += start_time.elapsed();
+        polled
+    }
+
+    async fn send_output(&mut self, batch: RecordBatch) -> Result<()> {
+        self.metrics.output_rows.add(batch.num_rows());
+        let start_time = Instant::now();
+        let sent = self.sender.send(Ok(batch)).await;
+        self.wait_time += start_time.elapsed();
+        sent.map_err(|_| {
+            DataFusionError::Execution("sort-merge join output is dropped".to_owned())
+        })
+    }
+
+    fn compare_heads(&mut self) -> Result<Ordering> {
+        let streamed_id = self.streamed.batch.as_ref().unwrap().id;
+        let buffered_id = self.buffered.batch.as_ref().unwrap().id;
+        let rebuild = match &self.heads_comparator {
+            Some((s, b, _)) => (*s, *b) != (streamed_id, buffered_id),
+            None => true,
+        };
+        if rebuild {
+            let comparator = KeyComparator::try_new(
+                &self.streamed.keys,
+                &self.buffered.keys,
+                &self.sort_options,
+            )?;
+            self.heads_comparator = Some((streamed_id, buffered_id, comparator));
+        }
+        let (_, _, comparator) = self.heads_comparator.as_ref().unwrap();
+        Ok(comparator.compare(self.streamed.idx, self.buffered.idx))
+    }
+
+    /// skips streamed rows of the current batch comparing to the buffered head
+    /// as `ordering`, which are unmatched
+    async fn skip_streamed(&mut self, ordering: Ordering) -> Result<()> {
+        let start = self.streamed.idx;
+        let mut end = start + 1;
+        {
+            let (_, _, comparator) = self.heads_comparator.as_ref().unwrap();
+            while end < self.streamed.num_rows()
+                && comparator.compare(end, self.buffered.idx) == ordering
+            {
+                end += 1;
+            }
+        }
+        self.output_unmatched_streamed(start, end).await?;
+        self.streamed.idx = end;
+        Ok(())
+    }
+
+    /// skips buffered rows of the current batch comparing to the streamed head
+    /// reversely as `ordering`, which are unmatched
+    async fn skip_buffered(&mut self, ordering: Ordering) -> Result<()> {
+        let start = self.buffered.idx;
+        let mut end = start + 1;
+        {
+            let (_, _, comparator) = self.heads_comparator.as_ref().unwrap();
+            while end < self.buffered.num_rows()
+                && comparator.compare(self.streamed.idx, end) == ordering
+            {
+                end += 1;
+            }
+        }
+        self.output_unmatched_buffered(start, end).await?;
+        self.buffered.idx = end;
+        Ok(())
+    }
+
+    async fn output_unmatched_streamed(
+        &mut self,
+        start: usize,
+        end: usize,
+    ) -> Result<()> {
+        if !matches!(
+            self.join_type,
+            JoinType::Left | JoinType::Full | JoinType::Anti
+        ) {
+            return Ok(());
+        }
+        let streamed = self.streamed.batch.clone().unwrap();
+        self.output.set_batches(Some(&streamed), None)?;
+        for i in start..end {
+            if let Some(batch) = self.output.push(Some(i as u32), None)? {
+                self.send_output(batch).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn output_unmatched_buffered(
+        &mut self,
+        start: usize,
+        end: usize,
+    ) -> Result<()> {
+        if !matches!(self.join_type, JoinType::Right | JoinType::Full) {
+            return Ok(());
+        }
+        let buffered = self.buffered.batch.clone().unwrap();
+        self.output.set_batches(None, Some(&buffered))?;
+        for i in start..end {
+            if let Some(batch) = self.output.push(None, Some(i as u32))? {
+                self.send_output(batch).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// joins the key group of the buffered head with all streamed rows of the
+    /// same key, the heads of both sides are known to be equal
+    async fn join_group(&mut self) -> Result<()> {
+        let group = self.collect_buffered_group().await?;
+        if group.spill.is_some() {
+            log::warn!(
+                "sort-merge join key group of {} rows exceeds {} bytes, spilled {} rows",
+                group.num_rows(),
+                self.max_buffered_group_bytes,
+                group.num_spilled_rows,
+            );
+        }
+
+        while self.poll_streamed().await? {
+            let streamed = self.streamed.batch.clone().unwrap();
+            let comparator = KeyComparator::try_new(
+                &self.streamed.keys,
+                &group.key,
+                &self.sort_options,
+            )?;
+            let start = self.streamed.idx;
+            let mut end = start;
+            while end < streamed.batch.num_rows() && comparator.compare(end, 0).is_eq() {
+                end += 1;
+            }
+            if end == start {
+                break;
+            }
+            self.join_streamed_run(&streamed, start, end, &group)
+                .await?;
+            self.streamed.idx = end;
+            if end < streamed.batch.num_rows() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// collects buffered rows equal to the buffered head, rows exceeding the
+    /// memory threshold are spilled
+    async fn collect_buffered_group(&mut self) -> Result<BufferedGroup> {
+        let head = self.buffered.idx;
+        let key = self
+            .buffered
+            .keys
+            .iter()
+            .map(|key| key.slice(head, 1))
+            .collect::<Vec<_>>();
+        let mut group = BufferedGroup::new(key);
+
+        while self.poll_buffered().await? {
+            let buffered = self.buffered.batch.clone().unwrap();
+            let comparator = KeyComparator::try_new(
+                &self.buffered.keys,
+                &group.key,
+                &self.sort_options,
+            )?;
+            let start = self.buffered.idx;
+            let mut end = start;
+            while end < buffered.batch.num_rows() && comparator.compare(end, 0).is_eq() {
+                end += 1;
+            }
+            if end == start {
+                break;
+            }
+            self.push_group_rows(&mut group, &buffered, start, end)?;
+            self.buffered.idx = end;
+            if end < buffered.batch.num_rows() {
+                break;
+            }
+        }
+        Ok(group)
+    }
+
+    fn push_group_rows(
+        &mut self,
+        group: &mut BufferedGroup,
+        buffered: &IdBatch,
+        start: usize,
+        end: usize,
+    ) -> Result<()> {
+        let num_rows = end - start;
+        let batch_rows = buffered.batch.num_rows();
+        let mem_bytes = batch_byte_size(&buffered.batch) * num_rows / batch_rows;
+
+        let exceeded = self.max_buffered_group_bytes > 0
+            && !group.batches.is_empty()
+            && group.mem_bytes + mem_bytes > self.max_buffered_group_bytes;
+        if group.spill.is_none() && !exceeded {
+            // slices share buffers with the original batch, so rows output from
+            // a whole in-memory batch are taken without copying
+            let batch = if num_rows == batch_rows {
+                buffered.clone()
+            } else {
+                IdBatch {
+                    id: self.next_batch_id(),
+                    batch: buffered.batch.slice(start, num_rows),
+                }
+            };
+            group.batches.push(batch);
+            group.mem_bytes += mem_bytes;
+            return Ok(());
+        }
+
+        // spilled rows are copied, so that sliced buffers are not written
+        let indices = UInt32Array::from_iter_values(start as u32..end as u32);
+        let columns = buffered
+            .batch
+            .columns()
+            .iter()
+            .map(|column| take(column.as_ref(), &indices, None))
+            .collect::<ArrowResult<Vec<_>>>()?;
+        let batch = RecordBatch::try_new(buffered.batch.schema(), columns)?;
+        if group.spill.is_none() {
+            self.metrics.spill_count.add(1);
+            let spill_file = SpillManager::get().create_spill_file()?;
+            group.spill = Some(SpilledBatches::new(spill_file));
+        }
+        let spilled_bytes = group.spill.as_mut().unwrap().write(&batch)?;
+        self.metrics.spilled_bytes.add(spilled_bytes);
+        group.num_spilled_rows += num_rows;
+        Ok(())
+    }
+
+    /// joins streamed rows in `start..end` with all rows of the group
+    async fn join_streamed_run(
+        &mut self,
+        streamed: &IdBatch,
+        start: usize,
+        end: usize,
+        group: &BufferedGroup,
+    ) -> Result<()> {
+        match self.join_type {
+            JoinType::Semi => {
+                self.output.set_batches(Some(streamed), None)?;
+                for i in start..end {
+                    if let Some(batch) = self.output.push(Some(i as u32), None)? {
+                        self.send_output(batch).await?;
+                    }
+                }
+                return Ok(());
+            }
+            JoinType::Anti => return Ok(()),
+            _ => {}
+        }
+
+        for buffered in &group.batches {
+            self.join_streamed_chunk(streamed, start, end, buffered)
+                .await?;
+        }
+        if let Some(spill) = &group.spill {
+            for batch in spill.read()? {
+                let buffered = IdBatch {
+                    id: self.next_batch_id(),
+                    batch: batch?,
+                };
+                self.join_streamed_chunk(streamed, start, end, &buffered)
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn join_streamed_chunk(
+        &mut self,
+        streamed: &IdBatch,
+        start: usize,
+        end: usize,
+        buffered: &IdBatch,
+    ) -> Result<()> {
+        self.output.set_batches(Some(streamed), Some(buffered))?;
+        for i in start..end {
+            for j in 0..buffered.batch.num_rows() {
+                if let Some(batch) = self.output.push(Some(i as u32), Some(j as u32))? {
+                    self.send_output(batch).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn next_batch_id(&mut self) -> usize {
+        self.next_batch_id += 1;
+        self.next_batch_id
+    }
+}
+
+/// polls the next non-empty batch if all rows of the current batch are
+/// consumed, returns false if the input is exhausted
+async fn poll_input(input: &mut JoinInput, next_batch_id: &mut usize) -> Result<bool> {
+    while input.idx >= input.num_rows() {
+        match input.stream.next().await {
+            Some(batch) => {
+                let batch = batch?;
+                if batch.num_rows() == 0 {
+                    continue;
+                }
+                *next_batch_id += 1;
+                input.keys = input
+                    .key_indices
+                    .iter()
+                    .map(|&i| batch.column(i).clone())
+                    .collect();
+                input.batch = Some(IdBatch {
+                    id: *next_batch_id,
+                    batch,
+                });
+                input.idx = 0;
+            }
+            None => {
+                input.batch = None;
+                input.keys.clear();
+                input.idx = 0;
+                return Ok(false);
+            }
+        }
+    }
+    Ok(true)
+}
+
+fn has_null(keys: &[ArrayRef], idx: usize) -> bool {
+    keys.iter().any(|key| key.is_null(idx))
+}
+
+/// Compares join keys of rows of two batches, under the sort options of the
+/// join inputs
+pub(crate) struct KeyComparator {
+    left_keys: Vec<ArrayRef>,
+    right_keys: Vec<ArrayRef>,
+    comparators: Vec<DynComparator>,
+    sort_options: Vec<SortOptions>,
+}
+
+impl KeyComparator {
+    pub(crate) fn try_new(
+        left_keys: &[ArrayRef],
+        right_keys: &[ArrayRef],
+        sort_options: &[SortOptions],
+    ) -> Result<Self> {
+        let comparators = left_keys
+            .iter()
+            .zip(right_keys)
+            .map(|(l, r)| build_compare(l.as_ref(), r.as_ref()))
+            .collect::<ArrowResult<Vec<_>>>()?;
+        Ok(Self {
+            left_keys: left_keys.to_vec(),
+            right_keys: right_keys.to_vec(),
+            comparators,
+            sort_options: sort_options.to_vec(),
+        })
+    }
+
+    pub(crate) fn compare(&self, l: usize, r: usize) -> Ordering {
+        for (i, comparator) in self.comparators.iter().enumerate() {
+            let options = &self.sort_options[i];
+            let ordering = match (
+                self.left_keys[i].is_valid(l),
+                self.right_keys[i].is_valid(r),
+            ) {
+                (true, true) if options.descending => comparator(l, r).reverse(),
+                (true, true) => comparator(l, r),
+                (false, false) => Ordering::Equal,
+                (false, true) if options.nulls_first => Ordering::Less,
+                (false, true) => Ordering::Greater,
+                (true, false) if options.nulls_first => Ordering::Greater,
+                (true, false) => Ordering::Less,
+            };
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        }
+        Ordering::Equal
+    }
+}
+
+/// rows of the buffered side sharing the same join key
+struct BufferedGroup {
+    /// join key of the group in a single row
+    key: Vec<ArrayRef>,
+    batches: Vec<IdBatch>,
+    mem_bytes: usize,
+    spill: Option<SpilledBatches>,
+    num_spilled_rows: usize,
+}
+
+impl BufferedGroup {
+    fn new(key: Vec<ArrayRef>) -> Self {
+        Self {
+            key,
+            batches: vec![],
+            mem_bytes: 0,
+            spill: None,
+            num_spilled_rows: 0,
+        }
+    }
+
+    fn num_rows(&self) -> usize {
+        let in_mem_rows: usize = self.batches.iter().map(|b| b.batch.num_rows()).sum();
+        in_mem_rows + self.num_spilled_rows
+    }
+}
+
+/// Batches written to a spill file, each as a length-prefixed block of a
+/// compressed IPC stream
+struct SpilledBatches {
+    file: SpillFile,
+    codec: SpillCodec,
+    num_batches: usize,
+}
+
+impl SpilledBatches {
+    fn new(file: SpillFile) -> Self {
+        Self {
+            file,
+            codec: SpillCodec::default(),
+            num_batches: 0,
+        }
+    }
+
+    /// appends the batch, returns number of bytes written
+    fn write(&mut self, batch: &RecordBatch) -> Result<usize> {
+        let mut ipc = vec![];
+        {
+            let mut writer = StreamWriter::try_new(&mut ipc, &batch.schema())?;
+            writer.write(batch)?;
+            writer.finish()?;
+        }
+        let block = self.codec.compress(&ipc)?;
+        let file = self.file.as_file_mut();
+        file.write_all(&(block.len() as u64).to_le_bytes())?;
+        file.write_all(&block)?;
+        self.num_batches += 1;
+        Ok(block.len() + 8)
+    }
+
+    /// reads back all spilled batches in writing order
+    fn read(&self) -> Result<SpilledBatchReader> {
+        Ok(SpilledBatchReader {
+            reader: BufReader::new(File::open(self.file.path())?),
+            codec: self.codec,
+            remaining: self.num_batches,
+        })
+    }
+}
+
+struct SpilledBatchReader {
+    reader: BufReader<File>,
+    codec: SpillCodec,
+    remaining: usize,
+}
+
+impl SpilledBatchReader {
+    fn read_batch(&mut self) -> Result<RecordBatch> {
+        let mut len = [0u8; 8];
+        self.reader.read_exact(&mut len)?;
+        let mut block = vec![0u8; u64::from_le_bytes(len) as usize];
+        self.reader.read_exact(&mut block)?;
+        let ipc = self.codec.decompress(&block)?;
+        match StreamReader::try_new(&ipc[..])?.next() {
+            Some(batch) => Ok(batch?),
+            None => Err(DataFusionError::Execution(
+                "missing batch in spilled block".to_owned(),
+            )),
+        }
+    }
+}
+
+impl Iterator for SpilledBatchReader {
+    type Item = Result<RecordBatch>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        Some(self.read_batch())
+    }
+}
+
+/// Builds output batches from pairs of streamed and buffered row indices.
+/// indices are accumulated as long as they refer to the same pair of batches
+/// and fit in the budget, and small batches are coalesced before output.
+struct JoinOutput {
+    schema: SchemaRef,
+    num_streamed_columns: usize,
+    budget: OutputBatchBudget,
+    streamed: Option<IdBatch>,
+    buffered: Option<IdBatch>,
+    /// estimated bytes of an output row of the current batches
+    row_bytes: usize,
+    streamed_indices: Vec<Option<u32>>,
+    buffered_indices: Vec<Option<u32>>,
+    staged: Vec<RecordBatch>,
+    num_staged_rows: usize,
+    num_staged_bytes: usize,
+}
+
+impl JoinOutput {
+    fn new(
+        schema: SchemaRef,
+        join_type: JoinType,
+        num_left_columns: usize,
+        budget: OutputBatchBudget,
+    ) -> Self {
+        // semi/anti joins output streamed columns only
+        let num_streamed_columns = match join_type {
+            JoinType::Semi | JoinType::Anti => schema.fields().len(),
+            _ => num_left_columns,
+        };
+        Self {
+            schema,
+            num_streamed_columns,
+            budget,
+            streamed: None,
+            buffered: None,
+            row_bytes: 0,
+            streamed_indices: vec![],
+            buffered_indices: vec![],
+            staged: vec![],
+            num_staged_rows: 0,
+            num_staged_bytes: 0,
+        }
+    }
+
+    /// sets batches which the following pushed indices refer to, None to keep
+    /// the current batch of the side
+    fn set_batches(
+        &mut self,
+        streamed: Option<&IdBatch>,
+        buffered: Option<&IdBatch>,
+    ) -> Result<()> {
+        let changed = |current: &Option<IdBatch>, new: Option<&IdBatch>| match new {
+            Some(new) => current.as_ref().map(|c| c.id) != Some(new.id),
+            None => false,
+        };
+        if changed(&self.streamed, streamed) || changed(&self.buffered, buffered) {
+            self.flush_indices()?;
+        }
+        if let Some(streamed) = streamed {
+            self.streamed = Some(streamed.clone());
+        }
+        if let Some(buffered) = buffered {
+            self.buffered = Some(buffered.clone());
+        }
+        self.row_bytes = [&self.streamed, &self.buffered]
+            .iter()
+            .filter_map(|batch| batch.as_ref())
+            .map(|batch| row_bytes(&batch.batch))
+            .sum();
+        Ok(())
+    }
+
+    /// pushes an output row, indices of absent sides are None. returns an
+    /// output batch if enough rows are accumulated
+    fn push(
+        &mut self,
+        streamed_idx: Option<u32>,
+        buffered_idx: Option<u32>,
+    ) -> Result<Option<RecordBatch>> {
+        self.streamed_indices.push(streamed_idx);
+        self.buffered_indices.push(buffered_idx);
+        if self.streamed_indices.len() >= self.budget.max_rows(self.row_bytes) {
+            self.flush_indices()?;
+        }
+        if self
+            .budget
+            .is_full(self.num_staged_rows, self.num_staged_bytes)
+        {
+            return self.take_staged();
+        }
+        Ok(None)
+    }
+
+    /// outputs all remaining rows
+    fn finish(&mut self) -> Result<Option<RecordBatch>> {
+        self.flush_indices()?;
+        self.take_staged()
+    }
+
+    fn flush_indices(&mut self) -> Result<()> {
+        if self.streamed_indices.is_empty() {
+            return Ok(());
+        }
+        let num_rows = self.streamed_indices.len();
+        let streamed_indices =
+            UInt32Array::from(std::mem::take(&mut self.streamed_indices));
+        let buffered_indices =
+            UInt32Array::from(std::mem::take(&mut self.buffered_indices));
+
+        let num_streamed_columns = self.num_streamed_columns;
+        let mut columns = Vec::with_capacity(self.schema.fields().len());
+        for (i, field) in self.schema.fields().iter().enumerate() {
+            let (batch, indices, column_idx) = if i < num_streamed_columns {
+                (&self.streamed, &streamed_indices, i)
+            } else {
+                (&self.buffered, &buffered_indices, i - num_streamed_columns)
+            };
+            columns.push(match batch {
+                Some(batch) => {
+                    take(batch.batch.column(column_idx).as_ref(), indices, None)?
+                }
+                None => new_null_array(field.data_type(), num_rows),
+            });
+        }
+        let batch = RecordBatch::try_new(self.schema.clone(), columns)?;
+        self.num_staged_rows += num_rows;
+        self.num_staged_bytes += batch_byte_size(&batch);
+        self.staged.push(batch);
+        Ok(())
+    }
+
+    fn take_staged(&mut self) -> Result<Option<RecordBatch>> {
+        if self.staged.is_empty() {
+            return Ok(None);
+        }
+        let batch = RecordBatch::concat(&self.schema, &self.staged)?;
+        self.staged.clear();
+        self.num_staged_rows = 0;
+        self.num_staged_bytes = 0;
+        Ok(Some(batch))
+    }
+}
+
+struct SortMergeJoinStream {
+    schema: SchemaRef,
+    output: Receiver<ArrowResult<RecordBatch>>,
+    join_handle: JoinHandle<()>,
+}
+
+impl Stream for SortMergeJoinStream {
+    type Item = ArrowResult<RecordBatch>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        self.output.poll_recv(cx)
+    }
+}
+
+impl RecordBatchStream for SortMergeJoinStream {
+    fn schema(&self) -> SchemaRef {
+        self.schema.clone()
+    }
+}
+
+impl Drop for SortMergeJoinStream {
+    fn drop(&mut self) {
+        self.join_handle.abort();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use datafusion::arrow::array::{Int32Array, StringArray};
+    use datafusion::arrow::compute::SortOptions;
+    use datafusion::arrow::datatypes::{DataType, Field, Schema};
+    use datafusion::arrow::record_batch::RecordBatch;
+    use datafusion::error::Result;
+    use datafusion::physical_plan::common::collect;
+    use datafusion::physical_plan::expressions::Column;
+    use datafusion::physical_plan::memory::MemoryExec;
+    use datafusion::physical_plan::ExecutionPlan;
+    use datafusion::prelude::{JoinType, SessionContext};
+
+    use super::*;
+    use crate::test_util::build_batch;
+
+    /// rows of the keys, each in a separate batch so that key groups span batches
+    fn sliced_memory_exec(name: &str, keys: Vec<Option<i32>>) -> Result<Arc<MemoryExec>> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new(name, DataType::Int32, true),
+            Field::new(&format!("{}_row", name), DataType::Utf8, false),
+        ]));
+        let rows = (0..keys.len())
+            .map(|i| format!("{}{}", name, i))
+            .collect::<Vec<_>>();
+        let batch = build_batch!(schema, keys, rows)?;
+        let batches = (0..batch.num_rows())
+            .map(|i| batch.slice(i, 1))
+            .collect::<Vec<_>>();
+        Ok(Arc::new(MemoryExec::try_new(&[batches], schema, None)?))
+    }
+
+    fn join_rows(join_type: JoinType) -> Result<Vec<(Option<String>, Option<String>)>> {
+        let left =
+            sliced_memory_exec("l", vec![None, Some(1), Some(2), Some(2), Some(4)])?;
+        let right =
+            sliced_memory_exec("r", vec![None, Some(2), Some(2), Some(3), Some(4)])?;
+        let join = SpillableSortMergeJoinExec::try_new(
+            left,
+            right,
+            vec![(Column::new("l", 0), Column::new("r", 0))],
+            join_type,
+            vec![SortOptions::default()],
+            false,
+            0,
+        )?;
+        let task_ctx = SessionContext::new().task_ctx();
+        let batches = tokio::runtime::Runtime::new()?
+            .block_on(async { collect(join.execute(0, task_ctx)?).await })?;
+
+        let mut rows = vec![];
+        for batch in &batches {
+            let column = |i: usize| -> Option<&StringArray> {
+                batch
+                    .columns()
+                    .get(i)?
+                    .as_any()
+                    .downcast_ref::<StringArray>()
+            };
+            for i in 0..batch.num_rows() {
+                let value = |array: Option<&StringArray>| {
+                    array
+                        .filter(|array| array.is_valid(i))
+                        .map(|array| array.value(i).to_owned())
+                };
+                rows.push((value(column(1)), value(column(3))));
+            }
+        }
+        Ok(rows)
+    }
+
+    #[test]
+    fn test_sort_merge_join() -> Result<()> {
+        let pair = |l: Option<&str>, r: Option<&str>| {
+            (l.map(str::to_owned), r.map(str::to_owned))
+        };
+        assert_eq!(
+            join_rows(JoinType::Inner)?,
+            vec![
+                pair(Some("l2"), Some("r1")),
+                pair(Some("l2"), Some("r2")),
+                pair(Some("l3"), Some("r1")),
+                pair(Some("l3"), Some("r2")),
+                pair(Some("l4"), Some("r4")),
+            ]
+        );
+        assert_eq!(
+            join_rows(JoinType::Full)?,
+            vec![
+                pair(Some("l0"), None),
+                pair(None, Some("r0")),
+                pair(Some("l1"), None),
+                pair(Some("l2"), Some("r1")),
+                pair(Some("l2"), Some("r2")),
+                pair(Some("l3"), Some("r1")),
+                pair(Some("l3"), Some("r2")),
+                pair(None, Some("r3")),
+                pair(Some("l4"), Some("r4")),
+            ]
+        );
+        assert_eq!(
+            join_rows(JoinType::Anti)?,
+            vec![pair(Some("l0"), None), pair(Some("l1"), None)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_join_output_budget() -> Result<()> {
+        // a hot key joining 10 streamed rows with 1000 buffered rows
+        let schema = |name: &str| {
+            Arc::new(Schema::new(vec![
+                Field::new(name, DataType::Int32, true),
+                Field::new(&format!("{}_row", name), DataType::Utf8, false),
+            ]))
+        };
+        let side = |name: &str, num_rows: usize| -> Result<IdBatch> {
+            let keys = vec![Some(1); num_rows];
+            let rows = (0..num_rows)
+                .map(|i| format!("{}{:04}", name, i))
+                .collect::<Vec<_>>();
+            Ok(IdBatch {
+                id: name.len(),
+                batch: build_batch!(schema(name), keys, rows)?,
+            })
+        };
+        let streamed = side("l", 10)?;
+        let buffered = side("rr", 1000)?;
+        let join_schema = build_join_schema(
+            &streamed.batch.schema(),
+            &buffered.batch.schema(),
+            &JoinType::Inner,
+        );
+
+        let max_batch_bytes = 64 << 10;
+        let mut output = JoinOutput::new(
+            Arc::new(join_schema),
+            JoinType::Inner,
+            2,
+            OutputBatchBudget::with_max_batch_bytes(1 << 20, max_batch_bytes),
+        );
+        output.set_batches(Some(&streamed), Some(&buffered))?;
+        let mut batches = vec![];
+        for i in 0..10 {
+            for j in 0..1000 {
+                batches.extend(output.push(Some(i), Some(j))?);
+            }
+        }
+        batches.extend(output.finish()?);
+
+        // output is built in bounded batches instead of a single batch
+        assert!(batches.len() > 1);
+        assert_eq!(batches.iter().map(|b| b.num_rows()).sum::<usize>(), 10000);
+        for batch in &batches {
+            assert!(batch_byte_size(batch) < 2 * max_batch_bytes);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_spilled_batches() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("r", DataType::Int32, true),
+            Field::new("r_row", DataType::Utf8, false),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema,
+            vec![
+                Arc::new(Int32Array::from(vec![Some(1), None])),
+                Arc::new(StringArray::from(vec!["r0", "r1"])),
+            ],
+        )?;
+        let spill_file = SpillManager::get().create_spill_file_for_task(-300)?;
+        let mut spill = SpilledBatches::new(spill_file);
+        spill.write(&batch)?;
+        spill.write(&batch)?;
+
+        // spilled batches are read back once for each streamed batch
+        for _ in 0..2 {
+            let read = spill.read()?.collect::<Result<Vec<_>>>()?;
+            assert_eq!(read, vec![batch.clone(), batch.clone()]);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,299 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Defines an operator splitting oversized batches of its input, and the
+//! budget of output batches built by joins and aggregations
+
+use std::any::Any;
+use std::fmt::Formatter;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use async_trait::async_trait;
+use datafusion::arrow::compute::concat;
+use datafusion::arrow::datatypes::SchemaRef;
+use datafusion::arrow::error::Result as ArrowResult;
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::error::Result;
+use datafusion::execution::context::TaskContext;
+use datafusion::physical_plan::common::batch_byte_size;
+use datafusion::physical_plan::expressions::PhysicalSortExpr;
+use datafusion::physical_plan::{
+    DisplayFormatType, ExecutionPlan, Partitioning, RecordBatchStream,
+    SendableRecordBatchStream, Statistics,
+};
+use futures::{Stream, StreamExt};
+
+use crate::native_conf::native_conf;
+
+/// Bounds output batches of joins and aggregations while they are built:
+/// output rows are built in chunks of at most `max_rows()`, and staged chunks
+/// are output once `is_full()`. a hot join key then produces a stream of
+/// batches of about `max_output_batch_bytes` instead of one giant batch.
+#[derive(Debug, Clone, Copy)]
+pub struct OutputBatchBudget {
+    batch_size: usize,
+    max_batch_bytes: usize,
+}
+
+impl OutputBatchBudget {
+    /// budget of `batch_size` rows and max_output_batch_bytes of native conf
+    pub fn new(batch_size: usize) -> Self {
+        Self::with_max_batch_bytes(batch_size, native_conf().max_output_batch_bytes)
+    }
+
+    /// budget of `batch_size` rows and `max_batch_bytes`, 0 to bound rows only
+    pub fn with_max_batch_bytes(batch_size: usize, max_batch_bytes: usize) -> Self {
+        Self {
+            batch_size: batch_size.max(1),
+            max_batch_bytes: match max_batch_bytes {
+                0 => usize::MAX,
+                max_batch_bytes => max_batch_bytes,
+            },
+        }
+    }
+
+    /// max number of output rows of about `row_bytes` each built at once
+    pub fn max_rows(&self, row_bytes: usize) -> usize {
+        (self.max_batch_bytes / row_bytes.max(1)).clamp(1, self.batch_size)
+    }
+
+    /// returns true if the staged output rows make an output batch
+    pub fn is_full(&self, num_rows: usize, num_bytes: usize) -> bool {
+        num_rows >= self.batch_size || num_bytes >= self.max_batch_bytes
+    }
+}
+
+/// average bytes of a row of the batch
+pub fn row_bytes(batch: &RecordBatch) -> usize {
+    batch_byte_size(batch) / batch.num_rows().max(1)
+}
+
+/// wraps scans and shuffle reads with SplitOversizedBatchesExec if enabled by
+/// native conf. batch_size counts rows, so batches of wide rows (hundreds of
+/// columns or large strings) are split to target a byte budget instead.
+pub fn split_oversized_input_batches(
+    input: Arc<dyn ExecutionPlan>,
+) -> Arc<dyn ExecutionPlan> {
+    match native_conf().max_input_batch_bytes {
+        0 => input,
+        max_batch_bytes => {
+            Arc::new(SplitOversizedBatchesExec::new(input, max_batch_bytes))
+        }
+    }
+}
+
+/// Splits input batches larger than `max_batch_bytes` into row ranges, which
+/// are compacted and streamed one by one. this bounds memory of downstream
+/// operators when scans or shuffle reads produce batches of wide rows.
+#[derive(Debug)]
+pub struct SplitOversizedBatchesExec {
+    input: Arc<dyn ExecutionPlan>,
+    max_batch_bytes: usize,
+}
+
+impl SplitOversizedBatchesExec {
+    pub fn new(input: Arc<dyn ExecutionPlan>, max_batch_bytes: usize) -> Self {
+        Self {
+            input,
+            max_batch_bytes,
+        }
+    }
+
+    pub fn input(&self) -> &Arc<dyn ExecutionPlan> {
+        &self.input
+    }
+}
+
+#[async_trait]
+impl ExecutionPlan for SplitOversizedBatchesExec {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.input.schema()
+    }
+
+    fn output_partitioning(&self) -> Partitioning {
+        self.input.output_partitioning()
+    }
+
+    fn output_ordering(&self) -> Option<&[PhysicalSortExpr]> {
+        self.input.output_ordering()
+    }
+
+    crate::transparent_plan_tree!(input, |self, input| Self::new(
+        input,
+        self.max_batch_bytes
+    ));
+
+    fn execute(
+        &self,
+        partition: usize,
+        context: Arc<TaskContext>,
+    ) -> Result<SendableRecordBatchStream> {
+        Ok(Box::pin(SplitOversizedBatchesStream {
+            input: self.input.execute(partition, context)?,
+            max_batch_bytes: self.max_batch_bytes,
+            pending: None,
+        }))
+    }
+
+    fn fmt_as(&self, t: DisplayFormatType, f: &mut Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "SplitOversizedBatchesExec: max_batch_bytes={}, ",
+            self.max_batch_bytes
+        )?;
+        self.input.fmt_as(t, f)
+    }
+
+    fn statistics(&self) -> Statistics {
+        self.input.statistics()
+    }
+}
+
+struct PendingBatch {
+    batch: RecordBatch,
+    offset: usize,
+    rows_per_slice: usize,
+}
+
+struct SplitOversizedBatchesStream {
+    input: SendableRecordBatchStream,
+    max_batch_bytes: usize,
+    pending: Option<PendingBatch>,
+}
+
+impl Stream for SplitOversizedBatchesStream {
+    type Item = ArrowResult<RecordBatch>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        if let Some(pending) = &mut self.pending {
+            let num_rows = pending.batch.num_rows();
+            let len = pending.rows_per_slice.min(num_rows - pending.offset);
+            let slice = slice_compacted(&pending.batch, pending.offset, len);
+            pending.offset += len;
+            if pending.offset >= num_rows {
+                self.pending = None;
+            }
+            return Poll::Ready(Some(slice));
+        }
+
+        match self.input.poll_next_unpin(cx) {
+            Poll::Ready(Some(Ok(batch))) => {
+                let rows_per_slice = rows_per_slice(
+                    batch.num_rows(),
+                    batch_byte_size(&batch),
+                    self.max_batch_bytes,
+                );
+                if rows_per_slice >= batch.num_rows() {
+                    return Poll::Ready(Some(Ok(batch)));
+                }
+                log::info!(
+                    "splitting oversized batch of {} rows into slices of {} rows",
+                    batch.num_rows(),
+                    rows_per_slice,
+                );
+                self.pending = Some(PendingBatch {
+                    batch,
+                    offset: 0,
+                    rows_per_slice,
+                });
+                self.poll_next(cx)
+            }
+            other => other,
+        }
+    }
+}
+
+impl RecordBatchStream for SplitOversizedBatchesStream {
+    fn schema(&self) -> SchemaRef {
+        self.input.schema()
+    }
+}
+
+fn rows_per_slice(num_rows: usize, batch_bytes: usize, max_batch_bytes: usize) -> usize {
+    if batch_bytes <= max_batch_bytes || num_rows <= 1 {
+        return num_rows;
+    }
+    let num_slices = (batch_bytes + max_batch_bytes - 1) / max_batch_bytes;
+    ((num_rows + num_slices - 1) / num_slices).max(1)
+}
+
+/// slices are copied so that the giant batch can be freed once all slices
+/// are consumed, and only the sliced data is exported through arrow FFI
+fn slice_compacted(
+    batch: &RecordBatch,
+    offset: usize,
+    len: usize,
+) -> ArrowResult<RecordBatch> {
+    let columns = batch
+        .columns()
+        .iter()
+        .map(|column| concat(&[column.slice(offset, len).as_ref()]))
+        .collect::<ArrowResult<Vec<_>>>()?;
+    RecordBatch::try_new(batch.schema(), columns)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use datafusion::arrow::array::{Array, Int32Array};
+    use datafusion::arrow::datatypes::{DataType, Field, Schema};
+
+    #[test]
+    fn test_output_batch_budget() {
+        let budget = OutputBatchBudget::with_max_batch_bytes(1000, 4000);
+        assert_eq!(budget.max_rows(8), 500);
+        assert_eq!(budget.max_rows(1), 1000);
+        assert_eq!(budget.max_rows(10000), 1);
+        assert!(!budget.is_full(999, 3999));
+        assert!(budget.is_full(1000, 0));
+        assert!(budget.is_full(1, 4000));
+
+        let budget = OutputBatchBudget::with_max_batch_bytes(1000, 0);
+        assert_eq!(budget.max_rows(1 << 30), 1000);
+        assert!(!budget.is_full(999, 1 << 40));
+    }
+
+    #[test]
+    fn test_slice_compacted() -> ArrowResult<()> {
+        assert_eq!(rows_per_slice(1000, 100, 100), 1000);
+        assert_eq!(rows_per_slice(1000, 250, 100), 334);
+        assert_eq!(rows_per_slice(1, 250, 100), 1);
+
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int32, false)]));
+        let array = Int32Array::from((0..1000).collect::<Vec<_>>());
+        let batch = RecordBatch::try_new(schema, vec![Arc::new(array)])?;
+
+        let slice = slice_compacted(&batch, 334, 334)?;
+        assert_eq!(slice.num_rows(), 334);
+        assert_eq!(slice.column(0).offset(), 0);
+        assert_eq!(
+            slice
+                .column(0)
+                .as_any()
+                .downcast_ref::<Int32Array>()
+                .unwrap(),
+            &Int32Array::from((334..668).collect::<Vec<_>>())
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,314 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Cancellation of native executions of killed spark tasks.
+//!
+//! the JVM side calls JniBridge.cancelNative() once the task is interrupted or
+//! completed, setting the cancellation flag shared by all native executions
+//! of the task. every operator of a cancellable plan checks the flag before
+//! producing its next batch, so that blocking operators (e.g. sorts consuming
+//! all their input) stop promptly instead of running until the next JNI call.
+//! loops not producing batches, e.g. writing shuffle partitions, check the
+//! flag themselves.
+
+use std::any::Any;
+use std::cell::RefCell;
+use std::fmt::Formatter;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Weak};
+use std::task::{Context, Poll};
+
+use dashmap::DashMap;
+use datafusion::arrow::datatypes::SchemaRef;
+use datafusion::arrow::error::{ArrowError, Result as ArrowResult};
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::error::Result;
+use datafusion::execution::context::TaskContext;
+use datafusion::physical_plan::expressions::PhysicalSortExpr;
+use datafusion::physical_plan::{
+    DisplayFormatType, ExecutionPlan, Partitioning, RecordBatchStream,
+    SendableRecordBatchStream, Statistics,
+};
+use futures::{Stream, StreamExt};
+use once_cell::sync::OnceCell;
+
+use crate::blaze_error::BlazeError;
+use crate::with_new_children_preserving_partitioning;
+
+static TASK_CANCELLATIONS: OnceCell<DashMap<i64, Weak<AtomicBool>>> = OnceCell::new();
+
+thread_local! {
+    static CURRENT_CANCELLATION: RefCell<Option<TaskCancellation>> = RefCell::new(None);
+}
+
+fn task_cancellations() -> &'static DashMap<i64, Weak<AtomicBool>> {
+    TASK_CANCELLATIONS.get_or_init(DashMap::new)
+}
+
+/// The cancellation flag of a spark task. a default cancellation is never
+/// cancelled.
+#[derive(Debug, Clone, Default)]
+pub struct TaskCancellation {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl TaskCancellation {
+    /// returns the cancellation of the task, shared by all native executions
+    /// of the task alive at the same time, e.g. retries of the same plan
+    pub fn register(task_attempt_id: i64) -> Self {
+        let cancellations = task_cancellations();
+        cancellations.retain(|_, cancelled| cancelled.strong_count() > 0);
+
+        let mut entry = cancellations
+            .entry(task_attempt_id)
+            .or_insert_with(Weak::new);
+        let cancelled = entry.upgrade().unwrap_or_else(|| {
+            let cancelled = Arc::new(AtomicBool::new(false));
+            *entry = Arc::downgrade(&cancelled);
+            cancelled
+        });
+        Self { cancelled }
+    }
+
+    /// returns the cancellation set for the current thread, or a cancellation
+    /// never cancelled if not set
+    pub fn current() -> Self {
+        CURRENT_CANCELLATION
+            .with(|current| current.borrow().clone())
+            .unwrap_or_default()
+    }
+
+    /// sets the cancellation returned by `current()` in the current thread
+    pub fn set_current(cancellation: Option<TaskCancellation>) {
+        CURRENT_CANCELLATION.with(|current| *current.borrow_mut() = cancellation);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// returns a cancelled error if the task is cancelled
+    pub fn check(&self) -> Result<()> {
+        if self.is_cancelled() {
+            return Err(BlazeError::Cancelled.into());
+        }
+        Ok(())
+    }
+}
+
+/// cancels all native executions of the task, returns false if the task has
+/// no running native executions
+pub fn cancel_task(task_attempt_id: i64) -> bool {
+    let cancelled = task_cancellations()
+        .remove(&task_attempt_id)
+        .and_then(|(_, cancelled)| cancelled.upgrade());
+    match cancelled {
+        Some(cancelled) => {
+            cancelled.store(true, Ordering::Relaxed);
+            true
+        }
+        None => false,
+    }
+}
+
+/// wraps every operator of the plan with a check of the cancellation
+pub fn cancellable_plan(
+    plan: Arc<dyn ExecutionPlan>,
+    cancellation: TaskCancellation,
+) -> Result<Arc<dyn ExecutionPlan>> {
+    let children = plan
+        .children()
+        .into_iter()
+        .map(|child| cancellable_plan(child, cancellation.clone()))
+        .collect::<Result<Vec<_>>>()?;
+    let plan = if children.is_empty() {
+        plan
+    } else {
+        with_new_children_preserving_partitioning(plan, children)?
+    };
+    Ok(Arc::new(CancellableExec {
+        input: plan,
+        cancellation,
+    }))
+}
+
+/// Fails its input operator with a cancelled error once the task is cancelled.
+#[derive(Debug)]
+struct CancellableExec {
+    input: Arc<dyn ExecutionPlan>,
+    cancellation: TaskCancellation,
+}
+
+impl ExecutionPlan for CancellableExec {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.input.schema()
+    }
+
+    fn output_partitioning(&self) -> Partitioning {
+        self.input.output_partitioning()
+    }
+
+    fn output_ordering(&self) -> Option<&[PhysicalSortExpr]> {
+        self.input.output_ordering()
+    }
+
+    crate::transparent_plan_tree!(input, |self, input| Self {
+        input,
+        cancellation: self.cancellation.clone()
+    });
+
+    fn execute(
+        &self,
+        partition: usize,
+        context: Arc<TaskContext>,
+    ) -> Result<SendableRecordBatchStream> {
+        self.cancellation.check()?;
+        Ok(Box::pin(CancellableStream {
+            input: self.input.execute(partition, context)?,
+            cancellation: self.cancellation.clone(),
+        }))
+    }
+
+    fn fmt_as(&self, t: DisplayFormatType, f: &mut Formatter) -> std::fmt::Result {
+        self.input.fmt_as(t, f)
+    }
+
+    fn statistics(&self) -> Statistics {
+        self.input.statistics()
+    }
+}
+
+struct CancellableStream {
+    input: SendableRecordBatchStream,
+    cancellation: TaskCancellation,
+}
+
+impl Stream for CancellableStream {
+    type Item = ArrowResult<RecordBatch>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        if self.cancellation.is_cancelled() {
+            let err = ArrowError::ExternalError(Box::new(BlazeError::Cancelled));
+            return Poll::Ready(Some(Err(err)));
+        }
+        self.input.poll_next_unpin(cx)
+    }
+}
+
+impl RecordBatchStream for CancellableStream {
+    fn schema(&self) -> SchemaRef {
+        self.input.schema()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use datafusion::arrow::array::Int32Array;
+    use datafusion::arrow::datatypes::{DataType, Field, Schema};
+    use datafusion::arrow::record_batch::RecordBatch;
+    use datafusion::error::Result;
+    use datafusion::physical_plan::common::collect;
+    use datafusion::physical_plan::expressions::col;
+    use datafusion::physical_plan::memory::MemoryExec;
+    use datafusion::physical_plan::sorts::sort::SortExec;
+    use datafusion::prelude::SessionContext;
+    use futures::StreamExt;
+
+    use crate::blaze_error::BlazeError;
+
+    use super::*;
+
+    #[test]
+    fn test_task_cancellation() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int32, false)]));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![Arc::new(Int32Array::from(vec![1, 2, 3]))],
+        )?;
+        let input = Arc::new(MemoryExec::try_new(
+            &[vec![batch.clone(), batch]],
+            schema,
+            None,
+        )?);
+
+        let cancellation = TaskCancellation::register(-1);
+        let retried = TaskCancellation::register(-1);
+        let plan = cancellable_plan(input, cancellation.clone())?;
+        assert!(TaskCancellation::current().check().is_ok());
+        assert!(!cancel_task(-2));
+
+        let runtime = tokio::runtime::Runtime::new()?;
+        let task_ctx = SessionContext::new().task_ctx();
+        let mut stream = plan.execute(0, task_ctx)?;
+        runtime.block_on(async {
+            assert!(stream.next().await.unwrap().is_ok());
+            assert!(cancel_task(-1));
+            assert!(retried.is_cancelled());
+            let err = stream.next().await.unwrap().unwrap_err();
+            assert_eq!(BlazeError::from(err).kind(), "cancelled");
+        });
+        assert!(cancellation.check().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_cancellable_partitioned_sort() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![Field::new("a", DataType::Int32, false)]));
+        let batch = |values: Vec<i32>| {
+            RecordBatch::try_new(schema.clone(), vec![Arc::new(Int32Array::from(values))])
+        };
+        let input = Arc::new(MemoryExec::try_new(
+            &[vec![batch(vec![3, 1, 2])?], vec![batch(vec![6, 4, 5])?]],
+            schema.clone(),
+            None,
+        )?);
+        let sort = Arc::new(SortExec::new_with_partitioning(
+            vec![PhysicalSortExpr {
+                expr: col("a", &schema)?,
+                options: Default::default(),
+            }],
+            input,
+            true,
+        ));
+
+        let plan = cancellable_plan(sort, TaskCancellation::default())?;
+        assert_eq!(plan.output_partitioning().partition_count(), 2);
+
+        let runtime = tokio::runtime::Runtime::new()?;
+        let task_ctx = SessionContext::new().task_ctx();
+        for (partition, expected) in [[1, 2, 3], [4, 5, 6]].into_iter().enumerate() {
+            let stream = plan.execute(partition, task_ctx.clone())?;
+            let batches = runtime.block_on(collect(stream))?;
+            let values = batches
+                .iter()
+                .flat_map(|batch| {
+                    let array = batch.column(0).as_any().downcast_ref::<Int32Array>();
+                    array.unwrap().values().to_vec()
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(values, expected);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,84 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Defines fixtures shared by unit tests of the operators
+
+use std::sync::Arc;
+
+use datafusion::arrow::array::{ArrayRef, Int32Array, Int64Array, StringArray};
+use datafusion::arrow::compute::cast;
+use datafusion::arrow::datatypes::{Field, Schema, SchemaRef};
+use datafusion::arrow::error::Result as ArrowResult;
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::error::Result;
+use datafusion::physical_plan::memory::MemoryExec;
+
+/// values of a column of a test batch
+pub trait TestColumn {
+    fn into_array(self) -> ArrayRef;
+}
+
+macro_rules! impl_test_column {
+    ($array_type:ty, $($value_type:ty),*) => {
+        $(
+            impl TestColumn for Vec<$value_type> {
+                fn into_array(self) -> ArrayRef {
+                    Arc::new(<$array_type>::from(self))
+                }
+            }
+        )*
+    };
+}
+impl_test_column!(Int32Array, i32, Option<i32>);
+impl_test_column!(Int64Array, i64, Option<i64>);
+impl_test_column!(StringArray, &str, Option<&str>, String, Option<String>);
+
+/// builds a batch of the schema from the columns, each cast to the type of
+/// its field (e.g. integer literals to Int64)
+pub fn build_batch(
+    schema: &SchemaRef,
+    columns: Vec<ArrayRef>,
+) -> ArrowResult<RecordBatch> {
+    let columns = columns
+        .iter()
+        .zip(schema.fields())
+        .map(|(column, field)| cast(column, field.data_type()))
+        .collect::<ArrowResult<Vec<_>>>()?;
+    RecordBatch::try_new(schema.clone(), columns)
+}
+
+/// builds a batch of the schema from vectors of values, e.g.
+/// `build_batch!(schema, vec![Some(1), None], vec!["a", "b"])`
+macro_rules! build_batch {
+    ($schema:expr, $($column:expr),+ $(,)?) => {
+        $crate::test_util::build_batch(
+            &$schema,
+            vec![$($crate::test_util::TestColumn::into_array($column)),+],
+        )
+    };
+}
+pub(crate) use build_batch;
+
+/// returns a MemoryExec of a single batch with one nullable column of the
+/// values
+pub fn memory_exec(name: &str, values: impl TestColumn) -> Result<Arc<MemoryExec>> {
+    let array = values.into_array();
+    let schema = Arc::new(Schema::new(vec![Field::new(
+        name,
+        array.data_type().clone(),
+        true,
+    )]));
+    let batch = RecordBatch::try_new(schema.clone(), vec![array])?;
+    Ok(Arc::new(MemoryExec::try_new(&[vec![batch]], schema, None)?))
+}
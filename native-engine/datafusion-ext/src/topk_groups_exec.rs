@@ -0,0 +1,514 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Top-n groups of final aggregations sorted by group keys and limited.
+//!
+//! for a limit over a sort of a final aggregation by its group keys, e.g.
+//! `GROUP BY k ORDER BY k LIMIT 100`, only the n first keys of each partition
+//! are in the output. rows of the aggregation input are pruned once their
+//! keys sort after the n first distinct keys seen so far, since at least n
+//! groups sort before them, so that the hash table of the aggregation only
+//! holds groups which may be in the output.
+//!
+//! groups sorted by aggregated values are not pruned, since values of a group
+//! still change with its later input rows.
+//!
+//! the same applies to a limited sort of rows read from a shuffle, e.g.
+//! `ORDER BY k LIMIT 100` over an exchange, where rows sorting after the n
+//! first rows seen so far are pruned from each decoded segment, before they
+//! are buffered by the sort.
+
+use std::any::Any;
+use std::cmp::Ordering;
+use std::fmt::Formatter;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+use datafusion::arrow::array::{ArrayRef, BooleanArray, UInt32Array};
+use datafusion::arrow::compute::{
+    concat, filter, filter_record_batch, lexsort_to_indices, take, SortColumn,
+};
+use datafusion::arrow::datatypes::SchemaRef;
+use datafusion::arrow::error::{ArrowError, Result as ArrowResult};
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::error::Result;
+use datafusion::execution::context::TaskContext;
+use datafusion::physical_plan::aggregates::{AggregateExec, AggregateMode};
+use datafusion::physical_plan::expressions::{Column, PhysicalSortExpr};
+use datafusion::physical_plan::sorts::sort::SortExec;
+use datafusion::physical_plan::{
+    DisplayFormatType, ExecutionPlan, Partitioning, RecordBatchStream,
+    SendableRecordBatchStream, Statistics,
+};
+use futures::{Stream, StreamExt};
+
+use crate::radix_sort_exec::RadixSortExec;
+use crate::rename_columns_exec::RenameColumnsExec;
+use crate::shuffle_reader_exec::ShuffleReaderExec;
+use crate::spillable_sort_merge_join_exec::KeyComparator;
+use crate::split_oversized_batches_exec::{
+    split_oversized_input_batches, SplitOversizedBatchesExec,
+};
+use crate::with_new_children_preserving_partitioning;
+
+/// prunes input rows of the aggregation under a sort by its group keys, if
+/// the sort is limited to at most `max_limit` rows. other plans are returned
+/// as is.
+pub fn topk_aggregate(
+    sort: Arc<dyn ExecutionPlan>,
+    limit: usize,
+    max_limit: usize,
+) -> Result<Arc<dyn ExecutionPlan>> {
+    if limit == 0 || limit > max_limit {
+        return Ok(sort);
+    }
+    let sort_exprs = match sort_exprs(&sort) {
+        Some(sort_exprs) => sort_exprs,
+        None => return Ok(sort),
+    };
+    let sort_input = sort.children()[0].clone();
+    let agg = match sort_input.as_any().downcast_ref::<AggregateExec>() {
+        Some(agg)
+            if matches!(
+                agg.mode(),
+                AggregateMode::Final | AggregateMode::FinalPartitioned
+            ) =>
+        {
+            agg
+        }
+        _ => return Ok(sort),
+    };
+
+    // all sort keys must be group keys, which are the first output columns
+    let mut keys = vec![];
+    for sort_expr in &sort_exprs {
+        match sort_expr.expr.as_any().downcast_ref::<Column>() {
+            Some(column) if column.index() < agg.group_expr().len() => {
+                keys.push(PhysicalSortExpr {
+                    expr: agg.group_expr()[column.index()].0.clone(),
+                    options: sort_expr.options,
+                });
+            }
+            _ => return Ok(sort),
+        }
+    }
+    if keys.is_empty() {
+        return Ok(sort);
+    }
+
+    let topk_groups = Arc::new(TopKGroupsExec {
+        input: agg.input().clone(),
+        keys,
+        limit,
+    });
+    let sort_input =
+        with_new_children_preserving_partitioning(sort_input, vec![topk_groups])?;
+    with_new_children_preserving_partitioning(sort, vec![sort_input])
+}
+
+/// prunes rows of each segment read by the shuffle reader under a sort, if
+/// the sort is limited to at most `max_limit` rows. other plans are returned
+/// as is.
+pub fn topk_shuffle_read(
+    sort: Arc<dyn ExecutionPlan>,
+    limit: usize,
+    max_limit: usize,
+) -> Result<Arc<dyn ExecutionPlan>> {
+    if limit == 0 || limit > max_limit {
+        return Ok(sort);
+    }
+    let sort_exprs = match sort_exprs(&sort) {
+        Some(sort_exprs) => sort_exprs,
+        None => return Ok(sort),
+    };
+
+    // sort keys are evaluated on the reader output through operators keeping
+    // rows and columns as is
+    let sort_input = sort.children()[0].clone();
+    let rename = sort_input
+        .as_any()
+        .is::<RenameColumnsExec>()
+        .then(|| sort_input.clone());
+    let plan = match &rename {
+        Some(rename) => rename.children()[0].clone(),
+        None => sort_input,
+    };
+    let (plan, split) = match plan.as_any().downcast_ref::<SplitOversizedBatchesExec>() {
+        Some(split) => (split.input().clone(), true),
+        None => (plan, false),
+    };
+    let reader = match plan.as_any().downcast_ref::<ShuffleReaderExec>() {
+        Some(reader) if reader.topk.is_none() => reader,
+        _ => return Ok(sort),
+    };
+
+    let mut plan: Arc<dyn ExecutionPlan> = Arc::new(reader.with_topk(sort_exprs, limit));
+    if split {
+        plan = split_oversized_input_batches(plan);
+    }
+    if let Some(rename) = rename {
+        plan = with_new_children_preserving_partitioning(rename, vec![plan])?;
+    }
+    with_new_children_preserving_partitioning(sort, vec![plan])
+}
+
+fn sort_exprs(sort: &Arc<dyn ExecutionPlan>) -> Option<Vec<PhysicalSortExpr>> {
+    if let Some(sort) = sort.as_any().downcast_ref::<SortExec>() {
+        Some(sort.expr().to_vec())
+    } else {
+        sort.as_any()
+            .downcast_ref::<RadixSortExec>()
+            .map(|sort| vec![sort.sort_expr().clone()])
+    }
+}
+
+/// Prunes input rows of an aggregation not in the top-n groups.
+#[derive(Debug)]
+pub struct TopKGroupsExec {
+    input: Arc<dyn ExecutionPlan>,
+    /// group keys evaluated on the input, with the options of the sort
+    keys: Vec<PhysicalSortExpr>,
+    limit: usize,
+}
+
+impl TopKGroupsExec {
+    pub fn input(&self) -> &Arc<dyn ExecutionPlan> {
+        &self.input
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// returns the same pruning of another input, e.g. a leaf converted again
+    /// by the plan cache
+    pub fn with_input(&self, input: Arc<dyn ExecutionPlan>) -> Self {
+        Self {
+            input,
+            keys: self.keys.clone(),
+            limit: self.limit,
+        }
+    }
+}
+
+impl ExecutionPlan for TopKGroupsExec {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.input.schema()
+    }
+
+    fn output_partitioning(&self) -> Partitioning {
+        self.input.output_partitioning()
+    }
+
+    fn output_ordering(&self) -> Option<&[PhysicalSortExpr]> {
+        self.input.output_ordering()
+    }
+
+    crate::transparent_plan_tree!(input, |self, input| Self {
+        input,
+        keys: self.keys.clone(),
+        limit: self.limit
+    });
+
+    fn execute(
+        &self,
+        partition: usize,
+        context: Arc<TaskContext>,
+    ) -> Result<SendableRecordBatchStream> {
+        Ok(Box::pin(TopKGroupsStream {
+            input: self.input.execute(partition, context)?,
+            threshold: TopKThreshold::new(self.keys.clone(), self.limit, true),
+        }))
+    }
+
+    fn fmt_as(&self, t: DisplayFormatType, f: &mut Formatter) -> std::fmt::Result {
+        self.input.fmt_as(t, f)
+    }
+
+    fn statistics(&self) -> Statistics {
+        self.input.statistics()
+    }
+}
+
+struct TopKGroupsStream {
+    input: SendableRecordBatchStream,
+    threshold: TopKThreshold,
+}
+
+/// Prunes rows sorting after the first `limit` keys seen so far
+pub struct TopKThreshold {
+    keys: Vec<PhysicalSortExpr>,
+    limit: usize,
+    /// whether equal keys are counted once, as keys of groups
+    distinct: bool,
+    /// first keys of the input in the sort order, at most `limit` rows. empty
+    /// if no rows are seen.
+    top_keys: Vec<ArrayRef>,
+}
+
+impl TopKThreshold {
+    pub fn new(keys: Vec<PhysicalSortExpr>, limit: usize, distinct: bool) -> Self {
+        Self {
+            keys,
+            limit,
+            distinct,
+            top_keys: vec![],
+        }
+    }
+
+    /// returns the rows of the batch which may sort before the `limit`-th key
+    pub fn prune(&mut self, batch: RecordBatch) -> Result<RecordBatch> {
+        let num_rows = batch.num_rows();
+        let options = self.keys.iter().map(|key| key.options).collect::<Vec<_>>();
+        let batch_keys = self
+            .keys
+            .iter()
+            .map(|key| Ok(key.expr.evaluate(&batch)?.into_array(num_rows)))
+            .collect::<Result<Vec<_>>>()?;
+
+        // rows sorting after the last of `limit` top keys are pruned
+        let top_full = self.top_keys.first().map(|keys| keys.len()) == Some(self.limit);
+        let (batch, batch_keys) = if top_full {
+            let comparator =
+                KeyComparator::try_new(&batch_keys, &self.top_keys, &options)?;
+            let last = self.limit - 1;
+            let selected = (0..num_rows)
+                .map(|i| Some(comparator.compare(i, last) != Ordering::Greater))
+                .collect::<BooleanArray>();
+            let batch_keys = batch_keys
+                .iter()
+                .map(|keys| filter(keys.as_ref(), &selected))
+                .collect::<ArrowResult<Vec<_>>>()?;
+            (filter_record_batch(&batch, &selected)?, batch_keys)
+        } else {
+            (batch, batch_keys)
+        };
+        if batch.num_rows() == 0 {
+            return Ok(batch);
+        }
+
+        // merges keys of the remaining rows into the top keys
+        let candidates = if self.top_keys.is_empty() {
+            batch_keys
+        } else {
+            self.top_keys
+                .iter()
+                .zip(&batch_keys)
+                .map(|(top_keys, keys)| concat(&[top_keys.as_ref(), keys.as_ref()]))
+                .collect::<ArrowResult<Vec<_>>>()?
+        };
+        let sort_columns = candidates
+            .iter()
+            .zip(&options)
+            .map(|(keys, options)| SortColumn {
+                values: keys.clone(),
+                options: Some(*options),
+            })
+            .collect::<Vec<_>>();
+        let sorted = lexsort_to_indices(&sort_columns, None)?;
+        let comparator = KeyComparator::try_new(&candidates, &candidates, &options)?;
+        let mut top: Vec<u32> = vec![];
+        for &idx in sorted.values() {
+            if top.len() == self.limit {
+                break;
+            }
+            match top.last() {
+                Some(&last)
+                    if self.distinct
+                        && comparator.compare(last as usize, idx as usize)
+                            == Ordering::Equal => {}
+                _ => top.push(idx),
+            }
+        }
+        let top = UInt32Array::from(top);
+        self.top_keys = candidates
+            .iter()
+            .map(|keys| take(keys.as_ref(), &top, None))
+            .collect::<ArrowResult<Vec<_>>>()?;
+        Ok(batch)
+    }
+}
+
+impl Stream for TopKGroupsStream {
+    type Item = ArrowResult<RecordBatch>;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        loop {
+            let batch = match self.input.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(batch))) => batch,
+                other => return other,
+            };
+            match self.threshold.prune(batch) {
+                Ok(batch) if batch.num_rows() == 0 => continue,
+                Ok(batch) => return Poll::Ready(Some(Ok(batch))),
+                Err(err) => {
+                    let err = ArrowError::ExternalError(Box::new(err));
+                    return Poll::Ready(Some(Err(err)));
+                }
+            }
+        }
+    }
+}
+
+impl RecordBatchStream for TopKGroupsStream {
+    fn schema(&self) -> SchemaRef {
+        self.input.schema()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use datafusion::arrow::array::Int32Array;
+    use datafusion::arrow::compute::SortOptions;
+    use datafusion::arrow::datatypes::{DataType, Field, Schema};
+    use datafusion::physical_plan::common::collect;
+    use datafusion::physical_plan::expressions::col;
+    use datafusion::physical_plan::limit::LocalLimitExec;
+    use datafusion::physical_plan::memory::MemoryExec;
+    use datafusion::prelude::SessionContext;
+
+    use crate::shuffle_reader_exec::ShuffleSegmentSource;
+
+    use super::*;
+    use crate::test_util::build_batch;
+
+    #[test]
+    fn test_topk_groups() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![Field::new("k", DataType::Int32, true)]));
+        let batches = vec![
+            build_batch!(schema, vec![Some(5), Some(3), Some(9), Some(3), None])?,
+            build_batch!(schema, vec![Some(7), Some(4), Some(8), Some(1), Some(3)])?,
+            build_batch!(schema, vec![Some(4), Some(6)])?,
+        ];
+        let input = Arc::new(MemoryExec::try_new(&[batches], schema.clone(), None)?);
+        let agg = Arc::new(AggregateExec::try_new(
+            AggregateMode::Final,
+            vec![(col("k", &schema)?, "k".to_owned())],
+            vec![],
+            input.clone(),
+            schema.clone(),
+        )?);
+        let options = SortOptions {
+            descending: false,
+            nulls_first: false,
+        };
+        let sort = Arc::new(SortExec::try_new(
+            vec![PhysicalSortExpr {
+                expr: col("k", &agg.schema())?,
+                options,
+            }],
+            agg,
+        )?);
+        let sort = topk_aggregate(sort, 3, 10)?;
+        let topk_groups = sort.children()[0].children()[0].clone();
+        let topk_groups = topk_groups
+            .as_any()
+            .downcast_ref::<TopKGroupsExec>()
+            .unwrap();
+        assert_eq!(topk_groups.limit(), 3);
+
+        // top keys are 3, 5, 9 after the first batch and 1, 3, 4 after the
+        // second one, so only key 6 of the last batch is pruned
+        let task_ctx = SessionContext::new().task_ctx();
+        let runtime = tokio::runtime::Runtime::new()?;
+        let pruned =
+            runtime.block_on(collect(topk_groups.execute(0, task_ctx.clone())?))?;
+        let num_pruned_rows = pruned.iter().map(|batch| batch.num_rows()).sum::<usize>();
+        assert_eq!(num_pruned_rows, 5 + 5 + 1);
+
+        let output = runtime.block_on(collect(
+            Arc::new(LocalLimitExec::new(sort, 3)).execute(0, task_ctx)?,
+        ))?;
+        let keys = output
+            .iter()
+            .flat_map(|batch| {
+                let keys = batch
+                    .column(0)
+                    .as_any()
+                    .downcast_ref::<Int32Array>()
+                    .unwrap();
+                keys.iter().collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(keys, vec![Some(1), Some(3), Some(4)]);
+
+        // groups not sorted by group keys are not pruned
+        let sort = Arc::new(SortExec::try_new(
+            vec![PhysicalSortExpr {
+                expr: col("k", &schema)?,
+                options,
+            }],
+            input,
+        )?);
+        let unchanged = topk_aggregate(sort.clone(), 3, 10)?;
+        assert!(Arc::ptr_eq(&unchanged, &(sort as Arc<dyn ExecutionPlan>)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_topk_shuffle_read() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![Field::new("k", DataType::Int32, true)]));
+        let keys = vec![PhysicalSortExpr {
+            expr: col("k", &schema)?,
+            options: SortOptions::default(),
+        }];
+
+        // equal keys are all counted, 3 is pruned once 1, 1, 2 are seen
+        let mut threshold = TopKThreshold::new(keys.clone(), 3, false);
+        let num_rows = |batch: RecordBatch| batch.num_rows();
+        assert_eq!(
+            num_rows(threshold.prune(build_batch!(schema, vec![Some(1), Some(4)])?)?),
+            2
+        );
+        assert_eq!(
+            num_rows(threshold.prune(build_batch!(schema, vec![Some(4), Some(1)])?)?),
+            2
+        );
+        assert_eq!(
+            num_rows(threshold.prune(build_batch!(schema, vec![Some(2), Some(5)])?)?),
+            1
+        );
+        assert_eq!(
+            num_rows(threshold.prune(build_batch!(schema, vec![Some(3), Some(1)])?)?),
+            1
+        );
+
+        let reader = Arc::new(ShuffleReaderExec::new(
+            1,
+            "shuffle".to_owned(),
+            schema.clone(),
+            ShuffleSegmentSource::SegmentChannels,
+            vec![],
+            Statistics::default(),
+        ));
+        let sort = Arc::new(SortExec::try_new(keys, reader)?);
+        let sort = topk_shuffle_read(sort, 3, 10)?;
+        let reader = sort.children()[0].clone();
+        let reader = reader.as_any().downcast_ref::<ShuffleReaderExec>().unwrap();
+        assert_eq!(reader.topk.as_ref().map(|(_, limit)| *limit), Some(3));
+
+        // sorts with limits over max_limit are unchanged
+        let unchanged = topk_shuffle_read(sort.clone(), 11, 10)?;
+        assert!(Arc::ptr_eq(&unchanged, &sort));
+        Ok(())
+    }
+}
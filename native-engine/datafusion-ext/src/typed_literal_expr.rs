@@ -0,0 +1,129 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Defines null and empty collection literals of arbitrary data types
+
+use std::any::Any;
+use std::fmt::{Display, Formatter};
+
+use datafusion::arrow::array::{
+    make_array, new_empty_array, new_null_array, ArrayData, ArrayRef,
+};
+use datafusion::arrow::buffer::Buffer;
+use datafusion::arrow::datatypes::{DataType, Schema};
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::physical_plan::{ColumnarValue, PhysicalExpr};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TypedLiteralKind {
+    Null,
+    EmptyCollection,
+}
+
+/// A literal which is built directly as an array of its data type, used for
+/// values like `CAST(NULL AS ARRAY<STRUCT<...>>)` or `map()` which cannot be
+/// represented by ScalarValue.
+#[derive(Debug)]
+pub struct TypedLiteralExpr {
+    data_type: DataType,
+    kind: TypedLiteralKind,
+}
+
+impl TypedLiteralExpr {
+    pub fn new_null(data_type: DataType) -> Self {
+        Self {
+            data_type,
+            kind: TypedLiteralKind::Null,
+        }
+    }
+
+    pub fn try_new_empty_collection(data_type: DataType) -> Result<Self> {
+        match &data_type {
+            DataType::List(_) | DataType::LargeList(_) | DataType::Map(_, _) => {
+                Ok(Self {
+                    data_type,
+                    kind: TypedLiteralKind::EmptyCollection,
+                })
+            }
+            other => Err(DataFusionError::Plan(format!(
+                "TypedLiteralExpr: empty collection of {:?} is not supported",
+                other
+            ))),
+        }
+    }
+
+    pub fn kind(&self) -> TypedLiteralKind {
+        self.kind
+    }
+}
+
+impl Display for TypedLiteralExpr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.kind {
+            TypedLiteralKind::Null => write!(f, "NULL::{:?}", self.data_type),
+            TypedLiteralKind::EmptyCollection => write!(f, "EMPTY::{:?}", self.data_type),
+        }
+    }
+}
+
+impl PhysicalExpr for TypedLiteralExpr {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn data_type(&self, _input_schema: &Schema) -> Result<DataType> {
+        Ok(self.data_type.clone())
+    }
+
+    fn nullable(&self, _input_schema: &Schema) -> Result<bool> {
+        Ok(self.kind == TypedLiteralKind::Null)
+    }
+
+    fn evaluate(&self, batch: &RecordBatch) -> Result<ColumnarValue> {
+        let num_rows = batch.num_rows();
+        Ok(ColumnarValue::Array(match self.kind {
+            TypedLiteralKind::Null => new_null_array(&self.data_type, num_rows),
+            TypedLiteralKind::EmptyCollection => {
+                new_empty_collection_array(&self.data_type, num_rows)?
+            }
+        }))
+    }
+}
+
+/// creates an array of `num_rows` empty lists/maps, all offsets are zero
+fn new_empty_collection_array(data_type: &DataType, num_rows: usize) -> Result<ArrayRef> {
+    let (offsets, child_type) = match data_type {
+        DataType::List(field) | DataType::Map(field, _) => (
+            Buffer::from_slice_ref(&vec![0i32; num_rows + 1]),
+            field.data_type(),
+        ),
+        DataType::LargeList(field) => (
+            Buffer::from_slice_ref(&vec![0i64; num_rows + 1]),
+            field.data_type(),
+        ),
+        other => {
+            return Err(DataFusionError::Execution(format!(
+                "TypedLiteralExpr: empty collection of {:?} is not supported",
+                other
+            )));
+        }
+    };
+    let data = ArrayData::builder(data_type.clone())
+        .len(num_rows)
+        .add_buffer(offsets)
+        .add_child_data(new_empty_array(child_type).data().clone())
+        .build()?;
+    Ok(make_array(data))
+}
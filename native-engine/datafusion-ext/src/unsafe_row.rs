@@ -0,0 +1,376 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Converts record batches from/into spark's UnsafeRow format
+//!
+//! each row is laid out as spark's UnsafeRowWriter does:
+//!  * null bitset, one bit per field, padded to 8-byte words.
+//!  * one 8-byte slot per field, holding the value of fixed-length fields, or
+//!    `(offset << 32) | size` of variable-length fields.
+//!  * variable-length data, each padded to 8 bytes. offsets are relative to
+//!    the start of the row.
+//!
+//! rows are written one after another, each prefixed with its size as a 4-byte
+//! integer. all values are in little endian, same as spark's Platform on
+//! supported architectures.
+
+use std::sync::Arc;
+
+use datafusion::arrow::array::*;
+use datafusion::arrow::datatypes::{DataType, Schema, SchemaRef, TimeUnit};
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::error::{DataFusionError, Result};
+
+/// returns true if all fields of the schema can be written as UnsafeRow by
+/// `write_unsafe_rows()`
+pub fn is_unsafe_row_convertible(schema: &Schema) -> bool {
+    schema
+        .fields()
+        .iter()
+        .all(|field| is_data_type_convertible(field.data_type()))
+}
+
+fn is_data_type_convertible(data_type: &DataType) -> bool {
+    matches!(
+        data_type,
+        DataType::Null
+            | DataType::Boolean
+            | DataType::Int8
+            | DataType::Int16
+            | DataType::Int32
+            | DataType::Int64
+            | DataType::Float32
+            | DataType::Float64
+            | DataType::Date32
+            | DataType::Timestamp(TimeUnit::Microsecond, _)
+            | DataType::Utf8
+            | DataType::Binary
+    ) || matches!(data_type, DataType::Decimal(precision, _) if *precision <= 18)
+}
+
+macro_rules! write_fixed {
+    ($output:expr, $slot:expr, $column:expr, $arraytype:ident, $row:expr) => {{
+        let array = $column.as_any().downcast_ref::<$arraytype>().unwrap();
+        let bytes = array.value($row).to_le_bytes();
+        $output[$slot..$slot + bytes.len()].copy_from_slice(&bytes);
+    }};
+}
+
+macro_rules! write_var_len {
+    ($output:expr, $base:expr, $slot:expr, $column:expr, $arraytype:ident, $row:expr) => {{
+        let array = $column.as_any().downcast_ref::<$arraytype>().unwrap();
+        let bytes: &[u8] = array.value($row).as_ref();
+        let offset = $output.len() - $base;
+        $output.extend_from_slice(bytes);
+        $output.resize($base + round_up_to_word(offset + bytes.len()), 0);
+
+        let offset_and_size = (offset as u64) << 32 | bytes.len() as u64;
+        $output[$slot..$slot + 8].copy_from_slice(&offset_and_size.to_le_bytes());
+    }};
+}
+
+/// appends all rows of the batch to `output` in UnsafeRow format
+pub fn write_unsafe_rows(batch: &RecordBatch, output: &mut Vec<u8>) -> Result<()> {
+    if !is_unsafe_row_convertible(&batch.schema()) {
+        return Err(DataFusionError::NotImplemented(format!(
+            "Converting schema to UnsafeRow is not supported: {:?}",
+            batch.schema()
+        )));
+    }
+    let num_fields = batch.num_columns();
+    let null_bitset_width = (num_fields + 63) / 64 * 8;
+    let fixed_width = null_bitset_width + num_fields * 8;
+
+    for row in 0..batch.num_rows() {
+        let size_pos = output.len();
+        output.extend_from_slice(&[0u8; 4]);
+        let base = output.len();
+        output.resize(base + fixed_width, 0);
+
+        for (i, column) in batch.columns().iter().enumerate() {
+            if column.is_null(row) {
+                output[base + i / 8] |= 1 << (i % 8);
+                continue;
+            }
+            let slot = base + null_bitset_width + i * 8;
+            match column.data_type() {
+                DataType::Boolean => {
+                    let array = column.as_any().downcast_ref::<BooleanArray>().unwrap();
+                    output[slot] = array.value(row) as u8;
+                }
+                DataType::Int8 => write_fixed!(output, slot, column, Int8Array, row),
+                DataType::Int16 => write_fixed!(output, slot, column, Int16Array, row),
+                DataType::Int32 => write_fixed!(output, slot, column, Int32Array, row),
+                DataType::Int64 => write_fixed!(output, slot, column, Int64Array, row),
+                DataType::Float32 => {
+                    write_fixed!(output, slot, column, Float32Array, row)
+                }
+                DataType::Float64 => {
+                    write_fixed!(output, slot, column, Float64Array, row)
+                }
+                DataType::Date32 => write_fixed!(output, slot, column, Date32Array, row),
+                DataType::Timestamp(TimeUnit::Microsecond, _) => {
+                    write_fixed!(output, slot, column, TimestampMicrosecondArray, row)
+                }
+                DataType::Decimal(_, _) => {
+                    // compact decimal, stored as unscaled long
+                    let array = column.as_any().downcast_ref::<DecimalArray>().unwrap();
+                    let unscaled = array.value(row) as i64;
+                    output[slot..slot + 8].copy_from_slice(&unscaled.to_le_bytes());
+                }
+                DataType::Utf8 => {
+                    write_var_len!(output, base, slot, column, StringArray, row)
+                }
+                DataType::Binary => {
+                    write_var_len!(output, base, slot, column, BinaryArray, row)
+                }
+                _ => unreachable!(), // checked by is_unsafe_row_convertible()
+            }
+        }
+
+        let row_size = (output.len() - base) as i32;
+        output[size_pos..base].copy_from_slice(&row_size.to_le_bytes());
+    }
+    Ok(())
+}
+
+fn round_up_to_word(num_bytes: usize) -> usize {
+    (num_bytes + 7) / 8 * 8
+}
+
+/// A row in UnsafeRow format, whose size is already validated to hold all
+/// fixed-length slots
+struct UnsafeRowRef<'a> {
+    data: &'a [u8],
+    null_bitset_width: usize,
+}
+
+impl<'a> UnsafeRowRef<'a> {
+    fn is_null(&self, i: usize) -> bool {
+        self.data[i / 8] & (1 << (i % 8)) != 0
+    }
+
+    fn fixed<const N: usize>(&self, i: usize) -> Option<[u8; N]> {
+        if self.is_null(i) {
+            return None;
+        }
+        let slot = self.null_bitset_width + i * 8;
+        Some(self.data[slot..slot + N].try_into().unwrap())
+    }
+
+    fn var_len(&self, i: usize) -> Result<Option<&'a [u8]>> {
+        let offset_and_size = match self.fixed::<8>(i) {
+            Some(bytes) => u64::from_le_bytes(bytes),
+            None => return Ok(None),
+        };
+        let offset = (offset_and_size >> 32) as usize;
+        let size = (offset_and_size & 0xffffffff) as usize;
+        offset
+            .checked_add(size)
+            .and_then(|end| self.data.get(offset..end))
+            .map(Some)
+            .ok_or_else(|| {
+                DataFusionError::Execution(format!(
+                    "UnsafeRow: variable-length field {} out of bounds",
+                    i
+                ))
+            })
+    }
+}
+
+macro_rules! read_fixed {
+    ($rows:expr, $i:expr, $arraytype:ident, $nativetype:ty) => {{
+        const N: usize = std::mem::size_of::<$nativetype>();
+        let values = $rows
+            .iter()
+            .map(|row| row.fixed::<N>($i).map(<$nativetype>::from_le_bytes))
+            .collect::<Vec<_>>();
+        Arc::new($arraytype::from(values)) as ArrayRef
+    }};
+}
+
+macro_rules! read_var_len {
+    ($rows:expr, $i:expr, $buildertype:ident, $convert:expr) => {{
+        let convert = $convert;
+        let mut builder = $buildertype::new($rows.len());
+        for row in &$rows {
+            match row.var_len($i)? {
+                Some(bytes) => builder.append_value(convert(bytes)?)?,
+                None => builder.append_null()?,
+            }
+        }
+        Arc::new(builder.finish()) as ArrayRef
+    }};
+}
+
+/// decodes size-prefixed UnsafeRows written by `write_unsafe_rows()` or the
+/// JVM side into a record batch
+pub fn read_unsafe_rows(data: &[u8], schema: &SchemaRef) -> Result<RecordBatch> {
+    if !is_unsafe_row_convertible(schema) {
+        return Err(DataFusionError::NotImplemented(format!(
+            "Converting UnsafeRow to schema is not supported: {:?}",
+            schema
+        )));
+    }
+    let num_fields = schema.fields().len();
+    let null_bitset_width = (num_fields + 63) / 64 * 8;
+    let fixed_width = null_bitset_width + num_fields * 8;
+
+    let mut rows = vec![];
+    let mut pos = 0;
+    while pos < data.len() {
+        let row_size = data
+            .get(pos..pos + 4)
+            .map(|bytes| i32::from_le_bytes(bytes.try_into().unwrap()))
+            .and_then(|row_size| usize::try_from(row_size).ok())
+            .filter(|&row_size| row_size >= fixed_width)
+            .filter(|&row_size| match pos.checked_add(4 + row_size) {
+                Some(row_end) => row_end <= data.len(),
+                None => false,
+            })
+            .ok_or_else(|| {
+                DataFusionError::Execution(format!(
+                    "UnsafeRow: invalid row size at position {}",
+                    pos
+                ))
+            })?;
+        rows.push(UnsafeRowRef {
+            data: &data[pos + 4..pos + 4 + row_size],
+            null_bitset_width,
+        });
+        pos += 4 + row_size;
+    }
+
+    let columns = schema
+        .fields()
+        .iter()
+        .enumerate()
+        .map(|(i, field)| -> Result<ArrayRef> {
+            Ok(match field.data_type() {
+                DataType::Null => Arc::new(NullArray::new(rows.len())) as ArrayRef,
+                DataType::Boolean => {
+                    let values = rows
+                        .iter()
+                        .map(|row| row.fixed::<1>(i).map(|b| b[0] != 0))
+                        .collect::<Vec<_>>();
+                    Arc::new(BooleanArray::from(values)) as ArrayRef
+                }
+                DataType::Int8 => read_fixed!(rows, i, Int8Array, i8),
+                DataType::Int16 => read_fixed!(rows, i, Int16Array, i16),
+                DataType::Int32 => read_fixed!(rows, i, Int32Array, i32),
+                DataType::Int64 => read_fixed!(rows, i, Int64Array, i64),
+                DataType::Float32 => read_fixed!(rows, i, Float32Array, f32),
+                DataType::Float64 => read_fixed!(rows, i, Float64Array, f64),
+                DataType::Date32 => read_fixed!(rows, i, Date32Array, i32),
+                DataType::Timestamp(TimeUnit::Microsecond, tz) => {
+                    let values = rows
+                        .iter()
+                        .map(|row| row.fixed::<8>(i).map(i64::from_le_bytes))
+                        .collect::<Vec<_>>();
+                    Arc::new(TimestampMicrosecondArray::from_opt_vec(values, tz.clone()))
+                        as ArrayRef
+                }
+                DataType::Decimal(precision, scale) => {
+                    let mut builder = DecimalBuilder::new(rows.len(), *precision, *scale);
+                    for row in &rows {
+                        match row.fixed::<8>(i) {
+                            Some(bytes) => {
+                                builder.append_value(i64::from_le_bytes(bytes) as i128)?
+                            }
+                            None => builder.append_null()?,
+                        }
+                    }
+                    Arc::new(builder.finish()) as ArrayRef
+                }
+                DataType::Utf8 => read_var_len!(rows, i, StringBuilder, |bytes| {
+                    std::str::from_utf8(bytes).map_err(|err| {
+                        DataFusionError::Execution(format!("UnsafeRow: {}", err))
+                    })
+                }),
+                DataType::Binary => {
+                    read_var_len!(rows, i, BinaryBuilder, |bytes| Result::Ok(bytes))
+                }
+                _ => unreachable!(), // checked by is_unsafe_row_convertible()
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok(RecordBatch::try_new(schema.clone(), columns)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use datafusion::arrow::datatypes::Field;
+
+    #[test]
+    fn test_write_unsafe_rows() -> Result<()> {
+        let schema = Schema::new(vec![
+            Field::new("i", DataType::Int32, true),
+            Field::new("s", DataType::Utf8, true),
+        ]);
+        let batch = RecordBatch::try_new(
+            Arc::new(schema),
+            vec![
+                Arc::new(Int32Array::from(vec![Some(7), None])),
+                Arc::new(StringArray::from(vec![Some("hello blaze"), None])),
+            ],
+        )?;
+        let mut output = vec![];
+        write_unsafe_rows(&batch, &mut output)?;
+
+        // row 0: bitset(8) + slots(16) + "hello blaze" padded to 16 bytes
+        assert_eq!(&output[0..4], &40i32.to_le_bytes());
+        let row0 = &output[4..44];
+        assert_eq!(&row0[0..8], &[0u8; 8]);
+        assert_eq!(&row0[8..16], &7i64.to_le_bytes());
+        assert_eq!(&row0[16..24], &((24u64 << 32) | 11).to_le_bytes());
+        assert_eq!(&row0[24..35], b"hello blaze");
+
+        // row 1: both fields are null
+        assert_eq!(&output[44..48], &24i32.to_le_bytes());
+        let row1 = &output[48..72];
+        assert_eq!(row1[0], 0b11);
+        assert_eq!(&row1[8..24], &[0u8; 16]);
+        assert_eq!(output.len(), 72);
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_unsafe_rows() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("b", DataType::Boolean, true),
+            Field::new("l", DataType::Int64, true),
+            Field::new("s", DataType::Utf8, true),
+        ]));
+        let batch = RecordBatch::try_new(
+            schema.clone(),
+            vec![
+                Arc::new(BooleanArray::from(vec![Some(true), None, Some(false)])),
+                Arc::new(Int64Array::from(vec![Some(-1), Some(2), None])),
+                Arc::new(StringArray::from(vec![None, Some(""), Some("blaze")])),
+            ],
+        )?;
+        let mut output = vec![];
+        write_unsafe_rows(&batch, &mut output)?;
+        assert_eq!(read_unsafe_rows(&output, &schema)?, batch);
+
+        // negative and truncated row sizes are errors instead of panics
+        let mut negative_size = output.clone();
+        negative_size[0..4].copy_from_slice(&(-8i32).to_le_bytes());
+        assert!(read_unsafe_rows(&negative_size, &schema).is_err());
+        assert!(read_unsafe_rows(&output[..output.len() - 1], &schema).is_err());
+        assert!(read_unsafe_rows(&output[..2], &schema).is_err());
+        Ok(())
+    }
+}
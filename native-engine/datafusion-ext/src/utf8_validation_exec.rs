@@ -0,0 +1,375 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Defines an operator validating UTF-8 string data entering the native engine
+
+use std::any::Any;
+use std::fmt::Formatter;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use datafusion::arrow::array::{
+    make_array, Array, ArrayData, ArrayRef, GenericStringArray, OffsetSizeTrait,
+};
+use datafusion::arrow::datatypes::{DataType, SchemaRef};
+use datafusion::arrow::error::ArrowError;
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::execution::context::TaskContext;
+use datafusion::physical_plan::expressions::PhysicalSortExpr;
+use datafusion::physical_plan::stream::RecordBatchStreamAdapter;
+use datafusion::physical_plan::{
+    DisplayFormatType, ExecutionPlan, Partitioning, SendableRecordBatchStream, Statistics,
+};
+use futures::StreamExt;
+
+use crate::native_conf::native_conf;
+
+/// How malformed UTF-8 in string columns from scans and JVM inputs is handled.
+/// arrow kernels assume string arrays are valid UTF-8, so trusting malformed
+/// data may panic or produce garbage far away from the source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Utf8ValidationPolicy {
+    /// fails with the source and position of the first malformed value
+    Error,
+    /// replaces malformed sequences with U+FFFD
+    Replace,
+    /// skips validation, for sources known to be well-formed
+    Trust,
+}
+
+impl Default for Utf8ValidationPolicy {
+    fn default() -> Self {
+        Utf8ValidationPolicy::Error
+    }
+}
+
+impl FromStr for Utf8ValidationPolicy {
+    type Err = DataFusionError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "error" | "validate" => Ok(Utf8ValidationPolicy::Error),
+            "replace" | "lossy" => Ok(Utf8ValidationPolicy::Replace),
+            "trust" => Ok(Utf8ValidationPolicy::Trust),
+            other => Err(DataFusionError::Plan(format!(
+                "unsupported utf8 validation policy: {}",
+                other
+            ))),
+        }
+    }
+}
+
+/// wraps the ingesting plan with ValidateUtf8Exec if it produces string data and
+/// validation is not disabled by native conf. `sources` describes data source of
+/// each partition, or of all partitions if only one is given.
+pub fn validate_utf8(
+    input: Arc<dyn ExecutionPlan>,
+    sources: Vec<String>,
+) -> Arc<dyn ExecutionPlan> {
+    let policy = native_conf().utf8_validation;
+    let has_string_fields = input
+        .schema()
+        .fields()
+        .iter()
+        .any(|field| contains_utf8(field.data_type()));
+
+    if policy == Utf8ValidationPolicy::Trust || !has_string_fields {
+        return input;
+    }
+    Arc::new(ValidateUtf8Exec::new(input, policy, sources))
+}
+
+/// Validates string columns of all batches from its input according to the
+/// policy.
+#[derive(Debug)]
+pub struct ValidateUtf8Exec {
+    input: Arc<dyn ExecutionPlan>,
+    policy: Utf8ValidationPolicy,
+    sources: Vec<String>,
+}
+
+impl ValidateUtf8Exec {
+    pub fn new(
+        input: Arc<dyn ExecutionPlan>,
+        policy: Utf8ValidationPolicy,
+        sources: Vec<String>,
+    ) -> Self {
+        Self {
+            input,
+            policy,
+            sources,
+        }
+    }
+
+    fn source(&self, partition: usize) -> String {
+        self.sources
+            .get(partition)
+            .or_else(|| self.sources.first())
+            .cloned()
+            .unwrap_or_else(|| "unknown source".to_owned())
+    }
+}
+
+#[async_trait]
+impl ExecutionPlan for ValidateUtf8Exec {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn schema(&self) -> SchemaRef {
+        self.input.schema()
+    }
+
+    fn output_partitioning(&self) -> Partitioning {
+        self.input.output_partitioning()
+    }
+
+    fn output_ordering(&self) -> Option<&[PhysicalSortExpr]> {
+        self.input.output_ordering()
+    }
+
+    crate::transparent_plan_tree!(input, |self, input| Self::new(
+        input,
+        self.policy,
+        self.sources.clone()
+    ));
+
+    fn execute(
+        &self,
+        partition: usize,
+        context: Arc<TaskContext>,
+    ) -> Result<SendableRecordBatchStream> {
+        let policy = self.policy;
+        let source = self.source(partition);
+        let input = self.input.execute(partition, context)?;
+        let validated = input.map(move |batch| {
+            batch.and_then(|batch| {
+                validate_batch(batch, policy, &source)
+                    .map_err(|e| ArrowError::ExternalError(Box::new(e)))
+            })
+        });
+        Ok(Box::pin(RecordBatchStreamAdapter::new(
+            self.schema(),
+            validated,
+        )))
+    }
+
+    fn fmt_as(&self, t: DisplayFormatType, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "ValidateUtf8Exec: policy={:?}, ", self.policy)?;
+        self.input.fmt_as(t, f)
+    }
+
+    fn statistics(&self) -> Statistics {
+        self.input.statistics()
+    }
+}
+
+fn contains_utf8(data_type: &DataType) -> bool {
+    match data_type {
+        DataType::Utf8 | DataType::LargeUtf8 => true,
+        DataType::List(field) | DataType::LargeList(field) => {
+            contains_utf8(field.data_type())
+        }
+        DataType::Struct(fields) => {
+            fields.iter().any(|field| contains_utf8(field.data_type()))
+        }
+        _ => false,
+    }
+}
+
+pub fn validate_batch(
+    batch: RecordBatch,
+    policy: Utf8ValidationPolicy,
+    source: &str,
+) -> Result<RecordBatch> {
+    if policy == Utf8ValidationPolicy::Trust {
+        return Ok(batch);
+    }
+    let schema = batch.schema();
+    let columns = batch
+        .columns()
+        .iter()
+        .zip(schema.fields())
+        .map(|(column, field)| validate_array(column, policy, field.name(), source))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(RecordBatch::try_new(schema.clone(), columns)?)
+}
+
+fn validate_array(
+    array: &ArrayRef,
+    policy: Utf8ValidationPolicy,
+    path: &str,
+    source: &str,
+) -> Result<ArrayRef> {
+    if !contains_utf8(array.data_type()) {
+        return Ok(array.clone());
+    }
+    match array.data_type() {
+        DataType::Utf8 => validate_string_array::<i32>(array, policy, path, source),
+        DataType::LargeUtf8 => validate_string_array::<i64>(array, policy, path, source),
+
+        // validate the children and rebuild the nested array
+        data_type => {
+            let data = array.data();
+            let children = data
+                .child_data()
+                .iter()
+                .map(|child| -> Result<ArrayData> {
+                    let child = make_array(child.clone());
+                    Ok(validate_array(&child, policy, path, source)?.data().clone())
+                })
+                .collect::<Result<Vec<_>>>()?;
+            Ok(make_array(ArrayData::try_new(
+                data_type.clone(),
+                data.len(),
+                data.null_buffer().cloned(),
+                data.offset(),
+                data.buffers().to_vec(),
+                children,
+            )?))
+        }
+    }
+}
+
+fn validate_string_array<OffsetSize: OffsetSizeTrait>(
+    array: &ArrayRef,
+    policy: Utf8ValidationPolicy,
+    path: &str,
+    source: &str,
+) -> Result<ArrayRef> {
+    let string_array = array
+        .as_any()
+        .downcast_ref::<GenericStringArray<OffsetSize>>()
+        .unwrap();
+    let invalid_row = match find_invalid_utf8(string_array) {
+        Some(invalid_row) => invalid_row,
+        None => return Ok(array.clone()),
+    };
+
+    let value_bytes = |i: usize| {
+        let offsets = string_array.value_offsets();
+        let start = offsets[i].to_usize().unwrap();
+        let end = offsets[i + 1].to_usize().unwrap();
+        &string_array.value_data().as_slice()[start..end]
+    };
+    match policy {
+        Utf8ValidationPolicy::Error => {
+            let bytes = value_bytes(invalid_row);
+            Err(DataFusionError::Execution(format!(
+                "invalid UTF-8 in column {} at row {} of {}: {:?} \
+                    (set native conf utf8_validation=replace to tolerate)",
+                path,
+                invalid_row,
+                source,
+                String::from_utf8_lossy(&bytes[..bytes.len().min(64)]),
+            )))
+        }
+        Utf8ValidationPolicy::Replace => {
+            log::warn!(
+                "replacing invalid UTF-8 in column {} of {}, first at row {}",
+                path,
+                source,
+                invalid_row,
+            );
+            let replaced = (0..string_array.len())
+                .map(|i| {
+                    string_array
+                        .is_valid(i)
+                        .then(|| String::from_utf8_lossy(value_bytes(i)).into_owned())
+                })
+                .collect::<GenericStringArray<OffsetSize>>();
+            Ok(Arc::new(replaced))
+        }
+        Utf8ValidationPolicy::Trust => Ok(array.clone()),
+    }
+}
+
+/// returns index of the first non-null malformed value
+fn find_invalid_utf8<OffsetSize: OffsetSizeTrait>(
+    array: &GenericStringArray<OffsetSize>,
+) -> Option<usize> {
+    let offsets = array.value_offsets();
+    let value_data = array.value_data();
+    let data = value_data.as_slice();
+    let start = offsets[0].to_usize().unwrap();
+    let end = offsets[array.len()].to_usize().unwrap();
+
+    // fast path: all values are valid if the whole value range is valid and
+    // every value starts on a char boundary
+    if let Ok(values) = std::str::from_utf8(&data[start..end]) {
+        if offsets
+            .iter()
+            .all(|offset| values.is_char_boundary(offset.to_usize().unwrap() - start))
+        {
+            return None;
+        }
+    }
+    (0..array.len()).find(|&i| {
+        let value_start = offsets[i].to_usize().unwrap();
+        let value_end = offsets[i + 1].to_usize().unwrap();
+        array.is_valid(i) && std::str::from_utf8(&data[value_start..value_end]).is_err()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use datafusion::arrow::array::StringArray;
+    use datafusion::arrow::buffer::Buffer;
+    use datafusion::arrow::datatypes::{Field, Schema};
+
+    fn malformed_string_array() -> ArrayRef {
+        // "ok", "\xff", null
+        make_array(unsafe {
+            ArrayData::new_unchecked(
+                DataType::Utf8,
+                3,
+                None,
+                Some(Buffer::from_slice_ref(&[0b011u8])),
+                0,
+                vec![
+                    Buffer::from_slice_ref(&[0i32, 2, 3, 3]),
+                    Buffer::from_slice_ref(b"ok\xff"),
+                ],
+                vec![],
+            )
+        })
+    }
+
+    #[test]
+    fn test_validate_utf8() -> Result<()> {
+        let schema = Arc::new(Schema::new(vec![Field::new("s", DataType::Utf8, true)]));
+        let batch = RecordBatch::try_new(schema, vec![malformed_string_array()])?;
+
+        let err =
+            validate_batch(batch.clone(), Utf8ValidationPolicy::Error, "test.parquet")
+                .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("column s at row 1 of test.parquet"));
+
+        let replaced = validate_batch(batch, Utf8ValidationPolicy::Replace, "")?;
+        assert_eq!(
+            replaced
+                .column(0)
+                .as_any()
+                .downcast_ref::<StringArray>()
+                .unwrap(),
+            &StringArray::from(vec![Some("ok"), Some("\u{fffd}"), None])
+        );
+        Ok(())
+    }
+}
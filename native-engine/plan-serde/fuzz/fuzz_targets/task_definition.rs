@@ -0,0 +1,32 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Fuzzes decoding and converting serialized task definitions, run with:
+//!
+//! `cargo +nightly fuzz run task_definition` in native-engine/plan-serde
+//!
+//! task definitions dumped with spark.blaze.dumpTaskDefinitions.dir make a
+//! good seed corpus. libfuzzer aborts on any panic including the ones caught
+//! by convert_plan(), so that panics of plan conversion are found as well.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use plan_serde::task_definition::{convert_plan, decode_task_definition};
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(task_definition) = decode_task_definition(data) {
+        let _ = convert_plan(task_definition.plan.as_ref().unwrap());
+    }
+});
@@ -22,6 +22,7 @@ use std::{
 
 use datafusion::arrow::error::ArrowError;
 use datafusion::error::DataFusionError;
+use datafusion_ext::blaze_error::BlazeError;
 
 pub type Result<T> = result::Result<T, PlanSerDeError>;
 
@@ -100,6 +101,16 @@ impl Display for PlanSerDeError {
 
 impl Error for PlanSerDeError {}
 
+impl From<PlanSerDeError> for BlazeError {
+    fn from(e: PlanSerDeError) -> Self {
+        match e {
+            PlanSerDeError::ArrowError(e) => BlazeError::from(e),
+            PlanSerDeError::DataFusionError(e) => BlazeError::from(e),
+            e => BlazeError::UserError(e.to_string()),
+        }
+    }
+}
+
 impl PlanSerDeError {
     pub(crate) fn required(field: impl Into<String>) -> PlanSerDeError {
         PlanSerDeError::MissingRequiredField(field.into())
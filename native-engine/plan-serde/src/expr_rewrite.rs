@@ -0,0 +1,243 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Rewrites of expressions applied when binding them to input schemas.
+//!
+//! - constant folding: deterministic expressions of only literals are
+//!   evaluated once and replaced with their values. expressions failing to
+//!   evaluate are kept, so that errors are only raised if there are rows.
+//! - common subexpression elimination: deterministic subexpressions repeated
+//!   in the expressions of an operator are replaced with the same
+//!   `CommonSubExpr`, evaluated once per batch. expressions are bound twice,
+//!   counting subexpressions first and then sharing the repeated ones.
+//!
+//! only expressions known to be deterministic are rewritten, expressions with
+//! states (e.g. rand() and monotonically_increasing_id()) are kept as is.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use datafusion::arrow::array::NullArray;
+use datafusion::arrow::datatypes::{DataType, Field, Schema};
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::error::Result;
+use datafusion::physical_plan::expressions::{
+    BinaryExpr, CaseExpr, CastExpr, Column, InListExpr, IsNotNullExpr, IsNullExpr,
+    Literal, NegativeExpr, NotExpr, TryCastExpr,
+};
+use datafusion::physical_plan::functions::ScalarFunctionExpr;
+use datafusion::physical_plan::{ColumnarValue, PhysicalExpr};
+use datafusion::scalar::ScalarValue;
+use datafusion_ext::char_varchar_expr::CharVarcharExpr;
+use datafusion_ext::common_subexpr::CommonSubExpr;
+use datafusion_ext::equal_null_safe_expr::EqualNullSafeExpr;
+use datafusion_ext::native_conf::native_conf;
+use datafusion_ext::normalize_float_expr::{
+    FloatComparisonExpr, NormalizeNaNAndZeroExpr,
+};
+use datafusion_ext::short_circuit_expr::ShortCircuitBinaryExpr;
+use datafusion_ext::spark_in_list_expr::SparkInListExpr;
+use datafusion_ext::spark_like_expr::SparkLikeExpr;
+
+/// scalar functions returning different values for the same arguments
+const VOLATILE_FUNCTIONS: &[&str] = &["random", "now"];
+
+/// returns the arguments of a deterministic expression, or None if the
+/// expression may not be deterministic
+fn deterministic_args(
+    expr: &Arc<dyn PhysicalExpr>,
+) -> Option<Vec<Arc<dyn PhysicalExpr>>> {
+    let expr = expr.as_any();
+    if expr.is::<Column>() || expr.is::<Literal>() {
+        Some(vec![])
+    } else if let Some(expr) = expr.downcast_ref::<BinaryExpr>() {
+        Some(vec![expr.left().clone(), expr.right().clone()])
+    } else if let Some(expr) = expr.downcast_ref::<ShortCircuitBinaryExpr>() {
+        Some(vec![expr.left().clone(), expr.right().clone()])
+    } else if let Some(expr) = expr.downcast_ref::<CaseExpr>() {
+        let mut args = vec![];
+        args.extend(expr.expr().clone());
+        for (when_expr, then_expr) in expr.when_then_expr() {
+            args.push(when_expr.clone());
+            args.push(then_expr.clone());
+        }
+        args.extend(expr.else_expr().cloned());
+        Some(args)
+    } else if let Some(expr) = expr.downcast_ref::<NotExpr>() {
+        Some(vec![expr.arg().clone()])
+    } else if let Some(expr) = expr.downcast_ref::<IsNullExpr>() {
+        Some(vec![expr.arg().clone()])
+    } else if let Some(expr) = expr.downcast_ref::<IsNotNullExpr>() {
+        Some(vec![expr.arg().clone()])
+    } else if let Some(expr) = expr.downcast_ref::<NegativeExpr>() {
+        Some(vec![expr.arg().clone()])
+    } else if let Some(expr) = expr.downcast_ref::<InListExpr>() {
+        let mut args = vec![expr.expr().clone()];
+        args.extend(expr.list().iter().cloned());
+        Some(args)
+    } else if let Some(expr) = expr.downcast_ref::<SparkInListExpr>() {
+        Some(vec![expr.expr().clone()])
+    } else if let Some(expr) = expr.downcast_ref::<SparkLikeExpr>() {
+        Some(vec![expr.expr().clone(), expr.pattern().clone()])
+    } else if let Some(expr) = expr.downcast_ref::<EqualNullSafeExpr>() {
+        Some(vec![expr.l().clone(), expr.r().clone()])
+    } else if let Some(expr) = expr.downcast_ref::<CastExpr>() {
+        Some(vec![expr.expr().clone()])
+    } else if let Some(expr) = expr.downcast_ref::<TryCastExpr>() {
+        Some(vec![expr.expr().clone()])
+    } else if let Some(expr) = expr.downcast_ref::<CharVarcharExpr>() {
+        Some(vec![expr.expr().clone()])
+    } else if let Some(expr) = expr.downcast_ref::<NormalizeNaNAndZeroExpr>() {
+        Some(vec![expr.expr().clone()])
+    } else if let Some(expr) = expr.downcast_ref::<FloatComparisonExpr>() {
+        Some(vec![expr.left().clone(), expr.right().clone()])
+    } else if let Some(expr) = expr.downcast_ref::<CommonSubExpr>() {
+        Some(vec![expr.expr().clone()])
+    } else if let Some(expr) = expr.downcast_ref::<ScalarFunctionExpr>() {
+        let name = expr.name().to_ascii_lowercase();
+        (!VOLATILE_FUNCTIONS.contains(&name.as_str())).then(|| expr.args().to_vec())
+    } else {
+        None
+    }
+}
+
+/// returns true if the expression is known to be deterministic
+pub fn is_deterministic(expr: &Arc<dyn PhysicalExpr>) -> bool {
+    deterministic_args(expr)
+        .map(|args| args.iter().all(is_deterministic))
+        .unwrap_or(false)
+}
+
+/// replaces a deterministic expression of only literal arguments with its
+/// value. arguments are bound, and thus folded, before the expression
+pub fn fold_constant(expr: Arc<dyn PhysicalExpr>) -> Arc<dyn PhysicalExpr> {
+    if !native_conf().constant_folding {
+        return expr;
+    }
+    let foldable = match deterministic_args(&expr) {
+        Some(args) => {
+            !args.is_empty() && args.iter().all(|arg| arg.as_any().is::<Literal>())
+        }
+        None => false,
+    };
+    if !foldable {
+        return expr;
+    }
+    match evaluate_constant(&expr) {
+        Ok(Some(value)) => Arc::new(Literal::new(value)),
+        Ok(None) | Err(_) => expr,
+    }
+}
+
+/// evaluates an expression of only literals on a single-row batch, returns
+/// None if the value is not representable as a literal of the same type
+fn evaluate_constant(expr: &Arc<dyn PhysicalExpr>) -> Result<Option<ScalarValue>> {
+    let schema = Arc::new(Schema::new(vec![Field::new("", DataType::Null, true)]));
+    let batch = RecordBatch::try_new(schema.clone(), vec![Arc::new(NullArray::new(1))])?;
+    let value = match expr.evaluate(&batch)? {
+        ColumnarValue::Scalar(value) => value,
+        ColumnarValue::Array(array) if array.len() == 1 => {
+            ScalarValue::try_from_array(&array, 0)?
+        }
+        ColumnarValue::Array(_) => return Ok(None),
+    };
+    if expr.data_type(&schema)? != value.get_datatype() {
+        return Ok(None);
+    }
+    Ok(Some(value))
+}
+
+thread_local! {
+    static COMMON_SUBEXPRS: RefCell<Option<CommonSubExprs>> = RefCell::new(None);
+}
+
+/// subexpressions of the expressions being bound, keyed by their debug
+/// formats, which include all fields of the expressions
+#[derive(Default)]
+struct CommonSubExprs {
+    counting: bool,
+    counts: HashMap<String, usize>,
+    shared: HashMap<String, Arc<dyn PhysicalExpr>>,
+}
+
+/// clears the subexpressions of the current thread on dropping, even if
+/// binding panics
+struct CommonSubExprsGuard;
+
+impl Drop for CommonSubExprsGuard {
+    fn drop(&mut self) {
+        COMMON_SUBEXPRS.with(|cse| *cse.borrow_mut() = None);
+    }
+}
+
+/// binds all expressions of an operator with `bind_exprs`, sharing repeated
+/// subexpressions among them. `bind_exprs` is called twice.
+pub fn bind_with_common_subexprs<T, E>(
+    bind_exprs: impl Fn() -> std::result::Result<T, E>,
+) -> std::result::Result<T, E> {
+    if !native_conf().common_subexpr_elimination {
+        return bind_exprs();
+    }
+    let _guard = CommonSubExprsGuard;
+    COMMON_SUBEXPRS.with(|cse| {
+        *cse.borrow_mut() = Some(CommonSubExprs {
+            counting: true,
+            ..Default::default()
+        })
+    });
+    bind_exprs()?;
+
+    COMMON_SUBEXPRS.with(|cse| {
+        if let Some(cse) = cse.borrow_mut().as_mut() {
+            cse.counting = false;
+        }
+    });
+    bind_exprs()
+}
+
+/// counts a bound expression, or replaces it with the shared subexpression if
+/// it is repeated. expressions are returned as is if not called in
+/// `bind_with_common_subexprs()`
+pub fn share_common_subexpr(expr: Arc<dyn PhysicalExpr>) -> Arc<dyn PhysicalExpr> {
+    COMMON_SUBEXPRS.with(|cse| {
+        let mut cse = cse.borrow_mut();
+        let cse = match cse.as_mut() {
+            Some(cse) => cse,
+            None => return expr,
+        };
+
+        // columns and literals are cheaper to evaluate than cache
+        let shareable = match deterministic_args(&expr) {
+            Some(args) => !args.is_empty() && args.iter().all(is_deterministic),
+            None => false,
+        };
+        if !shareable {
+            return expr;
+        }
+
+        let key = format!("{:?}", expr);
+        if cse.counting {
+            *cse.counts.entry(key).or_default() += 1;
+            return expr;
+        }
+        if cse.counts.get(&key).cloned().unwrap_or(0) < 2 {
+            return expr;
+        }
+        cse.shared
+            .entry(key)
+            .or_insert_with(|| Arc::new(CommonSubExpr::new(expr)))
+            .clone()
+    })
+}
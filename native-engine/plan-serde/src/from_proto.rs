@@ -27,6 +27,7 @@ use datafusion::logical_expr::{BuiltinScalarFunction, WindowFunction};
 use datafusion::logical_plan;
 use datafusion::logical_plan::window_frames::WindowFrame;
 use datafusion::logical_plan::*;
+use datafusion::parquet::basic::Compression;
 use datafusion::physical_plan::aggregates::create_aggregate_expr;
 use datafusion::physical_plan::aggregates::{AggregateExec, AggregateMode};
 use datafusion::physical_plan::coalesce_partitions::CoalescePartitionsExec;
@@ -60,23 +61,84 @@ use datafusion::physical_plan::{
 };
 use datafusion::scalar::ScalarValue;
 
+use datafusion_ext::array_function_expr::{
+    ArrayFunction, ArrayFunctionExpr, LambdaFunction,
+};
+use datafusion_ext::char_varchar_expr::{CharVarcharExpr, CharVarcharMode};
 use datafusion_ext::empty_partitions_exec::EmptyPartitionsExec;
+use datafusion_ext::equal_null_safe_expr::EqualNullSafeExpr;
+use datafusion_ext::existence_join_exec::{ExistenceJoinExec, ExistenceJoinMode};
+use datafusion_ext::fault_injection::inject_scan_faults;
+use datafusion_ext::ffi_reader_exec::FFIReaderExec;
+use datafusion_ext::ffi_stream_reader_exec::FFIStreamReaderExec;
+use datafusion_ext::filter_project_exec::FilterProjectExec;
 use datafusion_ext::global_object_store_registry;
+use datafusion_ext::grouping_expr::GroupingExpr;
+use datafusion_ext::join_key_normalization::{normalize_join_keys, JoinKeyNormalization};
 use datafusion_ext::jvm_to_native_exec::JvmToNativeExec;
+use datafusion_ext::late_materialization_filter_exec::LateMaterializationFilterExec;
+use datafusion_ext::native_conf::native_conf;
+use datafusion_ext::nested_loop_join_exec::NestedLoopJoinExec;
+use datafusion_ext::normalize_float_expr::{
+    is_float_type, normalize_sort_expr, FloatComparisonExpr, NormalizeNaNAndZeroExpr,
+};
+use datafusion_ext::parquet_column_metrics_exec::ParquetColumnMetricsExec;
+use datafusion_ext::parquet_sink_exec::{ParquetSinkExec, ParquetSinkOptions};
+use datafusion_ext::partial_agg_skipping_exec::skip_partial_agg;
+use datafusion_ext::partial_merge_aggregate_expr::PartialMergeAggregateExpr;
+use datafusion_ext::partition_scope_exec::PartitionScopeExec;
+use datafusion_ext::positional_delete_parquet_exec::{
+    PositionalDeleteParquetExec, RowDeletes,
+};
+use datafusion_ext::prefetch_scan_exec::prefetch_scan;
+use datafusion_ext::radix_sort_exec::radix_sort;
 use datafusion_ext::rename_columns_exec::RenameColumnsExec;
-use datafusion_ext::shuffle_reader_exec::ShuffleReaderExec;
+use datafusion_ext::reused_exchange_exec::ReusedExchangeExec;
+use datafusion_ext::row_input_exec::RowInputExec;
+use datafusion_ext::schema_adapted_parquet_exec::SchemaAdaptedParquetExec;
+use datafusion_ext::short_circuit_expr::ShortCircuitBinaryExpr;
+use datafusion_ext::shuffle_output_writer::ShuffleWriterOutput;
+use datafusion_ext::shuffle_reader_exec::{
+    ShuffleReadRange, ShuffleReaderExec, ShuffleSegmentSource,
+};
 use datafusion_ext::shuffle_writer_exec::ShuffleWriterExec;
+use datafusion_ext::sort_aggregate_exec::SortAggregateExec;
+use datafusion_ext::spark_approx_percentile::SparkApproxPercentile;
+use datafusion_ext::spark_bool_aggregate::SparkBoolAggregate;
+use datafusion_ext::spark_in_list_expr::SparkInListExpr;
+use datafusion_ext::spark_interval_expr::{SparkInterval, SparkIntervalAddExpr};
+use datafusion_ext::spark_like_expr::{SparkLikeExpr, SparkLikeKind};
+use datafusion_ext::spark_partition_expr::{SparkPartitionExpr, SparkPartitionExprKind};
+use datafusion_ext::spark_rand_expr::SparkRandExpr;
+use datafusion_ext::spillable_sort_merge_join_exec::SpillableSortMergeJoinExec;
+use datafusion_ext::split_oversized_batches_exec::split_oversized_input_batches;
+use datafusion_ext::topk_groups_exec::{topk_aggregate, topk_shuffle_read};
+use datafusion_ext::typed_literal_expr::TypedLiteralExpr;
+use datafusion_ext::utf8_validation_exec::validate_utf8;
 
 use crate::error::{FromOptionalField, PlanSerDeError};
+use crate::expr_rewrite::{
+    bind_with_common_subexprs, fold_constant, is_deterministic, share_common_subexpr,
+};
 use crate::protobuf::physical_expr_node::ExprType;
 use crate::protobuf::physical_plan_node::PhysicalPlanType;
 use crate::protobuf::repartition_exec_node::PartitionMethod;
 use crate::{convert_box_required, convert_required, into_required, protobuf, Schema};
 use crate::{from_proto_binary_op, proto_error, str_to_byte};
 
+/// binds the expression to the input schema, folding constants and sharing
+/// common subexpressions of the operator, see expr_rewrite
 fn bind(
     expr_in: Arc<dyn PhysicalExpr>,
     input_schema: &Arc<Schema>,
+) -> Result<Arc<dyn PhysicalExpr>, DataFusionError> {
+    let expr = bind_expr(expr_in, input_schema)?;
+    Ok(share_common_subexpr(fold_constant(expr)))
+}
+
+fn bind_expr(
+    expr_in: Arc<dyn PhysicalExpr>,
+    input_schema: &Arc<Schema>,
 ) -> Result<Arc<dyn PhysicalExpr>, DataFusionError> {
     let expr = expr_in.as_any();
 
@@ -86,12 +148,41 @@ fn bind(
             input_schema,
         )?))
     } else if let Some(expr) = expr.downcast_ref::<BinaryExpr>() {
-        let binary_expr = Arc::new(BinaryExpr::new(
+        let left = bind(expr.left().clone(), input_schema)?;
+        let right = bind(expr.right().clone(), input_schema)?;
+        let is_float_comparison = matches!(
+            expr.op(),
+            Operator::Eq
+                | Operator::NotEq
+                | Operator::Lt
+                | Operator::LtEq
+                | Operator::Gt
+                | Operator::GtEq
+        ) && is_float_type(&left.data_type(input_schema)?)
+            && is_float_type(&right.data_type(input_schema)?);
+        if is_float_comparison {
+            // NaNs are equal and greater than any other value in spark
+            return Ok(Arc::new(FloatComparisonExpr::try_new(
+                left,
+                *expr.op(),
+                right,
+            )?));
+        }
+        Ok(Arc::new(BinaryExpr::new(left, *expr.op(), right)))
+    } else if let Some(expr) = expr.downcast_ref::<FloatComparisonExpr>() {
+        let float_comparison = Arc::new(FloatComparisonExpr::try_new(
             bind(expr.left().clone(), input_schema)?,
             *expr.op(),
             bind(expr.right().clone(), input_schema)?,
-        ));
-        Ok(binary_expr)
+        )?);
+        Ok(float_comparison)
+    } else if let Some(expr) = expr.downcast_ref::<ShortCircuitBinaryExpr>() {
+        let sc_binary_expr = Arc::new(ShortCircuitBinaryExpr::try_new(
+            bind(expr.left().clone(), input_schema)?,
+            *expr.op(),
+            bind(expr.right().clone(), input_schema)?,
+        )?);
+        Ok(sc_binary_expr)
     } else if let Some(expr) = expr.downcast_ref::<CaseExpr>() {
         let case_expr = Arc::new(CaseExpr::try_new(
             expr.expr()
@@ -134,10 +225,57 @@ fn bind(
             expr.negated(),
         ));
         Ok(in_list)
+    } else if let Some(expr) = expr.downcast_ref::<SparkInListExpr>() {
+        let in_list = Arc::new(expr.with_expr(bind(expr.expr().clone(), input_schema)?));
+        Ok(in_list)
+    } else if let Some(expr) = expr.downcast_ref::<SparkLikeExpr>() {
+        let like = Arc::new(expr.with_exprs(
+            bind(expr.expr().clone(), input_schema)?,
+            bind(expr.pattern().clone(), input_schema)?,
+        ));
+        Ok(like)
+    } else if let Some(expr) = expr.downcast_ref::<SparkIntervalAddExpr>() {
+        let interval_add = Arc::new(SparkIntervalAddExpr::new(
+            bind(expr.expr().clone(), input_schema)?,
+            expr.interval(),
+            expr.zone_offset_seconds(),
+        ));
+        Ok(interval_add)
+    } else if let Some(expr) = expr.downcast_ref::<ArrayFunctionExpr>() {
+        Ok(Arc::new(expr.try_bind(input_schema, &bind)?))
+    } else if let Some(expr) = expr.downcast_ref::<EqualNullSafeExpr>() {
+        let equal_null_safe = Arc::new(EqualNullSafeExpr::new(
+            bind(expr.l().clone(), input_schema)?,
+            bind(expr.r().clone(), input_schema)?,
+        ));
+        Ok(equal_null_safe)
+    } else if let Some(expr) = expr.downcast_ref::<GroupingExpr>() {
+        let grouping = Arc::new(GroupingExpr::new(
+            bind(expr.grouping_id().clone(), input_schema)?,
+            expr.shift(),
+        ));
+        Ok(grouping)
+    } else if let Some(expr) = expr.downcast_ref::<CharVarcharExpr>() {
+        let char_varchar = Arc::new(CharVarcharExpr::new(
+            bind(expr.expr().clone(), input_schema)?,
+            expr.mode(),
+            expr.length(),
+        ));
+        Ok(char_varchar)
+    } else if let Some(expr) = expr.downcast_ref::<NormalizeNaNAndZeroExpr>() {
+        let normalized = Arc::new(NormalizeNaNAndZeroExpr::new(bind(
+            expr.expr().clone(),
+            input_schema,
+        )?));
+        Ok(normalized)
     } else if let Some(expr) = expr.downcast_ref::<NegativeExpr>() {
         let neg = Arc::new(NegativeExpr::new(bind(expr.arg().clone(), input_schema)?));
         Ok(neg)
-    } else if expr.downcast_ref::<Literal>().is_some() {
+    } else if expr.downcast_ref::<Literal>().is_some()
+        || expr.downcast_ref::<TypedLiteralExpr>().is_some()
+        || expr.downcast_ref::<SparkPartitionExpr>().is_some()
+        || expr.downcast_ref::<SparkRandExpr>().is_some()
+    {
         Ok(expr_in)
     } else if let Some(cast) = expr.downcast_ref::<CastExpr>() {
         let cast = Arc::new(CastExpr::new(
@@ -168,6 +306,18 @@ fn bind(
     }
 }
 
+/// evaluates the expressions of the operator in the scope of its executed
+/// partition if they may depend on it, e.g. spark_partition_id() and rand()
+fn partition_scoped(
+    plan: Arc<dyn ExecutionPlan>,
+    scoped: bool,
+) -> Arc<dyn ExecutionPlan> {
+    match scoped {
+        true => Arc::new(PartitionScopeExec::new(plan)),
+        false => plan,
+    }
+}
+
 impl TryInto<Arc<dyn ExecutionPlan>> for &protobuf::PhysicalPlanNode {
     type Error = PlanSerDeError;
 
@@ -182,16 +332,48 @@ impl TryInto<Arc<dyn ExecutionPlan>> for &protobuf::PhysicalPlanNode {
             PhysicalPlanType::Projection(projection) => {
                 let input: Arc<dyn ExecutionPlan> =
                     convert_box_required!(projection.input)?;
-                let exprs = projection
-                    .expr
-                    .iter()
-                    .zip(projection.expr_name.iter())
-                    .map(|(expr, name)| {
-                        Ok((bind(expr.try_into()?, &input.schema())?, name.to_string()))
-                    })
-                    .collect::<Result<Vec<(Arc<dyn PhysicalExpr>, String)>, Self::Error>>(
-                    )?;
-                Ok(Arc::new(ProjectionExec::try_new(exprs, input)?))
+                let exprs = bind_with_common_subexprs(|| {
+                    projection
+                        .expr
+                        .iter()
+                        .zip(projection.expr_name.iter())
+                        .map(|(expr, name)| {
+                            let expr = bind(expr.try_into()?, &input.schema())?;
+                            Ok((expr, name.to_string()))
+                        })
+                        .collect::<Result<Vec<_>, Self::Error>>()
+                })?;
+                let mut scoped = !exprs.iter().all(|(expr, _)| is_deterministic(expr));
+                if native_conf().filter_project_fusion && !exprs.is_empty() {
+                    // a filter evaluated in a partition scope is fused with the
+                    // projection in the same scope
+                    let (filter_scoped, filter) =
+                        match input.as_any().downcast_ref::<PartitionScopeExec>() {
+                            Some(scope) => (true, scope.input()),
+                            None => (false, &input),
+                        };
+                    let filter_any = filter.as_any();
+                    let filter = if let Some(filter) =
+                        filter_any.downcast_ref::<LateMaterializationFilterExec>()
+                    {
+                        Some((filter.predicate(), filter.input()))
+                    } else {
+                        filter_any
+                            .downcast_ref::<FilterExec>()
+                            .map(|filter| (filter.predicate(), filter.input()))
+                    };
+                    if let Some((predicate, filter_input)) = filter {
+                        scoped |= filter_scoped;
+                        let fused = Arc::new(FilterProjectExec::try_new(
+                            predicate.clone(),
+                            exprs,
+                            filter_input.clone(),
+                        )?);
+                        return Ok(partition_scoped(fused, scoped));
+                    }
+                }
+                let projection = Arc::new(ProjectionExec::try_new(exprs, input)?);
+                Ok(partition_scoped(projection, scoped))
             }
             PhysicalPlanType::Filter(filter) => {
                 let input: Arc<dyn ExecutionPlan> = convert_box_required!(filter.input)?;
@@ -205,30 +387,94 @@ impl TryInto<Arc<dyn ExecutionPlan>> for &protobuf::PhysicalPlanNode {
                         )
                     })?
                     .try_into()?;
-                Ok(Arc::new(FilterExec::try_new(
-                    bind(predicate, &input.schema())?,
-                    input,
-                )?))
+                let predicate = bind_with_common_subexprs(|| {
+                    bind(predicate.clone(), &input.schema())
+                })?;
+                let scoped = !is_deterministic(&predicate);
+                if native_conf().filter_late_materialization
+                    && !input.schema().fields().is_empty()
+                {
+                    let filter = Arc::new(LateMaterializationFilterExec::try_new(
+                        predicate, input,
+                    )?);
+                    return Ok(partition_scoped(filter, scoped));
+                }
+                let filter = Arc::new(FilterExec::try_new(predicate, input)?);
+                Ok(partition_scoped(filter, scoped))
+            }
+            PhysicalPlanType::CsvScan(scan) => {
+                Ok(prefetch_scan(split_oversized_input_batches(
+                    inject_scan_faults(Arc::new(CsvExec::new(
+                        scan.base_conf.as_ref().unwrap().try_into()?,
+                        scan.has_header,
+                        str_to_byte(&scan.delimiter)?,
+                    ))),
+                )))
             }
-            PhysicalPlanType::CsvScan(scan) => Ok(Arc::new(CsvExec::new(
-                scan.base_conf.as_ref().unwrap().try_into()?,
-                scan.has_header,
-                str_to_byte(&scan.delimiter)?,
-            ))),
             PhysicalPlanType::ParquetScan(scan) => {
                 let predicate = scan
                     .pruning_predicate
                     .as_ref()
                     .map(|expr| expr.try_into())
                     .transpose()?;
-                Ok(Arc::new(ParquetExec::new(
-                    scan.base_conf.as_ref().unwrap().try_into()?,
-                    predicate,
-                )))
+                let base_config: FileScanConfig =
+                    scan.base_conf.as_ref().unwrap().try_into()?;
+                let sources = base_config
+                    .file_groups
+                    .iter()
+                    .map(|files| {
+                        let paths = files
+                            .iter()
+                            .map(|file| file.file_meta.sized_file.path.clone())
+                            .collect::<Vec<_>>();
+                        format!("parquet files [{}]", paths.join(", "))
+                    })
+                    .collect::<Vec<_>>();
+                let deletes = scan
+                    .base_conf
+                    .as_ref()
+                    .unwrap()
+                    .file_groups
+                    .iter()
+                    .map(|group| {
+                        group
+                            .files
+                            .iter()
+                            .map(|file| file.deletes.as_ref().map(|d| d.into()))
+                            .collect::<Vec<Option<RowDeletes>>>()
+                    })
+                    .collect::<Vec<_>>();
+
+                let parquet_exec: Arc<dyn ExecutionPlan> =
+                    if deletes.iter().flatten().any(Option::is_some) {
+                        Arc::new(PositionalDeleteParquetExec::try_new(
+                            base_config.clone(),
+                            predicate,
+                            deletes,
+                        )?)
+                    } else if base_config.limit.is_none() {
+                        Arc::new(SchemaAdaptedParquetExec::try_new(
+                            base_config.clone(),
+                            predicate,
+                        )?)
+                    } else {
+                        Arc::new(ParquetExec::new(base_config.clone(), predicate))
+                    };
+                let parquet_exec: Arc<dyn ExecutionPlan> = if scan.column_metrics {
+                    Arc::new(ParquetColumnMetricsExec::new(parquet_exec, base_config))
+                } else {
+                    parquet_exec
+                };
+                Ok(prefetch_scan(split_oversized_input_batches(validate_utf8(
+                    inject_scan_faults(parquet_exec),
+                    sources,
+                ))))
             }
-            PhysicalPlanType::AvroScan(scan) => Ok(Arc::new(AvroExec::new(
-                scan.base_conf.as_ref().unwrap().try_into()?,
-            ))),
+            PhysicalPlanType::AvroScan(scan) => Ok(prefetch_scan(
+                split_oversized_input_batches(inject_scan_faults(Arc::new(
+                    AvroExec::new(scan.base_conf.as_ref().unwrap().try_into()?),
+                ))),
+            )),
             PhysicalPlanType::CoalesceBatches(coalesce_batches) => {
                 let input: Arc<dyn ExecutionPlan> =
                     convert_box_required!(coalesce_batches.input)?;
@@ -283,11 +529,21 @@ impl TryInto<Arc<dyn ExecutionPlan>> for &protobuf::PhysicalPlanNode {
             }
             PhysicalPlanType::GlobalLimit(limit) => {
                 let input: Arc<dyn ExecutionPlan> = convert_box_required!(limit.input)?;
-                Ok(Arc::new(GlobalLimitExec::new(input, limit.limit as usize)))
+                let limit = limit.limit as usize;
+                let conf = native_conf();
+                let input = topk_aggregate(input, limit, conf.topk_aggregate_max_limit)?;
+                let input =
+                    topk_shuffle_read(input, limit, conf.topk_shuffle_read_max_limit)?;
+                Ok(Arc::new(GlobalLimitExec::new(input, limit)))
             }
             PhysicalPlanType::LocalLimit(limit) => {
                 let input: Arc<dyn ExecutionPlan> = convert_box_required!(limit.input)?;
-                Ok(Arc::new(LocalLimitExec::new(input, limit.limit as usize)))
+                let limit = limit.limit as usize;
+                let conf = native_conf();
+                let input = topk_aggregate(input, limit, conf.topk_aggregate_max_limit)?;
+                let input =
+                    topk_shuffle_read(input, limit, conf.topk_shuffle_read_max_limit)?;
+                Ok(Arc::new(LocalLimitExec::new(input, limit)))
             }
             PhysicalPlanType::Window(window_agg) => {
                 let input: Arc<dyn ExecutionPlan> =
@@ -351,13 +607,17 @@ impl TryInto<Arc<dyn ExecutionPlan>> for &protobuf::PhysicalPlanNode {
                         hash_agg.mode
                     ))
                 })?;
+                // partial merge is executed as a partial aggregation merging
+                // input buffers, see PartialMergeAggregateExpr
                 let agg_mode: AggregateMode = match mode {
                     protobuf::AggregateMode::Partial => AggregateMode::Partial,
                     protobuf::AggregateMode::Final => AggregateMode::Final,
                     protobuf::AggregateMode::FinalPartitioned => {
                         AggregateMode::FinalPartitioned
                     }
+                    protobuf::AggregateMode::PartialMerge => AggregateMode::Partial,
                 };
+                let partial_merge = mode == protobuf::AggregateMode::PartialMerge;
                 let group = hash_agg
                     .group_expr
                     .iter()
@@ -383,6 +643,14 @@ impl TryInto<Arc<dyn ExecutionPlan>> for &protobuf::PhysicalPlanNode {
                 let physical_schema: SchemaRef =
                     SchemaRef::new((&input_schema).try_into()?);
 
+                // aggregated expressions of a partial merge refer to the input
+                // of the partial aggregation, not to the buffers
+                let aggr_input_schema = if partial_merge {
+                    physical_schema.clone()
+                } else {
+                    input.schema()
+                };
+
                 let physical_aggr_expr: Vec<Arc<dyn AggregateExpr>> = hash_agg
                     .aggr_expr
                     .iter()
@@ -408,7 +676,7 @@ impl TryInto<Arc<dyn ExecutionPlan>> for &protobuf::PhysicalPlanNode {
                                     )?;
                                 let agg_expr = bind(
                                     convert_box_required!(agg_node.expr)?,
-                                    &input.schema(),
+                                    &aggr_input_schema,
                                 )?;
                                 Ok(create_aggregate_expr(
                                     &aggr_function.into(),
@@ -418,6 +686,45 @@ impl TryInto<Arc<dyn ExecutionPlan>> for &protobuf::PhysicalPlanNode {
                                     name.to_string(),
                                 )?)
                             }
+                            ExprType::ApproxPercentileExpr(agg_node) => {
+                                let agg_expr = bind(
+                                    convert_box_required!(agg_node.expr)?,
+                                    &aggr_input_schema,
+                                )?;
+                                let data_type = agg_expr.data_type(&aggr_input_schema)?;
+                                Ok(Arc::new(SparkApproxPercentile::try_new(
+                                    agg_expr,
+                                    name.to_string(),
+                                    data_type,
+                                    agg_node.percentages.clone(),
+                                    agg_node.return_percentile_array,
+                                    agg_node.accuracy,
+                                )?)
+                                    as Arc<dyn AggregateExpr>)
+                            }
+                            ExprType::BoolAggregateExpr(agg_node) => {
+                                let function = protobuf::BoolAggregateFunction::from_i32(
+                                    agg_node.bool_aggr_function,
+                                )
+                                .ok_or_else(|| {
+                                    proto_error(format!(
+                                        "Received an unknown bool aggregate function: {}",
+                                        agg_node.bool_aggr_function
+                                    ))
+                                })?;
+                                let agg_expr = bind(
+                                    convert_box_required!(agg_node.expr)?,
+                                    &aggr_input_schema,
+                                )?;
+                                let data_type = agg_expr.data_type(&aggr_input_schema)?;
+                                Ok(Arc::new(SparkBoolAggregate::try_new(
+                                    agg_expr,
+                                    name.to_string(),
+                                    function.into(),
+                                    data_type,
+                                )?)
+                                    as Arc<dyn AggregateExpr>)
+                            }
                             _ => Err(PlanSerDeError::General(
                                 "Invalid aggregate  expression for AggregateExec"
                                     .to_string(),
@@ -425,32 +732,49 @@ impl TryInto<Arc<dyn ExecutionPlan>> for &protobuf::PhysicalPlanNode {
                         }
                     })
                     .collect::<Result<Vec<_>, _>>()?;
+                let physical_aggr_expr = if partial_merge {
+                    physical_aggr_expr
+                        .into_iter()
+                        .map(|expr| {
+                            Ok(Arc::new(PartialMergeAggregateExpr::try_new(
+                                expr,
+                                &input.schema(),
+                            )?) as Arc<dyn AggregateExpr>)
+                        })
+                        .collect::<Result<Vec<_>, PlanSerDeError>>()?
+                } else {
+                    physical_aggr_expr
+                };
 
-                Ok(Arc::new(AggregateExec::try_new(
+                if hash_agg.sorted_input && !group.is_empty() {
+                    return Ok(Arc::new(SortAggregateExec::try_new(
+                        agg_mode,
+                        group,
+                        physical_aggr_expr,
+                        input,
+                    )?));
+                }
+                let agg = Arc::new(AggregateExec::try_new(
                     agg_mode,
                     group,
                     physical_aggr_expr,
                     input,
                     Arc::new((&input_schema).try_into()?),
-                )?))
+                )?);
+                let conf = native_conf();
+                Ok(skip_partial_agg(
+                    agg,
+                    conf.partial_agg_skipping_min_rows,
+                    conf.partial_agg_skipping_min_ratio,
+                )?)
             }
             PhysicalPlanType::HashJoin(hashjoin) => {
                 let left: Arc<dyn ExecutionPlan> = convert_box_required!(hashjoin.left)?;
                 let right: Arc<dyn ExecutionPlan> =
                     convert_box_required!(hashjoin.right)?;
-                let on: Vec<(Column, Column)> = hashjoin
-                    .on
-                    .iter()
-                    .map(|col| {
-                        let left_col: Column = into_required!(col.left)?;
-                        let left_col_binded: Column =
-                            Column::new_with_schema(left_col.name(), &left.schema())?;
-                        let right_col: Column = into_required!(col.right)?;
-                        let right_col_binded: Column =
-                            Column::new_with_schema(right_col.name(), &right.schema())?;
-                        Ok((left_col_binded, right_col_binded))
-                    })
-                    .collect::<Result<_, Self::Error>>()?;
+                let (on, normalizations) =
+                    parse_protobuf_join_on(&left, &right, &hashjoin.on)?;
+                let normalized = normalize_join_keys(left, right, on, &normalizations)?;
                 let join_type = protobuf::JoinType::from_i32(hashjoin.join_type)
                     .ok_or_else(|| {
                         proto_error(format!(
@@ -471,33 +795,47 @@ impl TryInto<Arc<dyn ExecutionPlan>> for &protobuf::PhysicalPlanNode {
                     protobuf::PartitionMode::CollectLeft => PartitionMode::CollectLeft,
                     protobuf::PartitionMode::Partitioned => PartitionMode::Partitioned,
                 };
-                Ok(Arc::new(HashJoinExec::try_new(
-                    left,
-                    right,
-                    on,
-                    &join_type.into(),
-                    partition_mode,
-                    &hashjoin.null_equals_null,
-                )?))
+
+                // subquery joins unsupported by datafusion, the right side is built
+                let existence_mode = if !hashjoin.existence_column.is_empty() {
+                    Some(ExistenceJoinMode::Exists(hashjoin.existence_column.clone()))
+                } else if hashjoin.null_aware_anti {
+                    if join_type != protobuf::JoinType::Anti {
+                        return Err(proto_error(format!(
+                            "Received a null-aware HashJoinNode with JoinType {:?}",
+                            join_type
+                        )));
+                    }
+                    Some(ExistenceJoinMode::NullAwareAnti)
+                } else {
+                    None
+                };
+                let join: Arc<dyn ExecutionPlan> = match existence_mode {
+                    Some(mode) => Arc::new(ExistenceJoinExec::try_new(
+                        normalized.left.clone(),
+                        normalized.right.clone(),
+                        normalized.on.clone(),
+                        mode,
+                    )?),
+                    None => Arc::new(HashJoinExec::try_new(
+                        normalized.left.clone(),
+                        normalized.right.clone(),
+                        normalized.on.clone(),
+                        &join_type.into(),
+                        partition_mode,
+                        &hashjoin.null_equals_null,
+                    )?),
+                };
+                Ok(normalized.project_join_output(join)?)
             }
             PhysicalPlanType::SortMergeJoin(sort_merge_join) => {
                 let left: Arc<dyn ExecutionPlan> =
                     convert_box_required!(sort_merge_join.left)?;
                 let right: Arc<dyn ExecutionPlan> =
                     convert_box_required!(sort_merge_join.right)?;
-                let on: Vec<(Column, Column)> = sort_merge_join
-                    .on
-                    .iter()
-                    .map(|col| {
-                        let left_col: Column = into_required!(col.left)?;
-                        let left_col_binded: Column =
-                            Column::new_with_schema(left_col.name(), &left.schema())?;
-                        let right_col: Column = into_required!(col.right)?;
-                        let right_col_binded: Column =
-                            Column::new_with_schema(right_col.name(), &right.schema())?;
-                        Ok((left_col_binded, right_col_binded))
-                    })
-                    .collect::<Result<_, Self::Error>>()?;
+                let (on, normalizations) =
+                    parse_protobuf_join_on(&left, &right, &sort_merge_join.on)?;
+                let normalized = normalize_join_keys(left, right, on, &normalizations)?;
 
                 let sort_options = sort_merge_join
                     .sort_options
@@ -516,14 +854,28 @@ impl TryInto<Arc<dyn ExecutionPlan>> for &protobuf::PhysicalPlanNode {
                     ))
                 })?;
 
-                Ok(Arc::new(SortMergeJoinExec::try_new(
-                    left,
-                    right,
-                    on,
-                    join_type.into(),
-                    sort_options,
-                    sort_merge_join.null_equals_null,
-                )?))
+                let max_buffered_group_bytes = native_conf().smj_max_buffered_group_bytes;
+                let join: Arc<dyn ExecutionPlan> = if max_buffered_group_bytes > 0 {
+                    Arc::new(SpillableSortMergeJoinExec::try_new(
+                        normalized.left.clone(),
+                        normalized.right.clone(),
+                        normalized.on.clone(),
+                        join_type.into(),
+                        sort_options,
+                        sort_merge_join.null_equals_null,
+                        max_buffered_group_bytes,
+                    )?)
+                } else {
+                    Arc::new(SortMergeJoinExec::try_new(
+                        normalized.left.clone(),
+                        normalized.right.clone(),
+                        normalized.on.clone(),
+                        join_type.into(),
+                        sort_options,
+                        sort_merge_join.null_equals_null,
+                    )?)
+                };
+                Ok(normalized.project_join_output(join)?)
             }
             PhysicalPlanType::CrossJoin(crossjoin) => {
                 let left: Arc<dyn ExecutionPlan> = convert_box_required!(crossjoin.left)?;
@@ -531,6 +883,49 @@ impl TryInto<Arc<dyn ExecutionPlan>> for &protobuf::PhysicalPlanNode {
                     convert_box_required!(crossjoin.right)?;
                 Ok(Arc::new(CrossJoinExec::try_new(left, right)?))
             }
+            PhysicalPlanType::NestedLoopJoin(nested_loop_join) => {
+                let left: Arc<dyn ExecutionPlan> =
+                    convert_box_required!(nested_loop_join.left)?;
+                let right: Arc<dyn ExecutionPlan> =
+                    convert_box_required!(nested_loop_join.right)?;
+                let join_type = protobuf::JoinType::from_i32(nested_loop_join.join_type)
+                    .ok_or_else(|| {
+                        proto_error(format!(
+                            "Received a NestedLoopJoinNode with unknown JoinType {}",
+                            nested_loop_join.join_type
+                        ))
+                    })?;
+                let build_side =
+                    protobuf::JoinSide::from_i32(nested_loop_join.build_side)
+                        .ok_or_else(|| {
+                            proto_error(format!(
+                                "Received a NestedLoopJoinNode with unknown JoinSide {}",
+                                nested_loop_join.build_side
+                            ))
+                        })?;
+                let condition_schema = Arc::new(NestedLoopJoinExec::condition_schema(
+                    &left.schema(),
+                    &right.schema(),
+                ));
+                let condition = nested_loop_join
+                    .condition
+                    .as_ref()
+                    .map(|condition| -> Result<_, PlanSerDeError> {
+                        Ok(bind(condition.try_into()?, &condition_schema)?)
+                    })
+                    .transpose()?;
+                Ok(Arc::new(NestedLoopJoinExec::try_new(
+                    left,
+                    right,
+                    join_type.into(),
+                    condition,
+                    build_side.into(),
+                    nested_loop_join
+                        .children_partitions
+                        .as_ref()
+                        .map(|p| (p.left as usize, p.right as usize)),
+                )?))
+            }
             PhysicalPlanType::ShuffleWriter(shuffle_writer) => {
                 let input: Arc<dyn ExecutionPlan> =
                     convert_box_required!(shuffle_writer.input)?;
@@ -543,24 +938,134 @@ impl TryInto<Arc<dyn ExecutionPlan>> for &protobuf::PhysicalPlanNode {
                 Ok(Arc::new(ShuffleWriterExec::try_new(
                     input,
                     output_partitioning.unwrap(),
-                    shuffle_writer.output_data_file.clone(),
-                    shuffle_writer.output_index_file.clone(),
+                    parse_shuffle_writer_output(shuffle_writer),
+                    shuffle_writer.segment_passthrough,
+                    shuffle_writer.write_metrics_resource_id.clone(),
+                    shuffle_writer.codec_stage_key.clone(),
                 )?))
             }
             PhysicalPlanType::ShuffleReader(shuffle_reader) => {
                 let schema = Arc::new(convert_required!(shuffle_reader.schema)?);
-                Ok(Arc::new(ShuffleReaderExec::new(
+                let segment_source =
+                    protobuf::ShuffleSegmentSource::from_i32(shuffle_reader.segment_source)
+                        .ok_or_else(|| {
+                            proto_error(format!(
+                                "Received a ShuffleReaderExecNode message with unknown ShuffleSegmentSource {}",
+                                shuffle_reader.segment_source
+                            ))
+                        })?;
+                let statistics: Option<Statistics> = shuffle_reader
+                    .statistics
+                    .as_ref()
+                    .map(|statistics| statistics.try_into())
+                    .transpose()?;
+                if !shuffle_reader.reduce_ranges.is_empty()
+                    && !shuffle_reader.map_ranges.is_empty()
+                {
+                    return Err(proto_error(
+                        "Received a ShuffleReaderExecNode message with both reduce ranges and map ranges",
+                    ));
+                }
+                let reduce_ranges = shuffle_reader.reduce_ranges.iter().map(|range| {
+                    if range.start_reduce_id > range.end_reduce_id {
+                        return Err(proto_error(format!(
+                            "Received a ShuffleReaderExecNode message with invalid reduce range [{}, {})",
+                            range.start_reduce_id, range.end_reduce_id
+                        )));
+                    }
+                    Ok(ShuffleReadRange::Reduce(
+                        range.start_reduce_id as usize,
+                        range.end_reduce_id as usize,
+                    ))
+                });
+                let map_ranges = shuffle_reader.map_ranges.iter().map(|range| {
+                    if range.start_map_index > range.end_map_index {
+                        return Err(proto_error(format!(
+                            "Received a ShuffleReaderExecNode message with invalid map range [{}, {}) of reduce partition {}",
+                            range.start_map_index, range.end_map_index, range.reduce_id
+                        )));
+                    }
+                    Ok(ShuffleReadRange::Map {
+                        reduce_id: range.reduce_id as usize,
+                        start_map_index: range.start_map_index as usize,
+                        end_map_index: range.end_map_index as usize,
+                    })
+                });
+                let read_ranges = reduce_ranges
+                    .chain(map_ranges)
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok(split_oversized_input_batches(Arc::new(ShuffleReaderExec::new(
                     shuffle_reader.num_partitions as usize,
                     shuffle_reader.native_shuffle_id.clone(),
                     schema,
-                )))
+                    match segment_source {
+                        protobuf::ShuffleSegmentSource::SegmentChannels => {
+                            ShuffleSegmentSource::SegmentChannels
+                        }
+                        protobuf::ShuffleSegmentSource::BlockStreams => {
+                            ShuffleSegmentSource::BlockStreams
+                        }
+                        protobuf::ShuffleSegmentSource::PartitionedSegmentChannels => {
+                            ShuffleSegmentSource::PartitionedSegmentChannels
+                        }
+                    },
+                    read_ranges,
+                    statistics.unwrap_or_default(),
+                ))))
             }
             PhysicalPlanType::JvmToNative(jvm_to_native) => {
                 let schema = Arc::new(convert_required!(jvm_to_native.schema)?);
-                Ok(Arc::new(JvmToNativeExec::new(
-                    jvm_to_native.num_partitions as usize,
-                    jvm_to_native.native_resource_id.clone(),
+                Ok(validate_utf8(
+                    Arc::new(JvmToNativeExec::new(
+                        jvm_to_native.num_partitions as usize,
+                        jvm_to_native.native_resource_id.clone(),
+                        schema,
+                    )),
+                    vec![format!("JVM input {}", jvm_to_native.native_resource_id)],
+                ))
+            }
+            PhysicalPlanType::FfiReader(ffi_reader) => {
+                let schema = Arc::new(convert_required!(ffi_reader.schema)?);
+                Ok(validate_utf8(
+                    Arc::new(FFIReaderExec::new(
+                        ffi_reader.num_partitions as usize,
+                        ffi_reader.export_iter_provider_resource_id.clone(),
+                        schema,
+                    )),
+                    vec![format!(
+                        "JVM FFI input {}",
+                        ffi_reader.export_iter_provider_resource_id
+                    )],
+                ))
+            }
+            PhysicalPlanType::FfiStreamReader(ffi_stream_reader) => {
+                let schema = Arc::new(convert_required!(ffi_stream_reader.schema)?);
+                Ok(validate_utf8(
+                    Arc::new(FFIStreamReaderExec::new(
+                        ffi_stream_reader.num_partitions as usize,
+                        ffi_stream_reader.stream_provider_resource_id.clone(),
+                        schema,
+                    )),
+                    vec![format!(
+                        "JVM FFI stream input {}",
+                        ffi_stream_reader.stream_provider_resource_id
+                    )],
+                ))
+            }
+            PhysicalPlanType::RowInput(row_input) => {
+                let schema = Arc::new(convert_required!(row_input.schema)?);
+                Ok(Arc::new(RowInputExec::try_new(
+                    row_input.num_partitions as usize,
+                    row_input.native_resource_id.clone(),
                     schema,
+                )?))
+            }
+            PhysicalPlanType::ReusedExchange(reused_exchange) => {
+                let input: Arc<dyn ExecutionPlan> =
+                    convert_box_required!(reused_exchange.input)?;
+                Ok(Arc::new(ReusedExchangeExec::new(
+                    reused_exchange.exchange_id.clone(),
+                    input,
                 )))
             }
             PhysicalPlanType::Empty(empty) => {
@@ -590,13 +1095,17 @@ impl TryInto<Arc<dyn ExecutionPlan>> for &protobuf::PhysicalPlanNode {
                                     ))
                                 })?
                                 .as_ref();
-                            Ok(PhysicalSortExpr {
-                                expr: bind(expr.try_into()?, &input.schema()).unwrap(),
-                                options: SortOptions {
-                                    descending: !sort_expr.asc,
-                                    nulls_first: sort_expr.nulls_first,
+                            // float keys are normalized to sort NaNs last like spark
+                            Ok(normalize_sort_expr(
+                                PhysicalSortExpr {
+                                    expr: bind(expr.try_into()?, &input.schema()).unwrap(),
+                                    options: SortOptions {
+                                        descending: !sort_expr.asc,
+                                        nulls_first: sort_expr.nulls_first,
+                                    },
                                 },
-                            })
+                                &input.schema(),
+                            )?)
                         } else {
                             Err(PlanSerDeError::General(format!(
                                 "physical_plan::from_proto() {:?}",
@@ -606,9 +1115,10 @@ impl TryInto<Arc<dyn ExecutionPlan>> for &protobuf::PhysicalPlanNode {
                     })
                     .collect::<Result<Vec<_>, _>>()?;
                 // always preserve partitioning
-                Ok(Arc::new(SortExec::new_with_partitioning(
-                    exprs, input, true,
-                )))
+                Ok(radix_sort(
+                    Arc::new(SortExec::new_with_partitioning(exprs, input, true)),
+                    native_conf().radix_sort_max_bytes,
+                )?)
             }
             PhysicalPlanType::Union(union) => {
                 let inputs: Vec<Arc<dyn ExecutionPlan>> = union
@@ -633,6 +1143,45 @@ impl TryInto<Arc<dyn ExecutionPlan>> for &protobuf::PhysicalPlanNode {
                     rename_columns.renamed_column_names.clone(),
                 )?))
             }
+            PhysicalPlanType::ParquetSink(parquet_sink) => {
+                let input: Arc<dyn ExecutionPlan> =
+                    convert_box_required!(parquet_sink.input)?;
+                let compression =
+                    protobuf::ParquetCompression::from_i32(parquet_sink.compression)
+                        .ok_or_else(|| {
+                            proto_error(format!(
+                                "Received a ParquetSinkExecNode message with unknown ParquetCompression {}",
+                                parquet_sink.compression
+                            ))
+                        })?;
+                let options = ParquetSinkOptions {
+                    partition_columns: parquet_sink.partition_columns.clone(),
+                    bucket_columns: parquet_sink.bucket_columns.clone(),
+                    num_buckets: parquet_sink.num_buckets as usize,
+                    compression: match compression {
+                        protobuf::ParquetCompression::Uncompressed => {
+                            Compression::UNCOMPRESSED
+                        }
+                        protobuf::ParquetCompression::Snappy => Compression::SNAPPY,
+                        protobuf::ParquetCompression::Gzip => Compression::GZIP,
+                        protobuf::ParquetCompression::Zstd => Compression::ZSTD,
+                    },
+                    file_extension: parquet_sink.file_extension.clone(),
+                    max_file_bytes: parquet_sink.max_file_bytes,
+                    max_records_per_file: parquet_sink.max_records_per_file,
+                    write_empty_file: parquet_sink.write_empty_file,
+                    metadata: parquet_sink
+                        .metadata
+                        .iter()
+                        .map(|(k, v)| (k.clone(), v.clone()))
+                        .collect(),
+                };
+                Ok(Arc::new(ParquetSinkExec::try_new(
+                    input,
+                    parquet_sink.output_dir.clone(),
+                    options,
+                )?))
+            }
             PhysicalPlanType::Unresolved(_unresolved_shuffle) => {
                 unreachable!()
             }
@@ -732,20 +1281,48 @@ impl TryFrom<&protobuf::PhysicalExprNode> for Arc<dyn PhysicalExpr> {
                 let pcol: Column = c.into();
                 Arc::new(pcol)
             }
-            ExprType::Literal(scalar) => {
-                Arc::new(Literal::new(convert_required!(scalar.value)?))
-            }
+            ExprType::Literal(scalar) => match &scalar.value {
+                // typed nulls and empty collections may not fit into ScalarValue
+                Some(protobuf::scalar_value::Value::TypedNullValue(arrow_type)) => {
+                    Arc::new(TypedLiteralExpr::new_null(arrow_type.try_into()?))
+                }
+                Some(protobuf::scalar_value::Value::EmptyCollectionValue(arrow_type)) => {
+                    Arc::new(TypedLiteralExpr::try_new_empty_collection(
+                        arrow_type.try_into()?,
+                    )?)
+                }
+                _ => Arc::new(Literal::new(convert_required!(scalar.value)?)),
+            },
             ExprType::BinaryExpr(binary_expr) => Arc::new(BinaryExpr::new(
                 convert_box_required!(&binary_expr.l)?,
                 from_proto_binary_op(&binary_expr.op)?,
                 convert_box_required!(&binary_expr.r)?,
             )),
+            ExprType::ScBinaryExpr(sc_binary_expr) => {
+                Arc::new(ShortCircuitBinaryExpr::try_new(
+                    convert_box_required!(&sc_binary_expr.l)?,
+                    from_proto_binary_op(&sc_binary_expr.op)?,
+                    convert_box_required!(&sc_binary_expr.r)?,
+                )?)
+            }
             ExprType::AggregateExpr(_) => {
                 return Err(PlanSerDeError::General(
                     "Cannot convert aggregate expr node to physical expression"
                         .to_owned(),
                 ));
             }
+            ExprType::ApproxPercentileExpr(_) => {
+                return Err(PlanSerDeError::General(
+                    "Cannot convert approx percentile expr node to physical expression"
+                        .to_owned(),
+                ));
+            }
+            ExprType::BoolAggregateExpr(_) => {
+                return Err(PlanSerDeError::General(
+                    "Cannot convert bool aggregate expr node to physical expression"
+                        .to_owned(),
+                ));
+            }
             ExprType::WindowExpr(_) => {
                 return Err(PlanSerDeError::General(
                     "Cannot convert window expr node to physical expression".to_owned(),
@@ -768,14 +1345,25 @@ impl TryFrom<&protobuf::PhysicalExprNode> for Arc<dyn PhysicalExpr> {
             ExprType::Negative(e) => {
                 Arc::new(NegativeExpr::new(convert_box_required!(e.expr)?))
             }
-            ExprType::InList(e) => Arc::new(InListExpr::new(
-                convert_box_required!(e.expr)?,
-                e.list
+            ExprType::InList(e) => {
+                let expr: Arc<dyn PhysicalExpr> = convert_box_required!(e.expr)?;
+                let list = e
+                    .list
                     .iter()
                     .map(|x| x.try_into())
-                    .collect::<Result<Vec<_>, _>>()?,
-                e.negated,
-            )),
+                    .collect::<Result<Vec<Arc<dyn PhysicalExpr>>, _>>()?;
+
+                // literal lists of supported types are looked up in hash sets
+                let in_list = SparkInListExpr::try_new_with_literals(
+                    expr.clone(),
+                    &list,
+                    e.negated,
+                );
+                match in_list {
+                    Some(in_list) => Arc::new(in_list),
+                    None => Arc::new(InListExpr::new(expr, list, e.negated)),
+                }
+            }
             ExprType::Case(e) => Arc::new(CaseExpr::try_new(
                 e.expr.as_ref().map(|e| e.as_ref().try_into()).transpose()?,
                 e.when_then_expr
@@ -802,6 +1390,124 @@ impl TryFrom<&protobuf::PhysicalExprNode> for Arc<dyn PhysicalExpr> {
                 convert_box_required!(e.expr)?,
                 convert_required!(e.arrow_type)?,
             )),
+            ExprType::GroupingExpr(e) => Arc::new(GroupingExpr::new(
+                convert_box_required!(e.grouping_id)?,
+                e.shift,
+            )),
+            ExprType::CharVarcharExpr(e) => {
+                let mode =
+                    protobuf::CharVarcharMode::from_i32(e.mode).ok_or_else(|| {
+                        proto_error(format!(
+                            "Received an unknown char/varchar mode: {}",
+                            e.mode,
+                        ))
+                    })?;
+                Arc::new(CharVarcharExpr::new(
+                    convert_box_required!(e.expr)?,
+                    match mode {
+                        protobuf::CharVarcharMode::ReadSidePadding => {
+                            CharVarcharMode::ReadSidePadding
+                        }
+                        protobuf::CharVarcharMode::CharWriteSideCheck => {
+                            CharVarcharMode::CharWriteSideCheck
+                        }
+                        protobuf::CharVarcharMode::VarcharWriteSideCheck => {
+                            CharVarcharMode::VarcharWriteSideCheck
+                        }
+                    },
+                    e.length as usize,
+                ))
+            }
+            ExprType::SparkPartitionExpr(e) => {
+                let kind = protobuf::SparkPartitionExprKind::from_i32(e.kind)
+                    .ok_or_else(|| {
+                        proto_error(format!(
+                            "Received an unknown spark partition expr kind: {}",
+                            e.kind,
+                        ))
+                    })?;
+                Arc::new(SparkPartitionExpr::new(match kind {
+                    protobuf::SparkPartitionExprKind::SparkPartitionId => {
+                        SparkPartitionExprKind::SparkPartitionId
+                    }
+                    protobuf::SparkPartitionExprKind::MonotonicallyIncreasingId => {
+                        SparkPartitionExprKind::MonotonicallyIncreasingId
+                    }
+                }))
+            }
+            ExprType::EqualNullSafeExpr(e) => Arc::new(EqualNullSafeExpr::new(
+                convert_box_required!(e.l)?,
+                convert_box_required!(e.r)?,
+            )),
+            ExprType::SparkLikeExpr(e) => {
+                let kind = if e.rlike {
+                    SparkLikeKind::RLike
+                } else {
+                    let mut chars = e.escape_char.chars();
+                    match (chars.next(), chars.next()) {
+                        (Some(escape_char), None) => SparkLikeKind::Like { escape_char },
+                        _ => {
+                            return Err(proto_error(format!(
+                                "Received an invalid escape char: '{}'",
+                                e.escape_char,
+                            )));
+                        }
+                    }
+                };
+                Arc::new(SparkLikeExpr::try_new(
+                    convert_box_required!(e.expr)?,
+                    convert_box_required!(e.pattern)?,
+                    kind,
+                )?)
+            }
+            ExprType::SparkIntervalAddExpr(e) => Arc::new(SparkIntervalAddExpr::new(
+                convert_box_required!(e.expr)?,
+                SparkInterval {
+                    months: e.months,
+                    days: e.days,
+                    microseconds: e.microseconds,
+                },
+                e.zone_offset_seconds,
+            )),
+            ExprType::ArrayFunctionExpr(e) => {
+                let fun = match protobuf::ArrayFunction::from_i32(e.fun) {
+                    Some(protobuf::ArrayFunction::Transform) => ArrayFunction::Transform,
+                    Some(protobuf::ArrayFunction::Filter) => ArrayFunction::Filter,
+                    Some(protobuf::ArrayFunction::Exists) => ArrayFunction::Exists {
+                        three_valued: e.three_valued,
+                    },
+                    Some(protobuf::ArrayFunction::Aggregate) => ArrayFunction::Aggregate,
+                    None => {
+                        return Err(proto_error(format!(
+                            "Received an unknown array function: {}",
+                            e.fun,
+                        )));
+                    }
+                };
+                let zero = e
+                    .zero
+                    .as_ref()
+                    .map(|zero| zero.as_ref().try_into())
+                    .transpose()?;
+                let finish = e
+                    .finish
+                    .as_ref()
+                    .map(|finish| finish.as_ref().try_into())
+                    .transpose()?;
+                Arc::new(ArrayFunctionExpr::try_new(
+                    fun,
+                    convert_box_required!(e.array)?,
+                    convert_box_required!(e.function)?,
+                    zero,
+                    finish,
+                )?)
+            }
+            ExprType::NormalizeNanAndZeroExpr(e) => {
+                Arc::new(NormalizeNaNAndZeroExpr::new(convert_box_required!(e.expr)?))
+            }
+            ExprType::SparkRandExpr(e) => {
+                Arc::new(SparkRandExpr::new(e.seed, e.gaussian))
+            }
             ExprType::ScalarFunction(e) => {
                 let scalar_function = protobuf::ScalarFunction::from_i32(e.fun)
                     .ok_or_else(|| {
@@ -836,6 +1542,18 @@ impl TryFrom<&protobuf::PhysicalExprNode> for Arc<dyn PhysicalExpr> {
     }
 }
 
+impl TryFrom<&protobuf::PhysicalLambdaNode> for LambdaFunction {
+    type Error = PlanSerDeError;
+
+    fn try_from(lambda: &protobuf::PhysicalLambdaNode) -> Result<Self, Self::Error> {
+        Ok(LambdaFunction::new(
+            convert_box_required!(lambda.body)?,
+            lambda.args.clone(),
+            lambda.captured_columns.clone(),
+        ))
+    }
+}
+
 impl TryFrom<&protobuf::physical_window_expr_node::WindowFunction> for WindowFunction {
     type Error = PlanSerDeError;
 
@@ -893,6 +1611,55 @@ pub fn parse_protobuf_hash_partitioning(
     }
 }
 
+pub fn parse_shuffle_writer_output(
+    shuffle_writer: &protobuf::ShuffleWriterExecNode,
+) -> ShuffleWriterOutput {
+    if shuffle_writer.rss_partition_writer_resource_id.is_empty() {
+        ShuffleWriterOutput::Local {
+            output_data_file: shuffle_writer.output_data_file.clone(),
+            output_index_file: shuffle_writer.output_index_file.clone(),
+        }
+    } else {
+        ShuffleWriterOutput::Rss {
+            rss_partition_writer_resource_id: shuffle_writer
+                .rss_partition_writer_resource_id
+                .clone(),
+        }
+    }
+}
+
+/// binds join keys to both inputs and parses their normalizations
+pub fn parse_protobuf_join_on(
+    left: &Arc<dyn ExecutionPlan>,
+    right: &Arc<dyn ExecutionPlan>,
+    on: &[protobuf::JoinOn],
+) -> Result<JoinKeysWithNormalizations, PlanSerDeError> {
+    let mut join_on = vec![];
+    let mut normalizations = vec![];
+    for col in on {
+        let left_col: Column = into_required!(col.left)?;
+        let left_col_binded = Column::new_with_schema(left_col.name(), &left.schema())?;
+        let right_col: Column = into_required!(col.right)?;
+        let right_col_binded =
+            Column::new_with_schema(right_col.name(), &right.schema())?;
+        join_on.push((left_col_binded, right_col_binded));
+
+        let normalization = match &col.normalization {
+            Some(normalization) => Some(JoinKeyNormalization {
+                target_type: convert_required!(normalization.target_type)?,
+                trim_trailing_spaces: normalization.trim_trailing_spaces,
+                normalize_nan_and_zero: normalization.normalize_nan_and_zero,
+            }),
+            None => None,
+        };
+        normalizations.push(normalization);
+    }
+    Ok((join_on, normalizations))
+}
+
+type JoinKeysWithNormalizations =
+    (Vec<(Column, Column)>, Vec<Option<JoinKeyNormalization>>);
+
 impl TryFrom<&protobuf::PartitionedFile> for PartitionedFile {
     type Error = PlanSerDeError;
 
@@ -919,6 +1686,23 @@ impl TryFrom<&protobuf::PartitionedFile> for PartitionedFile {
     }
 }
 
+impl From<&protobuf::RowDeletes> for RowDeletes {
+    fn from(val: &protobuf::RowDeletes) -> Self {
+        RowDeletes {
+            deleted_positions: val.deleted_positions.clone(),
+            positional_delete_files: val
+                .positional_delete_files
+                .iter()
+                .map(|file| SizedFile {
+                    path: file.path.clone(),
+                    size: file.size,
+                })
+                .collect(),
+            referenced_path: Some(val.referenced_path.clone()).filter(|p| !p.is_empty()),
+        }
+    }
+}
+
 impl TryFrom<&protobuf::FileRange> for FileRange {
     type Error = PlanSerDeError;
 
@@ -1291,3 +2075,51 @@ impl From<&protobuf::Column> for logical_plan::Column {
         c.clone().into()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use datafusion::arrow::datatypes::DataType;
+    use datafusion_ext::intra_task_parallel_exec::{
+        parallelize_plan, IntraTaskParallelExec,
+    };
+
+    use super::*;
+    use crate::test_util::{
+        empty_partitions, group_by_first_column, hash_join_on_first_columns, schema,
+    };
+
+    #[test]
+    fn test_parallelize_converted_plans() -> Result<(), PlanSerDeError> {
+        let input_schema = schema(&[("a", DataType::Int32), ("b", DataType::Int32)]);
+        let input = empty_partitions(&input_schema, 2);
+        let partial_agg = group_by_first_column(
+            input.clone(),
+            &input_schema,
+            protobuf::AggregateMode::Partial,
+        );
+        let final_agg = group_by_first_column(
+            input.clone(),
+            &input_schema,
+            protobuf::AggregateMode::FinalPartitioned,
+        );
+        // keys of different types are normalized and projected out of the output
+        let right_schema = schema(&[("c", DataType::Int64)]);
+        let join = hash_join_on_first_columns(
+            input,
+            &input_schema,
+            empty_partitions(&right_schema, 2),
+            &right_schema,
+        );
+
+        for node in [partial_agg, final_agg, join] {
+            let plan: Arc<dyn ExecutionPlan> = (&node).try_into()?;
+            let parallelized = parallelize_plan(plan.clone(), 2)?;
+            assert!(
+                parallelized.as_any().is::<IntraTaskParallelExec>(),
+                "not parallelized: {:?}",
+                plan
+            );
+        }
+        Ok(())
+    }
+}
@@ -20,6 +20,8 @@ use datafusion::logical_plan::{JoinConstraint, Operator};
 use datafusion::physical_plan::aggregates::AggregateFunction;
 use datafusion::prelude::JoinType;
 use datafusion::scalar::ScalarValue;
+use datafusion_ext::nested_loop_join_exec::BuildSide;
+use datafusion_ext::spark_bool_aggregate::SparkBoolAggregateFunction;
 
 use crate::error::PlanSerDeError;
 use crate::protobuf::scalar_type;
@@ -31,7 +33,14 @@ pub mod protobuf {
 }
 
 pub mod error;
+pub mod expr_rewrite;
 pub mod from_proto;
+pub mod plan_cache;
+pub mod task_definition;
+pub mod validate;
+
+#[cfg(test)]
+mod test_util;
 
 pub(crate) fn proto_error<S: Into<String>>(message: S) -> PlanSerDeError {
     PlanSerDeError::General(message.into())
@@ -321,6 +330,15 @@ impl TryInto<datafusion::arrow::datatypes::DataType>
                 let value_datatype: DataType = pb_value_datatype.as_ref().try_into()?;
                 DataType::Dictionary(Box::new(key_datatype), Box::new(value_datatype))
             }
+            arrow_type::ArrowTypeEnum::Map(map) => {
+                let map_type: &protobuf::Field = map
+                    .as_ref()
+                    .field_type
+                    .as_ref()
+                    .ok_or_else(|| proto_error("Protobuf deserialization error: Map message missing required field 'field_type'"))?
+                    .as_ref();
+                DataType::Map(Box::new(map_type.try_into()?), map.keys_sorted)
+            }
         })
     }
 }
@@ -367,6 +385,25 @@ impl From<protobuf::JoinType> for JoinType {
     }
 }
 
+impl From<protobuf::BoolAggregateFunction> for SparkBoolAggregateFunction {
+    fn from(f: protobuf::BoolAggregateFunction) -> Self {
+        match f {
+            protobuf::BoolAggregateFunction::CountIf => Self::CountIf,
+            protobuf::BoolAggregateFunction::BoolAnd => Self::BoolAnd,
+            protobuf::BoolAggregateFunction::BoolOr => Self::BoolOr,
+        }
+    }
+}
+
+impl From<protobuf::JoinSide> for BuildSide {
+    fn from(t: protobuf::JoinSide) -> Self {
+        match t {
+            protobuf::JoinSide::LeftSide => BuildSide::Left,
+            protobuf::JoinSide::RightSide => BuildSide::Right,
+        }
+    }
+}
+
 impl From<JoinType> for protobuf::JoinType {
     fn from(t: JoinType) -> Self {
         match t {
@@ -815,8 +852,11 @@ impl From<&DataType> for protobuf::arrow_type::ArrowTypeEnum {
                     fractional: *fractional as u64,
                 })
             }
-            DataType::Map(_, _) => {
-                unimplemented!("Ballista does not yet support Map data type")
+            DataType::Map(entries_type, keys_sorted) => {
+                ArrowTypeEnum::Map(Box::new(protobuf::Map {
+                    field_type: Some(Box::new(entries_type.as_ref().into())),
+                    keys_sorted: *keys_sorted,
+                }))
             }
         }
     }
@@ -1091,6 +1131,10 @@ impl TryInto<datafusion::scalar::ScalarValue> for &protobuf::ScalarValue {
                     .ok_or_else(|| proto_error("Protobuf deserialization error found invalid enum variant for DatafusionScalar"))?;
                 null_type_enum.try_into()?
             }
+            protobuf::scalar_value::Value::TypedNullValue(v) => typed_null_scalar(v)?,
+            protobuf::scalar_value::Value::EmptyCollectionValue(v) => {
+                empty_collection_scalar(v)?
+            }
         })
     }
 }
@@ -1214,6 +1258,10 @@ impl TryInto<datafusion::scalar::ScalarValue> for &protobuf::scalar_value::Value
                     .ok_or_else(|| proto_error("Invalid scalar type"))?
                     .try_into()?
             }
+            protobuf::scalar_value::Value::TypedNullValue(v) => typed_null_scalar(v)?,
+            protobuf::scalar_value::Value::EmptyCollectionValue(v) => {
+                empty_collection_scalar(v)?
+            }
             protobuf::scalar_value::Value::DecimalValue(v) => {
                 let decimal = v.decimal.as_ref().unwrap();
                 ScalarValue::Decimal128(
@@ -1227,6 +1275,34 @@ impl TryInto<datafusion::scalar::ScalarValue> for &protobuf::scalar_value::Value
     }
 }
 
+fn typed_null_scalar(
+    arrow_type: &protobuf::ArrowType,
+) -> Result<datafusion::scalar::ScalarValue, PlanSerDeError> {
+    let data_type: DataType = arrow_type.try_into()?;
+    ScalarValue::try_from(&data_type).map_err(|_| {
+        proto_error(format!(
+            "Protobuf deserialization error: typed null of {:?} is not a valid scalar value",
+            data_type
+        ))
+    })
+}
+
+fn empty_collection_scalar(
+    arrow_type: &protobuf::ArrowType,
+) -> Result<datafusion::scalar::ScalarValue, PlanSerDeError> {
+    let data_type: DataType = arrow_type.try_into()?;
+    match data_type {
+        DataType::List(field) => Ok(ScalarValue::List(
+            Some(Box::new(vec![])),
+            Box::new(field.data_type().clone()),
+        )),
+        other => Err(proto_error(format!(
+            "Protobuf deserialization error: empty collection of {:?} is not a valid scalar value",
+            other
+        ))),
+    }
+}
+
 impl TryInto<datafusion::scalar::ScalarValue> for &protobuf::ScalarListValue {
     type Error = PlanSerDeError;
     fn try_into(self) -> Result<datafusion::scalar::ScalarValue, Self::Error> {
@@ -0,0 +1,366 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Caches converted plans of stages, so that tasks of the same stage skip
+//! converting the whole plan from protobuf.
+//!
+//! tasks of a stage share the same plan except their leaves (scanned files,
+//! shuffle inputs, resource ids) and shuffle writer outputs. a cached plan is
+//! a template keyed by the stage and a hash of the plan without these
+//! partition-specific parts. for a hit, only the leaves are converted and the
+//! other operators are rebuilt from the template with `with_new_children()`,
+//! so no state or metrics are shared with executions of other tasks.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::sync::{Arc, Mutex};
+
+use datafusion::physical_plan::sorts::sort::SortExec;
+use datafusion::physical_plan::ExecutionPlan;
+use datafusion_ext::parquet_sink_exec::{ParquetSinkExec, ParquetSinkOptions};
+use datafusion_ext::shuffle_reader_exec::ShuffleReaderExec;
+use datafusion_ext::shuffle_writer_exec::ShuffleWriterExec;
+use datafusion_ext::split_oversized_batches_exec::{
+    split_oversized_input_batches, SplitOversizedBatchesExec,
+};
+use datafusion_ext::topk_groups_exec::TopKGroupsExec;
+use once_cell::sync::OnceCell;
+use prost::Message;
+
+use crate::error::PlanSerDeError;
+use crate::from_proto::parse_shuffle_writer_output;
+use crate::protobuf::physical_plan_node::PhysicalPlanType;
+use crate::protobuf::PhysicalPlanNode;
+use crate::task_definition::convert_plan;
+use crate::validate::describe_plan_type;
+
+/// max number of cached plans, oldest plans are evicted first if exceeded
+const MAX_CACHED_PLANS: usize = 256;
+
+/// cached template of a plan, None if the plan cannot be rebuilt from a
+/// template (e.g. containing reused exchanges), in which case tasks of the
+/// stage always convert the whole plan
+type CachedPlan = Option<Arc<dyn ExecutionPlan>>;
+
+#[derive(Default)]
+struct PlanCache {
+    plans: HashMap<(u32, u64), CachedPlan>,
+    insertion_order: VecDeque<(u32, u64)>,
+}
+
+fn plan_cache() -> &'static Mutex<PlanCache> {
+    static PLAN_CACHE: OnceCell<Mutex<PlanCache>> = OnceCell::new();
+    PLAN_CACHE.get_or_init(|| Mutex::new(PlanCache::default()))
+}
+
+/// converts the plan of a task, reusing the converted plan of previous tasks
+/// of the same stage if possible. task_conf is the native conf overridden for
+/// the task, which is also embedded in converted plans.
+pub fn convert_plan_cached(
+    stage_id: u32,
+    task_conf: &HashMap<String, String>,
+    plan: &PhysicalPlanNode,
+) -> Result<Arc<dyn ExecutionPlan>, PlanSerDeError> {
+    let mut hasher = DefaultHasher::new();
+    task_conf
+        .iter()
+        .collect::<BTreeMap<_, _>>()
+        .hash(&mut hasher);
+    hash_template(plan, &mut hasher)?;
+    let key = (stage_id, hasher.finish());
+
+    let cached = plan_cache().lock().unwrap().plans.get(&key).cloned();
+    match cached {
+        Some(Some(template)) => {
+            if let Some(rebuilt) = rebind(&template, plan).ok().flatten() {
+                log::info!("Reused cached plan of stage {}", stage_id);
+                return Ok(rebuilt);
+            }
+            log::warn!("Cached plan of stage {} is not reusable", stage_id);
+            insert_plan(key, None);
+            convert_plan(plan)
+        }
+        Some(None) => convert_plan(plan),
+        None => {
+            // the template itself is never executed, tasks always run a
+            // rebuilt copy so that the cached template holds no task states
+            let template = convert_plan(plan)?;
+            match rebind(&template, plan).ok().flatten() {
+                Some(rebuilt) => {
+                    insert_plan(key, Some(template));
+                    Ok(rebuilt)
+                }
+                None => {
+                    insert_plan(key, None);
+                    Ok(template)
+                }
+            }
+        }
+    }
+}
+
+/// drops all cached plans, called when native conf is updated
+pub fn clear_plan_cache() {
+    let mut cache = plan_cache().lock().unwrap();
+    cache.plans.clear();
+    cache.insertion_order.clear();
+}
+
+fn insert_plan(key: (u32, u64), plan: CachedPlan) {
+    let mut cache = plan_cache().lock().unwrap();
+    if cache.plans.insert(key, plan).is_none() {
+        cache.insertion_order.push_back(key);
+    }
+    while cache.insertion_order.len() > MAX_CACHED_PLANS {
+        let oldest = cache.insertion_order.pop_front().unwrap();
+        cache.plans.remove(&oldest);
+    }
+}
+
+/// hashes the plan without leaves and partition-specific fields, leaves are
+/// only hashed by their operator names and are verified by their schemas
+/// when rebinding
+fn hash_template(
+    plan: &PhysicalPlanNode,
+    hasher: &mut DefaultHasher,
+) -> Result<(), PlanSerDeError> {
+    let plan_type = plan
+        .physical_plan_type
+        .as_ref()
+        .ok_or_else(|| PlanSerDeError::General("plan node type is not set".to_owned()))?;
+    let (operator, children, _) = describe_plan_type(plan_type);
+    operator.hash(hasher);
+    if !children.is_empty() {
+        let node = PhysicalPlanNode {
+            physical_plan_type: Some(without_children(plan_type)),
+        };
+        node.encode_to_vec().hash(hasher);
+    }
+    children.len().hash(hasher);
+    for child in children {
+        hash_template(child, hasher)?;
+    }
+    Ok(())
+}
+
+/// clones the plan node with its children and partition-specific fields
+/// cleared
+fn without_children(plan_type: &PhysicalPlanType) -> PhysicalPlanType {
+    macro_rules! cleared {
+        ($variant:ident, $node:expr, $($field:ident),*) => {{
+            let mut node = $node.clone();
+            $(node.$field = Default::default();)*
+            PhysicalPlanType::$variant(node)
+        }};
+    }
+    match plan_type {
+        PhysicalPlanType::Projection(n) => cleared!(Projection, n, input),
+        PhysicalPlanType::Filter(n) => cleared!(Filter, n, input),
+        PhysicalPlanType::GlobalLimit(n) => cleared!(GlobalLimit, n, input),
+        PhysicalPlanType::LocalLimit(n) => cleared!(LocalLimit, n, input),
+        PhysicalPlanType::HashAggregate(n) => cleared!(HashAggregate, n, input),
+        PhysicalPlanType::Window(n) => cleared!(Window, n, input),
+        PhysicalPlanType::Sort(n) => cleared!(Sort, n, input),
+        PhysicalPlanType::HashJoin(n) => cleared!(HashJoin, n, left, right),
+        PhysicalPlanType::SortMergeJoin(n) => cleared!(SortMergeJoin, n, left, right),
+        PhysicalPlanType::CrossJoin(n) => cleared!(CrossJoin, n, left, right),
+        PhysicalPlanType::NestedLoopJoin(n) => cleared!(NestedLoopJoin, n, left, right),
+        PhysicalPlanType::Union(n) => cleared!(Union, n, children),
+        PhysicalPlanType::CoalesceBatches(n) => cleared!(CoalesceBatches, n, input),
+        PhysicalPlanType::Merge(n) => cleared!(Merge, n, input),
+        PhysicalPlanType::Repartition(n) => cleared!(Repartition, n, input),
+        PhysicalPlanType::RenameColumns(n) => cleared!(RenameColumns, n, input),
+        PhysicalPlanType::ReusedExchange(n) => cleared!(ReusedExchange, n, input),
+        PhysicalPlanType::ShuffleWriter(n) => cleared!(
+            ShuffleWriter,
+            n,
+            input,
+            output_data_file,
+            output_index_file,
+            rss_partition_writer_resource_id,
+            write_metrics_resource_id
+        ),
+        PhysicalPlanType::ParquetSink(n) => {
+            cleared!(ParquetSink, n, input, output_dir, write_empty_file)
+        }
+        leaf => leaf.clone(),
+    }
+}
+
+/// rebuilds the template with leaves and shuffle writer outputs of the plan,
+/// returns None if the plan does not match the template
+fn rebind(
+    template: &Arc<dyn ExecutionPlan>,
+    plan: &PhysicalPlanNode,
+) -> Result<Option<Arc<dyn ExecutionPlan>>, PlanSerDeError> {
+    let plan_type = match &plan.physical_plan_type {
+        Some(plan_type) => plan_type,
+        None => return Ok(None),
+    };
+    let (_, children, _) = describe_plan_type(plan_type);
+    let template_children = template.children();
+    if children.len() != template_children.len() {
+        return Ok(None);
+    }
+
+    // leaves are converted again, and must produce the same schema since
+    // operators of the template are bound to it
+    if children.is_empty() {
+        let leaf = rebind_leaf(template, convert_plan(plan)?);
+        return Ok((leaf.schema() == template.schema()).then(|| leaf));
+    }
+
+    let mut rebound_children = vec![];
+    for (template_child, child) in template_children.iter().zip(children) {
+        match rebind(template_child, child)? {
+            Some(rebound_child) => rebound_children.push(rebound_child),
+            None => return Ok(None),
+        }
+    }
+
+    if let PhysicalPlanType::ShuffleWriter(shuffle_writer) = plan_type {
+        let template = match template.as_any().downcast_ref::<ShuffleWriterExec>() {
+            Some(template) => template,
+            None => return Ok(None),
+        };
+        return Ok(Some(Arc::new(template.with_new_output(
+            rebound_children.remove(0),
+            parse_shuffle_writer_output(shuffle_writer),
+            shuffle_writer.write_metrics_resource_id.clone(),
+        )?)));
+    }
+
+    if let PhysicalPlanType::ParquetSink(parquet_sink) = plan_type {
+        let template = match template.as_any().downcast_ref::<ParquetSinkExec>() {
+            Some(template) => template,
+            None => return Ok(None),
+        };
+        let options = ParquetSinkOptions {
+            write_empty_file: parquet_sink.write_empty_file,
+            ..template.options().clone()
+        };
+        return Ok(Some(Arc::new(ParquetSinkExec::try_new(
+            rebound_children.remove(0),
+            parquet_sink.output_dir.clone(),
+            options,
+        )?)));
+    }
+
+    // SortExec::with_new_children() does not preserve partitioning
+    if let Some(sort) = template.as_any().downcast_ref::<SortExec>() {
+        return Ok(Some(Arc::new(SortExec::new_with_partitioning(
+            sort.expr().to_vec(),
+            rebound_children.remove(0),
+            true,
+        ))));
+    }
+    Ok(Some(template.clone().with_new_children(rebound_children)?))
+}
+
+/// rebuilds the operators added over a leaf of the template by conversions
+/// of its parents around the converted leaf, e.g. pruning top-k groups of an
+/// aggregation over a shuffle read. leaves modified by conversions of their
+/// parents are rebound by themselves, e.g. pruning rows of a shuffle read.
+fn rebind_leaf(
+    template: &Arc<dyn ExecutionPlan>,
+    leaf: Arc<dyn ExecutionPlan>,
+) -> Arc<dyn ExecutionPlan> {
+    if let Some(topk_groups) = template.as_any().downcast_ref::<TopKGroupsExec>() {
+        let input = rebind_leaf(topk_groups.input(), leaf);
+        return Arc::new(topk_groups.with_input(input));
+    }
+
+    // splitting oversized batches is added by the conversion of the leaf
+    let template_any = template.as_any();
+    let leaf_any = leaf.as_any();
+    if let (Some(template_split), Some(leaf_split)) = (
+        template_any.downcast_ref::<SplitOversizedBatchesExec>(),
+        leaf_any.downcast_ref::<SplitOversizedBatchesExec>(),
+    ) {
+        let input = rebind_leaf(template_split.input(), leaf_split.input().clone());
+        return split_oversized_input_batches(input);
+    }
+    if let (Some(template_reader), Some(leaf_reader)) = (
+        template_any.downcast_ref::<ShuffleReaderExec>(),
+        leaf_any.downcast_ref::<ShuffleReaderExec>(),
+    ) {
+        return Arc::new(template_reader.rebind(leaf_reader));
+    }
+    leaf
+}
+
+#[cfg(test)]
+mod tests {
+    use datafusion::arrow::datatypes::DataType;
+
+    use super::*;
+    use crate::protobuf;
+    use crate::test_util::{
+        group_by_first_column, limited_sort_by_first_column, schema, shuffle_reader,
+    };
+
+    fn contains<T: 'static>(plan: &Arc<dyn ExecutionPlan>) -> bool {
+        plan.as_any().is::<T>() || plan.children().iter().any(contains::<T>)
+    }
+
+    #[test]
+    fn test_rebind_topk_groups() -> Result<(), PlanSerDeError> {
+        // GROUP BY a ORDER BY a LIMIT 10 of a shuffle read
+        let input_schema = schema(&[("a", DataType::Int32)]);
+        let agg = group_by_first_column(
+            shuffle_reader(&input_schema, 2),
+            &input_schema,
+            protobuf::AggregateMode::FinalPartitioned,
+        );
+        let plan = limited_sort_by_first_column(agg, &input_schema, 10);
+
+        // the first task converts the template, both tasks run rebuilt plans
+        for _ in 0..2 {
+            let converted = convert_plan_cached(1, &HashMap::new(), &plan)?;
+            assert!(contains::<TopKGroupsExec>(&converted));
+        }
+        Ok(())
+    }
+
+    /// returns the limit of rows pruned by the shuffle reader of the plan
+    fn shuffle_read_topk(plan: &Arc<dyn ExecutionPlan>) -> Option<usize> {
+        let plan_any = plan.as_any();
+        if let Some(split) = plan_any.downcast_ref::<SplitOversizedBatchesExec>() {
+            return shuffle_read_topk(split.input());
+        }
+        if let Some(reader) = plan_any.downcast_ref::<ShuffleReaderExec>() {
+            return reader.topk.as_ref().map(|(_, limit)| *limit);
+        }
+        plan.children().iter().find_map(shuffle_read_topk)
+    }
+
+    #[test]
+    fn test_rebind_topk_shuffle_read() -> Result<(), PlanSerDeError> {
+        // ORDER BY a LIMIT 10 of a shuffle read
+        let input_schema = schema(&[("a", DataType::Int32)]);
+        let plan = limited_sort_by_first_column(
+            shuffle_reader(&input_schema, 1),
+            &input_schema,
+            10,
+        );
+
+        // the first task converts the template, both tasks run rebuilt plans
+        for _ in 0..2 {
+            let converted = convert_plan_cached(2, &HashMap::new(), &plan)?;
+            assert_eq!(shuffle_read_topk(&converted), Some(10));
+        }
+        Ok(())
+    }
+}
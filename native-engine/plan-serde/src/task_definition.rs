@@ -0,0 +1,128 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Hardened decoding of task definitions received from JVM.
+//!
+//! malformed bytes and plans are reported as descriptive errors instead of
+//! panics. panics of plan conversion (e.g. on unexpected field values) are
+//! caught and turned into errors, so that a corrupted task payload fails its
+//! task instead of the executor. see `fuzz/` for the fuzz target.
+//!
+//! the JVM side checks the plan version of the native library once loaded,
+//! task definitions are also rejected if serialized with another version, so
+//! that a spark extension jar mismatching the native library fails fast
+//! instead of decoding fields of a different meaning.
+
+use std::convert::TryInto;
+use std::panic::AssertUnwindSafe;
+use std::sync::Arc;
+
+use datafusion::physical_plan::ExecutionPlan;
+use prost::Message;
+
+use crate::error::PlanSerDeError;
+use crate::protobuf::{NativeFeature, PhysicalPlanNode, TaskDefinition};
+
+/// version of the plan protocol, increased on every incompatible change of
+/// the protobuf messages. must match BlazeCallNativeWrapper.nativePlanVersion
+pub const PLAN_VERSION: u32 = 1;
+
+/// returns the bitmap of optional features supported by the native library
+pub fn supported_features() -> u64 {
+    [
+        NativeFeature::MultiplexedPartitions,
+        NativeFeature::BatchIterator,
+        NativeFeature::RowOutput,
+        NativeFeature::NativeExplain,
+        NativeFeature::NativeMemoryReport,
+        NativeFeature::FfiStreamInput,
+        NativeFeature::BatchStream,
+    ]
+    .into_iter()
+    .fold(0, |features, feature| features | 1 << feature as u64)
+}
+
+/// decodes a serialized task definition, which must have a task id and a plan
+pub fn decode_task_definition(raw: &[u8]) -> Result<TaskDefinition, PlanSerDeError> {
+    let task_definition = TaskDefinition::decode(raw).map_err(|err| {
+        PlanSerDeError::General(format!(
+            "malformed task definition of {} bytes: {}",
+            raw.len(),
+            err
+        ))
+    })?;
+    if task_definition.plan_version != PLAN_VERSION {
+        return Err(PlanSerDeError::General(format!(
+            concat!(
+                "task definition of plan version {} is not supported by native ",
+                "library of plan version {}, the spark extension jar may not match ",
+                "the native library",
+            ),
+            task_definition.plan_version, PLAN_VERSION,
+        )));
+    }
+    if task_definition.task_id.is_none() {
+        return Err(PlanSerDeError::required("TaskDefinition.task_id"));
+    }
+    if task_definition.plan.is_none() {
+        return Err(PlanSerDeError::required("TaskDefinition.plan"));
+    }
+    Ok(task_definition)
+}
+
+/// converts a plan from protobuf, returning an error if the conversion panics
+pub fn convert_plan(
+    plan: &PhysicalPlanNode,
+) -> Result<Arc<dyn ExecutionPlan>, PlanSerDeError> {
+    match std::panic::catch_unwind(AssertUnwindSafe(|| plan.try_into())) {
+        Ok(converted) => converted,
+        Err(panic) => {
+            let message = panic
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "unknown panic".to_owned());
+            Err(PlanSerDeError::Internal(format!(
+                "panicked converting plan: {}",
+                message
+            )))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::protobuf::PartitionId;
+
+    use super::*;
+
+    #[test]
+    fn test_decode_task_definition_version() {
+        let task_definition = |plan_version| TaskDefinition {
+            task_id: Some(PartitionId::default()),
+            plan: Some(PhysicalPlanNode::default()),
+            plan_version,
+            ..Default::default()
+        };
+
+        let raw = task_definition(PLAN_VERSION).encode_to_vec();
+        assert!(decode_task_definition(&raw).is_ok());
+
+        // definitions serialized with another plan version are rejected
+        // instead of decoding fields of a different meaning
+        let raw = task_definition(PLAN_VERSION + 1).encode_to_vec();
+        let err = decode_task_definition(&raw).unwrap_err();
+        assert!(err.to_string().contains("plan version"));
+    }
+}
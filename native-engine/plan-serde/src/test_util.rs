@@ -0,0 +1,142 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Defines protobuf plans shared by unit tests of the conversions
+
+use datafusion::arrow::datatypes::{DataType, Field, Schema};
+
+use crate::protobuf;
+use crate::protobuf::physical_expr_node::ExprType;
+use crate::protobuf::physical_plan_node::PhysicalPlanType;
+use crate::protobuf::{PhysicalExprNode, PhysicalPlanNode};
+
+/// schema of nullable columns of the names and types
+pub fn schema(fields: &[(&str, DataType)]) -> Schema {
+    Schema::new(
+        fields
+            .iter()
+            .map(|(name, data_type)| Field::new(name, data_type.clone(), true))
+            .collect(),
+    )
+}
+
+pub fn plan(plan_type: PhysicalPlanType) -> PhysicalPlanNode {
+    PhysicalPlanNode {
+        physical_plan_type: Some(plan_type),
+    }
+}
+
+/// a plan of `num_partitions` empty partitions of the schema
+pub fn empty_partitions(schema: &Schema, num_partitions: u32) -> PhysicalPlanNode {
+    plan(PhysicalPlanType::EmptyPartitions(
+        protobuf::EmptyPartitionsExecNode {
+            schema: Some(schema.into()),
+            num_partitions,
+        },
+    ))
+}
+
+/// a shuffle read of `num_partitions` partitions of the schema
+pub fn shuffle_reader(schema: &Schema, num_partitions: u32) -> PhysicalPlanNode {
+    plan(PhysicalPlanType::ShuffleReader(
+        protobuf::ShuffleReaderExecNode {
+            num_partitions,
+            schema: Some(schema.into()),
+            native_shuffle_id: "shuffle".to_owned(),
+            ..Default::default()
+        },
+    ))
+}
+
+pub fn column(name: &str, index: u32) -> protobuf::PhysicalColumn {
+    protobuf::PhysicalColumn {
+        name: name.to_owned(),
+        index,
+    }
+}
+
+pub fn column_expr(name: &str, index: u32) -> PhysicalExprNode {
+    PhysicalExprNode {
+        expr_type: Some(ExprType::Column(column(name, index))),
+    }
+}
+
+/// an aggregation of the input grouped by its first column, without
+/// aggregate functions
+pub fn group_by_first_column(
+    input: PhysicalPlanNode,
+    input_schema: &Schema,
+    mode: protobuf::AggregateMode,
+) -> PhysicalPlanNode {
+    let key = input_schema.field(0).name();
+    plan(PhysicalPlanType::HashAggregate(Box::new(
+        protobuf::HashAggregateExecNode {
+            group_expr: vec![column_expr(key, 0)],
+            group_expr_name: vec![key.to_owned()],
+            mode: mode as i32,
+            input: Some(Box::new(input)),
+            input_schema: Some(input_schema.into()),
+            ..Default::default()
+        },
+    )))
+}
+
+/// a partitioned inner hash join on the first columns of both sides
+pub fn hash_join_on_first_columns(
+    left: PhysicalPlanNode,
+    left_schema: &Schema,
+    right: PhysicalPlanNode,
+    right_schema: &Schema,
+) -> PhysicalPlanNode {
+    plan(PhysicalPlanType::HashJoin(Box::new(
+        protobuf::HashJoinExecNode {
+            left: Some(Box::new(left)),
+            right: Some(Box::new(right)),
+            on: vec![protobuf::JoinOn {
+                left: Some(column(left_schema.field(0).name(), 0)),
+                right: Some(column(right_schema.field(0).name(), 0)),
+                ..Default::default()
+            }],
+            join_type: protobuf::JoinType::Inner as i32,
+            partition_mode: protobuf::PartitionMode::Partitioned as i32,
+            ..Default::default()
+        },
+    )))
+}
+
+/// the first `limit` rows of the input sorted by its first column
+pub fn limited_sort_by_first_column(
+    input: PhysicalPlanNode,
+    input_schema: &Schema,
+    limit: u32,
+) -> PhysicalPlanNode {
+    let key = input_schema.field(0).name();
+    let sort_expr = PhysicalExprNode {
+        expr_type: Some(ExprType::Sort(Box::new(protobuf::PhysicalSortExprNode {
+            expr: Some(Box::new(column_expr(key, 0))),
+            asc: true,
+            nulls_first: true,
+        }))),
+    };
+    let sort = plan(PhysicalPlanType::Sort(Box::new(protobuf::SortExecNode {
+        input: Some(Box::new(input)),
+        expr: vec![sort_expr],
+    })));
+    plan(PhysicalPlanType::GlobalLimit(Box::new(
+        protobuf::GlobalLimitExecNode {
+            input: Some(Box::new(sort)),
+            limit,
+        },
+    )))
+}
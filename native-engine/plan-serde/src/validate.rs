@@ -0,0 +1,248 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Validates plans without executing them, reporting everything unsupported
+
+use std::convert::TryInto;
+use std::sync::Arc;
+
+use datafusion::physical_plan::{ExecutionPlan, PhysicalExpr};
+
+use crate::error::PlanSerDeError;
+use crate::protobuf::physical_expr_node::ExprType;
+use crate::protobuf::physical_plan_node::PhysicalPlanType;
+use crate::protobuf::{
+    PhysicalExprNode, PhysicalPlanNode, PlanValidationResult, UnsupportedItem,
+};
+
+/// Converts the plan node by node and collects all unsupported operators and
+/// expressions with their reasons.
+///
+/// children are validated before their parent. a parent is only reported if
+/// all its children are supported, since its conversion would otherwise fail
+/// with the same error of the child. paths of operators are child indices
+/// from the root, like `root.0.1`.
+pub fn validate_plan(plan: &PhysicalPlanNode) -> PlanValidationResult {
+    let mut unsupported = vec![];
+    validate_plan_node(plan, "root".to_owned(), &mut unsupported);
+    PlanValidationResult { unsupported }
+}
+
+/// returns true if the plan node and all its descendants are supported
+fn validate_plan_node(
+    plan: &PhysicalPlanNode,
+    path: String,
+    unsupported: &mut Vec<UnsupportedItem>,
+) -> bool {
+    let plan_type = match &plan.physical_plan_type {
+        Some(plan_type) => plan_type,
+        None => {
+            unsupported.push(UnsupportedItem {
+                path,
+                operator: "Unknown".to_owned(),
+                expression: String::new(),
+                reason: "plan node type is not set or unknown to native engine"
+                    .to_owned(),
+            });
+            return false;
+        }
+    };
+    let (operator, children, exprs) = describe_plan_type(plan_type);
+
+    let mut children_supported = true;
+    for (i, child) in children.into_iter().enumerate() {
+        let child_path = format!("{}.{}", path, i);
+        children_supported &= validate_plan_node(child, child_path, unsupported);
+    }
+    if !children_supported {
+        return false;
+    }
+
+    let mut exprs_supported = true;
+    for expr in exprs {
+        if let Err(err) = validate_expr(expr) {
+            unsupported.push(UnsupportedItem {
+                path: path.clone(),
+                operator: operator.to_owned(),
+                expression: format!("{:?}", expr),
+                reason: err.to_string(),
+            });
+            exprs_supported = false;
+        }
+    }
+    if !exprs_supported {
+        return false;
+    }
+
+    let converted: Result<Arc<dyn ExecutionPlan>, PlanSerDeError> = plan.try_into();
+    if let Err(err) = converted {
+        unsupported.push(UnsupportedItem {
+            path,
+            operator: operator.to_owned(),
+            expression: String::new(),
+            reason: err.to_string(),
+        });
+        return false;
+    }
+    true
+}
+
+/// converts the expression without binding it to the input schema. wrappers
+/// only valid in specific operators (sort/aggregate/window) are unpacked.
+fn validate_expr(expr: &PhysicalExprNode) -> Result<(), PlanSerDeError> {
+    let inner = match &expr.expr_type {
+        Some(ExprType::Sort(sort)) => sort.expr.as_deref(),
+        Some(ExprType::AggregateExpr(agg)) => agg.expr.as_deref(),
+        Some(ExprType::WindowExpr(window)) => window.expr.as_deref(),
+        Some(ExprType::ApproxPercentileExpr(percentile)) => percentile.expr.as_deref(),
+        Some(ExprType::BoolAggregateExpr(bool_agg)) => bool_agg.expr.as_deref(),
+        _ => Some(expr),
+    };
+    if let Some(inner) = inner {
+        let _: Arc<dyn PhysicalExpr> = inner.try_into()?;
+    }
+    Ok(())
+}
+
+/// returns operator name, child plans and expressions of the plan node
+pub(crate) fn describe_plan_type(
+    plan_type: &PhysicalPlanType,
+) -> (&'static str, Vec<&PhysicalPlanNode>, Vec<&PhysicalExprNode>) {
+    match plan_type {
+        PhysicalPlanType::ParquetScan(_) => ("ParquetScan", vec![], vec![]),
+        PhysicalPlanType::CsvScan(_) => ("CsvScan", vec![], vec![]),
+        PhysicalPlanType::AvroScan(_) => ("AvroScan", vec![], vec![]),
+        PhysicalPlanType::Empty(_) => ("Empty", vec![], vec![]),
+        PhysicalPlanType::EmptyPartitions(_) => ("EmptyPartitions", vec![], vec![]),
+        PhysicalPlanType::Unresolved(_) => ("UnresolvedShuffle", vec![], vec![]),
+        PhysicalPlanType::ShuffleReader(_) => ("ShuffleReader", vec![], vec![]),
+        PhysicalPlanType::JvmToNative(_) => ("JvmToNative", vec![], vec![]),
+        PhysicalPlanType::RowInput(_) => ("RowInput", vec![], vec![]),
+        PhysicalPlanType::FfiReader(_) => ("FFIReader", vec![], vec![]),
+        PhysicalPlanType::FfiStreamReader(_) => ("FFIStreamReader", vec![], vec![]),
+        PhysicalPlanType::Projection(projection) => (
+            "Projection",
+            projection.input.as_deref().into_iter().collect(),
+            projection.expr.iter().collect(),
+        ),
+        PhysicalPlanType::Filter(filter) => (
+            "Filter",
+            filter.input.as_deref().into_iter().collect(),
+            filter.expr.iter().collect(),
+        ),
+        PhysicalPlanType::GlobalLimit(limit) => (
+            "GlobalLimit",
+            limit.input.as_deref().into_iter().collect(),
+            vec![],
+        ),
+        PhysicalPlanType::LocalLimit(limit) => (
+            "LocalLimit",
+            limit.input.as_deref().into_iter().collect(),
+            vec![],
+        ),
+        PhysicalPlanType::HashAggregate(agg) => (
+            "HashAggregate",
+            agg.input.as_deref().into_iter().collect(),
+            agg.group_expr.iter().chain(&agg.aggr_expr).collect(),
+        ),
+        PhysicalPlanType::Window(window) => (
+            "Window",
+            window.input.as_deref().into_iter().collect(),
+            window.window_expr.iter().collect(),
+        ),
+        PhysicalPlanType::Sort(sort) => (
+            "Sort",
+            sort.input.as_deref().into_iter().collect(),
+            sort.expr.iter().collect(),
+        ),
+        PhysicalPlanType::HashJoin(join) => (
+            "HashJoin",
+            join.left
+                .iter()
+                .chain(&join.right)
+                .map(|c| c.as_ref())
+                .collect(),
+            vec![],
+        ),
+        PhysicalPlanType::SortMergeJoin(join) => (
+            "SortMergeJoin",
+            join.left
+                .iter()
+                .chain(&join.right)
+                .map(|c| c.as_ref())
+                .collect(),
+            vec![],
+        ),
+        PhysicalPlanType::CrossJoin(join) => (
+            "CrossJoin",
+            join.left
+                .iter()
+                .chain(&join.right)
+                .map(|c| c.as_ref())
+                .collect(),
+            vec![],
+        ),
+        PhysicalPlanType::NestedLoopJoin(join) => (
+            "NestedLoopJoin",
+            join.left
+                .iter()
+                .chain(&join.right)
+                .map(|c| c.as_ref())
+                .collect(),
+            join.condition.iter().collect(),
+        ),
+        PhysicalPlanType::Union(union) => {
+            ("Union", union.children.iter().collect(), vec![])
+        }
+        PhysicalPlanType::CoalesceBatches(coalesce) => (
+            "CoalesceBatches",
+            coalesce.input.as_deref().into_iter().collect(),
+            vec![],
+        ),
+        PhysicalPlanType::Merge(merge) => (
+            "CoalescePartitions",
+            merge.input.as_deref().into_iter().collect(),
+            vec![],
+        ),
+        PhysicalPlanType::Repartition(repartition) => (
+            "Repartition",
+            repartition.input.as_deref().into_iter().collect(),
+            vec![],
+        ),
+        PhysicalPlanType::ShuffleWriter(shuffle_writer) => (
+            "ShuffleWriter",
+            shuffle_writer.input.as_deref().into_iter().collect(),
+            shuffle_writer
+                .output_partitioning
+                .iter()
+                .flat_map(|partitioning| &partitioning.hash_expr)
+                .collect(),
+        ),
+        PhysicalPlanType::ParquetSink(parquet_sink) => (
+            "ParquetSink",
+            parquet_sink.input.as_deref().into_iter().collect(),
+            vec![],
+        ),
+        PhysicalPlanType::RenameColumns(rename_columns) => (
+            "RenameColumns",
+            rename_columns.input.as_deref().into_iter().collect(),
+            vec![],
+        ),
+        PhysicalPlanType::ReusedExchange(reused_exchange) => (
+            "ReusedExchange",
+            reused_exchange.input.as_deref().into_iter().collect(),
+            vec![],
+        ),
+    }
+}
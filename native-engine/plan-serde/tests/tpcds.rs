@@ -0,0 +1,333 @@
+// Copyright 2022 The Blaze Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Correctness harness executing native stages of TPC-DS queries against
+//! results recorded by vanilla spark.
+//!
+//! fixtures are task definitions dumped by spark with
+//! `spark.blaze.dumpTaskDefinitions.dir`, one directory per query:
+//!
+//! - `<query>/<task>.task`: a serialized TaskDefinition
+//! - `<query>/<task>.expected.arrow`: output rows of the task recorded by
+//!   vanilla spark, as an arrow IPC file. for map tasks these are the rows
+//!   written by the shuffle writer, which is not executed.
+//! - `<query>/<task>.input-<native shuffle id>.arrow`: rows read by the
+//!   shuffle reader of the task. tasks reading shuffles without recorded
+//!   inputs, or reading inputs from JVM, are skipped.
+//!
+//! scanned files are read from the paths recorded in the task definitions.
+//! rows are compared ignoring order, with floats rounded to 10 significant
+//! digits. run with:
+//!
+//! `BLAZE_TPCDS_FIXTURES=<dir> cargo test -p plan-serde --test tpcds`
+//!
+//! and optionally `BLAZE_TPCDS_QUERIES=q1,q72` to run some of the queries.
+
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use datafusion::arrow::array::{Array, ArrayRef, Float32Array, Float64Array};
+use datafusion::arrow::datatypes::DataType;
+use datafusion::arrow::ipc::reader::FileReader;
+use datafusion::arrow::record_batch::RecordBatch;
+use datafusion::arrow::util::display::array_value_to_string;
+use datafusion::error::{DataFusionError, Result};
+use datafusion::physical_plan::common::collect;
+use datafusion::physical_plan::memory::MemoryExec;
+use datafusion::physical_plan::ExecutionPlan;
+use datafusion::prelude::SessionContext;
+use datafusion_ext::jvm_to_native_exec::JvmToNativeExec;
+use datafusion_ext::native_conf::{native_conf_with_overrides, set_task_native_conf};
+use datafusion_ext::reused_exchange_exec::ReusedExchangeExec;
+use datafusion_ext::row_input_exec::RowInputExec;
+use datafusion_ext::shuffle_reader_exec::ShuffleReaderExec;
+use datafusion_ext::shuffle_writer_exec::ShuffleWriterExec;
+use datafusion_ext::split_oversized_batches_exec::{
+    split_oversized_input_batches, SplitOversizedBatchesExec,
+};
+use plan_serde::protobuf::TaskDefinition;
+use prost::Message;
+
+/// max number of mismatched rows printed for each task
+const MAX_PRINTED_DIFFS: usize = 10;
+
+enum Outcome {
+    Passed,
+    Skipped(String),
+    Failed(String),
+}
+
+#[test]
+fn test_tpcds_fixtures() -> Result<()> {
+    let fixtures_dir = match std::env::var("BLAZE_TPCDS_FIXTURES") {
+        Ok(dir) => PathBuf::from(dir),
+        Err(_) => {
+            eprintln!("BLAZE_TPCDS_FIXTURES is not set, skipping TPC-DS fixtures");
+            return Ok(());
+        }
+    };
+    let queries = std::env::var("BLAZE_TPCDS_QUERIES")
+        .map(|queries| queries.split(',').map(str::to_owned).collect::<Vec<_>>())
+        .unwrap_or_default();
+
+    let (mut passed, mut skipped, mut failures) = (0, 0, vec![]);
+    for query_dir in sorted_entries(&fixtures_dir)? {
+        let query = file_name(&query_dir);
+        if !query_dir.is_dir() || (!queries.is_empty() && !queries.contains(&query)) {
+            continue;
+        }
+        for task_path in sorted_entries(&query_dir)? {
+            let task = match file_name(&task_path).strip_suffix(".task") {
+                Some(task) => task.to_owned(),
+                None => continue,
+            };
+            match run_task(&query_dir, &task) {
+                Ok(Outcome::Passed) => passed += 1,
+                Ok(Outcome::Skipped(reason)) => {
+                    eprintln!("skipped {}/{}: {}", query, task, reason);
+                    skipped += 1;
+                }
+                Ok(Outcome::Failed(diff)) => {
+                    failures.push(format!("{}/{}: {}", query, task, diff));
+                }
+                Err(err) => {
+                    failures.push(format!("{}/{}: error: {}", query, task, err));
+                }
+            }
+        }
+    }
+
+    eprintln!(
+        "TPC-DS fixtures: {} passed, {} skipped, {} failed",
+        passed,
+        skipped,
+        failures.len()
+    );
+    assert!(
+        failures.is_empty(),
+        "mismatched tasks:\n{}",
+        failures.join("\n")
+    );
+    Ok(())
+}
+
+fn run_task(query_dir: &Path, task: &str) -> Result<Outcome> {
+    let expected_path = query_dir.join(format!("{}.expected.arrow", task));
+    if !expected_path.exists() {
+        return Ok(Outcome::Skipped("no recorded output".to_owned()));
+    }
+    let raw = std::fs::read(query_dir.join(format!("{}.task", task)))?;
+    let task_definition = TaskDefinition::decode(raw.as_slice()).map_err(|err| {
+        DataFusionError::Plan(format!("invalid task definition: {}", err))
+    })?;
+    let task_id = task_definition.task_id.as_ref().expect("task_id is empty");
+    let plan = task_definition.plan.as_ref().expect("plan is empty");
+    let partition_ids = if task_definition.partition_ids.is_empty() {
+        vec![task_id.partition_id]
+    } else {
+        task_definition.partition_ids.clone()
+    };
+
+    // tunables of the spark session are thread-local, batches are polled on
+    // this thread with a current-thread runtime
+    set_task_native_conf(if task_definition.conf.is_empty() {
+        None
+    } else {
+        Some(native_conf_with_overrides(task_definition.conf.clone())?)
+    });
+    let execution_plan: Arc<dyn ExecutionPlan> = plan
+        .try_into()
+        .map_err(|err| DataFusionError::Plan(format!("{}", err)))?;
+    let writer = execution_plan.as_any().downcast_ref::<ShuffleWriterExec>();
+    let execution_plan = match writer {
+        Some(writer) => writer.children()[0].clone(),
+        None => execution_plan,
+    };
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let task_ctx = SessionContext::new().task_ctx();
+    let mut actual = vec![];
+    for &partition_id in &partition_ids {
+        let partition_id = partition_id as usize;
+        let plan = execution_plan.clone();
+        let plan = match substitute_inputs(plan, query_dir, task, partition_id)? {
+            Ok(plan) => plan,
+            Err(reason) => return Ok(Outcome::Skipped(reason)),
+        };
+        let stream = plan.execute(partition_id, task_ctx.clone())?;
+        actual.extend(runtime.block_on(collect(stream))?);
+    }
+
+    let expected = read_arrow_file(&expected_path)?;
+    Ok(diff_rows(&format_rows(&expected)?, &format_rows(&actual)?))
+}
+
+/// replaces shuffle readers with their recorded inputs, returns the reason of
+/// skipping the task if inputs of the plan are not available
+fn substitute_inputs(
+    plan: Arc<dyn ExecutionPlan>,
+    query_dir: &Path,
+    task: &str,
+    partition_id: usize,
+) -> Result<std::result::Result<Arc<dyn ExecutionPlan>, String>> {
+    // splitting is transparent in the plan tree, readers are wrapped
+    if let Some(split) = plan.as_any().downcast_ref::<SplitOversizedBatchesExec>() {
+        if split.input().as_any().is::<ShuffleReaderExec>() {
+            let input = split.input().clone();
+            let input = substitute_inputs(input, query_dir, task, partition_id)?;
+            return Ok(input.map(split_oversized_input_batches));
+        }
+    }
+    if let Some(reader) = plan.as_any().downcast_ref::<ShuffleReaderExec>() {
+        let input_path =
+            query_dir.join(format!("{}.input-{}.arrow", task, reader.native_shuffle_id));
+        if !input_path.exists() {
+            return Ok(Err(format!(
+                "no recorded input of shuffle {}",
+                reader.native_shuffle_id
+            )));
+        }
+        let batches = read_arrow_file(&input_path)?
+            .into_iter()
+            .map(|batch| {
+                RecordBatch::try_new(reader.schema.clone(), batch.columns().to_vec())
+            })
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        let mut partitions = vec![vec![]; reader.num_partitions.max(partition_id + 1)];
+        partitions[partition_id] = batches;
+        let input = MemoryExec::try_new(&partitions, reader.schema.clone(), None)?;
+        return Ok(Ok(Arc::new(input)));
+    }
+
+    let any = plan.as_any();
+    if any.is::<JvmToNativeExec>()
+        || any.is::<RowInputExec>()
+        || any.is::<ReusedExchangeExec>()
+    {
+        return Ok(Err("reading inputs from JVM".to_owned()));
+    }
+    let mut children = vec![];
+    for child in plan.children() {
+        match substitute_inputs(child, query_dir, task, partition_id)? {
+            Ok(child) => children.push(child),
+            Err(reason) => return Ok(Err(reason)),
+        }
+    }
+    if children.is_empty() {
+        return Ok(Ok(plan));
+    }
+    Ok(Ok(plan.with_new_children(children)?))
+}
+
+fn read_arrow_file(path: &Path) -> Result<Vec<RecordBatch>> {
+    let reader = FileReader::try_new(File::open(path)?)?;
+    Ok(reader.collect::<std::result::Result<Vec<_>, _>>()?)
+}
+
+/// formats rows of the batches into sorted strings, columns are compared by
+/// position since native column names differ from spark's
+fn format_rows(batches: &[RecordBatch]) -> Result<Vec<String>> {
+    let mut rows = vec![];
+    for batch in batches {
+        for row in 0..batch.num_rows() {
+            let values = batch
+                .columns()
+                .iter()
+                .map(|column| format_value(column, row))
+                .collect::<Result<Vec<_>>>()?;
+            rows.push(values.join(" | "));
+        }
+    }
+    rows.sort_unstable();
+    Ok(rows)
+}
+
+fn format_value(column: &ArrayRef, row: usize) -> Result<String> {
+    if column.is_null(row) {
+        return Ok("NULL".to_owned());
+    }
+    Ok(match column.data_type() {
+        DataType::Float32 => {
+            let array = column.as_any().downcast_ref::<Float32Array>().unwrap();
+            format!("{:.9e}", array.value(row))
+        }
+        DataType::Float64 => {
+            let array = column.as_any().downcast_ref::<Float64Array>().unwrap();
+            format!("{:.9e}", array.value(row))
+        }
+        _ => array_value_to_string(column, row)?,
+    })
+}
+
+/// compares sorted rows as multisets
+fn diff_rows(expected: &[String], actual: &[String]) -> Outcome {
+    let (mut missing, mut unexpected) = (vec![], vec![]);
+    let (mut i, mut j) = (0, 0);
+    while i < expected.len() || j < actual.len() {
+        match (expected.get(i), actual.get(j)) {
+            (Some(e), Some(a)) if e == a => {
+                i += 1;
+                j += 1;
+            }
+            (Some(e), Some(a)) if e < a => {
+                missing.push(e);
+                i += 1;
+            }
+            (Some(e), None) => {
+                missing.push(e);
+                i += 1;
+            }
+            (_, Some(a)) => {
+                unexpected.push(a);
+                j += 1;
+            }
+            (None, None) => unreachable!(),
+        }
+    }
+    if missing.is_empty() && unexpected.is_empty() {
+        return Outcome::Passed;
+    }
+
+    let mut diff = format!(
+        "{} expected rows, {} actual rows, {} missing, {} unexpected",
+        expected.len(),
+        actual.len(),
+        missing.len(),
+        unexpected.len()
+    );
+    for row in missing.iter().take(MAX_PRINTED_DIFFS) {
+        diff.push_str(&format!("\n  - {}", row));
+    }
+    for row in unexpected.iter().take(MAX_PRINTED_DIFFS) {
+        diff.push_str(&format!("\n  + {}", row));
+    }
+    Outcome::Failed(diff)
+}
+
+fn sorted_entries(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut entries = std::fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort();
+    Ok(entries)
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
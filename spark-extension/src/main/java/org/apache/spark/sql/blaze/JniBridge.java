@@ -16,25 +16,183 @@
 
 package org.apache.spark.sql.blaze;
 
+import java.io.EOFException;
 import java.io.IOException;
 import java.nio.ByteBuffer;
+import java.nio.ByteOrder;
 import java.nio.channels.Channels;
 import java.nio.channels.ReadableByteChannel;
+import java.nio.channels.SeekableByteChannel;
+import java.nio.channels.WritableByteChannel;
+import java.nio.charset.StandardCharsets;
+import java.util.ArrayList;
+import java.util.List;
+import java.util.Map;
 import java.util.concurrent.ConcurrentHashMap;
+import java.util.concurrent.ExecutionException;
+import java.util.concurrent.ExecutorService;
+import java.util.concurrent.Future;
 import org.apache.hadoop.fs.FSDataInputStream;
+import org.apache.hadoop.fs.FSDataOutputStream;
 import org.apache.hadoop.fs.FileSystem;
+import org.apache.hadoop.fs.Path;
+import org.apache.spark.SparkEnv;
 import org.apache.spark.TaskContext;
 import org.apache.spark.TaskContext$;
+import org.apache.spark.TaskKilledException;
 import org.apache.spark.deploy.SparkHadoopUtil;
+import org.apache.spark.sql.blaze.execution.ShuffleSegmentIterator;
+import org.apache.spark.util.ThreadUtils;
+import org.blaze.FileSegmentSeekableByteChannel;
+import org.slf4j.Logger;
+import org.slf4j.LoggerFactory;
+import org.slf4j.MDC;
+import scala.collection.Iterator;
 
 public class JniBridge {
   public static final ConcurrentHashMap<String, Object> resourcesMap = new ConcurrentHashMap<>();
 
+  /**
+   * @return version of the plan protocol of the native library, task definitions must be
+   *     serialized with the same version
+   */
+  public static native int getNativeVersion();
+
+  /** @return bitmap of the optional features supported, see NativeFeature in plan.proto */
+  public static native long supportedFeatures();
+
+  /**
+   * initializes the native environment. if profilingEnabled, operator times and a pprof
+   * flamegraph of each native execution are dumped into the first of tmpDirs.
+   */
   public static native void initNative(
-      long batchSize, long nativeMemory, double memoryFraction, String tmpDirs);
+      long batchSize,
+      long nativeMemory,
+      double memoryFraction,
+      String tmpDirs,
+      boolean profilingEnabled);
 
   public static native void callNative(BlazeCallNativeWrapper wrapper);
 
+  /**
+   * opens a native iterator over the output of the task definition, polled with nextBatch() in
+   * the calling thread without the exchanger protocol of callNative(). only a single partition
+   * is executed.
+   *
+   * @return pointer of the iterator, must be released with closeBatchIterator()
+   */
+  public static native long openBatchIterator(byte[] taskDefinition);
+
+  /**
+   * exports the next output batch of the iterator into the given arrow C data structs
+   *
+   * @return row count of the batch, which is always positive, or -1 on the end of output
+   */
+  public static native int nextBatch(long iterPtr, long arrayPtr, long schemaPtr);
+
+  /**
+   * releases the iterator, updating the metrics with those of the native plan. spill directories
+   * of the task are removed if no other native execution of the task uses them
+   */
+  public static native void closeBatchIterator(long iterPtr, MetricNode metrics);
+
+  /**
+   * opens a native batch iterator of the task definition like openBatchIterator(), exported as
+   * an ArrowArrayStream into the released stream struct at streamPtr. the iterator is closed
+   * and the metrics updated once the stream is exhausted or released
+   */
+  public static native void exportBatchStream(
+      byte[] rawTaskDefinition, long streamPtr, MetricNode metrics);
+
+  /**
+   * cancels running native executions of the task, which stop before producing their next
+   * batches, and deletes spill files of the task. called once the task is interrupted or
+   * completed
+   */
+  public static native void cancelNative(long taskAttemptId);
+
+  /**
+   * adds values of counters registered by native operators of the task to the accumulators of
+   * the same names registered with NativeAccumulators, see updateNativeAccumulator(). other
+   * native states of the task, e.g. random generators of rand(), are released as well.
+   */
+  public static native void flushNativeCounters(long taskAttemptId);
+
+  /**
+   * publishes the local shuffle output written by the native shuffle writer of the task attempt
+   * into its attempt-scoped temp files. if another attempt of the same map task has already
+   * committed, its output is kept and the temp files of this attempt are deleted
+   *
+   * @return length of each output partition of the committed output
+   */
+  public static native long[] commitShuffleOutput(
+      String dataFile, String indexFile, long taskAttemptId);
+
+  /** deletes the temp files of local shuffle output written by the failed task attempt */
+  public static native void abortShuffleOutput(
+      String dataFile, String indexFile, long taskAttemptId);
+
+  /**
+   * atomically updates native tunables for subsequently launched tasks, supported keys are:
+   * batch_size, shuffle_compression_level, shuffle_codec (zstd, lz4 or adaptive),
+   * shuffle_zstd_dictionary_bytes (0 to disable dictionaries trained on small segments),
+   * shuffle_zstd_dictionary_samples (small segments sampled for training), shuffle_checksum,
+   * shuffle_fetch_batch_size, shuffle_fetch_batch_bytes, shuffle_local_mmap (memory-maps
+   * segments of local shuffle files natively), shuffle_sort_based_min_partitions (shuffles with
+   * at least this many output partitions sort rows by partition id), spill_mmap, plan_cache,
+   * scan_prefetch_batches, scan_prefetch_bytes, output_prefetch_bytes, log_level,
+   * max_output_batch_bytes (joins and sort aggregations build output batches of about this
+   * size), max_input_batch_bytes (batches of scans and shuffle reads are split to about this
+   * size), smj_max_buffered_group_bytes, shared_dictionary_columns (e.g.
+   * country,status, shuffled with executor-scoped dictionaries), shared_dictionary_max_values,
+   * utf8_validation (error, replace or trust), resource_wait_timeout_ms (max time waiting for
+   * resources not yet registered with putResource, 0 to fail immediately),
+   * resource_retry_backoff_ms, buffer_pool_fraction (max bytes of freed arrow buffers pooled for
+   * reuse, as a fraction of native memory, 0 to disable), intra_task_parallelism (threads
+   * executing sorts, aggregates and hash joins of a single task, 1 to disable),
+   * task_worker_threads (min threads of the runtime executing a task, named after its stage and
+   * partition, e.g. blaze-stage12-p3), spill_sweep_interval_secs (interval of removing spill
+   * directories left by tasks without live spill files, 0 to disable) and fault_injection (e.g.
+   * shuffle_read:corrupt:0.1, for resilience testing only). initial values can be set with spark
+   * confs prefixed by spark.blaze.native. the same keys set in a spark session override these
+   * tunables for tasks of the session only.
+   *
+   * @throws RuntimeException if any of the entries is invalid, in which case nothing is updated
+   */
+  public static native void updateConfig(Map<String, String> conf);
+
+  /** deletes spill files and the per-task spill directories left by the task */
+  public static native void releaseTaskSpills(long taskAttemptId);
+
+  /**
+   * converts the plan of a serialized TaskDefinition without executing it
+   *
+   * @return serialized PlanValidationResult listing all unsupported operators and expressions
+   */
+  public static native byte[] validateTaskDefinition(byte[] taskDefinition);
+
+  /**
+   * takes the explain of the latest native plan executed for the partition, annotated with
+   * runtime metrics of each native operator
+   *
+   * @return null if not requested by BlazeCallNativeWrapper.isNativeExplainRequested()
+   */
+  public static native String takeNativeExplain(int stageId, int partitionId);
+
+  /**
+   * reports native memory of the executor, for diagnosing leaks and overcommits
+   *
+   * @return single-line JSON of living memory reservations of each task, stats of the native
+   *     buffer pool, and batch iterators opened but not yet closed
+   */
+  public static native String dumpNativeMemory();
+
+  /**
+   * renders a serialized PlanConversionReport as single-line JSON, with fallbacks of the most
+   * operators first
+   */
+  public static native String renderPlanConversionReport(byte[] report);
+
   public static ClassLoader getContextClassLoader() {
     return Thread.currentThread().getContextClassLoader();
   }
@@ -59,6 +217,31 @@ public class JniBridge {
     TaskContext$.MODULE$.setTaskContext(tc);
   }
 
+  /** @return attempt id of the current task, or -1 if there is no task context */
+  public static long getTaskAttemptId() {
+    TaskContext tc = getTaskContext();
+    return tc != null ? tc.taskAttemptId() : -1L;
+  }
+
+  /** called by flushNativeCounters() in the thread of the completing task */
+  public static void updateNativeAccumulator(String name, long value) {
+    NativeAccumulators.update(name, value);
+  }
+
+  /**
+   * creates a memory consumer reserving native memory from the current task
+   *
+   * @return null if there is no task context or spark memory integration is disabled
+   */
+  public static BlazeMemoryConsumer newMemoryConsumer() {
+    TaskContext tc = getTaskContext();
+    if (tc == null
+        || !SparkEnv.get().conf().getBoolean("spark.blaze.memory.sparkIntegration.enabled", true)) {
+      return null;
+    }
+    return new BlazeMemoryConsumer(tc.taskMemoryManager());
+  }
+
   /**
    * shim method to FSDataInputStream.read()
    *
@@ -80,4 +263,206 @@ public class JniBridge {
       return bytesRead;
     }
   }
+
+  /**
+   * creates a file of its own file system for native writers, failing if the file exists
+   *
+   * @throws IOException
+   */
+  public static FSDataOutputStream createFSDataOutputStream(String path) throws IOException {
+    Path hadoopPath = new Path(path);
+    FileSystem fs = hadoopPath.getFileSystem(SparkHadoopUtil.get().conf());
+    return fs.create(hadoopPath, false);
+  }
+
+  /**
+   * shim method to FSDataOutputStream.write(), writes all remaining bytes of the buffer
+   *
+   * @throws IOException
+   */
+  public static void writeFSDataOutputStream(FSDataOutputStream out, ByteBuffer bb)
+      throws IOException {
+    WritableByteChannel channel = Channels.newChannel(out);
+    while (bb.hasRemaining()) {
+      channel.write(bb);
+    }
+  }
+
+  // kinds of segment records returned to the native shuffle reader. data records hold the
+  // compressed data of the segment, local file records hold the 8-byte little-endian offset and
+  // length of the segment followed by the utf-8 path of the local shuffle file, which is
+  // memory-mapped by native side
+  public static final byte SEGMENT_RECORD_DATA = 0;
+  public static final byte SEGMENT_RECORD_LOCAL_FILE = 1;
+
+  /**
+   * pulls up to maxSegments segment channels from the iterator, stopping early once their total
+   * size reaches maxBytes, and reads them concurrently. called by the native shuffle reader to
+   * fetch a batch of segments in a single JNI call.
+   *
+   * @param localFiles whether segments of local shuffle files are returned as local file records
+   * @return records of the fetched segments, empty if the iterator is exhausted
+   * @throws BlazeShuffleReadException if any of the segment channels cannot be read
+   */
+  public static byte[][] fetchSegments(
+      Iterator<SeekableByteChannel> segments, int maxSegments, long maxBytes, boolean localFiles)
+      throws IOException, InterruptedException {
+    List<SeekableByteChannel> channels = new ArrayList<>();
+    long totalBytes = 0;
+    while (channels.size() < maxSegments && totalBytes < maxBytes && segments.hasNext()) {
+      SeekableByteChannel channel = segments.next();
+      channels.add(channel);
+      totalBytes += channel.size();
+    }
+    long firstOrdinal =
+        segments instanceof ShuffleSegmentIterator
+            ? ((ShuffleSegmentIterator<?>) segments).numTaken() - channels.size()
+            : -1L;
+
+    byte[][] fetched = new byte[channels.size()][];
+    if (channels.size() == 1) {
+      try {
+        fetched[0] = readSegmentRecord(channels.get(0), localFiles);
+      } catch (IOException e) {
+        throw new BlazeShuffleReadException(
+            "error fetching shuffle segment", segments, firstOrdinal, e);
+      }
+      return fetched;
+    }
+    List<Future<byte[]>> futures = new ArrayList<>();
+    for (SeekableByteChannel channel : channels) {
+      futures.add(SegmentFetchPool.pool.submit(() -> readSegmentRecord(channel, localFiles)));
+    }
+    int i = 0;
+    try {
+      for (; i < futures.size(); i++) {
+        fetched[i] = futures.get(i).get();
+      }
+    } catch (ExecutionException e) {
+      long ordinal = firstOrdinal >= 0 ? firstOrdinal + i : -1L;
+      throw new BlazeShuffleReadException(
+          "error fetching shuffle segment", segments, ordinal, e.getCause());
+    } finally {
+      futures.forEach(future -> future.cancel(true));
+    }
+    return fetched;
+  }
+
+  /**
+   * reads the record of the next segment of the iterator fully into the direct buffer of native
+   * side, so that the native shuffle reader crosses JNI once per segment. records of segments
+   * tagged with upstream partition ids are prefixed by the 8-byte little-endian partition id.
+   *
+   * @param localFiles whether segments of local shuffle files are returned as local file records
+   * @return length of the record written into the buffer, -1 if the iterator is exhausted, or -2
+   *     minus the required capacity if the buffer is too small, in which case the segment is kept
+   *     and read again by the next call with a larger buffer
+   * @throws BlazeShuffleReadException if the segment channel cannot be read
+   */
+  public static long nextSegmentFully(Iterator<?> segments, ByteBuffer buffer, boolean localFiles) {
+    return ((ShuffleSegmentIterator<?>) segments).nextSegmentFully(buffer, localFiles);
+  }
+
+  private static byte[] readSegmentRecord(SeekableByteChannel channel, boolean localFiles)
+      throws IOException {
+    if (localFiles && channel instanceof FileSegmentSeekableByteChannel) {
+      return localFileSegmentRecord((FileSegmentSeekableByteChannel) channel);
+    }
+    ByteBuffer buf = ByteBuffer.allocate(1 + (int) channel.size());
+    buf.put(SEGMENT_RECORD_DATA);
+    while (buf.hasRemaining()) {
+      if (channel.read(buf) < 0) {
+        throw new EOFException("unexpected EOF reading shuffle segment");
+      }
+    }
+    return buf.array();
+  }
+
+  public static byte[] localFileSegmentRecord(FileSegmentSeekableByteChannel channel) {
+    byte[] path = channel.getFile().getAbsolutePath().getBytes(StandardCharsets.UTF_8);
+    return ByteBuffer.allocate(17 + path.length)
+        .order(ByteOrder.LITTLE_ENDIAN)
+        .put(SEGMENT_RECORD_LOCAL_FILE)
+        .putLong(channel.getOffset())
+        .putLong(channel.getLength())
+        .put(path)
+        .array();
+  }
+
+  /** lazily created, so that spark confs are available */
+  private static class SegmentFetchPool {
+    static final ExecutorService pool =
+        ThreadUtils.newDaemonCachedThreadPool(
+            "blaze-segment-fetcher",
+            SparkEnv.get().conf().getInt("spark.blaze.shuffle.fetchThreads", 8),
+            60);
+  }
+
+  private static final ConcurrentHashMap<String, Logger> nativeLoggers =
+      new ConcurrentHashMap<>();
+
+  /**
+   * creates the exception failing a native execution from the kind of the typed native error,
+   * cancelled executions are reported as killed tasks
+   *
+   * @param kind user, arrow, jni, oom or cancelled
+   */
+  public static Throwable newNativeException(String kind, String message) {
+    switch (kind) {
+      case "user":
+        return new BlazeNativeUserException(message);
+      case "arrow":
+        return new BlazeNativeArrowException(message);
+      case "jni":
+        return new BlazeNativeJniException(message);
+      case "oom":
+        return new BlazeNativeOutOfMemoryException(message);
+      case "cancelled":
+        return new TaskKilledException(message);
+      default:
+        return new RuntimeException(message);
+    }
+  }
+
+  /**
+   * logs a native record with the task context of the calling thread in MDC (taskId, stageId
+   * and partitionId), so that native logs interleave with JVM logs of the same task
+   *
+   * @param level 1=error, 2=warn, 3=info, 4=debug, 5=trace
+   * @param target module path of the native record, used as suffix of the logger name
+   */
+  public static void nativeLog(int level, String target, String message) {
+    Logger logger =
+        nativeLoggers.computeIfAbsent(target, t -> LoggerFactory.getLogger("blaze.native." + t));
+    TaskContext tc = getTaskContext();
+    if (tc != null) {
+      MDC.put("taskId", String.valueOf(tc.taskAttemptId()));
+      MDC.put("stageId", String.valueOf(tc.stageId()));
+      MDC.put("partitionId", String.valueOf(tc.partitionId()));
+    }
+    try {
+      switch (level) {
+        case 1:
+          logger.error(message);
+          break;
+        case 2:
+          logger.warn(message);
+          break;
+        case 3:
+          logger.info(message);
+          break;
+        case 4:
+          logger.debug(message);
+          break;
+        default:
+          logger.trace(message);
+      }
+    } finally {
+      if (tc != null) {
+        MDC.remove("taskId");
+        MDC.remove("stageId");
+        MDC.remove("partitionId");
+      }
+    }
+  }
 }
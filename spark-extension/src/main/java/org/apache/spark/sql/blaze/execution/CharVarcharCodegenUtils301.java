@@ -0,0 +1,66 @@
+/*
+ * Copyright 2022 The Blaze Authors
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+package org.apache.spark.sql.blaze.execution;
+
+import org.apache.spark.unsafe.types.UTF8String;
+
+/**
+ * Ported from CharVarcharCodegenUtils of spark 3.1+, which is not available in spark 3.0.
+ * invoked with StaticInvoke by expressions built in CharVarcharUtils301, which are converted to
+ * native char/varchar expressions.
+ */
+public class CharVarcharCodegenUtils301 {
+  private static final UTF8String SPACE = UTF8String.fromString(" ");
+
+  /** trims trailing spaces beyond the limit, UTF8String.trimTrailingSpaces is not in spark 3.0 */
+  private static UTF8String trimTrailingSpaces(UTF8String inputStr, int numChars, int limit) {
+    int numTailSpaces = numChars - inputStr.trimRight().numChars();
+    if (numTailSpaces < numChars - limit) {
+      throw new RuntimeException("Exceeds char/varchar type length limitation: " + limit);
+    }
+    return inputStr.substring(0, limit);
+  }
+
+  public static UTF8String charTypeWriteSideCheck(UTF8String inputStr, int limit) {
+    int numChars = inputStr.numChars();
+    if (numChars == limit) {
+      return inputStr;
+    } else if (numChars < limit) {
+      return inputStr.rpad(limit, SPACE);
+    } else {
+      return trimTrailingSpaces(inputStr, numChars, limit);
+    }
+  }
+
+  public static UTF8String varcharTypeWriteSideCheck(UTF8String inputStr, int limit) {
+    int numChars = inputStr.numChars();
+    if (numChars <= limit) {
+      return inputStr;
+    } else {
+      return trimTrailingSpaces(inputStr, numChars, limit);
+    }
+  }
+
+  public static UTF8String readSidePadding(UTF8String inputStr, int limit) {
+    int numChars = inputStr.numChars();
+    if (numChars < limit) {
+      return inputStr.rpad(limit, SPACE);
+    } else {
+      return inputStr;
+    }
+  }
+}
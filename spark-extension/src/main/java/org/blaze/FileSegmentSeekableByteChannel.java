@@ -36,6 +36,18 @@ public class FileSegmentSeekableByteChannel implements SeekableByteChannel {
     this.length = length;
   }
 
+  public File getFile() {
+    return file;
+  }
+
+  public long getOffset() {
+    return offset;
+  }
+
+  public long getLength() {
+    return length;
+  }
+
   @Override
   public int read(ByteBuffer dst) throws IOException {
     FileChannel channel = null;
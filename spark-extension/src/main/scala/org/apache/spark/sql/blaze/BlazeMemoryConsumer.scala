@@ -0,0 +1,83 @@
+/*
+ * Copyright 2022 The Blaze Authors
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+package org.apache.spark.sql.blaze
+
+import java.util.concurrent.ConcurrentHashMap
+import java.util.concurrent.atomic.AtomicBoolean
+
+import org.apache.spark.SparkEnv
+import org.apache.spark.TaskContext
+import org.apache.spark.internal.Logging
+import org.apache.spark.memory.MemoryConsumer
+import org.apache.spark.memory.TaskMemoryManager
+
+/**
+ * Reserves memory of native operators from the task's TaskMemoryManager.
+ *
+ * NOTE: spilling native data is not possible on the thread calling spill(), since the native
+ * operator may be blocked in acquire() on another thread. spill requests are recorded instead
+ * and taken by native side on the next acquire().
+ */
+class BlazeMemoryConsumer(taskMemoryManager: TaskMemoryManager)
+    extends MemoryConsumer(
+      taskMemoryManager,
+      taskMemoryManager.pageSizeBytes(),
+      taskMemoryManager.getTungstenMemoryMode)
+    with Logging {
+
+  private val spillRequested = new AtomicBoolean(false)
+
+  /** @return bytes actually acquired, may be less than requested */
+  def acquire(bytes: Long): Long = acquireMemory(bytes)
+
+  def release(bytes: Long): Unit = freeMemory(bytes)
+
+  def takeSpillRequest(): Boolean = spillRequested.getAndSet(false)
+
+  /**
+   * called by native side when memory cannot be acquired even after spilling.
+   *
+   * @return true if native side should abort and let BlazeCallNativeWrapper retry the task
+   *         with a smaller batch size
+   */
+  def reportOvercommit(report: String): Boolean = {
+    logWarning(s"native memory overcommitted: $report")
+    val retryEnabled =
+      SparkEnv.get.conf.getBoolean("spark.blaze.memory.retryOnOvercommit.enabled", false)
+    val taskContext = TaskContext.get
+    if (retryEnabled && taskContext != null) {
+      BlazeMemoryConsumer.overcommitReports.put(taskContext.taskAttemptId(), report)
+      return true
+    }
+    false
+  }
+
+  override def spill(size: Long, trigger: MemoryConsumer): Long = {
+    if (trigger ne this) {
+      spillRequested.set(true)
+    }
+    0L
+  }
+}
+
+object BlazeMemoryConsumer {
+  private val overcommitReports = new ConcurrentHashMap[Long, String]()
+
+  /** @return the report if native execution of the task was aborted for retrying */
+  def takeOvercommitReport(taskAttemptId: Long): Option[String] =
+    Option(overcommitReports.remove(taskAttemptId))
+}
@@ -0,0 +1,44 @@
+/*
+ * Copyright 2022 The Blaze Authors
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+package org.apache.spark.sql.blaze
+
+/**
+ * Failure of native execution, created by JniBridge.newNativeException from the typed error of
+ * the native side. cancelled executions are reported as killed tasks instead, and java exceptions
+ * thrown by JVM callbacks are propagated as they are.
+ *
+ * @param retryable whether retrying the task may succeed, e.g. when native memory is exhausted.
+ *                  non-retryable failures are caused by the plan or the data and fail again
+ */
+sealed abstract class BlazeNativeException(message: String, val retryable: Boolean)
+    extends RuntimeException(message)
+
+/** failure caused by the plan or the data, e.g. unsupported expressions or malformed inputs */
+class BlazeNativeUserException(message: String)
+    extends BlazeNativeException(message, retryable = false)
+
+/** failure of arrow computations */
+class BlazeNativeArrowException(message: String)
+    extends BlazeNativeException(message, retryable = false)
+
+/** failure calling into the JVM without a pending java exception */
+class BlazeNativeJniException(message: String)
+    extends BlazeNativeException(message, retryable = true)
+
+/** native memory exhausted */
+class BlazeNativeOutOfMemoryException(message: String)
+    extends BlazeNativeException(message, retryable = true)
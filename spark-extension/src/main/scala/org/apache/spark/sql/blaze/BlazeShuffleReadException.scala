@@ -0,0 +1,54 @@
+/*
+ * Copyright 2022 The Blaze Authors
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+package org.apache.spark.sql.blaze
+
+import java.io.IOException
+
+import org.apache.spark.shuffle.FetchFailedException
+import org.apache.spark.sql.blaze.execution.ShuffleSegmentIterator
+
+/**
+ * Failure of a native shuffle read, e.g. reading segment channels or decoding corrupted
+ * segments. native side throws it with the iterator the failed segment is taken from, so that it
+ * can be converted into a fetch failure of the map output the segment belongs to, letting spark
+ * retry the map stage instead of failing the task repeatedly.
+ *
+ * @param segmentOrdinal ordinal of the failed segment taken from the iterator, -1 if unknown
+ */
+class BlazeShuffleReadException(
+    message: String,
+    @transient private val segments: Iterator[_],
+    val segmentOrdinal: Long,
+    cause: Throwable)
+    extends IOException(message, cause) {
+
+  /** @return the fetch failure of the failed segment, or this exception if it is unknown */
+  def toFetchFailure: Throwable = fetchFailure.getOrElse(this)
+
+  // resolved on creation and registered in the task context, so that the task fails by the
+  // fetch failure even if this exception is not the one surfaced from native execution
+  @transient private val fetchFailure: Option[FetchFailedException] = getCause match {
+    case e: FetchFailedException => Some(e)
+    case e: BlazeShuffleReadException if e.fetchFailure.isDefined => e.fetchFailure
+    case _ =>
+      segments match {
+        case segments: ShuffleSegmentIterator[_] if segmentOrdinal >= 0 =>
+          segments.fetchFailure(segmentOrdinal, message, this)
+        case _ => None
+      }
+  }
+}
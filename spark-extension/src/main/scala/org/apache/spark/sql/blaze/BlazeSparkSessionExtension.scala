@@ -25,7 +25,13 @@ import org.apache.spark.SparkEnv
 import org.apache.spark.internal.Logging
 import org.apache.spark.rdd.RDD
 import org.apache.spark.sql.blaze.execution.ArrowShuffleExchangeExec301
+import org.apache.spark.sql.blaze.execution.CharVarcharUtils301
+import org.apache.spark.sql.blaze.execution.NativeParquetInsertIntoHadoopFsRelationCommand301
+import org.apache.spark.sql.blaze.plan.NativeBroadcastNestedLoopJoinExec
+import org.apache.spark.sql.blaze.plan.NativeCartesianProductExec
+import org.apache.spark.sql.blaze.plan.NativeExistenceJoinExec
 import org.apache.spark.sql.blaze.plan.NativeFilterExec
+import org.apache.spark.sql.blaze.plan.NativeInMemoryTableScanExec
 import org.apache.spark.sql.blaze.plan.NativeParquetScanExec
 import org.apache.spark.sql.blaze.plan.NativeProjectExec
 import org.apache.spark.sql.blaze.plan.NativeSortExec
@@ -38,9 +44,16 @@ import org.apache.spark.sql.execution.ProjectExec
 import org.apache.spark.sql.execution.SortExec
 import org.apache.spark.sql.execution.SparkPlan
 import org.apache.spark.sql.execution.UnionExec
+import org.apache.spark.sql.execution.columnar.InMemoryTableScanExec
+import org.apache.spark.sql.execution.command.DataWritingCommandExec
+import org.apache.spark.sql.execution.datasources.InsertIntoHadoopFsRelationCommand
 import org.apache.spark.sql.execution.datasources.parquet.ParquetFileFormat
 import org.apache.spark.sql.execution.exchange.BroadcastExchangeExec
 import org.apache.spark.sql.execution.exchange.ShuffleExchangeExec
+import org.apache.spark.sql.execution.joins.BroadcastNestedLoopJoinExec
+import org.apache.spark.sql.execution.joins.BuildLeft
+import org.apache.spark.sql.execution.joins.BuildRight
+import org.apache.spark.sql.execution.joins.CartesianProductExec
 import org.apache.spark.sql.execution.joins.SortMergeJoinExec
 import org.apache.spark.sql.execution.window.WindowExec
 import org.apache.spark.sql.internal.SQLConf
@@ -55,6 +68,8 @@ import org.apache.spark.sql.catalyst.expressions.NamedExpression
 import org.apache.spark.sql.catalyst.InternalRow
 import org.apache.spark.sql.catalyst.expressions.Attribute
 import org.apache.spark.sql.catalyst.expressions.SortOrder
+import org.apache.spark.sql.catalyst.plans.ExistenceJoin
+import org.apache.spark.sql.catalyst.plans.LeftAnti
 import org.apache.spark.sql.catalyst.plans.logical.LogicalPlan
 import org.apache.spark.sql.catalyst.plans.physical.Partitioning
 import org.apache.spark.sql.catalyst.trees.TreeNodeTag
@@ -67,6 +82,7 @@ import org.apache.spark.sql.execution.adaptive.ShuffleQueryStageExec
 import org.apache.spark.sql.execution.exchange.ReusedExchangeExec
 import org.apache.spark.sql.execution.exchange.ShuffleExchangeLike
 import org.apache.spark.sql.vectorized.ColumnarBatch
+import org.blaze.protobuf.FallbackReason
 
 class BlazeSparkSessionExtension extends (SparkSessionExtensions => Unit) with Logging {
   override def apply(extensions: SparkSessionExtensions): Unit = {
@@ -95,7 +111,9 @@ case class BlazeQueryStagePrepOverrides(sparkSession: SparkSession)
         case exec: ShuffleExchangeExec if enableNativeShuffle =>
           tryConvert(exec, convertShuffleExchangeExec)
         case exec: FileSourceScanExec if enableScan => tryConvert(exec, convertFileSourceScanExec)
-        case exec => exec
+        case exec: InMemoryTableScanExec if enableInMemoryTableScan =>
+          tryConvert(exec, convertInMemoryTableScanExec)
+        case exec => markDisabledByConf(exec)
       }
 
     logDebug(s"Transformed spark plan after QueryStagePrep:\n${sparkPlanTransformed
@@ -109,7 +127,7 @@ case class BlazeColumnarOverrides(sparkSession: SparkSession) extends ColumnarRu
 
   override def preColumnarTransitions: Rule[SparkPlan] =
     sparkPlan => {
-      // mark all skewed SMJ sorters
+      // mark all skewed SMJ sorters if skew joins are not converted
       sparkPlan.foreachUp {
         case SortMergeJoinExec(
               _,
@@ -118,7 +136,7 @@ case class BlazeColumnarOverrides(sparkSession: SparkSession) extends ColumnarRu
               _,
               SortExec(_, _, sortChild1, _),
               SortExec(_, _, sortChild2, _),
-              true) =>
+              true) if !enableSkewJoin =>
           sortChild1.setTagValue(skewJoinSortChildrenTag, true)
           sortChild2.setTagValue(skewJoinSortChildrenTag, true)
         case _ =>
@@ -137,13 +155,20 @@ case class BlazeColumnarOverrides(sparkSession: SparkSession) extends ColumnarRu
             tryConvert(exec, convertUnionExec)
           case exec: SortMergeJoinExec if enableSmj =>
             tryConvert(exec, convertSortMergeJoinExec)
-          case exec => exec
+          case exec: BroadcastNestedLoopJoinExec if enableBnlj =>
+            tryConvert(exec, convertBroadcastNestedLoopJoinExec)
+          case exec: CartesianProductExec if enableCartesian =>
+            tryConvert(exec, convertCartesianProductExec)
+          case exec: DataWritingCommandExec if enableParquetSink =>
+            tryConvert(exec, convertDataWritingCommandExec(sparkSession, _))
+          case exec => markDisabledByConf(exec)
         }
         .transformUp {
           // add ConvertToUnsafeRow before specified plans those require consuming unsafe rows
           case exec @ (
                 _: SortExec | _: CollectLimitExec | _: BroadcastExchangeExec |
-                _: SortMergeJoinExec | _: WindowExec
+                _: SortMergeJoinExec | _: WindowExec | _: BroadcastNestedLoopJoinExec |
+                _: CartesianProductExec
               ) =>
             exec.mapChildren(child => convertToUnsafeRow(child))
         }
@@ -159,6 +184,7 @@ case class BlazeColumnarOverrides(sparkSession: SparkSession) extends ColumnarRu
 
       logDebug(s"Transformed spark plan after postColumnarTransitions:\n${sparkPlanTransformed
         .treeString(verbose = true, addSuffix = true, printOperatorId = true)}")
+      PlanConversionTelemetry.recordConvertedPlan(sparkSession, sparkPlanTransformed)
       sparkPlanTransformed
     }
 }
@@ -172,6 +198,8 @@ private object Util extends Logging {
     SparkEnv.get.conf.getBoolean(ENABLE_OPERATION + "shuffle", defaultValue = true)
   val enableScan: Boolean =
     SparkEnv.get.conf.getBoolean(ENABLE_OPERATION + "scan", defaultValue = true)
+  val enableInMemoryTableScan: Boolean =
+    SparkEnv.get.conf.getBoolean(ENABLE_OPERATION + "inmemorytablescan", defaultValue = true)
   val enableProject: Boolean =
     SparkEnv.get.conf.getBoolean(ENABLE_OPERATION + "project", defaultValue = true)
   val enableFilter: Boolean =
@@ -182,19 +210,63 @@ private object Util extends Logging {
     SparkEnv.get.conf.getBoolean(ENABLE_OPERATION + "union", defaultValue = true)
   val enableSmj: Boolean =
     SparkEnv.get.conf.getBoolean(ENABLE_OPERATION + "sortmergejoin", defaultValue = true)
+  // splits of skewed partitions are read natively by mapper ranges
+  val enableSkewJoin: Boolean =
+    SparkEnv.get.conf.getBoolean(ENABLE_OPERATION + "skewjoin", defaultValue = true)
+  val enableBnlj: Boolean =
+    SparkEnv.get.conf.getBoolean(
+      ENABLE_OPERATION + "broadcastnestedloopjoin",
+      defaultValue = true)
+  val enableCartesian: Boolean =
+    SparkEnv.get.conf.getBoolean(ENABLE_OPERATION + "cartesianproduct", defaultValue = true)
+  val enableParquetSink: Boolean =
+    SparkEnv.get.conf.getBoolean(ENABLE_OPERATION + "parquetsink", defaultValue = false)
+
+  // non-native children of sorts and unions are bridged with ConvertToNativeExec, so that these
+  // operators and the supported plans above them still run natively
+  val enableJvmChildBridge: Boolean =
+    SparkEnv.get.conf.getBoolean(ENABLE_OPERATION + "jvmchildbridge", defaultValue = true)
 
   val skewJoinSortChildrenTag: TreeNodeTag[Boolean] = TreeNodeTag("skewJoinSortChildren")
 
   def tryConvert[T <: SparkPlan](exec: T, convert: T => SparkPlan): SparkPlan =
     try {
       val convertedExec = convert(exec)
+      if (convertedExec eq exec) {
+        PlanConversionTelemetry.markFallback(exec, FallbackReason.CHILD_NOT_NATIVE, "")
+      }
       convertedExec
     } catch {
       case e @ (_: NotImplementedError | _: Exception) =>
         logWarning(s"Error converting exec: ${exec.getClass.getSimpleName}: ${e.getMessage}")
+        PlanConversionTelemetry.markFallback(
+          exec,
+          PlanConversionTelemetry.classifyError(e),
+          e.getMessage)
         exec
     }
 
+  def markDisabledByConf(exec: SparkPlan): SparkPlan = {
+    val disabled = exec match {
+      case _: ShuffleExchangeExec => !enableNativeShuffle
+      case _: FileSourceScanExec => !enableScan
+      case _: InMemoryTableScanExec => !enableInMemoryTableScan
+      case _: ProjectExec => !enableProject
+      case _: FilterExec => !enableFilter
+      case _: SortExec => !enableSort
+      case _: UnionExec => !enableUnion
+      case _: SortMergeJoinExec => !enableSmj
+      case _: BroadcastNestedLoopJoinExec => !enableBnlj
+      case _: CartesianProductExec => !enableCartesian
+      case _: DataWritingCommandExec => !enableParquetSink
+      case _ => false
+    }
+    if (disabled) {
+      PlanConversionTelemetry.markFallback(exec, FallbackReason.DISABLED_BY_CONF, "")
+    }
+    exec
+  }
+
   def convertShuffleExchangeExec(exec: ShuffleExchangeExec): SparkPlan = {
     val ShuffleExchangeExec(outputPartitioning, child, noUserSpecifiedNumPartition) = exec
     logDebug(s"Converting ShuffleExchangeExec: ${exec.simpleStringWithNodeId}")
@@ -223,11 +295,22 @@ private object Util extends Logging {
     logDebug(s"  dataFilters: ${dataFilters}")
     logDebug(s"  tableIdentifier: ${tableIdentifier}")
     if (relation.fileFormat.isInstanceOf[ParquetFileFormat]) {
-      return NativeParquetScanExec(exec)
+      return CharVarcharUtils301.addPaddingForScan(NativeParquetScanExec(exec))
     }
+    PlanConversionTelemetry.markFallback(
+      exec,
+      FallbackReason.UNSUPPORTED_FEATURE,
+      s"unsupported file format: ${relation.fileFormat}")
     exec
   }
 
+  def convertInMemoryTableScanExec(exec: InMemoryTableScanExec): SparkPlan = {
+    logDebug(s"Converting InMemoryTableScanExec: ${exec.simpleStringWithNodeId}")
+    logDebug(s"  attributes: ${exec.attributes}")
+    logDebug(s"  predicates: ${exec.predicates}")
+    NativeInMemoryTableScanExec(exec)
+  }
+
   def convertProjectExec(exec: ProjectExec): SparkPlan =
     exec match {
       case ProjectExec(projectList, child) if NativeSupports.isNative(child) =>
@@ -251,14 +334,20 @@ private object Util extends Logging {
 
   def convertSortExec(exec: SortExec): SparkPlan = {
     if (exec.child.getTagValue(skewJoinSortChildrenTag).isDefined) {
-      return exec // do not convert skewed join SMJ sorters
+      // do not convert skewed join SMJ sorters
+      PlanConversionTelemetry.markFallback(
+        exec,
+        FallbackReason.UNSUPPORTED_FEATURE,
+        "sorter of skewed join")
+      return exec
     }
     exec match {
-      case SortExec(sortOrder, global, child, _) if NativeSupports.isNative(child) =>
+      case SortExec(sortOrder, global, child, _)
+          if NativeSupports.isNative(child) || enableJvmChildBridge =>
         logDebug(s"Converting SortExec: ${exec.simpleStringWithNodeId()}")
         logDebug(s"  global: ${global}")
         exec.sortOrder.foreach(s => logDebug(s"  sortOrder: ${s}"))
-        NativeSortExec(sortOrder, global, addRenameColumnsExec(child))
+        NativeSortExec(sortOrder, global, addRenameColumnsExec(bridgeJvmChild(child)))
       case _ =>
         logDebug(s"Ignoring SortExec: ${exec.simpleStringWithNodeId()}")
         exec
@@ -267,10 +356,12 @@ private object Util extends Logging {
 
   def convertUnionExec(exec: UnionExec): SparkPlan =
     exec match {
-      case UnionExec(children) if children.forall(c => NativeSupports.isNative(c)) =>
+      case UnionExec(children)
+          if children.forall(c => NativeSupports.isNative(c)) ||
+            (enableJvmChildBridge && children.exists(c => NativeSupports.isNative(c))) =>
         logDebug(s"Converting UnionExec: ${exec.simpleStringWithNodeId()}")
         NativeUnionExec(children.map(child => {
-          addRenameColumnsExec(child)
+          addRenameColumnsExec(bridgeJvmChild(child))
         }))
       case _ =>
         logDebug(s"Ignoring UnionExec: ${exec.simpleStringWithNodeId()}")
@@ -278,10 +369,34 @@ private object Util extends Logging {
     }
 
   def convertSortMergeJoinExec(exec: SortMergeJoinExec): SparkPlan = {
-    if (exec.isSkewJoin) {
-      throw new NotImplementedError("skew join is not yet supported")
+    if (exec.isSkewJoin && !enableSkewJoin) {
+      throw new NotImplementedError("skew join is not enabled")
     }
     exec match {
+      case SortMergeJoinExec(leftKeys, rightKeys, ExistenceJoin(exists), None, left, right, _)
+          if Seq(left, right).exists(NativeSupports.isNative) =>
+        logDebug(s"Converting existence SortMergeJoinExec: ${exec.simpleStringWithNodeId()}")
+        // both sides are co-partitioned by keys, which are joined by hashing
+        val (normalizedLeftKeys, normalizedRightKeys, keyNormalizations) =
+          NativeSortMergeJoinExec.normalizeJoinKeys(leftKeys, rightKeys)
+        val nativeLeft = left match {
+          case l if !NativeSupports.isNative(l) => ConvertToNativeExec(l)
+          case l => l
+        }
+        val nativeRight = right match {
+          case r if !NativeSupports.isNative(r) => ConvertToNativeExec(r)
+          case r => r
+        }
+        return NativeExistenceJoinExec(
+          addRenameColumnsExec(nativeLeft),
+          addRenameColumnsExec(nativeRight),
+          normalizedLeftKeys,
+          normalizedRightKeys,
+          Some(exists),
+          rightBroadcast = false,
+          keyNormalizations,
+          exec.output)
+
       case SortMergeJoinExec(leftKeys, rightKeys, joinType, condition, left, right, _) =>
         if (Seq(left, right).exists(NativeSupports.isNative)) {
           logDebug(s"Converting SortMergeJoinExec: ${exec.simpleStringWithNodeId()}")
@@ -331,18 +446,21 @@ private object Util extends Logging {
             case r if !NativeSupports.isNative(r) => ConvertToNativeExec(r)
             case r => r
           }
-          var modifiedLeftKeys = leftKeys
-          var modifiedRightKeys = rightKeys
+          // implicit casts of keys are replaced by native key normalizations
+          val (normalizedLeftKeys, normalizedRightKeys, keyNormalizations) =
+            NativeSortMergeJoinExec.normalizeJoinKeys(leftKeys, rightKeys)
+          var modifiedLeftKeys = normalizedLeftKeys
+          var modifiedRightKeys = normalizedRightKeys
           var needPostProject = false
 
-          if (leftKeys.exists(!_.isInstanceOf[AttributeReference])) {
-            val (keys, exec) = buildJoinColumnsProject(nativeLeft, leftKeys)
+          if (normalizedLeftKeys.exists(!_.isInstanceOf[AttributeReference])) {
+            val (keys, exec) = buildJoinColumnsProject(nativeLeft, normalizedLeftKeys)
             modifiedLeftKeys = keys
             nativeLeft = exec
             needPostProject = true
           }
-          if (rightKeys.exists(!_.isInstanceOf[AttributeReference])) {
-            val (keys, exec) = buildJoinColumnsProject(nativeRight, rightKeys)
+          if (normalizedRightKeys.exists(!_.isInstanceOf[AttributeReference])) {
+            val (keys, exec) = buildJoinColumnsProject(nativeRight, normalizedRightKeys)
             modifiedRightKeys = keys
             nativeRight = exec
             needPostProject = true
@@ -356,7 +474,8 @@ private object Util extends Logging {
             exec.output,
             exec.outputPartitioning,
             exec.outputOrdering,
-            joinType)
+            joinType,
+            keyNormalizations)
 
           val postProjectedSmj = if (needPostProject) {
             buildPostProject(smj)
@@ -380,6 +499,115 @@ private object Util extends Logging {
     exec
   }
 
+  def convertBroadcastNestedLoopJoinExec(exec: BroadcastNestedLoopJoinExec): SparkPlan =
+    exec match {
+      case BroadcastNestedLoopJoinExec(left, right, buildSide, joinType, condition) =>
+        val streamed = buildSide match {
+          case BuildLeft => right
+          case BuildRight => left
+        }
+        if (!NativeSupports.isNative(streamed)) {
+          logDebug(s"Ignoring BroadcastNestedLoopJoinExec: ${exec.simpleStringWithNodeId()}")
+          return exec
+        }
+
+        // single-column NOT IN subqueries are joined by keys instead of pairwise
+        val nullAwareAntiKeys = (joinType, buildSide, condition) match {
+          case (LeftAnti, BuildRight, Some(condition)) =>
+            NativeExistenceJoinExec
+              .extractNullAwareAntiKeys(condition, left, right)
+              .filter {
+                case (leftKey, rightKey) =>
+                  leftKey.isInstanceOf[AttributeReference] &&
+                    rightKey.isInstanceOf[AttributeReference] &&
+                    leftKey.dataType == rightKey.dataType
+              }
+          case _ => None
+        }
+        if (nullAwareAntiKeys.isDefined) {
+          val (leftKey, rightKey) = nullAwareAntiKeys.get
+          logDebug(s"Converting null-aware anti join: ${exec.simpleStringWithNodeId()}")
+          logDebug(s"  leftKey: ${leftKey}, rightKey: ${rightKey}")
+          return NativeExistenceJoinExec(
+            addRenameColumnsExec(left),
+            right,
+            Seq(leftKey),
+            Seq(rightKey),
+            existsAttr = None,
+            rightBroadcast = true,
+            keyNormalizations = Nil,
+            exec.output)
+        }
+        logDebug(s"Converting BroadcastNestedLoopJoinExec: ${exec.simpleStringWithNodeId()}")
+        logDebug(s"  buildSide: ${buildSide}")
+        logDebug(s"  joinType: ${joinType}")
+        logDebug(s"  condition: ${condition}")
+        // the broadcast side is read from the broadcast relation, not executed natively
+        NativeBroadcastNestedLoopJoinExec(
+          if (streamed eq left) addRenameColumnsExec(left) else left,
+          if (streamed eq right) addRenameColumnsExec(right) else right,
+          buildSide,
+          joinType,
+          condition,
+          exec.output)
+    }
+
+  def convertCartesianProductExec(exec: CartesianProductExec): SparkPlan =
+    exec match {
+      case CartesianProductExec(left, right, condition)
+          if Seq(left, right).exists(NativeSupports.isNative) =>
+        logDebug(s"Converting CartesianProductExec: ${exec.simpleStringWithNodeId()}")
+        logDebug(s"  condition: ${condition}")
+        val nativeLeft = left match {
+          case l if !NativeSupports.isNative(l) => ConvertToNativeExec(l)
+          case l => l
+        }
+        val nativeRight = right match {
+          case r if !NativeSupports.isNative(r) => ConvertToNativeExec(r)
+          case r => r
+        }
+        NativeCartesianProductExec(
+          addRenameColumnsExec(nativeLeft),
+          addRenameColumnsExec(nativeRight),
+          condition,
+          exec.output)
+      case _ =>
+        logDebug(s"Ignoring CartesianProductExec: ${exec.simpleStringWithNodeId()}")
+        exec
+    }
+
+  def convertDataWritingCommandExec(
+      sparkSession: SparkSession,
+      exec: DataWritingCommandExec): SparkPlan =
+    exec match {
+      case DataWritingCommandExec(cmd: InsertIntoHadoopFsRelationCommand, child)
+          if cmd.fileFormat.isInstanceOf[ParquetFileFormat] =>
+        logDebug(s"Converting DataWritingCommandExec: ${exec.simpleStringWithNodeId()}")
+        logDebug(s"  outputPath: ${cmd.outputPath}")
+        logDebug(s"  partitionColumns: ${cmd.partitionColumns}")
+        logDebug(s"  bucketSpec: ${cmd.bucketSpec}")
+        // the query is planned adaptively, whether it is native is decided when writing
+        NativeParquetInsertIntoHadoopFsRelationCommand301.checkSupported(sparkSession, cmd)
+        DataWritingCommandExec(NativeParquetInsertIntoHadoopFsRelationCommand301(cmd), child)
+      case DataWritingCommandExec(_: NativeParquetInsertIntoHadoopFsRelationCommand301, _) =>
+        exec
+      case _ =>
+        logDebug(s"Ignoring DataWritingCommandExec: ${exec.simpleStringWithNodeId()}")
+        PlanConversionTelemetry.markFallback(
+          exec,
+          FallbackReason.UNSUPPORTED_FEATURE,
+          s"unsupported writing command: ${exec.cmd.nodeName}")
+        exec
+    }
+
+  def bridgeJvmChild(child: SparkPlan): SparkPlan = {
+    if (NativeSupports.isNative(child)) {
+      return child
+    }
+    logDebug(s"Bridging non-native child: ${child.simpleStringWithNodeId()}")
+    ConvertToNativeExec(child)
+  }
+
   def convertToUnsafeRow(exec: SparkPlan): SparkPlan = {
     if (!NativeSupports.isNative(exec)) {
       return exec
@@ -18,6 +18,7 @@ package org.apache.spark.sql.blaze
 
 import java.util.UUID
 
+import org.apache.spark.sql.catalyst.InternalRow
 import org.apache.spark.sql.catalyst.expressions.Attribute
 import org.apache.spark.sql.catalyst.plans.logical.LogicalPlan
 import org.apache.spark.sql.catalyst.plans.physical.Partitioning
@@ -25,13 +26,18 @@ import org.apache.spark.sql.execution.SparkPlan
 import org.apache.spark.sql.execution.UnaryExecNode
 import org.apache.spark.sql.execution.metric.SQLMetric
 import org.apache.spark.SparkEnv
+import org.apache.spark.sql.blaze.execution.ArrowFFIExporter
 import org.apache.spark.sql.blaze.execution.ArrowWriterIterator
+import org.apache.spark.sql.blaze.execution.UnsafeRowBufferIterator
 import org.apache.spark.sql.internal.SQLConf
 import org.apache.spark.sql.types.StructField
 import org.apache.spark.sql.types.StructType
 import org.apache.spark.InterruptibleIterator
+import org.apache.spark.TaskContext
+import org.blaze.protobuf.FFIReaderExecNode
 import org.blaze.protobuf.JvmToNativeExecNode
 import org.blaze.protobuf.PhysicalPlanNode
+import org.blaze.protobuf.RowInputExecNode
 import org.blaze.protobuf.Schema
 
 case class ConvertToNativeExec(override val child: SparkPlan)
@@ -50,7 +56,6 @@ case class ConvertToNativeExec(override val child: SparkPlan)
 
   override def doExecuteNative(): NativeRDD = {
     val inputRDD = child.execute()
-    val timeZoneId = SparkEnv.get.conf.get(SQLConf.SESSION_LOCAL_TIMEZONE)
     val nativeMetrics = MetricNode(metrics, Nil)
 
     new NativeRDD(
@@ -59,30 +64,98 @@ case class ConvertToNativeExec(override val child: SparkPlan)
       inputRDD.partitions,
       inputRDD.dependencies,
       (partition, context) => {
-        val resourceId = "ConvertToNativeExec" +
-          s":stage=${context.stageId()}" +
-          s":partition=${context.partitionId()}" +
-          s":taskAttempt=${context.taskAttemptId()}" +
-          s":uuid=${UUID.randomUUID().toString}"
-        val provideIpcIterator = () => {
-          val inputRowIter = inputRDD.compute(partition, context)
-          val ipcIterator = new ArrowWriterIterator(inputRowIter, schema, timeZoneId, context)
-          new InterruptibleIterator(context, ipcIterator)
-        }
-        JniBridge.resourcesMap.put(resourceId, () => provideIpcIterator())
-
-        PhysicalPlanNode
-          .newBuilder()
-          .setJvmToNative(
-            JvmToNativeExecNode
-              .newBuilder()
-              .setSchema(nativeSchema)
-              .setNumPartitions(inputRDD.getNumPartitions)
-              .setNativeResourceId(resourceId)
-              .build())
-          .build()
+        ConvertToNativeExec.buildNativeInputPlan(
+          "ConvertToNativeExec",
+          schema,
+          nativeSchema,
+          inputRDD.getNumPartitions,
+          context,
+          () => inputRDD.compute(partition, context))
       })
   }
 
   override def doCanonicalize(): SparkPlan = child.canonicalized
 }
+
+object ConvertToNativeExec {
+
+  /**
+   * Builds a native leaf plan reading the provided rows of the task. the rows are registered
+   * as a task resource and are only computed once the native plan is executed.
+   */
+  def buildNativeInputPlan(
+      resourceName: String,
+      schema: StructType,
+      nativeSchema: Schema,
+      numPartitions: Int,
+      context: TaskContext,
+      provideRows: () => Iterator[InternalRow]): PhysicalPlanNode = {
+    val timeZoneId = SparkEnv.get.conf.get(SQLConf.SESSION_LOCAL_TIMEZONE)
+    val resourceId = resourceName +
+      s":stage=${context.stageId()}" +
+      s":partition=${context.partitionId()}" +
+      s":taskAttempt=${context.taskAttemptId()}" +
+      s":uuid=${UUID.randomUUID().toString}"
+
+    // rows are decoded natively if all fields are supported by RowInputExec
+    val rowInputEnabled =
+      SparkEnv.get.conf.getBoolean("spark.blaze.rowInput.enabled", true) &&
+        UnsafeRowBufferIterator.isSupported(schema)
+
+    // otherwise rows are written into arrow batches imported through FFI, or
+    // serialized into arrow IPC files if FFI reader is disabled
+    val ffiReaderEnabled = SparkEnv.get.conf.getBoolean("spark.blaze.ffiReader.enabled", true)
+
+    if (rowInputEnabled) {
+      val provideRowBufferIterator = () => {
+        new InterruptibleIterator(context, new UnsafeRowBufferIterator(provideRows(), schema))
+      }
+      JniBridge.resourcesMap.put(resourceId, () => provideRowBufferIterator())
+
+      PhysicalPlanNode
+        .newBuilder()
+        .setRowInput(
+          RowInputExecNode
+            .newBuilder()
+            .setSchema(nativeSchema)
+            .setNumPartitions(numPartitions)
+            .setNativeResourceId(resourceId)
+            .build())
+        .build()
+    } else if (ffiReaderEnabled) {
+      val provideExporter = () => {
+        val rows = new InterruptibleIterator(context, provideRows())
+        new ArrowFFIExporter(rows, schema, timeZoneId, context)
+      }
+      JniBridge.resourcesMap.put(resourceId, () => provideExporter())
+
+      PhysicalPlanNode
+        .newBuilder()
+        .setFfiReader(
+          FFIReaderExecNode
+            .newBuilder()
+            .setSchema(nativeSchema)
+            .setNumPartitions(numPartitions)
+            .setExportIterProviderResourceId(resourceId)
+            .build())
+        .build()
+    } else {
+      val provideIpcIterator = () => {
+        val ipcIterator = new ArrowWriterIterator(provideRows(), schema, timeZoneId, context)
+        new InterruptibleIterator(context, ipcIterator)
+      }
+      JniBridge.resourcesMap.put(resourceId, () => provideIpcIterator())
+
+      PhysicalPlanNode
+        .newBuilder()
+        .setJvmToNative(
+          JvmToNativeExecNode
+            .newBuilder()
+            .setSchema(nativeSchema)
+            .setNumPartitions(numPartitions)
+            .setNativeResourceId(resourceId)
+            .build())
+        .build()
+    }
+  }
+}
@@ -22,17 +22,22 @@ import java.util.concurrent.TimeUnit
 import scala.collection.JavaConverters._
 
 import org.apache.arrow.c.ArrowArray
+import org.apache.arrow.c.ArrowArrayStream
 import org.apache.arrow.c.ArrowSchema
 import org.apache.arrow.c.CDataDictionaryProvider
 import org.apache.arrow.c.Data
 import org.apache.arrow.vector.VectorSchemaRoot
+import org.apache.spark.Partition
 import org.apache.spark.TaskContext
 import org.apache.spark.sql.catalyst.InternalRow
+import org.apache.spark.sql.catalyst.expressions.UnsafeRow
 import org.apache.spark.sql.util2.ArrowColumnVector
 import org.apache.spark.sql.util2.ArrowUtils2
 import org.apache.spark.sql.vectorized.ColumnarBatch
 import org.apache.spark.sql.vectorized.ColumnVector
+import org.apache.spark.unsafe.Platform
 import org.apache.spark.util.CompletionIterator
+import org.blaze.protobuf.PhysicalPlanNode
 
 object FFIHelper {
   def tryWithResource[R <: AutoCloseable, T](createResource: => R)(f: R => T): T = {
@@ -59,9 +64,35 @@ object FFIHelper {
   def fromBlazeCallNative(
       wrapper: BlazeCallNativeWrapper,
       context: TaskContext): Iterator[InternalRow] = {
+    if (wrapper.isRowOutput) {
+      return fromBlazeCallNativeUnsafeRows(wrapper, context)
+    }
     fromBlazeCallNativeColumnar(wrapper, context).flatMap(batchAsRowIter)
   }
 
+  def fromBlazeCallNativeUnsafeRows(
+      wrapper: BlazeCallNativeWrapper,
+      context: TaskContext): Iterator[InternalRow] = {
+    context.addTaskCompletionListener[Unit](_ => wrapper.finish())
+
+    // rows are pointed into the copied buffers, no per-field conversion needed
+    val row = new UnsafeRow(wrapper.getRowOutputNumFields)
+    Iterator.continually(wrapper.nextRows()).takeWhile(_ != null).flatMap { rows =>
+      new Iterator[InternalRow] {
+        private var offset = 0
+
+        override def hasNext: Boolean = offset < rows.length
+
+        override def next(): InternalRow = {
+          val rowSize = Platform.getInt(rows, Platform.BYTE_ARRAY_OFFSET + offset)
+          row.pointTo(rows, Platform.BYTE_ARRAY_OFFSET + offset + 4, rowSize)
+          offset += 4 + rowSize
+          row
+        }
+      }
+    }
+  }
+
   def fromBlazeCallNativeColumnar(
       wrapper: BlazeCallNativeWrapper,
       context: TaskContext): Iterator[ColumnarBatch] = {
@@ -130,4 +161,129 @@ object FFIHelper {
       }
     }
   }
+
+  /**
+   * polls output batches of the native plan with JniBridge.nextBatch() in the task thread. the
+   * returned batches share the same vectors and are only valid until the next call of hasNext
+   */
+  def fromNativeBatchIterator(
+      nativePlan: PhysicalPlanNode,
+      metrics: MetricNode,
+      partition: Partition,
+      context: TaskContext): Iterator[ColumnarBatch] = {
+    BlazeCallNativeWrapper.initNative(context)
+    val taskDefinition = BlazeCallNativeWrapper.buildTaskDefinition(nativePlan, partition, context)
+    val iterPtr = JniBridge.openBatchIterator(taskDefinition.toByteArray)
+
+    val allocator =
+      ArrowUtils2.rootAllocator.newChildAllocator("fromNativeBatchIterator", 0, Long.MaxValue)
+    val provider = new CDataDictionaryProvider()
+
+    new Iterator[ColumnarBatch] {
+      private var root: VectorSchemaRoot = _
+      private var batch: ColumnarBatch = _
+      private var finished = false
+
+      context.addTaskCompletionListener[Unit](_ => finish())
+
+      override def hasNext: Boolean =
+        !finished && (batch != null || {
+          tryWithResource(ArrowSchema.allocateNew(allocator)) { consumerSchema =>
+            tryWithResource(ArrowArray.allocateNew(allocator)) { consumerArray =>
+              val schemaPtr: Long = consumerSchema.memoryAddress
+              val arrayPtr: Long = consumerArray.memoryAddress
+              if (JniBridge.nextBatch(iterPtr, arrayPtr, schemaPtr) < 0) {
+                finish()
+                return false
+              }
+
+              if (root == null) {
+                root =
+                  Data.importVectorSchemaRoot(allocator, consumerArray, consumerSchema, provider)
+              } else {
+                Data.importIntoVectorSchemaRoot(allocator, consumerArray, root, provider)
+              }
+              batch = rootAsBatch(root)
+              true
+            }
+          }
+        })
+
+      override def next(): ColumnarBatch = {
+        if (!hasNext) {
+          throw new NoSuchElementException("no more native batches")
+        }
+        val nextBatch = batch
+        batch = null
+        nextBatch
+      }
+
+      private def finish(): Unit = {
+        if (!finished) {
+          finished = true
+          JniBridge.closeBatchIterator(iterPtr, metrics)
+          if (root != null) {
+            root.close()
+          }
+          allocator.close()
+        }
+      }
+    }
+  }
+
+  /**
+   * reads output batches of the native plan from an ArrowArrayStream exported by
+   * JniBridge.exportBatchStream(), instead of polling with JniBridge.nextBatch(). the batches are
+   * read in the task thread and only valid until the next call of hasNext
+   */
+  def fromNativeBatchStream(
+      nativePlan: PhysicalPlanNode,
+      metrics: MetricNode,
+      partition: Partition,
+      context: TaskContext): Iterator[ColumnarBatch] = {
+    BlazeCallNativeWrapper.initNative(context)
+    val taskDefinition = BlazeCallNativeWrapper.buildTaskDefinition(nativePlan, partition, context)
+
+    val allocator =
+      ArrowUtils2.rootAllocator.newChildAllocator("fromNativeBatchStream", 0, Long.MaxValue)
+    val reader = tryWithResource(ArrowArrayStream.allocateNew(allocator)) { stream =>
+      JniBridge.exportBatchStream(taskDefinition.toByteArray, stream.memoryAddress, metrics)
+      Data.importArrayStream(allocator, stream)
+    }
+
+    new Iterator[ColumnarBatch] {
+      private var batch: ColumnarBatch = _
+      private var finished = false
+
+      context.addTaskCompletionListener[Unit](_ => finish())
+
+      override def hasNext: Boolean =
+        !finished && (batch != null || {
+          if (!reader.loadNextBatch()) {
+            finish()
+            return false
+          }
+          batch = rootAsBatch(reader.getVectorSchemaRoot)
+          true
+        })
+
+      override def next(): ColumnarBatch = {
+        if (!hasNext) {
+          throw new NoSuchElementException("no more native batches")
+        }
+        val nextBatch = batch
+        batch = null
+        nextBatch
+      }
+
+      // closing the reader releases the stream, which closes the native iterator
+      private def finish(): Unit = {
+        if (!finished) {
+          finished = true
+          reader.close()
+          allocator.close()
+        }
+      }
+    }
+  }
 }
@@ -0,0 +1,74 @@
+/*
+ * Copyright 2022 The Blaze Authors
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+package org.apache.spark.sql.blaze
+
+import java.util.concurrent.ConcurrentHashMap
+
+import scala.collection.JavaConverters._
+
+import org.apache.spark.internal.Logging
+import org.apache.spark.SparkContext
+import org.apache.spark.TaskContext
+import org.apache.spark.util.LongAccumulator
+
+/**
+ * Spark accumulators receiving values of named native counters, so that native operators and
+ * expressions can be instrumented like regular spark code.
+ *
+ * accumulators are registered on the driver before the native plans are executed, and are
+ * shipped to the tasks with native RDDs. native counters are flushed into the accumulators of the
+ * same names once the task completes, counters without accumulators are dropped.
+ */
+object NativeAccumulators extends Logging {
+  private val registered = new ConcurrentHashMap[String, LongAccumulator]()
+  private val taskAccumulators = new ConcurrentHashMap[Long, Map[String, LongAccumulator]]()
+
+  /** registers the accumulator of native counters with the name, called on the driver */
+  def register(sc: SparkContext, name: String): LongAccumulator =
+    registered.computeIfAbsent(name, _ => sc.longAccumulator(name))
+
+  def unregister(name: String): Unit = registered.remove(name)
+
+  /** accumulators registered on the driver, captured by native RDDs when created */
+  def registeredAccumulators: Map[String, LongAccumulator] = registered.asScala.toMap
+
+  /** binds the accumulators shipped with the native RDD to the task, called on executors */
+  def bindTask(context: TaskContext, accumulators: Map[String, LongAccumulator]): Unit = {
+    if (accumulators.isEmpty) {
+      return
+    }
+    val taskAttemptId = context.taskAttemptId()
+    if (taskAccumulators.putIfAbsent(taskAttemptId, accumulators) == null) {
+      // listeners run in reverse order, so this runs after the native counters are flushed by
+      // the listener added later by BlazeCallNativeWrapper.initNative()
+      context.addTaskCompletionListener[Unit](_ => taskAccumulators.remove(taskAttemptId))
+    } else {
+      taskAccumulators.merge(taskAttemptId, accumulators, _ ++ _)
+    }
+  }
+
+  def update(name: String, value: Long): Unit = {
+    val context = TaskContext.get()
+    val accumulator = Option(context)
+      .flatMap(context => Option(taskAccumulators.get(context.taskAttemptId())))
+      .flatMap(_.get(name))
+    accumulator match {
+      case Some(accumulator) => accumulator.add(value)
+      case None => logDebug(s"Dropping native counter without accumulator: $name=$value")
+    }
+  }
+}
@@ -16,23 +16,35 @@
 
 package org.apache.spark.sql.blaze
 
+import java.time.Instant
+
 import scala.collection.JavaConverters._
+import scala.util.DynamicVariable
+import scala.util.Try
 
+import org.apache.spark.sql.blaze.execution.CharVarcharCodegenUtils301
 import org.apache.spark.sql.catalyst.expressions.Abs
 import org.apache.spark.sql.catalyst.expressions.Acos
 import org.apache.spark.sql.catalyst.expressions.Add
 import org.apache.spark.sql.catalyst.expressions.And
+import org.apache.spark.sql.catalyst.expressions.ArrayAggregate
+import org.apache.spark.sql.catalyst.expressions.ArrayExists
+import org.apache.spark.sql.catalyst.expressions.ArrayFilter
+import org.apache.spark.sql.catalyst.expressions.ArrayTransform
 import org.apache.spark.sql.catalyst.expressions.Asin
 import org.apache.spark.sql.catalyst.expressions.Atan
 import org.apache.spark.sql.catalyst.expressions.AttributeReference
+import org.apache.spark.sql.catalyst.expressions.BitwiseAnd
 import org.apache.spark.sql.catalyst.expressions.CaseWhen
 import org.apache.spark.sql.catalyst.expressions.Cast
 import org.apache.spark.sql.catalyst.expressions.Ceil
 import org.apache.spark.sql.catalyst.expressions.Coalesce
 import org.apache.spark.sql.catalyst.expressions.Concat
 import org.apache.spark.sql.catalyst.expressions.Cos
+import org.apache.spark.sql.catalyst.expressions.DateAddInterval
 import org.apache.spark.sql.catalyst.expressions.DatePart
 import org.apache.spark.sql.catalyst.expressions.Divide
+import org.apache.spark.sql.catalyst.expressions.EqualNullSafe
 import org.apache.spark.sql.catalyst.expressions.EqualTo
 import org.apache.spark.sql.catalyst.expressions.Exp
 import org.apache.spark.sql.catalyst.expressions.Expression
@@ -43,6 +55,8 @@ import org.apache.spark.sql.catalyst.expressions.In
 import org.apache.spark.sql.catalyst.expressions.InSet
 import org.apache.spark.sql.catalyst.expressions.IsNotNull
 import org.apache.spark.sql.catalyst.expressions.IsNull
+import org.apache.spark.sql.catalyst.expressions.KnownFloatingPointNormalized
+import org.apache.spark.sql.catalyst.expressions.LambdaFunction
 import org.apache.spark.sql.catalyst.expressions.LessThan
 import org.apache.spark.sql.catalyst.expressions.LessThanOrEqual
 import org.apache.spark.sql.catalyst.expressions.Like
@@ -52,16 +66,23 @@ import org.apache.spark.sql.catalyst.expressions.Log10
 import org.apache.spark.sql.catalyst.expressions.Log2
 import org.apache.spark.sql.catalyst.expressions.Lower
 import org.apache.spark.sql.catalyst.expressions.Md5
+import org.apache.spark.sql.catalyst.expressions.MonotonicallyIncreasingID
 import org.apache.spark.sql.catalyst.expressions.Multiply
+import org.apache.spark.sql.catalyst.expressions.NamedLambdaVariable
 import org.apache.spark.sql.catalyst.expressions.Not
 import org.apache.spark.sql.catalyst.expressions.NullIf
 import org.apache.spark.sql.catalyst.expressions.OctetLength
 import org.apache.spark.sql.catalyst.expressions.Or
+import org.apache.spark.sql.catalyst.expressions.RLike
+import org.apache.spark.sql.catalyst.expressions.Rand
+import org.apache.spark.sql.catalyst.expressions.Randn
 import org.apache.spark.sql.catalyst.expressions.Remainder
 import org.apache.spark.sql.catalyst.expressions.Round
 import org.apache.spark.sql.catalyst.expressions.Sha2
+import org.apache.spark.sql.catalyst.expressions.ShiftRight
 import org.apache.spark.sql.catalyst.expressions.Signum
 import org.apache.spark.sql.catalyst.expressions.Sin
+import org.apache.spark.sql.catalyst.expressions.SparkPartitionID
 import org.apache.spark.sql.catalyst.expressions.Sqrt
 import org.apache.spark.sql.catalyst.expressions.StartsWith
 import org.apache.spark.sql.catalyst.expressions.StringTrim
@@ -70,8 +91,11 @@ import org.apache.spark.sql.catalyst.expressions.StringTrimRight
 import org.apache.spark.sql.catalyst.expressions.Substring
 import org.apache.spark.sql.catalyst.expressions.Subtract
 import org.apache.spark.sql.catalyst.expressions.Tan
+import org.apache.spark.sql.catalyst.expressions.TimeAdd
 import org.apache.spark.sql.catalyst.expressions.TruncDate
 import org.apache.spark.sql.catalyst.expressions.Upper
+import org.apache.spark.sql.catalyst.expressions.objects.StaticInvoke
+import org.apache.spark.sql.catalyst.optimizer.NormalizeNaNAndZero
 import org.apache.spark.sql.catalyst.plans.FullOuter
 import org.apache.spark.sql.catalyst.plans.Inner
 import org.apache.spark.sql.catalyst.plans.JoinType
@@ -79,9 +103,17 @@ import org.apache.spark.sql.catalyst.plans.LeftAnti
 import org.apache.spark.sql.catalyst.plans.LeftOuter
 import org.apache.spark.sql.catalyst.plans.LeftSemi
 import org.apache.spark.sql.catalyst.plans.RightOuter
+import org.apache.spark.sql.catalyst.util.ArrayData
+import org.apache.spark.sql.catalyst.util.MapData
+import org.apache.spark.sql.execution.ExecSubqueryExpression
+import org.apache.spark.sql.execution.InSubqueryExec
+import org.apache.spark.sql.execution.ScalarSubquery
+import org.apache.spark.sql.internal.SQLConf
+import org.apache.spark.sql.types.ArrayType
 import org.apache.spark.sql.types.BinaryType
 import org.apache.spark.sql.types.BooleanType
 import org.apache.spark.sql.types.ByteType
+import org.apache.spark.sql.types.CalendarIntervalType
 import org.apache.spark.sql.types.DataType
 import org.apache.spark.sql.types.DateType
 import org.apache.spark.sql.types.Decimal
@@ -90,32 +122,47 @@ import org.apache.spark.sql.types.DoubleType
 import org.apache.spark.sql.types.FloatType
 import org.apache.spark.sql.types.IntegerType
 import org.apache.spark.sql.types.LongType
+import org.apache.spark.sql.types.MapType
 import org.apache.spark.sql.types.NullType
 import org.apache.spark.sql.types.ShortType
 import org.apache.spark.sql.types.StringType
 import org.apache.spark.sql.types.StructField
 import org.apache.spark.sql.types.StructType
 import org.apache.spark.sql.types.TimestampType
+import org.apache.spark.unsafe.types.CalendarInterval
 import org.apache.spark.unsafe.types.UTF8String
+import org.blaze.protobuf.ArrayFunction
 import org.blaze.protobuf.ArrowType
 import org.blaze.protobuf.BinaryExprNode
 import org.blaze.protobuf.CaseNode
 import org.blaze.protobuf.CastNode
+import org.blaze.protobuf.CharVarcharMode
 import org.blaze.protobuf.Column
 import org.blaze.protobuf.EmptyMessage
 import org.blaze.protobuf.Field
 import org.blaze.protobuf.InListNode
 import org.blaze.protobuf.LogicalExprNode
+import org.blaze.protobuf.PhysicalArrayFunctionExprNode
 import org.blaze.protobuf.PhysicalBinaryExprNode
 import org.blaze.protobuf.PhysicalCaseNode
 import org.blaze.protobuf.PhysicalCastNode
+import org.blaze.protobuf.PhysicalCharVarcharExprNode
 import org.blaze.protobuf.PhysicalColumn
+import org.blaze.protobuf.PhysicalEqualNullSafeExprNode
 import org.blaze.protobuf.PhysicalExprNode
+import org.blaze.protobuf.PhysicalGroupingExprNode
 import org.blaze.protobuf.PhysicalInListNode
 import org.blaze.protobuf.PhysicalIsNotNull
 import org.blaze.protobuf.PhysicalIsNull
+import org.blaze.protobuf.PhysicalLambdaNode
+import org.blaze.protobuf.PhysicalNormalizeNaNAndZeroExprNode
 import org.blaze.protobuf.PhysicalNot
 import org.blaze.protobuf.PhysicalScalarFunctionNode
+import org.blaze.protobuf.PhysicalShortCircuitBinaryExprNode
+import org.blaze.protobuf.PhysicalSparkIntervalAddExprNode
+import org.blaze.protobuf.PhysicalSparkLikeExprNode
+import org.blaze.protobuf.PhysicalSparkPartitionExprNode
+import org.blaze.protobuf.PhysicalSparkRandExprNode
 import org.blaze.protobuf.PhysicalWhenThen
 import org.blaze.protobuf.PrimitiveScalarType
 import org.blaze.protobuf.ScalarDecimalValue
@@ -123,10 +170,36 @@ import org.blaze.protobuf.ScalarFunction
 import org.blaze.protobuf.ScalarFunctionNode
 import org.blaze.protobuf.ScalarValue
 import org.blaze.protobuf.Schema
+import org.blaze.protobuf.SparkPartitionExprKind
 import org.blaze.protobuf.Timestamp
 import org.blaze.protobuf.WhenThen
 
 object NativeConverters {
+  private val subqueryPlaceholdersAllowed = new DynamicVariable[Boolean](false)
+
+  /**
+   * Converts exprs when planning execs which convert their exprs again in doExecuteNative(),
+   * where results of subqueries are available. unevaluated subqueries are converted into typed
+   * null placeholders instead of failing the conversion.
+   */
+  def withSubqueryPlaceholders[T](convert: => T): T =
+    subqueryPlaceholdersAllowed.withValue(true)(convert)
+
+  /** returns None for the placeholder of an unevaluated subquery */
+  private def subqueryResult[T](subquery: ExecSubqueryExpression, result: => T): Option[T] = {
+    val evaluated = subquery match {
+      case e: InSubqueryExec => e.values().isDefined
+      case e: ScalarSubquery => Try(e.eval()).isSuccess
+    }
+    if (evaluated) {
+      return Some(result)
+    }
+    if (!subqueryPlaceholdersAllowed.value) {
+      throw new NotImplementedError(s"unevaluated subquery: $subquery")
+    }
+    None
+  }
+
   def convertToScalarType(dt: DataType): PrimitiveScalarType = {
     dt match {
       case NullType => PrimitiveScalarType.NULL
@@ -170,7 +243,28 @@ object NativeConverters {
             .setFractional(t.scale)
             .build())
 
-      // TODO: support complex data types
+      // complex types, field names follow spark's ArrowUtils
+      case t: ArrayType =>
+        arrowTypeBuilder.setLIST(
+          org.blaze.protobuf.List
+            .newBuilder()
+            .setFieldType(convertField(StructField("element", t.elementType, t.containsNull))))
+      case t: StructType =>
+        arrowTypeBuilder.setSTRUCT(
+          org.blaze.protobuf.Struct
+            .newBuilder()
+            .addAllSubFieldTypes(t.fields.map(convertField).toSeq.asJava))
+      case t: MapType =>
+        val entriesType = StructType(
+          Seq(
+            StructField("key", t.keyType, nullable = false),
+            StructField("value", t.valueType, t.valueContainsNull)))
+        arrowTypeBuilder.setMAP(
+          org.blaze.protobuf.Map
+            .newBuilder()
+            .setFieldType(convertField(StructField("entries", entriesType, nullable = false)))
+            .setKeysSorted(false))
+
       case _ =>
         throw new NotImplementedError(s"Data type conversion not implemented ${sparkDataType}")
     }
@@ -202,6 +296,10 @@ object NativeConverters {
             .setDecimal(decimalType)
             .setLongValue(decimalValue.toUnscaledLong))
       }
+      case _: ArrayType if sparkValue.asInstanceOf[ArrayData].numElements() == 0 =>
+        scalarValueBuilder.setEmptyCollectionValue(convertDataType(dataType))
+      case _: MapType if sparkValue.asInstanceOf[MapData].numElements() == 0 =>
+        scalarValueBuilder.setEmptyCollectionValue(convertDataType(dataType))
       // TODO: support complex data types
       case _ => throw new NotImplementedError(s"Value conversion not implemented ${dataType}")
     }
@@ -210,6 +308,18 @@ object NativeConverters {
     scalarValueBuilder.build()
   }
 
+  def convertNullValue(dataType: DataType): ScalarValue = {
+    val scalarValueBuilder = ScalarValue.newBuilder()
+    dataType match {
+      case NullType | BooleanType | ByteType | ShortType | IntegerType | LongType | FloatType |
+          DoubleType | StringType =>
+        scalarValueBuilder.setNullValue(convertToScalarType(dataType))
+      case _ =>
+        scalarValueBuilder.setTypedNullValue(convertDataType(dataType))
+    }
+    scalarValueBuilder.build()
+  }
+
   def convertField(sparkField: StructField): Field = {
     Field
       .newBuilder()
@@ -225,6 +335,21 @@ object NativeConverters {
     schemaBuilder.build()
   }
 
+  /**
+   * Returns true if evaluating expr on rows that spark would have skipped may change the
+   * result, i.e. expr is non-deterministic or may raise errors. such exprs must be evaluated in
+   * spark's left-to-right short-circuit order and never be reordered by the native side.
+   */
+  def requiresOrderedEvaluation(expr: Expression): Boolean = {
+    val ansiEnabled = SQLConf.get.ansiEnabled
+    expr.find {
+      case e if !e.deterministic => true
+      case _: Cast | _: Add | _: Subtract | _: Multiply | _: Divide | _: Remainder =>
+        ansiEnabled
+      case _ => false
+    }.isDefined
+  }
+
   def convertExpr(sparkExpr: Expression): PhysicalExprNode = {
     def buildExprNode(
         buildFn: (PhysicalExprNode.Builder) => PhysicalExprNode.Builder): PhysicalExprNode =
@@ -241,6 +366,20 @@ object NativeConverters {
             .build())
       }
 
+    def buildShortCircuitBinaryExprNode(
+        left: Expression,
+        right: Expression,
+        op: String): PhysicalExprNode =
+      buildExprNode {
+        _.setScBinaryExpr(
+          PhysicalShortCircuitBinaryExprNode
+            .newBuilder()
+            .setL(convertExpr(left))
+            .setR(convertExpr(right))
+            .setOp(op)
+            .build())
+      }
+
     def buildScalarFunction(
         fn: ScalarFunction,
         args: Seq[Expression],
@@ -256,6 +395,79 @@ object NativeConverters {
             .build())
       }
 
+    // same as the seed of RDG, which is protected
+    def buildRandExprNode(seedExpr: Expression, gaussian: Boolean): PhysicalExprNode = {
+      val seed = seedExpr match {
+        case Literal(s, IntegerType) => s.asInstanceOf[Int].toLong
+        case Literal(s, LongType) => s.asInstanceOf[Long]
+        case _ => throw new NotImplementedError(s"unsupported rand seed: $seedExpr")
+      }
+      buildExprNode {
+        _.setSparkRandExpr(
+          PhysicalSparkRandExprNode
+            .newBuilder()
+            .setSeed(seed)
+            .setGaussian(gaussian)
+            .build())
+      }
+    }
+
+    def buildIntervalAddExprNode(
+        expr: Expression,
+        interval: CalendarInterval,
+        zoneOffsetSeconds: Int): PhysicalExprNode =
+      buildExprNode {
+        _.setSparkIntervalAddExpr(
+          PhysicalSparkIntervalAddExprNode
+            .newBuilder()
+            .setExpr(convertExpr(expr))
+            .setMonths(interval.months)
+            .setDays(interval.days)
+            .setMicroseconds(interval.microseconds)
+            .setZoneOffsetSeconds(zoneOffsetSeconds)
+            .build())
+      }
+
+    // captured columns are the referenced columns and variables of enclosing
+    // lambdas, excluding the args of this lambda and its nested lambdas
+    def buildLambdaNode(function: Expression): PhysicalLambdaNode =
+      function match {
+        case LambdaFunction(body, arguments, _) =>
+          val args = arguments.map(_.toAttribute.toString)
+          val boundArgs = args ++ body.collect { case nested: LambdaFunction =>
+            nested.arguments.map(_.toAttribute.toString)
+          }.flatten
+          val capturedColumns = body
+            .collect {
+              case ar: AttributeReference => ar.toString
+              case v: NamedLambdaVariable => v.toAttribute.toString
+            }
+            .distinct
+            .filterNot(boundArgs.contains)
+          PhysicalLambdaNode
+            .newBuilder()
+            .setBody(convertExpr(body))
+            .addAllArgs(args.asJava)
+            .addAllCapturedColumns(capturedColumns.asJava)
+            .build()
+        case _ => throw new NotImplementedError(s"unsupported lambda function: $function")
+      }
+
+    def buildArrayFunctionExprNode(
+        fun: ArrayFunction,
+        array: Expression,
+        function: Expression,
+        build: PhysicalArrayFunctionExprNode.Builder => Unit = _ => ()): PhysicalExprNode =
+      buildExprNode { b =>
+        val builder = PhysicalArrayFunctionExprNode
+          .newBuilder()
+          .setFun(fun)
+          .setArray(convertExpr(array))
+          .setFunction(buildLambdaNode(function))
+        build(builder)
+        b.setArrayFunctionExpr(builder.build())
+      }
+
     def unpackBinaryTypeCast(expr: Expression) =
       expr match {
         case Cast(inner, BinaryType, _) => inner
@@ -268,7 +480,7 @@ object NativeConverters {
           if (!l.nullable) {
             b.setLiteral(convertValue(value, dataType))
           } else {
-            b.setLiteral(ScalarValue.newBuilder().setNullValue(convertToScalarType(dataType)))
+            b.setLiteral(convertNullValue(dataType))
           }
         }
       case ar: AttributeReference =>
@@ -276,6 +488,127 @@ object NativeConverters {
           _.setColumn(PhysicalColumn.newBuilder().setName(ar.toString()).build())
         }
 
+      case v: NamedLambdaVariable =>
+        buildExprNode {
+          _.setColumn(PhysicalColumn.newBuilder().setName(v.toAttribute.toString()).build())
+        }
+
+      // subqueries, converted into literals of their results
+      case e: ScalarSubquery =>
+        subqueryResult(e, e.eval()) match {
+          case Some(result) => convertExpr(Literal(result, e.dataType))
+          case None => convertExpr(Literal(null, e.dataType))
+        }
+      case e: InSubqueryExec if !e.child.dataType.isInstanceOf[StructType] =>
+        // InSubqueryExec yields false for unmatched values, even if the results have nulls
+        subqueryResult(e, e.values().get.toSeq.filter(_ != null)) match {
+          case Some(Seq()) =>
+            val nullIfNull = IsNull(e.child) -> Literal(null, BooleanType)
+            convertExpr(CaseWhen(Seq(nullIfNull), Literal(false)))
+          case Some(values) => convertExpr(In(e.child, values.map(Literal(_, e.child.dataType))))
+          case None => convertExpr(Literal(null, BooleanType))
+        }
+
+      // grouping(col), resolved by spark against the grouping id column of expand
+      case Cast(
+            BitwiseAnd(
+              ShiftRight(groupingId, Literal(shift: Int, IntegerType)),
+              Literal(1, IntegerType) | Literal(1L, LongType)),
+            ByteType,
+            _) if shift >= 0 =>
+        buildExprNode {
+          _.setGroupingExpr(
+            PhysicalGroupingExprNode
+              .newBuilder()
+              .setGroupingId(convertExpr(groupingId))
+              .setShift(shift)
+              .build())
+        }
+
+      // char/varchar padding and length checks built by CharVarcharUtils301
+      case e: StaticInvoke if e.staticObject == classOf[CharVarcharCodegenUtils301] =>
+        val mode = e.functionName match {
+          case "readSidePadding" => CharVarcharMode.READ_SIDE_PADDING
+          case "charTypeWriteSideCheck" => CharVarcharMode.CHAR_WRITE_SIDE_CHECK
+          case "varcharTypeWriteSideCheck" => CharVarcharMode.VARCHAR_WRITE_SIDE_CHECK
+        }
+        val Seq(child, Literal(length: Int, IntegerType)) = e.arguments
+        buildExprNode {
+          _.setCharVarcharExpr(
+            PhysicalCharVarcharExprNode
+              .newBuilder()
+              .setExpr(convertExpr(child))
+              .setMode(mode)
+              .setLength(length)
+              .build())
+        }
+
+      // partition dependent exprs, evaluated with the partition id of the native task
+      case SparkPartitionID() =>
+        buildExprNode {
+          _.setSparkPartitionExpr(
+            PhysicalSparkPartitionExprNode
+              .newBuilder()
+              .setKind(SparkPartitionExprKind.SPARK_PARTITION_ID)
+              .build())
+        }
+      case MonotonicallyIncreasingID() =>
+        buildExprNode {
+          _.setSparkPartitionExpr(
+            PhysicalSparkPartitionExprNode
+              .newBuilder()
+              .setKind(SparkPartitionExprKind.MONOTONICALLY_INCREASING_ID)
+              .build())
+        }
+
+      // date/timestamp + literal interval. months and days of timestamps are
+      // added natively only in fixed offset time zones
+      case e: DateAddInterval =>
+        e.interval match {
+          case Literal(interval: CalendarInterval, CalendarIntervalType)
+              if interval.microseconds == 0 =>
+            buildIntervalAddExprNode(e.start, interval, zoneOffsetSeconds = 0)
+          case _ => throw new NotImplementedError(s"unsupported date interval: $e")
+        }
+      case e: TimeAdd =>
+        val zoneRules = e.zoneId.getRules
+        e.interval match {
+          case Literal(interval: CalendarInterval, CalendarIntervalType)
+              if (interval.months == 0 && interval.days == 0) || zoneRules.isFixedOffset =>
+            val zoneOffsetSeconds = zoneRules.getOffset(Instant.EPOCH).getTotalSeconds
+            buildIntervalAddExprNode(e.start, interval, zoneOffsetSeconds)
+          case _ => throw new NotImplementedError(s"unsupported timestamp interval: $e")
+        }
+
+      // float keys of joins, aggregations and windows normalized by spark's optimizer
+      case KnownFloatingPointNormalized(child) => convertExpr(child)
+      case NormalizeNaNAndZero(child) =>
+        buildExprNode {
+          _.setNormalizeNanAndZeroExpr(
+            PhysicalNormalizeNaNAndZeroExprNode
+              .newBuilder()
+              .setExpr(convertExpr(child))
+              .build())
+        }
+
+      // seeded with seed + partition id of the native task like spark
+      case e: Rand => buildRandExprNode(e.child, gaussian = false)
+      case e: Randn => buildRandExprNode(e.child, gaussian = true)
+
+      // higher-order functions of arrays, lambdas are evaluated natively in batches
+      case e: ArrayTransform =>
+        buildArrayFunctionExprNode(ArrayFunction.TRANSFORM, e.argument, e.function)
+      case e: ArrayFilter =>
+        buildArrayFunctionExprNode(ArrayFunction.FILTER, e.argument, e.function)
+      case e: ArrayExists =>
+        buildArrayFunctionExprNode(ArrayFunction.EXISTS, e.argument, e.function, {
+          _.setThreeValued(e.followThreeValuedLogic)
+        })
+      case e: ArrayAggregate =>
+        buildArrayFunctionExprNode(ArrayFunction.AGGREGATE, e.argument, e.merge, {
+          _.setZero(convertExpr(e.zero)).setFinish(buildLambdaNode(e.finish))
+        })
+
       // cast
       case Cast(child, dataType, _) =>
         buildExprNode {
@@ -297,7 +630,7 @@ object NativeConverters {
               .addAllList(list.map(convertExpr).asJava))
         }
 
-      // in
+      // in, literal lists are looked up in hash sets natively
       case InSet(value, set) =>
         buildExprNode {
           _.setInList(
@@ -305,6 +638,7 @@ object NativeConverters {
               .newBuilder()
               .setExpr(convertExpr(value))
               .addAllList(set.map {
+                case null => convertExpr(Literal(null, value.dataType))
                 case utf8string: UTF8String => convertExpr(Literal(utf8string, StringType))
                 case v => convertExpr(Literal.apply(v))
               }.asJava))
@@ -326,6 +660,15 @@ object NativeConverters {
 
       // binary ops
       case EqualTo(lhs, rhs) => buildBinaryExprNode(lhs, rhs, "Eq")
+      case EqualNullSafe(lhs, rhs) =>
+        buildExprNode {
+          _.setEqualNullSafeExpr(
+            PhysicalEqualNullSafeExprNode
+              .newBuilder()
+              .setL(convertExpr(lhs))
+              .setR(convertExpr(rhs))
+              .build())
+        }
       case GreaterThan(lhs, rhs) => buildBinaryExprNode(lhs, rhs, "Gt")
       case LessThan(lhs, rhs) => buildBinaryExprNode(lhs, rhs, "Lt")
       case GreaterThanOrEqual(lhs, rhs) => buildBinaryExprNode(lhs, rhs, "GtEq")
@@ -335,7 +678,30 @@ object NativeConverters {
       case Multiply(lhs, rhs) => buildBinaryExprNode(lhs, rhs, "Multiply")
       case Divide(lhs, rhs) => buildBinaryExprNode(lhs, rhs, "Divide")
       case Remainder(lhs, rhs) => buildBinaryExprNode(lhs, rhs, "Modulo")
-      case Like(lhs, rhs, '\\') => buildBinaryExprNode(lhs, rhs, "Like")
+      case Like(lhs, rhs, escapeChar) =>
+        buildExprNode {
+          _.setSparkLikeExpr(
+            PhysicalSparkLikeExprNode
+              .newBuilder()
+              .setExpr(convertExpr(lhs))
+              .setPattern(convertExpr(rhs))
+              .setEscapeChar(escapeChar.toString)
+              .build())
+        }
+      case RLike(lhs, rhs) =>
+        buildExprNode {
+          _.setSparkLikeExpr(
+            PhysicalSparkLikeExprNode
+              .newBuilder()
+              .setExpr(convertExpr(lhs))
+              .setPattern(convertExpr(rhs))
+              .setRlike(true)
+              .build())
+        }
+      case And(lhs, rhs) if requiresOrderedEvaluation(rhs) =>
+        buildShortCircuitBinaryExprNode(lhs, rhs, "And")
+      case Or(lhs, rhs) if requiresOrderedEvaluation(rhs) =>
+        buildShortCircuitBinaryExprNode(lhs, rhs, "Or")
       case And(lhs, rhs) => buildBinaryExprNode(lhs, rhs, "And")
       case Or(lhs, rhs) => buildBinaryExprNode(lhs, rhs, "Or")
 
@@ -442,6 +808,46 @@ object NativeConverters {
             .addAllArgs(args.map(convertExprLogical).asJava))
       }
 
+    // captured columns are the referenced columns and variables of enclosing
+    // lambdas, excluding the args of this lambda and its nested lambdas
+    def buildLambdaNode(function: Expression): PhysicalLambdaNode =
+      function match {
+        case LambdaFunction(body, arguments, _) =>
+          val args = arguments.map(_.toAttribute.toString)
+          val boundArgs = args ++ body.collect { case nested: LambdaFunction =>
+            nested.arguments.map(_.toAttribute.toString)
+          }.flatten
+          val capturedColumns = body
+            .collect {
+              case ar: AttributeReference => ar.toString
+              case v: NamedLambdaVariable => v.toAttribute.toString
+            }
+            .distinct
+            .filterNot(boundArgs.contains)
+          PhysicalLambdaNode
+            .newBuilder()
+            .setBody(convertExpr(body))
+            .addAllArgs(args.asJava)
+            .addAllCapturedColumns(capturedColumns.asJava)
+            .build()
+        case _ => throw new NotImplementedError(s"unsupported lambda function: $function")
+      }
+
+    def buildArrayFunctionExprNode(
+        fun: ArrayFunction,
+        array: Expression,
+        function: Expression,
+        build: PhysicalArrayFunctionExprNode.Builder => Unit = _ => ()): PhysicalExprNode =
+      buildExprNode { b =>
+        val builder = PhysicalArrayFunctionExprNode
+          .newBuilder()
+          .setFun(fun)
+          .setArray(convertExpr(array))
+          .setFunction(buildLambdaNode(function))
+        build(builder)
+        b.setArrayFunctionExpr(builder.build())
+      }
+
     def unpackBinaryTypeCast(expr: Expression) =
       expr match {
         case Cast(inner, BinaryType, _) => inner
@@ -454,7 +860,7 @@ object NativeConverters {
           if (!l.nullable) {
             b.setLiteral(convertValue(value, dataType))
           } else {
-            b.setLiteral(ScalarValue.newBuilder().setNullValue(convertToScalarType(dataType)))
+            b.setLiteral(convertNullValue(dataType))
           }
         }
       case ar: AttributeReference =>
@@ -466,6 +872,20 @@ object NativeConverters {
           _.setColumn(Column.newBuilder().setName(ar.name).build())
         }
 
+      // higher-order functions of arrays, lambdas are evaluated natively in batches
+      case e: ArrayTransform =>
+        buildArrayFunctionExprNode(ArrayFunction.TRANSFORM, e.argument, e.function)
+      case e: ArrayFilter =>
+        buildArrayFunctionExprNode(ArrayFunction.FILTER, e.argument, e.function)
+      case e: ArrayExists =>
+        buildArrayFunctionExprNode(ArrayFunction.EXISTS, e.argument, e.function, {
+          _.setThreeValued(e.followThreeValuedLogic)
+        })
+      case e: ArrayAggregate =>
+        buildArrayFunctionExprNode(ArrayFunction.AGGREGATE, e.argument, e.merge, {
+          _.setZero(convertExpr(e.zero)).setFinish(buildLambdaNode(e.finish))
+        })
+
       // cast
       case Cast(child, dataType, _) =>
         buildExprNode {
@@ -24,6 +24,8 @@ import org.apache.spark.Partition
 import org.apache.spark.SparkContext
 import org.apache.spark.TaskContext
 import org.apache.spark.sql.vectorized.ColumnarBatch
+import org.apache.spark.util.CollectionAccumulator
+import org.apache.spark.util.LongAccumulator
 import org.blaze.protobuf.PhysicalPlanNode
 
 class NativeRDD(
@@ -35,12 +37,29 @@ class NativeRDD(
     extends RDD[InternalRow](rddSparkContext, rddDependencies)
     with Logging {
 
+  // native plan annotated with runtime metrics, collected from the first partition
+  val nativeExplain: Option[CollectionAccumulator[String]] =
+    if (rddSparkContext.conf.getBoolean("spark.blaze.nativeExplain.enabled", false)) {
+      Some(rddSparkContext.collectionAccumulator[String]("Native explain"))
+    } else {
+      None
+    }
+
+  // accumulators of native counters, shipped to the tasks with the RDD
+  val nativeAccumulators: Map[String, LongAccumulator] = NativeAccumulators.registeredAccumulators
+
   override protected def getPartitions: Array[Partition] = rddPartitions
   override protected def getDependencies: Seq[Dependency[_]] = rddDependencies
 
   override def compute(split: Partition, context: TaskContext): Iterator[InternalRow] = {
+    NativeAccumulators.bindTask(context, nativeAccumulators)
     val computingNativePlan = nativePlan(split, context)
-    NativeSupports.executeNativePlan(computingNativePlan, metrics, split, context)
+    NativeSupports.executeNativePlan(
+      computingNativePlan,
+      metrics,
+      split,
+      context,
+      nativeExplain)
   }
 
   def toColumnar: RDD[ColumnarBatch] =
@@ -49,8 +68,14 @@ class NativeRDD(
       override protected def getDependencies: Seq[Dependency[_]] = rddDependencies
 
       override def compute(split: Partition, context: TaskContext): Iterator[ColumnarBatch] = {
+        NativeAccumulators.bindTask(context, nativeAccumulators)
         val computingNativePlan = nativePlan(split, context)
-        NativeSupports.executeNativePlanColumnar(computingNativePlan, metrics, split, context)
+        NativeSupports.executeNativePlanColumnar(
+          computingNativePlan,
+          metrics,
+          split,
+          context,
+          nativeExplain)
       }
     }
 }
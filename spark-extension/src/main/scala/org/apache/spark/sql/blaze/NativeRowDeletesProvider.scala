@@ -0,0 +1,50 @@
+/*
+ * Copyright 2022 The Blaze Authors
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+package org.apache.spark.sql.blaze
+
+import org.apache.spark.SparkEnv
+import org.apache.spark.internal.Logging
+import org.apache.spark.sql.execution.datasources.HadoopFsRelation
+import org.apache.spark.util.Utils
+import org.blaze.protobuf.RowDeletes
+
+/**
+ * Provides rows deleted from data files of lakehouse tables, which are removed by positions when
+ * the files are scanned natively, e.g. positions decoded from delta deletion vectors, or iceberg
+ * positional delete files referencing the data file.
+ *
+ * implemented by integrations of table formats, which depend on their readers, and configured
+ * by spark.blaze.parquet.rowDeletesProvider with the class name. the class must have a no-arg
+ * constructor. it is called on driver side when planning native parquet scans.
+ */
+trait NativeRowDeletesProvider {
+
+  /** returns rows deleted from the data file of the relation, None if no rows are deleted */
+  def getRowDeletes(relation: HadoopFsRelation, filePath: String): Option[RowDeletes]
+}
+
+object NativeRowDeletesProvider extends Logging {
+  lazy val provider: Option[NativeRowDeletesProvider] =
+    SparkEnv.get.conf.getOption("spark.blaze.parquet.rowDeletesProvider").map { className =>
+      logInfo(s"Using native row deletes provider: $className")
+      Utils
+        .classForName(className)
+        .getConstructor()
+        .newInstance()
+        .asInstanceOf[NativeRowDeletesProvider]
+    }
+}
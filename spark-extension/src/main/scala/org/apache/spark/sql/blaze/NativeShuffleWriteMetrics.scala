@@ -0,0 +1,64 @@
+/*
+ * Copyright 2022 The Blaze Authors
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+package org.apache.spark.sql.blaze
+
+import org.apache.spark.executor.TaskMetrics
+import org.apache.spark.shuffle.ShuffleWriteMetricsReporter
+
+/**
+ * Receives metric deltas pushed by native shuffle writer during execution, and updates spark's
+ * shuffle write metrics and task-level spill metrics.
+ *
+ * @param countBytesWritten false if bytes written are already reported by the shuffle output
+ *                          (e.g. a remote shuffle service writer)
+ */
+class NativeShuffleWriteMetrics(
+    writeMetrics: ShuffleWriteMetricsReporter,
+    taskMetrics: TaskMetrics,
+    countBytesWritten: Boolean = true) {
+
+  private var peakMemUsed = 0L
+
+  /** called by native side, all values except memUsed are deltas since last call */
+  def update(
+      bytesWritten: Long,
+      recordsWritten: Long,
+      writeTime: Long,
+      memoryBytesSpilled: Long,
+      diskBytesSpilled: Long,
+      memUsed: Long): Unit = synchronized {
+    if (countBytesWritten && bytesWritten > 0) {
+      writeMetrics.incBytesWritten(bytesWritten)
+    }
+    if (recordsWritten > 0) {
+      writeMetrics.incRecordsWritten(recordsWritten)
+    }
+    if (writeTime > 0) {
+      writeMetrics.incWriteTime(writeTime)
+    }
+    if (memoryBytesSpilled > 0) {
+      taskMetrics.incMemoryBytesSpilled(memoryBytesSpilled)
+    }
+    if (diskBytesSpilled > 0) {
+      taskMetrics.incDiskBytesSpilled(diskBytesSpilled)
+    }
+    if (memUsed > peakMemUsed) {
+      taskMetrics.incPeakExecutionMemory(memUsed - peakMemUsed)
+      peakMemUsed = memUsed
+    }
+  }
+}
@@ -17,6 +17,7 @@
 package org.apache.spark.sql.blaze
 
 import java.io.{File, FileNotFoundException, IOException}
+import java.nio.ByteBuffer
 import java.nio.file.{Files, StandardCopyOption}
 import java.util.concurrent.SynchronousQueue
 import java.util.concurrent.TimeUnit
@@ -24,17 +25,24 @@ import java.util.concurrent.atomic.AtomicBoolean
 import java.util.UUID
 
 import scala.annotation.tailrec
+import scala.collection.JavaConverters._
 import scala.collection.immutable.TreeMap
+import scala.util.control.NonFatal
 
 import org.apache.spark.sql.execution.SparkPlan
+import org.apache.spark.MapOutputTrackerMaster
+import org.apache.spark.ShuffleDependency
 import org.apache.spark.SparkException
 import org.apache.spark.sql.catalyst.InternalRow
 import org.apache.spark.sql.execution.adaptive.CustomShuffleReaderExec
 import org.apache.spark.sql.execution.adaptive.QueryStageExec
+import org.apache.spark.sql.execution.adaptive.ShuffleQueryStageExec
 import org.apache.spark.TaskContext
 import org.apache.spark.internal.Logging
 import org.apache.spark.sql.execution.metric.SQLMetric
+import org.apache.spark.SparkConf
 import org.apache.spark.SparkEnv
+import org.apache.spark.sql.execution.exchange.Exchange
 import org.apache.spark.sql.execution.exchange.ReusedExchangeExec
 import org.apache.spark.Partition
 import org.apache.spark.sql.vectorized.ColumnarBatch
@@ -44,19 +52,32 @@ import org.apache.spark.sql.blaze.execution.ArrowBlockStoreShuffleReader301
 import org.apache.spark.sql.catalyst.expressions.Attribute
 import org.apache.spark.sql.execution.metric.SQLMetrics
 import org.apache.spark.sql.execution.CoalescedPartitionSpec
+import org.apache.spark.sql.execution.PartialReducerPartitionSpec
 import org.apache.spark.sql.execution.ShuffledRowRDD
 import org.apache.spark.sql.execution.ShufflePartitionSpec
 import org.apache.spark.sql.types.StructField
 import org.apache.spark.sql.types.StructType
+import org.apache.spark.util.CollectionAccumulator
+import org.blaze.protobuf.EmptyPartitionsExecNode
+import org.blaze.protobuf.MapRange
+import org.blaze.protobuf.NativeFeature
 import org.blaze.protobuf.PartitionId
 import org.blaze.protobuf.PhysicalPlanNode
+import org.blaze.protobuf.PlanValidationResult
+import org.blaze.protobuf.ReduceRange
+import org.blaze.protobuf.ReusedExchangeExecNode
 import org.blaze.protobuf.Schema
 import org.blaze.protobuf.ShuffleReaderExecNode
+import org.blaze.protobuf.Statistics
 import org.blaze.protobuf.TaskDefinition
+import org.blaze.protobuf.UnsupportedItem
 
 trait NativeSupports extends SparkPlan {
   def doExecuteNative(): NativeRDD
 
+  /** executes the native plan after preparing it and waiting for results of its subqueries */
+  final def executeNativeQuery(): NativeRDD = executeQuery(doExecuteNative())
+
   protected override def doExecute(): RDD[InternalRow] = doExecuteNative()
   protected override def doExecuteColumnar(): RDD[ColumnarBatch] = doExecuteNative().toColumnar
 }
@@ -85,20 +106,60 @@ object NativeSupports extends Logging {
   @tailrec
   def executeNative(plan: SparkPlan): NativeRDD =
     plan match {
-      case plan: NativeSupports => plan.doExecuteNative()
+      case plan: NativeSupports with Exchange if reuseExchangeEnabled =>
+        executeNativeExchange(plan)
+      case plan: NativeSupports => plan.executeNativeQuery()
       case plan: CustomShuffleReaderExec => executeNativeCustomShuffleReader(plan, plan.output)
       case plan: QueryStageExec => executeNative(plan.plan)
       case plan: ReusedExchangeExec => executeNative(plan.child)
       case _ => throw new SparkException(s"Underlying plan is not NativeSupports: ${plan}")
     }
 
+  private def reuseExchangeEnabled: Boolean =
+    SparkEnv.get.conf.getBoolean("spark.blaze.reuseExchange.enabled", true)
+
+  // references of the same exchange in a native plan (e.g. the original exchange and its
+  // ReusedExchangeExec) share the same native input, which is computed only once
+  private def executeNativeExchange(exchange: NativeSupports with Exchange): NativeRDD = {
+    val inputRDD = exchange.doExecuteNative()
+    val exchangeId = exchange.id
+
+    new NativeRDD(
+      inputRDD.sparkContext,
+      MetricNode(Map(), Seq(inputRDD.metrics)),
+      inputRDD.partitions,
+      inputRDD.dependencies,
+      (partition, taskContext) => {
+        val inputPartition = inputRDD.partitions(partition.index)
+        PhysicalPlanNode
+          .newBuilder()
+          .setReusedExchange(
+            ReusedExchangeExecNode
+              .newBuilder()
+              .setInput(inputRDD.nativePlan(inputPartition, taskContext))
+              .setExchangeId(s"exchange=$exchangeId:taskAttempt=${taskContext.taskAttemptId()}")
+              .build())
+          .build()
+      })
+  }
+
   def executeNativePlan(
       nativePlan: PhysicalPlanNode,
       metrics: MetricNode,
       partition: Partition,
-      context: TaskContext): Iterator[InternalRow] = {
-
-    val wrapper = BlazeCallNativeWrapper(nativePlan, partition, context, metrics)
+      context: TaskContext,
+      nativeExplain: Option[CollectionAccumulator[String]] = None): Iterator[InternalRow] = {
+
+    // let native side convert output batches into UnsafeRows
+    val rowOutputRequested =
+      SparkEnv.get.conf.getBoolean("spark.blaze.nativeColumnarToRow.enabled", true)
+    val wrapper = BlazeCallNativeWrapper(
+      nativePlan,
+      partition,
+      context,
+      metrics,
+      rowOutputRequested,
+      nativeExplain)
     FFIHelper.fromBlazeCallNative(wrapper, context)
   }
 
@@ -106,21 +167,164 @@ object NativeSupports extends Logging {
       nativePlan: PhysicalPlanNode,
       metrics: MetricNode,
       partition: Partition,
-      context: TaskContext): Iterator[ColumnarBatch] = {
-
-    val wrapper = BlazeCallNativeWrapper(nativePlan, partition, context, metrics)
+      context: TaskContext,
+      nativeExplain: Option[CollectionAccumulator[String]] = None): Iterator[ColumnarBatch] = {
+
+    // native plans only projecting JVM inputs are polled directly in the task thread, through
+    // an arrow stream if enabled
+    if (batchIteratorEnabled && nativeExplain.isEmpty && isProjectOnly(nativePlan)) {
+      if (batchStreamEnabled) {
+        return FFIHelper.fromNativeBatchStream(nativePlan, metrics, partition, context)
+      }
+      return FFIHelper.fromNativeBatchIterator(nativePlan, metrics, partition, context)
+    }
+    val wrapper = BlazeCallNativeWrapper(
+      nativePlan,
+      partition,
+      context,
+      metrics,
+      nativeExplain = nativeExplain)
     FFIHelper.fromBlazeCallNativeColumnar(wrapper, context)
   }
 
+  private def batchIteratorEnabled: Boolean =
+    SparkEnv.get.conf.getBoolean("spark.blaze.batchIterator.enabled", true)
+
+  private def batchStreamEnabled: Boolean =
+    SparkEnv.get.conf.getBoolean("spark.blaze.batchStream.enabled", false) &&
+      BlazeCallNativeWrapper.isNativeFeatureSupported(NativeFeature.BATCH_STREAM)
+
+  /** returns true if the native plan only projects (and renames) rows of a JVM input */
+  @tailrec
+  def isProjectOnly(nativePlan: PhysicalPlanNode): Boolean =
+    nativePlan.getPhysicalPlanTypeCase match {
+      case PhysicalPlanNode.PhysicalPlanTypeCase.PROJECTION =>
+        isProjectOnly(nativePlan.getProjection.getInput)
+      case PhysicalPlanNode.PhysicalPlanTypeCase.RENAME_COLUMNS =>
+        isProjectOnly(nativePlan.getRenameColumns.getInput)
+      case PhysicalPlanNode.PhysicalPlanTypeCase.JVM_TO_NATIVE => true
+      case PhysicalPlanNode.PhysicalPlanTypeCase.ROW_INPUT => true
+      case PhysicalPlanNode.PhysicalPlanTypeCase.FFI_READER => true
+      case PhysicalPlanNode.PhysicalPlanTypeCase.FFI_STREAM_READER => true
+      case _ => false
+    }
+
+  /**
+   * converts the native plan on native side without executing it, and logs every unsupported
+   * operator/expression with the reason
+   *
+   * @return all unsupported items, empty if the plan can be executed natively
+   */
+  def validateNativePlan(nativePlan: PhysicalPlanNode): Seq[UnsupportedItem] = {
+    BlazeCallNativeWrapper.loadNative()
+    // the plan is only converted, not executed by any task
+    val taskDefinition = TaskDefinition
+      .newBuilder()
+      .setTaskId(PartitionId.getDefaultInstance)
+      .setPlan(nativePlan)
+      .setPlanVersion(BlazeCallNativeWrapper.nativePlanVersion)
+      .build()
+    val result =
+      PlanValidationResult.parseFrom(JniBridge.validateTaskDefinition(taskDefinition.toByteArray))
+
+    val unsupported = result.getUnsupportedList.asScala
+    unsupported.foreach(item => logWarning(s"Native plan unsupported at ${describe(item)}"))
+    unsupported
+  }
+
+  private def describe(item: UnsupportedItem): String = {
+    val expr = if (item.getExpression.nonEmpty) s" expression ${item.getExpression}" else ""
+    s"${item.getPath} (${item.getOperator})$expr: ${item.getReason}"
+  }
+
+  private def validateWhenPlanning: Boolean =
+    SparkEnv.get.conf.getBoolean("spark.blaze.validateNativePlan.enabled", true)
+
+  /**
+   * validates a native operator with exprs converted when planning, by converting it over an
+   * empty input of the child output on native side. throws NotImplementedError with all
+   * unsupported items, so that the operator falls back with them as the reason reported by
+   * PlanConversionTelemetry
+   */
+  def checkNativeSupported(childOutput: Seq[Attribute])(
+      nativePlan: PhysicalPlanNode => PhysicalPlanNode): Unit = {
+    if (!validateWhenPlanning) {
+      return
+    }
+    val inputSchema = NativeConverters.convertSchema(StructType(childOutput.map(a =>
+      StructField(a.toString(), a.dataType, a.nullable, a.metadata))))
+    val input = PhysicalPlanNode
+      .newBuilder()
+      .setEmptyPartitions(
+        EmptyPartitionsExecNode.newBuilder().setSchema(inputSchema).setNumPartitions(1))
+      .build()
+
+    val unsupported =
+      try {
+        validateNativePlan(nativePlan(input))
+      } catch {
+        case NonFatal(e) =>
+          logWarning("Error validating native plan, assuming it is supported", e)
+          Nil
+      }
+    if (unsupported.nonEmpty) {
+      throw new NotImplementedError(
+        s"unsupported by native engine: ${unsupported.map(describe).mkString("; ")}")
+    }
+  }
+
+  /**
+   * returns the native plan executed by the rdd, annotated with runtime metrics of each
+   * native operator. only available after execution if spark.blaze.nativeExplain.enabled
+   */
+  def getNativeExplain(rdd: NativeRDD): Option[String] =
+    rdd.nativeExplain.flatMap(_.value.asScala.headOption)
+
   def getDefaultNativeMetrics(sc: SparkContext): Map[String, SQLMetric] =
     TreeMap(
       "output_rows" -> SQLMetrics.createMetric(sc, "Native.output_rows"),
       "output_batches" -> SQLMetrics.createMetric(sc, "Native.output_batches"),
       "input_rows" -> SQLMetrics.createMetric(sc, "Native.input_rows"),
       "input_batches" -> SQLMetrics.createMetric(sc, "Native.input_batches"),
+      "output_bytes" -> SQLMetrics.createSizeMetric(sc, "Native.output_bytes"),
+      "peak_mem_used" -> SQLMetrics.createSizeMetric(sc, "Native.peak_mem_used"),
       "elapsed_compute" -> SQLMetrics.createNanoTimingMetric(sc, "Native.elapsed_compute"),
       "join_time" -> SQLMetrics.createNanoTimingMetric(sc, "Native.join_time"))
 
+  /**
+   * returns sizes of all reduce partitions of the shuffle if all its map outputs are available,
+   * e.g. of materialized query stages. only available on the driver.
+   */
+  def getShufflePartitionSizes(dependency: ShuffleDependency[_, _, _]): Option[Array[Long]] = {
+    val numMappers = dependency.rdd.getNumPartitions
+    SparkEnv.get.mapOutputTracker match {
+      case tracker: MapOutputTrackerMaster
+          if numMappers > 0 && tracker.getNumAvailableOutputs(dependency.shuffleId) == numMappers =>
+        Some(tracker.getStatistics(dependency).bytesByPartitionId)
+      case _ => None
+    }
+  }
+
+  /**
+   * estimates statistics of reading reduce partitions [start, end) of a shuffle, rows are
+   * estimated in proportion to the sizes of the partitions
+   */
+  def buildShuffleReadStatistics(
+      partitionSizes: Array[Long],
+      totalRows: Long,
+      startReducerIndex: Int,
+      endReducerIndex: Int): Statistics = {
+    val totalBytes = partitionSizes.sum
+    val bytes = partitionSizes.slice(startReducerIndex, endReducerIndex).sum
+    val rows = if (totalBytes > 0) (BigInt(totalRows) * bytes / totalBytes).toLong else 0L
+    Statistics
+      .newBuilder()
+      .setNumRows(rows)
+      .setTotalByteSize(bytes)
+      .setIsExact(false)
+      .build()
+  }
+
   private def executeNativeCustomShuffleReader(
       exec: CustomShuffleReaderExec,
       output: Seq[Attribute]): NativeRDD = {
@@ -133,6 +337,11 @@ object NativeSupports extends Logging {
         val nativeSchema: Schema = NativeConverters.convertSchema(StructType(output.map(a =>
           StructField(a.toString(), a.dataType, a.nullable, a.metadata))))
         val metrics = MetricNode(Map(), Seq(inputRDD.metrics))
+        val partitionSizes = getShufflePartitionSizes(inputShuffledRowRDD.dependency)
+        val totalRows = exec.child match {
+          case stage: ShuffleQueryStageExec => stage.shuffle.runtimeStatistics.rowCount
+          case _ => None
+        }
 
         new NativeRDD(
           inputShuffledRowRDD.sparkContext,
@@ -150,33 +359,91 @@ object NativeSupports extends Logging {
 
             spec match {
               case CoalescedPartitionSpec(startReducerIndex, endReducerIndex) =>
-                // store fetch iterator in jni resource before native compute
+                // store fetch iterator provider of reduce ranges in jni resource before native
+                // compute, the native reader reads segments of each coalesced range
                 val jniResourceId = s"NativeShuffleReadExec:${UUID.randomUUID().toString}"
                 JniBridge.resourcesMap.put(
                   jniResourceId,
-                  () => {
+                  (startReduceId: java.lang.Long, endReduceId: java.lang.Long) => {
                     val shuffleManager = SparkEnv.get.shuffleManager
                     shuffleManager
                       .getReader(
                         shuffleHandle,
-                        startReducerIndex,
-                        endReducerIndex,
+                        startReduceId.toInt,
+                        endReduceId.toInt,
+                        taskContext,
+                        taskContext.taskMetrics().createTempShuffleReadMetrics())
+                      .asInstanceOf[ArrowBlockStoreShuffleReader301[_, _]]
+                      .readIpc()
+                  })
+
+                val shuffleReader = ShuffleReaderExecNode
+                  .newBuilder()
+                  .setSchema(nativeSchema)
+                  .setNumPartitions(inputShuffledRowRDD.getNumPartitions)
+                  .setNativeShuffleId(jniResourceId)
+                  .addReduceRanges(
+                    ReduceRange
+                      .newBuilder()
+                      .setStartReduceId(startReducerIndex)
+                      .setEndReduceId(endReducerIndex))
+                for (sizes <- partitionSizes; rows <- totalRows) {
+                  shuffleReader.setStatistics(
+                    buildShuffleReadStatistics(
+                      sizes,
+                      rows.toLong,
+                      startReducerIndex,
+                      endReducerIndex))
+                }
+                PhysicalPlanNode.newBuilder().setShuffleReader(shuffleReader.build()).build()
+
+              case PartialReducerPartitionSpec(reducerIndex, startMapIndex, endMapIndex) =>
+                // a split of a skewed partition in skew joins, only outputs of the mapper range
+                // are fetched
+                val jniResourceId = s"NativeShuffleReadExec:${UUID.randomUUID().toString}"
+                JniBridge.resourcesMap.put(
+                  jniResourceId,
+                  (
+                      reduceId: java.lang.Long,
+                      startMapId: java.lang.Long,
+                      endMapId: java.lang.Long) => {
+                    val shuffleManager = SparkEnv.get.shuffleManager
+                    shuffleManager
+                      .getReaderForRange(
+                        shuffleHandle,
+                        startMapId.toInt,
+                        endMapId.toInt,
+                        reduceId.toInt,
+                        reduceId.toInt + 1,
                         taskContext,
                         taskContext.taskMetrics().createTempShuffleReadMetrics())
                       .asInstanceOf[ArrowBlockStoreShuffleReader301[_, _]]
                       .readIpc()
                   })
 
-                PhysicalPlanNode
+                val shuffleReader = ShuffleReaderExecNode
                   .newBuilder()
-                  .setShuffleReader(
-                    ShuffleReaderExecNode
+                  .setSchema(nativeSchema)
+                  .setNumPartitions(inputShuffledRowRDD.getNumPartitions)
+                  .setNativeShuffleId(jniResourceId)
+                  .addMapRanges(
+                    MapRange
                       .newBuilder()
-                      .setSchema(nativeSchema)
-                      .setNumPartitions(inputShuffledRowRDD.getNumPartitions)
-                      .setNativeShuffleId(jniResourceId)
-                      .build())
-                  .build()
+                      .setReduceId(reducerIndex)
+                      .setStartMapIndex(startMapIndex)
+                      .setEndMapIndex(endMapIndex))
+                for (sizes <- partitionSizes; rows <- totalRows) {
+                  // mappers are assumed to output even parts of the skewed partition
+                  val numMappers = inputShuffledRowRDD.dependency.rdd.getNumPartitions
+                  val partitionStatistics =
+                    buildShuffleReadStatistics(sizes, rows.toLong, reducerIndex, reducerIndex + 1)
+                  val fraction = (endMapIndex - startMapIndex).toDouble / numMappers
+                  shuffleReader.setStatistics(
+                    partitionStatistics.toBuilder
+                      .setNumRows((partitionStatistics.getNumRows * fraction).toLong)
+                      .setTotalByteSize((partitionStatistics.getTotalByteSize * fraction).toLong))
+                }
+                PhysicalPlanNode.newBuilder().setShuffleReader(shuffleReader.build()).build()
 
               case unsupported =>
                 throw new NotImplementedError(
@@ -205,31 +472,46 @@ case class BlazeCallNativeWrapper(
     nativePlan: PhysicalPlanNode,
     partition: Partition,
     context: TaskContext,
-    metrics: MetricNode)
+    metrics: MetricNode,
+    rowOutputRequested: Boolean = false,
+    nativeExplain: Option[CollectionAccumulator[String]] = None,
+    partitionIds: Seq[Int] = Nil)
     extends Logging {
 
   private val valueQueue: SynchronousQueue[Object] = new SynchronousQueue()
   private val errorQueue: SynchronousQueue[Object] = new SynchronousQueue()
   private val finished: AtomicBoolean = new AtomicBoolean(false)
 
-  BlazeCallNativeWrapper.synchronized {
-    val conf = SparkEnv.get.conf
-    val batchSize = conf.getLong("spark.blaze.batchSize", 16384);
-    val nativeMemory = conf.getLong("spark.executor.memoryOverhead", Long.MaxValue) * 1024 * 1024;
-    val memoryFraction = conf.getDouble("spark.blaze.memoryFraction", 0.75);
-    val tmpDirs = SparkEnv.get.blockManager.diskBlockManager.localDirsString.mkString(",")
-
-    if (!BlazeCallNativeWrapper.nativeInitialized) {
-      logInfo(s"Initializing native environment ...")
-      BlazeCallNativeWrapper.load("blaze")
-      JniBridge.initNative(batchSize, nativeMemory, memoryFraction, tmpDirs)
-      BlazeCallNativeWrapper.nativeInitialized = true
+  // number of fields of UnsafeRows produced by native side, -1 if native side
+  // produces arrow batches. decided by native side in callNative()
+  private var rowOutputNumFields: Int = -1
+  private var outputRows: Array[Byte] = _
+
+  // if partitionIds are specified, these partitions of the native plan are executed
+  // concurrently and each output batch is tagged with the partition producing it
+  private var outputPartitionId: Int = partitionIds.headOption.getOrElse(partition.index)
+
+  // native execution is retried with a smaller batch size if native memory is overcommitted
+  // before any output is produced. 0 means the default batch size is used
+  private var batchSize: Long = 0
+  private var numRetries: Int = 0
+  private var outputProduced: Boolean = false
+
+  BlazeCallNativeWrapper.initNative(context)
+
+  // one explain is enough for the stage, so it is only collected from the first partition
+  private val nativeExplainRequested = nativeExplain.isDefined && partition.index == 0
+  if (nativeExplainRequested) {
+    context.addTaskCompletionListener[Unit] { _ =>
+      Option(JniBridge.takeNativeExplain(context.stageId(), partition.index))
+        .foreach(nativeExplain.get.add)
     }
   }
 
   logInfo(s"Start executing native plan")
   JniBridge.callNative(this)
 
+  def isNativeExplainRequested: Boolean = nativeExplainRequested
   def isFinished: Boolean = finished.get()
   def finish(): Unit = {
     if (!isFinished) {
@@ -237,30 +519,49 @@ case class BlazeCallNativeWrapper(
     }
   }
 
+  def isRowOutput: Boolean = rowOutputNumFields >= 0
+  def getRowOutputNumFields: Int = rowOutputNumFields
+
   protected def getMetrics: MetricNode = metrics
+  protected def isRowOutputRequested: Boolean = rowOutputRequested
+  protected def enableRowOutput(numFields: Int): Unit = {
+    rowOutputNumFields = numFields
+  }
 
-  protected def getRawTaskDefinition: Array[Byte] = {
-    // do not use context.partitionId since it is not correct in Union plans.
-    val partitionId: PartitionId = PartitionId
-      .newBuilder()
-      .setPartitionId(partition.index)
-      .setStageId(context.stageId())
-      .setJobId(partition.index.toString)
-      .build()
+  // called by native side with size-prefixed UnsafeRows of an output batch.
+  // the buffer points to native memory and must be copied before returning
+  protected def setOutputRows(buffer: ByteBuffer): Unit = {
+    val rows = new Array[Byte](buffer.remaining())
+    buffer.get(rows)
+    outputRows = rows
+  }
 
-    val taskDefinition = TaskDefinition
-      .newBuilder()
-      .setTaskId(partitionId)
-      .setPlan(nativePlan)
-      .build()
-    taskDefinition.toByteArray
+  // called by native side before each output batch of multiple partitions
+  protected def setOutputPartitionId(partitionId: Int): Unit = {
+    outputPartitionId = partitionId
   }
 
+  /** partition of the native plan producing the latest output batch */
+  def getOutputPartitionId: Int = outputPartitionId
+
+  protected def getBatchSize: Long = batchSize
+
+  // bytes of output batches the native side may compute ahead of consuming, 0 to disable
+  protected def getOutputPrefetchBytes: Long =
+    SparkEnv.get.conf.getSizeAsBytes("spark.blaze.outputPrefetchBytes", "0")
+
+  protected def getRawTaskDefinition: Array[Byte] =
+    BlazeCallNativeWrapper
+      .buildTaskDefinition(nativePlan, partition, context, partitionIds)
+      .toByteArray
+
   def nextBatch(schemaPtr: Long, arrayPtr: Long): Boolean = {
-    while (!isFinished && { checkError(); true } && !enqueueWithTimeout((schemaPtr, arrayPtr))) {}
-    while (!isFinished && { checkError(); true }) {
+    while (!isFinished && { checkError(); checkInterrupted(); true } &&
+      !enqueueWithTimeout((schemaPtr, arrayPtr))) {}
+    while (!isFinished && { checkError(); checkInterrupted(); true }) {
       dequeueWithTimeout() match {
         case java.lang.Boolean.TRUE =>
+          outputProduced = true
           return true
 
         case java.lang.Boolean.FALSE =>
@@ -274,6 +575,16 @@ case class BlazeCallNativeWrapper(
     !isFinished
   }
 
+  // returns size-prefixed UnsafeRows of the next output batch, or null if finished
+  def nextRows(): Array[Byte] = {
+    if (!nextBatch(0, 0)) {
+      return null
+    }
+    val rows = outputRows
+    outputRows = null
+    rows
+  }
+
   protected def enqueueWithTimeout(value: Object): Boolean = {
     valueQueue.offer(value, 100, TimeUnit.MILLISECONDS)
   }
@@ -289,16 +600,189 @@ case class BlazeCallNativeWrapper(
   protected def checkError(): Unit = {
     errorQueue.poll() match {
       case e: Throwable =>
-        finish()
-        throw e
+        BlazeMemoryConsumer.takeOvercommitReport(context.taskAttemptId()) match {
+          case Some(_) if !outputProduced && numRetries < BlazeCallNativeWrapper.maxRetries =>
+            retryWithSmallerBatchSize()
+          case Some(report) =>
+            finish()
+            throw new RuntimeException(s"native memory overcommitted: $report", e)
+          case None =>
+            finish()
+            e match {
+              // shuffle read failures are reported as fetch failures of map outputs
+              case e: BlazeShuffleReadException => throw e.toFetchFailure
+              case e => throw e
+            }
+        }
       case null =>
       // do nothing
     }
   }
+
+  // the native execution is cancelled as soon as the task is killed, instead of running until
+  // its next JNI call notices the interruption
+  private def checkInterrupted(): Unit = {
+    if (context.isInterrupted()) {
+      JniBridge.cancelNative(context.taskAttemptId())
+      finish()
+      context.killTaskIfInterrupted()
+    }
+  }
+
+  private def retryWithSmallerBatchSize(): Unit = {
+    val currentBatchSize =
+      if (batchSize > 0) batchSize else SparkEnv.get.conf.getLong("spark.blaze.batchSize", 16384)
+    batchSize = math.max(currentBatchSize / 2, 1)
+    numRetries += 1
+    logWarning(s"Retrying native plan with batchSize=$batchSize ($numRetries time(s))")
+    JniBridge.callNative(this)
+  }
 }
 
-object BlazeCallNativeWrapper {
+object BlazeCallNativeWrapper extends Logging {
   private var nativeInitialized: Boolean = false
+  private var nativeLoaded: Boolean = false
+  private var nativeFeatures: Long = 0L
+  private val maxRetries: Int = 3
+
+  /**
+   * version of the plan protocol task definitions are serialized with, must match PLAN_VERSION
+   * of the native library
+   */
+  val nativePlanVersion = 1
+
+  /** prefix of spark confs and session confs setting native tunables */
+  val nativeConfPrefix = "spark.blaze.native."
+
+  /**
+   * native memory is the memory overhead of executors, derived the same way as spark does if not
+   * set explicitly
+   */
+  private def executorMemoryOverheadMb(conf: SparkConf): Long =
+    if (conf.contains("spark.executor.memoryOverhead")) {
+      conf.getSizeAsMb("spark.executor.memoryOverhead")
+    } else {
+      val executorMemoryMb = conf.getSizeAsMb("spark.executor.memory", "1g")
+      math.max((executorMemoryMb * 0.1).toLong, 384L)
+    }
+
+  /**
+   * initializes the native environment on first call, and registers cleaning up native
+   * executions of the task when the task completes
+   */
+  def initNative(context: TaskContext): Unit = {
+    synchronized {
+      val conf = SparkEnv.get.conf
+      val batchSize = conf.getLong("spark.blaze.batchSize", 16384);
+      val nativeMemory = executorMemoryOverheadMb(conf) * 1024 * 1024
+      val memoryFraction = conf.getDouble("spark.blaze.memoryFraction", 0.75);
+      val tmpDirs = SparkEnv.get.blockManager.diskBlockManager.localDirsString.mkString(",")
+      val profilingEnabled = conf.getBoolean("spark.blaze.profiling.enabled", false)
+
+      if (!nativeInitialized) {
+        logInfo(s"Initializing native environment ...")
+        loadNative()
+        JniBridge.initNative(batchSize, nativeMemory, memoryFraction, tmpDirs, profilingEnabled)
+
+        // initial native tunables, e.g. spark.blaze.native.utf8_validation=replace
+        val nativeConf = conf.getAllWithPrefix(nativeConfPrefix).toMap
+        if (nativeConf.nonEmpty) {
+          JniBridge.updateConfig(nativeConf.asJava)
+        }
+        nativeInitialized = true
+      }
+    }
+
+    // native executions still running, e.g. if the output is not fully consumed, are cancelled
+    // and their spill files deleted when the task completes. native counters are flushed into
+    // the accumulators bound to the task
+    context.addTaskCompletionListener[Unit] { _ =>
+      JniBridge.cancelNative(context.taskAttemptId())
+      JniBridge.flushNativeCounters(context.taskAttemptId())
+      JniBridge.releaseTaskSpills(context.taskAttemptId())
+    }
+  }
+
+  def buildTaskDefinition(
+      nativePlan: PhysicalPlanNode,
+      partition: Partition,
+      context: TaskContext,
+      partitionIds: Seq[Int] = Nil): TaskDefinition = {
+    // do not use context.partitionId since it is not correct in Union plans.
+    val partitionId: PartitionId = PartitionId
+      .newBuilder()
+      .setPartitionId(partition.index)
+      .setStageId(context.stageId())
+      .setJobId(partition.index.toString)
+      .build()
+
+    // native tunables set in the spark session, e.g. by SET spark.blaze.native.batch_size=4096,
+    // are propagated by spark to tasks as local properties
+    val sessionNativeConf = context.getLocalProperties.asScala.collect {
+      case (key, value) if key.startsWith(nativeConfPrefix) =>
+        (key.stripPrefix(nativeConfPrefix), value)
+    }
+
+    val taskDefinition = TaskDefinition
+      .newBuilder()
+      .setTaskId(partitionId)
+      .setPlan(nativePlan)
+      .setPlanVersion(nativePlanVersion)
+      .putAllConf(sessionNativeConf.asJava)
+      .addAllPartitionIds(partitionIds.map(Integer.valueOf).asJava)
+      .build()
+    dumpTaskDefinition(context, taskDefinition)
+    taskDefinition
+  }
+
+  // task definitions are dumped as fixtures of the native TPC-DS correctness harness
+  private lazy val taskDefinitionDumpDir: Option[String] =
+    SparkEnv.get.conf.getOption("spark.blaze.dumpTaskDefinitions.dir")
+
+  /**
+   * writes the task definition to execution-<sql execution id>/stage-<stage>-<partition>.task
+   * under spark.blaze.dumpTaskDefinitions.dir, if set
+   */
+  def dumpTaskDefinition(context: TaskContext, taskDefinition: TaskDefinition): Unit = {
+    taskDefinitionDumpDir.foreach { dir =>
+      val executionId =
+        Option(context.getLocalProperty("spark.sql.execution.id")).getOrElse("unknown")
+      val executionDir = new File(dir, s"execution-$executionId")
+      executionDir.mkdirs()
+      val taskId = taskDefinition.getTaskId
+      val file = new File(executionDir, s"stage-${taskId.getStageId}-${taskId.getPartitionId}.task")
+      Files.write(file.toPath, taskDefinition.toByteArray)
+    }
+  }
+
+  /** loads native library without initializing native environment, e.g. for validating plans */
+  /**
+   * loads the native library, failing fast if it is built with another plan protocol than the
+   * spark extension
+   */
+  def loadNative(): Unit = synchronized {
+    if (!nativeLoaded) {
+      load("blaze")
+      val nativeVersion =
+        try {
+          JniBridge.getNativeVersion()
+        } catch {
+          case _: UnsatisfiedLinkError => 0
+        }
+      if (nativeVersion != nativePlanVersion) {
+        throw new IllegalStateException(
+          s"native library of plan version $nativeVersion does not match spark extension of " +
+            s"plan version $nativePlanVersion, check that both are built from the same release")
+      }
+      nativeFeatures = JniBridge.supportedFeatures()
+      nativeLoaded = true
+    }
+  }
+
+  def isNativeFeatureSupported(feature: NativeFeature): Boolean = {
+    loadNative()
+    (nativeFeatures & (1L << feature.getNumber)) != 0
+  }
 
   private def load(name: String): Unit = {
     val libraryToLoad = System.mapLibraryName(name)
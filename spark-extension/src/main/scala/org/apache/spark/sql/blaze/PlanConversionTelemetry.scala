@@ -0,0 +1,190 @@
+/*
+ * Copyright 2022 The Blaze Authors
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+package org.apache.spark.sql.blaze
+
+import java.util.concurrent.ConcurrentHashMap
+import java.util.concurrent.atomic.AtomicBoolean
+
+import scala.collection.JavaConverters._
+import scala.collection.mutable
+import scala.util.control.NonFatal
+
+import org.apache.spark.SparkContext
+import org.apache.spark.SparkEnv
+import org.apache.spark.internal.Logging
+import org.apache.spark.scheduler.SparkListener
+import org.apache.spark.scheduler.SparkListenerEvent
+import org.apache.spark.sql.SparkSession
+import org.apache.spark.sql.blaze.plan.NativeRenameColumnsExec
+import org.apache.spark.sql.catalyst.trees.TreeNodeTag
+import org.apache.spark.sql.execution.SQLExecution
+import org.apache.spark.sql.execution.SparkPlan
+import org.apache.spark.sql.execution.adaptive.CustomShuffleReaderExec
+import org.apache.spark.sql.execution.adaptive.QueryStageExec
+import org.apache.spark.sql.execution.exchange.ReusedExchangeExec
+import org.apache.spark.sql.execution.ui.SparkListenerSQLExecutionEnd
+import org.blaze.protobuf.FallbackCount
+import org.blaze.protobuf.FallbackReason
+import org.blaze.protobuf.PlanConversionReport
+
+/**
+ * Counts operators and expressions of each query converted to native or falling back, and logs
+ * a PlanConversionReport of the query as single-line JSON once the query ends, with fallbacks
+ * aggregated by reason code and operator. enabled by spark.blaze.planConversionReport.enabled.
+ *
+ * operators are counted once in the query stage they are planned in. operators falling back
+ * without a recorded reason have no native implementation.
+ */
+object PlanConversionTelemetry extends Logging {
+  val enabled: Boolean =
+    SparkEnv.get.conf.getBoolean("spark.blaze.planConversionReport.enabled", defaultValue = false)
+
+  val fallbackReasonTag: TreeNodeTag[(FallbackReason, String)] =
+    TreeNodeTag("blazeFallbackReason")
+
+  private val maxExampleLength = 256
+
+  private class FallbackStats(val example: String) {
+    var operators = 0L
+    var expressions = 0L
+  }
+
+  private class QueryStats {
+    var nativeOperators = 0L
+    var fallbackOperators = 0L
+    var nativeExpressions = 0L
+    var fallbackExpressions = 0L
+    val fallbacks = mutable.LinkedHashMap[(FallbackReason, String), FallbackStats]()
+  }
+
+  private val queries = new ConcurrentHashMap[Long, QueryStats]()
+  private val listenerRegistered = new AtomicBoolean(false)
+
+  /** records why the exec falls back, the first recorded reason is kept */
+  def markFallback(exec: SparkPlan, reason: FallbackReason, message: String): Unit = {
+    if (exec.getTagValue(fallbackReasonTag).isEmpty) {
+      val example = Option(message).getOrElse("").take(maxExampleLength)
+      exec.setTagValue(fallbackReasonTag, (reason, example))
+    }
+  }
+
+  /** classifies errors thrown by converting an exec, see NativeConverters for the messages */
+  def classifyError(e: Throwable): FallbackReason = {
+    val message = Option(e.getMessage).getOrElse("")
+    e match {
+      case _: NotImplementedError if message.startsWith("unsupported exception") =>
+        FallbackReason.UNSUPPORTED_EXPRESSION
+      case _: NotImplementedError
+          if message.contains("Data type") || message.contains("Value conversion") ||
+            message.contains("scalar type") =>
+        FallbackReason.UNSUPPORTED_DATA_TYPE
+      case _: NotImplementedError => FallbackReason.UNSUPPORTED_FEATURE
+      case _ => FallbackReason.CONVERSION_ERROR
+    }
+  }
+
+  /** counts operators of a plan transformed by blaze rules into the report of its query */
+  def recordConvertedPlan(sparkSession: SparkSession, plan: SparkPlan): Unit = {
+    if (!enabled) {
+      return
+    }
+    val executionId = sparkSession.sparkContext.getLocalProperty(SQLExecution.EXECUTION_ID_KEY)
+    if (executionId == null) {
+      return // not executed as a query, like explain
+    }
+    registerListener(sparkSession.sparkContext)
+
+    val stats = queries.computeIfAbsent(executionId.toLong, _ => new QueryStats)
+    stats.synchronized {
+      countOperators(plan, stats)
+    }
+  }
+
+  private def countOperators(plan: SparkPlan, stats: QueryStats): Unit =
+    plan match {
+      case _: QueryStageExec | _: ReusedExchangeExec => // counted in its own stage
+      case _: CustomShuffleReaderExec | _: ConvertToNativeExec | _: ConvertToUnsafeRowExec |
+          _: NativeRenameColumnsExec =>
+        plan.children.foreach(countOperators(_, stats))
+      case exec =>
+        val numExpressions = exec.expressions.map(_.collect { case e => e }.size).sum
+        if (NativeSupports.isNative(exec)) {
+          stats.nativeOperators += 1
+          stats.nativeExpressions += numExpressions
+        } else {
+          val (reason, message) = exec
+            .getTagValue(fallbackReasonTag)
+            .getOrElse((FallbackReason.NO_NATIVE_IMPLEMENTATION, ""))
+          val fallback = stats.fallbacks.getOrElseUpdate(
+            (reason, exec.nodeName),
+            new FallbackStats(message))
+          fallback.operators += 1
+          fallback.expressions += numExpressions
+          stats.fallbackOperators += 1
+          stats.fallbackExpressions += numExpressions
+        }
+        exec.children.foreach(countOperators(_, stats))
+    }
+
+  private def registerListener(sc: SparkContext): Unit = {
+    if (listenerRegistered.compareAndSet(false, true)) {
+      sc.addSparkListener(new SparkListener {
+        override def onOtherEvent(event: SparkListenerEvent): Unit =
+          event match {
+            case end: SparkListenerSQLExecutionEnd => report(end.executionId)
+            case _ =>
+          }
+      })
+    }
+  }
+
+  private def report(executionId: Long): Unit = {
+    val stats = queries.remove(executionId)
+    if (stats == null) {
+      return
+    }
+    val report = stats.synchronized {
+      PlanConversionReport
+        .newBuilder()
+        .setExecutionId(executionId)
+        .setNativeOperators(stats.nativeOperators)
+        .setFallbackOperators(stats.fallbackOperators)
+        .setNativeExpressions(stats.nativeExpressions)
+        .setFallbackExpressions(stats.fallbackExpressions)
+        .addAllFallbacks(stats.fallbacks.map {
+          case ((reason, operator), fallback) =>
+            FallbackCount
+              .newBuilder()
+              .setReason(reason)
+              .setOperator(operator)
+              .setOperators(fallback.operators)
+              .setExpressions(fallback.expressions)
+              .setExample(fallback.example)
+              .build()
+        }.asJava)
+        .build()
+    }
+
+    try {
+      BlazeCallNativeWrapper.loadNative()
+      logInfo(JniBridge.renderPlanConversionReport(report.toByteArray))
+    } catch {
+      case NonFatal(e) =>
+        logWarning(s"Error reporting plan conversion of execution $executionId", e)
+    }
+  }
+}
@@ -0,0 +1,49 @@
+/*
+ * Copyright 2022 The Blaze Authors
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+package org.apache.spark.sql.blaze
+
+import java.nio.channels.ReadableByteChannel
+
+import org.apache.spark.SparkEnv
+import org.apache.spark.TaskContext
+import org.apache.spark.shuffle.ShuffleHandle
+import org.apache.spark.shuffle.ShuffleReadMetricsReporter
+import org.apache.spark.util.Utils
+
+/**
+ * Fetches shuffle blocks written by [[RssPartitionWriterBase]] from a remote shuffle service
+ * (e.g. Uniffle, Celeborn). Each returned channel is a stream of concatenated shuffle segments,
+ * which are decoded by native ShuffleReaderExec.
+ */
+trait RssPartitionReaderFactory {
+  def readBlockStreams(
+      handle: ShuffleHandle,
+      startPartition: Int,
+      endPartition: Int,
+      context: TaskContext,
+      metrics: ShuffleReadMetricsReporter): Iterator[ReadableByteChannel]
+}
+
+object RssPartitionReaderFactory {
+  private val RSS_PARTITION_READER_FACTORY = "spark.blaze.shuffle.rssPartitionReaderFactory"
+
+  lazy val get: Option[RssPartitionReaderFactory] =
+    SparkEnv.get.conf
+      .getOption(RSS_PARTITION_READER_FACTORY)
+      .map(Utils.classForName(_).getConstructor().newInstance())
+      .map(_.asInstanceOf[RssPartitionReaderFactory])
+}
@@ -0,0 +1,57 @@
+/*
+ * Copyright 2022 The Blaze Authors
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+package org.apache.spark.sql.blaze
+
+import java.nio.ByteBuffer
+
+import org.apache.spark.ShuffleDependency
+import org.apache.spark.SparkEnv
+import org.apache.spark.TaskContext
+import org.apache.spark.shuffle.ShuffleWriteMetricsReporter
+import org.apache.spark.util.Utils
+
+/**
+ * Receives compressed shuffle blocks from native shuffle writer and pushes them to a remote
+ * shuffle service (e.g. Celeborn).
+ *
+ * NOTE: the buffer passed to write() points to native memory and is only valid during the call,
+ * its content must be consumed or copied before returning.
+ */
+abstract class RssPartitionWriterBase {
+  def write(partitionId: Int, buffer: ByteBuffer): Unit
+  def flush(): Unit
+  def close(): Unit
+  def getPartitionLengthMap: Array[Long]
+}
+
+trait RssPartitionWriterFactory {
+  def create(
+      dep: ShuffleDependency[_, _, _],
+      mapId: Long,
+      context: TaskContext,
+      metrics: ShuffleWriteMetricsReporter): RssPartitionWriterBase
+}
+
+object RssPartitionWriterFactory {
+  private val RSS_PARTITION_WRITER_FACTORY = "spark.blaze.shuffle.rssPartitionWriterFactory"
+
+  lazy val get: Option[RssPartitionWriterFactory] =
+    SparkEnv.get.conf
+      .getOption(RSS_PARTITION_WRITER_FACTORY)
+      .map(Utils.classForName(_).getConstructor().newInstance())
+      .map(_.asInstanceOf[RssPartitionWriterFactory])
+}
@@ -17,14 +17,18 @@
 package org.apache.spark.sql.blaze.execution
 
 import java.io.ByteArrayInputStream
+import java.io.EOFException
 import java.io.File
+import java.io.IOException
 import java.io.InputStream
 import java.nio.channels.Channels
 import java.nio.channels.SeekableByteChannel
 import java.nio.ByteBuffer
+import java.nio.ByteOrder
 import java.nio.file.Files
 import java.nio.file.Paths
 
+import scala.collection.mutable.ArrayBuffer
 import scala.reflect.io.Path
 
 import io.netty.channel.internal.ChannelUtils
@@ -41,14 +45,20 @@ import org.apache.spark.io.CompressionCodec
 import org.apache.spark.network.buffer.ManagedBuffer
 import org.apache.spark.serializer.SerializerManager
 import org.apache.spark.shuffle.BaseShuffleHandle
+import org.apache.spark.shuffle.FetchFailedException
 import org.apache.spark.shuffle.ShuffleReader
 import org.apache.spark.shuffle.ShuffleReadMetricsReporter
+import org.apache.spark.sql.blaze.BlazeShuffleReadException
+import org.apache.spark.sql.blaze.JniBridge
 import org.apache.spark.sql.blaze.execution.Converters.readManagedBufferToSegmentByteChannels
 import org.apache.spark.storage.BlockId
 import org.apache.spark.storage.BlockManager
 import org.apache.spark.storage.BlockManagerId
+import org.apache.spark.storage.ShuffleBlockBatchId
 import org.apache.spark.storage.ShuffleBlockFetcherIterator301
+import org.apache.spark.storage.ShuffleBlockId
 import org.apache.spark.util.CompletionIterator
+import org.blaze.FileSegmentSeekableByteChannel
 import org.blaze.NioSeekableByteChannel
 
 class ArrowBlockStoreShuffleReader301[K, C](
@@ -66,7 +76,10 @@ class ArrowBlockStoreShuffleReader301[K, C](
   private val dep = handle.dependency
   private val zcodec: CompressionCodec = Util.getZCodecForShuffle
 
-  private def fetchIterator: Iterator[(BlockId, ManagedBuffer)] = {
+  private def fetchIterator: Iterator[(BlockId, ManagedBuffer)] =
+    fetchIterator(fetchContinuousBlocksInBatch)
+
+  private def fetchIterator(doBatchFetch: Boolean): Iterator[(BlockId, ManagedBuffer)] = {
     new ShuffleBlockFetcherIterator301(
       context,
       SparkEnv.get.blockManager.blockStoreClient,
@@ -80,7 +93,7 @@ class ArrowBlockStoreShuffleReader301[K, C](
       SparkEnv.get.conf.get(config.SHUFFLE_DETECT_CORRUPT),
       SparkEnv.get.conf.get(config.SHUFFLE_DETECT_CORRUPT_MEMORY),
       readMetrics,
-      fetchContinuousBlocksInBatch).toCompletionIterator
+      doBatchFetch).toCompletionIterator
   }
 
   /** Read the combined key-values for this reduce task */
@@ -91,6 +104,7 @@ class ArrowBlockStoreShuffleReader301[K, C](
     // underlying InputStream when all records have been read.
     // use 0 as key since it's not used
     val recordIter = fetchIterator.flatMap { blockBuffer =>
+      val decompressor = new Util.SegmentDecompressor(zcodec)
       readManagedBufferToSegmentByteChannels(blockBuffer._2).toIterator
         .flatMap(channel => {
           // NOTE: as ArrowReader requires seekable input, the whole arrow data
@@ -99,12 +113,13 @@ class ArrowBlockStoreShuffleReader301[K, C](
           // TODO: avoid buffering the whole compressed data
           val buf = new Array[Byte](channel.size().asInstanceOf[Int])
           channel.read(ByteBuffer.wrap(buf))
-          val zis = zcodec.compressedInputStream(new ByteArrayInputStream(buf))
-          
-          val arrowData = IOUtils.toByteArray(zis)
-          val zchannel =
-            new NioSeekableByteChannel(ByteBuffer.wrap(arrowData), 0, arrowData.length)
-          new ArrowReaderIterator(zchannel, context)
+          decompressor.decompress(buf) match {
+            case Some(arrowData) =>
+              val zchannel =
+                new NioSeekableByteChannel(ByteBuffer.wrap(arrowData), 0, arrowData.length)
+              new ArrowReaderIterator(zchannel, context)
+            case None => Iterator.empty // dictionary segment
+          }
         })
         .map(x => (0, x))
     }
@@ -144,10 +159,60 @@ class ArrowBlockStoreShuffleReader301[K, C](
   }
 
   def readIpc(): Iterator[SeekableByteChannel] = {
-    val ipcIterator = fetchIterator.flatMap { blockBuffer =>
-      Converters.readManagedBufferToSegmentByteChannels(blockBuffer._2).toIterator
+    val blockSegments = fetchIterator.map {
+      case (blockId, blockBuffer) =>
+        (blockId, Converters.readManagedBufferToSegmentByteChannels(blockBuffer))
+    }
+    new ShuffleSegmentIterator(context, blockSegments, blockFetchFailure)
+  }
+
+  /**
+   * Same as readIpc(), with reduce partition id of each segment. blocks are fetched one by one
+   * because a batch-fetched block may span multiple partitions.
+   */
+  def readPartitionedIpc(): Iterator[(java.lang.Long, SeekableByteChannel)] = {
+    val blockSegments = fetchIterator(doBatchFetch = false).map {
+      case (blockId, blockBuffer) =>
+        val partitionId: java.lang.Long = blockId match {
+          case ShuffleBlockId(_, _, reduceId) => reduceId.toLong
+          case unsupported =>
+            throw new IllegalStateException(s"cannot read partitioned ipc of $unsupported")
+        }
+        val segments = Converters
+          .readManagedBufferToSegmentByteChannels(blockBuffer)
+          .map(channel => (partitionId, channel))
+        (blockId, segments)
+    }
+    new ShuffleSegmentIterator(context, blockSegments, blockFetchFailure)
+  }
+
+  /**
+   * creates the fetch failure of a block whose segments cannot be read, the map output is looked
+   * up again since the fetch iterator does not expose block addresses
+   */
+  private def blockFetchFailure(
+      blockId: BlockId,
+      message: String,
+      cause: Throwable): Option[FetchFailedException] = {
+    val (shuffleId, mapId, reduceId) = blockId match {
+      case ShuffleBlockId(shuffleId, mapId, reduceId) => (shuffleId, mapId, reduceId)
+      case ShuffleBlockBatchId(shuffleId, mapId, startReduceId, _) =>
+        (shuffleId, mapId, startReduceId)
+      case _ => return None
+    }
+    def isBlockOfMap(id: BlockId): Boolean = id match {
+      case ShuffleBlockId(`shuffleId`, `mapId`, _) => true
+      case ShuffleBlockBatchId(`shuffleId`, `mapId`, _, _) => true
+      case _ => false
+    }
+    val failures = blocksByAddress().flatMap {
+      case (address, blocks) =>
+        blocks.collectFirst {
+          case (id, _, mapIndex) if isBlockOfMap(id) =>
+            new FetchFailedException(address, shuffleId, mapId, mapIndex, reduceId, message, cause)
+        }
     }
-    new InterruptibleIterator(context, ipcIterator)
+    if (failures.hasNext) Some(failures.next()) else None
   }
 
   private def fetchContinuousBlocksInBatch: Boolean = {
@@ -175,3 +240,114 @@ class ArrowBlockStoreShuffleReader301[K, C](
     doBatchFetch
   }
 }
+
+/**
+ * Iterator of the segments of fetched shuffle blocks. the block of each taken segment is
+ * remembered, so that a segment failed to be read by the native shuffle reader can be converted
+ * into a fetch failure of its map output, letting spark regenerate it.
+ */
+class ShuffleSegmentIterator[T](
+    context: TaskContext,
+    blockSegments: Iterator[(BlockId, Seq[T])],
+    blockFetchFailure: (BlockId, String, Throwable) => Option[FetchFailedException])
+    extends Iterator[T]
+    with Logging {
+
+  // ordinal of the first segment of each block, in fetching order
+  private val blockOrdinals = ArrayBuffer[(Long, BlockId)]()
+  private var currentSegments: Iterator[T] = Iterator.empty
+  private var taken = 0L
+
+  // segment taken by nextSegmentFully() which does not fit the buffer of native side
+  private var oversizedSegment: Option[T] = None
+
+  override def hasNext: Boolean = {
+    context.killTaskIfInterrupted()
+    while (!currentSegments.hasNext && blockSegments.hasNext) {
+      val (blockId, segments) = blockSegments.next()
+      blockOrdinals += ((taken, blockId))
+      currentSegments = segments.iterator
+    }
+    currentSegments.hasNext
+  }
+
+  override def next(): T = {
+    if (!hasNext) {
+      throw new NoSuchElementException("no more shuffle segments")
+    }
+    taken += 1
+    currentSegments.next()
+  }
+
+  /** number of segments taken so far, which is the ordinal of the next segment */
+  def numTaken: Long = taken
+
+  /** see JniBridge.nextSegmentFully */
+  def nextSegmentFully(buffer: ByteBuffer, localFiles: Boolean): Long = {
+    val segment = oversizedSegment match {
+      case Some(segment) =>
+        oversizedSegment = None
+        segment
+      case None if hasNext => next()
+      case None => return -1
+    }
+    val (partitionId, channel) = (segment: Any) match {
+      case (partitionId: java.lang.Long, channel: SeekableByteChannel) =>
+        (Some(partitionId.longValue()), channel)
+      case channel: SeekableByteChannel => (None, channel)
+    }
+    val localFileRecord = channel match {
+      case channel: FileSegmentSeekableByteChannel if localFiles =>
+        Some(JniBridge.localFileSegmentRecord(channel))
+      case _ => None
+    }
+    val recordLength = localFileRecord.map(_.length.toLong).getOrElse(1 + channel.size())
+    val length = partitionId.map(_ => 8L).getOrElse(0L) + recordLength
+    if (length > buffer.capacity()) {
+      oversizedSegment = Some(segment)
+      return -2 - length
+    }
+
+    buffer.clear()
+    buffer.order(ByteOrder.LITTLE_ENDIAN)
+    partitionId.foreach(id => buffer.putLong(id))
+    localFileRecord match {
+      case Some(record) => buffer.put(record)
+      case None =>
+        buffer.put(JniBridge.SEGMENT_RECORD_DATA)
+        try {
+          while (buffer.position() < length) {
+            if (channel.read(buffer) < 0) {
+              throw new EOFException("unexpected EOF reading shuffle segment")
+            }
+          }
+        } catch {
+          case e: IOException =>
+            throw new BlazeShuffleReadException(
+              "error fetching shuffle segment",
+              this,
+              taken - 1,
+              e)
+        }
+    }
+    length
+  }
+
+  /**
+   * @return the fetch failure of the block the segment at the ordinal belongs to, which is also
+   *         registered in the task context
+   */
+  def fetchFailure(ordinal: Long, message: String, cause: Throwable)
+      : Option[FetchFailedException] = {
+    val blockIndex = blockOrdinals.lastIndexWhere(_._1 <= ordinal)
+    if (blockIndex < 0) {
+      logError(s"failed reading shuffle segment #$ordinal of unknown block: $message")
+      return None
+    }
+    val blockId = blockOrdinals(blockIndex)._2
+    logError(s"failed reading shuffle segment #$ordinal of $blockId: $message")
+    val failure = blockFetchFailure(blockId, message, cause)
+    failure.foreach(e => context.setFetchFailed(e))
+    failure
+  }
+}
@@ -0,0 +1,79 @@
+/*
+ * Copyright 2022 The Blaze Authors
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+package org.apache.spark.sql.blaze.execution
+
+import org.apache.arrow.c.ArrowArray
+import org.apache.arrow.c.ArrowSchema
+import org.apache.arrow.c.Data
+import org.apache.arrow.vector.VectorSchemaRoot
+import org.apache.arrow.vector.dictionary.DictionaryProvider.MapDictionaryProvider
+import org.apache.spark.sql.catalyst.InternalRow
+import org.apache.spark.sql.types.StructType
+import org.apache.spark.sql.util2.ArrowUtils2
+import org.apache.spark.sql.util2.ArrowWriter
+import org.apache.spark.util.Utils
+import org.apache.spark.TaskContext
+
+/**
+ * Exports rows of a JVM child plan as arrow batches into the FFI structs allocated by the native
+ * FFIReaderExec. each batch is written into a new root, whose buffers are retained by the export
+ * and released by the native side once the imported batch is dropped.
+ */
+class ArrowFFIExporter(
+    rowIter: Iterator[InternalRow],
+    schema: StructType,
+    timeZoneId: String,
+    taskContext: TaskContext,
+    recordBatchSize: Int = 10000) {
+
+  private val allocator =
+    ArrowUtils2.rootAllocator.newChildAllocator("arrowFFIExporter", 0, Long.MaxValue)
+  private val arrowSchema = ArrowUtils2.toArrowSchema(schema, timeZoneId)
+  private val emptyDictionaryProvider = new MapDictionaryProvider()
+
+  taskContext.addTaskCompletionListener[Unit] { _ =>
+    allocator.close()
+  }
+
+  /** called by the native side, returns false if there are no more batches */
+  def exportNextBatch(exportArrayPtr: Long, exportSchemaPtr: Long): Boolean = {
+    if (!rowIter.hasNext) {
+      return false
+    }
+    Utils.tryWithResource(VectorSchemaRoot.create(arrowSchema, allocator)) { root =>
+      val arrowWriter = ArrowWriter.create(root)
+      var numRows = 0
+      while (rowIter.hasNext && numRows < recordBatchSize) {
+        arrowWriter.write(rowIter.next())
+        numRows += 1
+      }
+      arrowWriter.finish()
+
+      Utils.tryWithResource(ArrowArray.wrap(exportArrayPtr)) { exportArray =>
+        Utils.tryWithResource(ArrowSchema.wrap(exportSchemaPtr)) { exportSchema =>
+          Data.exportVectorSchemaRoot(
+            allocator,
+            root,
+            emptyDictionaryProvider,
+            exportArray,
+            exportSchema)
+        }
+      }
+    }
+    true
+  }
+}
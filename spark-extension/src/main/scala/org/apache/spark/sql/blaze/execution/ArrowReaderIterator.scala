@@ -18,6 +18,11 @@ package org.apache.spark.sql.blaze.execution
 
 import java.nio.channels.SeekableByteChannel
 
+import scala.collection.JavaConverters._
+
+import org.apache.arrow.vector.FieldVector
+import org.apache.arrow.vector.VectorSchemaRoot
+import org.apache.arrow.vector.dictionary.DictionaryEncoder
 import org.apache.arrow.vector.ipc.ArrowFileReader
 import org.apache.spark.sql.catalyst.InternalRow
 import org.apache.spark.TaskContext
@@ -53,9 +58,26 @@ class ArrowReaderIterator(channel: SeekableByteChannel, taskContext: TaskContext
 
   private def nextBatch(): Iterator[InternalRow] = {
     if (arrowReader.loadNextBatch()) {
-      FFIHelper.batchAsRowIter(FFIHelper.rootAsBatch(root))
+      FFIHelper.batchAsRowIter(FFIHelper.rootAsBatch(decodeDictionaries(root)))
     } else {
       Iterator.empty
     }
   }
+
+  // string columns may be dictionary-encoded with shared dictionaries by native shuffle writers
+  private def decodeDictionaries(root: VectorSchemaRoot): VectorSchemaRoot = {
+    if (root.getSchema.getFields.asScala.forall(_.getDictionary == null)) {
+      return root
+    }
+    val dictionaries = arrowReader.getDictionaryVectors
+    val vectors = root.getFieldVectors.asScala.map { vector =>
+      Option(vector.getField.getDictionary) match {
+        case Some(encoding) =>
+          val dictionary = dictionaries.get(encoding.getId)
+          DictionaryEncoder.decode(vector, dictionary).asInstanceOf[FieldVector]
+        case None => vector
+      }
+    }
+    new VectorSchemaRoot(vectors.asJava)
+  }
 }
@@ -16,10 +16,6 @@
 
 package org.apache.spark.sql.blaze.execution
 
-import java.nio.file.Paths
-import java.nio.ByteBuffer
-import java.nio.ByteOrder
-import java.nio.file.Files
 import java.util.Random
 import java.util.function.Supplier
 import java.util.UUID
@@ -39,11 +35,16 @@ import org.apache.spark.shuffle.ShuffleWriteProcessor
 import org.apache.spark.shuffle.sort.SortShuffleManager
 import org.apache.spark.shuffle.IndexShuffleBlockResolver
 import org.apache.spark.sql.blaze.MetricNode
+import org.apache.spark.sql.blaze.NativeAccumulators
 import org.apache.spark.sql.blaze.NativeConverters
 import org.apache.spark.sql.blaze.NativeRDD
+import org.apache.spark.sql.blaze.NativeShuffleWriteMetrics
 import org.apache.spark.sql.blaze.NativeSupports
+import org.apache.spark.sql.blaze.execution.ArrowShuffleExchangeExec301.canPassthroughShuffleSegments
 import org.apache.spark.sql.blaze.execution.ArrowShuffleExchangeExec301.canUseNativeShuffleWrite
 import org.apache.spark.sql.blaze.JniBridge
+import org.apache.spark.sql.blaze.RssPartitionReaderFactory
+import org.apache.spark.sql.blaze.RssPartitionWriterFactory
 import org.apache.spark.sql.catalyst.InternalRow
 import org.apache.spark.sql.catalyst.errors.attachTree
 import org.apache.spark.sql.catalyst.expressions.Attribute
@@ -54,6 +55,8 @@ import org.apache.spark.sql.catalyst.expressions.codegen.LazilyGeneratedOrdering
 import org.apache.spark.sql.catalyst.plans.logical.Statistics
 import org.apache.spark.sql.catalyst.plans.physical._
 import org.apache.spark.sql.execution._
+import org.apache.spark.sql.execution.adaptive.CustomShuffleReaderExec
+import org.apache.spark.sql.execution.adaptive.ShuffleQueryStageExec
 import org.apache.spark.sql.execution.exchange.ShuffleExchangeExec
 import org.apache.spark.sql.execution.exchange.ShuffleExchangeLike
 import org.apache.spark.sql.execution.metric.SQLMetric
@@ -70,6 +73,7 @@ import org.blaze.protobuf.PhysicalHashRepartition
 import org.blaze.protobuf.PhysicalPlanNode
 import org.blaze.protobuf.Schema
 import org.blaze.protobuf.ShuffleReaderExecNode
+import org.blaze.protobuf.ShuffleSegmentSource
 import org.blaze.protobuf.ShuffleWriterExecNode
 
 case class ArrowShuffleExchangeExec301(
@@ -92,7 +96,14 @@ case class ArrowShuffleExchangeExec301(
       "dataSize" ->
         SQLMetrics.createSizeMetric(sparkContext, "data size")) ++ readMetrics ++ writeMetrics
 
-  @transient lazy val inputRDD: RDD[InternalRow] = child.execute()
+  @transient lazy val segmentPassthrough: Boolean =
+    canPassthroughShuffleSegments(child, outputPartitioning)
+
+  @transient lazy val inputRDD: RDD[InternalRow] = if (segmentPassthrough) {
+    NativeSupports.executeNative(child)
+  } else {
+    child.execute()
+  }
   // 'mapOutputStatisticsFuture' is only needed when enable AQE.
   @transient override lazy val mapOutputStatisticsFuture: Future[MapOutputStatistics] = {
     if (inputRDD.getNumPartitions == 0) {
@@ -115,7 +126,8 @@ case class ArrowShuffleExchangeExec301(
         child.output,
         outputPartitioning,
         serializer,
-        metrics)
+        metrics,
+        segmentPassthrough)
     } else {
       ArrowShuffleExchangeExec301.prepareShuffleDependency(
         inputRDD,
@@ -174,6 +186,9 @@ case class ArrowShuffleExchangeExec301(
   override def doExecuteNative(): NativeRDD = {
     val shuffleHandle = shuffleDependency.shuffleHandle
     val rdd = doExecute()
+    // statistics of the read partitions, known on the driver if the map stage has completed
+    val partitionSizes = NativeSupports.getShufflePartitionSizes(shuffleDependency)
+    val totalRows = metrics(SQLShuffleWriteMetricsReporter.SHUFFLE_RECORDS_WRITTEN).value
     val nativeMetrics =
       MetricNode(
         metrics
@@ -191,31 +206,51 @@ case class ArrowShuffleExchangeExec301(
 
         // store fetch iterator in jni resource before native compute
         val jniResourceId = s"NativeShuffleReadExec:${UUID.randomUUID().toString}"
-        JniBridge.resourcesMap.put(
-          jniResourceId,
-          () => {
-            val shuffleManager = SparkEnv.get.shuffleManager
-            shuffleManager
-              .getReader(
-                shuffleHandle,
-                partition.index,
-                partition.index + 1,
-                taskContext,
-                taskContext.taskMetrics().createTempShuffleReadMetrics())
-              .asInstanceOf[ArrowBlockStoreShuffleReader301[_, _]]
-              .readIpc()
-          })
+        val segmentSource = RssPartitionReaderFactory.get match {
+          case Some(rssPartitionReaderFactory) =>
+            JniBridge.resourcesMap.put(
+              jniResourceId,
+              () => {
+                val readBlockStreams = rssPartitionReaderFactory.readBlockStreams(
+                  shuffleHandle,
+                  partition.index,
+                  partition.index + 1,
+                  taskContext,
+                  taskContext.taskMetrics().createTempShuffleReadMetrics())
+                new InterruptibleIterator(taskContext, readBlockStreams)
+              })
+            ShuffleSegmentSource.BLOCK_STREAMS
+
+          case None =>
+            JniBridge.resourcesMap.put(
+              jniResourceId,
+              () => {
+                val shuffleManager = SparkEnv.get.shuffleManager
+                shuffleManager
+                  .getReader(
+                    shuffleHandle,
+                    partition.index,
+                    partition.index + 1,
+                    taskContext,
+                    taskContext.taskMetrics().createTempShuffleReadMetrics())
+                  .asInstanceOf[ArrowBlockStoreShuffleReader301[_, _]]
+                  .readIpc()
+              })
+            ShuffleSegmentSource.SEGMENT_CHANNELS
+        }
 
-        PhysicalPlanNode
+        val shuffleReader = ShuffleReaderExecNode
           .newBuilder()
-          .setShuffleReader(
-            ShuffleReaderExecNode
-              .newBuilder()
-              .setSchema(nativeSchema)
-              .setNumPartitions(rdd.getNumPartitions)
-              .setNativeShuffleId(jniResourceId)
-              .build())
-          .build()
+          .setSchema(nativeSchema)
+          .setNumPartitions(rdd.getNumPartitions)
+          .setNativeShuffleId(jniResourceId)
+          .setSegmentSource(segmentSource)
+        partitionSizes.foreach { sizes =>
+          shuffleReader.setStatistics(
+            NativeSupports
+              .buildShuffleReadStatistics(sizes, totalRows, partition.index, partition.index + 1))
+        }
+        PhysicalPlanNode.newBuilder().setShuffleReader(shuffleReader.build()).build()
       })
   }
 
@@ -230,12 +265,39 @@ object ArrowShuffleExchangeExec301 {
     rdd.isInstanceOf[NativeRDD] && outputPartitioning.isInstanceOf[HashPartitioning]
   }
 
+  /**
+   * Returns true if child is a native shuffle read of identical partitioning (e.g. a user
+   * repartition on top of an AQE-coalesced stage), so its compressed segments can be forwarded
+   * to the shuffle writer without a decode/re-encode cycle.
+   */
+  def canPassthroughShuffleSegments(
+      child: SparkPlan,
+      outputPartitioning: Partitioning): Boolean = {
+    def upstreamPartitioning(plan: SparkPlan): Option[Partitioning] =
+      plan match {
+        case exchange: ArrowShuffleExchangeExec301 => Some(exchange.outputPartitioning)
+        case stage: ShuffleQueryStageExec => upstreamPartitioning(stage.plan)
+        case reader: CustomShuffleReaderExec
+            if reader.partitionSpecs.forall(_.isInstanceOf[CoalescedPartitionSpec]) =>
+          upstreamPartitioning(reader.child)
+        case _ => None
+      }
+
+    val enabled =
+      SparkEnv.get.conf.getBoolean("spark.blaze.shuffle.segmentPassthrough.enabled", true)
+    enabled && RssPartitionReaderFactory.get.isEmpty &&
+    outputPartitioning.isInstanceOf[HashPartitioning] &&
+    upstreamPartitioning(child).contains(outputPartitioning)
+  }
+
   def prepareNativeShuffleDependency(
       rdd: RDD[InternalRow],
       outputAttributes: Seq[Attribute],
       outputPartitioning: Partitioning,
       serializer: Serializer,
-      metrics: Map[String, SQLMetric]): ShuffleDependency[Int, InternalRow, InternalRow] = {
+      metrics: Map[String, SQLMetric],
+      segmentPassthrough: Boolean = false)
+      : ShuffleDependency[Int, InternalRow, InternalRow] = {
 
     val nativeInputRDD = rdd.asInstanceOf[NativeRDD]
     val HashPartitioning(expressions, numPartitions) =
@@ -255,12 +317,18 @@ object ArrowShuffleExchangeExec301 {
       nativeInputRDD.dependencies,
       (partition, taskContext) => {
         val nativeInputPartition = nativeInputRDD.partitions(partition.index)
+        val nativeInputPlan = if (segmentPassthrough) {
+          createPassthroughShuffleReader(nativeInputRDD, nativeInputPartition, taskContext)
+        } else {
+          nativeInputRDD.nativePlan(nativeInputPartition, taskContext)
+        }
         PhysicalPlanNode
           .newBuilder()
           .setShuffleWriter(
             ShuffleWriterExecNode
               .newBuilder()
-              .setInput(nativeInputRDD.nativePlan(nativeInputPartition, taskContext))
+              .setInput(nativeInputPlan)
+              .setSegmentPassthrough(segmentPassthrough)
               .setOutputPartitioning(
                 PhysicalHashRepartition
                   .newBuilder()
@@ -284,6 +352,59 @@ object ArrowShuffleExchangeExec301 {
     dependency
   }
 
+  /**
+   * Rebuilds the native shuffle reader of `partition` so that it provides compressed segments
+   * along with their reduce partition ids.
+   */
+  private def createPassthroughShuffleReader(
+      nativeInputRDD: NativeRDD,
+      partition: Partition,
+      taskContext: TaskContext): PhysicalPlanNode = {
+    val shuffleHandle =
+      nativeInputRDD.dependencies.head.asInstanceOf[ShuffleDependency[_, _, _]].shuffleHandle
+    val shuffleReader = nativeInputRDD.nativePlan(partition, taskContext).getShuffleReader
+
+    // use reflection to get partitionSpec because ShuffledRowRDDPartition is private
+    val shuffledRDDPartitionClass =
+      Class.forName("org.apache.spark.sql.execution.ShuffledRowRDDPartition")
+    val specField = shuffledRDDPartitionClass.getDeclaredField("spec")
+    specField.setAccessible(true)
+    val CoalescedPartitionSpec(startReducerIndex, endReducerIndex) =
+      specField.get(partition).asInstanceOf[CoalescedPartitionSpec]
+
+    // replace the segments provider registered by the original reader, which provides segments
+    // of each reduce range if the reader reads ranges natively
+    def readPartitionedIpc(startReduceId: Int, endReduceId: Int) =
+      SparkEnv.get.shuffleManager
+        .getReader(
+          shuffleHandle,
+          startReduceId,
+          endReduceId,
+          taskContext,
+          taskContext.taskMetrics().createTempShuffleReadMetrics())
+        .asInstanceOf[ArrowBlockStoreShuffleReader301[_, _]]
+        .readPartitionedIpc()
+    if (shuffleReader.getReduceRangesCount > 0) {
+      JniBridge.resourcesMap.put(
+        shuffleReader.getNativeShuffleId,
+        (startReduceId: java.lang.Long, endReduceId: java.lang.Long) =>
+          readPartitionedIpc(startReduceId.toInt, endReduceId.toInt))
+    } else {
+      JniBridge.resourcesMap.put(
+        shuffleReader.getNativeShuffleId,
+        () => readPartitionedIpc(startReducerIndex, endReducerIndex))
+    }
+
+    PhysicalPlanNode
+      .newBuilder()
+      .setShuffleReader(
+        ShuffleReaderExecNode
+          .newBuilder(shuffleReader)
+          .setSegmentSource(ShuffleSegmentSource.PARTITIONED_SEGMENT_CHANNELS)
+          .build())
+      .build()
+  }
+
   /**
    * Returns a [[ShuffleDependency]] that will partition rows of its child based on
    * the partitioning scheme defined in `newPartitioning`. Those partitions of
@@ -524,53 +645,123 @@ object ArrowShuffleExchangeExec301 {
           context: TaskContext,
           partition: Partition): MapStatus = {
 
+        RssPartitionWriterFactory.get match {
+          case Some(rssPartitionWriterFactory) =>
+            return writeRss(rdd, dep, mapId, context, partition, rssPartitionWriterFactory)
+          case None =>
+        }
+
         val shuffleBlockResolver =
           SparkEnv.get.shuffleManager.shuffleBlockResolver.asInstanceOf[IndexShuffleBlockResolver]
-        val dataFile = shuffleBlockResolver.getDataFile(dep.shuffleId, mapId)
-        val tempDataFilePath = dataFile.getPath.replace(".data", ".data.tmp")
-        val tempIndexFilePath = dataFile.getPath.replace(".data", ".index.tmp")
+        val dataFilePath = shuffleBlockResolver.getDataFile(dep.shuffleId, mapId).getPath
+        val indexFilePath = dataFilePath.replace(".data", ".index")
+        val writeMetricsResourceId =
+          registerWriteMetrics(context, createMetricsReporter(context))
+
+        // native shuffle writer writes into temp files of this attempt, which are published
+        // atomically on commit, so that speculative attempts never corrupt each other's output
+        var partitionLengths: Array[Long] = null
+        try {
+          val nativeShuffleRDD =
+            rdd.asInstanceOf[MapPartitionsRDD[_, _]].prev.asInstanceOf[NativeRDD]
+          NativeAccumulators.bindTask(context, nativeShuffleRDD.nativeAccumulators)
+          val nativeShuffleWriterExec = PhysicalPlanNode
+            .newBuilder()
+            .setShuffleWriter(
+              ShuffleWriterExecNode
+                .newBuilder(nativeShuffleRDD.nativePlan(partition, context).getShuffleWriter)
+                .setOutputDataFile(dataFilePath)
+                .setOutputIndexFile(indexFilePath)
+                .setWriteMetricsResourceId(writeMetricsResourceId)
+                .setCodecStageKey(s"shuffle=${dep.shuffleId}")
+                .build())
+            .build()
+          val iterator = NativeSupports.executeNativePlan(
+            nativeShuffleWriterExec,
+            nativeShuffleRDD.metrics,
+            partition,
+            context,
+            nativeShuffleRDD.nativeExplain)
+          assert(iterator.toArray.isEmpty)
+          partitionLengths =
+            JniBridge.commitShuffleOutput(dataFilePath, indexFilePath, context.taskAttemptId())
+        } finally {
+          JniBridge.resourcesMap.remove(writeMetricsResourceId)
+          if (partitionLengths == null) {
+            JniBridge.abortShuffleOutput(dataFilePath, indexFilePath, context.taskAttemptId())
+          }
+        }
+        MapStatus.apply(SparkEnv.get.blockManager.shuffleServerId, partitionLengths, mapId)
+      }
 
-        val nativeShuffleRDD =
-          rdd.asInstanceOf[MapPartitionsRDD[_, _]].prev.asInstanceOf[NativeRDD]
-        val nativeShuffleWriterExec = PhysicalPlanNode
-          .newBuilder()
-          .setShuffleWriter(
-            ShuffleWriterExecNode
-              .newBuilder(nativeShuffleRDD.nativePlan(partition, context).getShuffleWriter)
-              .setOutputDataFile(tempDataFilePath)
-              .setOutputIndexFile(tempIndexFilePath)
-              .build())
-          .build()
-        val iterator = NativeSupports.executeNativePlan(
-          nativeShuffleWriterExec,
-          nativeShuffleRDD.metrics,
-          partition,
-          context)
-        assert(iterator.toArray.isEmpty)
-
-        // get partition lengths from shuffle write output index file
-        var offset = 0L
-        val partitionLengths = Files
-          .readAllBytes(Paths.get(tempIndexFilePath))
-          .grouped(8)
-          .drop(1) // first partition offset is always 0
-          .map(indexBytes => {
-            val partitionOffset =
-              ByteBuffer.wrap(indexBytes).order(ByteOrder.LITTLE_ENDIAN).getLong
-            val partitionLength = partitionOffset - offset
-            offset = partitionOffset
-            partitionLength
-          })
-          .toArray
-
-        // commit
-        shuffleBlockResolver.writeIndexFileAndCommit(
-          dep.shuffleId,
+      private def writeRss(
+          rdd: RDD[_],
+          dep: ShuffleDependency[_, _, _],
+          mapId: Long,
+          context: TaskContext,
+          partition: Partition,
+          rssPartitionWriterFactory: RssPartitionWriterFactory): MapStatus = {
+
+        val writeMetrics = createMetricsReporter(context)
+        val rssPartitionWriter = rssPartitionWriterFactory.create(
+          dep,
           mapId,
-          partitionLengths,
-          Paths.get(tempDataFilePath).toFile)
+          context,
+          writeMetrics)
+        val rssPartitionWriterResourceId =
+          s"RssPartitionWriter:${UUID.randomUUID().toString}"
+        JniBridge.resourcesMap.put(rssPartitionWriterResourceId, () => rssPartitionWriter)
+
+        // bytes written are reported by the rss partition writer
+        val writeMetricsResourceId =
+          registerWriteMetrics(context, writeMetrics, countBytesWritten = false)
+
+        try {
+          val nativeShuffleRDD =
+            rdd.asInstanceOf[MapPartitionsRDD[_, _]].prev.asInstanceOf[NativeRDD]
+          NativeAccumulators.bindTask(context, nativeShuffleRDD.nativeAccumulators)
+          val nativeShuffleWriterExec = PhysicalPlanNode
+            .newBuilder()
+            .setShuffleWriter(
+              ShuffleWriterExecNode
+                .newBuilder(nativeShuffleRDD.nativePlan(partition, context).getShuffleWriter)
+                .setRssPartitionWriterResourceId(rssPartitionWriterResourceId)
+                .setWriteMetricsResourceId(writeMetricsResourceId)
+                .setCodecStageKey(s"shuffle=${dep.shuffleId}")
+                .build())
+            .build()
+          val iterator = NativeSupports.executeNativePlan(
+            nativeShuffleWriterExec,
+            nativeShuffleRDD.metrics,
+            partition,
+            context,
+            nativeShuffleRDD.nativeExplain)
+          assert(iterator.toArray.isEmpty)
+        } finally {
+          JniBridge.resourcesMap.remove(rssPartitionWriterResourceId)
+          JniBridge.resourcesMap.remove(writeMetricsResourceId)
+          rssPartitionWriter.close()
+        }
+
+        val partitionLengths = rssPartitionWriter.getPartitionLengthMap
         MapStatus.apply(SparkEnv.get.blockManager.shuffleServerId, partitionLengths, mapId)
       }
+
+      /** registers the updater of shuffle write metrics pushed by native shuffle writer */
+      private def registerWriteMetrics(
+          context: TaskContext,
+          writeMetrics: ShuffleWriteMetricsReporter,
+          countBytesWritten: Boolean = true): String = {
+        val writeMetricsResourceId =
+          s"NativeShuffleWriteMetrics:${UUID.randomUUID().toString}"
+        JniBridge.resourcesMap.put(
+          writeMetricsResourceId,
+          new NativeShuffleWriteMetrics(
+            writeMetrics,
+            context.taskMetrics(),
+            countBytesWritten))
+        writeMetricsResourceId
+      }
     }
   }
 
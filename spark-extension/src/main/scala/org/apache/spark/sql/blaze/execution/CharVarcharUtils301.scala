@@ -0,0 +1,120 @@
+/*
+ * Copyright 2022 The Blaze Authors
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+package org.apache.spark.sql.blaze.execution
+
+import java.util.Locale
+
+import org.apache.spark.sql.blaze.Util.addRenameColumnsExec
+import org.apache.spark.sql.blaze.plan.NativeProjectExec
+import org.apache.spark.sql.catalyst.expressions.Alias
+import org.apache.spark.sql.catalyst.expressions.Attribute
+import org.apache.spark.sql.catalyst.expressions.Expression
+import org.apache.spark.sql.catalyst.expressions.Literal
+import org.apache.spark.sql.catalyst.expressions.NamedExpression
+import org.apache.spark.sql.catalyst.expressions.objects.StaticInvoke
+import org.apache.spark.sql.execution.SparkPlan
+import org.apache.spark.sql.internal.SQLConf
+import org.apache.spark.sql.types.HIVE_TYPE_STRING
+import org.apache.spark.sql.types.Metadata
+import org.apache.spark.sql.types.StringType
+import org.apache.spark.sql.types.StructType
+
+/**
+ * Char/varchar semantics of spark 3.1+ for native scans and writes, ported from
+ * CharVarcharUtils. spark 3.0 reads CHAR(n)/VARCHAR(n) columns of hive tables as strings, with
+ * the original hive types kept in the field metadata. when spark.blaze.charVarchar.enabled is
+ * set in the session, values of CHAR(n) columns are padded to n characters by native scans, and
+ * values written natively are padded or checked against the length limits.
+ */
+object CharVarcharUtils301 {
+  private val CHAR_PATTERN = "char\\((\\d+)\\)".r
+  private val VARCHAR_PATTERN = "varchar\\((\\d+)\\)".r
+
+  def enabled: Boolean =
+    SQLConf.get.getConfString("spark.blaze.charVarchar.enabled", "false").toBoolean
+
+  /** returns (isChar, length) if the field is a hive CHAR(n) or VARCHAR(n) column */
+  def getRawType(metadata: Metadata): Option[(Boolean, Int)] = {
+    if (!metadata.contains(HIVE_TYPE_STRING)) {
+      return None
+    }
+    metadata.getString(HIVE_TYPE_STRING).trim.toLowerCase(Locale.ROOT) match {
+      case CHAR_PATTERN(length) => Some((true, length.toInt))
+      case VARCHAR_PATTERN(length) => Some((false, length.toInt))
+      case _ => None
+    }
+  }
+
+  /** pads CHAR(n) columns of the native scan, keeping the output attributes of the scan */
+  def addPaddingForScan(scan: SparkPlan): SparkPlan = {
+    if (!enabled) {
+      return scan
+    }
+    val projectList = scan.output.map { attr =>
+      getRawType(attr.metadata) match {
+        case Some((true, length)) if attr.dataType == StringType =>
+          aliased(invoke("readSidePadding", attr, length), attr)
+        case _ => attr
+      }
+    }
+    if (projectList.forall(_.isInstanceOf[Attribute])) {
+      return scan
+    }
+    NativeProjectExec(projectList, addRenameColumnsExec(scan))
+  }
+
+  /**
+   * pads or checks columns of the native child written into CHAR(n) or VARCHAR(n) columns of
+   * the table, keeping the output attributes of the child
+   */
+  def addWriteSideCheck(
+      child: SparkPlan,
+      outputColumns: Seq[Attribute],
+      tableSchema: StructType): SparkPlan = {
+    if (!enabled) {
+      return child
+    }
+    val resolver = SQLConf.get.resolver
+    val projectList = outputColumns.map { attr =>
+      val rawType = tableSchema
+        .find(field => resolver(field.name, attr.name))
+        .flatMap(field => getRawType(field.metadata))
+      rawType match {
+        case Some((true, length)) if attr.dataType == StringType =>
+          aliased(invoke("charTypeWriteSideCheck", attr, length), attr)
+        case Some((false, length)) if attr.dataType == StringType =>
+          aliased(invoke("varcharTypeWriteSideCheck", attr, length), attr)
+        case _ => attr
+      }
+    }
+    if (projectList.forall(_.isInstanceOf[Attribute])) {
+      return child
+    }
+    NativeProjectExec(projectList, addRenameColumnsExec(child))
+  }
+
+  private def invoke(functionName: String, child: Expression, length: Int): Expression =
+    StaticInvoke(
+      classOf[CharVarcharCodegenUtils301],
+      StringType,
+      functionName,
+      child :: Literal(length) :: Nil,
+      returnNullable = false)
+
+  private def aliased(expr: Expression, attr: Attribute): NamedExpression =
+    Alias(expr, attr.name)(attr.exprId, attr.qualifier, Some(attr.metadata))
+}
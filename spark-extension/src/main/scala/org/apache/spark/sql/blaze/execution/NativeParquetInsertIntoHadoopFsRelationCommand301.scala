@@ -0,0 +1,576 @@
+/*
+ * Copyright 2022 The Blaze Authors
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+package org.apache.spark.sql.blaze.execution
+
+import java.io.IOException
+import java.util.Date
+import java.util.UUID
+
+import scala.collection.mutable
+
+import org.apache.hadoop.conf.Configuration
+import org.apache.hadoop.fs.FileSystem
+import org.apache.hadoop.fs.Path
+import org.apache.hadoop.mapreduce.Job
+import org.apache.hadoop.mapreduce.TaskAttemptID
+import org.apache.hadoop.mapreduce.TaskID
+import org.apache.hadoop.mapreduce.TaskType
+import org.apache.hadoop.mapreduce.lib.output.FileOutputFormat
+import org.apache.hadoop.mapreduce.task.TaskAttemptContextImpl
+import org.apache.spark.SPARK_VERSION_SHORT
+import org.apache.spark.SparkEnv
+import org.apache.spark.SparkException
+import org.apache.spark.TaskContext
+import org.apache.spark.internal.Logging
+import org.apache.spark.internal.io.FileCommitProtocol
+import org.apache.spark.internal.io.SparkHadoopWriterUtils
+import org.apache.spark.shuffle.FetchFailedException
+import org.apache.spark.sql.AnalysisException
+import org.apache.spark.sql.Row
+import org.apache.spark.sql.SPARK_VERSION_METADATA_KEY
+import org.apache.spark.sql.SaveMode
+import org.apache.spark.sql.SparkSession
+import org.apache.spark.sql.blaze.ConvertToUnsafeRowExec
+import org.apache.spark.sql.blaze.NativeConverters
+import org.apache.spark.sql.blaze.NativeSupports
+import org.apache.spark.sql.blaze.Util.addRenameColumnsExec
+import org.apache.spark.sql.blaze.plan.NativeParquetSinkExec
+import org.apache.spark.sql.blaze.plan.NativeRenameColumnsExec
+import org.apache.spark.sql.blaze.plan.NativeSortExec
+import org.apache.spark.sql.catalyst.InternalRow
+import org.apache.spark.sql.catalyst.catalog.CatalogTable
+import org.apache.spark.sql.catalyst.catalog.CatalogTablePartition
+import org.apache.spark.sql.catalyst.catalog.CatalogTypes.TablePartitionSpec
+import org.apache.spark.sql.catalyst.catalog.ExternalCatalogUtils.escapePathName
+import org.apache.spark.sql.catalyst.expressions.Ascending
+import org.apache.spark.sql.catalyst.expressions.AttributeSet
+import org.apache.spark.sql.catalyst.expressions.SortOrder
+import org.apache.spark.sql.catalyst.plans.logical.LogicalPlan
+import org.apache.spark.sql.catalyst.util.CaseInsensitiveMap
+import org.apache.spark.sql.execution.SparkPlan
+import org.apache.spark.sql.execution.adaptive.AdaptiveSparkPlanExec
+import org.apache.spark.sql.execution.command.AlterTableAddPartitionCommand
+import org.apache.spark.sql.execution.command.AlterTableDropPartitionCommand
+import org.apache.spark.sql.execution.command.CommandUtils
+import org.apache.spark.sql.execution.command.DataWritingCommand
+import org.apache.spark.sql.execution.datasources.BasicWriteTaskStats
+import org.apache.spark.sql.execution.datasources.DataSourceUtils
+import org.apache.spark.sql.execution.datasources.ExecutedWriteSummary
+import org.apache.spark.sql.execution.datasources.InsertIntoHadoopFsRelationCommand
+import org.apache.spark.sql.execution.datasources.PartitioningUtils
+import org.apache.spark.sql.execution.datasources.WriteTaskResult
+import org.apache.spark.sql.execution.datasources.parquet.ParquetOptions
+import org.apache.spark.sql.execution.datasources.parquet.ParquetReadSupport
+import org.apache.spark.sql.types.BooleanType
+import org.apache.spark.sql.types.ByteType
+import org.apache.spark.sql.types.DateType
+import org.apache.spark.sql.types.IntegerType
+import org.apache.spark.sql.types.LongType
+import org.apache.spark.sql.types.ShortType
+import org.apache.spark.sql.types.StringType
+import org.apache.spark.sql.types.StructType
+import org.apache.spark.sql.types.TimestampType
+import org.apache.spark.sql.util.SchemaUtils
+import org.apache.spark.util.SerializableConfiguration
+import org.apache.spark.util.Utils
+import org.blaze.protobuf.ParquetCompression
+
+/**
+ * InsertIntoHadoopFsRelationCommand (spark 3.0.3) writing parquet files natively.
+ *
+ * run() is ported from InsertIntoHadoopFsRelationCommand, with FileFormatWriter.write()
+ * replaced by writeNative(): rows are written by NativeParquetSinkExec into a staging directory
+ * of each task, and the written files are moved to the paths given by the commit protocol before
+ * committing the task, so that file names, partition paths and job commits are the same as
+ * spark's. falls back to the based command if the final plan of the query is not native.
+ */
+case class NativeParquetInsertIntoHadoopFsRelationCommand301(
+    basedCommand: InsertIntoHadoopFsRelationCommand)
+    extends DataWritingCommand
+    with Logging {
+  import NativeParquetInsertIntoHadoopFsRelationCommand301._
+
+  override def query: LogicalPlan = basedCommand.query
+  override def outputColumnNames: Seq[String] = basedCommand.outputColumnNames
+
+  override def run(sparkSession: SparkSession, child: SparkPlan): Seq[Row] = {
+    val InsertIntoHadoopFsRelationCommand(
+      outputPath,
+      staticPartitions,
+      ifPartitionNotExists,
+      partitionColumns,
+      _,
+      _,
+      options,
+      _,
+      mode,
+      catalogTable,
+      fileIndex,
+      _) = basedCommand
+
+    val nativeChild = getNativeChild(child)
+    if (nativeChild.isEmpty) {
+      logInfo(s"Writing $outputPath with spark, the query is not fully native")
+      return basedCommand.run(sparkSession, child)
+    }
+
+    // Most formats don't do well with duplicate columns, so lets not allow that
+    SchemaUtils.checkColumnNameDuplication(
+      outputColumnNames,
+      s"when inserting into $outputPath",
+      sparkSession.sessionState.conf.caseSensitiveAnalysis)
+
+    val hadoopConf = sparkSession.sessionState.newHadoopConfWithOptions(options)
+    val fs = outputPath.getFileSystem(hadoopConf)
+    val qualifiedOutputPath = outputPath.makeQualified(fs.getUri, fs.getWorkingDirectory)
+
+    val partitionsTrackedByCatalog = sparkSession.sessionState.conf.manageFilesourcePartitions &&
+      catalogTable.isDefined &&
+      catalogTable.get.partitionColumnNames.nonEmpty &&
+      catalogTable.get.tracksPartitionsInCatalog
+
+    var initialMatchingPartitions: Seq[TablePartitionSpec] = Nil
+    var customPartitionLocations: Map[TablePartitionSpec, String] = Map.empty
+    var matchingPartitions: Seq[CatalogTablePartition] = Seq.empty
+
+    // When partitions are tracked by the catalog, compute all custom partition locations that
+    // may be relevant to the insertion job.
+    if (partitionsTrackedByCatalog) {
+      matchingPartitions = sparkSession.sessionState.catalog
+        .listPartitions(catalogTable.get.identifier, Some(staticPartitions))
+      initialMatchingPartitions = matchingPartitions.map(_.spec)
+      customPartitionLocations =
+        getCustomPartitionLocations(fs, catalogTable.get, qualifiedOutputPath, matchingPartitions)
+    }
+
+    val jobId = UUID.randomUUID().toString
+    val committer = FileCommitProtocol.instantiate(
+      sparkSession.sessionState.conf.fileCommitProtocolClass,
+      jobId = jobId,
+      outputPath = outputPath.toString,
+      dynamicPartitionOverwrite = basedCommand.dynamicPartitionOverwrite)
+
+    val doInsertion = if (mode == SaveMode.Append) {
+      true
+    } else {
+      val pathExists = fs.exists(qualifiedOutputPath)
+      (mode, pathExists) match {
+        case (SaveMode.ErrorIfExists, true) =>
+          throw new AnalysisException(s"path $qualifiedOutputPath already exists.")
+        case (SaveMode.Overwrite, true) =>
+          if (ifPartitionNotExists && matchingPartitions.nonEmpty) {
+            false
+          } else if (basedCommand.dynamicPartitionOverwrite) {
+            // For dynamic partition overwrite, do not delete partition directories ahead.
+            true
+          } else {
+            deleteMatchingPartitions(fs, qualifiedOutputPath, customPartitionLocations, committer)
+            true
+          }
+        case (SaveMode.Overwrite, _) | (SaveMode.ErrorIfExists, false) =>
+          true
+        case (SaveMode.Ignore, exists) =>
+          !exists
+        case (s, exists) =>
+          throw new IllegalStateException(s"unsupported save mode $s ($exists)")
+      }
+    }
+
+    if (doInsertion) {
+
+      def refreshUpdatedPartitions(updatedPartitionPaths: Set[String]): Unit = {
+        val updatedPartitions = updatedPartitionPaths.map(PartitioningUtils.parsePathFragment)
+        if (partitionsTrackedByCatalog) {
+          val newPartitions = updatedPartitions -- initialMatchingPartitions
+          if (newPartitions.nonEmpty) {
+            AlterTableAddPartitionCommand(
+              catalogTable.get.identifier,
+              newPartitions.toSeq.map(p => (p, None)),
+              ifNotExists = true).run(sparkSession)
+          }
+          // For dynamic partition overwrite, we never remove partitions but only update existing
+          // ones.
+          if (mode == SaveMode.Overwrite && !basedCommand.dynamicPartitionOverwrite) {
+            val deletedPartitions = initialMatchingPartitions.toSet -- updatedPartitions
+            if (deletedPartitions.nonEmpty) {
+              AlterTableDropPartitionCommand(
+                catalogTable.get.identifier,
+                deletedPartitions.toSeq,
+                ifExists = true,
+                purge = false,
+                retainData = true /* already deleted */ ).run(sparkSession)
+            }
+          }
+        }
+      }
+
+      val updatedPartitionPaths = writeNative(
+        sparkSession,
+        nativeChild.get,
+        jobId,
+        committer,
+        qualifiedOutputPath,
+        customPartitionLocations,
+        hadoopConf)
+
+      // update metastore partition metadata
+      if (updatedPartitionPaths.isEmpty && staticPartitions.nonEmpty
+        && partitionColumns.length == staticPartitions.size) {
+        // Avoid empty static partition can't loaded to datasource table.
+        val staticPathFragment =
+          PartitioningUtils.getPathFragment(staticPartitions, partitionColumns)
+        refreshUpdatedPartitions(Set(staticPathFragment))
+      } else {
+        refreshUpdatedPartitions(updatedPartitionPaths)
+      }
+
+      // refresh cached files in FileIndex
+      fileIndex.foreach(_.refresh())
+      // refresh data cache if table is cached
+      sparkSession.catalog.refreshByPath(outputPath.toString)
+
+      if (catalogTable.nonEmpty) {
+        CommandUtils.updateTableStats(sparkSession, catalogTable.get)
+      }
+
+    } else {
+      logInfo("Skipping insertion into a relation that already exists.")
+    }
+
+    Seq.empty[Row]
+  }
+
+  /** writes the native child like FileFormatWriter.write(), returns the updated partitions */
+  private def writeNative(
+      sparkSession: SparkSession,
+      nativeChild: SparkPlan,
+      jobId: String,
+      committer: FileCommitProtocol,
+      qualifiedOutputPath: Path,
+      customPartitionLocations: Map[TablePartitionSpec, String],
+      hadoopConf: Configuration): Set[String] = {
+    val partitionColumns = basedCommand.partitionColumns
+    val bucketSpec = basedCommand.bucketSpec
+
+    val job = Job.getInstance(hadoopConf)
+    job.setOutputKeyClass(classOf[Void])
+    job.setOutputValueClass(classOf[InternalRow])
+    FileOutputFormat.setOutputPath(job, qualifiedOutputPath)
+
+    val partitionSet = AttributeSet(partitionColumns)
+    val dataColumns = outputColumns.filterNot(partitionSet.contains)
+    val dataSchema = dataColumns.toStructType
+    val caseInsensitiveOptions = CaseInsensitiveMap(basedCommand.options)
+    DataSourceUtils.verifySchema(basedCommand.fileFormat, dataSchema)
+
+    // sets up the output committer of the job, the output writer factory is not used
+    basedCommand.fileFormat.prepareWrite(sparkSession, job, caseInsensitiveOptions, dataSchema)
+
+    val (compression, fileExtension) = getCompression(sparkSession, caseInsensitiveOptions)
+    val maxRecordsPerFile = caseInsensitiveOptions
+      .get("maxRecordsPerFile")
+      .map(_.toLong)
+      .getOrElse(sparkSession.sessionState.conf.maxRecordsPerFile)
+    val maxFileBytes =
+      SparkEnv.get.conf.getSizeAsBytes("spark.blaze.parquetSink.maxFileBytes", "0")
+
+    // files of each bucket are kept open by the native writer, so rows are only required to be
+    // sorted by partition columns and sort columns of buckets, without bucket ids
+    val sortColumns = bucketSpec.toSeq.flatMap { spec =>
+      spec.sortColumnNames.map(c => dataColumns.find(_.name == c).get)
+    }
+    val checkedChild = basedCommand.catalogTable match {
+      case Some(table) =>
+        CharVarcharUtils301.addWriteSideCheck(nativeChild, outputColumns, table.schema)
+      case None => nativeChild
+    }
+    val requiredOrdering = partitionColumns ++ sortColumns
+    val actualOrdering = checkedChild.outputOrdering.map(_.child)
+    val orderingMatched = requiredOrdering.length <= actualOrdering.length &&
+      requiredOrdering.zip(actualOrdering).forall {
+        case (requiredOrder, childOutputOrder) => requiredOrder.semanticEquals(childOutputOrder)
+      }
+    val sortedChild = if (orderingMatched) {
+      checkedChild
+    } else {
+      NativeSortExec(
+        requiredOrdering.map(SortOrder(_, Ascending)),
+        global = false,
+        addRenameColumnsExec(checkedChild))
+    }
+
+    val stagingDir = new Path(qualifiedOutputPath, s"_blaze-staging-$jobId")
+    val sink = NativeParquetSinkExec(
+      NativeRenameColumnsExec(sortedChild, outputColumns.map(_.name)),
+      stagingDir.toString,
+      partitionColumns.map(_.name),
+      bucketSpec,
+      compression,
+      fileExtension,
+      math.max(maxRecordsPerFile, 0L),
+      maxFileBytes,
+      Map(
+        ParquetReadSupport.SPARK_METADATA_KEY -> dataSchema.json,
+        SPARK_VERSION_METADATA_KEY -> SPARK_VERSION_SHORT))
+    val rdd = NativeSupports.executeNative(sink)
+
+    committer.setupJob(job)
+    try {
+      val serializableHadoopConf = new SerializableConfiguration(job.getConfiguration)
+      val jobIdInstant = new Date().getTime
+      val ret = new Array[WriteTaskResult](rdd.partitions.length)
+      sparkSession.sparkContext.runJob(
+        rdd,
+        (taskContext: TaskContext, iter: Iterator[InternalRow]) => {
+          executeTask(
+            committer,
+            serializableHadoopConf.value,
+            jobIdInstant,
+            stagingDir.toString,
+            customPartitionLocations,
+            taskContext,
+            iter)
+        },
+        rdd.partitions.indices,
+        (index, res: WriteTaskResult) => {
+          committer.onTaskCommit(res.commitMsg)
+          ret(index) = res
+        })
+
+      val commitMsgs = ret.map(_.commitMsg)
+      committer.commitJob(job, commitMsgs)
+      basicWriteJobStatsTracker(hadoopConf).processStats(ret.flatMap(_.summary.stats))
+      ret.map(_.summary.updatedPartitions).reduceOption(_ ++ _).getOrElse(Set.empty)
+    } catch {
+      case cause: Throwable =>
+        logError(s"Aborting native parquet writing job of $qualifiedOutputPath.", cause)
+        committer.abortJob(job)
+        throw new SparkException("Job aborted.", cause)
+    } finally {
+      stagingDir.getFileSystem(hadoopConf).delete(stagingDir, true)
+    }
+  }
+
+  /**
+   * Deletes all partition files that match the specified static prefix. Partitions with custom
+   * locations are also cleared based on the custom locations map given to this class.
+   */
+  private def deleteMatchingPartitions(
+      fs: FileSystem,
+      qualifiedOutputPath: Path,
+      customPartitionLocations: Map[TablePartitionSpec, String],
+      committer: FileCommitProtocol): Unit = {
+    val staticPartitions = basedCommand.staticPartitions
+    val staticPartitionPrefix = if (staticPartitions.nonEmpty) {
+      "/" + basedCommand.partitionColumns
+        .flatMap { p =>
+          staticPartitions.get(p.name) match {
+            case Some(value) =>
+              Some(escapePathName(p.name) + "=" + escapePathName(value))
+            case None =>
+              None
+          }
+        }
+        .mkString("/")
+    } else {
+      ""
+    }
+    // first clear the path determined by the static partition keys (e.g. /table/foo=1)
+    val staticPrefixPath = qualifiedOutputPath.suffix(staticPartitionPrefix)
+    if (fs.exists(staticPrefixPath) && !committer.deleteWithJob(fs, staticPrefixPath, true)) {
+      throw new IOException(
+        s"Unable to clear output directory $staticPrefixPath prior to writing to it")
+    }
+    // now clear all custom partition locations (e.g. /custom/dir/where/foo=2/bar=4)
+    for ((spec, customLoc) <- customPartitionLocations) {
+      assert(
+        (staticPartitions.toSet -- spec).isEmpty,
+        "Custom partition location did not match static partitioning keys")
+      val path = new Path(customLoc)
+      if (fs.exists(path) && !committer.deleteWithJob(fs, path, true)) {
+        throw new IOException(s"Unable to clear partition directory $path prior to writing to it")
+      }
+    }
+  }
+
+  /**
+   * Given a set of input partitions, returns those that have locations that differ from the
+   * Hive default (e.g. /k1=v1/k2=v2). These partitions were manually assigned locations by
+   * the user.
+   */
+  private def getCustomPartitionLocations(
+      fs: FileSystem,
+      table: CatalogTable,
+      qualifiedOutputPath: Path,
+      partitions: Seq[CatalogTablePartition]): Map[TablePartitionSpec, String] = {
+    partitions.flatMap { p =>
+      val defaultLocation = qualifiedOutputPath
+        .suffix("/" + PartitioningUtils.getPathFragment(p.spec, table.partitionSchema))
+        .toString
+      val catalogLocation =
+        new Path(p.location).makeQualified(fs.getUri, fs.getWorkingDirectory).toString
+      if (catalogLocation != defaultLocation) {
+        Some(p.spec -> catalogLocation)
+      } else {
+        None
+      }
+    }.toMap
+  }
+}
+
+object NativeParquetInsertIntoHadoopFsRelationCommand301 extends Logging {
+
+  /** throws NotImplementedError if the command cannot be written natively */
+  def checkSupported(sparkSession: SparkSession, cmd: InsertIntoHadoopFsRelationCommand): Unit = {
+    getCompression(sparkSession, CaseInsensitiveMap(cmd.options))
+
+    // partition values are formatted natively into directory names
+    cmd.partitionColumns.foreach { p =>
+      p.dataType match {
+        case BooleanType | ByteType | ShortType | IntegerType | LongType | StringType |
+            DateType =>
+        case other =>
+          throw new NotImplementedError(s"Data type of partition column is not supported: $other")
+      }
+    }
+
+    // spark writes timestamps as INT96 by default, which is not written natively
+    val partitionSet = AttributeSet(cmd.partitionColumns)
+    val dataColumns = cmd.outputColumns.filterNot(partitionSet.contains)
+    if (dataColumns.exists(_.dataType.existsRecursively(_ == TimestampType))) {
+      throw new NotImplementedError("Data type of writing parquet is not supported: timestamp")
+    }
+    NativeConverters.convertSchema(StructType.fromAttributes(cmd.outputColumns))
+  }
+
+  /** returns the native compression and spark's file extension of the parquet codec */
+  private def getCompression(
+      sparkSession: SparkSession,
+      options: CaseInsensitiveMap[String]): (ParquetCompression, String) = {
+    new ParquetOptions(options, sparkSession.sessionState.conf).compressionCodecClassName match {
+      case "UNCOMPRESSED" => (ParquetCompression.UNCOMPRESSED, ".parquet")
+      case "SNAPPY" => (ParquetCompression.SNAPPY, ".snappy.parquet")
+      case "GZIP" => (ParquetCompression.GZIP, ".gz.parquet")
+      case "ZSTD" => (ParquetCompression.ZSTD, ".zstd.parquet")
+      case other =>
+        // lz4 of parquet-mr is framed by hadoop, which is not compatible with native writers
+        throw new NotImplementedError(s"Parquet compression codec is not supported: $other")
+    }
+  }
+
+  /** returns the native plan under the final plan of the query, None if it is not native */
+  private def getNativeChild(child: SparkPlan): Option[SparkPlan] = {
+    val finalPlan = child match {
+      case adaptive: AdaptiveSparkPlanExec =>
+        adaptive.execute() // materializes query stages and finalizes the plan
+        adaptive.executedPlan
+      case plan => plan
+    }
+    val nativeChild = finalPlan match {
+      case ConvertToUnsafeRowExec(native) if NativeSupports.isNative(native) => Some(native)
+      case plan if NativeSupports.isNative(plan) => Some(plan)
+      case _ => None
+    }
+
+    // like spark, an empty file is written for queries without partitions, which is not
+    // supported by native writers
+    nativeChild.filter(NativeSupports.executeNative(_).partitions.nonEmpty)
+  }
+
+  /** executes a task writing rows like FileFormatWriter.executeTask() */
+  private def executeTask(
+      committer: FileCommitProtocol,
+      hadoopConf: Configuration,
+      jobIdInstant: Long,
+      stagingDir: String,
+      customPartitionLocations: Map[TablePartitionSpec, String],
+      taskContext: TaskContext,
+      writtenFiles: Iterator[InternalRow]): WriteTaskResult = {
+
+    val jobId = SparkHadoopWriterUtils.createJobID(new Date(jobIdInstant), taskContext.stageId())
+    val taskId = new TaskID(jobId, TaskType.MAP, taskContext.partitionId())
+    val taskAttemptId = new TaskAttemptID(taskId, taskContext.attemptNumber())
+
+    // Set up the attempt context required to use in the output committer.
+    hadoopConf.set("mapreduce.job.id", jobId.toString)
+    hadoopConf.set("mapreduce.task.id", taskAttemptId.getTaskID.toString)
+    hadoopConf.set("mapreduce.task.attempt.id", taskAttemptId.toString)
+    hadoopConf.setBoolean("mapreduce.task.ismap", true)
+    hadoopConf.setInt("mapreduce.task.partition", 0)
+    val taskAttemptContext = new TaskAttemptContextImpl(hadoopConf, taskAttemptId)
+    committer.setupTask(taskAttemptContext)
+
+    // same output dir as the native plan of the task, see NativeParquetSinkExec
+    val taskOutputDir = new Path(
+      NativeParquetSinkExec.taskOutputDir(
+        stagingDir,
+        taskContext.partitionId(),
+        taskContext.taskAttemptId()))
+    val fs = taskOutputDir.getFileSystem(hadoopConf)
+
+    try {
+      Utils.tryWithSafeFinallyAndFailureCallbacks(block = {
+        val updatedPartitions = mutable.LinkedHashSet[String]()
+        var numFiles = 0
+        var numRows = 0L
+        var numBytes = 0L
+
+        // files are written natively while consuming the output
+        writtenFiles.foreach { row =>
+          val partition = if (row.isNullAt(0)) None else Some(row.getUTF8String(0).toString)
+          val stagedPath = new Path(taskOutputDir, row.getUTF8String(1).toString)
+          val ext = row.getUTF8String(2).toString
+
+          val customPath = partition.flatMap { dir =>
+            customPartitionLocations.get(PartitioningUtils.parsePathFragment(dir))
+          }
+          val path = new Path(customPath match {
+            case Some(customPath) =>
+              committer.newTaskTempFileAbsPath(taskAttemptContext, customPath, ext)
+            case None =>
+              committer.newTaskTempFile(taskAttemptContext, partition, ext)
+          })
+          fs.mkdirs(path.getParent)
+          if (!fs.rename(stagedPath, path)) {
+            throw new IOException(s"Failed to rename $stagedPath to $path")
+          }
+
+          partition.foreach(updatedPartitions.add)
+          numFiles += 1
+          numRows += row.getLong(3)
+          numBytes += row.getLong(4)
+        }
+
+        val commitMsg = committer.commitTask(taskAttemptContext)
+        val stats = BasicWriteTaskStats(updatedPartitions.size, numFiles, numBytes, numRows)
+        WriteTaskResult(commitMsg, ExecutedWriteSummary(updatedPartitions.toSet, Seq(stats)))
+      })(
+        catchBlock = {
+          committer.abortTask(taskAttemptContext)
+          logError(s"Job $jobId aborted.")
+        },
+        finallyBlock = {
+          fs.delete(taskOutputDir, true)
+        })
+    } catch {
+      case e: FetchFailedException =>
+        throw e
+      case t: Throwable =>
+        throw new SparkException("Task failed while writing rows.", t)
+    }
+  }
+}
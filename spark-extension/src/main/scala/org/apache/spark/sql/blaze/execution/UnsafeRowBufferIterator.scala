@@ -0,0 +1,84 @@
+/*
+ * Copyright 2022 The Blaze Authors
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+package org.apache.spark.sql.blaze.execution
+
+import java.nio.ByteBuffer
+import java.nio.ByteOrder
+
+import org.apache.spark.sql.catalyst.InternalRow
+import org.apache.spark.sql.catalyst.expressions.UnsafeProjection
+import org.apache.spark.sql.types._
+import org.apache.spark.unsafe.Platform
+
+/**
+ * Packs rows into direct ByteBuffers of size-prefixed UnsafeRows, which are decoded natively by
+ * RowInputExec.
+ *
+ * NOTE: the returned buffer is reused by the following next() call.
+ */
+class UnsafeRowBufferIterator(
+    rowIter: Iterator[InternalRow],
+    schema: StructType,
+    recordBatchSize: Int = 10000)
+    extends Iterator[ByteBuffer] {
+
+  private val projection = UnsafeProjection.create(schema)
+  private var buffer = allocate(1024 * 1024)
+
+  override def hasNext: Boolean = rowIter.hasNext
+  override def next(): ByteBuffer = {
+    buffer.clear()
+    var numRows = 0
+    while (rowIter.hasNext && numRows < recordBatchSize) {
+      val row = projection(rowIter.next())
+      val rowSize = row.getSizeInBytes
+      ensureRemaining(4 + rowSize)
+      buffer.putInt(rowSize)
+      row.getBaseObject match {
+        case bytes: Array[Byte] =>
+          buffer.put(bytes, (row.getBaseOffset - Platform.BYTE_ARRAY_OFFSET).toInt, rowSize)
+        case _ =>
+          buffer.put(row.getBytes)
+      }
+      numRows += 1
+    }
+    buffer.flip()
+    buffer.slice()
+  }
+
+  private def ensureRemaining(numBytes: Int): Unit = {
+    if (buffer.remaining() < numBytes) {
+      val newBuffer = allocate(math.max(buffer.capacity() * 2, buffer.position() + numBytes))
+      buffer.flip()
+      newBuffer.put(buffer)
+      buffer = newBuffer
+    }
+  }
+
+  private def allocate(capacity: Int): ByteBuffer =
+    ByteBuffer.allocateDirect(capacity).order(ByteOrder.LITTLE_ENDIAN)
+}
+
+object UnsafeRowBufferIterator {
+  def isSupported(schema: StructType): Boolean =
+    schema.fields.forall(_.dataType match {
+      case BooleanType | ByteType | ShortType | IntegerType | LongType => true
+      case FloatType | DoubleType | DateType | StringType | BinaryType => true
+      case t: DecimalType => t.precision <= 18
+      case _ => false
+    })
+}
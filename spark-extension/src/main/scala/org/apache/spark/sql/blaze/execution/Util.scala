@@ -16,6 +16,16 @@
 
 package org.apache.spark.sql.blaze.execution
 
+import java.io.ByteArrayInputStream
+import java.io.IOException
+import java.nio.ByteBuffer
+import java.nio.ByteOrder
+import java.nio.charset.StandardCharsets
+import java.util.zip.CRC32
+
+import com.github.luben.zstd.ZstdInputStream
+import net.jpountz.lz4.LZ4Factory
+import org.apache.commons.compress.utils.IOUtils
 import org.apache.spark.internal.config.IO_COMPRESSION_CODEC
 import org.apache.spark.io.CompressionCodec
 import org.apache.spark.SparkEnv
@@ -23,7 +33,17 @@ import org.apache.spark.internal.Logging
 
 object Util extends Logging {
 
-  // only zstd compression is supported at the moment
+  // magic of lz4 segments chosen by native shuffle writer, zstd segments have no extra header
+  private val lz4SegmentMagic = "BLZ4".getBytes(StandardCharsets.US_ASCII)
+
+  // magics of zstd dictionary segments and of segments compressed with the preceding dictionary
+  private val zstdDictionarySegmentMagic = "BZDD".getBytes(StandardCharsets.US_ASCII)
+  private val zstdDictionaryCompressedMagic = "BZDC".getBytes(StandardCharsets.US_ASCII)
+
+  // magic of segments wrapped with crc32 checksums by native shuffle writer
+  private val checksumSegmentMagic = "BCRC".getBytes(StandardCharsets.US_ASCII)
+
+  // only zstd compression is supported by JVM-side writers at the moment
   def getZCodecForShuffle: CompressionCodec = {
     val sparkConf = SparkEnv.get.conf
     val zcodecConfName = "spark.blaze.shuffle.compression.codec"
@@ -36,4 +56,56 @@ object Util extends Logging {
     }
     CompressionCodec.createCodec(sparkConf, "zstd")
   }
+
+  /** decompresses a shuffle segment without its length trailer into arrow IPC file data */
+  def decompressSegment(zcodec: CompressionCodec, zdata: Array[Byte]): Array[Byte] = {
+    if (zdata.length >= 12 && zdata.take(4).sameElements(lz4SegmentMagic)) {
+      // magic, lz4 block length, uncompressed length and lz4 block, all little-endian
+      val uncompressedLength = ByteBuffer.wrap(zdata).order(ByteOrder.LITTLE_ENDIAN).getInt(8)
+      LZ4Factory.fastestInstance().fastDecompressor().decompress(zdata, 12, uncompressedLength)
+    } else {
+      IOUtils.toByteArray(zcodec.compressedInputStream(new ByteArrayInputStream(zdata)))
+    }
+  }
+
+  /**
+   * Decompresses the segments of a shuffle block in order, keeping the zstd dictionary embedded
+   * ahead of the segments compressed with it.
+   */
+  class SegmentDecompressor(zcodec: CompressionCodec) {
+    private var dictionary: Option[(Int, Array[Byte])] = None
+
+    /** returns arrow IPC file data of a segment without its trailer, None for dictionaries */
+    def decompress(zdata: Array[Byte]): Option[Array[Byte]] = {
+      val header = ByteBuffer.wrap(zdata).order(ByteOrder.LITTLE_ENDIAN)
+      if (zdata.length >= 8 && zdata.take(4).sameElements(checksumSegmentMagic)) {
+        // magic, crc32 of the wrapped segment data and the wrapped segment
+        val crc = new CRC32()
+        crc.update(zdata, 8, zdata.length - 8)
+        if (crc.getValue.toInt != header.getInt(4)) {
+          throw new IOException("corrupted shuffle segment: checksum mismatch")
+        }
+        decompress(zdata.drop(8))
+      } else if (zdata.length >= 8 && zdata.take(4).sameElements(zstdDictionarySegmentMagic)) {
+        // magic, dictionary length and the dictionary with its id at offset 4
+        val dictionaryData = zdata.slice(8, 8 + header.getInt(4))
+        val id = ByteBuffer.wrap(dictionaryData).order(ByteOrder.LITTLE_ENDIAN).getInt(4)
+        dictionary = Some((id, dictionaryData))
+        None
+      } else if (zdata.length >= 8 &&
+        zdata.take(4).sameElements(zstdDictionaryCompressedMagic)) {
+        // magic, dictionary id and the zstd frame
+        val id = header.getInt(4)
+        val dictionaryData = dictionary.collect {
+          case (dictionaryId, data) if dictionaryId == id => data
+        }.getOrElse {
+          throw new IllegalStateException(s"missing zstd dictionary $id of shuffle segment")
+        }
+        val input = new ZstdInputStream(new ByteArrayInputStream(zdata, 8, zdata.length - 8))
+        Some(IOUtils.toByteArray(input.setDict(dictionaryData)))
+      } else {
+        Some(decompressSegment(zcodec, zdata))
+      }
+    }
+  }
 }
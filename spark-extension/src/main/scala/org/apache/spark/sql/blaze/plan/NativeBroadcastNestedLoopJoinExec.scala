@@ -0,0 +1,151 @@
+/*
+ * Copyright 2022 The Blaze Authors
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+package org.apache.spark.sql.blaze.plan
+
+import org.apache.spark.OneToOneDependency
+import org.apache.spark.rdd.RDD
+import org.apache.spark.sql.blaze.ConvertToNativeExec
+import org.apache.spark.sql.blaze.MetricNode
+import org.apache.spark.sql.blaze.NativeConverters
+import org.apache.spark.sql.blaze.NativeRDD
+import org.apache.spark.sql.blaze.NativeSupports
+import org.apache.spark.sql.catalyst.InternalRow
+import org.apache.spark.sql.catalyst.expressions.Attribute
+import org.apache.spark.sql.catalyst.expressions.Expression
+import org.apache.spark.sql.catalyst.plans.Inner
+import org.apache.spark.sql.catalyst.plans.JoinType
+import org.apache.spark.sql.catalyst.plans.LeftAnti
+import org.apache.spark.sql.catalyst.plans.LeftOuter
+import org.apache.spark.sql.catalyst.plans.LeftSemi
+import org.apache.spark.sql.catalyst.plans.RightOuter
+import org.apache.spark.sql.catalyst.plans.physical.Partitioning
+import org.apache.spark.sql.execution.BinaryExecNode
+import org.apache.spark.sql.execution.SparkPlan
+import org.apache.spark.sql.execution.joins.BroadcastNestedLoopJoinExec
+import org.apache.spark.sql.execution.joins.BuildLeft
+import org.apache.spark.sql.execution.joins.BuildRight
+import org.apache.spark.sql.execution.joins.BuildSide
+import org.apache.spark.sql.execution.metric.SQLMetric
+import org.apache.spark.sql.types.StructField
+import org.apache.spark.sql.types.StructType
+import org.blaze.protobuf.JoinSide
+import org.blaze.protobuf.NestedLoopJoinExecNode
+import org.blaze.protobuf.PhysicalPlanNode
+import org.blaze.protobuf.Schema
+
+/**
+ * Joins the native streamed side with the broadcast rows, which are read natively by each task
+ * of the streamed side. the condition is evaluated natively as a nested-loop join.
+ */
+case class NativeBroadcastNestedLoopJoinExec(
+    override val left: SparkPlan,
+    override val right: SparkPlan,
+    buildSide: BuildSide,
+    joinType: JoinType,
+    condition: Option[Expression],
+    override val output: Seq[Attribute])
+    extends BinaryExecNode
+    with NativeSupports {
+
+  NativeBroadcastNestedLoopJoinExec.checkSupported(joinType, buildSide)
+
+  override lazy val metrics: Map[String, SQLMetric] =
+    NativeSupports.getDefaultNativeMetrics(sparkContext)
+
+  // metrics of the native input reading broadcast rows
+  private lazy val broadcastInputMetrics: Map[String, SQLMetric] =
+    NativeSupports.getDefaultNativeMetrics(sparkContext)
+
+  private val (streamed, broadcast) = buildSide match {
+    case BuildLeft => (right, left)
+    case BuildRight => (left, right)
+  }
+
+  override def outputPartitioning: Partitioning = streamed.outputPartitioning
+
+  private val nativeJoinType = NativeConverters.convertJoinType(joinType)
+
+  private val nativeCondition = condition.map(NativeConverters.convertExpr)
+
+  private val nativeBroadcastSchema: Schema = NativeConverters.convertSchema(
+    StructType(
+      broadcast.output.map(a => StructField(a.toString(), a.dataType, a.nullable, a.metadata))))
+
+  override def doExecuteNative(): NativeRDD = {
+    val streamedRDD = NativeSupports.executeNative(streamed)
+    val broadcastRows = broadcast.executeBroadcast[Array[InternalRow]]()
+    val broadcastSchema = broadcast.schema
+    val broadcastMetrics = MetricNode(broadcastInputMetrics, Nil)
+    val nativeMetrics = buildSide match {
+      case BuildLeft => MetricNode(metrics, Seq(broadcastMetrics, streamedRDD.metrics))
+      case BuildRight => MetricNode(metrics, Seq(streamedRDD.metrics, broadcastMetrics))
+    }
+
+    new NativeRDD(
+      sparkContext,
+      nativeMetrics,
+      streamedRDD.partitions,
+      Seq(new OneToOneDependency[InternalRow](streamedRDD.asInstanceOf[RDD[InternalRow]])),
+      (partition, taskContext) => {
+        val streamedChild = streamedRDD.nativePlan(partition, taskContext)
+        val broadcastChild = ConvertToNativeExec.buildNativeInputPlan(
+          "NativeBroadcastNestedLoopJoinExec",
+          broadcastSchema,
+          nativeBroadcastSchema,
+          streamedRDD.getNumPartitions,
+          taskContext,
+          () => broadcastRows.value.iterator)
+
+        val nestedLoopJoin = NestedLoopJoinExecNode.newBuilder().setJoinType(nativeJoinType)
+        buildSide match {
+          case BuildLeft =>
+            nestedLoopJoin
+              .setLeft(broadcastChild)
+              .setRight(streamedChild)
+              .setBuildSide(JoinSide.LEFT_SIDE)
+          case BuildRight =>
+            nestedLoopJoin
+              .setLeft(streamedChild)
+              .setRight(broadcastChild)
+              .setBuildSide(JoinSide.RIGHT_SIDE)
+        }
+        nativeCondition.foreach(nestedLoopJoin.setCondition)
+        PhysicalPlanNode.newBuilder().setNestedLoopJoin(nestedLoopJoin).build()
+      })
+  }
+
+  override def doCanonicalize(): SparkPlan =
+    BroadcastNestedLoopJoinExec(left, right, buildSide, joinType, condition).canonicalized
+}
+
+object NativeBroadcastNestedLoopJoinExec {
+
+  /**
+   * the native nested-loop join does not track matched rows of the build side, so outer, semi
+   * and anti joins must stream their preserved side.
+   */
+  def checkSupported(joinType: JoinType, buildSide: BuildSide): Unit = {
+    (joinType, buildSide) match {
+      case (Inner, _) =>
+      case (LeftOuter | LeftSemi | LeftAnti, BuildRight) =>
+      case (RightOuter, BuildLeft) =>
+      case _ =>
+        throw new NotImplementedError(
+          s"nested-loop join is not yet supported: joinType=${joinType}, buildSide=${buildSide}")
+    }
+  }
+}
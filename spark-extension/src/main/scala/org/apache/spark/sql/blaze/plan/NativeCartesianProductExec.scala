@@ -0,0 +1,114 @@
+/*
+ * Copyright 2022 The Blaze Authors
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+package org.apache.spark.sql.blaze.plan
+
+import org.apache.spark.NarrowDependency
+import org.apache.spark.Partition
+import org.apache.spark.rdd.CartesianPartition
+import org.apache.spark.rdd.RDD
+import org.apache.spark.sql.blaze.MetricNode
+import org.apache.spark.sql.blaze.NativeConverters
+import org.apache.spark.sql.blaze.NativeRDD
+import org.apache.spark.sql.blaze.NativeSupports
+import org.apache.spark.sql.catalyst.InternalRow
+import org.apache.spark.sql.catalyst.expressions.Attribute
+import org.apache.spark.sql.catalyst.expressions.Expression
+import org.apache.spark.sql.catalyst.plans.physical.Partitioning
+import org.apache.spark.sql.catalyst.plans.physical.UnknownPartitioning
+import org.apache.spark.sql.execution.BinaryExecNode
+import org.apache.spark.sql.execution.SparkPlan
+import org.apache.spark.sql.execution.joins.CartesianProductExec
+import org.apache.spark.sql.execution.metric.SQLMetric
+import org.blaze.protobuf.JoinSide
+import org.blaze.protobuf.JoinType
+import org.blaze.protobuf.NestedLoopJoinExecNode
+import org.blaze.protobuf.PartitionPair
+import org.blaze.protobuf.PhysicalPlanNode
+
+/**
+ * Joins each pair of partitions of both sides natively, as spark's CartesianRDD does. the
+ * native plan of each pair executes the paired partitions of its children, and the right
+ * partition is collected by the nested-loop join.
+ */
+case class NativeCartesianProductExec(
+    override val left: SparkPlan,
+    override val right: SparkPlan,
+    condition: Option[Expression],
+    override val output: Seq[Attribute])
+    extends BinaryExecNode
+    with NativeSupports {
+
+  override lazy val metrics: Map[String, SQLMetric] =
+    NativeSupports.getDefaultNativeMetrics(sparkContext)
+
+  override def outputPartitioning: Partitioning =
+    UnknownPartitioning(left.outputPartitioning.numPartitions *
+      right.outputPartitioning.numPartitions)
+
+  private val nativeCondition = condition.map(NativeConverters.convertExpr)
+
+  override def doExecuteNative(): NativeRDD = {
+    val leftRDD = NativeSupports.executeNative(left)
+    val rightRDD = NativeSupports.executeNative(right)
+    val nativeMetrics = MetricNode(metrics, Seq(leftRDD.metrics, rightRDD.metrics))
+
+    val numRightPartitions = rightRDD.partitions.length
+    val partitions: Array[Partition] = (for {
+      s1 <- leftRDD.partitions
+      s2 <- rightRDD.partitions
+    } yield {
+      val index = s1.index * numRightPartitions + s2.index
+      new CartesianPartition(index, leftRDD, rightRDD, s1.index, s2.index)
+    }).toArray
+
+    val dependencies = Seq(
+      new NarrowDependency[InternalRow](leftRDD.asInstanceOf[RDD[InternalRow]]) {
+        override def getParents(id: Int): Seq[Int] = Seq(id / numRightPartitions)
+      },
+      new NarrowDependency[InternalRow](rightRDD.asInstanceOf[RDD[InternalRow]]) {
+        override def getParents(id: Int): Seq[Int] = Seq(id % numRightPartitions)
+      })
+
+    new NativeRDD(
+      sparkContext,
+      nativeMetrics,
+      partitions,
+      dependencies,
+      (partition, taskContext) => {
+        val cartesianPartition = partition.asInstanceOf[CartesianPartition]
+        val leftChild = leftRDD.nativePlan(cartesianPartition.s1, taskContext)
+        val rightChild = rightRDD.nativePlan(cartesianPartition.s2, taskContext)
+
+        val nestedLoopJoin = NestedLoopJoinExecNode
+          .newBuilder()
+          .setLeft(leftChild)
+          .setRight(rightChild)
+          .setJoinType(JoinType.INNER)
+          .setBuildSide(JoinSide.RIGHT_SIDE)
+          .setChildrenPartitions(
+            PartitionPair
+              .newBuilder()
+              .setLeft(cartesianPartition.s1.index)
+              .setRight(cartesianPartition.s2.index))
+        nativeCondition.foreach(nestedLoopJoin.setCondition)
+        PhysicalPlanNode.newBuilder().setNestedLoopJoin(nestedLoopJoin).build()
+      })
+  }
+
+  override def doCanonicalize(): SparkPlan =
+    CartesianProductExec(left, right, condition).canonicalized
+}
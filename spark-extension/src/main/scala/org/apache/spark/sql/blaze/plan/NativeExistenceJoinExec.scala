@@ -0,0 +1,175 @@
+/*
+ * Copyright 2022 The Blaze Authors
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+package org.apache.spark.sql.blaze.plan
+
+import scala.collection.JavaConverters._
+
+import org.apache.spark.OneToOneDependency
+import org.apache.spark.rdd.RDD
+import org.apache.spark.sql.blaze.ConvertToNativeExec
+import org.apache.spark.sql.blaze.MetricNode
+import org.apache.spark.sql.blaze.NativeConverters
+import org.apache.spark.sql.blaze.NativeRDD
+import org.apache.spark.sql.blaze.NativeSupports
+import org.apache.spark.sql.catalyst.InternalRow
+import org.apache.spark.sql.catalyst.expressions.Attribute
+import org.apache.spark.sql.catalyst.expressions.EqualTo
+import org.apache.spark.sql.catalyst.expressions.Expression
+import org.apache.spark.sql.catalyst.expressions.IsNull
+import org.apache.spark.sql.catalyst.expressions.Or
+import org.apache.spark.sql.catalyst.plans.physical.Partitioning
+import org.apache.spark.sql.execution.BinaryExecNode
+import org.apache.spark.sql.execution.SparkPlan
+import org.apache.spark.sql.execution.metric.SQLMetric
+import org.apache.spark.sql.types.StructField
+import org.apache.spark.sql.types.StructType
+import org.blaze.protobuf.HashJoinExecNode
+import org.blaze.protobuf.JoinKeyNormalization
+import org.blaze.protobuf.JoinOn
+import org.blaze.protobuf.JoinType
+import org.blaze.protobuf.PartitionMode
+import org.blaze.protobuf.PhysicalPlanNode
+import org.blaze.protobuf.Schema
+
+/**
+ * Joins left rows against the keys of the right side natively, with spark's semantics of
+ * subquery joins: an existence join outputs all left rows with the exists attribute, and a
+ * null-aware anti join (the NOT IN rewrite, if existsAttr is None) follows the null semantics
+ * of NOT IN.
+ *
+ * if rightBroadcast is set, the right side is a broadcast of rows read natively by each task
+ * of the left side, otherwise both sides are co-partitioned native plans.
+ */
+case class NativeExistenceJoinExec(
+    override val left: SparkPlan,
+    override val right: SparkPlan,
+    leftKeys: Seq[Expression],
+    rightKeys: Seq[Expression],
+    existsAttr: Option[Attribute],
+    rightBroadcast: Boolean,
+    keyNormalizations: Seq[Option[JoinKeyNormalization]],
+    override val output: Seq[Attribute])
+    extends BinaryExecNode
+    with NativeSupports {
+
+  override lazy val metrics: Map[String, SQLMetric] =
+    NativeSupports.getDefaultNativeMetrics(sparkContext)
+
+  // metrics of the native input reading broadcast rows
+  private lazy val broadcastInputMetrics: Map[String, SQLMetric] =
+    NativeSupports.getDefaultNativeMetrics(sparkContext)
+
+  override def outputPartitioning: Partitioning = left.outputPartitioning
+
+  private val nativeJoinOn = leftKeys.zip(rightKeys).zipWithIndex.map {
+    case ((leftKey, rightKey), i) =>
+      val leftColumn = NativeConverters.convertExpr(leftKey).getColumn match {
+        case column if column.getName.isEmpty =>
+          throw new NotImplementedError(s"existence join leftKey is not column: ${leftKey}")
+        case column => column
+      }
+      val rightColumn = NativeConverters.convertExpr(rightKey).getColumn match {
+        case column if column.getName.isEmpty =>
+          throw new NotImplementedError(s"existence join rightKey is not column: ${rightKey}")
+        case column => column
+      }
+      val joinOn = JoinOn
+        .newBuilder()
+        .setLeft(leftColumn)
+        .setRight(rightColumn)
+      keyNormalizations.lift(i).flatten.foreach(joinOn.setNormalization)
+      joinOn.build()
+  }
+
+  private val nativeRightSchema: Schema = NativeConverters.convertSchema(
+    StructType(
+      right.output.map(a => StructField(a.toString(), a.dataType, a.nullable, a.metadata))))
+
+  override def doExecuteNative(): NativeRDD = {
+    val leftRDD = NativeSupports.executeNative(left)
+    val rightRDD = if (rightBroadcast) None else Some(NativeSupports.executeNative(right))
+    val broadcastRows =
+      if (rightBroadcast) Some(right.executeBroadcast[Array[InternalRow]]()) else None
+    val rightSchema = right.schema
+    val rightMetrics = rightRDD.map(_.metrics).getOrElse(MetricNode(broadcastInputMetrics, Nil))
+    val nativeMetrics = MetricNode(metrics, Seq(leftRDD.metrics, rightMetrics))
+
+    val dependencies =
+      (Seq(leftRDD) ++ rightRDD).map(rdd =>
+        new OneToOneDependency[InternalRow](rdd.asInstanceOf[RDD[InternalRow]]))
+    new NativeRDD(
+      sparkContext,
+      nativeMetrics,
+      leftRDD.partitions,
+      dependencies,
+      (partition, taskContext) => {
+        val leftChild = leftRDD.nativePlan(partition, taskContext)
+        val rightChild = rightRDD match {
+          case Some(rdd) => rdd.nativePlan(rdd.partitions(partition.index), taskContext)
+          case None =>
+            ConvertToNativeExec.buildNativeInputPlan(
+              "NativeExistenceJoinExec",
+              rightSchema,
+              nativeRightSchema,
+              leftRDD.getNumPartitions,
+              taskContext,
+              () => broadcastRows.get.value.iterator)
+        }
+
+        val hashJoin = HashJoinExecNode
+          .newBuilder()
+          .setLeft(leftChild)
+          .setRight(rightChild)
+          .addAllOn(nativeJoinOn.asJava)
+          .setPartitionMode(PartitionMode.PARTITIONED)
+          .setNullEqualsNull(false)
+        existsAttr match {
+          case Some(attr) =>
+            hashJoin.setJoinType(JoinType.LEFT).setExistenceColumn(attr.toString())
+          case None =>
+            hashJoin.setJoinType(JoinType.ANTI).setNullAwareAnti(true)
+        }
+        PhysicalPlanNode.newBuilder().setHashJoin(hashJoin).build()
+      })
+  }
+}
+
+object NativeExistenceJoinExec {
+
+  /**
+   * Extracts keys of the single-column NOT IN rewrite, which spark 3.0 plans as an anti join
+   * with condition `a = b OR isnull(a = b)`. returns left and right keys if matched.
+   */
+  def extractNullAwareAntiKeys(
+      condition: Expression,
+      left: SparkPlan,
+      right: SparkPlan): Option[(Expression, Expression)] = {
+    condition match {
+      case Or(equalTo @ EqualTo(l, r), IsNull(isNullEqualTo))
+          if equalTo.semanticEquals(isNullEqualTo) =>
+        if (l.references.subsetOf(left.outputSet) && r.references.subsetOf(right.outputSet)) {
+          Some((l, r))
+        } else if (l.references.subsetOf(right.outputSet) &&
+          r.references.subsetOf(left.outputSet)) {
+          Some((r, l))
+        } else {
+          None
+        }
+      case _ => None
+    }
+  }
+}
@@ -29,6 +29,7 @@ import org.apache.spark.sql.execution.UnaryExecNode
 import org.apache.spark.sql.execution.metric.SQLMetric
 import org.apache.spark.sql.execution.FilterExec
 import org.blaze.protobuf.FilterExecNode
+import org.blaze.protobuf.PhysicalExprNode
 import org.blaze.protobuf.PhysicalPlanNode
 
 case class NativeFilterExec(condition: Expression, override val child: SparkPlan)
@@ -42,11 +43,18 @@ case class NativeFilterExec(condition: Expression, override val child: SparkPlan
   override def outputPartitioning: Partitioning = child.outputPartitioning
   override def outputOrdering: Seq[SortOrder] = child.outputOrdering
 
-  private val nativeFilterExpr = NativeConverters.convertExpr(condition)
+  // converted when planning to fall back if the condition is not supported, by the converters
+  // or by the native engine
+  locally {
+    val nativeFilterExpr =
+      NativeConverters.withSubqueryPlaceholders(NativeConverters.convertExpr(condition))
+    NativeSupports.checkNativeSupported(child.output)(nativeFilter(nativeFilterExpr, _))
+  }
 
   override def doExecuteNative(): NativeRDD = {
     val inputRDD = NativeSupports.executeNative(child)
     val nativeMetrics = MetricNode(metrics, Seq(inputRDD.metrics))
+    val nativeFilterExpr = NativeConverters.convertExpr(condition) // with results of subqueries
 
     new NativeRDD(
       sparkContext,
@@ -55,15 +63,21 @@ case class NativeFilterExec(condition: Expression, override val child: SparkPlan
       inputRDD.dependencies,
       (partition, taskContext) => {
         val inputPartition = inputRDD.partitions(partition.index)
-        val nativeFilterExec = FilterExecNode
-          .newBuilder()
-          .setInput(inputRDD.nativePlan(inputPartition, taskContext))
-          .setExpr(nativeFilterExpr)
-          .build()
-        PhysicalPlanNode.newBuilder().setFilter(nativeFilterExec).build()
+        nativeFilter(nativeFilterExpr, inputRDD.nativePlan(inputPartition, taskContext))
       })
   }
 
+  private def nativeFilter(
+      nativeFilterExpr: PhysicalExprNode,
+      input: PhysicalPlanNode): PhysicalPlanNode = {
+    val nativeFilterExec = FilterExecNode
+      .newBuilder()
+      .setInput(input)
+      .setExpr(nativeFilterExpr)
+      .build()
+    PhysicalPlanNode.newBuilder().setFilter(nativeFilterExec).build()
+  }
+
   override def doCanonicalize(): SparkPlan =
     FilterExec(condition, child).canonicalized
 }
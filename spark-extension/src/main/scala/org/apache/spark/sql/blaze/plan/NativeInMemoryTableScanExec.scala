@@ -0,0 +1,89 @@
+/*
+ * Copyright 2022 The Blaze Authors
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+package org.apache.spark.sql.blaze.plan
+
+import scala.collection.JavaConverters._
+
+import org.apache.spark.sql.blaze.ConvertToNativeExec
+import org.apache.spark.sql.blaze.MetricNode
+import org.apache.spark.sql.blaze.NativeConverters
+import org.apache.spark.sql.blaze.NativeRDD
+import org.apache.spark.sql.blaze.NativeSupports
+import org.apache.spark.sql.catalyst.expressions.Attribute
+import org.apache.spark.sql.catalyst.plans.physical.Partitioning
+import org.apache.spark.sql.execution.LeafExecNode
+import org.apache.spark.sql.execution.SparkPlan
+import org.apache.spark.sql.execution.columnar.InMemoryTableScanExec
+import org.apache.spark.sql.execution.metric.SQLMetric
+import org.apache.spark.sql.types.StructField
+import org.apache.spark.sql.types.StructType
+import org.blaze.protobuf.Schema
+
+/**
+ * Reads blocks of a cached table (df.cache) into native operators, so that queries over cached
+ * tables are not falling back to JVM at the scan.
+ *
+ * cached blocks are read as columnar batches if supported by the cached relation, whose rows are
+ * converted to native inputs directly without projecting to unsafe rows by ColumnarToRowExec.
+ */
+case class NativeInMemoryTableScanExec(basedScan: InMemoryTableScanExec)
+    extends LeafExecNode
+    with NativeSupports {
+
+  override lazy val metrics: Map[String, SQLMetric] =
+    NativeSupports.getDefaultNativeMetrics(sparkContext)
+
+  override def output: Seq[Attribute] = basedScan.output
+  override def outputPartitioning: Partitioning = basedScan.outputPartitioning
+
+  private val nativeSchema: Schema = NativeConverters.convertSchema(
+    StructType(output.map(a => StructField(a.toString(), a.dataType, a.nullable, a.metadata))))
+
+  override def doExecuteNative(): NativeRDD = {
+    val inputRDD = if (basedScan.supportsColumnar) {
+      basedScan
+        .executeColumnar()
+        .mapPartitionsInternal(_.flatMap(_.rowIterator().asScala))
+    } else {
+      basedScan.execute()
+    }
+    val nativeMetrics = MetricNode(metrics, Nil)
+
+    new NativeRDD(
+      sparkContext,
+      nativeMetrics,
+      inputRDD.partitions,
+      inputRDD.dependencies,
+      (partition, context) => {
+        ConvertToNativeExec.buildNativeInputPlan(
+          "NativeInMemoryTableScanExec",
+          schema,
+          nativeSchema,
+          inputRDD.getNumPartitions,
+          context,
+          () => inputRDD.compute(partition, context))
+      })
+  }
+
+  override val nodeName: String =
+    s"NativeInMemoryTableScan ${basedScan.relation.cacheBuilder.tableName.getOrElse("")}"
+
+  override def simpleString(maxFields: Int): String =
+    s"$nodeName (${basedScan.simpleString(maxFields)})"
+
+  override def doCanonicalize(): SparkPlan = basedScan.canonicalized
+}
@@ -21,37 +21,113 @@ import scala.collection.JavaConverters._
 import org.apache.spark.sql.blaze.MetricNode
 import org.apache.spark.sql.blaze.NativeConverters
 import org.apache.spark.sql.blaze.NativeRDD
+import org.apache.spark.sql.blaze.NativeRowDeletesProvider
 import org.apache.spark.sql.blaze.NativeSupports
 import org.apache.spark.sql.catalyst.expressions.And
 import org.apache.spark.sql.catalyst.expressions.Attribute
+import org.apache.spark.sql.catalyst.expressions.EmptyRow
+import org.apache.spark.sql.catalyst.expressions.EqualTo
+import org.apache.spark.sql.catalyst.expressions.Literal
+import org.apache.spark.sql.catalyst.expressions.SortOrder
 import org.apache.spark.sql.execution.FileSourceScanExec
 import org.apache.spark.sql.execution.LeafExecNode
 import org.apache.spark.sql.execution.datasources.FileScanRDD
 import org.apache.spark.Partition
+import org.apache.spark.sql.catalyst.plans.physical.HashPartitioning
 import org.apache.spark.sql.catalyst.plans.physical.Partitioning
 import org.apache.spark.sql.execution.metric.SQLMetric
+import org.apache.spark.sql.execution.metric.SQLMetrics
 import org.apache.spark.sql.execution.SparkPlan
+import org.apache.spark.sql.internal.SQLConf
+import org.apache.spark.sql.types.StructType
 import org.blaze.protobuf.FileGroup
 import org.blaze.protobuf.FileRange
 import org.blaze.protobuf.FileScanExecConf
 import org.blaze.protobuf.ParquetScanExecNode
 import org.blaze.protobuf.PartitionedFile
 import org.blaze.protobuf.PhysicalPlanNode
+import org.blaze.protobuf.RowDeletes
 import org.blaze.protobuf.Statistics
 
 case class NativeParquetScanExec(basedFileScan: FileSourceScanExec)
     extends LeafExecNode
     with NativeSupports {
 
-  override lazy val metrics: Map[String, SQLMetric] =
-    NativeSupports.getDefaultNativeMetrics(sparkContext)
+  private val columnMetricsEnabled =
+    sparkContext.conf.getBoolean("spark.blaze.parquet.columnMetrics.enabled", false)
+
+  override lazy val metrics: Map[String, SQLMetric] = {
+    val columnMetrics = if (columnMetricsEnabled) {
+      basedFileScan.requiredSchema.fieldNames.toSeq.flatMap { name =>
+        Seq(
+          s"decode_time.$name" ->
+            SQLMetrics.createNanoTimingMetric(sparkContext, s"Native.$name estimated decode_time"),
+          s"compressed_bytes.$name" ->
+            SQLMetrics.createSizeMetric(sparkContext, s"Native.$name compressed_bytes"),
+          s"uncompressed_bytes.$name" ->
+            SQLMetrics.createSizeMetric(sparkContext, s"Native.$name uncompressed_bytes"),
+          s"dictionary_chunks.$name" ->
+            SQLMetrics.createMetric(sparkContext, s"Native.$name dictionary_chunks"),
+          s"plain_chunks.$name" ->
+            SQLMetrics.createMetric(sparkContext, s"Native.$name plain_chunks"),
+          s"rle_chunks.$name" ->
+            SQLMetrics.createMetric(sparkContext, s"Native.$name rle_chunks"),
+          s"other_chunks.$name" ->
+            SQLMetrics.createMetric(sparkContext, s"Native.$name other_chunks"))
+      }
+    } else {
+      Nil
+    }
+    NativeSupports.getDefaultNativeMetrics(sparkContext) ++ columnMetrics
+  }
 
   override def output: Seq[Attribute] = basedFileScan.output
   override def outputPartitioning: Partitioning = basedFileScan.outputPartitioning
+  override def outputOrdering: Seq[SortOrder] = basedFileScan.outputOrdering
 
   private val inputFileScanRDD = basedFileScan.inputRDD.asInstanceOf[FileScanRDD]
 
-  private val nativeFileSchema = NativeConverters.convertSchema(basedFileScan.relation.schema)
+  // partition columns are the trailing fields of the native scan schema, filled with partition
+  // values of each file. data columns overlapping with partition columns are not read
+  private val partitionSchema = basedFileScan.relation.partitionSchema
+  private val fileSchema = {
+    val resolver = SQLConf.get.resolver
+    val dataFields = basedFileScan.relation.dataSchema.filterNot { field =>
+      partitionSchema.exists(partitionField => resolver(field.name, partitionField.name))
+    }
+    StructType(dataFields ++ partitionSchema)
+  }
+  private val nativeFileSchema = NativeConverters.convertSchema(fileSchema)
+
+  // partitions of bucketed scans are buckets, with bucket ids as partition indices
+  private val bucketSpec = basedFileScan.relation.bucketSpec
+    .filter(_ => basedFileScan.relation.sparkSession.sessionState.conf.bucketingEnabled)
+
+  // spark 3.0 only prunes buckets of a single bucket column, buckets of multiple bucket columns
+  // are pruned here if all of them are filtered by equality with literals
+  private val selectedBucketId: Option[Int] = bucketSpec
+    .filter(_.bucketColumnNames.length > 1 && basedFileScan.optionalBucketSet.isEmpty)
+    .flatMap { spec =>
+      val resolver = SQLConf.get.resolver
+      val equalities = basedFileScan.dataFilters.flatMap {
+        case EqualTo(attr: Attribute, Literal(v, dt)) if v != null && dt == attr.dataType =>
+          Some(attr.name -> Literal(v, dt))
+        case EqualTo(Literal(v, dt), attr: Attribute) if v != null && dt == attr.dataType =>
+          Some(attr.name -> Literal(v, dt))
+        case _ => None
+      }
+      val bucketValues = spec.bucketColumnNames.map { bucketColumnName =>
+        equalities.collectFirst {
+          case (name, value) if resolver(name, bucketColumnName) => value
+        }
+      }
+      if (bucketValues.forall(_.isDefined)) {
+        val partitioning = HashPartitioning(bucketValues.map(_.get), spec.numBuckets)
+        Some(partitioning.partitionIdExpression.eval(EmptyRow).asInstanceOf[Int])
+      } else {
+        None
+      }
+    }
   private val nativePruningPredicateFilter = basedFileScan.dataFilters
     .reduceOption(And)
     .map(NativeConverters.convertExprLogical)
@@ -65,9 +141,18 @@ case class NativeParquetScanExec(basedFileScan: FileSourceScanExec)
       .groupBy(_.filePath)
       .mapValues(_.map(_.length).sum)
 
+    // rows deleted from files of lakehouse tables, shared by splits of the same file
+    val rowDeletes = NativeRowDeletesProvider.provider match {
+      case Some(provider) =>
+        fileSizes.keys.flatMap { filePath =>
+          provider.getRowDeletes(basedFileScan.relation, filePath).map(filePath -> _)
+        }.toMap
+      case None => Map[String, RowDeletes]()
+    }
+
     // list input file statuses
-    def nativePartitionedFile(file: org.apache.spark.sql.execution.datasources.PartitionedFile) =
-      PartitionedFile
+    def nativePartitionedFile(file: org.apache.spark.sql.execution.datasources.PartitionedFile) = {
+      val nativeFile = PartitionedFile
         .newBuilder()
         .setPath(file.filePath.replaceFirst("^file://", ""))
         .setSize(fileSizes(file.filePath))
@@ -78,12 +163,27 @@ case class NativeParquetScanExec(basedFileScan: FileSourceScanExec)
             .setStart(file.start)
             .setEnd(file.start + file.length)
             .build())
-        .build()
+      partitionSchema.zipWithIndex.foreach { case (field, i) =>
+        val partitionValue = if (file.partitionValues.isNullAt(i)) {
+          NativeConverters.convertNullValue(field.dataType)
+        } else {
+          val value = file.partitionValues.get(i, field.dataType)
+          NativeConverters.convertValue(value, field.dataType)
+        }
+        nativeFile.addPartitionValues(partitionValue)
+      }
+      rowDeletes.get(file.filePath).foreach(nativeFile.setDeletes)
+      nativeFile.build()
+    }
 
     partitions.map { partition =>
+      val files = selectedBucketId match {
+        case Some(bucketId) if partition.index != bucketId => Nil
+        case _ => partition.files.map(nativePartitionedFile).toList
+      }
       FileGroup
         .newBuilder()
-        .addAllFiles(partition.files.map(nativePartitionedFile).toList.asJava)
+        .addAllFiles(files.asJava)
         .build()
     }
   }
@@ -92,9 +192,10 @@ case class NativeParquetScanExec(basedFileScan: FileSourceScanExec)
     val partitions = inputFileScanRDD.filePartitions.toArray
     val nativeMetrics = MetricNode(metrics, Nil)
 
-    val fileSchema = basedFileScan.relation.schema
-    val outputSchema = basedFileScan.requiredSchema.fields
-    val projection = outputSchema.map(field => fileSchema.fieldIndex(field.name))
+    // outputs required data columns followed by all partition columns, like FileSourceScanExec
+    val numDataFields = fileSchema.length - partitionSchema.length
+    val projection = basedFileScan.requiredSchema.fields.map(field =>
+      fileSchema.fieldIndex(field.name)) ++ partitionSchema.indices.map(numDataFields + _)
 
     new NativeRDD(
       sparkContext,
@@ -113,6 +214,7 @@ case class NativeParquetScanExec(basedFileScan: FileSourceScanExec)
         val nativeParquetScanExecBuilder = ParquetScanExecNode
           .newBuilder()
           .setBaseConf(nativeParquetScanConf)
+          .setColumnMetrics(columnMetricsEnabled)
 
         nativePruningPredicateFilter match {
           case Some(filter) => nativeParquetScanExecBuilder.setPruningPredicate(filter)
@@ -0,0 +1,113 @@
+/*
+ * Copyright 2022 The Blaze Authors
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+package org.apache.spark.sql.blaze.plan
+
+import scala.collection.JavaConverters._
+
+import org.apache.spark.sql.blaze.MetricNode
+import org.apache.spark.sql.blaze.NativeRDD
+import org.apache.spark.sql.blaze.NativeSupports
+import org.apache.spark.sql.catalyst.catalog.BucketSpec
+import org.apache.spark.sql.catalyst.expressions.Attribute
+import org.apache.spark.sql.catalyst.expressions.AttributeReference
+import org.apache.spark.sql.catalyst.plans.physical.Partitioning
+import org.apache.spark.sql.execution.SparkPlan
+import org.apache.spark.sql.execution.UnaryExecNode
+import org.apache.spark.sql.execution.metric.SQLMetric
+import org.apache.spark.sql.types.LongType
+import org.apache.spark.sql.types.StringType
+import org.blaze.protobuf.ParquetCompression
+import org.blaze.protobuf.ParquetSinkExecNode
+import org.blaze.protobuf.PhysicalPlanNode
+
+/**
+ * Writes rows of each task into parquet files under a staging directory of the task, and outputs
+ * a row for each written file: (partition, path, ext, num_rows, num_bytes), where path is
+ * relative to the staging directory of the task, see NativeParquetSinkExec.taskOutputDir.
+ *
+ * columns of the child are written by names, and the child must be sorted by partition columns.
+ * files are committed by NativeParquetInsertIntoHadoopFsRelationCommand301.
+ */
+case class NativeParquetSinkExec(
+    override val child: SparkPlan,
+    stagingDir: String,
+    partitionColumns: Seq[String],
+    bucketSpec: Option[BucketSpec],
+    compression: ParquetCompression,
+    fileExtension: String,
+    maxRecordsPerFile: Long,
+    maxFileBytes: Long,
+    metadata: Map[String, String])
+    extends UnaryExecNode
+    with NativeSupports {
+
+  override lazy val metrics: Map[String, SQLMetric] =
+    NativeSupports.getDefaultNativeMetrics(sparkContext)
+
+  override val output: Seq[Attribute] = Seq(
+    AttributeReference("partition", StringType, nullable = true)(),
+    AttributeReference("path", StringType, nullable = false)(),
+    AttributeReference("ext", StringType, nullable = false)(),
+    AttributeReference("num_rows", LongType, nullable = false)(),
+    AttributeReference("num_bytes", LongType, nullable = false)())
+
+  override def outputPartitioning: Partitioning = child.outputPartitioning
+
+  override def doExecuteNative(): NativeRDD = {
+    val inputRDD = NativeSupports.executeNative(child)
+    val nativeMetrics = MetricNode(metrics, Seq(inputRDD.metrics))
+
+    new NativeRDD(
+      sparkContext,
+      nativeMetrics,
+      inputRDD.partitions,
+      inputRDD.dependencies,
+      (partition, taskContext) => {
+        val inputPartition = inputRDD.partitions(partition.index)
+        val outputDir = NativeParquetSinkExec.taskOutputDir(
+          stagingDir,
+          partition.index,
+          taskContext.taskAttemptId())
+
+        // like spark, the first task writes an empty file if there is no rows to write, so
+        // that the schema is kept in the output
+        val writeEmptyFile =
+          partition.index == 0 && partitionColumns.isEmpty && bucketSpec.isEmpty
+
+        val nativeParquetSinkExec = ParquetSinkExecNode
+          .newBuilder()
+          .setInput(inputRDD.nativePlan(inputPartition, taskContext))
+          .setOutputDir(outputDir)
+          .addAllPartitionColumns(partitionColumns.asJava)
+          .addAllBucketColumns(bucketSpec.toSeq.flatMap(_.bucketColumnNames).asJava)
+          .setNumBuckets(bucketSpec.map(_.numBuckets).getOrElse(0))
+          .setCompression(compression)
+          .setFileExtension(fileExtension)
+          .setMaxFileBytes(maxFileBytes)
+          .setMaxRecordsPerFile(maxRecordsPerFile)
+          .setWriteEmptyFile(writeEmptyFile)
+          .putAllMetadata(metadata.asJava)
+          .build()
+        PhysicalPlanNode.newBuilder().setParquetSink(nativeParquetSinkExec).build()
+      })
+  }
+}
+
+object NativeParquetSinkExec {
+  def taskOutputDir(stagingDir: String, partitionId: Int, taskAttemptId: Long): String =
+    s"$stagingDir/$partitionId-$taskAttemptId"
+}
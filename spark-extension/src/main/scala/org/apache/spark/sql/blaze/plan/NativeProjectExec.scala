@@ -54,6 +54,7 @@ case class NativeProjectExec(projectList: Seq[NamedExpression], override val chi
   override def doExecuteNative(): NativeRDD = {
     val inputRDD = NativeSupports.executeNative(child)
     val nativeMetrics = MetricNode(metrics, Seq(inputRDD.metrics))
+    val nativeNamedExprs = convertNamedExprs() // with results of subqueries
 
     new NativeRDD(
       sparkContext,
@@ -62,17 +63,30 @@ case class NativeProjectExec(projectList: Seq[NamedExpression], override val chi
       inputRDD.dependencies,
       (partition, taskContext) => {
         val inputPartition = inputRDD.partitions(partition.index)
-        val nativeProjectExec = ProjectionExecNode
-          .newBuilder()
-          .addAllExprName(nativeNamedExprs.map(_._1).asJava)
-          .addAllExpr(nativeNamedExprs.map(_._2).asJava)
-          .setInput(inputRDD.nativePlan(inputPartition, taskContext))
-          .build()
-        PhysicalPlanNode.newBuilder().setProjection(nativeProjectExec).build()
+        nativeProjection(nativeNamedExprs, inputRDD.nativePlan(inputPartition, taskContext))
       })
   }
 
-  private val nativeNamedExprs: Seq[(String, PhysicalExprNode)] = {
+  // converted when planning to fall back if any expr is not supported, by the converters or
+  // by the native engine
+  locally {
+    val nativeNamedExprs = NativeConverters.withSubqueryPlaceholders(convertNamedExprs())
+    NativeSupports.checkNativeSupported(child.output)(nativeProjection(nativeNamedExprs, _))
+  }
+
+  private def nativeProjection(
+      nativeNamedExprs: Seq[(String, PhysicalExprNode)],
+      input: PhysicalPlanNode): PhysicalPlanNode = {
+    val nativeProjectExec = ProjectionExecNode
+      .newBuilder()
+      .addAllExprName(nativeNamedExprs.map(_._1).asJava)
+      .addAllExpr(nativeNamedExprs.map(_._2).asJava)
+      .setInput(input)
+      .build()
+    PhysicalPlanNode.newBuilder().setProjection(nativeProjectExec).build()
+  }
+
+  private def convertNamedExprs(): Seq[(String, PhysicalExprNode)] = {
     val namedExprs = ArrayBuffer[(String, PhysicalExprNode)]()
     var numAddedColumns = 0
 
@@ -24,6 +24,9 @@ import org.apache.spark.sql.blaze.NativeConverters
 import org.apache.spark.sql.blaze.NativeRDD
 import org.apache.spark.sql.blaze.NativeSupports
 import org.apache.spark.sql.catalyst.expressions.Attribute
+import org.apache.spark.sql.catalyst.expressions.AttributeReference
+import org.apache.spark.sql.catalyst.expressions.Cast
+import org.apache.spark.sql.catalyst.expressions.KnownFloatingPointNormalized
 import org.apache.spark.sql.catalyst.InternalRow
 import org.apache.spark.sql.catalyst.expressions.SortOrder
 import org.apache.spark.sql.catalyst.plans.physical.Partitioning
@@ -32,9 +35,15 @@ import org.apache.spark.sql.execution.SparkPlan
 import org.apache.spark.sql.execution.metric.SQLMetric
 import org.apache.spark.OneToOneDependency
 import org.apache.spark.sql.catalyst.expressions.Expression
+import org.apache.spark.sql.catalyst.optimizer.NormalizeNaNAndZero
 import org.apache.spark.sql.catalyst.plans.JoinType
 import org.apache.spark.sql.execution.BinaryExecNode
 import org.apache.spark.sql.execution.joins.SortMergeJoinExec
+import org.apache.spark.sql.types.DataType
+import org.apache.spark.sql.types.DecimalType
+import org.apache.spark.sql.types.IntegralType
+import org.apache.spark.sql.types.StringType
+import org.blaze.protobuf.JoinKeyNormalization
 import org.blaze.protobuf.JoinOn
 import org.blaze.protobuf.PhysicalPlanNode
 import org.blaze.protobuf.SortMergeJoinExecNode
@@ -48,15 +57,16 @@ case class NativeSortMergeJoinExec(
     override val output: Seq[Attribute],
     override val outputPartitioning: Partitioning,
     override val outputOrdering: Seq[SortOrder],
-    joinType: JoinType)
+    joinType: JoinType,
+    keyNormalizations: Seq[Option[JoinKeyNormalization]] = Nil)
     extends BinaryExecNode
     with NativeSupports {
 
   override lazy val metrics: Map[String, SQLMetric] =
     NativeSupports.getDefaultNativeMetrics(sparkContext)
 
-  private val nativeJoinOn = leftKeys.zip(rightKeys).map {
-    case (leftKey, rightKey) =>
+  private val nativeJoinOn = leftKeys.zip(rightKeys).zipWithIndex.map {
+    case ((leftKey, rightKey), i) =>
       val leftColumn = NativeConverters.convertExpr(leftKey).getColumn match {
         case column if column.getName.isEmpty =>
           throw new NotImplementedError(s"SMJ leftKey is not column: ${leftKey}")
@@ -67,11 +77,12 @@ case class NativeSortMergeJoinExec(
           throw new NotImplementedError(s"SMJ leftKey is not column: ${rightKey}")
         case column => column
       }
-      JoinOn
+      val joinOn = JoinOn
         .newBuilder()
         .setLeft(leftColumn)
         .setRight(rightColumn)
-        .build()
+      keyNormalizations.lift(i).flatten.foreach(joinOn.setNormalization)
+      joinOn.build()
   }
 
   private val nativeSortOptions = nativeJoinOn.map(_ => {
@@ -132,3 +143,67 @@ case class NativeSortMergeJoinExec(
       right,
       isSkewJoin = false).canonicalized
 }
+
+object NativeSortMergeJoinExec {
+
+  /**
+   * Strips implicit casts spark inserted into join keys of different types. the casts are
+   * replaced by key normalizations, applied natively to keys of both sides. keys of CHAR
+   * columns are normalized by trimming trailing spaces, following hive semantics. float keys
+   * normalized by spark are normalized natively, where NaNs match each other and -0.0 matches
+   * 0.0.
+   */
+  def normalizeJoinKeys(leftKeys: Seq[Expression], rightKeys: Seq[Expression])
+      : (Seq[Expression], Seq[Expression], Seq[Option[JoinKeyNormalization]]) = {
+
+    def stripCast(key: Expression): Expression = key match {
+      case KnownFloatingPointNormalized(NormalizeNaNAndZero(attr: AttributeReference)) =>
+        attr
+      case Cast(attr: AttributeReference, dataType, _)
+          if isNormalizableCast(attr.dataType, dataType) =>
+        attr
+      case key => key
+    }
+
+    def isNormalizableCast(from: DataType, to: DataType): Boolean = (from, to) match {
+      case (_: IntegralType, _: IntegralType) => from.defaultSize <= to.defaultSize
+      case (_: IntegralType | _: DecimalType, _: DecimalType) => true
+      case _ => false
+    }
+
+    def isCharAttribute(key: Expression): Boolean = key match {
+      case attr: AttributeReference if attr.dataType == StringType =>
+        attr.metadata.contains("HIVE_TYPE_STRING") &&
+          attr.metadata.getString("HIVE_TYPE_STRING").toLowerCase.startsWith("char")
+      case _ => false
+    }
+
+    leftKeys
+      .zip(rightKeys)
+      .map {
+        case (leftKey, rightKey) =>
+          val strippedLeftKey = stripCast(leftKey)
+          val strippedRightKey = stripCast(rightKey)
+          val trimTrailingSpaces = Seq(leftKey, rightKey).exists(isCharAttribute)
+          val normalizeNaNAndZero = Seq(leftKey, rightKey).exists {
+            case KnownFloatingPointNormalized(_) => true
+            case _ => false
+          }
+          val casted = (strippedLeftKey ne leftKey) || (strippedRightKey ne rightKey)
+
+          val normalization = if (casted || trimTrailingSpaces || normalizeNaNAndZero) {
+            Some(
+              JoinKeyNormalization
+                .newBuilder()
+                .setTargetType(NativeConverters.convertDataType(leftKey.dataType))
+                .setTrimTrailingSpaces(trimTrailingSpaces)
+                .setNormalizeNanAndZero(normalizeNaNAndZero)
+                .build())
+          } else {
+            None
+          }
+          (strippedLeftKey, strippedRightKey, normalization)
+      }
+      .unzip3
+  }
+}